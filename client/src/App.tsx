@@ -1,15 +1,34 @@
 import {Component, createSignal, For, createResource, Show} from 'solid-js';
 import {Interface} from "./Interface";
 
-export type ServerConfig = { cert_digest_base64: string, default_port: number };
+export type Endpoint = { name?: string, url: string, host: string, port: number, cert_digest_base64: string };
+
+export type Capabilities = { min_protocol_version: number, max_protocol_version: number, features: string[], feature_bits: number, max_datagram_size: number, max_control_packet_len: number, codecs: string[], mixing: boolean, e2ee: boolean, recording: boolean, chat: { max_len: number, history_len: number }, files: { max_size_mb: number, allowed_types: string[] } | null, accounts: { required: boolean, registration: boolean, guests: boolean } };
+
+export type ServerConfig = { cert_digest_base64: string, default_port: number, endpoints?: Endpoint[], capabilities?: Capabilities };
 
 const App: Component = () => {
     const [config, setConfig] = createSignal<ServerConfig>();
 
-    fetch("http://localhost:8080/config.json")
-        .then(response => response.json())
-        .then(data => setConfig(data) && console.log('Config:', data))
-        .catch(error => console.error('Error:', error));
+    // Join links carry the server's URL and certificate digest, for devices that cannot reach
+    // its config on localhost.
+    const params = new URLSearchParams(window.location.search);
+    const server = params.get("server");
+    const digest = params.get("digest");
+    if (server && digest) {
+        const url = new URL(server);
+        const port = Number(url.port || 443);
+        setConfig({
+            cert_digest_base64: digest,
+            default_port: port,
+            endpoints: [{ url: server, host: url.hostname, port, cert_digest_base64: digest }],
+        });
+    } else {
+        fetch("http://localhost:8080/config.json")
+            .then(response => response.json())
+            .then(data => setConfig(data) && console.log('Config:', data))
+            .catch(error => console.error('Error:', error));
+    }
 
     return (
         <Show when={config()} fallback={<div>Loading...</div>}>
@@ -1,4 +1,4 @@
-import {Component, createSignal, For} from "solid-js";
+import {Component, createSignal, For, Show} from "solid-js";
 import {ServerConfig} from "./App";
 
 import {base64ToArrayBuffer} from "./util";
@@ -6,8 +6,9 @@ import {base64ToArrayBuffer} from "./util";
 type LogEntry = { text: string; severity: "info" | "error" };
 
 export const Interface: Component<{ config: ServerConfig }> = ({ config }) => {
+    const endpoints = config.endpoints ?? [];
     const [url, setUrl] = createSignal(
-        `https://localhost:${config.default_port}/`
+        endpoints[0]?.url ?? `https://localhost:${config.default_port}/`
     );
     const [connected, setConnected] = createSignal(false);
     const [sendType, setSendType] = createSignal<"datagram" | "unidi" | "bidi">(
@@ -28,7 +29,11 @@ export const Interface: Component<{ config: ServerConfig }> = ({ config }) => {
     // Connect handler
     const connect = async () => {
         try {
-            const HASH = base64ToArrayBuffer(config.cert_digest_base64);
+            // Endpoints behind a proxy may serve another certificate than the server's.
+            const endpoint = endpoints.find((endpoint) => endpoint.url === url());
+            const HASH = base64ToArrayBuffer(
+                endpoint?.cert_digest_base64 ?? config.cert_digest_base64
+            );
             const transport = new WebTransport(url(), {
                 serverCertificateHashes: [
                     { algorithm: "sha-256", value: HASH },
@@ -173,6 +178,24 @@ export const Interface: Component<{ config: ServerConfig }> = ({ config }) => {
 
             <div>
                 <h2>Establish WebTransport connection</h2>
+                <Show when={endpoints.length > 1}>
+                    <div class="input-line">
+                        <label for="endpoint">Endpoint:</label>
+                        <select
+                            id="endpoint"
+                            onChange={(e) => setUrl(e.target.value)}
+                            disabled={connected()}
+                        >
+                            <For each={endpoints}>
+                                {(endpoint) => (
+                                    <option value={endpoint.url} selected={endpoint.url === url()}>
+                                        {endpoint.name ? `${endpoint.name} (${endpoint.url})` : endpoint.url}
+                                    </option>
+                                )}
+                            </For>
+                        </select>
+                    </div>
+                </Show>
                 <div class="input-line">
                     <label for="url">URL:</label>
                     <input
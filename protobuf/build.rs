@@ -1,13 +1,184 @@
 extern crate prost_build;
 
+use std::collections::BTreeMap;
+use std::fmt::Write;
 use std::io::Result;
+use std::path::PathBuf;
+
+use prost::Message;
+use prost_types::FileDescriptorSet;
+use prost_types::field_descriptor_proto::Label;
+use prost_types::field_descriptor_proto::Type;
+
+const PROTOS: &[&str] = &[
+    "src/common.proto",
+    "src/packet.proto",
+    "src/admin.proto",
+    "src/events.proto",
+];
+
+/// The wire-visible shape of every message and enum, as last accepted.
+const SCHEMA_LOCK: &str = "schema.lock";
+
 fn main() -> Result<()> {
-    prost_build::compile_protos(
-        &[
-            "src/common.proto",
-            "src/packet.proto",
-        ],
-        &["src/"]
-    )?;
+    let descriptors = PathBuf::from(std::env::var("OUT_DIR").unwrap()).join("descriptors.bin");
+    prost_build::Config::new()
+        // Packets can be logged as JSON, as the server does with `--dump-packets`.
+        .type_attribute(".system", "#[derive(serde::Serialize)]")
+        .file_descriptor_set_path(&descriptors)
+        .compile_protos(PROTOS, &["src/"])?;
+
+    for path in PROTOS.iter().chain([&SCHEMA_LOCK, &"build.rs"]) {
+        println!("cargo:rerun-if-changed={path}");
+    }
+    println!("cargo:rerun-if-env-changed=UPDATE_SCHEMA_LOCK");
+    let set = FileDescriptorSet::decode(std::fs::read(&descriptors)?.as_slice())?;
+    if let Err(problem) = check_schema(&set) {
+        eprintln!("{problem}");
+        std::process::exit(1);
+    }
     Ok(())
 }
+
+/// A field or enum value, keyed by its message or enum and its number, which is what older
+/// peers see on the wire.
+type Schema = BTreeMap<(String, i32), Entry>;
+
+#[derive(PartialEq)]
+struct Entry {
+    /// The field's or value's name, then its label and type for fields.
+    shape: String,
+}
+
+/// Fails the build when the protos change in a way older clients or servers would not notice:
+/// a field or enum value that is removed without reserving its number, renumbered or retyped,
+/// or added without being recorded in `schema.lock`.
+///
+/// Additions are safe on the wire, since older peers skip what they do not know, but they
+/// break older peers silently when the other side depends on them. So every addition is
+/// recorded deliberately, once whatever needs it is gated behind a `Feature` bit negotiated in
+/// HELLO or a protocol version. `UPDATE_SCHEMA_LOCK=1 cargo build` rewrites the lock.
+fn check_schema(set: &FileDescriptorSet) -> std::result::Result<(), String> {
+    let mut schema = Schema::new();
+    let mut reserved: Vec<(String, i32, i32)> = Vec::new();
+    for file in &set.file {
+        let package = file.package();
+        for message in &file.message_type {
+            collect_message(package, message, &mut schema, &mut reserved);
+        }
+        for enumeration in &file.enum_type {
+            collect_enum(package, enumeration, &mut schema, &mut reserved);
+        }
+    }
+    let current = render(&schema);
+
+    if std::env::var_os("UPDATE_SCHEMA_LOCK").is_some() {
+        return std::fs::write(SCHEMA_LOCK, current)
+            .map_err(|err| format!("Cannot write {SCHEMA_LOCK}: {err}"));
+    }
+    let locked = std::fs::read_to_string(SCHEMA_LOCK).map_err(|err| {
+        format!("Cannot read {SCHEMA_LOCK}: {err}; write it with UPDATE_SCHEMA_LOCK=1")
+    })?;
+
+    let mut problems = Vec::new();
+    for line in locked.lines().filter(|line| !line.is_empty()) {
+        let Some((owner, number, shape)) = parse(line) else {
+            problems.push(format!("malformed line in {SCHEMA_LOCK}: {line}"));
+            continue;
+        };
+        let key = (owner.to_owned(), number);
+        match schema.remove(&key) {
+            Some(entry) if entry.shape == shape => {}
+            Some(entry) => problems.push(format!(
+                "{owner} {number} changed from `{shape}` to `{}`; numbers must keep their type",
+                entry.shape
+            )),
+            None if reserved
+                .iter()
+                .any(|(name, start, end)| *name == owner && (*start..*end).contains(&number)) => {}
+            None => problems.push(format!(
+                "{owner} {number} (`{shape}`) was removed; reserve its number instead"
+            )),
+        }
+    }
+    for ((owner, number), entry) in &schema {
+        problems.push(format!(
+            "{owner} {number} (`{}`) is new; gate what depends on it behind a Feature bit or \
+             protocol version, then record it with UPDATE_SCHEMA_LOCK=1",
+            entry.shape
+        ));
+    }
+    if problems.is_empty() {
+        return Ok(());
+    }
+    Err(format!(
+        "The protos no longer match {SCHEMA_LOCK}:\n  {}",
+        problems.join("\n  ")
+    ))
+}
+
+fn collect_message(
+    scope: &str,
+    message: &prost_types::DescriptorProto,
+    schema: &mut Schema,
+    reserved: &mut Vec<(String, i32, i32)>,
+) {
+    let name = format!("{scope}.{}", message.name());
+    for field in &message.field {
+        let label = match field.label() {
+            Label::Repeated => "repeated ",
+            _ if field.proto3_optional() => "optional ",
+            _ => "",
+        };
+        let kind = match field.r#type() {
+            Type::Message | Type::Enum => field.type_name().trim_start_matches('.').to_owned(),
+            other => other
+                .as_str_name()
+                .trim_start_matches("TYPE_")
+                .to_lowercase(),
+        };
+        let shape = format!("{} {label}{kind}", field.name());
+        schema.insert((name.clone(), field.number()), Entry { shape });
+    }
+    // Descriptor ranges end exclusively.
+    for range in &message.reserved_range {
+        reserved.push((name.clone(), range.start(), range.end()));
+    }
+    for nested in &message.nested_type {
+        collect_message(&name, nested, schema, reserved);
+    }
+    for enumeration in &message.enum_type {
+        collect_enum(&name, enumeration, schema, reserved);
+    }
+}
+
+fn collect_enum(
+    scope: &str,
+    enumeration: &prost_types::EnumDescriptorProto,
+    schema: &mut Schema,
+    reserved: &mut Vec<(String, i32, i32)>,
+) {
+    let name = format!("{scope}.{}", enumeration.name());
+    for value in &enumeration.value {
+        let shape = value.name().to_owned();
+        schema.insert((name.clone(), value.number()), Entry { shape });
+    }
+    // Enum ranges end inclusively.
+    for range in &enumeration.reserved_range {
+        reserved.push((name.clone(), range.start(), range.end() + 1));
+    }
+}
+
+fn render(schema: &Schema) -> String {
+    let mut out = String::new();
+    for ((owner, number), entry) in schema {
+        let _ = writeln!(out, "{owner} {number} {}", entry.shape);
+    }
+    out
+}
+
+fn parse(line: &str) -> Option<(&str, i32, &str)> {
+    let (owner, rest) = line.split_once(' ')?;
+    let (number, shape) = rest.split_once(' ')?;
+    Some((owner, number.parse().ok()?, shape))
+}
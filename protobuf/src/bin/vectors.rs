@@ -0,0 +1,462 @@
+//! Golden byte vectors of the protocol's packets, in `vectors.json`, for the server and the
+//! browser client to check their encodings against.
+//!
+//! ```text
+//! cargo run -p protobuf --bin vectors [-- --write]
+//! ```
+//!
+//! Without `--write`, it encodes the samples below and fails if any differs from its vector in
+//! the file, or if a packet type has no vector. With it, it rewrites the file from the samples,
+//! which is how a deliberate change to `packet.proto` is recorded. `cargo test` runs the same
+//! check, and the client's side is `pnpm verify-vectors` in this directory.
+
+use std::path::Path;
+use std::process::ExitCode;
+
+use prost::Message;
+use protobuf::system;
+use protobuf::system::PacketType;
+use serde_json::Value;
+use serde_json::json;
+
+const PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/vectors.json");
+
+/// A packet's payload: its message encoded, without the packet type byte in front of it.
+struct Vector {
+    name: &'static str,
+    packet_type: Option<PacketType>,
+
+    /// The message's full name, or `None` for payloads that are not messages.
+    message: Option<&'static str>,
+    payload: Vec<u8>,
+}
+
+impl Vector {
+    fn new(packet_type: PacketType, message: &'static str, value: impl Message) -> Self {
+        Self {
+            name: packet_type.as_str_name(),
+            packet_type: Some(packet_type),
+            message: Some(message),
+            payload: value.encode_to_vec(),
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "name": self.name,
+            "packet_type": self.packet_type.map(|packet_type| packet_type as i32),
+            "message": self.message,
+            "payload": hex(&self.payload),
+        })
+    }
+}
+
+/// A sample of every packet, with every field set so that each one is on the wire.
+fn samples() -> Vec<Vector> {
+    let user = system::RoomUser {
+        session_id: -4_611_686_018_427_387_904,
+        username: "alice".to_owned(),
+        avatar_url: "/avatars/alice".to_owned(),
+    };
+    vec![
+        Vector::new(
+            PacketType::AuthRequest,
+            "system.AuthRequest",
+            system::AuthRequest {
+                username: "alice".to_owned(),
+                token: "hunter2".to_owned(),
+                tenant: "acme".to_owned(),
+                resume_token: String::new(),
+                session_token: String::new(),
+            },
+        ),
+        Vector::new(
+            PacketType::AuthResponseSuccess,
+            "system.AuthResponseSuccess",
+            system::AuthResponseSuccess {
+                session_id: 1_234_567_890_123,
+                blocked: vec!["mallory".to_owned()],
+            },
+        ),
+        Vector::new(
+            PacketType::AuthResponseError,
+            "system.AuthResponseError",
+            system::AuthResponseError {
+                r#type: system::auth_response_error::Type::UnknownTenant.into(),
+            },
+        ),
+        Vector::new(
+            PacketType::JoinRoomRequest,
+            "system.JoinRoomRequest",
+            system::JoinRoomRequest {
+                room_key: "lobby".to_owned(),
+            },
+        ),
+        Vector::new(
+            PacketType::JoinRoomResponse,
+            "system.JoinRoomResponse",
+            system::JoinRoomResponse {
+                users: vec![
+                    user.clone(),
+                    system::RoomUser {
+                        session_id: 7,
+                        username: "bob".to_owned(),
+                        avatar_url: String::new(),
+                    },
+                ],
+                room_key: "lobby".to_owned(),
+                room_id: 0x8f6e_1a2b,
+            },
+        ),
+        Vector::new(PacketType::UserJoined, "system.RoomUser", user),
+        // USER_LEFT carries the session ID as a big-endian i64 rather than a message.
+        Vector {
+            name: PacketType::UserLeft.as_str_name(),
+            packet_type: Some(PacketType::UserLeft),
+            message: None,
+            payload: 1_234_567_890_123i64.to_be_bytes().to_vec(),
+        },
+        Vector::new(
+            PacketType::Hello,
+            "system.Hello",
+            system::Hello {
+                protocol_version: 3,
+                min_protocol_version: 1,
+                features: 63,
+            },
+        ),
+        Vector::new(
+            PacketType::HelloAck,
+            "system.HelloAck",
+            system::HelloAck {
+                protocol_version: 1,
+                features: 17,
+                max_datagram_size: 1200,
+                signaling_token: "q5mV0f2Lh8Xw3sYb".to_owned(),
+            },
+        ),
+        Vector::new(
+            PacketType::HelloError,
+            "system.HelloError",
+            system::HelloError {
+                r#type: system::hello_error::Type::HandshakeRequired.into(),
+                min_protocol_version: 1,
+                max_protocol_version: 2,
+            },
+        ),
+        Vector::new(
+            PacketType::Error,
+            "system.Error",
+            system::Error {
+                code: system::error::Code::RoomFull.into(),
+                detail: "room is full".to_owned(),
+                packet_type: Some(PacketType::JoinRoomRequest.into()),
+                retry_after_ms: 0,
+            },
+        ),
+        Vector::new(
+            PacketType::Fragment,
+            "system.Fragment",
+            system::Fragment {
+                message_id: 9,
+                index: 1,
+                count: 3,
+                data: vec![0x00, 0x7f, 0x80, 0xff],
+            },
+        ),
+        Vector::new(
+            PacketType::ClockSync,
+            "system.ClockSync",
+            system::ClockSync {
+                server_send_time_us: 1_700_000_000_000_000,
+                client_receive_time_us: 1_700_000_000_012_345,
+                client_send_time_us: 1_700_000_000_012_400,
+            },
+        ),
+        Vector::new(
+            PacketType::PlayoutReport,
+            "system.PlayoutReport",
+            system::PlayoutReport {
+                playout_delay_us: 60_000,
+            },
+        ),
+        Vector::new(
+            PacketType::SessionStats,
+            "system.SessionStats",
+            system::SessionStats {
+                loss: 0.125,
+                jitter_us: 4_500,
+                rtt_us: 38_000,
+                forwarded_speakers: 2,
+                audio_on_streams: true,
+            },
+        ),
+        Vector::new(
+            PacketType::JoinRoomRedirect,
+            "system.JoinRoomRedirect",
+            system::JoinRoomRedirect {
+                room_key: "lobby".to_owned(),
+                address: "https://b.example.org:4433/".to_owned(),
+                resume_token: "5f0c9e2a7b3d41e8a6c2f9d04b7e1a3c".to_owned(),
+            },
+        ),
+        Vector::new(
+            PacketType::ChatMessage,
+            "system.ChatMessage",
+            system::ChatMessage {
+                session_id: 7,
+                username: "bob".to_owned(),
+                text: "héllo 👋".to_owned(),
+                message_id: 42,
+                attachment: Some(system::FileAttachment {
+                    name: "notes.txt".to_owned(),
+                    content_type: "text/plain".to_owned(),
+                    size: 1_048_576,
+                    url: "/files/42/notes.txt".to_owned(),
+                }),
+                sent_at_ms: 1_767_225_600_000,
+                history: true,
+                client_message_id: 9,
+                previous_message_id: 41,
+            },
+        ),
+        Vector::new(
+            PacketType::Reaction,
+            "system.Reaction",
+            system::Reaction {
+                session_id: 7,
+                emoji: "👍".to_owned(),
+                message_id: 42,
+            },
+        ),
+        // FILE_UPLOAD and MEDIA_TRACK name the headers of file and media streams.
+        Vector::new(
+            PacketType::FileUpload,
+            "system.FileUpload",
+            system::FileUpload {
+                name: "notes.txt".to_owned(),
+                content_type: "text/plain".to_owned(),
+                size: 1_048_576,
+            },
+        ),
+        Vector::new(
+            PacketType::MediaTrack,
+            "system.MediaTrack",
+            system::MediaTrack {
+                session_id: 7,
+                track: 2,
+                kind: "video".to_owned(),
+            },
+        ),
+        Vector::new(
+            PacketType::ForwardingLimit,
+            "system.ForwardingLimit",
+            system::ForwardingLimit {
+                max_speakers: 3,
+                detail: "your connection is falling behind".to_owned(),
+            },
+        ),
+        Vector::new(
+            PacketType::ChatAck,
+            "system.ChatAck",
+            system::ChatAck {
+                client_message_id: 9,
+                message_id: 42,
+                previous_message_id: 41,
+                sent_at_ms: 1_767_225_600_000,
+            },
+        ),
+        Vector::new(
+            PacketType::ChatBacklogRequest,
+            "system.ChatBacklogRequest",
+            system::ChatBacklogRequest {
+                after_message_id: 41,
+            },
+        ),
+        Vector::new(
+            PacketType::DirectMessage,
+            "system.DirectMessage",
+            system::DirectMessage {
+                session_id: 7,
+                username: "bob".to_owned(),
+                to_session_id: -4_611_686_018_427_387_904,
+                text: "psst 🤫".to_owned(),
+                sent_at_ms: 1_767_225_600_000,
+                client_message_id: 9,
+            },
+        ),
+        Vector::new(
+            PacketType::BlockList,
+            "system.BlockList",
+            system::BlockList {
+                usernames: vec!["mallory".to_owned(), "trudy".to_owned()],
+            },
+        ),
+        Vector::new(
+            PacketType::LobbyWaiting,
+            "system.LobbyWaiting",
+            system::LobbyWaiting {
+                room_key: "standup".to_owned(),
+            },
+        ),
+        Vector::new(
+            PacketType::LobbyPending,
+            "system.LobbyPending",
+            system::LobbyPending {
+                session_id: 7,
+                username: "bob".to_owned(),
+                waiting: true,
+            },
+        ),
+        Vector::new(
+            PacketType::LobbyDecision,
+            "system.LobbyDecision",
+            system::LobbyDecision {
+                session_id: 7,
+                admit: true,
+            },
+        ),
+        Vector::new(
+            PacketType::SlowMode,
+            "system.SlowMode",
+            system::SlowMode { interval_secs: 30 },
+        ),
+        Vector::new(
+            PacketType::Announcement,
+            "system.Announcement",
+            system::Announcement {
+                text: "Maintenance at 22:00 UTC".to_owned(),
+                sent_at_ms: 1_700_000_000_000,
+            },
+        ),
+        Vector::new(
+            PacketType::Maintenance,
+            "system.Maintenance",
+            system::Maintenance {
+                starts_at: 1_700_000_000,
+                secs_left: 300,
+                reason: "Upgrading to 2.0".to_owned(),
+            },
+        ),
+        // COMPRESSED carries a whole packet deflated rather than a message; this one inflates to
+        // the USER_LEFT packet above.
+        Vector {
+            name: PacketType::Compressed.as_str_name(),
+            packet_type: Some(PacketType::Compressed),
+            message: None,
+            payload: vec![
+                0x63, 0x63, 0x60, 0x60, 0x94, 0x2f, 0xfc, 0xcd, 0x72, 0x1a, 0x00,
+            ],
+        },
+        Vector::new(PacketType::Ping, "system.Ping", system::Ping { id: 12 }),
+        Vector::new(PacketType::Pong, "system.Ping", system::Ping { id: 12 }),
+        Vector {
+            name: "MEDIA_FRAME",
+            packet_type: None,
+            message: Some("system.MediaFrame"),
+            payload: system::MediaFrame {
+                sequence: 300,
+                timestamp_us: 1_700_000_000_000_000,
+                keyframe: true,
+                payload: vec![0x00, 0x00, 0x01, 0x65],
+            }
+            .encode_to_vec(),
+        },
+    ]
+}
+
+fn main() -> ExitCode {
+    let samples = samples();
+    let write = std::env::args().skip(1).any(|arg| arg == "--write");
+    if write {
+        let vectors: Vec<Value> = samples.iter().map(Vector::to_json).collect();
+        let file = serde_json::to_string_pretty(&json!({ "vectors": vectors })).unwrap() + "\n";
+        if let Err(error) = std::fs::write(PATH, file) {
+            eprintln!("Cannot write {PATH}: {error}");
+            return ExitCode::FAILURE;
+        }
+        println!("Wrote {} vectors to {PATH}", samples.len());
+        return ExitCode::SUCCESS;
+    }
+
+    let failures = match check(&samples) {
+        Ok(failures) => failures,
+        Err(error) => {
+            eprintln!("{error}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if failures.is_empty() {
+        println!("All {} vectors match", samples.len());
+        return ExitCode::SUCCESS;
+    }
+    for failure in &failures {
+        eprintln!("{failure}");
+    }
+    eprintln!("If packet.proto was changed on purpose, rewrite the vectors with --write.");
+    ExitCode::FAILURE
+}
+
+/// How `samples` differ from the vectors in the file, or why the file cannot be read.
+fn check(samples: &[Vector]) -> Result<Vec<String>, String> {
+    let file = std::fs::read_to_string(Path::new(PATH))
+        .map_err(|error| format!("Cannot read {PATH}: {error}"))?;
+    let golden: Vec<Value> = match serde_json::from_str::<Value>(&file) {
+        Ok(Value::Object(mut file)) => match file.remove("vectors") {
+            Some(Value::Array(vectors)) => vectors,
+            _ => Vec::new(),
+        },
+        _ => return Err(format!("{PATH} is malformed")),
+    };
+
+    let mut failures = Vec::new();
+    for sample in samples {
+        let found = golden.iter().find(|vector| vector["name"] == sample.name);
+        match found {
+            None => failures.push(format!("{}: no vector", sample.name)),
+            Some(vector) if *vector != sample.to_json() => failures.push(format!(
+                "{}: encodes as {}, but the vector is {}",
+                sample.name,
+                sample.to_json(),
+                vector
+            )),
+            Some(_) => {}
+        }
+    }
+    for vector in &golden {
+        if !samples.iter().any(|sample| vector["name"] == sample.name) {
+            failures.push(format!("{}: no sample", vector["name"]));
+        }
+    }
+    // A packet type added to packet.proto needs a sample before the vectors pass.
+    for value in 0.. {
+        let Ok(packet_type) = PacketType::try_from(value) else {
+            break;
+        };
+        if !samples
+            .iter()
+            .any(|sample| sample.packet_type == Some(packet_type))
+        {
+            failures.push(format!("{}: no sample", packet_type.as_str_name()));
+        }
+    }
+    Ok(failures)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn vectors_match() {
+        let failures = super::check(&super::samples()).unwrap();
+        assert!(
+            failures.is_empty(),
+            "{}\nIf packet.proto was changed on purpose, rewrite the vectors with \
+             `cargo run -p protobuf --bin vectors -- --write`.",
+            failures.join("\n")
+        );
+    }
+}
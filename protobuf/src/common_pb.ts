@@ -10,7 +10,7 @@ import type { Message } from "@bufbuild/protobuf";
  * Describes the file common.proto.
  */
 export const file_common: GenFile = /*@__PURE__*/
-  fileDesc("Cgxjb21tb24ucHJvdG8SBnN5c3RlbSIwCghSb29tVXNlchISCgpzZXNzaW9uX2lkGAEgASgDEhAKCHVzZXJuYW1lGAIgASgJYgZwcm90bzM");
+  fileDesc("Cgxjb21tb24ucHJvdG8SBnN5c3RlbSJECghSb29tVXNlchISCgpzZXNzaW9uX2lkGAEgASgDEhAKCHVzZXJuYW1lGAIgASgJEhIKCmF2YXRhcl91cmwYAyABKAliBnByb3RvMw");
 
 /**
  * @generated from message system.RoomUser
@@ -29,6 +29,13 @@ export type RoomUser = Message<"system.RoomUser"> & {
    * @generated from field: string username = 2;
    */
   username: string;
+
+  /**
+   * Where the user's avatar can be fetched over HTTP, if they uploaded one.
+   *
+   * @generated from field: string avatar_url = 3;
+   */
+  avatarUrl: string;
 };
 
 /**
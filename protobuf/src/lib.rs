@@ -1,3 +1,11 @@
 pub mod system {
     include!(concat!(env!("OUT_DIR"), "/system.rs"));
 }
+
+pub mod admin {
+    include!(concat!(env!("OUT_DIR"), "/admin.rs"));
+}
+
+pub mod events {
+    include!(concat!(env!("OUT_DIR"), "/events.rs"));
+}
@@ -12,7 +12,140 @@ import type { Message } from "@bufbuild/protobuf";
  * Describes the file packet.proto.
  */
 export const file_packet: GenFile = /*@__PURE__*/
-  fileDesc("CgxwYWNrZXQucHJvdG8SBnN5c3RlbSIuCgtBdXRoUmVxdWVzdBIQCgh1c2VybmFtZRgBIAEoCRINCgV0b2tlbhgCIAEoCSIpChNBdXRoUmVzcG9uc2VTdWNjZXNzEhIKCnNlc3Npb25faWQYASABKAMieQoRQXV0aFJlc3BvbnNlRXJyb3ISLAoEdHlwZRgBIAEoDjIeLnN5c3RlbS5BdXRoUmVzcG9uc2VFcnJvci5UeXBlIjYKBFR5cGUSFwoTSU5WQUxJRF9DUkVERU5USUFMUxAAEhUKEUFMUkVBRFlfTE9HR0VEX0lOEAEiIwoPSm9pblJvb21SZXF1ZXN0EhAKCHJvb21fa2V5GAEgASgJIjMKEEpvaW5Sb29tUmVzcG9uc2USHwoFdXNlcnMYASADKAsyEC5zeXN0ZW0uUm9vbVVzZXIqoQEKClBhY2tldFR5cGUSEAoMQVVUSF9SRVFVRVNUEAASGQoVQVVUSF9SRVNQT05TRV9TVUNDRVNTEAESFwoTQVVUSF9SRVNQT05TRV9FUlJPUhACEhUKEUpPSU5fUk9PTV9SRVFVRVNUEAMSFgoSSk9JTl9ST09NX1JFU1BPTlNFEAQSDwoLVVNFUl9KT0lORUQQBRINCglVU0VSX0xFRlQQBmIGcHJvdG8z", [file_common]);
+  fileDesc("CgxwYWNrZXQucHJvdG8SBnN5c3RlbSJRCgVIZWxsbxIYChBwcm90b2NvbF92ZXJzaW9uGAEgASgNEhwKFG1pbl9wcm90b2NvbF92ZXJzaW9uGAIgASgNEhAKCGZlYXR1cmVzGAMgASgNImoKCEhlbGxvQWNrEhgKEHByb3RvY29sX3ZlcnNpb24YASABKA0SEAoIZmVhdHVyZXMYAiABKA0SGQoRbWF4X2RhdGFncmFtX3NpemUYAyABKA0SFwoPc2lnbmFsaW5nX3Rva2VuGAQgASgJIqgBCgpIZWxsb0Vycm9yEiUKBHR5cGUYASABKA4yFy5zeXN0ZW0uSGVsbG9FcnJvci5UeXBlEhwKFG1pbl9wcm90b2NvbF92ZXJzaW9uGAIgASgNEhwKFG1heF9wcm90b2NvbF92ZXJzaW9uGAMgASgNIjcKBFR5cGUSFwoTVU5TVVBQT1JURURfVkVSU0lPThAAEhYKEkhBTkRTSEFLRV9SRVFVSVJFRBABImsKC0F1dGhSZXF1ZXN0EhAKCHVzZXJuYW1lGAEgASgJEg0KBXRva2VuGAIgASgJEg4KBnRlbmFudBgDIAEoCRIUCgxyZXN1bWVfdG9rZW4YBCABKAkSFQoNc2Vzc2lvbl90b2tlbhgFIAEoCSI6ChNBdXRoUmVzcG9uc2VTdWNjZXNzEhIKCnNlc3Npb25faWQYASABKAMSDwoHYmxvY2tlZBgCIAMoCSKNAQoRQXV0aFJlc3BvbnNlRXJyb3ISLAoEdHlwZRgBIAEoDjIeLnN5c3RlbS5BdXRoUmVzcG9uc2VFcnJvci5UeXBlIkoKBFR5cGUSFwoTSU5WQUxJRF9DUkVERU5USUFMUxAAEhUKEUFMUkVBRFlfTE9HR0VEX0lOEAESEgoOVU5LTk9XTl9URU5BTlQQAiIjCg9Kb2luUm9vbVJlcXVlc3QSEAoIcm9vbV9rZXkYASABKAkiVgoQSm9pblJvb21SZXNwb25zZRIfCgV1c2VycxgBIAMoCzIQLnN5c3RlbS5Sb29tVXNlchIQCghyb29tX2tleRgCIAEoCRIPCgdyb29tX2lkGAMgASgNIksKEEpvaW5Sb29tUmVkaXJlY3QSEAoIcm9vbV9rZXkYASABKAkSDwoHYWRkcmVzcxgCIAEoCRIUCgxyZXN1bWVfdG9rZW4YAyABKAki3gEKC0NoYXRNZXNzYWdlEhIKCnNlc3Npb25faWQYASABKAMSEAoIdXNlcm5hbWUYAiABKAkSDAoEdGV4dBgDIAEoCRISCgptZXNzYWdlX2lkGAQgASgEEioKCmF0dGFjaG1lbnQYBSABKAsyFi5zeXN0ZW0uRmlsZUF0dGFjaG1lbnQSEgoKc2VudF9hdF9tcxgGIAEoBBIPCgdoaXN0b3J5GAcgASgIEhkKEWNsaWVudF9tZXNzYWdlX2lkGAggASgEEhsKE3ByZXZpb3VzX21lc3NhZ2VfaWQYCSABKAQiaQoHQ2hhdEFjaxIZChFjbGllbnRfbWVzc2FnZV9pZBgBIAEoBBISCgptZXNzYWdlX2lkGAIgASgEEhsKE3ByZXZpb3VzX21lc3NhZ2VfaWQYAyABKAQSEgoKc2VudF9hdF9tcxgEIAEoBCIuChJDaGF0QmFja2xvZ1JlcXVlc3QSGAoQYWZ0ZXJfbWVzc2FnZV9pZBgBIAEoBCKJAQoNRGlyZWN0TWVzc2FnZRISCgpzZXNzaW9uX2lkGAEgASgDEhAKCHVzZXJuYW1lGAIgASgJEhUKDXRvX3Nlc3Npb25faWQYAyABKAMSDAoEdGV4dBgEIAEoCRISCgpzZW50X2F0X21zGAUgASgEEhkKEWNsaWVudF9tZXNzYWdlX2lkGAYgASgEIh4KCUJsb2NrTGlzdBIRCgl1c2VybmFtZXMYASADKAkiIAoMTG9iYnlXYWl0aW5nEhAKCHJvb21fa2V5GAEgASgJIkUKDExvYmJ5UGVuZGluZxISCgpzZXNzaW9uX2lkGAEgASgDEhAKCHVzZXJuYW1lGAIgASgJEg8KB3dhaXRpbmcYAyABKAgiMgoNTG9iYnlEZWNpc2lvbhISCgpzZXNzaW9uX2lkGAEgASgDEg0KBWFkbWl0GAIgASgIIiEKCFNsb3dNb2RlEhUKDWludGVydmFsX3NlY3MYASABKA0iMAoMQW5ub3VuY2VtZW50EgwKBHRleHQYASABKAkSEgoKc2VudF9hdF9tcxgCIAEoBCJDCgtNYWludGVuYW5jZRIRCglzdGFydHNfYXQYASABKAQSEQoJc2Vjc19sZWZ0GAIgASgNEg4KBnJlYXNvbhgDIAEoCSI+CgpGaWxlVXBsb2FkEgwKBG5hbWUYASABKAkSFAoMY29udGVudF90eXBlGAIgASgJEgwKBHNpemUYAyABKAQiPQoKTWVkaWFUcmFjaxISCgpzZXNzaW9uX2lkGAEgASgDEg0KBXRyYWNrGAIgASgNEgwKBGtpbmQYAyABKAkiVwoKTWVkaWFGcmFtZRIQCghzZXF1ZW5jZRgBIAEoBBIUCgx0aW1lc3RhbXBfdXMYAiABKAQSEAoIa2V5ZnJhbWUYAyABKAgSDwoHcGF5bG9hZBgEIAEoDCJPCg5GaWxlQXR0YWNobWVudBIMCgRuYW1lGAEgASgJEhQKDGNvbnRlbnRfdHlwZRgCIAEoCRIMCgRzaXplGAMgASgEEgsKA3VybBgEIAEoCSJBCghSZWFjdGlvbhISCgpzZXNzaW9uX2lkGAEgASgDEg0KBWVtb2ppGAIgASgJEhIKCm1lc3NhZ2VfaWQYAyABKAQikwMKBUVycm9yEiAKBGNvZGUYASABKA4yEi5zeXN0ZW0uRXJyb3IuQ29kZRIOCgZkZXRhaWwYAiABKAkSLAoLcGFja2V0X3R5cGUYAyABKA4yEi5zeXN0ZW0uUGFja2V0VHlwZUgAiAEBEhYKDnJldHJ5X2FmdGVyX21zGAQgASgNIoECCgRDb2RlEgsKB1VOS05PV04QABIUChBNQUxGT1JNRURfUEFDS0VUEAESFQoRVU5FWFBFQ1RFRF9QQUNLRVQQAhIVChFOT1RfQVVUSEVOVElDQVRFRBADEhAKDElOVkFMSURfUk9PTRAEEhMKD0FMUkVBRFlfSU5fUk9PTRAFEhAKDFJBVEVfTElNSVRFRBAGEhUKEVBFUk1JU1NJT05fREVOSUVEEAcSEAoMSURMRV9USU1FT1VUEAgSDQoJUk9PTV9GVUxMEAkSFAoQQklUUkFURV9FWENFRURFRBAKEhAKDFVTRVJfT0ZGTElORRALEg8KC01BSU5URU5BTkNFEAxCDgoMX3BhY2tldF90eXBlIkoKCEZyYWdtZW50EhIKCm1lc3NhZ2VfaWQYASABKA0SDQoFaW5kZXgYAiABKA0SDQoFY291bnQYAyABKA0SDAoEZGF0YRgEIAEoDCJlCglDbG9ja1N5bmMSGwoTc2VydmVyX3NlbmRfdGltZV91cxgBIAEoBBIeChZjbGllbnRfcmVjZWl2ZV90aW1lX3VzGAIgASgEEhsKE2NsaWVudF9zZW5kX3RpbWVfdXMYAyABKAQiKQoNUGxheW91dFJlcG9ydBIYChBwbGF5b3V0X2RlbGF5X3VzGAEgASgNInUKDFNlc3Npb25TdGF0cxIMCgRsb3NzGAEgASgCEhEKCWppdHRlcl91cxgCIAEoDRIOCgZydHRfdXMYAyABKA0SGgoSZm9yd2FyZGVkX3NwZWFrZXJzGAQgASgNEhgKEGF1ZGlvX29uX3N0cmVhbXMYBSABKAgiNwoPRm9yd2FyZGluZ0xpbWl0EhQKDG1heF9zcGVha2VycxgBIAEoDRIOCgZkZXRhaWwYAiABKAkiEgoEUGluZxIKCgJpZBgBIAEoDSroBAoKUGFja2V0VHlwZRIQCgxBVVRIX1JFUVVFU1QQABIZChVBVVRIX1JFU1BPTlNFX1NVQ0NFU1MQARIXChNBVVRIX1JFU1BPTlNFX0VSUk9SEAISFQoRSk9JTl9ST09NX1JFUVVFU1QQAxIWChJKT0lOX1JPT01fUkVTUE9OU0UQBBIPCgtVU0VSX0pPSU5FRBAFEg0KCVVTRVJfTEVGVBAGEgkKBUhFTExPEAcSDQoJSEVMTE9fQUNLEAgSDwoLSEVMTE9fRVJST1IQCRIJCgVFUlJPUhAKEgwKCEZSQUdNRU5UEAsSDgoKQ0xPQ0tfU1lOQxAMEhIKDlBMQVlPVVRfUkVQT1JUEA0SEQoNU0VTU0lPTl9TVEFUUxAOEhYKEkpPSU5fUk9PTV9SRURJUkVDVBAPEhAKDENIQVRfTUVTU0FHRRAQEgwKCFJFQUNUSU9OEBESDwoLRklMRV9VUExPQUQQEhIPCgtNRURJQV9UUkFDSxATEhQKEEZPUldBUkRJTkdfTElNSVQQFBIICgRQSU5HEBUSCAoEUE9ORxAWEgwKCENIQVRfQUNLEBcSGAoUQ0hBVF9CQUNLTE9HX1JFUVVFU1QQGBISCg5ESVJFQ1RfTUVTU0FHRRAZEg4KCkJMT0NLX0xJU1QQGhIRCg1MT0JCWV9XQUlUSU5HEBsSEQoNTE9CQllfUEVORElORxAcEhIKDkxPQkJZX0RFQ0lTSU9OEB0SDQoJU0xPV19NT0RFEB4SEAoMQU5OT1VOQ0VNRU5UEB8SDwoLTUFJTlRFTkFOQ0UQIBIOCgpDT01QUkVTU0VEECEq9QEKCUNsb3NlQ29kZRIVChFDTE9TRV9DT0RFX05PUk1BTBAAEhUKEUNMT1NFX0NPREVfS0lDS0VEEAESFQoRQ0xPU0VfQ09ERV9CQU5ORUQQAhIeChpDTE9TRV9DT0RFX1NFUlZFUl9TSFVURE9XThADEiEKHUNMT1NFX0NPREVfUFJPVE9DT0xfVklPTEFUSU9OEAQSGwoXQ0xPU0VfQ09ERV9JRExFX1RJTUVPVVQQBRIiCh5DTE9TRV9DT0RFX1VOU1VQUE9SVEVEX1ZFUlNJT04QBhIfChtDTE9TRV9DT0RFX0JJVFJBVEVfRVhDRUVERUQQByrkAwoHRmVhdHVyZRIQCgxGRUFUVVJFX05PTkUQABIbChdGRUFUVVJFX1ZPSUNFX0RBVEFHUkFNUxABEhkKFUZFQVRVUkVfRlJBR01FTlRBVElPThACEhsKF0ZFQVRVUkVfTEFURU5DWV9SRVBPUlRTEAQSGQoVRkVBVFVSRV9TRVNTSU9OX1NUQVRTEAgSFQoRRkVBVFVSRV9SRURJUkVDVFMQEBIZChVGRUFUVVJFX01FRElBX1NUUkVBTVMQIBIdChlGRUFUVVJFX0ZPUldBUkRJTkdfTElNSVRTEEASEgoNRkVBVFVSRV9QSU5HUxCAARIXChJGRUFUVVJFX1JPT01fTU9WRVMQgAISFgoRRkVBVFVSRV9NSUdSQVRJT04QgAQSGQoURkVBVFVSRV9DSEFUX0hJU1RPUlkQgAgSGgoVRkVBVFVSRV9DSEFUX1JFQ0VJUFRTEIAQEhwKF0ZFQVRVUkVfRElSRUNUX01FU1NBR0VTEIAgEhIKDUZFQVRVUkVfTE9CQlkQgEASGgoURkVBVFVSRV9BVURJT19IRUFERVIQgIABEhkKE0ZFQVRVUkVfQ09NUFJFU1NJT04QgIACEiEKG0ZFQVRVUkVfV0VCU09DS0VUX1NJR05BTElORxCAgARiBnByb3RvMw", [file_common]);
+
+/**
+ * @generated from message system.Hello
+ */
+export type Hello = Message<"system.Hello"> & {
+  /**
+   * The newest protocol version the client speaks.
+   *
+   * @generated from field: uint32 protocol_version = 1;
+   */
+  protocolVersion: number;
+
+  /**
+   * The oldest protocol version the client still speaks.
+   *
+   * @generated from field: uint32 min_protocol_version = 2;
+   */
+  minProtocolVersion: number;
+
+  /**
+   * Bitmask of `Feature` values the client supports.
+   *
+   * @generated from field: uint32 features = 3;
+   */
+  features: number;
+};
+
+/**
+ * Describes the message system.Hello.
+ * Use `create(HelloSchema)` to create a new message.
+ */
+export const HelloSchema: GenMessage<Hello> = /*@__PURE__*/
+  messageDesc(file_packet, 0);
+
+/**
+ * @generated from message system.HelloAck
+ */
+export type HelloAck = Message<"system.HelloAck"> & {
+  /**
+   * The protocol version both sides will use for the rest of the session.
+   *
+   * @generated from field: uint32 protocol_version = 1;
+   */
+  protocolVersion: number;
+
+  /**
+   * Bitmask of `Feature` values enabled for this session.
+   *
+   * @generated from field: uint32 features = 2;
+   */
+  features: number;
+
+  /**
+   * The largest datagram the server can currently send to the client, in bytes. Control
+   * packets larger than this arrive as FRAGMENT packets.
+   *
+   * @generated from field: uint32 max_datagram_size = 3;
+   */
+  maxDatagramSize: number;
+
+  /**
+   * The single-use token that attaches a WebSocket to this session, if the client negotiated
+   * FEATURE_WEBSOCKET_SIGNALING. It is only good while the session lasts.
+   *
+   * @generated from field: string signaling_token = 4;
+   */
+  signalingToken: string;
+};
+
+/**
+ * Describes the message system.HelloAck.
+ * Use `create(HelloAckSchema)` to create a new message.
+ */
+export const HelloAckSchema: GenMessage<HelloAck> = /*@__PURE__*/
+  messageDesc(file_packet, 1);
+
+/**
+ * @generated from message system.HelloError
+ */
+export type HelloError = Message<"system.HelloError"> & {
+  /**
+   * The error type.
+   *
+   * @generated from field: system.HelloError.Type type = 1;
+   */
+  type: HelloError_Type;
+
+  /**
+   * The oldest protocol version the server speaks.
+   *
+   * @generated from field: uint32 min_protocol_version = 2;
+   */
+  minProtocolVersion: number;
+
+  /**
+   * The newest protocol version the server speaks.
+   *
+   * @generated from field: uint32 max_protocol_version = 3;
+   */
+  maxProtocolVersion: number;
+};
+
+/**
+ * Describes the message system.HelloError.
+ * Use `create(HelloErrorSchema)` to create a new message.
+ */
+export const HelloErrorSchema: GenMessage<HelloError> = /*@__PURE__*/
+  messageDesc(file_packet, 2);
+
+/**
+ * @generated from enum system.HelloError.Type
+ */
+export enum HelloError_Type {
+  /**
+   * None of the client's protocol versions are supported by the server.
+   *
+   * @generated from enum value: UNSUPPORTED_VERSION = 0;
+   */
+  UNSUPPORTED_VERSION = 0,
+
+  /**
+   * The client sent another packet before completing the handshake.
+   *
+   * @generated from enum value: HANDSHAKE_REQUIRED = 1;
+   */
+  HANDSHAKE_REQUIRED = 1,
+}
+
+/**
+ * Describes the enum system.HelloError.Type.
+ */
+export const HelloError_TypeSchema: GenEnum<HelloError_Type> = /*@__PURE__*/
+  enumDesc(file_packet, 2, 0);
 
 /**
  * @generated from message system.AuthRequest
@@ -26,11 +159,37 @@ export type AuthRequest = Message<"system.AuthRequest"> & {
   username: string;
 
   /**
-   * Unused for now.
+   * The account password, on servers with user accounts.
    *
    * @generated from field: string token = 2;
    */
   token: string;
+
+  /**
+   * The ID of the tenant to log in to, on servers hosting several communities. Usernames,
+   * accounts and rooms are all the tenant's own.
+   *
+   * @generated from field: string tenant = 3;
+   */
+  tenant: string;
+
+  /**
+   * A JOIN_ROOM_REDIRECT's `resume_token`, to log in as the user handed over with it instead
+   * of with `username`, `token` and `tenant`. An unknown or expired one is rejected with
+   * INVALID_CREDENTIALS.
+   *
+   * @generated from field: string resume_token = 4;
+   */
+  resumeToken: string;
+
+  /**
+   * A token from the HTTP server's `POST /session`, to log in as the user it was issued to
+   * instead of with `username`, `token` and `tenant`. It works once, within a minute. An
+   * unknown or expired one is rejected with INVALID_CREDENTIALS.
+   *
+   * @generated from field: string session_token = 5;
+   */
+  sessionToken: string;
 };
 
 /**
@@ -38,7 +197,7 @@ export type AuthRequest = Message<"system.AuthRequest"> & {
  * Use `create(AuthRequestSchema)` to create a new message.
  */
 export const AuthRequestSchema: GenMessage<AuthRequest> = /*@__PURE__*/
-  messageDesc(file_packet, 0);
+  messageDesc(file_packet, 3);
 
 /**
  * @generated from message system.AuthResponseSuccess
@@ -50,6 +209,14 @@ export type AuthResponseSuccess = Message<"system.AuthResponseSuccess"> & {
    * @generated from field: int64 session_id = 1;
    */
   sessionId: bigint;
+
+  /**
+   * The users the account blocks, as last set with a BLOCK_LIST. Empty on servers without
+   * accounts.
+   *
+   * @generated from field: repeated string blocked = 2;
+   */
+  blocked: string[];
 };
 
 /**
@@ -57,7 +224,7 @@ export type AuthResponseSuccess = Message<"system.AuthResponseSuccess"> & {
  * Use `create(AuthResponseSuccessSchema)` to create a new message.
  */
 export const AuthResponseSuccessSchema: GenMessage<AuthResponseSuccess> = /*@__PURE__*/
-  messageDesc(file_packet, 1);
+  messageDesc(file_packet, 4);
 
 /**
  * @generated from message system.AuthResponseError
@@ -76,7 +243,7 @@ export type AuthResponseError = Message<"system.AuthResponseError"> & {
  * Use `create(AuthResponseErrorSchema)` to create a new message.
  */
 export const AuthResponseErrorSchema: GenMessage<AuthResponseError> = /*@__PURE__*/
-  messageDesc(file_packet, 2);
+  messageDesc(file_packet, 5);
 
 /**
  * @generated from enum system.AuthResponseError.Type
@@ -95,13 +262,20 @@ export enum AuthResponseError_Type {
    * @generated from enum value: ALREADY_LOGGED_IN = 1;
    */
   ALREADY_LOGGED_IN = 1,
+
+  /**
+   * The server hosts several tenants, and the request named none of them.
+   *
+   * @generated from enum value: UNKNOWN_TENANT = 2;
+   */
+  UNKNOWN_TENANT = 2,
 }
 
 /**
  * Describes the enum system.AuthResponseError.Type.
  */
 export const AuthResponseError_TypeSchema: GenEnum<AuthResponseError_Type> = /*@__PURE__*/
-  enumDesc(file_packet, 2, 0);
+  enumDesc(file_packet, 5, 0);
 
 /**
  * @generated from message system.JoinRoomRequest
@@ -118,7 +292,7 @@ export type JoinRoomRequest = Message<"system.JoinRoomRequest"> & {
  * Use `create(JoinRoomRequestSchema)` to create a new message.
  */
 export const JoinRoomRequestSchema: GenMessage<JoinRoomRequest> = /*@__PURE__*/
-  messageDesc(file_packet, 3);
+  messageDesc(file_packet, 6);
 
 /**
  * @generated from message system.JoinRoomResponse
@@ -128,6 +302,20 @@ export type JoinRoomResponse = Message<"system.JoinRoomResponse"> & {
    * @generated from field: repeated system.RoomUser users = 1;
    */
   users: RoomUser[];
+
+  /**
+   * The room joined, which is the requested one unless the server moved the client.
+   *
+   * @generated from field: string room_key = 2;
+   */
+  roomKey: string;
+
+  /**
+   * The room's ID in voice headers; see FEATURE_AUDIO_HEADER.
+   *
+   * @generated from field: uint32 room_id = 3;
+   */
+  roomId: number;
 };
 
 /**
@@ -135,51 +323,1405 @@ export type JoinRoomResponse = Message<"system.JoinRoomResponse"> & {
  * Use `create(JoinRoomResponseSchema)` to create a new message.
  */
 export const JoinRoomResponseSchema: GenMessage<JoinRoomResponse> = /*@__PURE__*/
-  messageDesc(file_packet, 4);
+  messageDesc(file_packet, 7);
 
 /**
- * @generated from enum system.PacketType
+ * Sent instead of JOIN_ROOM_RESPONSE, to clients that negotiated FEATURE_REDIRECTS, when the
+ * room is hosted by another server or this one is draining, and unasked to clients in the room
+ * that negotiated FEATURE_MIGRATION when it starts draining. The client should connect to that
+ * server and join again.
+ *
+ * @generated from message system.JoinRoomRedirect
  */
-export enum PacketType {
+export type JoinRoomRedirect = Message<"system.JoinRoomRedirect"> & {
   /**
-   * @generated from enum value: AUTH_REQUEST = 0;
+   * The requested room key.
+   *
+   * @generated from field: string room_key = 1;
    */
-  AUTH_REQUEST = 0,
+  roomKey: string;
 
   /**
-   * @generated from enum value: AUTH_RESPONSE_SUCCESS = 1;
+   * The HTTP address of the server hosting the room, whose `/config.json` describes how to
+   * connect to it.
+   *
+   * @generated from field: string address = 2;
    */
-  AUTH_RESPONSE_SUCCESS = 1,
+  address: string;
 
   /**
-   * @generated from enum value: AUTH_RESPONSE_ERROR = 2;
+   * Set when this server handed the session over to that one, for the client to log in there
+   * with instead of its credentials, within a minute. Joining the same room there restores
+   * whether the user was muted.
+   *
+   * @generated from field: string resume_token = 3;
    */
-  AUTH_RESPONSE_ERROR = 2,
+  resumeToken: string;
+};
 
+/**
+ * Describes the message system.JoinRoomRedirect.
+ * Use `create(JoinRoomRedirectSchema)` to create a new message.
+ */
+export const JoinRoomRedirectSchema: GenMessage<JoinRoomRedirect> = /*@__PURE__*/
+  messageDesc(file_packet, 8);
+
+/**
+ * A text message in a room. Clients send it with only `text` set, and the server delivers it to
+ * everyone in the room, the sender included, with the sender filled in.
+ *
+ * @generated from message system.ChatMessage
+ */
+export type ChatMessage = Message<"system.ChatMessage"> & {
   /**
-   * @generated from enum value: JOIN_ROOM_REQUEST = 3;
+   * The sender's session, or 0 for messages bridged from outside the server.
+   *
+   * @generated from field: int64 session_id = 1;
    */
-  JOIN_ROOM_REQUEST = 3,
+  sessionId: bigint;
 
   /**
-   * @generated from enum value: JOIN_ROOM_RESPONSE = 4;
+   * @generated from field: string username = 2;
    */
-  JOIN_ROOM_RESPONSE = 4,
+  username: string;
 
   /**
-   * @generated from enum value: USER_JOINED = 5;
+   * @generated from field: string text = 3;
    */
-  USER_JOINED = 5,
+  text: string;
 
   /**
-   * @generated from enum value: USER_LEFT = 6;
+   * Assigned by the server when it delivers the message, so reactions can refer to it.
+   *
+   * @generated from field: uint64 message_id = 4;
    */
-  USER_LEFT = 6,
-}
+  messageId: bigint;
+
+  /**
+   * A file shared with the message. Only the server sets it.
+   *
+   * @generated from field: system.FileAttachment attachment = 5;
+   */
+  attachment?: FileAttachment;
+
+  /**
+   * When the server delivered the message, in milliseconds since the Unix epoch. Only the
+   * server sets it.
+   *
+   * @generated from field: uint64 sent_at_ms = 6;
+   */
+  sentAtMs: bigint;
+
+  /**
+   * Whether the message is from the room's history, sent to a client that just joined, rather
+   * than new. Only the server sets it.
+   *
+   * @generated from field: bool history = 7;
+   */
+  history: boolean;
+
+  /**
+   * Chosen by a client that negotiated FEATURE_CHAT_RECEIPTS for a message it sends, to match
+   * the CHAT_ACK for it. The server does not pass it on.
+   *
+   * @generated from field: uint64 client_message_id = 8;
+   */
+  clientMessageId: bigint;
+
+  /**
+   * The ID of the message delivered in the room before this one, or 0 if the server knows of
+   * none. Every member of a room receives its messages in the order they were delivered, so a
+   * client that last saw another message in the room missed some, and can ask for them with a
+   * CHAT_BACKLOG_REQUEST. Only the server sets it.
+   *
+   * @generated from field: uint64 previous_message_id = 9;
+   */
+  previousMessageId: bigint;
+};
 
 /**
- * Describes the enum system.PacketType.
+ * Describes the message system.ChatMessage.
+ * Use `create(ChatMessageSchema)` to create a new message.
  */
-export const PacketTypeSchema: GenEnum<PacketType> = /*@__PURE__*/
-  enumDesc(file_packet, 0);
+export const ChatMessageSchema: GenMessage<ChatMessage> = /*@__PURE__*/
+  messageDesc(file_packet, 9);
+
+/**
+ * Sent to a client that negotiated FEATURE_CHAT_RECEIPTS once a chat message it sent was
+ * delivered to its room. A message that is refused gets an ERROR with the CHAT_MESSAGE packet
+ * type instead.
+ *
+ * @generated from message system.ChatAck
+ */
+export type ChatAck = Message<"system.ChatAck"> & {
+  /**
+   * The `client_message_id` the client sent the message with.
+   *
+   * @generated from field: uint64 client_message_id = 1;
+   */
+  clientMessageId: bigint;
+
+  /**
+   * The ID the server delivered the message with.
+   *
+   * @generated from field: uint64 message_id = 2;
+   */
+  messageId: bigint;
+
+  /**
+   * `previous_message_id` of the delivered message.
+   *
+   * @generated from field: uint64 previous_message_id = 3;
+   */
+  previousMessageId: bigint;
+
+  /**
+   * `sent_at_ms` of the delivered message.
+   *
+   * @generated from field: uint64 sent_at_ms = 4;
+   */
+  sentAtMs: bigint;
+};
+
+/**
+ * Describes the message system.ChatAck.
+ * Use `create(ChatAckSchema)` to create a new message.
+ */
+export const ChatAckSchema: GenMessage<ChatAck> = /*@__PURE__*/
+  messageDesc(file_packet, 10);
+
+/**
+ * Sent by a client in a room that negotiated FEATURE_CHAT_RECEIPTS, such as after it
+ * reconnected, for the room's messages delivered after the last one it saw. The server sends
+ * those it still keeps in the room's history as CHAT_MESSAGE packets with `history` set, oldest
+ * first. If the first one's `previous_message_id` is not `after_message_id`, older messages are
+ * no longer kept.
+ *
+ * @generated from message system.ChatBacklogRequest
+ */
+export type ChatBacklogRequest = Message<"system.ChatBacklogRequest"> & {
+  /**
+   * @generated from field: uint64 after_message_id = 1;
+   */
+  afterMessageId: bigint;
+};
+
+/**
+ * Describes the message system.ChatBacklogRequest.
+ * Use `create(ChatBacklogRequestSchema)` to create a new message.
+ */
+export const ChatBacklogRequestSchema: GenMessage<ChatBacklogRequest> = /*@__PURE__*/
+  messageDesc(file_packet, 11);
+
+/**
+ * A chat message from one user to another, sent by a client that negotiated
+ * FEATURE_DIRECT_MESSAGES. The server passes it only to the session it is for, whichever room
+ * either is in, and sends a copy back to the sender once it did. It is refused with an ERROR
+ * with the DIRECT_MESSAGE packet type: USER_OFFLINE if that session is not connected or is not
+ * keeping up with what it is sent, or PERMISSION_DENIED if it did not negotiate
+ * FEATURE_DIRECT_MESSAGES, either user blocks the other or the server's chat filter refuses the
+ * message. Direct messages are not kept.
+ *
+ * @generated from message system.DirectMessage
+ */
+export type DirectMessage = Message<"system.DirectMessage"> & {
+  /**
+   * The sender's session. Only the server sets it.
+   *
+   * @generated from field: int64 session_id = 1;
+   */
+  sessionId: bigint;
+
+  /**
+   * The sender's username. Only the server sets it.
+   *
+   * @generated from field: string username = 2;
+   */
+  username: string;
+
+  /**
+   * The session the message is for.
+   *
+   * @generated from field: int64 to_session_id = 3;
+   */
+  toSessionId: bigint;
+
+  /**
+   * @generated from field: string text = 4;
+   */
+  text: string;
+
+  /**
+   * When the server delivered the message, in milliseconds since the Unix epoch. Only the
+   * server sets it.
+   *
+   * @generated from field: uint64 sent_at_ms = 5;
+   */
+  sentAtMs: bigint;
+
+  /**
+   * Chosen by the sender, and only set in the copy sent back to it.
+   *
+   * @generated from field: uint64 client_message_id = 6;
+   */
+  clientMessageId: bigint;
+};
+
+/**
+ * Describes the message system.DirectMessage.
+ * Use `create(DirectMessageSchema)` to create a new message.
+ */
+export const DirectMessageSchema: GenMessage<DirectMessage> = /*@__PURE__*/
+  messageDesc(file_packet, 12);
+
+/**
+ * Sent by an authenticated client to set the users it blocks, replacing the ones it blocked
+ * before. The server delivers none of their voice, chat messages or direct messages to the
+ * client, and refuses direct messages from the client to them. On servers with accounts, the list
+ * is saved with the client's account, and applies again from its next login; elsewhere it lasts
+ * as long as the session, so clients send it again when they reconnect.
+ *
+ * @generated from message system.BlockList
+ */
+export type BlockList = Message<"system.BlockList"> & {
+  /**
+   * @generated from field: repeated string usernames = 1;
+   */
+  usernames: string[];
+};
+
+/**
+ * Describes the message system.BlockList.
+ * Use `create(BlockListSchema)` to create a new message.
+ */
+export const BlockListSchema: GenMessage<BlockList> = /*@__PURE__*/
+  messageDesc(file_packet, 13);
+
+/**
+ * Sent instead of JOIN_ROOM_RESPONSE to a client that negotiated FEATURE_LOBBY and asked to join
+ * a room with a waiting room, where it waits for a moderator to admit it. Until then the client
+ * is in no room, so it hears and is heard by nobody. Once admitted it is sent JOIN_ROOM_RESPONSE,
+ * and if refused an ERROR with PERMISSION_DENIED and the JOIN_ROOM_REQUEST packet type. Joining
+ * another room stops the wait.
+ *
+ * @generated from message system.LobbyWaiting
+ */
+export type LobbyWaiting = Message<"system.LobbyWaiting"> & {
+  /**
+   * @generated from field: string room_key = 1;
+   */
+  roomKey: string;
+};
+
+/**
+ * Describes the message system.LobbyWaiting.
+ * Use `create(LobbyWaitingSchema)` to create a new message.
+ */
+export const LobbyWaitingSchema: GenMessage<LobbyWaiting> = /*@__PURE__*/
+  messageDesc(file_packet, 14);
+
+/**
+ * Sent to the moderators in a room with a waiting room that negotiated FEATURE_LOBBY when a client
+ * starts waiting to join it, and again with `waiting` unset when it stops, whether it was
+ * admitted, refused or gave up. A moderator joining the room is sent one for every client already
+ * waiting.
+ *
+ * @generated from message system.LobbyPending
+ */
+export type LobbyPending = Message<"system.LobbyPending"> & {
+  /**
+   * @generated from field: int64 session_id = 1;
+   */
+  sessionId: bigint;
+
+  /**
+   * @generated from field: string username = 2;
+   */
+  username: string;
+
+  /**
+   * @generated from field: bool waiting = 3;
+   */
+  waiting: boolean;
+};
+
+/**
+ * Describes the message system.LobbyPending.
+ * Use `create(LobbyPendingSchema)` to create a new message.
+ */
+export const LobbyPendingSchema: GenMessage<LobbyPending> = /*@__PURE__*/
+  messageDesc(file_packet, 15);
+
+/**
+ * Sent by a moderator in a room that negotiated FEATURE_LOBBY to admit or refuse a client waiting
+ * to join it. Decisions about clients no longer waiting are ignored, and those of clients that
+ * are not moderators refused with PERMISSION_DENIED.
+ *
+ * @generated from message system.LobbyDecision
+ */
+export type LobbyDecision = Message<"system.LobbyDecision"> & {
+  /**
+   * @generated from field: int64 session_id = 1;
+   */
+  sessionId: bigint;
+
+  /**
+   * @generated from field: bool admit = 2;
+   */
+  admit: boolean;
+};
+
+/**
+ * Describes the message system.LobbyDecision.
+ * Use `create(LobbyDecisionSchema)` to create a new message.
+ */
+export const LobbyDecisionSchema: GenMessage<LobbyDecision> = /*@__PURE__*/
+  messageDesc(file_packet, 16);
+
+/**
+ * Sent by a moderator in a room to put it in slow mode, where each user may send a chat message
+ * every `interval_secs`, or with 0 to take it out of it. The server sends it to the room's members
+ * when it changes, and to clients joining a room in slow mode right after JOIN_ROOM_RESPONSE.
+ * Moderators are not slowed. Messages sent too soon are refused with RATE_LIMITED and
+ * `retry_after_ms`, and slow modes set by clients that are not moderators with PERMISSION_DENIED.
+ *
+ * @generated from message system.SlowMode
+ */
+export type SlowMode = Message<"system.SlowMode"> & {
+  /**
+   * @generated from field: uint32 interval_secs = 1;
+   */
+  intervalSecs: number;
+};
+
+/**
+ * Describes the message system.SlowMode.
+ * Use `create(SlowModeSchema)` to create a new message.
+ */
+export const SlowModeSchema: GenMessage<SlowMode> = /*@__PURE__*/
+  messageDesc(file_packet, 17);
+
+/**
+ * Sent by the server to every connected client, in a room or not, when the operator makes an
+ * announcement, such as a maintenance notice. Its audio, if any, is played into the client's
+ * room as a user of its own.
+ *
+ * @generated from message system.Announcement
+ */
+export type Announcement = Message<"system.Announcement"> & {
+  /**
+   * @generated from field: string text = 1;
+   */
+  text: string;
+
+  /**
+   * Milliseconds since the Unix epoch.
+   *
+   * @generated from field: uint64 sent_at_ms = 2;
+   */
+  sentAtMs: bigint;
+};
+
+/**
+ * Describes the message system.Announcement.
+ * Use `create(AnnouncementSchema)` to create a new message.
+ */
+export const AnnouncementSchema: GenMessage<Announcement> = /*@__PURE__*/
+  messageDesc(file_packet, 18);
+
+/**
+ * Sent by the server to every connected client when the operator schedules a maintenance
+ * window, then again as it nears, to clients that authenticate while one is scheduled, and with
+ * `starts_at` unset if it is cancelled. When it starts, the server drains as for a deploy. Joins
+ * in its last minutes are refused with MAINTENANCE.
+ *
+ * @generated from message system.Maintenance
+ */
+export type Maintenance = Message<"system.Maintenance"> & {
+  /**
+   * Seconds since the Unix epoch.
+   *
+   * @generated from field: uint64 starts_at = 1;
+   */
+  startsAt: bigint;
+
+  /**
+   * How long until it starts, in seconds, for clients whose clock is off.
+   *
+   * @generated from field: uint32 secs_left = 2;
+   */
+  secsLeft: number;
+
+  /**
+   * Why the server shuts down, for display.
+   *
+   * @generated from field: string reason = 3;
+   */
+  reason: string;
+};
+
+/**
+ * Describes the message system.Maintenance.
+ * Use `create(MaintenanceSchema)` to create a new message.
+ */
+export const MaintenanceSchema: GenMessage<Maintenance> = /*@__PURE__*/
+  messageDesc(file_packet, 19);
+
+/**
+ * Describes a file a client shares in its room. Files are not sent as control packets but on a
+ * unidirectional stream of their own: the byte 0xFE, the big-endian 16-bit length of this
+ * message and the message, then exactly `size` bytes of the file. Once the file is stored, the
+ * server shares it in the room as a chat message from the uploader with an `attachment`. Errors
+ * about uploads carry the FILE_UPLOAD packet type.
+ *
+ * @generated from message system.FileUpload
+ */
+export type FileUpload = Message<"system.FileUpload"> & {
+  /**
+   * @generated from field: string name = 1;
+   */
+  name: string;
+
+  /**
+   * The file's MIME type, such as `image/png`.
+   *
+   * @generated from field: string content_type = 2;
+   */
+  contentType: string;
+
+  /**
+   * The file's length in bytes.
+   *
+   * @generated from field: uint64 size = 3;
+   */
+  size: bigint;
+};
+
+/**
+ * Describes the message system.FileUpload.
+ * Use `create(FileUploadSchema)` to create a new message.
+ */
+export const FileUploadSchema: GenMessage<FileUpload> = /*@__PURE__*/
+  messageDesc(file_packet, 20);
+
+/**
+ * Describes a media track, such as a screen share or a camera, which a client relays to its room
+ * alongside its voice. Tracks are not sent as control packets but on a unidirectional stream
+ * each, by clients that negotiated FEATURE_MEDIA_STREAMS: the byte 0xFD, the big-endian 16-bit
+ * length of this message and the message, then any number of frames, each the big-endian 32-bit
+ * length of a MediaFrame and the MediaFrame. The server relays the track on a stream of the same
+ * format to every other member of the room that negotiated FEATURE_MEDIA_STREAMS, with
+ * `session_id` set to the sender's, and finishes that stream when the sender's ends. Errors about
+ * tracks carry the MEDIA_TRACK packet type.
+ *
+ * @generated from message system.MediaTrack
+ */
+export type MediaTrack = Message<"system.MediaTrack"> & {
+  /**
+   * The sender. Only the server sets it.
+   *
+   * @generated from field: int64 session_id = 1;
+   */
+  sessionId: bigint;
+
+  /**
+   * Chosen by the sender, to tell its tracks apart.
+   *
+   * @generated from field: uint32 track = 2;
+   */
+  track: number;
+
+  /**
+   * What the track carries, such as `screen` or `video/vp8`, so receivers can pick a decoder.
+   *
+   * @generated from field: string kind = 3;
+   */
+  kind: string;
+};
+
+/**
+ * Describes the message system.MediaTrack.
+ * Use `create(MediaTrackSchema)` to create a new message.
+ */
+export const MediaTrackSchema: GenMessage<MediaTrack> = /*@__PURE__*/
+  messageDesc(file_packet, 21);
+
+/**
+ * One frame of a media track. Frames are numbered and timed independently of voice, and the
+ * server relays them as they are without looking into them.
+ *
+ * @generated from message system.MediaFrame
+ */
+export type MediaFrame = Message<"system.MediaFrame"> & {
+  /**
+   * Counts the track's frames from the sender's choice of start.
+   *
+   * @generated from field: uint64 sequence = 1;
+   */
+  sequence: bigint;
+
+  /**
+   * When the frame was captured, in microseconds on the sender's clock.
+   *
+   * @generated from field: uint64 timestamp_us = 2;
+   */
+  timestampUs: bigint;
+
+  /**
+   * Whether the frame decodes without the frames before it. Receivers that fall behind have
+   * frames dropped until the track's next keyframe.
+   *
+   * @generated from field: bool keyframe = 3;
+   */
+  keyframe: boolean;
+
+  /**
+   * @generated from field: bytes payload = 4;
+   */
+  payload: Uint8Array;
+};
+
+/**
+ * Describes the message system.MediaFrame.
+ * Use `create(MediaFrameSchema)` to create a new message.
+ */
+export const MediaFrameSchema: GenMessage<MediaFrame> = /*@__PURE__*/
+  messageDesc(file_packet, 22);
+
+/**
+ * A file shared in a room.
+ *
+ * @generated from message system.FileAttachment
+ */
+export type FileAttachment = Message<"system.FileAttachment"> & {
+  /**
+   * @generated from field: string name = 1;
+   */
+  name: string;
+
+  /**
+   * @generated from field: string content_type = 2;
+   */
+  contentType: string;
+
+  /**
+   * @generated from field: uint64 size = 3;
+   */
+  size: bigint;
+
+  /**
+   * Where the file can be downloaded, until the link expires.
+   *
+   * @generated from field: string url = 4;
+   */
+  url: string;
+};
+
+/**
+ * Describes the message system.FileAttachment.
+ * Use `create(FileAttachmentSchema)` to create a new message.
+ */
+export const FileAttachmentSchema: GenMessage<FileAttachment> = /*@__PURE__*/
+  messageDesc(file_packet, 23);
+
+/**
+ * A non-verbal reaction in a room, such as applause during a talk. Clients send it with `emoji`
+ * and optionally `message_id` set, and the server delivers it to everyone in the room, the sender
+ * included, with the sender filled in.
+ *
+ * @generated from message system.Reaction
+ */
+export type Reaction = Message<"system.Reaction"> & {
+  /**
+   * @generated from field: int64 session_id = 1;
+   */
+  sessionId: bigint;
+
+  /**
+   * The emoji itself, or a short code such as `:clap:` for clients to render.
+   *
+   * @generated from field: string emoji = 2;
+   */
+  emoji: string;
+
+  /**
+   * The chat message reacted to, or 0 for a reaction to the room as a whole.
+   *
+   * @generated from field: uint64 message_id = 3;
+   */
+  messageId: bigint;
+};
+
+/**
+ * Describes the message system.Reaction.
+ * Use `create(ReactionSchema)` to create a new message.
+ */
+export const ReactionSchema: GenMessage<Reaction> = /*@__PURE__*/
+  messageDesc(file_packet, 24);
+
+/**
+ * Sent by the server when it rejects a packet.
+ *
+ * @generated from message system.Error
+ */
+export type Error = Message<"system.Error"> & {
+  /**
+   * The error code.
+   *
+   * @generated from field: system.Error.Code code = 1;
+   */
+  code: Error_Code;
+
+  /**
+   * Human-readable description of the error, for logging and display only.
+   *
+   * @generated from field: string detail = 2;
+   */
+  detail: string;
+
+  /**
+   * The type of the rejected packet, if it could be determined.
+   *
+   * @generated from field: optional system.PacketType packet_type = 3;
+   */
+  packetType?: PacketType;
+
+  /**
+   * With RATE_LIMITED, how long to wait before the packet would be accepted, if the server
+   * knows.
+   *
+   * @generated from field: uint32 retry_after_ms = 4;
+   */
+  retryAfterMs: number;
+};
+
+/**
+ * Describes the message system.Error.
+ * Use `create(ErrorSchema)` to create a new message.
+ */
+export const ErrorSchema: GenMessage<Error> = /*@__PURE__*/
+  messageDesc(file_packet, 25);
+
+/**
+ * @generated from enum system.Error.Code
+ */
+export enum Error_Code {
+  /**
+   * An error not covered by any other code.
+   *
+   * @generated from enum value: UNKNOWN = 0;
+   */
+  UNKNOWN = 0,
+
+  /**
+   * The packet could not be decoded.
+   *
+   * @generated from enum value: MALFORMED_PACKET = 1;
+   */
+  MALFORMED_PACKET = 1,
+
+  /**
+   * The packet is not valid in the session's current state.
+   *
+   * @generated from enum value: UNEXPECTED_PACKET = 2;
+   */
+  UNEXPECTED_PACKET = 2,
+
+  /**
+   * The packet requires an authenticated session.
+   *
+   * @generated from enum value: NOT_AUTHENTICATED = 3;
+   */
+  NOT_AUTHENTICATED = 3,
+
+  /**
+   * The requested room key is invalid.
+   *
+   * @generated from enum value: INVALID_ROOM = 4;
+   */
+  INVALID_ROOM = 4,
+
+  /**
+   * The session is already in a room.
+   *
+   * @generated from enum value: ALREADY_IN_ROOM = 5;
+   */
+  ALREADY_IN_ROOM = 5,
+
+  /**
+   * The client is sending packets too quickly.
+   *
+   * @generated from enum value: RATE_LIMITED = 6;
+   */
+  RATE_LIMITED = 6,
+
+  /**
+   * The client is not allowed to perform the request.
+   *
+   * @generated from enum value: PERMISSION_DENIED = 7;
+   */
+  PERMISSION_DENIED = 7,
+
+  /**
+   * The session is being closed because the client sent no traffic, or answered no PING,
+   * for too long.
+   *
+   * @generated from enum value: IDLE_TIMEOUT = 8;
+   */
+  IDLE_TIMEOUT = 8,
+
+  /**
+   * The room already holds as many users as it may.
+   *
+   * @generated from enum value: ROOM_FULL = 9;
+   */
+  ROOM_FULL = 9,
+
+  /**
+   * The client is sending audio over its bitrate cap. Frames over it are dropped, and the
+   * session is closed if it keeps on.
+   *
+   * @generated from enum value: BITRATE_EXCEEDED = 10;
+   */
+  BITRATE_EXCEEDED = 10,
+
+  /**
+   * The user the packet was for is not connected.
+   *
+   * @generated from enum value: USER_OFFLINE = 11;
+   */
+  USER_OFFLINE = 11,
+
+  /**
+   * The server shuts down for maintenance in a few minutes, and takes no new joins.
+   *
+   * @generated from enum value: MAINTENANCE = 12;
+   */
+  MAINTENANCE = 12,
+}
+
+/**
+ * Describes the enum system.Error.Code.
+ */
+export const Error_CodeSchema: GenEnum<Error_Code> = /*@__PURE__*/
+  enumDesc(file_packet, 25, 0);
+
+/**
+ * One piece of a control packet too large for a single datagram, including its type byte.
+ * Fragments of a packet share a `message_id` and may arrive in any order.
+ *
+ * @generated from message system.Fragment
+ */
+export type Fragment = Message<"system.Fragment"> & {
+  /**
+   * Identifies the fragmented packet among the sender's packets.
+   *
+   * @generated from field: uint32 message_id = 1;
+   */
+  messageId: number;
+
+  /**
+   * The position of this fragment, from 0 to `count - 1`.
+   *
+   * @generated from field: uint32 index = 2;
+   */
+  index: number;
+
+  /**
+   * The number of fragments in the packet.
+   *
+   * @generated from field: uint32 count = 3;
+   */
+  count: number;
+
+  /**
+   * @generated from field: bytes data = 4;
+   */
+  data: Uint8Array;
+};
+
+/**
+ * Describes the message system.Fragment.
+ * Use `create(FragmentSchema)` to create a new message.
+ */
+export const FragmentSchema: GenMessage<Fragment> = /*@__PURE__*/
+  messageDesc(file_packet, 26);
+
+/**
+ * An NTP-style clock offset probe. The server sends it with `server_send_time_us` set, and the
+ * client echoes it back as soon as possible with the other two fields filled in. All times are
+ * microseconds since the Unix epoch on the sender's clock.
+ *
+ * @generated from message system.ClockSync
+ */
+export type ClockSync = Message<"system.ClockSync"> & {
+  /**
+   * @generated from field: uint64 server_send_time_us = 1;
+   */
+  serverSendTimeUs: bigint;
+
+  /**
+   * @generated from field: uint64 client_receive_time_us = 2;
+   */
+  clientReceiveTimeUs: bigint;
+
+  /**
+   * @generated from field: uint64 client_send_time_us = 3;
+   */
+  clientSendTimeUs: bigint;
+};
+
+/**
+ * Describes the message system.ClockSync.
+ * Use `create(ClockSyncSchema)` to create a new message.
+ */
+export const ClockSyncSchema: GenMessage<ClockSync> = /*@__PURE__*/
+  messageDesc(file_packet, 27);
+
+/**
+ * Reported periodically by clients that negotiated FEATURE_LATENCY_REPORTS.
+ *
+ * @generated from message system.PlayoutReport
+ */
+export type PlayoutReport = Message<"system.PlayoutReport"> & {
+  /**
+   * The average time from a voice frame's arrival to its playout, in microseconds, including
+   * the jitter buffer and the audio device.
+   *
+   * @generated from field: uint32 playout_delay_us = 1;
+   */
+  playoutDelayUs: number;
+};
+
+/**
+ * Describes the message system.PlayoutReport.
+ * Use `create(PlayoutReportSchema)` to create a new message.
+ */
+export const PlayoutReportSchema: GenMessage<PlayoutReport> = /*@__PURE__*/
+  messageDesc(file_packet, 28);
+
+/**
+ * Connection quality over the last second, sent by the server to clients that negotiated
+ * FEATURE_SESSION_STATS.
+ *
+ * @generated from message system.SessionStats
+ */
+export type SessionStats = Message<"system.SessionStats"> & {
+  /**
+   * The fraction of packets sent to the client that were lost, from 0 to 1.
+   *
+   * @generated from field: float loss = 1;
+   */
+  loss: number;
+
+  /**
+   * The jitter of the client's voice datagrams as they arrive at the server, in microseconds.
+   *
+   * @generated from field: uint32 jitter_us = 2;
+   */
+  jitterUs: number;
+
+  /**
+   * The round-trip time between the client and the server, in microseconds.
+   *
+   * @generated from field: uint32 rtt_us = 3;
+   */
+  rttUs: number;
+
+  /**
+   * The number of speakers whose audio was sent to the client.
+   *
+   * @generated from field: uint32 forwarded_speakers = 4;
+   */
+  forwardedSpeakers: number;
+
+  /**
+   * Whether audio is sent to the client on streams, because it did not negotiate
+   * FEATURE_VOICE_DATAGRAMS or its connection takes no datagrams.
+   *
+   * @generated from field: bool audio_on_streams = 5;
+   */
+  audioOnStreams: boolean;
+};
+
+/**
+ * Describes the message system.SessionStats.
+ * Use `create(SessionStatsSchema)` to create a new message.
+ */
+export const SessionStatsSchema: GenMessage<SessionStats> = /*@__PURE__*/
+  messageDesc(file_packet, 29);
+
+/**
+ * Sent by the server when the client's audio queue kept overflowing, so it is sent fewer
+ * speakers, and again when that is lifted. Speakers keep their place while they talk.
+ *
+ * @generated from message system.ForwardingLimit
+ */
+export type ForwardingLimit = Message<"system.ForwardingLimit"> & {
+  /**
+   * The most speakers sent to the client at once, or 0 when every speaker is sent again.
+   *
+   * @generated from field: uint32 max_speakers = 1;
+   */
+  maxSpeakers: number;
+
+  /**
+   * Human-readable explanation, for logging and display only.
+   *
+   * @generated from field: string detail = 2;
+   */
+  detail: string;
+};
+
+/**
+ * Describes the message system.ForwardingLimit.
+ * Use `create(ForwardingLimitSchema)` to create a new message.
+ */
+export const ForwardingLimitSchema: GenMessage<ForwardingLimit> = /*@__PURE__*/
+  messageDesc(file_packet, 30);
+
+/**
+ * A liveness probe, sent by the server as PING to clients that negotiated FEATURE_PINGS. The
+ * client echoes it back as a PONG as soon as possible. An answer to a PING also answers the
+ * ones sent before it.
+ *
+ * @generated from message system.Ping
+ */
+export type Ping = Message<"system.Ping"> & {
+  /**
+   * Counts up with each PING sent in the session.
+   *
+   * @generated from field: uint32 id = 1;
+   */
+  id: number;
+};
+
+/**
+ * Describes the message system.Ping.
+ * Use `create(PingSchema)` to create a new message.
+ */
+export const PingSchema: GenMessage<Ping> = /*@__PURE__*/
+  messageDesc(file_packet, 31);
+
+/**
+ * @generated from enum system.PacketType
+ */
+export enum PacketType {
+  /**
+   * @generated from enum value: AUTH_REQUEST = 0;
+   */
+  AUTH_REQUEST = 0,
+
+  /**
+   * @generated from enum value: AUTH_RESPONSE_SUCCESS = 1;
+   */
+  AUTH_RESPONSE_SUCCESS = 1,
+
+  /**
+   * @generated from enum value: AUTH_RESPONSE_ERROR = 2;
+   */
+  AUTH_RESPONSE_ERROR = 2,
+
+  /**
+   * @generated from enum value: JOIN_ROOM_REQUEST = 3;
+   */
+  JOIN_ROOM_REQUEST = 3,
+
+  /**
+   * @generated from enum value: JOIN_ROOM_RESPONSE = 4;
+   */
+  JOIN_ROOM_RESPONSE = 4,
+
+  /**
+   * @generated from enum value: USER_JOINED = 5;
+   */
+  USER_JOINED = 5,
+
+  /**
+   * @generated from enum value: USER_LEFT = 6;
+   */
+  USER_LEFT = 6,
+
+  /**
+   * @generated from enum value: HELLO = 7;
+   */
+  HELLO = 7,
+
+  /**
+   * @generated from enum value: HELLO_ACK = 8;
+   */
+  HELLO_ACK = 8,
+
+  /**
+   * @generated from enum value: HELLO_ERROR = 9;
+   */
+  HELLO_ERROR = 9,
+
+  /**
+   * @generated from enum value: ERROR = 10;
+   */
+  ERROR = 10,
+
+  /**
+   * @generated from enum value: FRAGMENT = 11;
+   */
+  FRAGMENT = 11,
+
+  /**
+   * @generated from enum value: CLOCK_SYNC = 12;
+   */
+  CLOCK_SYNC = 12,
+
+  /**
+   * @generated from enum value: PLAYOUT_REPORT = 13;
+   */
+  PLAYOUT_REPORT = 13,
+
+  /**
+   * @generated from enum value: SESSION_STATS = 14;
+   */
+  SESSION_STATS = 14,
+
+  /**
+   * @generated from enum value: JOIN_ROOM_REDIRECT = 15;
+   */
+  JOIN_ROOM_REDIRECT = 15,
+
+  /**
+   * @generated from enum value: CHAT_MESSAGE = 16;
+   */
+  CHAT_MESSAGE = 16,
+
+  /**
+   * @generated from enum value: REACTION = 17;
+   */
+  REACTION = 17,
+
+  /**
+   * @generated from enum value: FILE_UPLOAD = 18;
+   */
+  FILE_UPLOAD = 18,
+
+  /**
+   * @generated from enum value: MEDIA_TRACK = 19;
+   */
+  MEDIA_TRACK = 19,
+
+  /**
+   * @generated from enum value: FORWARDING_LIMIT = 20;
+   */
+  FORWARDING_LIMIT = 20,
+
+  /**
+   * @generated from enum value: PING = 21;
+   */
+  PING = 21,
+
+  /**
+   * @generated from enum value: PONG = 22;
+   */
+  PONG = 22,
+
+  /**
+   * @generated from enum value: CHAT_ACK = 23;
+   */
+  CHAT_ACK = 23,
+
+  /**
+   * @generated from enum value: CHAT_BACKLOG_REQUEST = 24;
+   */
+  CHAT_BACKLOG_REQUEST = 24,
+
+  /**
+   * @generated from enum value: DIRECT_MESSAGE = 25;
+   */
+  DIRECT_MESSAGE = 25,
+
+  /**
+   * @generated from enum value: BLOCK_LIST = 26;
+   */
+  BLOCK_LIST = 26,
+
+  /**
+   * @generated from enum value: LOBBY_WAITING = 27;
+   */
+  LOBBY_WAITING = 27,
+
+  /**
+   * @generated from enum value: LOBBY_PENDING = 28;
+   */
+  LOBBY_PENDING = 28,
+
+  /**
+   * @generated from enum value: LOBBY_DECISION = 29;
+   */
+  LOBBY_DECISION = 29,
+
+  /**
+   * @generated from enum value: SLOW_MODE = 30;
+   */
+  SLOW_MODE = 30,
+
+  /**
+   * @generated from enum value: ANNOUNCEMENT = 31;
+   */
+  ANNOUNCEMENT = 31,
+
+  /**
+   * @generated from enum value: MAINTENANCE = 32;
+   */
+  MAINTENANCE = 32,
+
+  /**
+   * A control packet compressed, sent by the server to clients that negotiated
+   * FEATURE_COMPRESSION; see there.
+   *
+   * @generated from enum value: COMPRESSED = 33;
+   */
+  COMPRESSED = 33,
+}
+
+/**
+ * Describes the enum system.PacketType.
+ */
+export const PacketTypeSchema: GenEnum<PacketType> = /*@__PURE__*/
+  enumDesc(file_packet, 0);
+
+/**
+ * Application error codes sent in the CONNECTION_CLOSE frame when the server ends a session.
+ *
+ * @generated from enum system.CloseCode
+ */
+export enum CloseCode {
+  /**
+   * The session ended normally.
+   *
+   * @generated from enum value: CLOSE_CODE_NORMAL = 0;
+   */
+  NORMAL = 0,
+
+  /**
+   * A moderator removed the user from the server.
+   *
+   * @generated from enum value: CLOSE_CODE_KICKED = 1;
+   */
+  KICKED = 1,
+
+  /**
+   * The user is banned from the server.
+   *
+   * @generated from enum value: CLOSE_CODE_BANNED = 2;
+   */
+  BANNED = 2,
+
+  /**
+   * The server is shutting down.
+   *
+   * @generated from enum value: CLOSE_CODE_SERVER_SHUTDOWN = 3;
+   */
+  SERVER_SHUTDOWN = 3,
+
+  /**
+   * The client violated the protocol.
+   *
+   * @generated from enum value: CLOSE_CODE_PROTOCOL_VIOLATION = 4;
+   */
+  PROTOCOL_VIOLATION = 4,
+
+  /**
+   * The client sent no traffic, or answered no PING, for too long.
+   *
+   * @generated from enum value: CLOSE_CODE_IDLE_TIMEOUT = 5;
+   */
+  IDLE_TIMEOUT = 5,
+
+  /**
+   * The client and server share no protocol version.
+   *
+   * @generated from enum value: CLOSE_CODE_UNSUPPORTED_VERSION = 6;
+   */
+  UNSUPPORTED_VERSION = 6,
+
+  /**
+   * The client sent audio over its bitrate cap for too long.
+   *
+   * @generated from enum value: CLOSE_CODE_BITRATE_EXCEEDED = 7;
+   */
+  BITRATE_EXCEEDED = 7,
+}
+
+/**
+ * Describes the enum system.CloseCode.
+ */
+export const CloseCodeSchema: GenEnum<CloseCode> = /*@__PURE__*/
+  enumDesc(file_packet, 1);
+
+/**
+ * Optional protocol features, used as bit flags in `Hello.features` and `HelloAck.features`.
+ *
+ * Older peers skip fields and packet types they do not know, so anything added to this file that
+ * the other side must act on gets a bit here, or a new protocol version, and is only sent once
+ * negotiated. The build checks every change against `schema.lock`.
+ *
+ * @generated from enum system.Feature
+ */
+export enum Feature {
+  /**
+   * @generated from enum value: FEATURE_NONE = 0;
+   */
+  NONE = 0,
+
+  /**
+   * Voice data is carried in datagrams. Without it, the server sends each voice frame on a
+   * unidirectional stream of its own, and clients may send theirs the same way.
+   *
+   * @generated from enum value: FEATURE_VOICE_DATAGRAMS = 1;
+   */
+  VOICE_DATAGRAMS = 1,
+
+  /**
+   * Control packets larger than a datagram are split into FRAGMENT packets.
+   *
+   * @generated from enum value: FEATURE_FRAGMENTATION = 2;
+   */
+  FRAGMENTATION = 2,
+
+  /**
+   * The server measures clock offset with CLOCK_SYNC, voice datagrams sent by the client
+   * start with a capture timestamp, and the client sends PLAYOUT_REPORT packets.
+   *
+   * @generated from enum value: FEATURE_LATENCY_REPORTS = 4;
+   */
+  LATENCY_REPORTS = 4,
+
+  /**
+   * The server sends SESSION_STATS once per second.
+   *
+   * @generated from enum value: FEATURE_SESSION_STATS = 8;
+   */
+  SESSION_STATS = 8,
+
+  /**
+   * The server may answer JOIN_ROOM_REQUEST with JOIN_ROOM_REDIRECT.
+   *
+   * @generated from enum value: FEATURE_REDIRECTS = 16;
+   */
+  REDIRECTS = 16,
+
+  /**
+   * The client sends and receives media tracks on unidirectional streams; see MediaTrack.
+   *
+   * @generated from enum value: FEATURE_MEDIA_STREAMS = 32;
+   */
+  MEDIA_STREAMS = 32,
+
+  /**
+   * The server sends FORWARDING_LIMIT when it limits or stops limiting the speakers sent to
+   * the client.
+   *
+   * @generated from enum value: FEATURE_FORWARDING_LIMITS = 64;
+   */
+  FORWARDING_LIMITS = 64,
+
+  /**
+   * The server sends PING every so often, the client answers each with a PONG, and sessions
+   * that leave too many unanswered in a row are closed; see Ping.
+   *
+   * @generated from enum value: FEATURE_PINGS = 128;
+   */
+  PINGS = 128,
+
+  /**
+   * The server may move the client to another room, sending JOIN_ROOM_RESPONSE unasked with
+   * the new room's `room_key` and users. USER_JOINED and USER_LEFT then refer to that room.
+   *
+   * @generated from enum value: FEATURE_ROOM_MOVES = 256;
+   */
+  ROOM_MOVES = 256,
+
+  /**
+   * The server may send JOIN_ROOM_REDIRECT unasked to a client in a room, when it drains for a
+   * deploy, for the client to connect to the server it names and join the room there.
+   *
+   * @generated from enum value: FEATURE_MIGRATION = 512;
+   */
+  MIGRATION = 512,
+
+  /**
+   * The server sends the room's recent chat messages after JOIN_ROOM_RESPONSE, oldest first,
+   * as CHAT_MESSAGE packets with `history` set.
+   *
+   * @generated from enum value: FEATURE_CHAT_HISTORY = 1024;
+   */
+  CHAT_HISTORY = 1024,
+
+  /**
+   * The server confirms each chat message the client sends with a CHAT_ACK, and answers
+   * CHAT_BACKLOG_REQUEST with the messages of the room it missed.
+   *
+   * @generated from enum value: FEATURE_CHAT_RECEIPTS = 2048;
+   */
+  CHAT_RECEIPTS = 2048,
+
+  /**
+   * The client sends and receives DIRECT_MESSAGE packets.
+   *
+   * @generated from enum value: FEATURE_DIRECT_MESSAGES = 4096;
+   */
+  DIRECT_MESSAGES = 4096,
+
+  /**
+   * The client can wait to be admitted to rooms with a waiting room, being sent LOBBY_WAITING
+   * instead of JOIN_ROOM_RESPONSE, and a moderator's client is sent LOBBY_PENDING and may
+   * answer with LOBBY_DECISION.
+   *
+   * @generated from enum value: FEATURE_LOBBY = 8192;
+   */
+  LOBBY = 8192,
+
+  /**
+   * Voice datagrams both ways start with a fixed-size header instead of the prefix and session
+   * ID alone: the 0xFF prefix, the speaker's session ID as an int64, the room's ID (from
+   * JOIN_ROOM_RESPONSE) and the frame's sequence number as uint32s, and the capture time in
+   * microseconds since the Unix epoch as a uint64, or 0, all big-endian, 25 bytes in all. The
+   * payload follows it, and the server forwards it without reading it, so it may be encrypted
+   * end to end unless the room is mixed. The server drops frames whose session ID is not the
+   * client's and, quietly, those for a room the client is no longer in. Clients that also
+   * negotiated FEATURE_LATENCY_REPORTS put the capture timestamp in the header, not ahead of
+   * it.
+   *
+   * @generated from enum value: FEATURE_AUDIO_HEADER = 16384;
+   */
+  AUDIO_HEADER = 16384,
+
+  /**
+   * The server may send control packets of 512 bytes or more, such as JOIN_ROOM_RESPONSE with
+   * a large room's users or chat history, as COMPRESSED packets, whose payload is the whole
+   * packet, type byte included, compressed with raw DEFLATE (RFC 1951), as browsers inflate
+   * with `DecompressionStream("deflate-raw")`. Packets are only compressed when that makes
+   * them smaller, and before being split into FRAGMENT packets. Clients send nothing
+   * compressed.
+   *
+   * @generated from enum value: FEATURE_COMPRESSION = 32768;
+   */
+  COMPRESSION = 32768,
+
+  /**
+   * The client may move its control channel to a WebSocket, opened to `/signaling?token=` on
+   * the HTTP server that serves `/config.json` with HelloAck's `signaling_token`. Each binary
+   * message is one control packet, type byte included, both ways, and the server sends none
+   * as FRAGMENT while the socket is open. Voice stays on WebTransport. Control packets the
+   * client sends over WebTransport are still handled, and the server sends its own over
+   * WebTransport again if the socket closes.
+   *
+   * @generated from enum value: FEATURE_WEBSOCKET_SIGNALING = 65536;
+   */
+  WEBSOCKET_SIGNALING = 65536,
+}
+
+/**
+ * Describes the enum system.Feature.
+ */
+export const FeatureSchema: GenEnum<Feature> = /*@__PURE__*/
+  enumDesc(file_packet, 2);
 
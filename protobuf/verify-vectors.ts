@@ -0,0 +1,63 @@
+// Checks this package's generated code against the golden vectors in vectors.json, which are
+// written by the server's encoder (`cargo run -p protobuf --bin vectors -- --write`). Each
+// payload must decode and encode again to the same bytes, so a field the generated code lacks
+// or numbers differently fails instead of being dropped silently.
+//
+// Run with `pnpm verify-vectors`, after `pnpm gen`.
+
+import { readFileSync } from "node:fs";
+import { createRegistry, fromBinary, toBinary } from "@bufbuild/protobuf";
+import { file_common } from "./src/common_pb";
+import { file_packet } from "./src/packet_pb";
+
+type Vector = {
+    name: string;
+    packet_type: number | null;
+    message: string | null;
+    payload: string;
+};
+
+const registry = createRegistry(file_common, file_packet);
+const file = readFileSync(new URL("./vectors.json", import.meta.url), "utf8");
+const { vectors } = JSON.parse(file) as { vectors: Vector[] };
+
+const hex = (bytes: Uint8Array) =>
+    Array.from(bytes, (byte) => byte.toString(16).padStart(2, "0")).join("");
+const unhex = (text: string) =>
+    Uint8Array.from(text.match(/../g) ?? [], (byte) => parseInt(byte, 16));
+
+const failures: string[] = [];
+const packetType = registry.getEnum("system.PacketType");
+for (const vector of vectors) {
+    if (vector.packet_type !== null) {
+        const value = packetType?.values.find((value) => value.name === vector.name);
+        if (value?.number !== vector.packet_type) {
+            failures.push(`${vector.name}: not packet type ${vector.packet_type}`);
+        }
+    }
+    if (vector.message === null) {
+        continue;
+    }
+    const schema = registry.getMessage(vector.message);
+    if (!schema) {
+        failures.push(`${vector.name}: no message ${vector.message}`);
+        continue;
+    }
+    try {
+        const message = fromBinary(schema, unhex(vector.payload));
+        const encoded = hex(toBinary(schema, message, { writeUnknownFields: false }));
+        if (encoded !== vector.payload) {
+            failures.push(`${vector.name}: encodes again as ${encoded}, not ${vector.payload}`);
+        }
+    } catch (error) {
+        failures.push(`${vector.name}: cannot decode ${vector.payload}: ${error}`);
+    }
+}
+
+if (failures.length) {
+    for (const failure of failures) {
+        console.error(failure);
+    }
+    process.exit(1);
+}
+console.log(`All ${vectors.length} vectors match`);
@@ -0,0 +1,32 @@
+//! Benchmarks for fanning a voice frame out to every listener in a room.
+//!
+//! Run with `cargo bench --bench fanout`. Each round frames one speaker's packet and queues it
+//! for every listener, then drains the queues the way each listener's writer task would.
+
+use std::hint::black_box;
+use std::sync::Arc;
+use std::time::Duration;
+
+use voice_server::fanout;
+use voice_server::fanout::AudioQueue;
+
+mod support;
+
+fn main() {
+    let payload = vec![0x5A; 80];
+
+    for listeners in [10, 50, 100, 500, 1000] {
+        let queues: Vec<AudioQueue> = (0..listeners)
+            .map(|_| AudioQueue::new(32, Duration::from_millis(200), Arc::default()))
+            .collect();
+        support::bench(&format!("fan out to {listeners} listeners"), || {
+            let frame = fanout::voice(1, black_box(&payload));
+            for queue in &queues {
+                queue.push(frame.clone());
+            }
+            for queue in &queues {
+                black_box(queue.pop());
+            }
+        });
+    }
+}
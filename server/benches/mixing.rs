@@ -0,0 +1,121 @@
+//! Mixing benchmarks, comparing the SIMD kernels against plain scalar loops.
+//!
+//! Run with `cargo bench --bench mixing`. Reports the median time per room tick.
+
+use std::hint::black_box;
+use std::time::Duration;
+
+mod support;
+
+// Its unit tests come along, unused, when the benchmark is built as a test.
+#[path = "../src/dsp.rs"]
+#[allow(dead_code, unused_imports)]
+mod dsp;
+
+/// Samples in one 20 ms frame at 48 kHz.
+const FRAME_SAMPLES: usize = 960;
+
+fn scalar_accumulate(total: &mut [i32], pcm: &[i16]) {
+    for (sum, sample) in total.iter_mut().zip(pcm) {
+        *sum += i32::from(*sample);
+    }
+}
+
+fn scalar_mix_down(total: &[i32], own: Option<&[i16]>, out: &mut [i16]) {
+    for (i, out) in out.iter_mut().enumerate().take(total.len()) {
+        let own_sample = own.map_or(0, |own| i32::from(own[i]));
+        *out = (total[i] - own_sample).clamp(i16::MIN.into(), i16::MAX.into()) as i16;
+    }
+}
+
+fn scalar_rms(pcm: &[i16]) -> f32 {
+    let sum: u64 = pcm
+        .iter()
+        .map(|sample| (i64::from(*sample) * i64::from(*sample)) as u64)
+        .sum();
+    ((sum as f64 / pcm.len() as f64).sqrt() / 32768.0) as f32
+}
+
+struct Kernels {
+    accumulate: fn(&mut [i32], &[i16]),
+    mix_down: fn(&[i32], Option<&[i16]>, &mut [i16]),
+    rms: fn(&[i16]) -> f32,
+}
+
+const SIMD: Kernels = Kernels {
+    accumulate: dsp::accumulate,
+    mix_down: dsp::mix_down,
+    rms: dsp::rms,
+};
+
+const SCALAR: Kernels = Kernels {
+    accumulate: scalar_accumulate,
+    mix_down: scalar_mix_down,
+    rms: scalar_rms,
+};
+
+/// One mixer tick for a room: meter and sum every speaker, then mix down for every listener.
+fn tick(kernels: &Kernels, frames: &[Vec<i16>], total: &mut [i32], out: &mut [i16]) -> f32 {
+    let mut level = 0.0;
+    total.fill(0);
+    for pcm in frames {
+        level += (kernels.rms)(pcm);
+        (kernels.accumulate)(total, pcm);
+    }
+    for pcm in frames {
+        (kernels.mix_down)(total, Some(pcm), out);
+        black_box(&out);
+    }
+    level
+}
+
+fn median(kernels: &Kernels, frames: &[Vec<i16>]) -> Duration {
+    let mut total = vec![0i32; FRAME_SAMPLES];
+    let mut out = vec![0i16; FRAME_SAMPLES];
+    support::median(|| tick(kernels, black_box(frames), &mut total, &mut out))
+}
+
+/// Deterministic pseudo-random frames, loud enough to clip when summed.
+fn frames(participants: usize) -> Vec<Vec<i16>> {
+    let mut state = 0x2545_f491_u32;
+    (0..participants)
+        .map(|_| {
+            (0..FRAME_SAMPLES)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 17;
+                    state ^= state << 5;
+                    state as i16
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn main() {
+    // The kernels must agree before their timings mean anything.
+    let check = frames(8);
+    let (mut simd_total, mut scalar_total) = (vec![0; FRAME_SAMPLES], vec![0; FRAME_SAMPLES]);
+    let (mut simd_out, mut scalar_out) = (vec![0; FRAME_SAMPLES], vec![0; FRAME_SAMPLES]);
+    let simd_level = tick(&SIMD, &check, &mut simd_total, &mut simd_out);
+    let scalar_level = tick(&SCALAR, &check, &mut scalar_total, &mut scalar_out);
+    assert_eq!(simd_total, scalar_total);
+    assert_eq!(simd_out, scalar_out);
+    assert_eq!(simd_level, scalar_level);
+
+    println!(
+        "{:>12} {:>12} {:>12} {:>8}",
+        "participants", "scalar", "simd", "speedup"
+    );
+    for participants in [10, 50, 100, 200] {
+        let frames = frames(participants);
+        let scalar = median(&SCALAR, &frames);
+        let simd = median(&SIMD, &frames);
+        println!(
+            "{participants:>12} {:>12?} {:>12?} {:>7.2}x",
+            scalar,
+            simd,
+            scalar.as_secs_f64() / simd.as_secs_f64()
+        );
+    }
+}
@@ -0,0 +1,85 @@
+//! Packet encoding and decoding benchmarks.
+//!
+//! Run with `cargo bench --bench protocol`.
+
+use std::hint::black_box;
+
+use protobuf::system::AuthRequest;
+use protobuf::system::JoinRoomRequest;
+use protobuf::system::JoinRoomResponse;
+use protobuf::system::PacketType;
+use protobuf::system::RoomUser;
+
+mod support;
+
+#[path = "../src/protocol.rs"]
+#[allow(dead_code)]
+mod protocol;
+#[path = "../src/transport.rs"]
+#[allow(dead_code)]
+mod transport;
+
+mod store {
+    pub type BoxFuture<'a, T> = std::pin::Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+}
+
+use protocol::ClientPacket;
+
+/// Packets encoded or decoded per timed round.
+const BATCH: usize = 1000;
+
+fn main() {
+    // A 20 ms Opus frame at a typical voice bitrate.
+    let payload = vec![0x5A; 80];
+    support::bench("voice encode (x1000)", || {
+        for session_id in 0..BATCH as i64 {
+            black_box(protocol::voice(session_id, black_box(&payload)));
+        }
+    });
+
+    let auth = protocol::encode(
+        PacketType::AuthRequest,
+        &AuthRequest {
+            username: "listener".to_owned(),
+            ..Default::default()
+        },
+    );
+    support::bench("auth request decode (x1000)", || {
+        for _ in 0..BATCH {
+            black_box(ClientPacket::decode(black_box(&auth)).unwrap());
+        }
+    });
+
+    let join = protocol::encode(
+        PacketType::JoinRoomRequest,
+        &JoinRoomRequest {
+            room_key: "lobby".to_owned(),
+        },
+    );
+    support::bench("join request decode (x1000)", || {
+        for _ in 0..BATCH {
+            black_box(ClientPacket::decode(black_box(&join)).unwrap());
+        }
+    });
+
+    // A join response for a full room is the largest control packet the server sends.
+    let response = JoinRoomResponse {
+        users: (0..50)
+            .map(|session_id| RoomUser {
+                session_id,
+                username: format!("user-{session_id}"),
+                avatar_url: format!("/avatars/user-{session_id}?v=1791981179"),
+            })
+            .collect(),
+        room_key: "lobby".to_owned(),
+        room_id: protocol::room_id("lobby"),
+    };
+    support::bench("join response encode, 50 users (x1000)", || {
+        for _ in 0..BATCH {
+            black_box(protocol::encode(
+                PacketType::JoinRoomResponse,
+                black_box(&response),
+            ));
+        }
+    });
+}
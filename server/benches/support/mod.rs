@@ -0,0 +1,36 @@
+//! A minimal timing harness shared by the benchmarks.
+//!
+//! Criterion is not available to this workspace, so each benchmark is a `harness = false`
+//! binary that times its closure over a fixed number of rounds and reports the median.
+
+// Not every benchmark uses every helper.
+#![allow(dead_code)]
+
+use std::hint::black_box;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Rounds timed per benchmark, after one warm-up round.
+const ROUNDS: usize = 51;
+
+/// The median time of one call to `f`.
+pub fn median<T>(mut f: impl FnMut() -> T) -> Duration {
+    black_box(f());
+
+    let mut times: Vec<Duration> = (0..ROUNDS)
+        .map(|_| {
+            let start = Instant::now();
+            black_box(f());
+            start.elapsed()
+        })
+        .collect();
+    times.sort();
+    times[ROUNDS / 2]
+}
+
+/// Times `f` and prints its median under `name`.
+pub fn bench<T>(name: &str, f: impl FnMut() -> T) -> Duration {
+    let time = median(f);
+    println!("{name:<40} {time:>12?}");
+    time
+}
@@ -0,0 +1,412 @@
+//! User accounts: password hashes, roles, bans and the users each one blocks.
+//!
+//! Accounts are kept behind the [`AccountStore`] trait. The bundled [`FileStore`] keeps them in a
+//! JSON file carrying a schema version, which is brought up to date by [`MIGRATIONS`] when the
+//! file is opened and rewritten atomically on every change.
+//!
+//! Passwords are hashed with PBKDF2-HMAC-SHA256 and a random salt per account.
+//!
+//! Operators manage accounts with `server accounts <command>`; see [`run_command`].
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::num::NonZeroU32;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use anyhow::Context;
+use anyhow::Result;
+use anyhow::bail;
+use axum::http::HeaderMap;
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use ring::pbkdf2;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+use serde_json::json;
+
+use crate::store::BoxFuture;
+
+/// PBKDF2 iterations for new password hashes.
+const PBKDF2_ITERATIONS: u32 = 100_000;
+
+const SALT_LEN: usize = 16;
+
+const HASH_LEN: usize = 32;
+
+/// Upgrades an accounts file from the version at its index to the next one.
+type Migration = fn(&mut Value);
+
+/// Every schema change, in order. A file's `version` is the number of migrations applied to it.
+const MIGRATIONS: &[Migration] = &[
+    // 1: the initial schema.
+    |file| *file = json!({ "accounts": {} }),
+    // 2: the users each account blocks.
+    |file| {
+        if let Some(accounts) = file["accounts"].as_object_mut() {
+            for account in accounts.values_mut() {
+                account["blocked"] = json!([]);
+            }
+        }
+    },
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    User,
+    Moderator,
+    Admin,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Account {
+    pub username: String,
+
+    /// `pbkdf2-sha256$<iterations>$<salt>$<hash>`, with the salt and hash in base64.
+    pub password_hash: String,
+
+    pub role: Role,
+
+    pub ban: Option<Ban>,
+
+    /// Seconds since the Unix epoch.
+    pub created_at: u64,
+
+    /// When the account's avatar was last uploaded, in seconds since the Unix epoch, if it has
+    /// one.
+    pub avatar_updated_at: Option<u64>,
+
+    /// The usernames the user blocks, as last set with a BLOCK_LIST.
+    pub blocked: BTreeSet<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ban {
+    pub reason: String,
+
+    /// When the ban ends, in seconds since the Unix epoch. Unset bans are permanent.
+    pub until: Option<u64>,
+}
+
+impl Account {
+    /// Creates an account with a freshly hashed password.
+    pub fn new(username: &str, password: &str, role: Role) -> Self {
+        Self {
+            username: username.to_owned(),
+            password_hash: hash_password(password),
+            role,
+            ban: None,
+            created_at: now_secs(),
+            avatar_updated_at: None,
+            blocked: BTreeSet::new(),
+        }
+    }
+
+    /// The account's ban, if it is in force.
+    pub fn active_ban(&self) -> Option<&Ban> {
+        self.ban
+            .as_ref()
+            .filter(|ban| ban.until.is_none_or(|until| until > now_secs()))
+    }
+}
+
+pub trait AccountStore: Send + Sync {
+    fn get<'a>(&'a self, username: &'a str) -> BoxFuture<'a, Result<Option<Account>>>;
+
+    /// Adds an account, or replaces the one with the same username.
+    fn put(&self, account: Account) -> BoxFuture<'_, Result<()>>;
+
+    fn delete<'a>(&'a self, username: &'a str) -> BoxFuture<'a, Result<bool>>;
+
+    fn list(&self) -> BoxFuture<'_, Result<Vec<Account>>>;
+}
+
+/// Accounts kept in a JSON file.
+pub struct FileStore {
+    path: PathBuf,
+    accounts: Mutex<BTreeMap<String, Account>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct AccountsFile {
+    version: usize,
+    accounts: BTreeMap<String, Account>,
+}
+
+impl FileStore {
+    /// Opens the accounts file at `path`, creating or migrating it as needed.
+    pub fn open(path: PathBuf) -> Result<Self> {
+        let mut file = match std::fs::read_to_string(&path) {
+            Ok(json) => serde_json::from_str(&json)
+                .with_context(|| format!("Invalid accounts file {}", path.display()))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => json!({ "version": 0 }),
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("Cannot read accounts file {}", path.display()));
+            }
+        };
+
+        let version = file["version"].as_u64().unwrap_or(0) as usize;
+        if version > MIGRATIONS.len() {
+            bail!(
+                "Accounts file {} has version {version}, newer than this server",
+                path.display()
+            );
+        }
+        for migration in &MIGRATIONS[version..] {
+            migration(&mut file);
+        }
+        file["version"] = MIGRATIONS.len().into();
+
+        let file: AccountsFile = serde_json::from_value(file)
+            .with_context(|| format!("Invalid accounts file {}", path.display()))?;
+        let store = Self {
+            path,
+            accounts: Mutex::new(file.accounts),
+        };
+        if version < MIGRATIONS.len() {
+            store.save(&store.accounts.lock().unwrap())?;
+        }
+        Ok(store)
+    }
+
+    /// Writes the accounts to a temporary file and moves it over the old one, so a crash never
+    /// leaves a partly written file behind.
+    fn save(&self, accounts: &BTreeMap<String, Account>) -> Result<()> {
+        let json = serde_json::to_string_pretty(&AccountsFile {
+            version: MIGRATIONS.len(),
+            accounts: accounts.clone(),
+        })?;
+        let temp = self.path.with_extension("tmp");
+        std::fs::write(&temp, json)
+            .with_context(|| format!("Cannot write accounts file {}", temp.display()))?;
+        std::fs::rename(&temp, &self.path)
+            .with_context(|| format!("Cannot replace accounts file {}", self.path.display()))
+    }
+}
+
+impl AccountStore for FileStore {
+    fn get<'a>(&'a self, username: &'a str) -> BoxFuture<'a, Result<Option<Account>>> {
+        Box::pin(async move { Ok(self.accounts.lock().unwrap().get(username).cloned()) })
+    }
+
+    fn put(&self, account: Account) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            let mut accounts = self.accounts.lock().unwrap();
+            accounts.insert(account.username.clone(), account);
+            self.save(&accounts)
+        })
+    }
+
+    fn delete<'a>(&'a self, username: &'a str) -> BoxFuture<'a, Result<bool>> {
+        Box::pin(async move {
+            let mut accounts = self.accounts.lock().unwrap();
+            if accounts.remove(username).is_none() {
+                return Ok(false);
+            }
+            self.save(&accounts)?;
+            Ok(true)
+        })
+    }
+
+    fn list(&self) -> BoxFuture<'_, Result<Vec<Account>>> {
+        Box::pin(async move { Ok(self.accounts.lock().unwrap().values().cloned().collect()) })
+    }
+}
+
+/// Why an account could not log in.
+#[derive(Debug)]
+pub enum LoginError {
+    /// The account does not exist, or the password is wrong.
+    InvalidCredentials,
+
+    /// The account is banned.
+    Banned(Ban),
+}
+
+/// Checks a login against the store. Unknown usernames get a new account when `register` is
+/// set.
+pub async fn login(
+    store: &dyn AccountStore,
+    username: &str,
+    password: &str,
+    register: bool,
+) -> Result<Result<Account, LoginError>> {
+    let account = match store.get(username).await? {
+        Some(account) => account,
+        None if register && !password.is_empty() => {
+            let (username, password) = (username.to_owned(), password.to_owned());
+            let account =
+                tokio::task::spawn_blocking(move || Account::new(&username, &password, Role::User))
+                    .await?;
+            store.put(account.clone()).await?;
+            return Ok(Ok(account));
+        }
+        None => return Ok(Err(LoginError::InvalidCredentials)),
+    };
+
+    let (hash, password) = (account.password_hash.clone(), password.to_owned());
+    let verified = tokio::task::spawn_blocking(move || verify_password(&hash, &password)).await?;
+    if !verified {
+        return Ok(Err(LoginError::InvalidCredentials));
+    }
+    if let Some(ban) = account.active_ban() {
+        return Ok(Err(LoginError::Banned(ban.clone())));
+    }
+    Ok(Ok(account))
+}
+
+/// The username and password of an `Authorization: Basic` header.
+pub fn basic_credentials(headers: &HeaderMap) -> Option<(String, String)> {
+    let encoded = headers
+        .get("authorization")?
+        .to_str()
+        .ok()?
+        .strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(BASE64_STANDARD.decode(encoded).ok()?).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some((username.to_owned(), password.to_owned()))
+}
+
+/// The usage of `server accounts`.
+const USAGE: &str = "usage: server [--config <path>] accounts <command>
+
+commands:
+  list
+  add <username> <password> [user|moderator|admin]
+  remove <username>
+  ban <username> <reason> [days]
+  unban <username>";
+
+/// The arguments after `accounts` on the command line, if the server was started with it.
+pub fn command_args() -> Option<Vec<String>> {
+    let mut args = Vec::new();
+    let mut all = std::env::args().skip(1);
+    while let Some(arg) = all.next() {
+        if arg == "--config" {
+            all.next();
+        } else if arg != "--dump-packets" {
+            args.push(arg);
+        }
+    }
+
+    (args.first().map(String::as_str) == Some("accounts")).then(|| args.split_off(1))
+}
+
+/// Runs an account management command against the store in the config.
+pub async fn run_command(path: Option<PathBuf>, args: &[String]) -> Result<()> {
+    let path = path.context("accounts.path is not set in the config")?;
+    let store = FileStore::open(path)?;
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    match args[..] {
+        ["list"] => {
+            for account in store.list().await? {
+                let ban = match account.active_ban() {
+                    Some(ban) => format!(" (banned: {})", ban.reason),
+                    None => String::new(),
+                };
+                println!("{} {:?}{ban}", account.username, account.role);
+            }
+        }
+        ["add", username, password, ref role @ ..] => {
+            let role = match role {
+                [] | ["user"] => Role::User,
+                ["moderator"] => Role::Moderator,
+                ["admin"] => Role::Admin,
+                _ => bail!("{USAGE}"),
+            };
+            store.put(Account::new(username, password, role)).await?;
+        }
+        ["remove", username] => {
+            if !store.delete(username).await? {
+                bail!("no account named '{username}'");
+            }
+        }
+        ["ban", username, reason, ref days @ ..] => {
+            let until = match days {
+                [] => None,
+                [days] => {
+                    let days: u64 = days.parse().context("days must be a number")?;
+                    Some(now_secs() + days * 24 * 60 * 60)
+                }
+                _ => bail!("{USAGE}"),
+            };
+            let mut account = store
+                .get(username)
+                .await?
+                .with_context(|| format!("no account named '{username}'"))?;
+            account.ban = Some(Ban {
+                reason: reason.to_owned(),
+                until,
+            });
+            store.put(account).await?;
+        }
+        ["unban", username] => {
+            let mut account = store
+                .get(username)
+                .await?
+                .with_context(|| format!("no account named '{username}'"))?;
+            account.ban = None;
+            store.put(account).await?;
+        }
+        _ => bail!("{USAGE}"),
+    }
+
+    Ok(())
+}
+
+fn hash_password(password: &str) -> String {
+    let salt: [u8; SALT_LEN] = rand::random();
+    let mut hash = [0; HASH_LEN];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        NonZeroU32::new(PBKDF2_ITERATIONS).unwrap(),
+        &salt,
+        password.as_bytes(),
+        &mut hash,
+    );
+    format!(
+        "pbkdf2-sha256${PBKDF2_ITERATIONS}${}${}",
+        BASE64_STANDARD.encode(salt),
+        BASE64_STANDARD.encode(hash)
+    )
+}
+
+fn verify_password(password_hash: &str, password: &str) -> bool {
+    let mut parts = password_hash.split('$');
+    let (Some("pbkdf2-sha256"), Some(iterations), Some(salt), Some(hash), None) = (
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+    ) else {
+        return false;
+    };
+    let (Some(iterations), Ok(salt), Ok(hash)) = (
+        iterations.parse().ok().and_then(NonZeroU32::new),
+        BASE64_STANDARD.decode(salt),
+        BASE64_STANDARD.decode(hash),
+    ) else {
+        return false;
+    };
+
+    pbkdf2::verify(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        &salt,
+        password.as_bytes(),
+        &hash,
+    )
+    .is_ok()
+}
+
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
@@ -0,0 +1,862 @@
+//! The admin API: the `admin.Admin` service from the `protobuf` crate, served as gRPC-Web.
+//!
+//! gRPC-Web carries gRPC calls in plain HTTP/1.1 POSTs, so the service runs on the existing HTTP
+//! server at `/admin.Admin/<method>`. Typed clients come from any gRPC-Web code generator, and
+//! native gRPC clients can reach it through a translating proxy such as Envoy. Only the binary
+//! `application/grpc-web+proto` encoding is supported.
+//!
+//! Like every admin endpoint, it requires `admin.token` as a bearer token. A tenant's API key
+//! may be used instead, to see and act on only the tenant's rooms and sessions, which are then
+//! named without the tenant. So may an [`api_keys`](crate::api_keys) key, for the methods its
+//! permissions cover.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use axum::Router;
+use axum::body::Bytes;
+use axum::extract::Path;
+use axum::extract::State;
+use axum::http::HeaderMap;
+use axum::http::StatusCode;
+use axum::http::header::CONTENT_TYPE;
+use axum::response::IntoResponse;
+use axum::response::Response;
+use axum::routing::post;
+use prost::Message;
+use protobuf::admin;
+use protobuf::system::CloseCode;
+use tracing::info;
+use tracing::warn;
+
+use crate::accounts;
+use crate::accounts::Ban;
+use crate::announcements;
+use crate::api_keys::ApiKey;
+use crate::api_keys::Permission;
+use crate::broadcast::Broadcasts;
+use crate::drain;
+use crate::handoff;
+use crate::maintenance;
+use crate::maintenance::Window;
+use crate::metrics::METRICS;
+use crate::protocol;
+use crate::reload;
+use crate::rooms::MAX_ROOM_KEY_LEN;
+use crate::rooms::SessionId;
+use crate::schedule;
+use crate::schedule::ScheduledRoom;
+use crate::secrets;
+use crate::state::ServerState;
+use crate::tenants;
+
+const GRPC_WEB: &str = "application/grpc-web+proto";
+
+/// Flags the trailer frame ending a gRPC-Web response body.
+const TRAILER_FLAG: u8 = 0x80;
+
+/// The gRPC status codes the service returns.
+#[derive(Clone, Copy)]
+enum Code {
+    Ok = 0,
+    InvalidArgument = 3,
+    PermissionDenied = 7,
+    FailedPrecondition = 9,
+    Unimplemented = 12,
+    Internal = 13,
+    Unauthenticated = 16,
+}
+
+pub fn router(state: Arc<ServerState>) -> Router {
+    Router::new()
+        .route("/admin.Admin/{method}", post(call))
+        .with_state(state)
+}
+
+/// What an admin request may act on.
+pub enum Scope {
+    /// The whole server, for requests with the admin token.
+    Server,
+
+    /// One tenant's rooms and users, for requests with its API key.
+    Tenant(String),
+}
+
+impl Scope {
+    /// The key of a room or user the caller names, which for tenants is within their tenant.
+    pub fn key(&self, name: &str) -> String {
+        match self {
+            Self::Server => name.to_owned(),
+            Self::Tenant(tenant) => tenants::key(Some(tenant), name),
+        }
+    }
+
+    /// The name the caller knows a room or user by, or `None` if it is another tenant's.
+    pub fn name<'a>(&self, key: &'a str) -> Option<&'a str> {
+        match self {
+            Self::Server => Some(key),
+            Self::Tenant(tenant) => tenants::name(tenant, key),
+        }
+    }
+}
+
+/// Who an admin request comes from: what it may act on, and do.
+pub struct Caller {
+    pub scope: Scope,
+    permissions: Vec<Permission>,
+}
+
+impl Caller {
+    pub fn allows(&self, permission: Permission) -> bool {
+        self.permissions.contains(&permission)
+            && !(permission.is_server_wide() && matches!(self.scope, Scope::Tenant(_)))
+    }
+}
+
+/// Checks the request's bearer token against the admin token, the tenants' API keys and the
+/// API keys created through the admin API.
+pub fn authenticate(
+    state: &ServerState,
+    headers: &HeaderMap,
+) -> Result<Caller, (StatusCode, &'static str)> {
+    let config = &state.config;
+    if config.admin.token.is_none() && config.tenants.is_empty() && state.api_keys.is_empty() {
+        return Err((StatusCode::NOT_FOUND, "admin endpoints are disabled"));
+    }
+
+    let provided = headers
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let Some(provided) = provided else {
+        return Err((StatusCode::UNAUTHORIZED, "invalid admin token"));
+    };
+    if config
+        .admin
+        .token
+        .as_deref()
+        .is_some_and(|token| secrets::matches(token, provided))
+    {
+        return Ok(Caller {
+            scope: Scope::Server,
+            permissions: Permission::ALL.to_vec(),
+        });
+    }
+    if let Some(tenant) = tenants::by_api_key(config, provided) {
+        return Ok(Caller {
+            scope: Scope::Tenant(tenant.to_owned()),
+            permissions: Permission::ALL.to_vec(),
+        });
+    }
+    match state.api_keys.find(provided) {
+        Some(key) => Ok(Caller {
+            scope: key.tenant.map_or(Scope::Server, Scope::Tenant),
+            permissions: key.permissions,
+        }),
+        None => Err((StatusCode::UNAUTHORIZED, "invalid admin token")),
+    }
+}
+
+/// Checks that the request's bearer token grants `permission`.
+pub fn authorize(
+    state: &ServerState,
+    headers: &HeaderMap,
+    permission: Permission,
+) -> Result<Caller, (StatusCode, &'static str)> {
+    let caller = authenticate(state, headers)?;
+    if !caller.allows(permission) {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "the token lacks the permission for this endpoint",
+        ));
+    }
+    Ok(caller)
+}
+
+async fn call(
+    State(state): State<Arc<ServerState>>,
+    Path(method): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let caller = match authenticate(&state, &headers) {
+        Ok(caller) => caller,
+        Err((_, message)) => return status(Code::Unauthenticated, message),
+    };
+    let scope = &caller.scope;
+
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+    if !matches!(content_type, Some("application/grpc-web" | GRPC_WEB)) {
+        return StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response();
+    }
+    let Some(request) = unframe(&body) else {
+        return status(Code::InvalidArgument, "malformed request frame");
+    };
+
+    let permission = match method.as_str() {
+        "ListRooms" | "ListSessions" | "GetRoomForwarding" => Permission::RoomsRead,
+        "KickSession" | "MuteSession" => Permission::SessionsKick,
+        "BanUser" => Permission::UsersBan,
+        "StartRtmpPush" | "StopRtmpPush" | "ListRtmpPushes" => Permission::BroadcastsManage,
+        "GetUsage" => Permission::UsageRead,
+        "CreateApiKey" | "RevokeApiKey" | "ListApiKeys" => Permission::KeysManage,
+        "ReloadConfig" => Permission::ConfigReload,
+        "Drain" | "ScheduleMaintenance" => Permission::ServerDrain,
+        "ScheduleRoom" | "CancelScheduledRoom" | "ListScheduledRooms" => Permission::RoomsSchedule,
+        "Announce" => Permission::AnnouncementsSend,
+        _ => return status(Code::Unimplemented, "unknown method"),
+    };
+    if !caller.allows(permission) {
+        return status(
+            Code::PermissionDenied,
+            "the token lacks the permission for this method",
+        );
+    }
+
+    match method.as_str() {
+        "ListRooms" => reply(list_rooms(&state, scope)),
+        "ListSessions" => reply(list_sessions(&state, scope)),
+        "KickSession" => match admin::KickSessionRequest::decode(request) {
+            Ok(request) => reply(admin::KickSessionResponse {
+                kicked: in_session_scope(&state, scope, request.session_id)
+                    && state.registry.kick(request.session_id),
+            }),
+            Err(_) => status(Code::InvalidArgument, "malformed KickSessionRequest"),
+        },
+        "StartRtmpPush" => match admin::StartRtmpPushRequest::decode(request) {
+            Ok(request) if request.room_key.chars().count() > MAX_ROOM_KEY_LEN => {
+                status(Code::InvalidArgument, "room key is too long")
+            }
+            Ok(request) if !request.url.starts_with("rtmp://") => {
+                status(Code::InvalidArgument, "URL must start with rtmp://")
+            }
+            Ok(request) => {
+                Broadcasts::start_rtmp_push(&state, &scope.key(&request.room_key), &request.url);
+                reply(admin::StartRtmpPushResponse {})
+            }
+            Err(_) => status(Code::InvalidArgument, "malformed StartRtmpPushRequest"),
+        },
+        "StopRtmpPush" => match admin::StopRtmpPushRequest::decode(request) {
+            Ok(request) => reply(admin::StopRtmpPushResponse {
+                stopped: state
+                    .broadcasts
+                    .stop_rtmp_push(&scope.key(&request.room_key)),
+            }),
+            Err(_) => status(Code::InvalidArgument, "malformed StopRtmpPushRequest"),
+        },
+        "ListRtmpPushes" => reply(admin::ListRtmpPushesResponse {
+            pushes: state
+                .broadcasts
+                .rtmp_pushes()
+                .into_iter()
+                .filter_map(|(room_key, url)| {
+                    let room_key = scope.name(&room_key)?.to_owned();
+                    Some(admin::RtmpPush { room_key, url })
+                })
+                .collect(),
+        }),
+        "GetUsage" => reply(get_usage(&state, scope)),
+        "CreateApiKey" => match admin::CreateApiKeyRequest::decode(request) {
+            Ok(request) => create_api_key(&state, &caller, request),
+            Err(_) => status(Code::InvalidArgument, "malformed CreateApiKeyRequest"),
+        },
+        "RevokeApiKey" => match admin::RevokeApiKeyRequest::decode(request) {
+            Ok(request) => match state
+                .api_keys
+                .revoke(&request.id, |key| in_scope(scope, key))
+            {
+                Ok(revoked) => reply(admin::RevokeApiKeyResponse { revoked }),
+                Err(err) => {
+                    warn!("Cannot revoke API key: {err:#}");
+                    status(Code::Internal, "cannot save the API keys")
+                }
+            },
+            Err(_) => status(Code::InvalidArgument, "malformed RevokeApiKeyRequest"),
+        },
+        "ListApiKeys" => reply(admin::ListApiKeysResponse {
+            keys: state
+                .api_keys
+                .list()
+                .into_iter()
+                .filter(|key| in_scope(scope, key))
+                .map(api_key)
+                .collect(),
+        }),
+        "MuteSession" => match admin::MuteSessionRequest::decode(request) {
+            Ok(request) => reply(admin::MuteSessionResponse {
+                found: in_session_scope(&state, scope, request.session_id)
+                    && state.registry.set_muted(request.session_id, request.muted),
+            }),
+            Err(_) => status(Code::InvalidArgument, "malformed MuteSessionRequest"),
+        },
+        "BanUser" => match admin::BanUserRequest::decode(request) {
+            Ok(request) => ban_user(&state, scope, request).await,
+            Err(_) => status(Code::InvalidArgument, "malformed BanUserRequest"),
+        },
+        "GetRoomForwarding" => match admin::GetRoomForwardingRequest::decode(request) {
+            Ok(request) => reply(get_room_forwarding(&state, &scope.key(&request.room_key))),
+            Err(_) => status(Code::InvalidArgument, "malformed GetRoomForwardingRequest"),
+        },
+        "ReloadConfig" => match reload::reload(&state).await {
+            Ok(restart_required) => reply(admin::ReloadConfigResponse { restart_required }),
+            Err(err) => {
+                warn!("Cannot reload the config: {err:#}");
+                status(Code::FailedPrecondition, "cannot reload the config")
+            }
+        },
+        "Drain" => match admin::DrainRequest::decode(request) {
+            Ok(request)
+                if !request.address.is_empty()
+                    && !request.address.starts_with("http://")
+                    && !request.address.starts_with("https://") =>
+            {
+                status(
+                    Code::InvalidArgument,
+                    "address must start with http:// or https://",
+                )
+            }
+            Ok(request) => {
+                let timeout = match request.timeout_secs {
+                    0 => drain::DEFAULT_TIMEOUT,
+                    secs => Duration::from_secs(secs.into()),
+                };
+                let address = Some(request.address).filter(|address| !address.is_empty());
+                let handed_over = handoff::drain(&state, address.as_deref(), timeout).await;
+                reply(admin::DrainResponse {
+                    started: handed_over.is_some(),
+                    sessions: state.registry.sessions().len() as u32,
+                    handed_over: handed_over.unwrap_or_default() as u32,
+                })
+            }
+            Err(_) => status(Code::InvalidArgument, "malformed DrainRequest"),
+        },
+        "ScheduleRoom" => match admin::ScheduleRoomRequest::decode(request) {
+            Ok(request) => schedule_room(&state, scope, request.room.unwrap_or_default()),
+            Err(_) => status(Code::InvalidArgument, "malformed ScheduleRoomRequest"),
+        },
+        "CancelScheduledRoom" => match admin::CancelScheduledRoomRequest::decode(request) {
+            Ok(request) => match state
+                .schedule
+                .cancel(&request.id, |room| scope.name(&room.room_key).is_some())
+            {
+                Ok(cancelled) => {
+                    if cancelled {
+                        info!("Cancelled scheduled room {}", request.id);
+                    }
+                    reply(admin::CancelScheduledRoomResponse { cancelled })
+                }
+                Err(err) => {
+                    warn!("Cannot cancel scheduled room: {err:#}");
+                    status(Code::Internal, "cannot save the schedule")
+                }
+            },
+            Err(_) => status(
+                Code::InvalidArgument,
+                "malformed CancelScheduledRoomRequest",
+            ),
+        },
+        "ListScheduledRooms" => reply(admin::ListScheduledRoomsResponse {
+            rooms: state
+                .schedule
+                .list()
+                .into_iter()
+                .filter_map(|room| scheduled_room(scope, room))
+                .collect(),
+        }),
+        "ScheduleMaintenance" => match admin::ScheduleMaintenanceRequest::decode(request) {
+            Ok(request) => schedule_maintenance(&state, request).await,
+            Err(_) => status(
+                Code::InvalidArgument,
+                "malformed ScheduleMaintenanceRequest",
+            ),
+        },
+        "Announce" => match admin::AnnounceRequest::decode(request) {
+            Ok(request) => announce(&state, scope, request).await,
+            Err(_) => status(Code::InvalidArgument, "malformed AnnounceRequest"),
+        },
+        _ => unreachable!(),
+    }
+}
+
+/// Creates a key with at most the caller's permissions, limited to the caller's tenant if it
+/// has one.
+fn create_api_key(
+    state: &ServerState,
+    caller: &Caller,
+    request: admin::CreateApiKeyRequest,
+) -> Response {
+    let mut permissions = Vec::new();
+    for name in &request.permissions {
+        let Some(permission) = Permission::parse(name) else {
+            return status(Code::InvalidArgument, "unknown permission");
+        };
+        if !caller.allows(permission) {
+            return status(
+                Code::PermissionDenied,
+                "keys cannot have permissions their creator lacks",
+            );
+        }
+        if !permissions.contains(&permission) {
+            permissions.push(permission);
+        }
+    }
+
+    let tenant = match (&caller.scope, request.tenant) {
+        (Scope::Tenant(own), None) => Some(own.clone()),
+        (Scope::Tenant(own), Some(tenant)) if tenant == *own => Some(tenant),
+        (Scope::Tenant(_), Some(_)) => {
+            return status(
+                Code::PermissionDenied,
+                "tenants can only create keys of their own",
+            );
+        }
+        (Scope::Server, Some(tenant)) if !state.config.tenants.contains_key(&tenant) => {
+            return status(Code::InvalidArgument, "unknown tenant");
+        }
+        (Scope::Server, tenant) => tenant,
+    };
+    if tenant.is_some()
+        && permissions
+            .iter()
+            .any(|permission| permission.is_server_wide())
+    {
+        return status(
+            Code::InvalidArgument,
+            "keys limited to a tenant cannot act on the whole server",
+        );
+    }
+
+    match state
+        .api_keys
+        .create(&request.name, tenant.as_deref(), permissions)
+    {
+        Ok((key, secret)) => {
+            info!("Created API key {} ({})", key.id, key.name);
+            reply(admin::CreateApiKeyResponse {
+                key: Some(api_key(key)),
+                secret,
+            })
+        }
+        Err(err) => {
+            warn!("Cannot create API key: {err:#}");
+            status(Code::Internal, "cannot save the API keys")
+        }
+    }
+}
+
+/// Books a room of the caller's tenant, for participants of its tenant.
+fn schedule_room(state: &ServerState, scope: &Scope, room: admin::ScheduledRoom) -> Response {
+    if room.room_key.is_empty() || room.room_key.chars().count() > MAX_ROOM_KEY_LEN {
+        return status(Code::InvalidArgument, "invalid room key");
+    }
+    let duration_secs = u64::from(room.duration_secs);
+    if duration_secs == 0 || duration_secs > schedule::MAX_DURATION_SECS {
+        return status(
+            Code::InvalidArgument,
+            "the duration must be between a second and a day",
+        );
+    }
+    if room.starts_at.saturating_add(duration_secs) <= accounts::now_secs() {
+        return status(Code::InvalidArgument, "the room would already have ended");
+    }
+    if room.record && !state.recordings.enabled() {
+        return status(
+            Code::FailedPrecondition,
+            "recordings need storage.dir or storage.s3",
+        );
+    }
+
+    let booked = ScheduledRoom::new(
+        scope.key(&room.room_key),
+        room.title,
+        room.starts_at,
+        duration_secs,
+        room.participants
+            .iter()
+            .map(|name| scope.key(name))
+            .collect(),
+        room.record,
+    );
+    match state.schedule.add(booked.clone()) {
+        Ok(true) => {
+            info!(
+                "Scheduled room {} in '{}' at {}",
+                booked.id, booked.room_key, booked.starts_at
+            );
+            reply(admin::ScheduleRoomResponse {
+                room: scheduled_room(scope, booked),
+            })
+        }
+        Ok(false) => status(
+            Code::FailedPrecondition,
+            "the room is already booked for some of that time",
+        ),
+        Err(err) => {
+            warn!("Cannot schedule room: {err:#}");
+            status(Code::Internal, "cannot save the schedule")
+        }
+    }
+}
+
+/// Schedules or cancels a maintenance window, and tells every client.
+async fn schedule_maintenance(
+    state: &ServerState,
+    request: admin::ScheduleMaintenanceRequest,
+) -> Response {
+    if request.starts_at == 0 {
+        let replaced = state.maintenance.cancel();
+        if replaced {
+            maintenance::notify(state);
+        }
+        return reply(admin::ScheduleMaintenanceResponse { replaced });
+    }
+    if request.starts_at <= accounts::now_secs() {
+        return status(
+            Code::InvalidArgument,
+            "maintenance must start in the future",
+        );
+    }
+    if !request.address.is_empty()
+        && !request.address.starts_with("http://")
+        && !request.address.starts_with("https://")
+    {
+        return status(
+            Code::InvalidArgument,
+            "address must start with http:// or https://",
+        );
+    }
+    let timeout = match request.timeout_secs {
+        0 => drain::DEFAULT_TIMEOUT,
+        secs => Duration::from_secs(secs.into()),
+    };
+    let address = Some(request.address).filter(|address| !address.is_empty());
+    let replaced = state.maintenance.schedule(Window::new(
+        request.starts_at,
+        request.reason,
+        address,
+        timeout,
+    ));
+    maintenance::notify(state);
+    reply(admin::ScheduleMaintenanceResponse { replaced })
+}
+
+/// Sends an announcement to every session of the caller's tenant, and plays its audio into every
+/// room of the tenant with members.
+async fn announce(
+    state: &Arc<ServerState>,
+    scope: &Scope,
+    request: admin::AnnounceRequest,
+) -> Response {
+    if request.text.trim().is_empty() || request.text.chars().count() > protocol::MAX_CHAT_LEN {
+        return status(Code::InvalidArgument, "invalid announcement text");
+    }
+    let audio = if request.audio.is_empty() {
+        None
+    } else {
+        match announcements::load(&state.config.announcements, &request.audio) {
+            Ok(pcm) => Some(Arc::<[i16]>::from(pcm)),
+            Err(err) => {
+                warn!("Cannot load announcement audio: {err:#}");
+                return status(
+                    Code::FailedPrecondition,
+                    "cannot load the announcement audio",
+                );
+            }
+        }
+    };
+
+    let outboxes: Vec<_> = state
+        .registry
+        .client_outboxes()
+        .into_iter()
+        .filter(|(user_key, _)| scope.name(user_key).is_some())
+        .map(|(_, outbox)| outbox)
+        .collect();
+    info!(
+        "Announcing to {} sessions: {}",
+        outboxes.len(),
+        request.text
+    );
+    let sessions = announcements::send(outboxes, &request.text);
+
+    let mut rooms = 0;
+    if let Some(audio) = audio {
+        for (room_key, session_ids) in state.registry.rooms() {
+            if !session_ids.is_empty() && scope.name(&room_key).is_some() {
+                announcements::play(state, room_key, audio.clone());
+                rooms += 1;
+            }
+        }
+    }
+    reply(admin::AnnounceResponse { sessions, rooms })
+}
+
+/// A scheduled room as the caller knows it, or `None` if it is another tenant's.
+fn scheduled_room(scope: &Scope, room: ScheduledRoom) -> Option<admin::ScheduledRoom> {
+    Some(admin::ScheduledRoom {
+        room_key: scope.name(&room.room_key)?.to_owned(),
+        participants: room
+            .participants
+            .iter()
+            .filter_map(|key| Some(scope.name(key)?.to_owned()))
+            .collect(),
+        duration_secs: room.duration_secs as u32,
+        id: room.id,
+        title: room.title,
+        starts_at: room.starts_at,
+        record: room.record,
+    })
+}
+
+/// Whether a session exists and belongs to the caller's tenant, or the caller acts on the
+/// whole server.
+fn in_session_scope(state: &ServerState, scope: &Scope, session_id: SessionId) -> bool {
+    state
+        .registry
+        .sessions()
+        .iter()
+        .any(|(id, username)| *id == session_id && scope.name(username).is_some())
+}
+
+/// Bans an account of the caller's tenant, and kicks its sessions.
+async fn ban_user(state: &ServerState, scope: &Scope, request: admin::BanUserRequest) -> Response {
+    let Some(accounts) = &state.accounts else {
+        return status(Code::FailedPrecondition, "accounts are not enabled");
+    };
+    let key = scope.key(&request.username);
+    let mut account = match accounts.get(&key).await {
+        Ok(Some(account)) => account,
+        Ok(None) => return status(Code::InvalidArgument, "no such account"),
+        Err(err) => {
+            warn!("Cannot read account {key}: {err:#}");
+            return status(Code::Internal, "cannot read the account");
+        }
+    };
+    account.ban = Some(Ban {
+        reason: request.reason,
+        until: request
+            .days
+            .map(|days| accounts::now_secs() + u64::from(days) * 24 * 60 * 60),
+    });
+    if let Err(err) = accounts.put(account).await {
+        warn!("Cannot ban {key}: {err:#}");
+        return status(Code::Internal, "cannot save the account");
+    }
+    info!("Banned {key}");
+
+    let mut kicked = 0;
+    for (session_id, username) in state.registry.sessions() {
+        if username == key && state.registry.close(session_id, CloseCode::Banned) {
+            kicked += 1;
+        }
+    }
+    reply(admin::BanUserResponse { kicked })
+}
+
+/// Whether a key belongs to the caller's tenant, or the caller acts on the whole server.
+fn in_scope(scope: &Scope, key: &ApiKey) -> bool {
+    match scope {
+        Scope::Server => true,
+        Scope::Tenant(own) => key.tenant.as_ref() == Some(own),
+    }
+}
+
+fn api_key(key: ApiKey) -> admin::ApiKey {
+    admin::ApiKey {
+        id: key.id,
+        name: key.name,
+        permissions: key
+            .permissions
+            .iter()
+            .map(|permission| permission.as_str().to_owned())
+            .collect(),
+        tenant: key.tenant,
+        created_at: key.created_at,
+    }
+}
+
+fn list_rooms(state: &ServerState, scope: &Scope) -> admin::ListRoomsResponse {
+    let rooms = state
+        .registry
+        .rooms()
+        .into_iter()
+        .filter_map(|(room_key, session_ids)| {
+            Some(admin::Room {
+                room_key: scope.name(&room_key)?.to_owned(),
+                session_ids,
+            })
+        })
+        .collect();
+    admin::ListRoomsResponse { rooms }
+}
+
+/// Every member of a room, and how each one's voice reaches each other one. The room key is
+/// keyed with the caller's tenant.
+fn get_room_forwarding(state: &ServerState, room_key: &str) -> admin::GetRoomForwardingResponse {
+    let roster = state.registry.room_roster(room_key);
+    let mut forwarding = Vec::new();
+    for speaker in &roster {
+        for listener in roster
+            .iter()
+            .filter(|listener| listener.session_id != speaker.session_id)
+        {
+            let route = if speaker.muted {
+                admin::Route::SpeakerMuted
+            } else if state.mixer.is_some() {
+                admin::Route::Mixed
+            } else if !listener.outbox.audio().holds_place(speaker.session_id) {
+                admin::Route::ListenerLimited
+            } else {
+                admin::Route::Forwarded
+            };
+            forwarding.push(admin::Forwarding {
+                speaker_session_id: speaker.session_id,
+                listener_session_id: listener.session_id,
+                route: route.into(),
+            });
+        }
+    }
+
+    admin::GetRoomForwardingResponse {
+        found: !roster.is_empty(),
+        relays: state.relay.relayed_to(room_key),
+        members: roster
+            .into_iter()
+            .map(|member| admin::RoomMember {
+                session_id: member.session_id,
+                username: member.username,
+                muted: member.muted,
+                bridged: member.bridged,
+                queue_delay_us: member.outbox.queue_delay(),
+                max_speakers: member
+                    .outbox
+                    .audio()
+                    .max_speakers()
+                    .map(|max_speakers| max_speakers as u32),
+            })
+            .collect(),
+        forwarding,
+    }
+}
+
+pub fn list_sessions(state: &ServerState, scope: &Scope) -> admin::ListSessionsResponse {
+    let mut room_keys = HashMap::new();
+    for (room_key, session_ids) in state.registry.rooms() {
+        for session_id in session_ids {
+            room_keys.insert(session_id, room_key.clone());
+        }
+    }
+
+    let sessions = state
+        .registry
+        .sessions()
+        .into_iter()
+        .filter_map(|(session_id, username)| {
+            let username = scope.name(&username)?.to_owned();
+            let metrics = METRICS.session(session_id);
+            Some(admin::Session {
+                session_id,
+                username,
+                room_key: room_keys
+                    .remove(&session_id)
+                    .and_then(|room_key| Some(scope.name(&room_key)?.to_owned())),
+                qoe_score: metrics
+                    .as_ref()
+                    .and_then(|metrics| metrics.qoe.get())
+                    .map(|qoe| qoe as f64 / 1e3),
+                rtt_us: metrics
+                    .as_ref()
+                    .and_then(|metrics| metrics.latency.rtt.get())
+                    .map(|rtt| rtt.clamp(0, u32::MAX.into()) as u32),
+                bytes_sent: metrics
+                    .as_ref()
+                    .map_or(0, |metrics| metrics.bytes_sent.load(Ordering::Relaxed)),
+                bytes_received: metrics
+                    .as_ref()
+                    .map_or(0, |metrics| metrics.bytes_received.load(Ordering::Relaxed)),
+                muted: state.registry.is_muted(session_id),
+                audio_on_streams: metrics
+                    .as_ref()
+                    .is_some_and(|metrics| metrics.audio_on_streams.load(Ordering::Relaxed)),
+                country: metrics
+                    .as_ref()
+                    .and_then(|metrics| Some(metrics.location.country.as_deref()?.to_owned())),
+                asn: metrics.as_ref().and_then(|metrics| metrics.location.asn),
+                as_organization: metrics.as_ref().and_then(|metrics| {
+                    Some(metrics.location.as_organization.as_deref()?.to_owned())
+                }),
+                drops: metrics.map(|metrics| {
+                    let drops = &metrics.drops;
+                    admin::PacketDrops {
+                        rate_limited: drops.rate_limited.get(),
+                        queue_full: drops.queue_full.get(),
+                        stale: drops.stale.get(),
+                        malformed: drops.malformed.get(),
+                        oversized: drops.oversized.get(),
+                    }
+                }),
+            })
+        })
+        .collect();
+    admin::ListSessionsResponse { sessions }
+}
+
+fn get_usage(state: &ServerState, scope: &Scope) -> admin::GetUsageResponse {
+    let tenants = state
+        .usage
+        .totals()
+        .into_iter()
+        .filter(|(tenant, _)| match scope {
+            Scope::Server => true,
+            Scope::Tenant(own) => tenant == own,
+        })
+        .map(|(tenant, usage)| admin::TenantUsage {
+            tenant,
+            participant_seconds: usage.participant_seconds,
+            bytes_sent: usage.bytes_sent,
+            bytes_received: usage.bytes_received,
+            stored_bytes: usage.stored_bytes,
+        })
+        .collect();
+    admin::GetUsageResponse { tenants }
+}
+
+/// The message in a request body holding a single uncompressed frame.
+fn unframe(body: &[u8]) -> Option<&[u8]> {
+    let (header, message) = body.split_at_checked(5)?;
+    let len = u32::from_be_bytes(header[1..].try_into().unwrap()) as usize;
+    (header[0] == 0 && message.len() == len).then_some(message)
+}
+
+fn frame(flag: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(5 + payload.len());
+    frame.push(flag);
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// A successful response: the message, then trailers with an OK status.
+fn reply(message: impl Message) -> Response {
+    let mut body = frame(0, &message.encode_to_vec());
+    let trailers = format!("grpc-status:{}\r\n", Code::Ok as u8);
+    body.extend(frame(TRAILER_FLAG, trailers.as_bytes()));
+    ([(CONTENT_TYPE, GRPC_WEB)], body).into_response()
+}
+
+/// A failed call, as a trailers-only response with the status in the headers.
+fn status(code: Code, message: &'static str) -> Response {
+    (
+        [
+            (CONTENT_TYPE.as_str(), GRPC_WEB.to_owned()),
+            ("grpc-status", (code as u8).to_string()),
+            ("grpc-message", message.to_owned()),
+        ],
+        (),
+    )
+        .into_response()
+}
@@ -0,0 +1,145 @@
+//! Server-wide announcements, such as maintenance notices, made through the admin API.
+//!
+//! An announcement is sent as ANNOUNCEMENT to every session, in a room or not, but those whose
+//! control queue is full, which would otherwise hold up the rest. Its audio, if it has any, is
+//! played into every open room by a participant of its own, `announcement <room key>`, so it
+//! reaches listeners forwarded or through the mixer like any speaker's voice. The audio comes from
+//! WAV files in `announcements.audio_dir`; the server does not synthesize speech, so spoken notices
+//! are recorded, or synthesized, beforehand.
+
+use std::sync::Arc;
+
+use anyhow::Context;
+use anyhow::Result;
+use anyhow::bail;
+use protobuf::system;
+use protobuf::system::PacketType;
+use tracing::Instrument;
+use tracing::info;
+use tracing::info_span;
+use tracing::warn;
+
+use crate::config::AnnouncementsConfig;
+use crate::latency;
+use crate::outbox::Outbox;
+use crate::participant;
+use crate::participant::Participant;
+use crate::protocol;
+use crate::state::ServerState;
+
+/// The sample rate announcements' audio must have, which is the rooms'.
+const SAMPLE_RATE: u32 = 48_000;
+
+/// The longest audio an announcement may play, in seconds.
+const MAX_AUDIO_SECS: usize = 10 * 60;
+
+/// Reads the audio of the WAV file called `name` in `announcements.audio_dir`.
+pub fn load(config: &AnnouncementsConfig, name: &str) -> Result<Vec<i16>> {
+    let Some(dir) = &config.audio_dir else {
+        bail!("announcements.audio_dir is not set");
+    };
+    if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+        bail!("invalid audio file name '{name}'");
+    }
+    let path = dir.join(name);
+    let bytes = std::fs::read(&path)
+        .with_context(|| format!("Cannot read announcement audio {}", path.display()))?;
+    parse_wav(&bytes).with_context(|| format!("Unsupported WAV file {}", path.display()))
+}
+
+/// The samples of a WAV file of 16-bit mono PCM at 48 kHz.
+fn parse_wav(bytes: &[u8]) -> Result<Vec<i16>> {
+    if bytes.get(..4) != Some(b"RIFF") || bytes.get(8..12) != Some(b"WAVE") {
+        bail!("not a WAV file");
+    }
+    let mut format = None;
+    let mut offset = 12;
+    while let Some(header) = bytes.get(offset..offset + 8) {
+        let len = u32::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+        let start = offset + 8;
+        let Some(chunk) = bytes.get(start..start.saturating_add(len)) else {
+            bail!(
+                "truncated {:?} chunk",
+                String::from_utf8_lossy(&header[..4])
+            );
+        };
+        match &header[..4] {
+            b"fmt " if chunk.len() >= 16 => {
+                let u16_at = |at: usize| u16::from_le_bytes([chunk[at], chunk[at + 1]]);
+                let rate = u32::from_le_bytes(chunk[4..8].try_into().unwrap());
+                format = Some((u16_at(0), u16_at(2), rate, u16_at(14)));
+            }
+            b"data" => {
+                match format {
+                    Some((1, 1, SAMPLE_RATE, 16)) => {}
+                    Some((encoding, channels, rate, bits)) => bail!(
+                        "{bits}-bit audio in format {encoding} with {channels} channels at \
+                         {rate} Hz, not 16-bit mono PCM at {SAMPLE_RATE} Hz"
+                    ),
+                    None => bail!("no fmt chunk before the data"),
+                }
+                if chunk.len() / 2 > MAX_AUDIO_SECS * SAMPLE_RATE as usize {
+                    bail!("longer than {MAX_AUDIO_SECS} seconds");
+                }
+                return Ok(chunk
+                    .chunks_exact(2)
+                    .map(|sample| i16::from_le_bytes([sample[0], sample[1]]))
+                    .collect());
+            }
+            _ => {}
+        }
+        // Chunks are padded to an even length.
+        offset = start + len + len % 2;
+    }
+    bail!("no data chunk")
+}
+
+/// Sends an announcement to the sessions with `outboxes`, but those whose control queue is full,
+/// returning how many it was queued for.
+pub fn send(outboxes: Vec<Outbox>, text: &str) -> u32 {
+    let packet = protocol::encode(
+        PacketType::Announcement,
+        &system::Announcement {
+            text: text.to_owned(),
+            sent_at_ms: latency::now_us() / 1000,
+        },
+    );
+    let mut sent = 0;
+    for outbox in outboxes {
+        if outbox.try_send_control(packet.clone()) {
+            sent += 1;
+        }
+    }
+    sent
+}
+
+/// Plays an announcement's audio into a room, by its key with its tenant, in the background.
+pub fn play(state: &Arc<ServerState>, room_key: String, pcm: Arc<[i16]>) {
+    let state = state.clone();
+    let span = info_span!("Announcement", room_key);
+    tokio::spawn(
+        async move {
+            let participant = match Participant::join(
+                &state,
+                &format!("announcement {room_key}"),
+                &room_key,
+                Arc::default(),
+            )
+            .await
+            {
+                Ok(participant) => participant,
+                Err(err) => {
+                    warn!("Cannot play the announcement: {err:?}");
+                    return;
+                }
+            };
+            info!("Playing the announcement");
+            let mut ticks = tokio::time::interval(participant::FRAME_INTERVAL);
+            for frame in pcm.chunks(participant::FRAME_SAMPLES) {
+                ticks.tick().await;
+                participant.speak(&state, frame);
+            }
+        }
+        .instrument(span),
+    );
+}
@@ -0,0 +1,247 @@
+//! API keys: credentials for the admin endpoints limited to some of their permissions, and
+//! optionally to one tenant, created and revoked at runtime through the admin API.
+//!
+//! A key is shown once, when it is created, and only its SHA-256 hash is kept, in
+//! `admin.api_keys_path` if it is set and in memory otherwise. The admin token and tenants' API
+//! keys from the config have every permission.
+
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use anyhow::Context;
+use anyhow::Result;
+use ring::digest;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::accounts;
+
+/// What an API key may do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Permission {
+    /// List rooms and sessions.
+    #[serde(rename = "rooms:read")]
+    RoomsRead,
+
+    /// Kick and mute sessions.
+    #[serde(rename = "sessions:kick")]
+    SessionsKick,
+
+    /// Schedule, cancel and list scheduled rooms.
+    #[serde(rename = "rooms:schedule")]
+    RoomsSchedule,
+
+    /// Start, stop and list RTMP pushes of rooms' mixes.
+    #[serde(rename = "broadcasts:manage")]
+    BroadcastsManage,
+
+    /// Send announcements to every connected client.
+    #[serde(rename = "announcements:send")]
+    AnnouncementsSend,
+
+    /// Read tenants' usage.
+    #[serde(rename = "usage:read")]
+    UsageRead,
+
+    /// Export and delete users' data.
+    #[serde(rename = "users:data")]
+    UsersData,
+
+    /// Ban accounts.
+    #[serde(rename = "users:ban")]
+    UsersBan,
+
+    /// Create, list and revoke API keys, with at most the creator's own permissions.
+    #[serde(rename = "keys:manage")]
+    KeysManage,
+
+    /// Profile the server, which is never limited to one tenant.
+    #[serde(rename = "debug:profile")]
+    DebugProfile,
+
+    /// Follow the server's log, which is never limited to one tenant.
+    #[serde(rename = "debug:logs")]
+    DebugLogs,
+
+    /// Reload the config file, which is never limited to one tenant either.
+    #[serde(rename = "config:reload")]
+    ConfigReload,
+
+    /// Drain the server for a deploy, which is never limited to one tenant either.
+    #[serde(rename = "server:drain")]
+    ServerDrain,
+}
+
+impl Permission {
+    pub const ALL: [Self; 13] = [
+        Self::RoomsRead,
+        Self::SessionsKick,
+        Self::RoomsSchedule,
+        Self::BroadcastsManage,
+        Self::AnnouncementsSend,
+        Self::UsageRead,
+        Self::UsersData,
+        Self::UsersBan,
+        Self::KeysManage,
+        Self::DebugProfile,
+        Self::DebugLogs,
+        Self::ConfigReload,
+        Self::ServerDrain,
+    ];
+
+    /// The permission's name, such as `rooms:read`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::RoomsRead => "rooms:read",
+            Self::SessionsKick => "sessions:kick",
+            Self::RoomsSchedule => "rooms:schedule",
+            Self::BroadcastsManage => "broadcasts:manage",
+            Self::AnnouncementsSend => "announcements:send",
+            Self::UsageRead => "usage:read",
+            Self::UsersData => "users:data",
+            Self::UsersBan => "users:ban",
+            Self::KeysManage => "keys:manage",
+            Self::DebugProfile => "debug:profile",
+            Self::DebugLogs => "debug:logs",
+            Self::ConfigReload => "config:reload",
+            Self::ServerDrain => "server:drain",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|permission| permission.as_str() == name)
+    }
+
+    /// Whether the permission acts on the whole server, so tenants cannot have it.
+    pub fn is_server_wide(self) -> bool {
+        matches!(
+            self,
+            Self::DebugProfile | Self::DebugLogs | Self::ConfigReload | Self::ServerDrain
+        )
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub id: String,
+
+    /// What the key is for, as given by its creator.
+    pub name: String,
+
+    /// The tenant the key is limited to, if any.
+    pub tenant: Option<String>,
+
+    pub permissions: Vec<Permission>,
+
+    /// Seconds since the Unix epoch.
+    pub created_at: u64,
+
+    /// The SHA-256 hash of the key, hex encoded.
+    hash: String,
+}
+
+/// The API keys that have been created and not revoked.
+pub struct ApiKeys {
+    path: Option<PathBuf>,
+    keys: Mutex<Vec<ApiKey>>,
+}
+
+impl ApiKeys {
+    /// Opens the keys file at `path`, if any, creating it when a key is first created.
+    pub fn open(path: Option<PathBuf>) -> Result<Self> {
+        let keys = match &path {
+            Some(path) => match std::fs::read_to_string(path) {
+                Ok(json) => serde_json::from_str(&json)
+                    .with_context(|| format!("Invalid API keys file {}", path.display()))?,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+                Err(err) => {
+                    return Err(err)
+                        .with_context(|| format!("Cannot read API keys file {}", path.display()));
+                }
+            },
+            None => Vec::new(),
+        };
+        Ok(Self {
+            path,
+            keys: Mutex::new(keys),
+        })
+    }
+
+    /// Creates a key, returning it along with the secret to hand to its user.
+    pub fn create(
+        &self,
+        name: &str,
+        tenant: Option<&str>,
+        permissions: Vec<Permission>,
+    ) -> Result<(ApiKey, String)> {
+        let secret = format!("vck_{}", hex(&rand::random::<[u8; 32]>()));
+        let key = ApiKey {
+            id: hex(&rand::random::<[u8; 8]>()),
+            name: name.to_owned(),
+            tenant: tenant.map(str::to_owned),
+            permissions,
+            created_at: accounts::now_secs(),
+            hash: hash(&secret),
+        };
+
+        let mut keys = self.keys.lock().unwrap();
+        keys.push(key.clone());
+        self.save(&keys)?;
+        Ok((key, secret))
+    }
+
+    /// Revokes a key that `allowed` accepts. Returns `false` if there is no such key.
+    pub fn revoke(&self, id: &str, allowed: impl Fn(&ApiKey) -> bool) -> Result<bool> {
+        let mut keys = self.keys.lock().unwrap();
+        let Some(index) = keys.iter().position(|key| key.id == id && allowed(key)) else {
+            return Ok(false);
+        };
+        keys.remove(index);
+        self.save(&keys)?;
+        Ok(true)
+    }
+
+    pub fn list(&self) -> Vec<ApiKey> {
+        self.keys.lock().unwrap().clone()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.lock().unwrap().is_empty()
+    }
+
+    /// The key a request presented, if it is a valid one.
+    pub fn find(&self, secret: &str) -> Option<ApiKey> {
+        let hash = hash(secret);
+        let keys = self.keys.lock().unwrap();
+        keys.iter().find(|key| key.hash == hash).cloned()
+    }
+
+    /// Writes the keys to a temporary file and moves it over the old one, so a crash never
+    /// leaves a partly written file behind.
+    fn save(&self, keys: &[ApiKey]) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let json = serde_json::to_string_pretty(keys)?;
+        let temp = path.with_extension("tmp");
+        std::fs::write(&temp, json)
+            .with_context(|| format!("Cannot write API keys file {}", temp.display()))?;
+        std::fs::rename(&temp, path)
+            .with_context(|| format!("Cannot replace API keys file {}", path.display()))
+    }
+}
+
+fn hash(secret: &str) -> String {
+    hex(digest::digest(&digest::SHA256, secret.as_bytes()).as_ref())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        write!(hex, "{byte:02x}").unwrap();
+    }
+    hex
+}
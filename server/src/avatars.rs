@@ -0,0 +1,270 @@
+//! Avatars: users upload a picture of themselves over HTTP, which the server serves back and
+//! links to in their [`RoomUser`] entries.
+//!
+//! `POST /avatar` takes the image as the request body, authenticated with the uploader's
+//! account password as HTTP Basic credentials, so avatars need `accounts.path`, and
+//! `storage` to keep them in. Only PNG, JPEG, GIF and WebP images are accepted. The server
+//! reads their dimensions from the image header but does not decode or resize them, so images
+//! larger than `avatars.max_dimension` are refused rather than scaled down.
+//!
+//! `GET /avatars/<username>` serves the image. Avatar links carry the upload time, so clients
+//! and caches fetch a new avatar as soon as it changes.
+//!
+//! On servers with tenants, users log in, and their avatars are served, by their username keyed
+//! with their tenant, as in `acme/alice`.
+
+use std::sync::Arc;
+
+use axum::Router;
+use axum::body::Bytes;
+use axum::extract::DefaultBodyLimit;
+use axum::extract::Path;
+use axum::extract::State;
+use axum::http::HeaderMap;
+use axum::http::HeaderValue;
+use axum::http::StatusCode;
+use axum::http::header::CACHE_CONTROL;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::header::X_CONTENT_TYPE_OPTIONS;
+use axum::response::IntoResponse;
+use axum::response::Response;
+use axum::routing::get;
+use axum::routing::post;
+use protobuf::system::RoomUser;
+use tracing::info;
+use tracing::warn;
+
+use crate::accounts;
+use crate::accounts::LoginError;
+use crate::http_client;
+use crate::state::ServerState;
+use crate::tenants;
+
+pub fn router(state: Arc<ServerState>) -> Router {
+    let body_limit = state.config.avatars.max_size();
+    Router::new()
+        .route(
+            "/avatar",
+            post(upload).layer(DefaultBodyLimit::max(body_limit)),
+        )
+        .route("/avatars/{username}", get(download))
+        .with_state(state)
+}
+
+/// Sets the avatar link of a user of `tenant`, if they uploaded an avatar.
+pub async fn fill(state: &ServerState, tenant: Option<&str>, user: &mut RoomUser) {
+    let (Some(accounts), Some(_)) = (&state.accounts, &state.blobs) else {
+        return;
+    };
+    let username = tenants::key(tenant, &user.username);
+    match accounts.get(&username).await {
+        Ok(Some(account)) => {
+            if let Some(updated_at) = account.avatar_updated_at {
+                user.avatar_url = link(state, &username, updated_at);
+            }
+        }
+        Ok(None) => {}
+        Err(err) => warn!("Cannot look up the avatar of '{}': {err:#}", user.username),
+    }
+}
+
+fn link(state: &ServerState, username: &str, updated_at: u64) -> String {
+    format!(
+        "{}/avatars/{}?v={updated_at}",
+        state
+            .config
+            .files
+            .public_url
+            .as_deref()
+            .unwrap_or("")
+            .trim_end_matches('/'),
+        http_client::percent_encode(username)
+    )
+}
+
+/// The blob key of a user's avatar. Usernames are hex encoded, so any username is a safe key.
+pub fn key(username: &str) -> String {
+    let hex: String = username.bytes().map(|byte| format!("{byte:02x}")).collect();
+    format!("avatars/{hex}")
+}
+
+async fn upload(
+    State(state): State<Arc<ServerState>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let (Some(accounts), Some(blobs)) = (&state.accounts, &state.blobs) else {
+        return (StatusCode::NOT_FOUND, "this server does not store avatars").into_response();
+    };
+
+    let Some((username, password)) = accounts::basic_credentials(&headers) else {
+        return (
+            StatusCode::UNAUTHORIZED,
+            [("www-authenticate", "Basic realm=\"avatars\"")],
+            "log in with your username and password",
+        )
+            .into_response();
+    };
+    let mut account = match accounts::login(accounts.as_ref(), &username, &password, false).await {
+        Ok(Ok(account)) => account,
+        Ok(Err(LoginError::InvalidCredentials)) => {
+            return (StatusCode::UNAUTHORIZED, "invalid username or password").into_response();
+        }
+        Ok(Err(LoginError::Banned(ban))) => {
+            return (StatusCode::FORBIDDEN, format!("banned: {}", ban.reason)).into_response();
+        }
+        Err(err) => {
+            warn!("Cannot check avatar upload login: {err:#}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let Some(image) = probe(&body) else {
+        return (
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "avatars must be PNG, JPEG, GIF or WebP images",
+        )
+            .into_response();
+    };
+    let max_dimension = state.config.avatars.max_dimension;
+    if image.width == 0 || image.height == 0 {
+        return (StatusCode::BAD_REQUEST, "the image has no pixels").into_response();
+    }
+    if image.width > max_dimension || image.height > max_dimension {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!(
+                "avatars must be at most {max_dimension}x{max_dimension} pixels, not {}x{}",
+                image.width, image.height
+            ),
+        )
+            .into_response();
+    }
+
+    if let Err(err) = blobs.put(&key(&username), image.content_type, &body).await {
+        warn!("Cannot store the avatar of '{username}': {err:#}");
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+    state.usage.record_stored(&state, &username, body.len());
+    let updated_at = accounts::now_secs();
+    account.avatar_updated_at = Some(updated_at);
+    if let Err(err) = accounts.put(account).await {
+        warn!("Cannot record the avatar of '{username}': {err:#}");
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+    info!(
+        "'{username}' uploaded a {}x{} avatar",
+        image.width, image.height
+    );
+
+    link(&state, &username, updated_at).into_response()
+}
+
+async fn download(State(state): State<Arc<ServerState>>, Path(username): Path<String>) -> Response {
+    let Some(blobs) = &state.blobs else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let data = match blobs.get(&key(&username)).await {
+        Ok(Some(data)) => data,
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(err) => {
+            warn!("Cannot fetch the avatar of '{username}': {err:#}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    // Checked on upload, so stored avatars are always one of the accepted formats.
+    let content_type = probe(&data).map_or("application/octet-stream", |image| image.content_type);
+    (
+        [
+            (CONTENT_TYPE, HeaderValue::from_static(content_type)),
+            (X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff")),
+            (
+                CACHE_CONTROL,
+                HeaderValue::from_static("public, max-age=3600"),
+            ),
+        ],
+        data,
+    )
+        .into_response()
+}
+
+/// What an image's header says about it.
+struct Image {
+    content_type: &'static str,
+    width: u32,
+    height: u32,
+}
+
+/// Recognizes a PNG, JPEG, GIF or WebP image and reads its dimensions.
+fn probe(data: &[u8]) -> Option<Image> {
+    let image = |content_type, width, height| {
+        Some(Image {
+            content_type,
+            width,
+            height,
+        })
+    };
+    let u16_be = |at: usize| {
+        Some(u32::from(u16::from_be_bytes([
+            *data.get(at)?,
+            *data.get(at + 1)?,
+        ])))
+    };
+    let u16_le = |at: usize| {
+        Some(u32::from(u16::from_le_bytes([
+            *data.get(at)?,
+            *data.get(at + 1)?,
+        ])))
+    };
+    let u32_be = |at: usize| Some(u32::from_be_bytes(data.get(at..at + 4)?.try_into().ok()?));
+    let u24_le = |at: usize| {
+        let bytes = data.get(at..at + 3)?;
+        Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0]))
+    };
+
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") && data.get(12..16) == Some(b"IHDR") {
+        return image("image/png", u32_be(16)?, u32_be(20)?);
+    }
+    if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        return image("image/gif", u16_le(6)?, u16_le(8)?);
+    }
+    if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP") {
+        return match data.get(12..16)? {
+            // Lossy: the frame header follows a start code.
+            b"VP8 " if data.get(23..26) == Some(&[0x9d, 0x01, 0x2a]) => {
+                image("image/webp", u16_le(26)? & 0x3fff, u16_le(28)? & 0x3fff)
+            }
+            // Lossless: 14 bits each of width and height, minus one.
+            b"VP8L" if data.get(20) == Some(&0x2f) => {
+                let bits = u32::from_le_bytes(data.get(21..25)?.try_into().ok()?);
+                image(
+                    "image/webp",
+                    (bits & 0x3fff) + 1,
+                    ((bits >> 14) & 0x3fff) + 1,
+                )
+            }
+            b"VP8X" => image("image/webp", u24_le(24)? + 1, u24_le(27)? + 1),
+            _ => None,
+        };
+    }
+    if data.starts_with(&[0xff, 0xd8]) {
+        // Walk the segments to the start of frame, which holds the dimensions.
+        let mut at = 2;
+        while *data.get(at)? == 0xff {
+            let marker = *data.get(at + 1)?;
+            match marker {
+                // Padding before a marker.
+                0xff => at += 1,
+                // Markers without a length.
+                0x01 | 0xd0..=0xd7 => at += 2,
+                // Every start of frame but DHT, JPG and DAC, which share the range.
+                0xc0..=0xcf if !matches!(marker, 0xc4 | 0xc8 | 0xcc) => {
+                    return image("image/jpeg", u16_be(at + 7)?, u16_be(at + 5)?);
+                }
+                _ => at += 2 + u16_be(at + 2)? as usize,
+            }
+        }
+    }
+    None
+}
@@ -0,0 +1,132 @@
+//! Caps on the audio bitrate users send.
+//!
+//! `session.max_user_kbps` caps the voice frames of each user, and `session.max_room_kbps` those
+//! of a room's speakers together. Frames over either cap are dropped before they are forwarded.
+//! Once a second, each session checks what its client sent in the second that ended, dropped
+//! frames included. A client over its cap is warned with a `BITRATE_EXCEEDED` error, and after
+//! `session.bitrate_grace_secs` such seconds in a row its session is closed. Rooms over their
+//! cap only lose frames, since no one speaker is to blame.
+
+use std::time::Instant;
+
+use crate::config::SessionConfig;
+use crate::metrics::METRICS;
+
+/// A token bucket of bytes, holding up to a second's worth.
+pub struct ByteBucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Default for ByteBucket {
+    fn default() -> Self {
+        Self {
+            tokens: 0.0,
+            refilled: Instant::now(),
+        }
+    }
+}
+
+impl ByteBucket {
+    /// Takes `bytes` at a rate of `kbps` kilobits per second, returning `false` if too few are
+    /// left. A zero rate allows everything.
+    pub fn try_take(&mut self, kbps: u32, bytes: usize) -> bool {
+        if kbps == 0 {
+            return true;
+        }
+
+        let rate = f64::from(kbps) * 1000.0 / 8.0;
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(rate);
+        self.refilled = now;
+
+        let bytes = bytes as f64;
+        if self.tokens < bytes {
+            return false;
+        }
+        self.tokens -= bytes;
+        true
+    }
+}
+
+/// What a check found about the bitrate a client sends.
+pub enum Verdict {
+    /// The client started sending over its cap, at this many kilobits per second.
+    Warn(u64),
+
+    /// The client sent over its cap for too long, at this many kilobits per second.
+    Close(u64),
+}
+
+/// Meters the audio a client sends against the caps.
+pub struct UserBitrate {
+    /// The caps as of the last check, so frames need not read the live config.
+    max_user_kbps: u32,
+    max_room_kbps: u32,
+
+    bucket: ByteBucket,
+
+    /// Bytes of voice the client sent since the last check, dropped frames included.
+    bytes: u64,
+    checked: Instant,
+
+    /// Seconds in a row the client sent over its cap.
+    over_secs: u64,
+}
+
+impl UserBitrate {
+    pub fn new(config: &SessionConfig) -> Self {
+        Self {
+            max_user_kbps: config.max_user_kbps,
+            max_room_kbps: config.max_room_kbps,
+            bucket: ByteBucket::default(),
+            bytes: 0,
+            checked: Instant::now(),
+            over_secs: 0,
+        }
+    }
+
+    /// The cap on the room's speakers together, in kilobits per second. Zero is no cap.
+    pub fn max_room_kbps(&self) -> u32 {
+        self.max_room_kbps
+    }
+
+    /// Meters a voice frame of `bytes` bytes, returning `false` if it is over the user's cap and
+    /// must be dropped.
+    pub fn admit(&mut self, bytes: usize) -> bool {
+        self.bytes += bytes as u64;
+        if self.bucket.try_take(self.max_user_kbps, bytes) {
+            return true;
+        }
+        METRICS.audio_frames_over_bitrate.inc();
+        false
+    }
+
+    /// Checks the time since the last check, and takes the caps from `config`, as after a
+    /// reload.
+    pub fn check(&mut self, config: &SessionConfig) -> Option<Verdict> {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.checked).as_secs_f64();
+        let kbps = if elapsed > 0.0 {
+            (self.bytes as f64 * 8.0 / 1000.0 / elapsed) as u64
+        } else {
+            0
+        };
+        self.bytes = 0;
+        self.checked = now;
+        self.max_user_kbps = config.max_user_kbps;
+        self.max_room_kbps = config.max_room_kbps;
+
+        if self.max_user_kbps == 0 || kbps <= self.max_user_kbps.into() {
+            self.over_secs = 0;
+            return None;
+        }
+        self.over_secs += 1;
+        if config.bitrate_grace_secs > 0 && self.over_secs > config.bitrate_grace_secs {
+            METRICS.bitrate_disconnects.inc();
+            return Some(Verdict::Close(kbps));
+        }
+        (self.over_secs == 1).then_some(Verdict::Warn(kbps))
+    }
+}
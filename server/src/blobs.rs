@@ -0,0 +1,199 @@
+//! Storage for files users upload, kept behind the [`BlobStore`] trait: a directory on disk,
+//! or an S3 bucket.
+//!
+//! Keys are chosen by the server, so they are always safe as both paths and object keys.
+//!
+//! With `storage.encryption_key` set, blobs are encrypted with AES-256-GCM before they leave
+//! the server's memory, so a leaked disk or bucket does not leak what users shared. Each blob is
+//! [`ENCRYPTED_MAGIC`], a random nonce, then the ciphertext, which is bound to its key so blobs
+//! cannot be swapped for one another. They are only decrypted as they are served, to those with
+//! a valid download link.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::Context;
+use anyhow::Result;
+use anyhow::anyhow;
+use anyhow::bail;
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use ring::aead;
+
+use crate::config::StorageConfig;
+use crate::s3;
+use crate::store::BoxFuture;
+
+pub trait BlobStore: Send + Sync {
+    fn put<'a>(
+        &'a self,
+        key: &'a str,
+        content_type: &'a str,
+        data: &'a [u8],
+    ) -> BoxFuture<'a, Result<()>>;
+
+    /// Fetches a blob, or `None` if there is none under `key`.
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Vec<u8>>>>;
+
+    /// Deletes a blob, if there is one under `key`.
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<()>>;
+}
+
+/// What encrypted blobs start with, telling them apart from blobs stored before encryption was
+/// turned on.
+const ENCRYPTED_MAGIC: &[u8] = b"VCENC1";
+
+/// Opens the configured store, if any.
+pub fn open(config: &StorageConfig) -> Result<Option<Arc<dyn BlobStore>>> {
+    let store: Arc<dyn BlobStore> = match (&config.s3, &config.dir) {
+        (Some(s3), _) => Arc::new(S3Store(s3::Client::new(s3)?)),
+        (None, Some(dir)) => Arc::new(DiskStore { dir: dir.clone() }),
+        (None, None) => return Ok(None),
+    };
+    let Some(key) = &config.encryption_key else {
+        return Ok(Some(store));
+    };
+
+    let key = BASE64_STANDARD
+        .decode(key)
+        .ok()
+        .and_then(|key| aead::UnboundKey::new(&aead::AES_256_GCM, &key).ok())
+        .context("storage.encryption_key must be 32 bytes in base64")?;
+    Ok(Some(Arc::new(EncryptedStore {
+        inner: store,
+        key: aead::LessSafeKey::new(key),
+    })))
+}
+
+/// Blobs as files in a directory, one per key.
+struct DiskStore {
+    dir: PathBuf,
+}
+
+impl BlobStore for DiskStore {
+    fn put<'a>(
+        &'a self,
+        key: &'a str,
+        _content_type: &'a str,
+        data: &'a [u8],
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let path = self.dir.join(key);
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent)
+                    .await
+                    .with_context(|| format!("Cannot create {}", parent.display()))?;
+            }
+
+            // Written aside and renamed, so a blob is never seen half written.
+            let partial = path.with_extension("partial");
+            tokio::fs::write(&partial, data)
+                .await
+                .with_context(|| format!("Cannot write {}", partial.display()))?;
+            tokio::fs::rename(&partial, &path).await?;
+            Ok(())
+        })
+    }
+
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Vec<u8>>>> {
+        Box::pin(async move {
+            match tokio::fs::read(self.dir.join(key)).await {
+                Ok(data) => Ok(Some(data)),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(err) => Err(err.into()),
+            }
+        })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            match tokio::fs::remove_file(self.dir.join(key)).await {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+                _ => Ok(()),
+            }
+        })
+    }
+}
+
+/// Encrypts the blobs of another store.
+struct EncryptedStore {
+    inner: Arc<dyn BlobStore>,
+    key: aead::LessSafeKey,
+}
+
+impl BlobStore for EncryptedStore {
+    fn put<'a>(
+        &'a self,
+        key: &'a str,
+        content_type: &'a str,
+        data: &'a [u8],
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let nonce: [u8; aead::NONCE_LEN] = rand::random();
+            let mut sealed = data.to_vec();
+            self.key
+                .seal_in_place_append_tag(
+                    aead::Nonce::assume_unique_for_key(nonce),
+                    aead::Aad::from(key.as_bytes()),
+                    &mut sealed,
+                )
+                .map_err(|_| anyhow!("Cannot encrypt {key}"))?;
+
+            let mut blob = Vec::with_capacity(ENCRYPTED_MAGIC.len() + nonce.len() + sealed.len());
+            blob.extend_from_slice(ENCRYPTED_MAGIC);
+            blob.extend_from_slice(&nonce);
+            blob.extend_from_slice(&sealed);
+            self.inner.put(key, content_type, &blob).await
+        })
+    }
+
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Vec<u8>>>> {
+        Box::pin(async move {
+            let Some(mut blob) = self.inner.get(key).await? else {
+                return Ok(None);
+            };
+            let Some(sealed) = blob.strip_prefix(ENCRYPTED_MAGIC) else {
+                return Ok(Some(blob));
+            };
+            if sealed.len() < aead::NONCE_LEN + aead::AES_256_GCM.tag_len() {
+                bail!("{key} is truncated");
+            }
+
+            let nonce = aead::Nonce::assume_unique_for_key(sealed[..aead::NONCE_LEN].try_into()?);
+            let start = ENCRYPTED_MAGIC.len() + aead::NONCE_LEN;
+            let len = self
+                .key
+                .open_in_place(nonce, aead::Aad::from(key.as_bytes()), &mut blob[start..])
+                .map_err(|_| anyhow!("Cannot decrypt {key}: wrong key or corrupted blob"))?
+                .len();
+            blob.copy_within(start..start + len, 0);
+            blob.truncate(len);
+            Ok(Some(blob))
+        })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<()>> {
+        self.inner.delete(key)
+    }
+}
+
+struct S3Store(s3::Client);
+
+impl BlobStore for S3Store {
+    fn put<'a>(
+        &'a self,
+        key: &'a str,
+        content_type: &'a str,
+        data: &'a [u8],
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(self.0.put_object(key, content_type, data))
+    }
+
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Vec<u8>>>> {
+        Box::pin(self.0.get_object(key))
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<()>> {
+        Box::pin(self.0.delete_object(key))
+    }
+}
@@ -0,0 +1,379 @@
+//! `POST /session`, how clients start: it logs a user in over HTTP, or makes up a guest, and
+//! answers with a session token and where to connect.
+//!
+//! The body is JSON, `{"username": ..., "password": ..., "tenant": ...}`, every field optional.
+//! Without a username the client is given a guest name, `guest-` and six hex digits, refused
+//! when logins require an account unless `accounts.allow_guests` is set. The answer carries what
+//! `/config.json` does, the certificate digest, port and endpoints, alongside the token, so one
+//! request has clients ready to connect. The client then logs in with the token in AUTH_REQUEST's
+//! `session_token` rather than its credentials, which the server has already checked. Tokens
+//! work once, within [`TOKEN_TTL`]. `/config.json` stays for clients that log in the old way.
+//!
+//! Requests are rate limited by client address before any password is hashed, and the tokens
+//! outstanding are capped, so that a flood of requests costs neither CPU nor memory without
+//! bound. IPv6 clients are limited by their /64, which one client can hold in full. At most
+//! `accept.max_session_clients` addresses are tracked; past that, those whose limit has refilled
+//! are forgotten, and if none has, requests from new addresses are refused until one does.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use axum::Json;
+use axum::Router;
+use axum::extract::ConnectInfo;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::response::Redirect;
+use axum::response::Response;
+use axum::routing::post;
+use base64::Engine;
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::info;
+use tracing::warn;
+
+use crate::accounts;
+use crate::accounts::LoginError;
+use crate::http::ServerConfig;
+use crate::rate_limit::RateLimiter;
+use crate::state::ServerState;
+use crate::tenants::Scoped;
+
+/// How long a session token can be logged in with.
+pub const TOKEN_TTL: Duration = Duration::from_secs(60);
+
+/// How many guest names are tried before giving up, where each may be an account's.
+const GUEST_NAME_ATTEMPTS: usize = 8;
+
+/// A login checked by `POST /session`, for its client to log in with over WebTransport.
+pub struct Grant {
+    /// The tenant's ID, empty without one.
+    pub tenant: String,
+    pub username: String,
+    expires: Instant,
+}
+
+/// The session tokens issued and not yet used, by token.
+#[derive(Default)]
+pub struct SessionTokens {
+    grants: Mutex<HashMap<String, Grant>>,
+}
+
+impl SessionTokens {
+    /// Takes the login granted with `token`, unless there is none or it expired.
+    pub fn take(&self, token: &str) -> Option<Grant> {
+        let mut grants = self.grants.lock().unwrap();
+        let now = Instant::now();
+        grants.retain(|_, grant| grant.expires > now);
+        grants.remove(token)
+    }
+
+    /// Issues a token for a login, unless `max_tokens` are outstanding.
+    fn issue(&self, tenant: String, username: String, max_tokens: usize) -> Option<String> {
+        let mut grants = self.grants.lock().unwrap();
+        let now = Instant::now();
+        grants.retain(|_, grant| grant.expires > now);
+        if grants.len() >= max_tokens {
+            return None;
+        }
+        let token = BASE64_URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>());
+        let grant = Grant {
+            tenant,
+            username,
+            expires: now + TOKEN_TTL,
+        };
+        grants.insert(token.clone(), grant);
+        Some(token)
+    }
+}
+
+/// The rate limits of the clients that sent requests lately, by address.
+#[derive(Default)]
+struct ClientLimits {
+    limiters: HashMap<IpAddr, RateLimiter>,
+}
+
+impl ClientLimits {
+    /// Takes a token for a request from `address`, returning `false` if it is over its limit, or
+    /// if it is new and `max_clients` others are tracked, none with their limit refilled.
+    fn try_acquire(&mut self, address: IpAddr, rate: u32, burst: u32, max_clients: usize) -> bool {
+        if rate == 0 {
+            self.limiters.clear();
+            return true;
+        }
+        let address = client_key(address);
+        if !self.limiters.contains_key(&address) && self.limiters.len() >= max_clients {
+            self.limiters.retain(|_, limiter| !limiter.is_full());
+            if self.limiters.len() >= max_clients {
+                return false;
+            }
+        }
+        let limiter = self
+            .limiters
+            .entry(address)
+            .or_insert_with(|| RateLimiter::new(rate, burst));
+        limiter.set_limit(rate, burst);
+        limiter.try_acquire()
+    }
+}
+
+/// The address a client is limited by: IPv4 addresses as they are, IPv6 ones by their /64.
+fn client_key(address: IpAddr) -> IpAddr {
+    match address.to_canonical() {
+        IpAddr::V6(address) => {
+            let network = address.to_bits() & !u128::from(u64::MAX);
+            IpAddr::V6(Ipv6Addr::from_bits(network))
+        }
+        address => address,
+    }
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct SessionRequest {
+    username: String,
+    password: String,
+    tenant: String,
+}
+
+#[derive(Serialize)]
+struct SessionResponse {
+    token: String,
+    expires_in_secs: u64,
+    username: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tenant: Option<String>,
+    guest: bool,
+    #[serde(flatten)]
+    server: ServerConfig,
+}
+
+type BootstrapState = (
+    Arc<ServerState>,
+    Arc<ServerConfig>,
+    Arc<Mutex<ClientLimits>>,
+);
+
+/// The `POST /session` route. It needs the client's address, so the router must be served with
+/// `into_make_service_with_connect_info::<SocketAddr>`.
+pub fn router(state: Arc<ServerState>, server_config: ServerConfig) -> Router {
+    Router::new().route("/session", post(start)).with_state((
+        state,
+        Arc::new(server_config),
+        Arc::default(),
+    ))
+}
+
+async fn start(
+    State((state, server_config, clients)): State<BootstrapState>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    Json(request): Json<SessionRequest>,
+) -> Result<Response, (StatusCode, &'static str)> {
+    // While draining, clients start on the server taking over. A 307 keeps the method and body.
+    if let Some(address) = state.drain.address() {
+        return Ok(Redirect::temporary(&format!("{address}/session")).into_response());
+    }
+
+    let config = state.live_config();
+    let admitted = clients.lock().unwrap().try_acquire(
+        client.ip(),
+        config.accept.session_rate,
+        config.accept.session_burst,
+        config.accept.max_session_clients,
+    );
+    if !admitted {
+        return Err((StatusCode::TOO_MANY_REQUESTS, "too many session requests"));
+    }
+
+    let tenant = match state.config.tenants.get_key_value(&request.tenant) {
+        Some((tenant, _)) => Some(tenant.clone()),
+        None if state.config.tenants.is_empty() => None,
+        None => return Err((StatusCode::NOT_FOUND, "unknown tenant")),
+    };
+
+    let guest = request.username.is_empty();
+    let username = if guest {
+        guest_name(&state, tenant.as_deref()).await?
+    } else {
+        if let Some(accounts) = &state.accounts {
+            let user = Scoped {
+                tenant: tenant.as_deref(),
+                name: &request.username,
+            };
+            let register = state.config.accounts.allow_registration;
+            match accounts::login(accounts.as_ref(), &user.key(), &request.password, register).await
+            {
+                Ok(Ok(_)) => {}
+                Ok(Err(LoginError::InvalidCredentials)) => {
+                    info!("Rejecting login for '{}'", request.username);
+                    return Err((StatusCode::UNAUTHORIZED, "invalid credentials"));
+                }
+                Ok(Err(LoginError::Banned(_))) => {
+                    info!("Refusing banned user '{}'", request.username);
+                    return Err((StatusCode::FORBIDDEN, "banned"));
+                }
+                Err(err) => {
+                    warn!("Cannot check the login for '{}': {err:#}", request.username);
+                    return Err((StatusCode::INTERNAL_SERVER_ERROR, "cannot check the login"));
+                }
+            }
+        }
+        request.username
+    };
+
+    let token = state.session_tokens.issue(
+        tenant.clone().unwrap_or_default(),
+        username.clone(),
+        config.accept.max_session_tokens,
+    );
+    let Some(token) = token else {
+        warn!(
+            "Refusing a session request: {} tokens are outstanding",
+            config.accept.max_session_tokens
+        );
+        return Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            "too many session tokens outstanding",
+        ));
+    };
+    Ok(Json(SessionResponse {
+        token,
+        expires_in_secs: TOKEN_TTL.as_secs(),
+        username,
+        tenant,
+        guest,
+        server: server_config.as_ref().clone(),
+    })
+    .into_response())
+}
+
+/// Makes up a guest name that is no account's.
+async fn guest_name(
+    state: &ServerState,
+    tenant: Option<&str>,
+) -> Result<String, (StatusCode, &'static str)> {
+    let Some(accounts) = &state.accounts else {
+        return Ok(random_guest_name());
+    };
+    if !state.config.accounts.allow_guests {
+        return Err((StatusCode::FORBIDDEN, "guests are not allowed"));
+    }
+    for _ in 0..GUEST_NAME_ATTEMPTS {
+        let name = random_guest_name();
+        let user = Scoped {
+            tenant,
+            name: &name,
+        };
+        match accounts.get(&user.key()).await {
+            Ok(None) => return Ok(name),
+            Ok(Some(_)) => {}
+            Err(err) => {
+                warn!("Cannot look up a guest name: {err:#}");
+                return Err((StatusCode::INTERNAL_SERVER_ERROR, "cannot make up a guest"));
+            }
+        }
+    }
+    Err((StatusCode::SERVICE_UNAVAILABLE, "cannot make up a guest"))
+}
+
+fn random_guest_name() -> String {
+    format!("guest-{:06x}", rand::random_range(0..0x100_0000u32))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use std::thread;
+    use std::time::Duration;
+    use std::time::Instant;
+
+    use super::ClientLimits;
+    use super::Grant;
+    use super::SessionTokens;
+
+    fn issue(tokens: &SessionTokens, username: &str, max_tokens: usize) -> Option<String> {
+        tokens.issue(String::new(), username.into(), max_tokens)
+    }
+
+    #[test]
+    fn tokens_work_once() {
+        let tokens = SessionTokens::default();
+        let token = issue(&tokens, "alice", 10).unwrap();
+        assert_eq!(tokens.take(&token).unwrap().username, "alice");
+        assert!(tokens.take(&token).is_none());
+        assert!(tokens.take("forged").is_none());
+    }
+
+    #[test]
+    fn outstanding_tokens_are_capped() {
+        let tokens = SessionTokens::default();
+        let issued: Vec<String> = (0..3)
+            .map(|user| issue(&tokens, &format!("user-{user}"), 3).unwrap())
+            .collect();
+        assert!(issue(&tokens, "mallory", 3).is_none());
+
+        // Using a token makes room for another.
+        tokens.take(&issued[0]).unwrap();
+        assert!(issue(&tokens, "bob", 3).is_some());
+    }
+
+    #[test]
+    fn issuing_purges_expired_tokens() {
+        let tokens = SessionTokens::default();
+        tokens.grants.lock().unwrap().extend((0..3).map(|user| {
+            let grant = Grant {
+                tenant: String::new(),
+                username: format!("user-{user}"),
+                expires: Instant::now(),
+            };
+            (format!("expired-{user}"), grant)
+        }));
+        let token = issue(&tokens, "alice", 3).unwrap();
+        assert_eq!(tokens.grants.lock().unwrap().len(), 1);
+        assert!(tokens.take("expired-0").is_none());
+        assert!(tokens.take(&token).is_some());
+    }
+
+    fn address(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn clients_are_limited_separately() {
+        let mut clients = ClientLimits::default();
+        let mut acquire = |client: &str| clients.try_acquire(address(client), 1, 2, 10);
+        assert!(acquire("192.0.2.1"));
+        assert!(acquire("192.0.2.1"));
+        assert!(!acquire("192.0.2.1"));
+        assert!(acquire("192.0.2.2"));
+
+        // An IPv6 client's whole /64 shares a limit, as does an IPv4 address mapped into IPv6.
+        assert!(acquire("2001:db8::1"));
+        assert!(acquire("2001:db8::2"));
+        assert!(!acquire("2001:db8::ffff"));
+        assert!(acquire("2001:db8:0:1::1"));
+        assert!(!acquire("::ffff:192.0.2.1"));
+    }
+
+    #[test]
+    fn tracked_clients_are_capped() {
+        let mut clients = ClientLimits::default();
+        let mut acquire = |client: &str| clients.try_acquire(address(client), 100, 1, 2);
+        assert!(acquire("192.0.2.1"));
+        assert!(acquire("192.0.2.2"));
+        assert!(!acquire("192.0.2.3"));
+
+        // Clients whose limit has refilled are forgotten to make room.
+        thread::sleep(Duration::from_millis(50));
+        assert!(acquire("192.0.2.3"));
+        assert_eq!(clients.limiters.len(), 1);
+    }
+}
@@ -0,0 +1,518 @@
+//! Live streams of rooms' mixed audio, for listeners without WebTransport.
+//!
+//! A room listed in `broadcast.rooms` can be heard at `/rooms/<room_key>/live.ogg` by any
+//! number of listeners, with browsers' audio elements or any media player. Its listeners share
+//! one stream, which joins the room as a participant named `live <room_key>` while anyone is
+//! listening, so the room's members can see they are on air. Rooms in `broadcast.icecast` are
+//! also pushed to Icecast mounts, and stay on air for as long as the server runs. Operators can
+//! push any room to an RTMP endpoint through the admin API, which keeps it on air until the push
+//! is stopped. Scheduled rooms booked with `record` are stored the same way while they are on;
+//! see [`recordings`](crate::recordings).
+//!
+//! Streams are Ogg FLAC. Each page holds one 20 ms frame, and listeners joining late get the
+//! stream's header pages followed by the pages from then on. RTMP endpoints get the same FLAC
+//! frames without the Ogg framing.
+
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::Context;
+use anyhow::Result;
+use anyhow::bail;
+use axum::Router;
+use axum::body::Body;
+use axum::extract::Path;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::http::header::CACHE_CONTROL;
+use axum::http::header::CONTENT_TYPE;
+use axum::response::IntoResponse;
+use axum::response::Response;
+use axum::routing::get;
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use bytes::Bytes;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::Notify;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::oneshot;
+use tokio::task::AbortHandle;
+use tracing::Instrument;
+use tracing::info;
+use tracing::info_span;
+use tracing::warn;
+
+use crate::config::IcecastMount;
+use crate::events::Event;
+use crate::flac;
+use crate::ogg;
+use crate::participant;
+use crate::participant::Participant;
+use crate::recordings::Recording;
+use crate::recordings::SEGMENT_LEN;
+use crate::rooms::MAX_ROOM_KEY_LEN;
+use crate::rtmp;
+use crate::state::ServerState;
+
+/// Pages buffered for each listener, about two seconds of audio, before a slow listener skips
+/// ahead.
+const LISTENER_BUFFER_PAGES: usize = 100;
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// The largest response header accepted from Icecast.
+const MAX_ICECAST_RESPONSE_LEN: usize = 8192;
+
+/// The rooms currently on air.
+#[derive(Default)]
+pub struct Broadcasts {
+    streams: Mutex<HashMap<String, Arc<Stream>>>,
+
+    /// The RTMP pushes started through the admin API, by room key.
+    rtmp_pushes: Mutex<HashMap<String, RtmpPush>>,
+}
+
+struct Stream {
+    /// The pages every listener must get first.
+    headers: Bytes,
+
+    /// The FLAC STREAMINFO block, which RTMP endpoints get instead of the header pages.
+    stream_info: Bytes,
+
+    frames: broadcast::Sender<Frame>,
+}
+
+/// One 20 ms frame of a stream, encoded for each kind of listener.
+#[derive(Clone)]
+struct Frame {
+    flac: Bytes,
+
+    /// The FLAC frame in an Ogg page.
+    page: Bytes,
+}
+
+/// A listener's view of a stream.
+struct Subscription {
+    headers: Bytes,
+    stream_info: Bytes,
+    frames: broadcast::Receiver<Frame>,
+}
+
+struct RtmpPush {
+    url: String,
+    task: AbortHandle,
+}
+
+/// Encodes room audio into Ogg FLAC pages.
+struct Encoder {
+    flac: flac::Encoder,
+    ogg: ogg::Stream,
+    samples: u64,
+}
+
+impl Broadcasts {
+    /// Subscribes to a room's stream, putting the room on air if it is not already.
+    fn subscribe(state: &Arc<ServerState>, room_key: &str) -> Subscription {
+        let mut streams = state.broadcasts.streams.lock().unwrap();
+        if let Some(stream) = streams.get(room_key) {
+            return Subscription {
+                headers: stream.headers.clone(),
+                stream_info: stream.stream_info.clone(),
+                frames: stream.frames.subscribe(),
+            };
+        }
+
+        let mut encoder = Encoder::new();
+        let (frames, receiver) = broadcast::channel(LISTENER_BUFFER_PAGES);
+        let stream = Arc::new(Stream {
+            headers: encoder.headers(),
+            stream_info: encoder.flac.stream_info(true).into(),
+            frames,
+        });
+        streams.insert(room_key.to_owned(), stream.clone());
+        tokio::spawn(
+            run(state.clone(), room_key.to_owned(), stream.clone(), encoder)
+                .instrument(info_span!("Broadcast", room_key)),
+        );
+
+        Subscription {
+            headers: stream.headers.clone(),
+            stream_info: stream.stream_info.clone(),
+            frames: receiver,
+        }
+    }
+
+    /// Starts pushing a room to an RTMP endpoint, replacing any push the room already has.
+    pub fn start_rtmp_push(state: &Arc<ServerState>, room_key: &str, url: &str) {
+        let task = tokio::spawn(
+            push_to_rtmp(state.clone(), room_key.to_owned(), url.to_owned())
+                .instrument(info_span!("RTMP", room_key)),
+        );
+        let push = RtmpPush {
+            url: url.to_owned(),
+            task: task.abort_handle(),
+        };
+        let replaced = state
+            .broadcasts
+            .rtmp_pushes
+            .lock()
+            .unwrap()
+            .insert(room_key.to_owned(), push);
+        if let Some(replaced) = replaced {
+            replaced.task.abort();
+        }
+    }
+
+    /// Starts recording a room's stream into the blob store, until the returned sender is used or
+    /// dropped.
+    pub fn start_recording(state: &Arc<ServerState>, recording: Recording) -> oneshot::Sender<()> {
+        let (stop, stopped) = oneshot::channel();
+        let span = info_span!("Recording", room_key = recording.room_key);
+        tokio::spawn(record(state.clone(), recording, stopped).instrument(span));
+        stop
+    }
+
+    /// Stops a room's RTMP push. Returns whether it had one.
+    pub fn stop_rtmp_push(&self, room_key: &str) -> bool {
+        let push = self.rtmp_pushes.lock().unwrap().remove(room_key);
+        push.map(|push| push.task.abort()).is_some()
+    }
+
+    /// Every RTMP push, as room keys and URLs.
+    pub fn rtmp_pushes(&self) -> Vec<(String, String)> {
+        self.rtmp_pushes
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(room_key, push)| (room_key.clone(), push.url.clone()))
+            .collect()
+    }
+
+    /// Takes a room off air, unless `stream` has already been replaced.
+    fn remove(&self, room_key: &str, stream: &Arc<Stream>) {
+        let mut streams = self.streams.lock().unwrap();
+        if streams
+            .get(room_key)
+            .is_some_and(|current| Arc::ptr_eq(current, stream))
+        {
+            streams.remove(room_key);
+        }
+    }
+
+    /// Takes a room off air if nobody is listening, checking under the lock so that nobody can
+    /// subscribe to a stream that is ending. Returns whether it did.
+    fn remove_if_unheard(&self, room_key: &str, stream: &Arc<Stream>) -> bool {
+        let mut streams = self.streams.lock().unwrap();
+        if stream.frames.receiver_count() > 0 {
+            return false;
+        }
+        if streams
+            .get(room_key)
+            .is_some_and(|current| Arc::ptr_eq(current, stream))
+        {
+            streams.remove(room_key);
+        }
+        true
+    }
+}
+
+/// Mixes a room into its stream until the last listener leaves, or the stream is kicked.
+async fn run(state: Arc<ServerState>, room_key: String, stream: Arc<Stream>, mut encoder: Encoder) {
+    let hang_up = Arc::new(Notify::new());
+    let mut participant = match Participant::join(
+        &state,
+        &format!("live {room_key}"),
+        &room_key,
+        hang_up.clone(),
+    )
+    .await
+    {
+        Ok(participant) => participant,
+        Err(err) => {
+            warn!("Cannot put room on air: {err:?}");
+            state.broadcasts.remove(&room_key, &stream);
+            return;
+        }
+    };
+    info!("Room is on air");
+
+    let mut ticks = tokio::time::interval(participant::FRAME_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = hang_up.notified() => break,
+            _ = ticks.tick() => {}
+        }
+
+        if state.broadcasts.remove_if_unheard(&room_key, &stream) {
+            break;
+        }
+        let _ = stream.frames.send(encoder.frame(&participant.mix()));
+    }
+
+    state.broadcasts.remove(&room_key, &stream);
+    info!("Room is off air");
+}
+
+impl Encoder {
+    fn new() -> Self {
+        Self {
+            flac: flac::Encoder::new(participant::FRAME_SAMPLES as u16),
+            ogg: ogg::Stream::new(),
+            samples: 0,
+        }
+    }
+
+    /// The identification and comment header pages.
+    fn headers(&mut self) -> Bytes {
+        // The Ogg FLAC mapping's identification packet: version 1.0, with one more header
+        // packet to come, then FLAC's own signature and STREAMINFO.
+        let mut identification = b"\x7fFLAC\x01\x00\x00\x01fLaC".to_vec();
+        identification.extend(self.flac.stream_info(false));
+        let comment = flac::vorbis_comment("webtransport-voice-chat", true);
+
+        let mut headers = self
+            .ogg
+            .page(&identification, 0, ogg::BEGINNING_OF_STREAM)
+            .to_vec();
+        headers.extend_from_slice(&self.ogg.page(&comment, 0, 0));
+        headers.into()
+    }
+
+    fn frame(&mut self, pcm: &[i16]) -> Frame {
+        let flac = Bytes::from(self.flac.encode(pcm));
+        self.samples += pcm.len() as u64;
+        Frame {
+            page: self.ogg.page(&flac, self.samples, 0),
+            flac,
+        }
+    }
+}
+
+pub fn router(state: Arc<ServerState>) -> Router {
+    Router::new()
+        .route("/rooms/{room_key}/live.ogg", get(listen))
+        .with_state(state)
+}
+
+async fn listen(State(state): State<Arc<ServerState>>, Path(room_key): Path<String>) -> Response {
+    let on_air = state.config.broadcast.rooms.contains(&room_key)
+        && room_key.chars().count() <= MAX_ROOM_KEY_LEN;
+    if !on_air {
+        return (StatusCode::NOT_FOUND, "this room is not broadcast").into_response();
+    }
+
+    let subscription = Broadcasts::subscribe(&state, &room_key);
+    let headers = futures_util::stream::once(async move { Ok(subscription.headers) });
+    let pages = futures_util::stream::unfold(subscription.frames, |mut frames| async move {
+        loop {
+            match frames.recv().await {
+                Ok(frame) => return Some((Ok::<_, RecvError>(frame.page), frames)),
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    (
+        [(CONTENT_TYPE, "audio/ogg"), (CACHE_CONTROL, "no-cache")],
+        Body::from_stream(futures_util::StreamExt::chain(headers, pages)),
+    )
+        .into_response()
+}
+
+/// Keeps every configured Icecast mount fed, reconnecting whenever a source connection drops.
+pub fn push_to_icecast(state: &Arc<ServerState>) {
+    for mount in &state.config.broadcast.icecast {
+        let (state, mount) = (state.clone(), mount.clone());
+        let span = info_span!("Icecast", room_key = mount.room_key);
+        tokio::spawn(
+            async move {
+                loop {
+                    if let Err(err) = push(&state, &mount).await {
+                        warn!("Icecast source failed: {err:#}");
+                    }
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                }
+            }
+            .instrument(span),
+        );
+    }
+}
+
+/// Streams a room to an Icecast mount, as a source client using HTTP PUT.
+async fn push(state: &Arc<ServerState>, mount: &IcecastMount) -> Result<()> {
+    let rest = mount
+        .url
+        .strip_prefix("http://")
+        .context("Icecast URL must start with http://")?;
+    let (credentials, rest) = rest
+        .split_once('@')
+        .context("Icecast URL must include the source credentials")?;
+    let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+    let address = if authority.contains(':') {
+        authority.to_owned()
+    } else {
+        format!("{authority}:8000")
+    };
+
+    let mut connection = TcpStream::connect(&address)
+        .await
+        .with_context(|| format!("Cannot connect to Icecast at {address}"))?;
+    let request = format!(
+        "PUT {path} HTTP/1.1\r\nHost: {authority}\r\nAuthorization: Basic {}\r\n\
+         Content-Type: application/ogg\r\nIce-Name: {}\r\nIce-Public: 0\r\n\r\n",
+        BASE64_STANDARD.encode(credentials),
+        mount.room_key,
+    );
+    connection.write_all(request.as_bytes()).await?;
+
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() > MAX_ICECAST_RESPONSE_LEN {
+            bail!("Icecast sent a response header over {MAX_ICECAST_RESPONSE_LEN} bytes");
+        }
+        let byte = connection
+            .read_u8()
+            .await
+            .context("Icecast closed the connection")?;
+        response.push(byte);
+    }
+    let status = String::from_utf8_lossy(&response);
+    let status = status.lines().next().unwrap_or_default();
+    if status
+        .split(' ')
+        .nth(1)
+        .is_none_or(|code| !code.starts_with('2'))
+    {
+        bail!("Icecast refused the source: {status}");
+    }
+    info!("Streaming to {authority}{path}");
+
+    let mut subscription = Broadcasts::subscribe(state, &mount.room_key);
+    connection.write_all(&subscription.headers).await?;
+    loop {
+        match subscription.frames.recv().await {
+            Ok(frame) => connection.write_all(&frame.page).await?,
+            Err(RecvError::Lagged(skipped)) => warn!("Icecast fell {skipped} pages behind"),
+            Err(RecvError::Closed) => bail!("the room went off air"),
+        }
+    }
+}
+
+/// Records a room until `stopped` fires.
+async fn record(state: Arc<ServerState>, recording: Recording, stopped: oneshot::Receiver<()>) {
+    if let Err(err) = write_recording(&state, recording, stopped).await {
+        warn!("Recording failed: {err:#}");
+    }
+}
+
+/// Stores a room's stream in segments, from its header pages on, with who is in the room while
+/// it is recorded.
+async fn write_recording(
+    state: &Arc<ServerState>,
+    mut recording: Recording,
+    mut stopped: oneshot::Receiver<()>,
+) -> Result<()> {
+    let room_key = recording.room_key.clone();
+    let mut events = state.registry.events().subscribe();
+    recording.usernames = usernames(state, &room_key);
+    state
+        .recordings
+        .begin(&recording)
+        .await
+        .context("Cannot list the recording")?;
+    let mut subscription = Broadcasts::subscribe(state, &room_key);
+    let mut segment = subscription.headers.to_vec();
+    info!("Recording {}", recording.id);
+    loop {
+        tokio::select! {
+            frame = subscription.frames.recv() => match frame {
+                Ok(frame) => segment.extend_from_slice(&frame.page),
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("The recording fell {skipped} pages behind");
+                }
+                Err(RecvError::Closed) => {
+                    state.recordings.store(&recording.id, &segment, &recording.usernames).await?;
+                    bail!("the room went off air");
+                }
+            },
+            event = events.recv() => match event {
+                Ok(Event::Joined { room_key: joined, user }) if *joined == *room_key => {
+                    recording.usernames.insert(user.username);
+                }
+                Err(RecvError::Lagged(_)) => recording.usernames.extend(usernames(state, &room_key)),
+                _ => {}
+            },
+            _ = &mut stopped => {
+                state.recordings.store(&recording.id, &segment, &recording.usernames).await?;
+                info!("Recorded {}", recording.id);
+                return Ok(());
+            }
+        }
+
+        if segment.len() >= SEGMENT_LEN {
+            if !state
+                .recordings
+                .store(&recording.id, &segment, &recording.usernames)
+                .await?
+            {
+                info!("Recording {} was deleted, so it stops", recording.id);
+                return Ok(());
+            }
+            segment.clear();
+        }
+    }
+}
+
+/// The names of the users in a room.
+fn usernames(state: &ServerState, room_key: &str) -> BTreeSet<String> {
+    state
+        .registry
+        .room_roster(room_key)
+        .into_iter()
+        .map(|member| member.username)
+        .collect()
+}
+
+/// Keeps a room pushed to an RTMP endpoint until the push is stopped, reconnecting whenever the
+/// connection drops.
+async fn push_to_rtmp(state: Arc<ServerState>, room_key: String, url: String) {
+    loop {
+        if let Err(err) = publish(&state, &room_key, &url).await {
+            warn!("RTMP push failed: {err:#}");
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+/// Streams a room to an RTMP endpoint.
+async fn publish(state: &Arc<ServerState>, room_key: &str, url: &str) -> Result<()> {
+    let mut publisher = rtmp::Publisher::connect(url).await?;
+    info!("Publishing to RTMP");
+
+    let mut subscription = Broadcasts::subscribe(state, room_key);
+    publisher.start_audio(&subscription.stream_info).await?;
+    let mut timestamp_ms = 0;
+    loop {
+        let frame = tokio::select! {
+            result = publisher.closed() => return result,
+            frame = subscription.frames.recv() => frame,
+        };
+        match frame {
+            Ok(frame) => publisher.send_frame(timestamp_ms, &frame.flac).await?,
+            Err(RecvError::Lagged(skipped)) => {
+                warn!("RTMP fell {skipped} frames behind");
+                timestamp_ms += skipped as u32 * participant::FRAME_INTERVAL.as_millis() as u32;
+                continue;
+            }
+            Err(RecvError::Closed) => bail!("the room went off air"),
+        }
+        timestamp_ms += participant::FRAME_INTERVAL.as_millis() as u32;
+    }
+}
@@ -0,0 +1,119 @@
+//! A shared pool of reusable read buffers.
+//!
+//! Sessions only hold a buffer while reading a control packet from a stream, so idle sessions
+//! cost no buffer memory and busy ones reuse buffers instead of allocating their own. While
+//! memory is over the soft limit, returned buffers are freed instead of kept.
+
+use std::ops::Deref;
+use std::ops::DerefMut;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+use crate::memory::Charge;
+use crate::memory::MEMORY;
+
+pub struct BufferPool {
+    buffers: Mutex<Vec<(Vec<u8>, Charge)>>,
+    buffer_len: usize,
+    max_pooled: usize,
+    in_use: AtomicUsize,
+}
+
+impl BufferPool {
+    /// Creates a pool of `buffer_len`-byte buffers, keeping at most `max_pooled` idle buffers.
+    pub fn new(buffer_len: usize, max_pooled: usize) -> Arc<Self> {
+        Arc::new(Self {
+            buffers: Mutex::new(Vec::new()),
+            buffer_len,
+            max_pooled,
+            in_use: AtomicUsize::new(0),
+        })
+    }
+
+    /// Takes a buffer from the pool, allocating one if the pool is empty.
+    pub fn acquire(self: &Arc<Self>) -> PooledBuffer {
+        let (buffer, charge) = self.buffers.lock().unwrap().pop().unwrap_or_else(|| {
+            (
+                vec![0; self.buffer_len],
+                Charge::new(&MEMORY.read_buffers, self.buffer_len),
+            )
+        });
+        self.in_use.fetch_add(1, Ordering::Relaxed);
+
+        PooledBuffer {
+            buffer,
+            charge,
+            pool: self.clone(),
+        }
+    }
+
+    /// How many buffers are kept in the pool.
+    pub fn idle(&self) -> usize {
+        self.buffers.lock().unwrap().len()
+    }
+
+    /// How many buffers are borrowed from the pool.
+    pub fn in_use(&self) -> usize {
+        self.in_use.load(Ordering::Relaxed)
+    }
+
+    fn release(&self, mut buffer: Vec<u8>, mut charge: Charge) {
+        self.in_use.fetch_sub(1, Ordering::Relaxed);
+        if MEMORY.over_soft_limit() {
+            return;
+        }
+
+        // Buffers grown for oversized packets go back to the pool at their original size.
+        buffer.resize(self.buffer_len, 0);
+        buffer.shrink_to(self.buffer_len);
+        charge.set(buffer.capacity());
+
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < self.max_pooled {
+            buffers.push((buffer, charge));
+        }
+    }
+}
+
+/// A buffer borrowed from a [`BufferPool`], returned to it when dropped.
+pub struct PooledBuffer {
+    buffer: Vec<u8>,
+    charge: Charge,
+    pool: Arc<BufferPool>,
+}
+
+impl PooledBuffer {
+    /// Grows the buffer to `len` bytes, for packets larger than the pool's buffer size.
+    pub fn grow(&mut self, len: usize) {
+        if len > self.buffer.len() {
+            self.buffer.resize(len, 0);
+            self.charge.set(self.buffer.capacity());
+        }
+    }
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buffer
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buffer
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        let charge = Charge::new(&MEMORY.read_buffers, 0);
+        self.pool.release(
+            std::mem::take(&mut self.buffer),
+            std::mem::replace(&mut self.charge, charge),
+        );
+    }
+}
@@ -0,0 +1,62 @@
+//! Chaos mode: failures injected at random, to test how clients reconnect and how the server
+//! cleans up when sessions end badly.
+//!
+//! The failures are only built in with the `chaos` feature (`cargo run --features chaos`), and
+//! happen once rates are set in the config's `chaos` section, which is reloadable. Sessions are
+//! closed as if the server were shutting down, voice frames are held back before they are
+//! forwarded to the room's other members, and control packets from clients are dropped before
+//! they are handled. Closed sessions and dropped packets are logged as warnings, and held back
+//! frames at debug level.
+
+#[cfg(feature = "chaos")]
+use std::time::Duration;
+
+use anyhow::Result;
+use anyhow::bail;
+
+#[cfg(feature = "chaos")]
+use crate::config::ChaosConfig;
+use crate::config::Config;
+
+/// Checks the rates, and that a server without the feature is not asked for chaos.
+pub fn validate(config: &Config) -> Result<()> {
+    let chaos = &config.chaos;
+    let rates = [
+        ("close_rate", chaos.close_rate),
+        ("delay_rate", chaos.delay_rate),
+        ("drop_rate", chaos.drop_rate),
+    ];
+    for (name, rate) in rates {
+        if !(0.0..=1.0).contains(&rate) {
+            bail!("chaos.{name} must be from 0 to 1");
+        }
+    }
+    if !cfg!(feature = "chaos") && rates.iter().any(|&(_, rate)| rate > 0.0) {
+        bail!("chaos needs a server built with the chaos feature");
+    }
+    Ok(())
+}
+
+/// Whether to close a session this second.
+#[cfg(feature = "chaos")]
+pub fn close_session(config: &ChaosConfig) -> bool {
+    roll(config.close_rate)
+}
+
+/// Whether to drop a control packet.
+#[cfg(feature = "chaos")]
+pub fn drop_control(config: &ChaosConfig) -> bool {
+    roll(config.drop_rate)
+}
+
+/// How long to hold back a voice frame, if at all.
+#[cfg(feature = "chaos")]
+pub fn forward_delay(config: &ChaosConfig) -> Option<Duration> {
+    roll(config.delay_rate)
+        .then(|| Duration::from_millis(rand::random_range(0..=config.max_delay_ms)))
+}
+
+#[cfg(feature = "chaos")]
+fn roll(rate: f64) -> bool {
+    rate > 0.0 && rand::random::<f64>() < rate
+}
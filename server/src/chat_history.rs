@@ -0,0 +1,273 @@
+//! Each room's recent chat messages, sent to clients that join it so they have context.
+//!
+//! The last `chat.history_len` messages delivered in each room are kept, for at most
+//! `chat.history_rooms` rooms, and sent to clients that negotiated `FEATURE_CHAT_HISTORY` right
+//! after their JOIN_ROOM_RESPONSE, with `history` set. With `chat.history_path`, every message is
+//! also appended to that file, which is read back and compacted to what is kept at startup, so
+//! the history survives restarts. Users' data requests export and anonymize their messages there
+//! as in the event log. Attachments are kept with the links they were shared with, which may have
+//! expired by the time a client joins.
+//!
+//! Each room's messages are also chained by `previous_message_id`, and delivered one at a time
+//! in that order, so clients can tell they missed some and ask for them with a
+//! CHAT_BACKLOG_REQUEST.
+
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use anyhow::Context;
+use anyhow::Result;
+use protobuf::system::ChatMessage;
+use protobuf::system::FileAttachment;
+use serde_json::Value;
+use serde_json::json;
+use tracing::info;
+use tracing::warn;
+
+use crate::config::ChatConfig;
+use crate::json_log::JsonLog;
+use crate::memory::Charge;
+use crate::memory::MEMORY;
+use crate::tenants::Scoped;
+
+pub struct ChatHistory {
+    len: usize,
+    max_rooms: usize,
+    rooms: Mutex<HashMap<String, Room>>,
+    file: Option<JsonLog>,
+}
+
+/// A room's kept messages, oldest first.
+#[derive(Default)]
+struct Room {
+    messages: VecDeque<Kept>,
+
+    /// The ID of the room's last message, kept even with no messages kept.
+    last_message_id: u64,
+
+    /// When the room's last message was delivered, to forget the quietest room first.
+    last_sent_at_ms: u64,
+
+    /// Held while a message is queued for the room's members, so they receive the room's
+    /// messages in the order they are chained in.
+    delivery: Arc<Mutex<()>>,
+}
+
+struct Kept {
+    message: ChatMessage,
+    _charge: Charge,
+}
+
+impl ChatHistory {
+    pub fn open(config: &ChatConfig) -> Result<Self> {
+        let mut history = Self {
+            len: config.history_len,
+            max_rooms: config.history_rooms.max(1),
+            rooms: Mutex::default(),
+            file: None,
+        };
+        if history.len > 0
+            && let Some(path) = &config.history_path
+        {
+            history.load(path)?;
+        }
+        Ok(history)
+    }
+
+    /// Keeps the messages in the file at `path`, and compacts it to them.
+    fn load(&mut self, path: &Path) -> Result<()> {
+        let file = JsonLog::open(path).context("Cannot open the chat history")?;
+        let mut loaded = 0;
+        for entry in file.entries()? {
+            // Anonymized messages have no text left to show.
+            if let Some((room_key, message)) = from_entry(&entry) {
+                self.keep(room_key, message);
+                loaded += 1;
+            }
+        }
+
+        let rooms = self.rooms.get_mut().unwrap();
+        let mut entries: Vec<(u64, Value)> = rooms
+            .iter()
+            .flat_map(|(room_key, room)| {
+                room.messages
+                    .iter()
+                    .map(|kept| (kept.message.sent_at_ms, entry(room_key, &kept.message)))
+            })
+            .collect();
+        entries.sort_by_key(|(sent_at_ms, _)| *sent_at_ms);
+        let entries: Vec<Value> = entries.into_iter().map(|(_, entry)| entry).collect();
+        file.replace(&entries)?;
+        info!(
+            "Loaded {} of {loaded} chat messages in {} rooms from {}",
+            entries.len(),
+            rooms.len(),
+            path.display()
+        );
+        self.file = Some(file);
+        Ok(())
+    }
+
+    /// The highest message ID kept, for IDs to continue after it.
+    pub fn last_message_id(&self) -> u64 {
+        self.rooms
+            .lock()
+            .unwrap()
+            .values()
+            .flat_map(|room| &room.messages)
+            .map(|kept| kept.message.message_id)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// What to hold while delivering a message in a room.
+    pub fn delivery(&self, room_key: &str) -> Arc<Mutex<()>> {
+        let mut rooms = self.rooms.lock().unwrap();
+        self.room(&mut rooms, room_key).delivery.clone()
+    }
+
+    /// Keeps a message about to be delivered in a room, chaining it to the room's last message.
+    pub fn record(&self, room_key: &str, message: &mut ChatMessage) {
+        {
+            let mut rooms = self.rooms.lock().unwrap();
+            let room = self.room(&mut rooms, room_key);
+            message.previous_message_id = room.last_message_id;
+            room.last_message_id = message.message_id;
+        }
+        if self.len == 0 {
+            return;
+        }
+        self.keep(room_key, message.clone());
+        if let Some(file) = &self.file
+            && let Err(err) = file.append(&entry(room_key, message))
+        {
+            warn!("Cannot write to the chat history: {err}");
+        }
+    }
+
+    /// A room's entry, forgetting the quietest room to make space for it if there is none.
+    fn room<'a>(&self, rooms: &'a mut HashMap<String, Room>, room_key: &str) -> &'a mut Room {
+        if !rooms.contains_key(room_key) && rooms.len() >= self.max_rooms {
+            let quietest = rooms
+                .iter()
+                .min_by_key(|(_, room)| room.last_sent_at_ms)
+                .map(|(room_key, _)| room_key.clone());
+            if let Some(quietest) = quietest {
+                rooms.remove(&quietest);
+            }
+        }
+        rooms.entry(room_key.to_owned()).or_default()
+    }
+
+    fn keep(&self, room_key: &str, message: ChatMessage) {
+        let mut rooms = self.rooms.lock().unwrap();
+        let room = self.room(&mut rooms, room_key);
+        room.last_message_id = room.last_message_id.max(message.message_id);
+        room.last_sent_at_ms = room.last_sent_at_ms.max(message.sent_at_ms);
+        let bytes = message.username.len() + message.text.len();
+        room.messages.push_back(Kept {
+            message,
+            _charge: Charge::new(&MEMORY.chat_history, bytes),
+        });
+        while room.messages.len() > self.len {
+            room.messages.pop_front();
+        }
+    }
+
+    /// The messages kept for a room after the one with ID `after_message_id`, or all of them
+    /// for 0, oldest first, marked as history.
+    pub fn recent(&self, room_key: &str, after_message_id: u64) -> Vec<ChatMessage> {
+        let rooms = self.rooms.lock().unwrap();
+        let Some(room) = rooms.get(room_key) else {
+            return Vec::new();
+        };
+        room.messages
+            .iter()
+            .filter(|kept| kept.message.message_id > after_message_id)
+            .map(|kept| ChatMessage {
+                history: true,
+                ..kept.message.clone()
+            })
+            .collect()
+    }
+
+    /// The messages a user wrote that are kept, in the file if there is one.
+    pub fn export_user(&self, user: Scoped) -> Result<Value> {
+        if let Some(file) = &self.file {
+            return Ok(file.find(user)?.into());
+        }
+        let rooms = self.rooms.lock().unwrap();
+        let entries: Vec<Value> = rooms
+            .iter()
+            .filter(|(room_key, _)| user.owns(room_key))
+            .flat_map(|(room_key, room)| {
+                room.messages
+                    .iter()
+                    .filter(|kept| kept.message.username == user.name)
+                    .map(|kept| entry(room_key, &kept.message))
+            })
+            .collect();
+        Ok(entries.into())
+    }
+
+    /// Forgets the messages a user wrote, and anonymizes them in the file.
+    pub fn erase_user(&self, user: Scoped) -> Result<()> {
+        for (room_key, room) in self.rooms.lock().unwrap().iter_mut() {
+            if user.owns(room_key) {
+                room.messages
+                    .retain(|kept| kept.message.username != user.name);
+            }
+        }
+        if let Some(file) = &self.file {
+            file.anonymize(user)?;
+        }
+        Ok(())
+    }
+}
+
+fn entry(room_key: &str, message: &ChatMessage) -> Value {
+    let mut entry = json!({
+        "sent_at_ms": message.sent_at_ms,
+        "room_key": room_key,
+        "session_id": message.session_id,
+        "username": message.username,
+        "message_id": message.message_id,
+        "previous_message_id": message.previous_message_id,
+        "text": message.text,
+    });
+    if let Some(attachment) = &message.attachment {
+        entry["attachment"] = json!({
+            "name": attachment.name,
+            "content_type": attachment.content_type,
+            "size": attachment.size,
+            "url": attachment.url,
+        });
+    }
+    entry
+}
+
+fn from_entry(entry: &Value) -> Option<(&str, ChatMessage)> {
+    let string = |value: &Value| value.as_str().map(str::to_owned);
+    let attachment = &entry["attachment"];
+    let attachment = attachment.is_object().then(|| FileAttachment {
+        name: string(&attachment["name"]).unwrap_or_default(),
+        content_type: string(&attachment["content_type"]).unwrap_or_default(),
+        size: attachment["size"].as_u64().unwrap_or_default(),
+        url: string(&attachment["url"]).unwrap_or_default(),
+    });
+    let message = ChatMessage {
+        session_id: entry["session_id"].as_i64()?,
+        username: string(&entry["username"])?,
+        text: string(&entry["text"])?,
+        message_id: entry["message_id"].as_u64()?,
+        attachment,
+        sent_at_ms: entry["sent_at_ms"].as_u64()?,
+        history: false,
+        client_message_id: 0,
+        previous_message_id: entry["previous_message_id"].as_u64().unwrap_or_default(),
+    };
+    Some((entry["room_key"].as_str()?, message))
+}
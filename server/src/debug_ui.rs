@@ -0,0 +1,108 @@
+//! A debug page for operators at `/debug`: the live sessions with their stats, and the tail of
+//! the log as it is written.
+//!
+//! Browsers cannot send a bearer token when loading a page or with `EventSource`, so the page
+//! itself holds nothing: it asks for a token and fetches `/debug/sessions` (which needs
+//! `rooms:read`) and the `/debug/logs` event stream (which needs `debug:logs`) with it.
+
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use axum::Json;
+use axum::Router;
+use axum::extract::State;
+use axum::http::HeaderMap;
+use axum::http::StatusCode;
+use axum::response::Html;
+use axum::response::Sse;
+use axum::response::sse::Event;
+use axum::response::sse::KeepAlive;
+use axum::routing::get;
+use futures_util::Stream;
+use serde_json::Value;
+use serde_json::json;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::admin;
+use crate::api_keys::Permission;
+use crate::log_tail;
+use crate::metrics::METRICS;
+use crate::state::ServerState;
+
+const PAGE: &str = include_str!("debug_ui.html");
+
+pub fn router(state: Arc<ServerState>) -> Router {
+    Router::new()
+        .route("/debug", get(|| async { Html(PAGE) }))
+        .route("/debug/sessions", get(sessions))
+        .route("/debug/logs", get(logs))
+        .with_state(state)
+}
+
+/// The sessions the caller may see, with their latency estimates added to what
+/// `ListSessions` returns.
+async fn sessions(
+    State(state): State<Arc<ServerState>>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, (StatusCode, &'static str)> {
+    let caller = admin::authorize(&state, &headers, Permission::RoomsRead)?;
+    let sessions: Vec<Value> = admin::list_sessions(&state, &caller.scope)
+        .sessions
+        .into_iter()
+        .map(|session| {
+            let metrics = METRICS.session(session.session_id);
+            let latency = metrics.as_ref().map(|metrics| &metrics.latency);
+            let ms = |us: Option<i64>| us.map(|us| us as f64 / 1e3);
+            json!({
+                // Session IDs do not fit in JavaScript numbers.
+                "session_id": session.session_id.to_string(),
+                "username": session.username,
+                "room_key": session.room_key,
+                "qoe_score": session.qoe_score,
+                "rtt_ms": session.rtt_us.map(|rtt| rtt as f64 / 1e3),
+                "uplink_ms": ms(latency.and_then(|latency| latency.uplink.get())),
+                "downlink_ms": ms(latency.and_then(|latency| latency.downlink())),
+                "bytes_sent": session.bytes_sent,
+                "bytes_received": session.bytes_received,
+                "muted": session.muted,
+                "audio_on_streams": session.audio_on_streams,
+                "country": session.country,
+                "asn": session.asn,
+                "as_organization": session.as_organization,
+                "drops": session.drops.map(|drops| json!({
+                    "rate_limited": drops.rate_limited,
+                    "queue_full": drops.queue_full,
+                    "stale": drops.stale,
+                    "malformed": drops.malformed,
+                    "oversized": drops.oversized,
+                })),
+            })
+        })
+        .collect();
+    Ok(Json(json!({ "sessions": sessions })))
+}
+
+/// The log lines kept, then each new one, as server-sent events of one JSON line each.
+async fn logs(
+    State(state): State<Arc<ServerState>>,
+    headers: HeaderMap,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, (StatusCode, &'static str)> {
+    admin::authorize(&state, &headers, Permission::DebugLogs)?;
+
+    let (kept, receiver) = log_tail::follow();
+    let kept = futures_util::stream::iter(kept);
+    let new = futures_util::stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(line) => return Some((line, receiver)),
+                // A follower too slow to keep up misses lines rather than holding the log.
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+    let events = futures_util::StreamExt::map(futures_util::StreamExt::chain(kept, new), |line| {
+        Ok(Event::default().data(line))
+    });
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
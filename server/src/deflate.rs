@@ -0,0 +1,385 @@
+//! A DEFLATE (RFC 1951) compressor, for the control packets sent COMPRESSED to clients that
+//! negotiated `FEATURE_COMPRESSION`.
+//!
+//! Repeats are found with LZ77 over hash chains and coded, like the literals between them, with
+//! DEFLATE's fixed Huffman codes in a single block. That gives up a little of what dynamic codes
+//! would save, but the usernames, room keys and links that make control packets large repeat
+//! often enough for matches to do most of the work. Browsers inflate the result with
+//! `DecompressionStream("deflate-raw")`. Nothing is inflated here, since clients send no
+//! compressed packets.
+
+/// How far back matches may start.
+const WINDOW: usize = 32 * 1024;
+
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+
+/// How many earlier positions with the same hash are tried for each match.
+const MAX_CHAIN: usize = 64;
+
+const HASH_BITS: u32 = 15;
+
+/// The shortest length of each length code, from 257, and how many extra bits follow it.
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+
+/// The shortest distance of each distance code, and how many extra bits follow it.
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/// The end-of-block symbol.
+const END_OF_BLOCK: u16 = 256;
+
+/// Compresses `data` into a raw DEFLATE stream, without a zlib or gzip wrapper.
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = BitWriter::with_capacity(data.len() / 2);
+    // One final block, with fixed Huffman codes.
+    out.write(1, 1);
+    out.write(1, 2);
+
+    let mut matcher = Matcher::new(data);
+    let mut pos = 0;
+    while pos < data.len() {
+        match matcher.longest_match(pos) {
+            Some((len, distance)) => {
+                write_length(&mut out, len);
+                write_distance(&mut out, distance);
+                for at in pos..pos + len {
+                    matcher.insert(at);
+                }
+                pos += len;
+            }
+            None => {
+                write_symbol(&mut out, data[pos].into());
+                matcher.insert(pos);
+                pos += 1;
+            }
+        }
+    }
+    write_symbol(&mut out, END_OF_BLOCK);
+    out.finish()
+}
+
+/// The earlier positions of each 3-byte sequence, newest first.
+struct Matcher<'a> {
+    data: &'a [u8],
+
+    /// The newest position of each hash.
+    head: Vec<Option<usize>>,
+
+    /// The previous position with the same hash as each position.
+    previous: Vec<Option<usize>>,
+}
+
+impl<'a> Matcher<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            head: vec![None; 1 << HASH_BITS],
+            previous: vec![None; data.len()],
+        }
+    }
+
+    fn hash(&self, pos: usize) -> usize {
+        let bytes = &self.data[pos..pos + MIN_MATCH];
+        let key = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0]);
+        (key.wrapping_mul(0x9E37_79B1) >> (32 - HASH_BITS)) as usize
+    }
+
+    fn insert(&mut self, pos: usize) {
+        if pos + MIN_MATCH > self.data.len() {
+            return;
+        }
+        let hash = self.hash(pos);
+        self.previous[pos] = self.head[hash].replace(pos);
+    }
+
+    /// The longest earlier repeat of the bytes at `pos`, as its length and distance back.
+    fn longest_match(&self, pos: usize) -> Option<(usize, usize)> {
+        if pos + MIN_MATCH > self.data.len() {
+            return None;
+        }
+        let max_len = (self.data.len() - pos).min(MAX_MATCH);
+        let mut best: Option<(usize, usize)> = None;
+        let mut candidate = self.head[self.hash(pos)];
+        for _ in 0..MAX_CHAIN {
+            let Some(start) = candidate else {
+                break;
+            };
+            if pos - start > WINDOW {
+                break;
+            }
+            let len = self.data[start..]
+                .iter()
+                .zip(&self.data[pos..pos + max_len])
+                .take_while(|(a, b)| a == b)
+                .count();
+            if len >= MIN_MATCH && best.is_none_or(|(best_len, _)| len > best_len) {
+                best = Some((len, pos - start));
+                if len == max_len {
+                    break;
+                }
+            }
+            candidate = self.previous[start];
+        }
+        best
+    }
+}
+
+/// Writes a literal or length symbol with its fixed Huffman code.
+fn write_symbol(out: &mut BitWriter, symbol: u16) {
+    let (code, len) = match symbol {
+        0..=143 => (0x30 + symbol, 8),
+        144..=255 => (0x190 + symbol - 144, 9),
+        256..=279 => (symbol - 256, 7),
+        _ => (0xC0 + symbol - 280, 8),
+    };
+    out.write_code(code.into(), len);
+}
+
+fn write_length(out: &mut BitWriter, len: usize) {
+    let index = LENGTH_BASE
+        .iter()
+        .rposition(|&base| usize::from(base) <= len)
+        .unwrap();
+    write_symbol(out, 257 + index as u16);
+    out.write(
+        (len - usize::from(LENGTH_BASE[index])) as u32,
+        LENGTH_EXTRA[index].into(),
+    );
+}
+
+fn write_distance(out: &mut BitWriter, distance: usize) {
+    let index = DISTANCE_BASE
+        .iter()
+        .rposition(|&base| usize::from(base) <= distance)
+        .unwrap();
+    // Distance codes are all five bits long.
+    out.write_code(index as u32, 5);
+    out.write(
+        (distance - usize::from(DISTANCE_BASE[index])) as u32,
+        DISTANCE_EXTRA[index].into(),
+    );
+}
+
+/// Packs bits least significant first, as DEFLATE reads them.
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    fn with_capacity(capacity: usize) -> Self {
+        Self {
+            out: Vec::with_capacity(capacity),
+            bits: 0,
+            count: 0,
+        }
+    }
+
+    /// Writes the low `count` bits of `value`, least significant first.
+    fn write(&mut self, value: u32, count: u32) {
+        self.bits |= u64::from(value) << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// Writes a Huffman code of `len` bits, which DEFLATE packs most significant bit first.
+    fn write_code(&mut self, code: u32, len: u32) {
+        self.write(code.reverse_bits() >> (32 - len), len);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.out.push(self.bits as u8);
+        }
+        self.out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DISTANCE_BASE;
+    use super::DISTANCE_EXTRA;
+    use super::LENGTH_BASE;
+    use super::LENGTH_EXTRA;
+    use super::MAX_MATCH;
+    use super::WINDOW;
+    use super::compress;
+
+    /// Reads bits least significant first, as [`super::BitWriter`] packs them.
+    struct BitReader<'a> {
+        data: &'a [u8],
+        pos: usize,
+    }
+
+    impl BitReader<'_> {
+        fn bit(&mut self) -> u32 {
+            let byte = self.data[self.pos / 8];
+            let bit = (byte >> (self.pos % 8)) & 1;
+            self.pos += 1;
+            bit.into()
+        }
+
+        fn bits(&mut self, count: u8) -> usize {
+            (0..count).fold(0, |value, at| value | (self.bit() as usize) << at)
+        }
+
+        /// A Huffman code of `len` bits, which DEFLATE packs most significant bit first.
+        fn code(&mut self, len: u32) -> u32 {
+            (0..len).fold(0, |code, _| code << 1 | self.bit())
+        }
+
+        /// A literal or length symbol in the fixed Huffman code.
+        fn symbol(&mut self) -> u16 {
+            let code = self.code(7);
+            if code <= 0b001_0111 {
+                return 256 + code as u16;
+            }
+            let code = code << 1 | self.bit();
+            match code {
+                0x30..=0xBF => (code - 0x30) as u16,
+                0xC0..=0xC7 => (280 + code - 0xC0) as u16,
+                _ => (144 + (code << 1 | self.bit()) - 0x190) as u16,
+            }
+        }
+    }
+
+    /// Inflates a raw DEFLATE stream of fixed Huffman blocks, returning the data and the
+    /// matches it was coded with, as lengths and distances.
+    fn inflate(data: &[u8]) -> (Vec<u8>, Vec<(usize, usize)>) {
+        let mut reader = BitReader { data, pos: 0 };
+        let mut out = Vec::new();
+        let mut matches = Vec::new();
+        loop {
+            let last = reader.bits(1) == 1;
+            assert_eq!(reader.bits(2), 1, "not a fixed Huffman block");
+            loop {
+                let symbol = reader.symbol();
+                match symbol {
+                    0..=255 => out.push(symbol as u8),
+                    256 => break,
+                    _ => {
+                        let index = usize::from(symbol - 257);
+                        let len =
+                            usize::from(LENGTH_BASE[index]) + reader.bits(LENGTH_EXTRA[index]);
+                        let index = reader.code(5) as usize;
+                        let distance =
+                            usize::from(DISTANCE_BASE[index]) + reader.bits(DISTANCE_EXTRA[index]);
+                        assert!(distance <= out.len(), "a match before the start");
+                        // Byte by byte, since a match may overlap what it repeats.
+                        let start = out.len() - distance;
+                        for at in start..start + len {
+                            out.push(out[at]);
+                        }
+                        matches.push((len, distance));
+                    }
+                }
+            }
+            if last {
+                return (out, matches);
+            }
+        }
+    }
+
+    /// `len` bytes that do not repeat, from a xorshift generator.
+    fn noise(len: usize, mut seed: u64) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                seed as u8
+            })
+            .collect()
+    }
+
+    fn round_trip(data: &[u8]) -> Vec<(usize, usize)> {
+        let (inflated, matches) = inflate(&compress(data));
+        assert_eq!(inflated, data);
+        matches
+    }
+
+    #[test]
+    fn inflates_zlib_fixed_blocks() {
+        // Written by zlib with its fixed Huffman strategy, to check the inflater against.
+        let cases: [(&str, &[u8]); 3] = [
+            ("4b0400", b"a"),
+            ("cb48cdc9c957c8402701", b"hello hello hello hello"),
+            ("4b4c1c05c40200", &[b'a'; 300]),
+        ];
+        for (hex, data) in cases {
+            let bytes: Vec<u8> = (0..hex.len())
+                .step_by(2)
+                .map(|at| u8::from_str_radix(&hex[at..at + 2], 16).unwrap())
+                .collect();
+            assert_eq!(inflate(&bytes).0, data);
+        }
+    }
+
+    #[test]
+    fn compresses_a_byte_as_zlib_does() {
+        assert_eq!(compress(b"a"), [0x4B, 0x04, 0x00]);
+    }
+
+    #[test]
+    fn round_trips() {
+        round_trip(b"");
+        round_trip(b"ab");
+        round_trip(&noise(5000, 1));
+        let text = "alice joined the lobby; bob joined the lobby; carol joined the lobby";
+        assert!(!round_trip(text.as_bytes()).is_empty());
+        assert!(compress(text.as_bytes()).len() < text.len());
+    }
+
+    #[test]
+    fn overlapping_matches() {
+        // Each run repeats the byte or pair just before it, so its match overlaps itself.
+        let mut data = vec![b'a'; 1000];
+        data.extend(b"xy".repeat(500));
+        let matches = round_trip(&data);
+        assert!(matches.contains(&(MAX_MATCH, 1)));
+        assert!(matches.contains(&(MAX_MATCH, 2)));
+    }
+
+    #[test]
+    fn longest_matches() {
+        let block = noise(MAX_MATCH + 10, 2);
+        let data = [block.as_slice(), &block].concat();
+        let matches = round_trip(&data);
+        assert_eq!(matches[0], (MAX_MATCH, block.len()));
+    }
+
+    #[test]
+    fn matches_across_the_window() {
+        let block = noise(WINDOW, 3);
+        let data = [block.as_slice(), &block[..300]].concat();
+        let matches = round_trip(&data);
+        assert!(
+            matches.contains(&(MAX_MATCH, WINDOW)),
+            "no match {WINDOW} bytes back"
+        );
+
+        // One further back is out of the window.
+        let block = noise(WINDOW + 1, 4);
+        let data = [block.as_slice(), &block[..300]].concat();
+        let matches = round_trip(&data);
+        assert!(matches.iter().all(|&(_, distance)| distance <= WINDOW));
+    }
+}
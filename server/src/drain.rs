@@ -0,0 +1,128 @@
+//! Draining the server for a rolling deploy.
+//!
+//! Once draining, the server refuses new sessions and redirects `/config.json` to the address it
+//! drains to, if it has one, so clients that reconnect find the server replacing it. Clients in a
+//! room that negotiated `FEATURE_MIGRATION` are sent `JOIN_ROOM_REDIRECT` to that address at
+//! once, clients that negotiated `FEATURE_REDIRECTS` are redirected there when they join, and
+//! others stay until they leave. Sessions handed over to the server taking over, as described in
+//! [`handoff`](crate::handoff), are sent a resume token with their redirect. The server exits
+//! once its last session ends, or once the drain's timeout elapses, closing the connections left
+//! with `CLOSE_CODE_SERVER_SHUTDOWN`.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+use tokio::sync::watch;
+use tracing::info;
+
+use crate::rooms::Registry;
+use crate::rooms::SessionId;
+
+/// How long a drain lasts when no timeout is given.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// How often a drain checks whether sessions are left.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Where a drain sends clients, and until when.
+#[derive(Clone)]
+pub struct Target {
+    /// The HTTP address of the server taking over, whose `/config.json` describes how to
+    /// connect to it.
+    pub address: Option<Arc<str>>,
+
+    deadline: Instant,
+
+    /// The tokens the sessions handed over resume with there.
+    tokens: HashMap<SessionId, Arc<str>>,
+}
+
+/// Whether the server is draining, for sessions and the servers to follow.
+pub struct Drain {
+    target: watch::Sender<Option<Target>>,
+}
+
+impl Default for Drain {
+    fn default() -> Self {
+        Self {
+            target: watch::Sender::new(None),
+        }
+    }
+}
+
+impl Drain {
+    /// Starts draining to `address`, if given, for at most `timeout`, with the `tokens` of the
+    /// sessions handed over there. Returns `false` if the server is already draining.
+    pub fn begin(
+        &self,
+        address: Option<&str>,
+        timeout: Duration,
+        tokens: HashMap<SessionId, Arc<str>>,
+    ) -> bool {
+        self.target.send_if_modified(|target| {
+            if target.is_some() {
+                return false;
+            }
+            info!(
+                "Draining to {} for at most {} seconds",
+                address.unwrap_or("nowhere"),
+                timeout.as_secs()
+            );
+            *target = Some(Target {
+                address: address.map(|address| address.trim_end_matches('/').into()),
+                deadline: Instant::now() + timeout,
+                tokens,
+            });
+            true
+        })
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.target.borrow().is_some()
+    }
+
+    /// The address the server drains to, if it is draining to one.
+    pub fn address(&self) -> Option<Arc<str>> {
+        self.target.borrow().as_ref()?.address.clone()
+    }
+
+    /// The token a session handed over resumes with, if it was.
+    pub fn resume_token(&self, session_id: SessionId) -> Option<Arc<str>> {
+        self.target
+            .borrow()
+            .as_ref()?
+            .tokens
+            .get(&session_id)
+            .cloned()
+    }
+
+    /// Follows the drain, which changes once, when draining starts.
+    pub fn subscribe(&self) -> watch::Receiver<Option<Target>> {
+        self.target.subscribe()
+    }
+
+    /// Completes once the server is drained: it is draining, and no session is left or the
+    /// timeout elapsed.
+    pub async fn drained(&self, registry: &Registry) {
+        let mut target = self.target.subscribe();
+        let deadline = match target.wait_for(Option::is_some).await {
+            Ok(target) => target.as_ref().unwrap().deadline,
+            Err(_) => return std::future::pending().await,
+        };
+
+        loop {
+            let sessions = registry.sessions().len();
+            if sessions == 0 {
+                info!("Drained: no sessions left");
+                return;
+            }
+            if Instant::now() >= deadline {
+                info!("Drain timed out with {sessions} sessions left");
+                return;
+            }
+            tokio::time::sleep(CHECK_INTERVAL).await;
+        }
+    }
+}
@@ -0,0 +1,231 @@
+//! PCM summation, clipping and level metering for the mixer.
+//!
+//! On x86_64 these use SSE2, which every x86_64 CPU has, and elsewhere they fall back to
+//! scalar loops. Both produce identical results.
+
+/// Adds `pcm` into the running `total`, sample by sample.
+pub fn accumulate(total: &mut [i32], pcm: &[i16]) {
+    let len = total.len().min(pcm.len());
+    let (total, pcm) = (&mut total[..len], &pcm[..len]);
+
+    // SAFETY: SSE2 is part of the x86_64 baseline.
+    #[cfg(target_arch = "x86_64")]
+    let done = unsafe { sse2::accumulate(total, pcm) };
+    #[cfg(not(target_arch = "x86_64"))]
+    let done = 0;
+
+    for (sum, sample) in total[done..].iter_mut().zip(&pcm[done..]) {
+        *sum += i32::from(*sample);
+    }
+}
+
+/// Writes `total - own` into `out`, saturating each sample to the `i16` range. Without `own`,
+/// writes `total` itself.
+pub fn mix_down(total: &[i32], own: Option<&[i16]>, out: &mut [i16]) {
+    let len = match own {
+        Some(own) => total.len().min(own.len()).min(out.len()),
+        None => total.len().min(out.len()),
+    };
+    let (total, own, out) = (&total[..len], own.map(|own| &own[..len]), &mut out[..len]);
+
+    // SAFETY: SSE2 is part of the x86_64 baseline.
+    #[cfg(target_arch = "x86_64")]
+    let done = unsafe { sse2::mix_down(total, own, out) };
+    #[cfg(not(target_arch = "x86_64"))]
+    let done = 0;
+
+    for i in done..len {
+        let own_sample = own.map_or(0, |own| i32::from(own[i]));
+        out[i] = (total[i] - own_sample).clamp(i16::MIN.into(), i16::MAX.into()) as i16;
+    }
+}
+
+/// The root mean square level of `pcm`, from 0.0 for silence to 1.0 for full scale.
+pub fn rms(pcm: &[i16]) -> f32 {
+    if pcm.is_empty() {
+        return 0.0;
+    }
+
+    // SAFETY: SSE2 is part of the x86_64 baseline.
+    #[cfg(target_arch = "x86_64")]
+    let (done, mut sum) = unsafe { sse2::sum_squares(pcm) };
+    #[cfg(not(target_arch = "x86_64"))]
+    let (done, mut sum) = (0, 0u64);
+
+    for sample in &pcm[done..] {
+        sum += (i64::from(*sample) * i64::from(*sample)) as u64;
+    }
+
+    ((sum as f64 / pcm.len() as f64).sqrt() / 32768.0) as f32
+}
+
+/// SSE2 kernels. Each processes whole blocks of eight samples and returns how many samples it
+/// handled, leaving the remainder to the scalar loop.
+#[cfg(target_arch = "x86_64")]
+mod sse2 {
+    use std::arch::x86_64::*;
+
+    const LANES: usize = 8;
+
+    /// Sign-extends eight `i16` lanes into two vectors of four `i32` lanes.
+    #[target_feature(enable = "sse2")]
+    fn widen(samples: __m128i) -> (__m128i, __m128i) {
+        let low = _mm_srai_epi32::<16>(_mm_unpacklo_epi16(samples, samples));
+        let high = _mm_srai_epi32::<16>(_mm_unpackhi_epi16(samples, samples));
+        (low, high)
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub fn accumulate(total: &mut [i32], pcm: &[i16]) -> usize {
+        let blocks = pcm.len() / LANES;
+        for block in 0..blocks {
+            let i = block * LANES;
+            // SAFETY: `total` and `pcm` have at least `i + LANES` elements, and unaligned loads
+            // and stores have no alignment requirement.
+            unsafe {
+                let samples = _mm_loadu_si128(pcm.as_ptr().add(i).cast());
+                let (low, high) = widen(samples);
+                let sums = total.as_mut_ptr().add(i).cast::<__m128i>();
+                _mm_storeu_si128(sums, _mm_add_epi32(_mm_loadu_si128(sums), low));
+                _mm_storeu_si128(
+                    sums.add(1),
+                    _mm_add_epi32(_mm_loadu_si128(sums.add(1)), high),
+                );
+            }
+        }
+        blocks * LANES
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub fn mix_down(total: &[i32], own: Option<&[i16]>, out: &mut [i16]) -> usize {
+        let blocks = out.len() / LANES;
+        for block in 0..blocks {
+            let i = block * LANES;
+            // SAFETY: all three slices have at least `i + LANES` elements, and unaligned loads
+            // and stores have no alignment requirement.
+            unsafe {
+                let sums = total.as_ptr().add(i).cast::<__m128i>();
+                let mut low = _mm_loadu_si128(sums);
+                let mut high = _mm_loadu_si128(sums.add(1));
+                if let Some(own) = own {
+                    let (own_low, own_high) = widen(_mm_loadu_si128(own.as_ptr().add(i).cast()));
+                    low = _mm_sub_epi32(low, own_low);
+                    high = _mm_sub_epi32(high, own_high);
+                }
+                // Packing saturates to the i16 range, which is the clipping step.
+                _mm_storeu_si128(out.as_mut_ptr().add(i).cast(), _mm_packs_epi32(low, high));
+            }
+        }
+        blocks * LANES
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub fn sum_squares(pcm: &[i16]) -> (usize, u64) {
+        let blocks = pcm.len() / LANES;
+        let zero = _mm_setzero_si128();
+        let mut sums = zero;
+        for block in 0..blocks {
+            // SAFETY: `pcm` has at least `(block + 1) * LANES` elements, and unaligned loads
+            // have no alignment requirement.
+            let samples = unsafe { _mm_loadu_si128(pcm.as_ptr().add(block * LANES).cast()) };
+            // Each pair of squares fits in 32 bits only as an unsigned value, so widen the
+            // pairs to 64 bits before accumulating.
+            let pairs = _mm_madd_epi16(samples, samples);
+            sums = _mm_add_epi64(sums, _mm_unpacklo_epi32(pairs, zero));
+            sums = _mm_add_epi64(sums, _mm_unpackhi_epi32(pairs, zero));
+        }
+
+        let mut lanes = [0u64; 2];
+        // SAFETY: `lanes` is 16 bytes, and unaligned stores have no alignment requirement.
+        unsafe { _mm_storeu_si128(lanes.as_mut_ptr().cast(), sums) };
+        (blocks * LANES, lanes[0] + lanes[1])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    /// Samples weighted towards full scale, where saturation and squaring overflow would show.
+    fn samples(rng: &mut StdRng, len: usize) -> Vec<i16> {
+        (0..len)
+            .map(|_| match rng.random_range(0..4) {
+                0 => i16::MIN,
+                1 => i16::MAX,
+                _ => rng.random(),
+            })
+            .collect()
+    }
+
+    /// Every length up to a few blocks, so each remainder the scalar loop finishes is covered,
+    /// at offsets that leave the slices unaligned, each with several random inputs.
+    fn cases() -> impl Iterator<Item = (usize, usize)> {
+        (0..=40)
+            .flat_map(|len| (0..3).map(move |offset| (len, offset)))
+            .flat_map(|case| std::iter::repeat_n(case, 8))
+    }
+
+    #[test]
+    fn accumulate_matches_scalar() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for (len, offset) in cases() {
+            let pcm = samples(&mut rng, len + offset);
+            let mut total: Vec<i32> = (0..len + offset)
+                .map(|_| rng.random_range(-(1 << 20)..1 << 20))
+                .collect();
+            let expected: Vec<i32> = total[offset..]
+                .iter()
+                .zip(&pcm[offset..])
+                .map(|(sum, sample)| sum + i32::from(*sample))
+                .collect();
+
+            super::accumulate(&mut total[offset..], &pcm[offset..]);
+            assert_eq!(total[offset..], expected, "{len} samples at {offset}");
+        }
+    }
+
+    #[test]
+    fn mix_down_matches_scalar() {
+        let mut rng = StdRng::seed_from_u64(2);
+        for (len, offset) in cases() {
+            let total: Vec<i32> = (0..len + offset)
+                .map(|_| rng.random_range(-(1 << 17)..1 << 17))
+                .collect();
+            let own = samples(&mut rng, len + offset);
+            for own in [None, Some(&own[offset..])] {
+                let expected: Vec<i16> = (0..len)
+                    .map(|i| {
+                        let own_sample = own.map_or(0, |own| i32::from(own[i]));
+                        (total[offset + i] - own_sample).clamp(i16::MIN.into(), i16::MAX.into())
+                            as i16
+                    })
+                    .collect();
+
+                let mut out = vec![0; len + offset];
+                super::mix_down(&total[offset..], own, &mut out[offset..]);
+                assert_eq!(out[offset..], expected, "{len} samples at {offset}");
+            }
+        }
+    }
+
+    #[test]
+    fn rms_matches_scalar() {
+        let mut rng = StdRng::seed_from_u64(3);
+        for (len, offset) in cases() {
+            let pcm = samples(&mut rng, len + offset);
+            let pcm = &pcm[offset..];
+            let sum: u64 = pcm
+                .iter()
+                .map(|sample| (i64::from(*sample) * i64::from(*sample)) as u64)
+                .sum();
+            let expected = if pcm.is_empty() {
+                0.0
+            } else {
+                ((sum as f64 / pcm.len() as f64).sqrt() / 32768.0) as f32
+            };
+            assert_eq!(super::rms(pcm), expected, "{len} samples at {offset}");
+        }
+    }
+}
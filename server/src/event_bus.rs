@@ -0,0 +1,247 @@
+//! Publishes server events to an MQTT broker or NATS server, so other systems such as home
+//! automation or game servers can react to what happens in rooms.
+//!
+//! Events go to a topic per kind under `events.topic_prefix`, such as `voice/sessions/started`
+//! and `voice/rooms/<room_key>/joined` over MQTT, or `voice.rooms.<room_key>.joined` over NATS.
+//! Characters in room keys that would be wildcards or separators become `_`. Payloads are JSON
+//! objects, or `events.ServerEvent` protobufs with `events.format` set to `protobuf`.
+//!
+//! Events are delivered at most once: MQTT messages are published at QoS 0, and events that
+//! happen while the broker is unreachable are dropped once more than
+//! [`EVENT_BUFFER_LEN`](crate::events::EVENT_BUFFER_LEN) are waiting.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use prost::Message;
+use protobuf::events::ServerEvent;
+use protobuf::events::server_event::Type;
+use protobuf::system::RoomUser;
+use serde_json::json;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::Instrument;
+use tracing::info;
+use tracing::info_span;
+use tracing::warn;
+
+use crate::config::EventFormat;
+use crate::config::EventsConfig;
+use crate::events::Event;
+use crate::latency;
+use crate::mqtt;
+use crate::nats;
+use crate::state::ServerState;
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// How often the client pings the broker, to keep the connection open and find out when it has
+/// silently gone away.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(mqtt::KEEP_ALIVE.as_secs() / 2);
+
+enum Broker {
+    Mqtt(mqtt::Client),
+    Nats(nats::Client),
+}
+
+impl Broker {
+    async fn publish(&mut self, levels: &[String], payload: &[u8]) -> Result<()> {
+        match self {
+            Self::Mqtt(client) => client.publish(&levels.join("/"), payload).await,
+            Self::Nats(client) => client.publish(&levels.join("."), payload).await,
+        }
+    }
+
+    async fn ping(&mut self) -> Result<()> {
+        match self {
+            Self::Mqtt(client) => client.ping().await,
+            Self::Nats(client) => client.ping().await,
+        }
+    }
+
+    /// Waits for the broker to expect a PONG, failing once the connection closes. Safe to cancel.
+    async fn next_ping(&mut self) -> Result<()> {
+        match self {
+            Self::Mqtt(client) => client.closed().await,
+            Self::Nats(client) => client.next_ping().await,
+        }
+    }
+
+    async fn pong(&mut self) -> Result<()> {
+        match self {
+            // MQTT brokers never ping.
+            Self::Mqtt(_) => Ok(()),
+            Self::Nats(client) => client.pong().await,
+        }
+    }
+}
+
+/// Spawns a task publishing to each configured broker, reconnecting whenever it disconnects.
+pub fn publish(state: &Arc<ServerState>) {
+    let config = &state.config.events;
+    let brokers = [
+        (config.mqtt_url.clone(), "MQTT"),
+        (config.nats_url.clone(), "NATS"),
+    ];
+    for (url, protocol) in brokers {
+        let Some(url) = url else {
+            continue;
+        };
+        let state = state.clone();
+        // Subscribed up front, so events during a reconnect are published once it succeeds.
+        let mut events = state.registry.events().subscribe();
+        tokio::spawn(
+            async move {
+                loop {
+                    match run(&state, &url, &mut events).await {
+                        Ok(()) => return,
+                        Err(err) => warn!("Event publisher failed: {err:#}"),
+                    }
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                }
+            }
+            .instrument(info_span!("Event publisher", protocol)),
+        );
+    }
+}
+
+/// Publishes events until the connection fails, or the server shuts down.
+async fn run(
+    state: &ServerState,
+    url: &str,
+    events: &mut broadcast::Receiver<Event>,
+) -> Result<()> {
+    let client_id = format!("webtransport-voice-chat-{:016x}", rand::random::<u64>());
+    let mut broker = if url.starts_with("mqtt://") {
+        Broker::Mqtt(mqtt::Client::connect(url, &client_id).await?)
+    } else {
+        Broker::Nats(nats::Client::connect(url, &client_id).await?)
+    };
+    info!("Publishing events");
+
+    let config = &state.config.events;
+    let mut keep_alive = tokio::time::interval_at(
+        tokio::time::Instant::now() + KEEP_ALIVE_INTERVAL,
+        KEEP_ALIVE_INTERVAL,
+    );
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => {
+                    let (levels, payload) = encode(&event, config);
+                    broker.publish(&levels, &payload).await?;
+                }
+                Err(RecvError::Lagged(skipped)) => warn!("Dropped {skipped} events"),
+                Err(RecvError::Closed) => return Ok(()),
+            },
+            _ = keep_alive.tick() => broker.ping().await?,
+            result = broker.next_ping() => {
+                result?;
+                broker.pong().await?;
+            }
+        }
+    }
+}
+
+/// The topic levels and payload an event is published as.
+fn encode(event: &Event, config: &EventsConfig) -> (Vec<String>, Vec<u8>) {
+    let (event_type, room_key, user, text) = match event {
+        Event::SessionStarted { user } => (Type::SessionStarted, None, member(user), None),
+        Event::SessionEnded { user } => (Type::SessionEnded, None, member(user), None),
+        Event::RoomOpened { room_key } => (Type::RoomOpened, Some(room_key), None, None),
+        Event::RoomClosed { room_key } => (Type::RoomClosed, Some(room_key), None, None),
+        Event::Joined { room_key, user } => (Type::UserJoined, Some(room_key), member(user), None),
+        Event::Left { room_key, user } => (Type::UserLeft, Some(room_key), member(user), None),
+        Event::Chat { room_key, message } => (
+            Type::Chat,
+            Some(room_key),
+            Some((message.session_id, message.username.as_str())),
+            Some(message.text.as_str()),
+        ),
+        Event::SpeakingStarted { room_key, user } => {
+            (Type::SpeakingStarted, Some(room_key), member(user), None)
+        }
+        Event::SpeakingStopped { room_key, user } => {
+            (Type::SpeakingStopped, Some(room_key), member(user), None)
+        }
+    };
+
+    let kind = match event_type {
+        Type::SessionStarted => "started",
+        Type::SessionEnded => "ended",
+        Type::RoomOpened => "opened",
+        Type::RoomClosed => "closed",
+        Type::UserJoined => "joined",
+        Type::UserLeft => "left",
+        Type::Chat => "chat",
+        Type::SpeakingStarted => "speaking",
+        Type::SpeakingStopped => "silent",
+    };
+    let levels = match room_key {
+        Some(room_key) => vec![
+            config.topic_prefix.clone(),
+            "rooms".to_owned(),
+            sanitize(room_key),
+            kind.to_owned(),
+        ],
+        None => vec![
+            config.topic_prefix.clone(),
+            "sessions".to_owned(),
+            kind.to_owned(),
+        ],
+    };
+
+    let time_us = latency::now_us();
+    let payload = match config.format {
+        EventFormat::Json => {
+            let mut payload = json!({
+                "type": event_type.as_str_name().to_ascii_lowercase(),
+                "time_us": time_us,
+            });
+            if let Some(room_key) = room_key {
+                payload["room_key"] = (**room_key).into();
+            }
+            if let Some((session_id, username)) = user {
+                payload["session_id"] = session_id.into();
+                payload["username"] = username.into();
+            }
+            if let Some(text) = text {
+                payload["text"] = text.into();
+            }
+            payload.to_string().into_bytes()
+        }
+        EventFormat::Protobuf => {
+            let (session_id, username) = user.unwrap_or_default();
+            ServerEvent {
+                r#type: event_type.into(),
+                time_us,
+                room_key: room_key
+                    .map(|room_key| room_key.to_string())
+                    .unwrap_or_default(),
+                session_id,
+                username: username.to_owned(),
+                text: text.unwrap_or_default().to_owned(),
+            }
+            .encode_to_vec()
+        }
+    };
+
+    (levels, payload)
+}
+
+/// Replaces the characters that are separators or wildcards in MQTT topics or NATS subjects.
+fn sanitize(level: &str) -> String {
+    level
+        .chars()
+        .map(|c| match c {
+            '/' | '+' | '#' | '.' | '*' | '>' => '_',
+            c if c.is_whitespace() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+fn member(user: &RoomUser) -> Option<(i64, &str)> {
+    Some((user.session_id, user.username.as_str()))
+}
@@ -0,0 +1,93 @@
+//! Server activity as a stream of events, for integrations that follow rooms without being in
+//! them.
+//!
+//! Every session starting and ending, room opening and closing, join, leave, chat message and
+//! start and end of speech on this node is published to [`Events`], which any number of
+//! subscribers can follow. Subscribers that fall more than [`EVENT_BUFFER_LEN`] events behind
+//! skip the oldest.
+
+use std::sync::Arc;
+
+use protobuf::system::ChatMessage;
+use protobuf::system::RoomUser;
+use tokio::sync::broadcast;
+
+/// Events buffered for each subscriber.
+pub const EVENT_BUFFER_LEN: usize = 1024;
+
+#[derive(Debug, Clone)]
+pub enum Event {
+    SessionStarted {
+        user: RoomUser,
+    },
+    SessionEnded {
+        user: RoomUser,
+    },
+    RoomOpened {
+        room_key: Arc<str>,
+    },
+    RoomClosed {
+        room_key: Arc<str>,
+    },
+    Joined {
+        room_key: Arc<str>,
+        user: RoomUser,
+    },
+    Left {
+        room_key: Arc<str>,
+        user: RoomUser,
+    },
+    Chat {
+        room_key: Arc<str>,
+        message: ChatMessage,
+    },
+    /// A user started sending voice to their room.
+    SpeakingStarted {
+        room_key: Arc<str>,
+        user: RoomUser,
+    },
+    /// A user sent no voice for a moment, or left their room while speaking.
+    SpeakingStopped {
+        room_key: Arc<str>,
+        user: RoomUser,
+    },
+}
+
+impl Event {
+    /// The room the event happened in, unless it is about a session.
+    pub fn room_key(&self) -> Option<&Arc<str>> {
+        match self {
+            Self::SessionStarted { .. } | Self::SessionEnded { .. } => None,
+            Self::RoomOpened { room_key }
+            | Self::RoomClosed { room_key }
+            | Self::Joined { room_key, .. }
+            | Self::Left { room_key, .. }
+            | Self::Chat { room_key, .. }
+            | Self::SpeakingStarted { room_key, .. }
+            | Self::SpeakingStopped { room_key, .. } => Some(room_key),
+        }
+    }
+}
+
+pub struct Events {
+    sender: broadcast::Sender<Event>,
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Self {
+            sender: broadcast::channel(EVENT_BUFFER_LEN).0,
+        }
+    }
+}
+
+impl Events {
+    pub fn publish(&self, event: Event) {
+        // Sending only fails when nobody is subscribed.
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+}
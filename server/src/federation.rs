@@ -0,0 +1,188 @@
+//! Federation between independently operated servers, so their users can share rooms.
+//!
+//! A server with `federation.server_name` set trunks audio with each server in
+//! `federation.peers` the same way cluster nodes do (see [`trunk`](crate::trunk)), but with a
+//! secret per peer instead of one for the whole cluster, and with rooms namespaced by server:
+//! users on `a.example` join the room `standup` on `b.example` as `standup@b.example`, and
+//! users on `b.example` hear them in `standup`. Each side relays its speakers with room keys
+//! rewritten into the other's namespace, and only accepts frames for its own rooms or the
+//! sender's.
+//!
+//! Trunks are authenticated both ways before any audio flows. The dialing server names itself
+//! in a request header, then both sides prove they know the secret they share by signing each
+//! other's random nonce with HMAC-SHA256, so the secret never crosses the wire.
+
+use std::time::Duration;
+
+use anyhow::Context;
+use anyhow::Result;
+use anyhow::bail;
+use ring::hmac;
+use wtransport::Connection;
+
+use crate::config::Config;
+use crate::config::FederationPeer;
+
+/// The path federated trunk sessions are requested on.
+pub const FEDERATION_PATH: &str = "/federation";
+
+/// The request header naming the dialing server.
+pub const ORIGIN_HEADER: &str = "x-federation-origin";
+
+const NONCE_LEN: usize = 32;
+
+/// The length of an HMAC-SHA256 tag.
+const PROOF_LEN: usize = 32;
+
+/// How long a peer has to complete the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// One side of a federated trunk: this server and the peer at the other end.
+#[derive(Clone)]
+pub struct Federation {
+    pub server_name: String,
+    pub peer: FederationPeer,
+}
+
+impl Federation {
+    /// The configured peer named `name`, if this server federates at all.
+    pub fn find(config: &Config, name: &str) -> Option<Self> {
+        let server_name = config.federation.server_name.as_ref()?;
+        let peer = config
+            .federation
+            .peers
+            .iter()
+            .find(|peer| peer.name == name)?;
+        Some(Self {
+            server_name: server_name.clone(),
+            peer: peer.clone(),
+        })
+    }
+
+    /// The key the peer knows a room on this server by, or `None` for rooms on a third server,
+    /// which are never relayed.
+    pub fn to_peer(&self, room_key: &str) -> Option<String> {
+        match room_key.rsplit_once('@') {
+            Some((room, server)) if server == self.peer.name => Some(room.to_owned()),
+            Some(_) => None,
+            None => Some(format!("{room_key}@{}", self.server_name)),
+        }
+    }
+
+    /// Whether the peer may relay audio into a room on this server: only rooms of this server's
+    /// own, and the peer's rooms as this server's users know them.
+    pub fn accepts(&self, room_key: &str) -> bool {
+        match room_key.rsplit_once('@') {
+            Some((_, server)) => server == self.peer.name,
+            None => true,
+        }
+    }
+
+    /// Proves this server to the peer it dialed, and checks the peer's proof.
+    pub async fn authenticate_dialer(&self, connection: &Connection) -> Result<()> {
+        tokio::time::timeout(HANDSHAKE_TIMEOUT, self.dial_handshake(connection))
+            .await
+            .context("peer did not complete the handshake in time")?
+    }
+
+    /// Proves this server to the peer that dialed it, and checks the peer's proof.
+    pub async fn authenticate_acceptor(&self, connection: &Connection) -> Result<()> {
+        tokio::time::timeout(HANDSHAKE_TIMEOUT, self.accept_handshake(connection))
+            .await
+            .context("peer did not complete the handshake in time")?
+    }
+
+    async fn dial_handshake(&self, connection: &Connection) -> Result<()> {
+        let (mut send, mut recv) = connection.open_bi().await?.await?;
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        send.write_all(&nonce).await?;
+
+        let mut reply = [0; NONCE_LEN + PROOF_LEN];
+        recv.read_exact(&mut reply)
+            .await
+            .context("peer closed the handshake")?;
+        let (peer_nonce, peer_proof) = reply.split_at(NONCE_LEN);
+        let accepted = self.transcript(
+            "accept",
+            &self.server_name,
+            &self.peer.name,
+            &nonce,
+            peer_nonce,
+        );
+        hmac::verify(&self.key(), &accepted, peer_proof)
+            .ok()
+            .context("peer does not know the federation secret")?;
+
+        let dialed = self.transcript(
+            "dial",
+            &self.server_name,
+            &self.peer.name,
+            &nonce,
+            peer_nonce,
+        );
+        send.write_all(hmac::sign(&self.key(), &dialed).as_ref())
+            .await?;
+        send.finish().await?;
+        Ok(())
+    }
+
+    async fn accept_handshake(&self, connection: &Connection) -> Result<()> {
+        let (mut send, mut recv) = connection.accept_bi().await?;
+        let mut peer_nonce = [0; NONCE_LEN];
+        recv.read_exact(&mut peer_nonce)
+            .await
+            .context("peer closed the handshake")?;
+
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let accepted = self.transcript(
+            "accept",
+            &self.peer.name,
+            &self.server_name,
+            &peer_nonce,
+            &nonce,
+        );
+        send.write_all(&nonce).await?;
+        send.write_all(hmac::sign(&self.key(), &accepted).as_ref())
+            .await?;
+
+        let mut peer_proof = [0; PROOF_LEN];
+        recv.read_exact(&mut peer_proof)
+            .await
+            .context("peer closed the handshake")?;
+        let dialed = self.transcript(
+            "dial",
+            &self.peer.name,
+            &self.server_name,
+            &peer_nonce,
+            &nonce,
+        );
+        if hmac::verify(&self.key(), &dialed, &peer_proof).is_err() {
+            bail!("peer does not know the federation secret");
+        }
+        Ok(())
+    }
+
+    fn key(&self) -> hmac::Key {
+        hmac::Key::new(hmac::HMAC_SHA256, self.peer.secret.as_bytes())
+    }
+
+    /// What each side signs: its role, both servers' names and both nonces, so a proof is only
+    /// good for one handshake in one direction.
+    fn transcript(
+        &self,
+        role: &str,
+        dialer: &str,
+        acceptor: &str,
+        dialer_nonce: &[u8],
+        acceptor_nonce: &[u8],
+    ) -> Vec<u8> {
+        let mut transcript = Vec::new();
+        for part in [role.as_bytes(), dialer.as_bytes(), acceptor.as_bytes()] {
+            transcript.extend_from_slice(&(part.len() as u16).to_be_bytes());
+            transcript.extend_from_slice(part);
+        }
+        transcript.extend_from_slice(dialer_nonce);
+        transcript.extend_from_slice(acceptor_nonce);
+        transcript
+    }
+}
@@ -0,0 +1,363 @@
+//! File sharing: uploads over dedicated streams, and signed download links served over HTTP.
+//!
+//! A client in a room shares a file by opening a unidirectional stream that starts with
+//! [`FILE_STREAM_PREFIX`](protocol::FILE_STREAM_PREFIX), as described on
+//! [`system::FileUpload`]. The server checks the declared size and type against the room's
+//! `files` limits before reading the file, stores it in the configured [`BlobStore`] under a
+//! random ID, and shares it in the room as a chat message with a download link. Links are
+//! signed with HMAC-SHA256 and expire after `files.url_ttl_secs`, so only those who were in the
+//! room when the file was shared can fetch it.
+//!
+//! [`BlobStore`]: crate::blobs::BlobStore
+
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use axum::Router;
+use axum::extract::Path;
+use axum::extract::Query;
+use axum::extract::State;
+use axum::http::HeaderValue;
+use axum::http::StatusCode;
+use axum::http::header::CACHE_CONTROL;
+use axum::http::header::CONTENT_DISPOSITION;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::header::X_CONTENT_TYPE_OPTIONS;
+use axum::response::IntoResponse;
+use axum::response::Response;
+use axum::routing::get;
+use base64::Engine;
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use prost::Message;
+use protobuf::system;
+use protobuf::system::PacketType;
+use protobuf::system::error;
+use ring::hmac;
+use serde::Deserialize;
+use tokio::io::AsyncReadExt;
+use tracing::info;
+use tracing::warn;
+
+use crate::config::FilesConfig;
+use crate::http_client;
+use crate::outbox::Outbox;
+use crate::protocol;
+use crate::rooms::SessionId;
+use crate::state::ServerState;
+use crate::transport::RecvStream;
+
+/// Uploads each session may have in progress at once.
+pub const MAX_CONCURRENT_UPLOADS: usize = 2;
+
+/// How long an upload may take, however large the file.
+const UPLOAD_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// The longest accepted file name, in characters.
+const MAX_NAME_LEN: usize = 255;
+
+/// The longest accepted content type.
+const MAX_CONTENT_TYPE_LEN: usize = 127;
+
+/// Signs and checks download links.
+pub struct Files {
+    key: hmac::Key,
+}
+
+/// Who is uploading a file, and where to share it.
+pub struct Uploader {
+    pub session_id: SessionId,
+    pub username: String,
+    pub room_key: Arc<str>,
+    pub outbox: Outbox,
+}
+
+/// Why an upload failed, as sent back to the uploader.
+type UploadError = (error::Code, String);
+
+impl Files {
+    pub fn new(config: &FilesConfig) -> Self {
+        let secret = match &config.url_secret {
+            Some(secret) => secret.as_bytes().to_vec(),
+            None => rand::random::<[u8; 32]>().to_vec(),
+        };
+        Self {
+            key: hmac::Key::new(hmac::HMAC_SHA256, &secret),
+        }
+    }
+
+    /// A download link for a stored file, valid for `files.url_ttl_secs`.
+    fn link(&self, config: &FilesConfig, id: &str, name: &str, content_type: &str) -> String {
+        let expires = unix_time() + config.url_ttl_secs;
+        let signature = self.signature(id, name, content_type, expires);
+        format!(
+            "{}/files/{id}/{}?type={}&expires={expires}&signature={signature}",
+            config
+                .public_url
+                .as_deref()
+                .unwrap_or("")
+                .trim_end_matches('/'),
+            http_client::percent_encode(name),
+            http_client::percent_encode(content_type),
+        )
+    }
+
+    fn signature(&self, id: &str, name: &str, content_type: &str, expires: u64) -> String {
+        let signed = format!("{id}\n{name}\n{content_type}\n{expires}");
+        BASE64_URL_SAFE_NO_PAD.encode(hmac::sign(&self.key, signed.as_bytes()))
+    }
+
+    fn verify(&self, id: &str, name: &str, link: &Link) -> bool {
+        let signed = format!("{id}\n{name}\n{}\n{}", link.content_type, link.expires);
+        BASE64_URL_SAFE_NO_PAD
+            .decode(&link.signature)
+            .is_ok_and(|signature| hmac::verify(&self.key, signed.as_bytes(), &signature).is_ok())
+    }
+}
+
+/// Receives a file on a stream whose first bytes are already in `received`, then shares it in
+/// the uploader's room. Failures are reported to the uploader.
+pub async fn receive(
+    state: Arc<ServerState>,
+    uploader: Uploader,
+    mut stream: Box<dyn RecvStream>,
+    received: Vec<u8>,
+) {
+    let result = tokio::time::timeout(
+        UPLOAD_TIMEOUT,
+        upload(&state, &uploader, &mut *stream, received),
+    )
+    .await
+    .unwrap_or_else(|_| {
+        Err((
+            error::Code::Unknown,
+            format!(
+                "uploads must finish within {} seconds",
+                UPLOAD_TIMEOUT.as_secs()
+            ),
+        ))
+    });
+
+    if let Err((code, detail)) = result {
+        warn!("Refusing upload: {} ({detail})", code.as_str_name());
+        let packet = protocol::error(code, detail, Some(PacketType::FileUpload));
+        let _ = uploader.outbox.send_control(packet).await;
+    }
+}
+
+async fn upload(
+    state: &ServerState,
+    uploader: &Uploader,
+    stream: &mut dyn RecvStream,
+    mut received: Vec<u8>,
+) -> Result<(), UploadError> {
+    let malformed = |detail: &str| (error::Code::MalformedPacket, detail.to_owned());
+    let Some(blobs) = &state.blobs else {
+        return Err((
+            error::Code::PermissionDenied,
+            "this server does not store files".to_owned(),
+        ));
+    };
+
+    if !read_until(stream, &mut received, 3).await? {
+        return Err(malformed("upload stream ended before its header"));
+    }
+    let header_len = usize::from(u16::from_be_bytes([received[1], received[2]]));
+    if !read_until(stream, &mut received, 3 + header_len).await? {
+        return Err(malformed("upload stream ended before its header"));
+    }
+    let header = system::FileUpload::decode(&received[3..3 + header_len])
+        .map_err(|_| malformed("malformed FileUpload header"))?;
+    let mut data = received.split_off(3 + header_len);
+
+    let name = sanitize_name(&header.name)
+        .ok_or_else(|| malformed("file names must have 1 to 255 characters"))?;
+    let content_type = parse_content_type(&header.content_type)
+        .ok_or_else(|| malformed("content types must look like type/subtype"))?;
+    let limits = state.config.files.limits(&uploader.room_key);
+    if header.size > limits.max_size() {
+        return Err((
+            error::Code::PermissionDenied,
+            format!(
+                "files in this room must be at most {} MB",
+                limits.max_size_mb
+            ),
+        ));
+    }
+    if !limits.allows(&content_type) {
+        return Err((
+            error::Code::PermissionDenied,
+            format!("{content_type} files are not allowed in this room"),
+        ));
+    }
+
+    // The declared size is within the limit, so the file can be read whole.
+    let size = header.size as usize;
+    let mut chunk = vec![0; 16 * 1024];
+    while data.len() <= size {
+        match stream.read(&mut chunk).await {
+            Ok(0) => break,
+            Ok(len) => data.extend_from_slice(&chunk[..len]),
+            Err(err) => return Err((error::Code::Unknown, format!("upload failed: {err}"))),
+        }
+    }
+    if data.len() != size {
+        return Err(malformed(&format!(
+            "the file was declared as {size} bytes, but {} were sent",
+            data.len()
+        )));
+    }
+
+    let id = format!("{:032x}", rand::random::<u128>());
+    if let Err(err) = blobs
+        .put(&format!("files/{id}"), &content_type, &data)
+        .await
+    {
+        warn!("Cannot store uploaded file: {err:#}");
+        return Err((error::Code::Unknown, "cannot store the file".to_owned()));
+    }
+    info!(
+        "Session {} shared '{name}' ({size} bytes) in '{}'",
+        uploader.session_id, uploader.room_key
+    );
+    state
+        .usage
+        .record_stored(state, &uploader.room_key, data.len());
+
+    let url = state
+        .files
+        .link(&state.config.files, &id, &name, &content_type);
+    let message = system::ChatMessage {
+        session_id: uploader.session_id,
+        username: uploader.username.clone(),
+        text: format!("{name}: {url}"),
+        attachment: Some(system::FileAttachment {
+            name,
+            content_type,
+            size: header.size,
+            url,
+        }),
+        ..Default::default()
+    };
+    state
+        .send_chat(&uploader.room_key, message)
+        .map(drop)
+        .map_err(|reason| (error::Code::PermissionDenied, reason))
+}
+
+/// Reads from the stream until `buffer` holds at least `len` bytes, returning `false` if the
+/// stream ends first.
+async fn read_until(
+    stream: &mut dyn RecvStream,
+    buffer: &mut Vec<u8>,
+    len: usize,
+) -> Result<bool, UploadError> {
+    let mut chunk = [0; 4096];
+    while buffer.len() < len {
+        match stream.read(&mut chunk).await {
+            Ok(0) => return Ok(false),
+            Ok(read) => buffer.extend_from_slice(&chunk[..read]),
+            Err(err) => return Err((error::Code::Unknown, format!("upload failed: {err}"))),
+        }
+    }
+    Ok(true)
+}
+
+/// Replaces the characters of a file name that are unsafe in paths or headers.
+fn sanitize_name(name: &str) -> Option<String> {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | '"' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let name = name.trim();
+    (!name.is_empty() && name.chars().count() <= MAX_NAME_LEN).then(|| name.to_owned())
+}
+
+/// Lowercases a `type/subtype` content type, or returns `None` if it is not one.
+fn parse_content_type(content_type: &str) -> Option<String> {
+    let (kind, subtype) = content_type.split_once('/')?;
+    let token = |part: &str| {
+        !part.is_empty()
+            && part
+                .bytes()
+                .all(|byte| byte.is_ascii_alphanumeric() || b"!#$&^_.+-".contains(&byte))
+    };
+    (content_type.len() <= MAX_CONTENT_TYPE_LEN && token(kind) && token(subtype))
+        .then(|| content_type.to_ascii_lowercase())
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+pub fn router(state: Arc<ServerState>) -> Router {
+    Router::new()
+        .route("/files/{id}/{name}", get(download))
+        .with_state(state)
+}
+
+/// The query string of a download link.
+#[derive(Deserialize)]
+struct Link {
+    #[serde(rename = "type")]
+    content_type: String,
+    expires: u64,
+    signature: String,
+}
+
+async fn download(
+    State(state): State<Arc<ServerState>>,
+    Path((id, name)): Path<(String, String)>,
+    Query(link): Query<Link>,
+) -> Response {
+    let valid_id = id.len() == 32 && id.bytes().all(|byte| byte.is_ascii_hexdigit());
+    if !valid_id || !state.files.verify(&id, &name, &link) {
+        return (StatusCode::FORBIDDEN, "invalid download link").into_response();
+    }
+    if link.expires < unix_time() {
+        return (StatusCode::GONE, "this download link has expired").into_response();
+    }
+    let Some(blobs) = &state.blobs else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let data = match blobs.get(&format!("files/{id}")).await {
+        Ok(Some(data)) => data,
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(err) => {
+            warn!("Cannot fetch shared file {id}: {err:#}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    // Always a download, so shared HTML never runs as this server's pages.
+    let disposition = format!(
+        "attachment; filename*=UTF-8''{}",
+        http_client::percent_encode(&name)
+    );
+    let content_type = HeaderValue::from_str(&link.content_type)
+        .unwrap_or(HeaderValue::from_static("application/octet-stream"));
+    (
+        [
+            (CONTENT_TYPE, content_type),
+            (
+                CONTENT_DISPOSITION,
+                HeaderValue::from_str(&disposition).unwrap(),
+            ),
+            (X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff")),
+            (
+                CACHE_CONTROL,
+                HeaderValue::from_static("private, max-age=86400"),
+            ),
+        ],
+        data,
+    )
+        .into_response()
+}
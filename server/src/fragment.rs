@@ -0,0 +1,162 @@
+//! Splitting control packets that exceed the connection's datagram size, and reassembling
+//! them on receipt.
+//!
+//! An oversized packet, type byte included, is cut into pieces that are each wrapped in a
+//! FRAGMENT packet small enough for one datagram. The receiver buffers pieces per message ID
+//! until all of them have arrived. Lost fragments are never retransmitted, so incomplete
+//! messages are evicted once too many newer ones are pending.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use bytes::Bytes;
+use prost::Message;
+use protobuf::system;
+use protobuf::system::PacketType;
+
+use crate::memory::Charge;
+use crate::memory::MEMORY;
+use crate::protocol;
+
+/// Bytes reserved in each datagram for the FRAGMENT type byte and message fields.
+const FRAGMENT_OVERHEAD: usize = 32;
+
+/// The most fragments accepted for one packet.
+const MAX_FRAGMENTS: u32 = 256;
+
+/// Incomplete packets buffered at once before the oldest is discarded.
+const MAX_PENDING: usize = 4;
+
+/// Splits `packet` into FRAGMENT packets no larger than `max_datagram_size`, or returns it
+/// unchanged if it already fits.
+pub fn split(packet: Bytes, max_datagram_size: usize, message_id: u32) -> Vec<Bytes> {
+    if packet.len() <= max_datagram_size {
+        return vec![packet];
+    }
+
+    let chunk_len = max_datagram_size.saturating_sub(FRAGMENT_OVERHEAD).max(1);
+    let count = packet.len().div_ceil(chunk_len) as u32;
+    (0..count)
+        .map(|index| {
+            let start = index as usize * chunk_len;
+            let end = (start + chunk_len).min(packet.len());
+            protocol::encode(
+                PacketType::Fragment,
+                &system::Fragment {
+                    message_id,
+                    index,
+                    count,
+                    data: packet[start..end].to_vec(),
+                },
+            )
+        })
+        .collect()
+}
+
+/// Collects fragments back into whole packets.
+pub struct Reassembler {
+    pending: HashMap<u32, Partial>,
+    max_len: usize,
+    received: u64,
+}
+
+struct Partial {
+    pieces: Vec<Option<Vec<u8>>>,
+    len: usize,
+    first_received: u64,
+    charge: Charge,
+}
+
+impl Reassembler {
+    /// Creates a reassembler accepting packets of up to `max_len` bytes.
+    pub fn new(max_len: usize) -> Self {
+        Self {
+            pending: HashMap::new(),
+            max_len,
+            received: 0,
+        }
+    }
+
+    /// Adds a FRAGMENT packet's payload, returning the whole packet once every piece of it has
+    /// arrived.
+    pub fn push(&mut self, payload: &[u8]) -> Result<Option<Vec<u8>>, FragmentError> {
+        let fragment = system::Fragment::decode(payload).map_err(FragmentError::Decode)?;
+        if fragment.count == 0 || fragment.count > MAX_FRAGMENTS || fragment.index >= fragment.count
+        {
+            return Err(FragmentError::Invalid);
+        }
+
+        if !self.pending.contains_key(&fragment.message_id) && self.pending.len() == MAX_PENDING {
+            self.evict_oldest();
+        }
+
+        self.received += 1;
+        let partial = self
+            .pending
+            .entry(fragment.message_id)
+            .or_insert_with(|| Partial {
+                pieces: vec![None; fragment.count as usize],
+                len: 0,
+                first_received: self.received,
+                charge: Charge::new(&MEMORY.reassembly, 0),
+            });
+        if partial.pieces.len() != fragment.count as usize {
+            self.pending.remove(&fragment.message_id);
+            return Err(FragmentError::Invalid);
+        }
+
+        let piece = &mut partial.pieces[fragment.index as usize];
+        if piece.is_none() {
+            partial.len += fragment.data.len();
+            *piece = Some(fragment.data);
+            partial.charge.set(partial.len);
+        }
+        if partial.len > self.max_len {
+            self.pending.remove(&fragment.message_id);
+            return Err(FragmentError::TooLarge);
+        }
+        if partial.pieces.iter().any(Option::is_none) {
+            return Ok(None);
+        }
+
+        let partial = self.pending.remove(&fragment.message_id).unwrap();
+        let mut packet = Vec::with_capacity(partial.len);
+        for piece in partial.pieces.into_iter().flatten() {
+            packet.extend_from_slice(&piece);
+        }
+        Ok(Some(packet))
+    }
+
+    fn evict_oldest(&mut self) {
+        let oldest = self
+            .pending
+            .iter()
+            .min_by_key(|(_, partial)| partial.first_received)
+            .map(|(message_id, _)| *message_id);
+        if let Some(message_id) = oldest {
+            self.pending.remove(&message_id);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum FragmentError {
+    /// The payload is not a valid FRAGMENT message.
+    Decode(prost::DecodeError),
+
+    /// The fragment's index or count is out of range or inconsistent with earlier fragments.
+    Invalid,
+
+    /// The reassembled packet would exceed the size limit.
+    TooLarge,
+}
+
+impl fmt::Display for FragmentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Decode(err) => write!(f, "malformed fragment: {err}"),
+            Self::Invalid => write!(f, "inconsistent fragment index or count"),
+            Self::TooLarge => write!(f, "fragmented packet too large"),
+        }
+    }
+}
@@ -0,0 +1,133 @@
+//! G.711 companding, and the rate conversion between telephone audio and room audio.
+//!
+//! Phone calls carry 8 kHz G.711, as μ-law (PCMU) or A-law (PCMA). Rooms carry 16-bit PCM at
+//! 48 kHz, so every room frame of 960 samples is one 20 ms frame of 160 telephone samples.
+
+/// How many room samples there are for each telephone sample.
+pub const RATE_RATIO: usize = 6;
+
+/// Which companding law a call uses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Law {
+    Mu,
+    A,
+}
+
+impl Law {
+    /// The law carried by a static RTP payload type.
+    pub fn from_payload_type(payload_type: u8) -> Option<Self> {
+        match payload_type {
+            0 => Some(Self::Mu),
+            8 => Some(Self::A),
+            _ => None,
+        }
+    }
+
+    pub fn payload_type(self) -> u8 {
+        match self {
+            Self::Mu => 0,
+            Self::A => 8,
+        }
+    }
+
+    /// The encoding name used in SDP.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Mu => "PCMU",
+            Self::A => "PCMA",
+        }
+    }
+
+    pub fn encode(self, pcm: &[i16]) -> Vec<u8> {
+        let encode = match self {
+            Self::Mu => encode_mu,
+            Self::A => encode_a,
+        };
+        pcm.iter().map(|sample| encode(*sample)).collect()
+    }
+
+    pub fn decode(self, encoded: &[u8]) -> Vec<i16> {
+        let decode = match self {
+            Self::Mu => decode_mu,
+            Self::A => decode_a,
+        };
+        encoded.iter().map(|byte| decode(*byte)).collect()
+    }
+}
+
+const MU_BIAS: i32 = 0x84;
+
+const MU_CLIP: i32 = 32635;
+
+fn encode_mu(sample: i16) -> u8 {
+    let sign = if sample < 0 { 0x80 } else { 0 };
+    let magnitude = (sample.unsigned_abs() as i32).min(MU_CLIP) + MU_BIAS;
+    // The position of the highest set bit above the 8 covered by the first segment.
+    let exponent = (24 - magnitude.leading_zeros() as i32).clamp(0, 7);
+    let mantissa = (magnitude >> (exponent + 3)) & 0x0F;
+    !(sign | (exponent << 4) | mantissa) as u8
+}
+
+fn decode_mu(byte: u8) -> i16 {
+    let byte = !byte as i32;
+    let exponent = (byte >> 4) & 0x07;
+    let mantissa = byte & 0x0F;
+    let magnitude = (((mantissa << 3) + MU_BIAS) << exponent) - MU_BIAS;
+    (if byte & 0x80 != 0 {
+        -magnitude
+    } else {
+        magnitude
+    }) as i16
+}
+
+fn encode_a(sample: i16) -> u8 {
+    let sign = if sample >= 0 { 0x80 } else { 0 };
+    let magnitude = (sample.unsigned_abs() as i32).min(i16::MAX.into());
+    let code = if magnitude < 256 {
+        magnitude >> 4
+    } else {
+        let exponent = (24 - magnitude.leading_zeros() as i32).min(7);
+        (exponent << 4) | ((magnitude >> (exponent + 3)) & 0x0F)
+    };
+    ((sign | code) ^ 0x55) as u8
+}
+
+fn decode_a(byte: u8) -> i16 {
+    let byte = (byte ^ 0x55) as i32;
+    let exponent = (byte >> 4) & 0x07;
+    let mantissa = byte & 0x0F;
+    let magnitude = match exponent {
+        0 => (mantissa << 4) + 8,
+        _ => ((mantissa << 4) + 0x108) << (exponent - 1),
+    };
+    (if byte & 0x80 != 0 {
+        magnitude
+    } else {
+        -magnitude
+    }) as i16
+}
+
+/// Converts telephone samples to room samples by linear interpolation. `previous` is the last
+/// sample of the previous frame, and is updated to this frame's last sample.
+pub fn upsample(pcm: &[i16], previous: &mut i16) -> Vec<i16> {
+    let mut out = Vec::with_capacity(pcm.len() * RATE_RATIO);
+    for &sample in pcm {
+        let (from, to) = (i32::from(*previous), i32::from(sample));
+        for step in 1..=RATE_RATIO as i32 {
+            out.push((from + (to - from) * step / RATE_RATIO as i32) as i16);
+        }
+        *previous = sample;
+    }
+    out
+}
+
+/// Converts room samples to telephone samples, averaging each group of samples to keep the
+/// frequencies a phone cannot carry from aliasing.
+pub fn downsample(pcm: &[i16]) -> Vec<i16> {
+    pcm.chunks(RATE_RATIO)
+        .map(|group| {
+            let sum: i32 = group.iter().map(|sample| i32::from(*sample)).sum();
+            (sum / group.len() as i32) as i16
+        })
+        .collect()
+}
@@ -0,0 +1,356 @@
+//! Locating clients by IP address, for abuse analysis and latency debugging.
+//!
+//! With `geoip.country_database` or `geoip.asn_database` set, each session's client address is
+//! looked up once it connects, in MaxMind DB files such as GeoLite2-Country and GeoLite2-ASN, and
+//! the session is tagged with the country and autonomous system found: in its connection's log
+//! span, in `ListSessions`, and in `voice_sessions_by_country` on `/metrics`.
+//!
+//! Only what those lookups need of the MaxMind DB format is implemented: walking the search tree
+//! to an address's record, and decoding the record's maps, strings and unsigned integers. Other
+//! values are skipped.
+
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Context;
+use anyhow::Result;
+use anyhow::bail;
+use tracing::info;
+
+use crate::config::GeoIpConfig;
+
+/// What precedes the metadata at the end of a database.
+const METADATA_MARKER: &[u8] = b"\xab\xcd\xefMaxMind.com";
+
+/// The zero bytes separating the search tree from the data section.
+const DATA_SECTION_SEPARATOR: usize = 16;
+
+/// How deeply maps and arrays may nest in a record, so a corrupt file cannot exhaust the stack.
+const MAX_DEPTH: usize = 16;
+
+/// Where a client is, as far as the databases know.
+#[derive(Clone, Debug, Default)]
+pub struct Location {
+    /// The ISO 3166-1 code of the country, such as `DE`.
+    pub country: Option<Arc<str>>,
+
+    /// The number of the autonomous system announcing the address.
+    pub asn: Option<u32>,
+
+    /// The organization running the autonomous system.
+    pub as_organization: Option<Arc<str>>,
+}
+
+/// The databases addresses are looked up in.
+#[derive(Default)]
+pub struct GeoIp {
+    country: Option<Database>,
+    asn: Option<Database>,
+}
+
+impl GeoIp {
+    /// Opens the databases `config` names, if any.
+    pub fn open(config: &GeoIpConfig) -> Result<Self> {
+        let open = |path: &Option<_>| path.as_deref().map(Database::open).transpose();
+        Ok(Self {
+            country: open(&config.country_database)?,
+            asn: open(&config.asn_database)?,
+        })
+    }
+
+    /// Looks `address` up in every database.
+    pub fn locate(&self, address: IpAddr) -> Location {
+        let address = address.to_canonical();
+        let country = self.country.as_ref().and_then(|db| db.lookup(address));
+        let asn = self.asn.as_ref().and_then(|db| db.lookup(address));
+        Location {
+            country: country
+                .as_ref()
+                .and_then(|record| record.get(&["country", "iso_code"])?.as_str())
+                .map(Into::into),
+            asn: asn
+                .as_ref()
+                .and_then(|record| record.get(&["autonomous_system_number"])?.as_uint())
+                .and_then(|asn| u32::try_from(asn).ok()),
+            as_organization: asn
+                .as_ref()
+                .and_then(|record| record.get(&["autonomous_system_organization"])?.as_str())
+                .map(Into::into),
+        }
+    }
+}
+
+/// A value decoded from a database's data section.
+#[derive(Debug)]
+enum Value {
+    Map(Vec<(String, Value)>),
+    String(String),
+    Uint(u64),
+
+    /// A value of a type lookups have no use for, such as a double or an array.
+    Other,
+}
+
+impl Value {
+    /// The value at `path` through nested maps.
+    fn get(&self, path: &[&str]) -> Option<&Value> {
+        let Some((key, rest)) = path.split_first() else {
+            return Some(self);
+        };
+        match self {
+            Self::Map(entries) => entries
+                .iter()
+                .find(|(name, _)| name == key)
+                .and_then(|(_, value)| value.get(rest)),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(string) => Some(string),
+            _ => None,
+        }
+    }
+
+    fn as_uint(&self) -> Option<u64> {
+        match self {
+            Self::Uint(n) => Some(*n),
+            _ => None,
+        }
+    }
+}
+
+/// A MaxMind DB file, read into memory.
+struct Database {
+    bytes: Vec<u8>,
+    node_count: usize,
+    record_size: usize,
+    ip_version: u64,
+
+    /// Where the data section starts.
+    data_start: usize,
+}
+
+impl Database {
+    fn open(path: &Path) -> Result<Self> {
+        let bytes = std::fs::read(path)
+            .with_context(|| format!("Cannot read the GeoIP database {}", path.display()))?;
+        let database = Self::parse(bytes)
+            .with_context(|| format!("Malformed GeoIP database {}", path.display()))?;
+        info!(
+            "Loaded the GeoIP database {} with {} nodes",
+            path.display(),
+            database.node_count
+        );
+        Ok(database)
+    }
+
+    fn parse(bytes: Vec<u8>) -> Result<Self> {
+        let Some(metadata_start) = bytes
+            .windows(METADATA_MARKER.len())
+            .rposition(|window| window == METADATA_MARKER)
+            .map(|position| position + METADATA_MARKER.len())
+        else {
+            bail!("no metadata");
+        };
+        let metadata = Decoder {
+            bytes: &bytes,
+            base: metadata_start,
+        }
+        .decode(metadata_start, 0)
+        .map(|(value, _)| value);
+        let field = |name| {
+            metadata
+                .as_ref()
+                .and_then(|metadata| metadata.get(&[name])?.as_uint())
+                .with_context(|| format!("no {name} in the metadata"))
+        };
+        let node_count = usize::try_from(field("node_count")?)?;
+        let record_size = usize::try_from(field("record_size")?)?;
+        let ip_version = field("ip_version")?;
+        if !matches!(record_size, 24 | 28 | 32) {
+            bail!("unsupported record size {record_size}");
+        }
+        if !matches!(ip_version, 4 | 6) {
+            bail!("unsupported IP version {ip_version}");
+        }
+
+        let tree_len = node_count
+            .checked_mul(record_size / 4)
+            .context("search tree too large")?;
+        let data_start = tree_len + DATA_SECTION_SEPARATOR;
+        if data_start > metadata_start {
+            bail!("search tree overlaps the metadata");
+        }
+        Ok(Self {
+            bytes,
+            node_count,
+            record_size,
+            ip_version,
+            data_start,
+        })
+    }
+
+    /// The record for `address`, if the database has one.
+    fn lookup(&self, address: IpAddr) -> Option<Value> {
+        let bits: Vec<bool> = match (address, self.ip_version) {
+            // IPv4 addresses are at ::a.b.c.d in IPv6 databases.
+            (IpAddr::V4(address), 6) => std::iter::repeat_n(false, 96)
+                .chain(bits_of(&address.octets()))
+                .collect(),
+            (IpAddr::V4(address), _) => bits_of(&address.octets()).collect(),
+            (IpAddr::V6(address), 6) => bits_of(&address.octets()).collect(),
+            (IpAddr::V6(_), _) => return None,
+        };
+
+        let mut node = 0;
+        for bit in bits {
+            if node >= self.node_count {
+                break;
+            }
+            node = self.record(node, bit)?;
+        }
+        if node <= self.node_count {
+            // The address is in no network the database knows, or it ran out of address bits.
+            return None;
+        }
+        let offset =
+            self.data_start + (node - self.node_count).checked_sub(DATA_SECTION_SEPARATOR)?;
+        Decoder {
+            bytes: &self.bytes,
+            base: self.data_start,
+        }
+        .decode(offset, 0)
+        .map(|(value, _)| value)
+    }
+
+    /// The left or right record of a search tree node.
+    fn record(&self, node: usize, right: bool) -> Option<usize> {
+        let node_len = self.record_size / 4;
+        let bytes = self.bytes.get(node * node_len..(node + 1) * node_len)?;
+        let be = |bytes: &[u8]| bytes.iter().fold(0, |n, &byte| n << 8 | usize::from(byte));
+        Some(match (self.record_size, right) {
+            (24, false) => be(&bytes[..3]),
+            (24, true) => be(&bytes[3..]),
+            (28, false) => usize::from(bytes[3] >> 4) << 24 | be(&bytes[..3]),
+            (28, true) => usize::from(bytes[3] & 0x0f) << 24 | be(&bytes[4..]),
+            (_, false) => be(&bytes[..4]),
+            (_, true) => be(&bytes[4..]),
+        })
+    }
+}
+
+/// The bits of an address, most significant first.
+fn bits_of(octets: &[u8]) -> impl Iterator<Item = bool> + '_ {
+    octets
+        .iter()
+        .flat_map(|octet| (0..8).rev().map(move |bit| octet >> bit & 1 == 1))
+}
+
+/// Decodes values from the data section starting at `base`, which pointers are relative to.
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    base: usize,
+}
+
+impl Decoder<'_> {
+    /// Decodes the value at `offset`, returning it and the offset after it.
+    fn decode(&self, offset: usize, depth: usize) -> Option<(Value, usize)> {
+        if depth > MAX_DEPTH {
+            return None;
+        }
+        let control = *self.bytes.get(offset)?;
+        let mut offset = offset + 1;
+        let mut kind = control >> 5;
+        if kind == 1 {
+            return self.pointer(control, offset, depth);
+        }
+        if kind == 0 {
+            kind = self.bytes.get(offset)?.checked_add(7)?;
+            offset += 1;
+        }
+
+        let (size, offset) = self.size(control, offset)?;
+        let end = offset.checked_add(size)?;
+        match kind {
+            // A UTF-8 string.
+            2 => {
+                let string = std::str::from_utf8(self.bytes.get(offset..end)?).ok()?;
+                Some((Value::String(string.to_owned()), end))
+            }
+            // Unsigned 16, 32, 64 and 128-bit integers, the last kept only if they fit.
+            5 | 6 | 9 | 10 => {
+                let bytes = self.bytes.get(offset..end)?;
+                let value = if bytes.len() <= 8 {
+                    Value::Uint(bytes.iter().fold(0, |n, &byte| n << 8 | u64::from(byte)))
+                } else {
+                    Value::Other
+                };
+                Some((value, end))
+            }
+            // A map of `size` entries.
+            7 => {
+                let mut entries = Vec::with_capacity(size.min(64));
+                let mut offset = offset;
+                for _ in 0..size {
+                    let (key, next) = self.decode(offset, depth + 1)?;
+                    let (value, next) = self.decode(next, depth + 1)?;
+                    let Value::String(key) = key else {
+                        return None;
+                    };
+                    entries.push((key, value));
+                    offset = next;
+                }
+                Some((Value::Map(entries), offset))
+            }
+            // An array of `size` values, skipped.
+            11 => {
+                let mut offset = offset;
+                for _ in 0..size {
+                    (_, offset) = self.decode(offset, depth + 1)?;
+                }
+                Some((Value::Other, offset))
+            }
+            // A boolean, whose size is its value.
+            14 => Some((Value::Other, offset)),
+            // Doubles, bytes, signed integers and floats.
+            3 | 4 | 8 | 15 => {
+                self.bytes.get(offset..end)?;
+                Some((Value::Other, end))
+            }
+            _ => None,
+        }
+    }
+
+    /// The size in a control byte, with the bytes extending it, and the offset after them.
+    fn size(&self, control: u8, offset: usize) -> Option<(usize, usize)> {
+        let be = |len: usize| {
+            let bytes = self.bytes.get(offset..offset + len)?;
+            Some(bytes.iter().fold(0, |n, &byte| n << 8 | usize::from(byte)))
+        };
+        Some(match control & 0x1f {
+            29 => (29 + be(1)?, offset + 1),
+            30 => (285 + be(2)?, offset + 2),
+            31 => (65_821 + be(3)?, offset + 3),
+            size => (usize::from(size), offset),
+        })
+    }
+
+    /// Decodes the value a pointer points to. The pointer's own end is what follows it.
+    fn pointer(&self, control: u8, offset: usize, depth: usize) -> Option<(Value, usize)> {
+        let len = usize::from(control >> 3 & 0x3) + 1;
+        let bytes = self.bytes.get(offset..offset + len)?;
+        let be = bytes.iter().fold(0, |n, &byte| n << 8 | usize::from(byte));
+        let value = usize::from(control & 0x7);
+        let target = match len {
+            1 => value << 8 | be,
+            2 => (value << 16 | be) + 2_048,
+            3 => (value << 24 | be) + 526_336,
+            _ => be,
+        };
+        let (value, _) = self.decode(self.base.checked_add(target)?, depth + 1)?;
+        Some((value, offset + len))
+    }
+}
@@ -0,0 +1,208 @@
+//! Handing a draining server's sessions over to the server taking over from it.
+//!
+//! With `cluster.trunk_secret` set on both, a server draining to an address first POSTs what it
+//! knows of its rooms to that server's `/handoff`, authorized with the secret: each room's
+//! members, whether they are muted, and a random resume token for each. The server taking over
+//! keeps the tokens for [`RESUME_TTL`], and the draining one sends each member its token in the
+//! `JOIN_ROOM_REDIRECT` that moves it. A client that logs in there with the token is logged in as
+//! the same user without its credentials, and rejoins its room as muted as it was, so a
+//! blue/green deploy costs conversations no more than a reconnect. Chat message IDs continue
+//! from the draining server's, so reactions to earlier messages still refer to them.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::Result;
+use axum::Json;
+use axum::Router;
+use axum::extract::State;
+use axum::http::HeaderMap;
+use axum::http::StatusCode;
+use axum::routing::post;
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::info;
+use tracing::warn;
+
+use crate::http_client;
+use crate::rooms::SessionId;
+use crate::secrets;
+use crate::state::ServerState;
+
+/// How long a handed over session can be resumed for.
+pub const RESUME_TTL: Duration = Duration::from_secs(60);
+
+/// What a draining server hands over.
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    rooms: Vec<RoomState>,
+    last_message_id: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RoomState {
+    /// The room's key with its tenant.
+    room_key: String,
+    users: Vec<UserState>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct UserState {
+    tenant: Option<String>,
+    username: String,
+    muted: bool,
+    token: String,
+}
+
+/// A session handed over by a draining server, for its client to resume.
+pub struct Resume {
+    /// The tenant's ID, empty without one.
+    pub tenant: String,
+    pub username: String,
+
+    /// The key with its tenant of the room the session was in.
+    pub room_key: String,
+    pub muted: bool,
+    expires: Instant,
+}
+
+/// The sessions handed over to this server, by resume token.
+#[derive(Default)]
+pub struct Handoffs {
+    resumes: Mutex<HashMap<String, Resume>>,
+}
+
+impl Handoffs {
+    /// Takes the session handed over with `token`, unless there is none or it expired.
+    pub fn take(&self, token: &str) -> Option<Resume> {
+        let mut resumes = self.resumes.lock().unwrap();
+        let now = Instant::now();
+        resumes.retain(|_, resume| resume.expires > now);
+        resumes.remove(token)
+    }
+
+    fn accept(&self, snapshot: Snapshot) -> usize {
+        let expires = Instant::now() + RESUME_TTL;
+        let mut resumes = self.resumes.lock().unwrap();
+        let mut accepted = 0;
+        for room in snapshot.rooms {
+            for user in room.users {
+                let resume = Resume {
+                    tenant: user.tenant.unwrap_or_default(),
+                    username: user.username,
+                    room_key: room.room_key.clone(),
+                    muted: user.muted,
+                    expires,
+                };
+                resumes.insert(user.token, resume);
+                accepted += 1;
+            }
+        }
+        accepted
+    }
+}
+
+/// Drains the server to `address`, if given, for at most `timeout`, handing its sessions over
+/// first if it can. Returns how many sessions were handed over, or `None` if the server is
+/// already draining.
+pub async fn drain(state: &ServerState, address: Option<&str>, timeout: Duration) -> Option<usize> {
+    if state.drain.is_draining() {
+        return None;
+    }
+    let tokens = match (address, &state.config.cluster.trunk_secret) {
+        (Some(address), Some(secret)) => match hand_over(state, address, secret).await {
+            Ok(tokens) => tokens,
+            Err(err) => {
+                warn!("Cannot hand sessions over to {address}: {err:#}");
+                HashMap::new()
+            }
+        },
+        _ => HashMap::new(),
+    };
+    let handed_over = tokens.len();
+    state
+        .drain
+        .begin(address, timeout, tokens)
+        .then_some(handed_over)
+}
+
+/// Sends the server at `address` the rooms' members, returning the token each can resume with.
+async fn hand_over(
+    state: &ServerState,
+    address: &str,
+    secret: &str,
+) -> Result<HashMap<SessionId, Arc<str>>> {
+    let mut tokens = HashMap::new();
+    let mut rooms = Vec::new();
+    for (room_key, _) in state.registry.rooms() {
+        let users: Vec<UserState> = state
+            .registry
+            .room_roster(&room_key)
+            .into_iter()
+            .filter(|member| !member.bridged)
+            .map(|member| {
+                let token = format!("{:032x}", rand::random::<u128>());
+                tokens.insert(member.session_id, token.as_str().into());
+                UserState {
+                    tenant: member.tenant.as_deref().map(str::to_owned),
+                    username: member.username,
+                    muted: member.muted,
+                    token,
+                }
+            })
+            .collect();
+        if !users.is_empty() {
+            rooms.push(RoomState { room_key, users });
+        }
+    }
+
+    let snapshot = Snapshot {
+        rooms,
+        last_message_id: state.last_message_id(),
+    };
+    let url = format!("{}/handoff", address.trim_end_matches('/'));
+    let _: serde_json::Value = http_client::authorized_json(
+        "POST",
+        &url,
+        secret,
+        Some(&serde_json::to_value(&snapshot)?),
+        http_client::TIMEOUT,
+    )
+    .await?;
+    info!("Handed {} sessions over to {address}", tokens.len());
+    Ok(tokens)
+}
+
+/// The endpoint draining servers hand their sessions over to.
+pub fn router(state: Arc<ServerState>) -> Router {
+    Router::new()
+        .route("/handoff", post(accept))
+        .with_state(state)
+}
+
+async fn accept(
+    State(state): State<Arc<ServerState>>,
+    headers: HeaderMap,
+    Json(snapshot): Json<Snapshot>,
+) -> Result<Json<serde_json::Value>, (StatusCode, &'static str)> {
+    let Some(secret) = &state.config.cluster.trunk_secret else {
+        return Err((StatusCode::NOT_FOUND, "handoffs need cluster.trunk_secret"));
+    };
+    let authorized = headers
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|provided| secrets::matches(secret, provided));
+    if !authorized {
+        warn!("Refusing unauthorized handoff");
+        return Err((StatusCode::UNAUTHORIZED, "invalid trunk secret"));
+    }
+
+    state.continue_message_ids(snapshot.last_message_id);
+    let accepted = state.handoffs.accept(snapshot);
+    info!("Accepted {accepted} handed over sessions");
+    Ok(Json(serde_json::json!({ "accepted": accepted })))
+}
@@ -0,0 +1,55 @@
+//! Gauges of what the server holds on to: registry entries, exported session metrics, read
+//! buffers and tasks.
+//!
+//! With no sessions connected, these all settle back to where they were at startup. A soak run
+//! (`voiceload soak`) scrapes them between rounds of connecting and disconnecting clients, and
+//! fails when they keep growing.
+
+use std::fmt::Write;
+
+use crate::metrics::METRICS;
+use crate::state::ServerState;
+
+pub fn render_metrics(state: &ServerState) -> String {
+    let mut out = String::new();
+
+    let name = "voice_registry_entries";
+    let _ = writeln!(
+        out,
+        "# HELP {name} Entries in the room registry's maps, by map."
+    );
+    let _ = writeln!(out, "# TYPE {name} gauge");
+    for (map, len) in state.registry.sizes() {
+        let _ = writeln!(out, "{name}{{map=\"{map}\"}} {len}");
+    }
+
+    let name = "voice_read_buffers";
+    let _ = writeln!(
+        out,
+        "# HELP {name} Read buffers of the shared pool, by state."
+    );
+    let _ = writeln!(out, "# TYPE {name} gauge");
+    let _ = writeln!(out, "{name}{{state=\"idle\"}} {}", state.buffers.idle());
+    let _ = writeln!(out, "{name}{{state=\"in_use\"}} {}", state.buffers.in_use());
+
+    let gauges = [
+        (
+            "voice_tracked_sessions",
+            "Sessions whose metrics are exported.",
+            METRICS.tracked_sessions(),
+        ),
+        (
+            "voice_tasks",
+            "Tasks alive on the runtime.",
+            tokio::runtime::Handle::current()
+                .metrics()
+                .num_alive_tasks(),
+        ),
+    ];
+    for (name, help, value) in gauges {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} gauge");
+        let _ = writeln!(out, "{name} {value}");
+    }
+    out
+}
@@ -0,0 +1,377 @@
+//! The HTTP server: `POST /session`, which logs clients in and tells them where to connect,
+//! `/config.json` for older clients, metrics, the admin, debug and file endpoints, and WebSocket
+//! signaling.
+
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use anyhow::Result;
+use axum::Router;
+use axum::extract::ConnectInfo;
+use axum::extract::Query;
+use axum::extract::connect_info::IntoMakeServiceWithConnectInfo;
+use axum::http::HeaderMap;
+use axum::http::Method;
+use axum::http::StatusCode;
+use axum::http::header;
+use axum::middleware::AddExtension;
+use axum::response::IntoResponse;
+use axum::response::Redirect;
+use axum::routing::get;
+use axum::serve;
+use axum::serve::Serve;
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use protobuf::system::Feature;
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::net::TcpListener;
+use tower_http::cors::AllowOrigin;
+use tracing::info;
+use wtransport::tls::Sha256Digest;
+
+use crate::admin;
+use crate::api_keys::Permission;
+use crate::avatars;
+use crate::bootstrap;
+use crate::broadcast;
+use crate::config::Config;
+use crate::config::EndpointConfig;
+use crate::debug_ui;
+use crate::files;
+use crate::handoff;
+use crate::holdings;
+use crate::metrics::METRICS;
+use crate::mixer::Pcm16Codec;
+use crate::privacy;
+use crate::profiler;
+use crate::protocol;
+use crate::session::Session;
+use crate::signaling;
+use crate::state::ServerState;
+use crate::tenants;
+
+/// The server's `/config.json`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerConfig {
+    pub cert_digest_base64: String,
+    pub default_port: u16,
+    pub endpoints: Vec<Endpoint>,
+
+    /// Missing from older servers' documents.
+    #[serde(default)]
+    pub capabilities: Capabilities,
+}
+
+/// What the server supports, so clients can adapt to it rather than find out by trying.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Capabilities {
+    /// The protocol versions the server speaks, as HELLO gives them.
+    pub min_protocol_version: u32,
+    pub max_protocol_version: u32,
+
+    /// The `Feature`s the server negotiates, by name.
+    pub features: Vec<String>,
+
+    /// The same features as the bitmask HELLO takes.
+    pub feature_bits: u32,
+
+    /// The largest datagram sent until the path's own is known, which HELLO_ACK gives.
+    pub max_datagram_size: u32,
+
+    /// The largest control packet accepted on a stream or WebSocket, in bytes.
+    pub max_control_packet_len: u32,
+
+    /// The voice codecs the server decodes, to mix rooms and play announcements. Forwarded
+    /// rooms carry whatever their clients send without the server reading it.
+    pub codecs: Vec<String>,
+
+    /// Whether rooms are mixed on the server rather than forwarded.
+    pub mixing: bool,
+
+    /// Whether voice may be encrypted end to end, which takes `FEATURE_AUDIO_HEADER` and rooms
+    /// that are forwarded.
+    pub e2ee: bool,
+
+    /// Whether rooms booked through the admin API can be recorded.
+    pub recording: bool,
+
+    pub chat: ChatCapabilities,
+
+    /// The files users may share, if they may.
+    pub files: Option<FileCapabilities>,
+
+    pub accounts: AccountCapabilities,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChatCapabilities {
+    /// The longest message, in characters.
+    pub max_len: u32,
+
+    /// How many recent messages clients that join a room are sent, if they negotiated
+    /// `FEATURE_CHAT_HISTORY`.
+    pub history_len: u32,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FileCapabilities {
+    pub max_size_mb: u64,
+
+    /// The content types allowed, all of them if empty.
+    pub allowed_types: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AccountCapabilities {
+    /// Whether logins need a password.
+    pub required: bool,
+
+    /// Whether unknown usernames get an account on their first login.
+    pub registration: bool,
+
+    /// Whether `POST /session` makes up guests for clients that give no username.
+    pub guests: bool,
+}
+
+impl Capabilities {
+    pub fn new(config: &Config) -> Self {
+        let accounts = config.accounts.path.is_some();
+        let storage = config.storage.s3.is_some() || config.storage.dir.is_some();
+        Self {
+            min_protocol_version: protocol::MIN_PROTOCOL_VERSION,
+            max_protocol_version: protocol::PROTOCOL_VERSION,
+            features: (0..u32::BITS)
+                .map(|bit| 1 << bit)
+                .filter(|bit| protocol::SUPPORTED_FEATURES & bit != 0)
+                .filter_map(|bit| Feature::try_from(bit as i32).ok())
+                .map(|feature| feature.as_str_name().to_owned())
+                .collect(),
+            feature_bits: protocol::SUPPORTED_FEATURES,
+            max_datagram_size: protocol::MAX_DATAGRAM_SIZE as u32,
+            max_control_packet_len: Session::MAX_STREAM_PACKET_LEN as u32,
+            codecs: vec![Pcm16Codec::NAME.to_owned()],
+            mixing: config.mixer.enabled,
+            e2ee: !config.mixer.enabled,
+            recording: storage,
+            chat: ChatCapabilities {
+                max_len: protocol::MAX_CHAT_LEN as u32,
+                history_len: config.chat.history_len as u32,
+            },
+            files: storage.then(|| FileCapabilities {
+                max_size_mb: config.files.limits.max_size_mb,
+                allowed_types: config.files.limits.allowed_types.clone(),
+            }),
+            accounts: AccountCapabilities {
+                required: accounts,
+                registration: accounts && config.accounts.allow_registration,
+                guests: !accounts || config.accounts.allow_guests,
+            },
+        }
+    }
+}
+
+/// An address clients may reach the server at.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Endpoint {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub url: String,
+    pub host: String,
+    pub port: u16,
+    pub cert_digest_base64: String,
+}
+
+impl ServerConfig {
+    pub fn new(cert_digest: &Sha256Digest, webtransport_port: u16, config: &Config) -> Self {
+        let cert_digest_base64 = BASE64_STANDARD.encode(cert_digest.as_ref());
+        let localhost = EndpointConfig {
+            name: None,
+            host: "localhost".to_owned(),
+            port: None,
+            cert_digest_base64: None,
+        };
+        let endpoints = match config.listen.endpoints.as_slice() {
+            [] => std::slice::from_ref(&localhost),
+            endpoints => endpoints,
+        };
+        let endpoints = endpoints
+            .iter()
+            .map(|endpoint| {
+                let port = endpoint.port.unwrap_or(webtransport_port);
+                // IPv6 addresses are bracketed in URLs.
+                let url = match endpoint.host.parse::<Ipv6Addr>() {
+                    Ok(_) => format!("https://[{}]:{port}/", endpoint.host),
+                    Err(_) => format!("https://{}:{port}/", endpoint.host),
+                };
+                Endpoint {
+                    name: endpoint.name.clone(),
+                    url,
+                    host: endpoint.host.clone(),
+                    port,
+                    cert_digest_base64: endpoint
+                        .cert_digest_base64
+                        .clone()
+                        .unwrap_or_else(|| cert_digest_base64.clone()),
+                }
+            })
+            .collect();
+
+        Self {
+            cert_digest_base64,
+            default_port: webtransport_port,
+            endpoints,
+            capabilities: Capabilities::new(config),
+        }
+    }
+}
+
+pub struct HttpServer {
+    serve: Serve<
+        TcpListener,
+        IntoMakeServiceWithConnectInfo<Router, SocketAddr>,
+        AddExtension<Router, ConnectInfo<SocketAddr>>,
+    >,
+    local_port: u16,
+}
+
+impl HttpServer {
+    pub const PORT: u16 = 8080;
+
+    /// The longest CPU profile that can be requested, in seconds.
+    const MAX_PROFILE_SECS: u64 = 300;
+
+    pub async fn new(
+        cert_digest: &Sha256Digest,
+        webtransport_port: u16,
+        state: &Arc<ServerState>,
+        socket: Option<std::net::TcpListener>,
+    ) -> Result<Self> {
+        let router = Self::build_router(cert_digest, webtransport_port, state);
+
+        let listener = match socket {
+            Some(socket) => {
+                TcpListener::from_std(socket).context("Cannot use the TCP listener from systemd")?
+            }
+            None => TcpListener::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), Self::PORT))
+                .await
+                .context("Cannot bind TCP listener for HTTP server")?,
+        };
+
+        let local_port = listener
+            .local_addr()
+            .context("Cannot get local port")?
+            .port();
+
+        Ok(HttpServer {
+            // The client's address is what `POST /session` is rate limited by.
+            serve: serve(
+                listener,
+                router.into_make_service_with_connect_info::<SocketAddr>(),
+            ),
+            local_port,
+        })
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.local_port
+    }
+
+    pub async fn serve(self) -> Result<()> {
+        info!("Server running on port {}", self.local_port());
+
+        self.serve.await.context("HTTP server error")?;
+
+        Ok(())
+    }
+
+    fn build_router(
+        cert_digest: &Sha256Digest,
+        webtransport_port: u16,
+        state: &Arc<ServerState>,
+    ) -> Router {
+        let server_config = ServerConfig::new(cert_digest, webtransport_port, &state.config);
+        let config_json =
+            serde_json::to_string(&server_config).expect("failed to serialize server config");
+
+        // Create CORS middleware, checking origins against the reloadable config
+        let cors_state = state.clone();
+        let cors = tower_http::cors::CorsLayer::new()
+            .allow_methods([Method::GET, Method::POST])
+            .allow_headers([header::CONTENT_TYPE])
+            .allow_origin(AllowOrigin::predicate(move |origin, _| {
+                let origins = &cors_state.live_config().http.cors_origins;
+                origins.is_empty() || origins.iter().any(|allowed| allowed == origin)
+            }));
+
+        // While draining, clients are sent to the server taking over.
+        let drain_state = state.clone();
+        let config = move || {
+            let response = match drain_state.drain.address() {
+                Some(address) => {
+                    Redirect::temporary(&format!("{address}/config.json")).into_response()
+                }
+                None => config_json.clone().into_response(),
+            };
+            async move { response }
+        };
+
+        let metrics_state = state.clone();
+        let admin_state = state.clone();
+        let debug = Router::new().route(
+            "/debug/pprof/profile",
+            get(move |headers: HeaderMap, query: Query<ProfileQuery>| {
+                Self::profile(admin_state, headers, query)
+            }),
+        );
+
+        Router::new()
+            .route("/config.json", get(config))
+            .merge(bootstrap::router(state.clone(), server_config))
+            .route(
+                "/metrics",
+                get(move || async move {
+                    METRICS.render()
+                        + &holdings::render_metrics(&metrics_state)
+                        + &tenants::render_metrics(&metrics_state)
+                }),
+            )
+            .layer(cors)
+            .merge(debug)
+            .merge(debug_ui::router(state.clone()))
+            .merge(admin::router(state.clone()))
+            .merge(broadcast::router(state.clone()))
+            .merge(files::router(state.clone()))
+            .merge(avatars::router(state.clone()))
+            .merge(privacy::router(state.clone()))
+            .merge(handoff::router(state.clone()))
+            .merge(signaling::router(state.clone()))
+    }
+
+    /// Profiles the server for `?seconds=` (30 by default) and returns the folded stacks.
+    async fn profile(
+        state: Arc<ServerState>,
+        headers: HeaderMap,
+        Query(query): Query<ProfileQuery>,
+    ) -> Result<String, (StatusCode, &'static str)> {
+        admin::authorize(&state, &headers, Permission::DebugProfile)?;
+
+        let seconds = query.seconds.unwrap_or(30).clamp(1, Self::MAX_PROFILE_SECS);
+        profiler::profile(Duration::from_secs(seconds))
+            .await
+            .map_err(|_| (StatusCode::CONFLICT, "a profile is already running"))
+    }
+}
+
+#[derive(Deserialize)]
+struct ProfileQuery {
+    seconds: Option<u64>,
+}
@@ -0,0 +1,184 @@
+//! A minimal HTTP/1.1 client, for webhooks, for fetching other nodes' `/config.json`, for the
+//! Matrix client-server API, and for S3.
+//!
+//! Requests are written directly over a TCP connection so the server needs no HTTP client
+//! dependency. Only `http://` URLs are supported; put a proxy in front of endpoints that require
+//! TLS.
+
+use std::fmt::Write as _;
+use std::time::Duration;
+
+use anyhow::Context;
+use anyhow::Result;
+use anyhow::bail;
+use serde::de::DeserializeOwned;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+/// How long a request may take before it is abandoned.
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+const JSON: (&str, &str) = ("Content-Type", "application/json");
+
+pub struct Response {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+/// POSTs `body` to `url` as JSON, failing unless the endpoint answers with a 2xx status.
+pub async fn post(url: &str, body: &serde_json::Value) -> Result<()> {
+    let body = body.to_string();
+    request(url, "POST", &[JSON], Some(body.as_bytes()), TIMEOUT).await?;
+    Ok(())
+}
+
+/// GETs `url` and parses the response body as JSON.
+pub async fn get_json<T: DeserializeOwned>(url: &str) -> Result<T> {
+    let body = request(url, "GET", &[], None, TIMEOUT).await?;
+    serde_json::from_slice(&body).with_context(|| format!("invalid JSON from {url}"))
+}
+
+/// Sends a request authorized with a bearer token and parses the response body as JSON. Long
+/// polls need a `timeout` longer than the time the server holds the request for.
+pub async fn authorized_json<T: DeserializeOwned>(
+    method: &str,
+    url: &str,
+    token: &str,
+    body: Option<&serde_json::Value>,
+    timeout: Duration,
+) -> Result<T> {
+    let authorization = format!("Bearer {token}");
+    let headers = [JSON, ("Authorization", authorization.as_str())];
+    let body = body.map(serde_json::Value::to_string);
+    let body = request(
+        url,
+        method,
+        &headers,
+        body.as_ref().map(String::as_bytes),
+        timeout,
+    )
+    .await?;
+    serde_json::from_slice(&body).with_context(|| format!("invalid JSON from {url}"))
+}
+
+/// Sends a request, returning the response body unless the status is not 2xx.
+async fn request(
+    url: &str,
+    method: &str,
+    headers: &[(&str, &str)],
+    body: Option<&[u8]>,
+    timeout: Duration,
+) -> Result<Vec<u8>> {
+    let response = send(url, method, headers, body, timeout).await?;
+    if !(200..300).contains(&response.status) {
+        bail!("{url} answered {}", response.status);
+    }
+    Ok(response.body)
+}
+
+/// Sends a request with the given headers, returning the response whatever its status.
+pub async fn send(
+    url: &str,
+    method: &str,
+    headers: &[(&str, &str)],
+    body: Option<&[u8]>,
+    timeout: Duration,
+) -> Result<Response> {
+    tokio::time::timeout(timeout, send_impl(url, method, headers, body))
+        .await
+        .with_context(|| format!("{method} {url} timed out"))?
+}
+
+async fn send_impl(
+    url: &str,
+    method: &str,
+    headers: &[(&str, &str)],
+    body: Option<&[u8]>,
+) -> Result<Response> {
+    let rest = url
+        .strip_prefix("http://")
+        .context("URL must start with http://")?;
+    let (authority, path) = match rest.find('/') {
+        Some(index) => rest.split_at(index),
+        None => (rest, "/"),
+    };
+    let address = if authority.contains(':') {
+        authority.to_owned()
+    } else {
+        format!("{authority}:80")
+    };
+
+    let mut request = format!("{method} {path} HTTP/1.1\r\nHost: {authority}\r\n");
+    for (name, value) in headers {
+        write!(request, "{name}: {value}\r\n").unwrap();
+    }
+    if let Some(body) = body {
+        write!(request, "Content-Length: {}\r\n", body.len()).unwrap();
+    }
+    request.push_str("Connection: close\r\n\r\n");
+
+    let mut stream = TcpStream::connect(&address)
+        .await
+        .with_context(|| format!("cannot connect to {address}"))?;
+    stream.write_all(request.as_bytes()).await?;
+    if let Some(body) = body {
+        stream.write_all(body).await?;
+    }
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    let status_line = response
+        .split(|&byte| byte == b'\r')
+        .next()
+        .unwrap_or_default();
+    let status = String::from_utf8_lossy(status_line);
+    let status = status
+        .split(' ')
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .with_context(|| format!("{url} answered '{status}'"))?;
+
+    let body_start = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map_or(response.len(), |index| index + 4);
+    let head = String::from_utf8_lossy(&response[..body_start]).to_ascii_lowercase();
+    let body = response.split_off(body_start);
+    let body = if head.contains("\r\ntransfer-encoding: chunked") {
+        dechunk(&body).with_context(|| format!("{url} sent a malformed chunked body"))?
+    } else {
+        body
+    };
+    Ok(Response { status, body })
+}
+
+/// Percent-encodes everything but unreserved characters, for URL paths and query strings.
+pub fn percent_encode(component: &str) -> String {
+    let mut encoded = String::with_capacity(component.len());
+    for byte in component.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            write!(encoded, "%{byte:02X}").unwrap();
+        }
+    }
+    encoded
+}
+
+/// Decodes a body sent with chunked transfer encoding.
+fn dechunk(mut body: &[u8]) -> Option<Vec<u8>> {
+    let mut decoded = Vec::new();
+    loop {
+        let line_end = body.windows(2).position(|window| window == b"\r\n")?;
+        let size = std::str::from_utf8(&body[..line_end]).ok()?;
+        let size = size.split(';').next()?.trim();
+        let size = usize::from_str_radix(size, 16).ok()?;
+        if size == 0 {
+            return Some(decoded);
+        }
+        let chunk = body.get(line_end + 2..line_end + 2 + size)?;
+        decoded.extend_from_slice(chunk);
+        body = body.get(line_end + 4 + size..)?;
+    }
+}
@@ -0,0 +1,58 @@
+//! The join link logged at startup: the web client's URL carrying the server's WebTransport URL
+//! and certificate digest, so a phone on the same LAN connects by opening it. With
+//! `join.qr_code`, it is also printed as a QR code when the output is a terminal.
+
+use std::io::IsTerminal;
+
+use tracing::info;
+use tracing::warn;
+use wtransport::tls::Sha256Digest;
+
+use crate::config::Config;
+use crate::http::ServerConfig;
+use crate::http_client::percent_encode;
+use crate::mdns;
+use crate::qr::QrCode;
+
+/// Logs the join link, and prints its QR code if enabled.
+pub fn print(config: &Config, cert_digest: &Sha256Digest, port: u16) {
+    let link = link(config, cert_digest, port);
+    info!("Join from another device at {link}");
+    if !config.join.qr_code || !std::io::stdout().is_terminal() {
+        return;
+    }
+    match QrCode::encode(link.as_bytes()) {
+        Some(code) => print!("{}", code.render()),
+        None => warn!("The join link is too long for a QR code"),
+    }
+}
+
+fn link(config: &Config, cert_digest: &Sha256Digest, port: u16) -> String {
+    let host = match mdns::lan_address() {
+        Ok(address) => address.to_string(),
+        Err(_) => "localhost".to_owned(),
+    };
+    let client = match &config.join.client_url {
+        Some(client) => client.clone(),
+        None => format!("http://{host}:3000/"),
+    };
+
+    // The first endpoint in `listen.endpoints` is the one to advertise, if there is one.
+    let server_config = ServerConfig::new(cert_digest, port, config);
+    let (server, digest) = match server_config.endpoints.first() {
+        Some(endpoint) if !config.listen.endpoints.is_empty() => {
+            (endpoint.url.clone(), endpoint.cert_digest_base64.clone())
+        }
+        _ => (
+            format!("https://{host}:{port}/"),
+            server_config.cert_digest_base64,
+        ),
+    };
+
+    let separator = if client.contains('?') { '&' } else { '?' };
+    format!(
+        "{client}{separator}server={}&digest={}",
+        percent_encode(&server),
+        percent_encode(&digest)
+    )
+}
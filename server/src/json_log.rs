@@ -0,0 +1,150 @@
+//! Append-only files of JSON lines, such as the event and audit logs and the chat history, which
+//! can be searched and rewritten to answer users' data requests.
+
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::LineWriter;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use anyhow::Context;
+use anyhow::Result;
+use serde_json::Value;
+
+use crate::tenants::Scoped;
+
+pub struct JsonLog {
+    path: PathBuf,
+    file: Mutex<LineWriter<File>>,
+}
+
+impl JsonLog {
+    pub fn open(path: &Path) -> Result<Self> {
+        Ok(Self {
+            path: path.to_owned(),
+            file: Mutex::new(Self::open_file(path)?),
+        })
+    }
+
+    fn open_file(path: &Path) -> Result<LineWriter<File>> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Cannot open {}", path.display()))?;
+        Ok(LineWriter::new(file))
+    }
+
+    pub fn append(&self, entry: &Value) -> std::io::Result<()> {
+        writeln!(self.file.lock().unwrap(), "{entry}")
+    }
+
+    /// The entries about a user: those naming them as `username`, in rooms of their tenant.
+    pub fn find(&self, user: Scoped) -> Result<Vec<Value>> {
+        self.filter(|entry| is_about(entry, user))
+    }
+
+    /// Every entry in the file, skipping lines that are not JSON.
+    pub fn entries(&self) -> Result<Vec<Value>> {
+        self.filter(|_| true)
+    }
+
+    fn filter(&self, matches: impl Fn(&Value) -> bool) -> Result<Vec<Value>> {
+        let _file = self.file.lock().unwrap();
+        let reader = BufReader::new(
+            File::open(&self.path)
+                .with_context(|| format!("Cannot read {}", self.path.display()))?,
+        );
+        let mut entries = Vec::new();
+        for line in reader.lines() {
+            if let Ok(entry) = serde_json::from_str::<Value>(&line?)
+                && matches(&entry)
+            {
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Clears the name from a user's entries and drops the `text` they wrote, returning how
+    /// many entries there were.
+    pub fn anonymize(&self, user: Scoped) -> Result<usize> {
+        self.rewrite(
+            |entry| is_about(entry, user),
+            |entry| {
+                entry["username"] = Value::Null;
+                if let Some(entry) = entry.as_object_mut() {
+                    entry.remove("text");
+                }
+            },
+        )
+    }
+
+    /// Replaces the file's entries with `entries`, as when compacting it. Like
+    /// [`Self::anonymize`], the file is written aside and moved over the old one.
+    pub fn replace(&self, entries: &[Value]) -> Result<()> {
+        let mut file = self.file.lock().unwrap();
+        file.flush()?;
+
+        let temp = self.path.with_extension("tmp");
+        let mut rewritten = BufWriter::new(
+            File::create(&temp).with_context(|| format!("Cannot write {}", temp.display()))?,
+        );
+        for entry in entries {
+            writeln!(rewritten, "{entry}")?;
+        }
+        rewritten.into_inner()?.sync_all()?;
+
+        std::fs::rename(&temp, &self.path)
+            .with_context(|| format!("Cannot replace {}", self.path.display()))?;
+        *file = Self::open_file(&self.path)?;
+        Ok(())
+    }
+
+    /// Rewrites every entry that `matches` with `edit`, returning how many there were. The file
+    /// is rewritten aside and moved over the old one, so a crash never loses entries.
+    fn rewrite(
+        &self,
+        matches: impl Fn(&Value) -> bool,
+        edit: impl Fn(&mut Value),
+    ) -> Result<usize> {
+        let mut file = self.file.lock().unwrap();
+        file.flush()?;
+
+        let reader = BufReader::new(
+            File::open(&self.path)
+                .with_context(|| format!("Cannot read {}", self.path.display()))?,
+        );
+        let temp = self.path.with_extension("tmp");
+        let mut rewritten = BufWriter::new(
+            File::create(&temp).with_context(|| format!("Cannot write {}", temp.display()))?,
+        );
+        let mut edited = 0;
+        for line in reader.lines() {
+            let line = line?;
+            match serde_json::from_str::<Value>(&line) {
+                Ok(mut entry) if matches(&entry) => {
+                    edit(&mut entry);
+                    edited += 1;
+                    writeln!(rewritten, "{entry}")?;
+                }
+                _ => writeln!(rewritten, "{line}")?,
+            }
+        }
+        rewritten.into_inner()?.sync_all()?;
+
+        std::fs::rename(&temp, &self.path)
+            .with_context(|| format!("Cannot replace {}", self.path.display()))?;
+        *file = Self::open_file(&self.path)?;
+        Ok(edited)
+    }
+}
+
+fn is_about(entry: &Value, user: Scoped) -> bool {
+    entry["username"] == user.name && user.owns(entry["room_key"].as_str().unwrap_or_default())
+}
@@ -0,0 +1,113 @@
+//! Per-session latency estimation.
+//!
+//! For clients that negotiate `FEATURE_LATENCY_REPORTS`, the server periodically probes the
+//! client's clock with CLOCK_SYNC and estimates its offset NTP-style. With the offset known, the
+//! capture timestamp on each voice datagram gives the speaker's uplink latency, from capture to
+//! arrival at the server. A listener's downlink latency is the time frames spend in its outbox,
+//! half the connection's RTT, and the playout delay the client reports.
+//!
+//! The mouth-to-ear latency from one user to another is the speaker's uplink latency plus the
+//! listener's downlink latency. Both are exported per session at `/metrics`.
+
+use std::sync::atomic::AtomicI64;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use protobuf::system::ClockSync;
+
+/// A session's latency estimates. Every value is `None` until it has been measured.
+#[derive(Default)]
+pub struct Latency {
+    /// How far the client's clock is ahead of the server's.
+    pub clock_offset: Ewma,
+
+    /// Round-trip time, as smoothed by QUIC.
+    pub rtt: Ewma,
+
+    /// Capture to arrival at the server, for the client's own voice.
+    pub uplink: Ewma,
+
+    /// Time frames spend queued in the client's outbox, as averaged by the outbox.
+    pub queue_delay: Ewma,
+
+    /// Arrival at the client to playout, as reported by the client.
+    pub playout_delay: Ewma,
+}
+
+impl Latency {
+    /// Updates the clock offset from a CLOCK_SYNC reply received at `receive_time_us`.
+    pub fn record_clock_sync(&self, reply: &ClockSync, receive_time_us: u64) {
+        let t1 = reply.server_send_time_us as i64;
+        let t2 = reply.client_receive_time_us as i64;
+        let t3 = reply.client_send_time_us as i64;
+        let t4 = receive_time_us as i64;
+        self.clock_offset.record(((t2 - t1) + (t3 - t4)) / 2);
+    }
+
+    /// Updates the uplink latency from a voice frame captured at `capture_time_us` on the
+    /// client's clock and received at `receive_time_us` on the server's.
+    pub fn record_capture(&self, capture_time_us: u64, receive_time_us: u64) {
+        let Some(offset) = self.clock_offset.get() else {
+            return;
+        };
+        let captured = capture_time_us as i64 - offset;
+        self.uplink
+            .record((receive_time_us as i64 - captured).max(0));
+    }
+
+    /// Outbox queueing, one-way network delay and playout, if all are known.
+    pub fn downlink(&self) -> Option<i64> {
+        Some(self.queue_delay.get()? + self.rtt.get()? / 2 + self.playout_delay.get()?)
+    }
+}
+
+/// An exponentially weighted moving average of an integer value, such as a time in
+/// microseconds. Readable from any thread, but updated by a single writer.
+pub struct Ewma(AtomicI64);
+
+impl Ewma {
+    /// Marks an average with no samples yet.
+    const UNSET: i64 = i64::MIN;
+
+    /// The weight of the average against each new sample, as a power of two.
+    const SHIFT: u32 = 3;
+
+    pub fn record(&self, sample: i64) {
+        let old = self.0.load(Ordering::Relaxed);
+        let new = match old {
+            Self::UNSET => sample,
+            old => old + ((sample - old) >> Self::SHIFT),
+        };
+        self.0.store(new, Ordering::Relaxed);
+    }
+
+    /// Replaces the average with a value smoothed elsewhere.
+    pub fn set(&self, value: i64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    pub fn record_duration(&self, sample: Duration) {
+        self.record(sample.as_micros().min(i64::MAX as u128) as i64);
+    }
+
+    pub fn get(&self) -> Option<i64> {
+        match self.0.load(Ordering::Relaxed) {
+            Self::UNSET => None,
+            value => Some(value),
+        }
+    }
+}
+
+impl Default for Ewma {
+    fn default() -> Self {
+        Self(AtomicI64::new(Self::UNSET))
+    }
+}
+
+/// The current time in microseconds since the Unix epoch.
+pub fn now_us() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |since| since.as_micros() as u64)
+}
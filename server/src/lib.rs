@@ -0,0 +1,136 @@
+//! A voice chat server over WebTransport, to run on its own as the `server` binary or to embed
+//! in another application.
+//!
+//! [`ServerBuilder`] starts a [`Server`] from a [`Config`], which serves until told to shut
+//! down. [`Server::events`] streams the [`Event`]s of its sessions and rooms, such as users
+//! joining and starting to speak, and its [`ServerHandle`] manages it as it runs, with a
+//! [`RoomHandle`] for each room to list its users or send it chat messages.
+//! [`init_logging`] installs the logging the `server` binary uses.
+
+pub mod config;
+
+mod accounts;
+mod admin;
+mod announcements;
+mod api_keys;
+mod avatars;
+mod bitrate;
+mod blobs;
+mod bootstrap;
+mod broadcast;
+mod buffer_pool;
+mod chaos;
+mod chat_history;
+mod debug_ui;
+mod deflate;
+mod drain;
+mod dsp;
+mod event_bus;
+mod events;
+mod federation;
+mod files;
+mod flac;
+mod fragment;
+mod g711;
+mod geoip;
+mod handoff;
+mod holdings;
+mod http;
+mod http_client;
+mod join;
+mod json_log;
+mod latency;
+mod lifecycle;
+mod lobby;
+mod log_sampling;
+mod log_tail;
+mod logging;
+mod maintenance;
+mod matrix;
+mod mdns;
+mod media;
+mod memory;
+mod metrics;
+mod mixer;
+mod moderation;
+mod mqtt;
+mod nats;
+mod ogg;
+mod outbox;
+mod packet_dump;
+mod participant;
+mod placement;
+mod plugin;
+mod privacy;
+mod profiler;
+mod protocol;
+mod qoe;
+mod qr;
+mod rate_limit;
+mod recordings;
+mod redis;
+mod reload;
+mod rooms;
+mod rtmp;
+mod rtp;
+mod s3;
+mod schedule;
+mod secrets;
+mod server;
+mod session;
+mod siblings;
+mod signaling;
+mod sigv4;
+mod sip;
+mod slow_consumer;
+mod state;
+mod stats;
+mod store;
+mod systemd;
+mod tenants;
+mod transport;
+mod trunk;
+mod usage;
+mod webtransport;
+
+pub use config::Config;
+pub use events::Event;
+pub use logging::init_logging;
+pub use participant::JoinRefused;
+pub use protobuf;
+pub use rooms::SessionId;
+pub use server::Announcer;
+pub use server::RoomHandle;
+pub use server::Server;
+pub use server::ServerBuilder;
+pub use server::ServerHandle;
+
+/// The voice fan-out path, for the tests in `tests/` and the benchmarks to drive without
+/// connections. Not part of the embedding API.
+#[doc(hidden)]
+pub mod fanout {
+    pub use crate::outbox::AudioQueue;
+    pub use crate::outbox::Outbox;
+    pub use crate::protocol::VoiceFrame;
+    pub use crate::protocol::VoiceHeader;
+    pub use crate::protocol::room_id;
+    pub use crate::protocol::voice;
+    pub use crate::rooms::Link;
+    pub use crate::rooms::Registry;
+}
+
+/// The command-line tools the `server` binary runs instead of serving.
+pub mod cli {
+    pub use crate::accounts::command_args as accounts_command_args;
+    pub use crate::accounts::run_command as run_accounts_command;
+
+    use crate::systemd;
+
+    /// Waits for Ctrl-C, or for SIGTERM as systemd stops services with.
+    pub async fn shutdown_signal() {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            () = systemd::terminated() => {}
+        }
+    }
+}
@@ -0,0 +1,288 @@
+//! The phases a session goes through, and the control packets each admits.
+//!
+//! A session is [`Phase::Handshaking`] until its HELLO is negotiated, then logs in and joins a
+//! room, and ends [`Phase::Closing`]. It only moves forward, through the [`Transition`]s
+//! [`Phase::next`] allows, and each control packet is checked with [`Phase::admit`] before it is
+//! handled, so handlers can rely on the phase they are called in.
+
+use protobuf::system::PacketType;
+use protobuf::system::error;
+
+/// Where a session is in its lifecycle.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// Waiting for the client's HELLO.
+    Handshaking,
+
+    /// The protocol is negotiated, and the client has yet to log in.
+    Negotiated,
+
+    /// Logged in, and in no room.
+    Authenticated,
+
+    /// In a room, whose voice the session sends and receives.
+    InRoom,
+
+    /// Closing the connection. Nothing more is handled.
+    Closing,
+}
+
+/// What moves a session from one phase to the next.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transition {
+    /// The HELLO was negotiated.
+    Negotiate,
+
+    /// The client logged in.
+    Authenticate,
+
+    /// The session joined a room.
+    Join,
+
+    /// The connection is being closed, from any phase.
+    Close,
+}
+
+/// Why a control packet is not admitted in a phase, to reject it with.
+pub struct Refusal {
+    pub code: error::Code,
+    pub detail: &'static str,
+}
+
+impl Phase {
+    /// The phase after `transition`, or `None` if this phase cannot make it.
+    pub fn next(self, transition: Transition) -> Option<Self> {
+        match (self, transition) {
+            (Self::Closing, _) => None,
+            (_, Transition::Close) => Some(Self::Closing),
+            (Self::Handshaking, Transition::Negotiate) => Some(Self::Negotiated),
+            (Self::Negotiated, Transition::Authenticate) => Some(Self::Authenticated),
+            (Self::Authenticated, Transition::Join) => Some(Self::InRoom),
+            _ => None,
+        }
+    }
+
+    /// Checks that the client may send a control packet of `packet_type` in this phase.
+    pub fn admit(self, packet_type: PacketType) -> Result<(), Refusal> {
+        use error::Code;
+
+        let (code, detail) = match (self, packet_type) {
+            (Self::Closing, _) => (Code::UnexpectedPacket, "the session is closing"),
+            (Self::Handshaking, PacketType::Hello) => return Ok(()),
+            (Self::Handshaking, _) => (Code::UnexpectedPacket, "the handshake must come first"),
+            (_, PacketType::Hello) => (Code::UnexpectedPacket, "handshake already completed"),
+            (Self::Authenticated | Self::InRoom, PacketType::AuthRequest) => {
+                (Code::UnexpectedPacket, "already authenticated")
+            }
+            (Self::Negotiated, PacketType::JoinRoomRequest) => (
+                Code::NotAuthenticated,
+                "cannot join a room before authenticating",
+            ),
+            (Self::InRoom, PacketType::JoinRoomRequest) => {
+                (Code::AlreadyInRoom, "already in a room")
+            }
+            (Self::Negotiated, PacketType::ChatMessage) => {
+                (Code::NotAuthenticated, "cannot chat before authenticating")
+            }
+            (Self::Authenticated, PacketType::ChatMessage) => {
+                (Code::UnexpectedPacket, "cannot chat before joining a room")
+            }
+            (Self::Negotiated | Self::Authenticated, PacketType::Reaction) => {
+                (Code::UnexpectedPacket, "cannot react before joining a room")
+            }
+            (Self::Negotiated | Self::Authenticated, PacketType::LobbyDecision) => (
+                Code::UnexpectedPacket,
+                "cannot admit users before joining a room",
+            ),
+            (Self::Negotiated | Self::Authenticated, PacketType::SlowMode) => (
+                Code::UnexpectedPacket,
+                "cannot set slow mode before joining a room",
+            ),
+            _ => return Ok(()),
+        };
+        Err(Refusal { code, detail })
+    }
+
+    /// Whether the session is in a room, so its voice is forwarded.
+    pub fn in_room(self) -> bool {
+        self == Self::InRoom
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use protobuf::system::PacketType;
+    use protobuf::system::error::Code;
+
+    use super::Phase;
+    use super::Transition;
+
+    const PHASES: [Phase; 5] = [
+        Phase::Handshaking,
+        Phase::Negotiated,
+        Phase::Authenticated,
+        Phase::InRoom,
+        Phase::Closing,
+    ];
+
+    const TRANSITIONS: [Transition; 4] = [
+        Transition::Negotiate,
+        Transition::Authenticate,
+        Transition::Join,
+        Transition::Close,
+    ];
+
+    /// The code `packet_type` is refused with in `phase`, or `None` if it is admitted.
+    fn refusal(phase: Phase, packet_type: PacketType) -> Option<Code> {
+        phase.admit(packet_type).err().map(|refusal| refusal.code)
+    }
+
+    #[test]
+    fn next() {
+        use Phase::*;
+
+        let legal = [
+            (Handshaking, Transition::Negotiate, Negotiated),
+            (Negotiated, Transition::Authenticate, Authenticated),
+            (Authenticated, Transition::Join, InRoom),
+            (Handshaking, Transition::Close, Closing),
+            (Negotiated, Transition::Close, Closing),
+            (Authenticated, Transition::Close, Closing),
+            (InRoom, Transition::Close, Closing),
+        ];
+        for phase in PHASES {
+            for transition in TRANSITIONS {
+                let expected = legal
+                    .iter()
+                    .find(|(from, by, _)| *from == phase && *by == transition)
+                    .map(|&(_, _, to)| to);
+                assert_eq!(
+                    phase.next(transition),
+                    expected,
+                    "{phase:?} on {transition:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn phases_only_move_forward() {
+        let mut phase = Phase::Handshaking;
+        for transition in [
+            Transition::Negotiate,
+            Transition::Authenticate,
+            Transition::Join,
+        ] {
+            phase = phase.next(transition).unwrap();
+            assert_eq!(phase.next(Transition::Negotiate), None);
+        }
+        assert!(phase.in_room());
+        assert_eq!(phase.next(Transition::Close), Some(Phase::Closing));
+    }
+
+    #[test]
+    fn handshake_comes_first() {
+        assert_eq!(refusal(Phase::Handshaking, PacketType::Hello), None);
+        for packet_type in [
+            PacketType::AuthRequest,
+            PacketType::JoinRoomRequest,
+            PacketType::ChatMessage,
+            PacketType::Ping,
+        ] {
+            assert_eq!(
+                refusal(Phase::Handshaking, packet_type),
+                Some(Code::UnexpectedPacket)
+            );
+        }
+        for phase in [Phase::Negotiated, Phase::Authenticated, Phase::InRoom] {
+            assert_eq!(
+                refusal(phase, PacketType::Hello),
+                Some(Code::UnexpectedPacket)
+            );
+        }
+    }
+
+    #[test]
+    fn closing_admits_nothing() {
+        for value in 0.. {
+            let Ok(packet_type) = PacketType::try_from(value) else {
+                break;
+            };
+            assert_eq!(
+                refusal(Phase::Closing, packet_type),
+                Some(Code::UnexpectedPacket)
+            );
+        }
+    }
+
+    #[test]
+    fn login_and_join_in_order() {
+        assert_eq!(refusal(Phase::Negotiated, PacketType::AuthRequest), None);
+        assert_eq!(
+            refusal(Phase::Negotiated, PacketType::JoinRoomRequest),
+            Some(Code::NotAuthenticated)
+        );
+        assert_eq!(
+            refusal(Phase::Authenticated, PacketType::AuthRequest),
+            Some(Code::UnexpectedPacket)
+        );
+        assert_eq!(
+            refusal(Phase::Authenticated, PacketType::JoinRoomRequest),
+            None
+        );
+        assert_eq!(
+            refusal(Phase::InRoom, PacketType::AuthRequest),
+            Some(Code::UnexpectedPacket)
+        );
+        assert_eq!(
+            refusal(Phase::InRoom, PacketType::JoinRoomRequest),
+            Some(Code::AlreadyInRoom)
+        );
+    }
+
+    #[test]
+    fn room_packets_need_a_room() {
+        assert_eq!(
+            refusal(Phase::Negotiated, PacketType::ChatMessage),
+            Some(Code::NotAuthenticated)
+        );
+        assert_eq!(
+            refusal(Phase::Authenticated, PacketType::ChatMessage),
+            Some(Code::UnexpectedPacket)
+        );
+        for packet_type in [
+            PacketType::Reaction,
+            PacketType::LobbyDecision,
+            PacketType::SlowMode,
+        ] {
+            for phase in [Phase::Negotiated, Phase::Authenticated] {
+                assert_eq!(refusal(phase, packet_type), Some(Code::UnexpectedPacket));
+            }
+        }
+        for packet_type in [
+            PacketType::ChatMessage,
+            PacketType::Reaction,
+            PacketType::LobbyDecision,
+            PacketType::SlowMode,
+        ] {
+            assert_eq!(refusal(Phase::InRoom, packet_type), None);
+        }
+    }
+
+    #[test]
+    fn session_packets_in_any_phase_after_the_handshake() {
+        for phase in [Phase::Negotiated, Phase::Authenticated, Phase::InRoom] {
+            for packet_type in [
+                PacketType::Ping,
+                PacketType::ClockSync,
+                PacketType::BlockList,
+            ] {
+                assert_eq!(
+                    refusal(phase, packet_type),
+                    None,
+                    "{phase:?} {packet_type:?}"
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,189 @@
+//! Waiting rooms: rooms in `lobby.rooms`, whose joiners wait until a moderator admits them.
+//!
+//! Joiners whose account is not a moderator's or an admin's are put in the room's lobby rather
+//! than the room. They stay registered, but in no room, so none of their voice is forwarded and
+//! they hear nobody. The room's moderators whose clients negotiated `FEATURE_LOBBY` are sent a
+//! LOBBY_PENDING whenever a joiner starts or stops waiting, and admit or refuse them with
+//! LOBBY_DECISION. A joiner leaves the lobby when its [`Ticket`] is dropped, so sessions that
+//! end or join elsewhere while waiting are taken off the moderators' lists.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use anyhow::Result;
+use anyhow::bail;
+use protobuf::system;
+use protobuf::system::PacketType;
+use tokio::sync::oneshot;
+
+use crate::config::Config;
+use crate::protocol;
+use crate::rooms::Registry;
+use crate::rooms::SessionId;
+
+pub fn validate(config: &Config) -> Result<()> {
+    if !config.lobby.rooms.is_empty() && config.accounts.path.is_none() {
+        bail!("lobby.rooms needs accounts.path, since only accounts can be moderators");
+    }
+    Ok(())
+}
+
+/// The sessions waiting to join each room with a waiting room.
+pub struct Lobby {
+    registry: Arc<Registry>,
+    rooms: Mutex<HashMap<String, Vec<Waiter>>>,
+}
+
+struct Waiter {
+    session_id: SessionId,
+    username: String,
+    decision: oneshot::Sender<bool>,
+}
+
+/// A session's place in a room's lobby, which it leaves when this is dropped.
+pub struct Ticket {
+    lobby: Arc<Lobby>,
+    session_id: SessionId,
+    room_key: String,
+
+    /// The room as the client named it.
+    name: String,
+
+    decision: oneshot::Receiver<bool>,
+}
+
+impl Lobby {
+    pub fn new(registry: Arc<Registry>) -> Self {
+        Self {
+            registry,
+            rooms: Mutex::default(),
+        }
+    }
+
+    /// Puts a session in a room's lobby, and tells the room's moderators.
+    pub fn wait(
+        self: &Arc<Self>,
+        room_key: &str,
+        name: &str,
+        session_id: SessionId,
+        username: &str,
+    ) -> Ticket {
+        let (decision, receiver) = oneshot::channel();
+        self.rooms
+            .lock()
+            .unwrap()
+            .entry(room_key.to_owned())
+            .or_default()
+            .push(Waiter {
+                session_id,
+                username: username.to_owned(),
+                decision,
+            });
+        announce(&self.registry, room_key, session_id, username, true);
+        Ticket {
+            lobby: self.clone(),
+            session_id,
+            room_key: room_key.to_owned(),
+            name: name.to_owned(),
+            decision: receiver,
+        }
+    }
+
+    /// Who waits to join a room, as sent to a moderator joining it.
+    pub fn waiting(&self, room_key: &str) -> Vec<system::LobbyPending> {
+        let rooms = self.rooms.lock().unwrap();
+        rooms
+            .get(room_key)
+            .into_iter()
+            .flatten()
+            .map(|waiter| system::LobbyPending {
+                session_id: waiter.session_id,
+                username: waiter.username.clone(),
+                waiting: true,
+            })
+            .collect()
+    }
+
+    /// Admits or refuses a session waiting to join a room, and tells the room's moderators.
+    /// Returns `false` if it is not waiting.
+    pub fn decide(&self, room_key: &str, session_id: SessionId, admit: bool) -> bool {
+        let Some(waiter) = self.remove(room_key, session_id) else {
+            return false;
+        };
+        let _ = waiter.decision.send(admit);
+        announce(
+            &self.registry,
+            room_key,
+            session_id,
+            &waiter.username,
+            false,
+        );
+        true
+    }
+
+    fn remove(&self, room_key: &str, session_id: SessionId) -> Option<Waiter> {
+        let mut rooms = self.rooms.lock().unwrap();
+        let waiters = rooms.get_mut(room_key)?;
+        let index = waiters
+            .iter()
+            .position(|waiter| waiter.session_id == session_id)?;
+        let waiter = waiters.remove(index);
+        if waiters.is_empty() {
+            rooms.remove(room_key);
+        }
+        Some(waiter)
+    }
+}
+
+impl Ticket {
+    pub fn room_key(&self) -> &str {
+        &self.room_key
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Waits for a moderator to admit or refuse the session, returning whether one admitted it.
+    pub async fn decision(&mut self) -> bool {
+        (&mut self.decision).await.unwrap_or(false)
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        let Some(waiter) = self.lobby.remove(&self.room_key, self.session_id) else {
+            return;
+        };
+        announce(
+            &self.lobby.registry,
+            &self.room_key,
+            self.session_id,
+            &waiter.username,
+            false,
+        );
+    }
+}
+
+/// Tells a room's moderators that a session started or stopped waiting to join it, but those
+/// whose control queue is full.
+fn announce(
+    registry: &Registry,
+    room_key: &str,
+    session_id: SessionId,
+    username: &str,
+    waiting: bool,
+) {
+    let packet = protocol::encode(
+        PacketType::LobbyPending,
+        &system::LobbyPending {
+            session_id,
+            username: username.to_owned(),
+            waiting,
+        },
+    );
+    for outbox in registry.room_moderators(room_key) {
+        outbox.try_send_control(packet.clone());
+    }
+}
@@ -0,0 +1,135 @@
+//! Sampled logging of events that can happen for every packet or frame.
+//!
+//! Each kind of such event logs through a [`Sampler`] of its own, which lets one event in
+//! `every` into the log and counts the rest. `log.sample_rates` sets `every` by the sampler's
+//! name, over its default, and once every `log.summary_secs` the server logs how many events of
+//! each kind it left out since the last summary, so a flood still shows in the log as a line a
+//! minute instead of drowning it.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::Once;
+use std::sync::RwLock;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use tracing::info;
+
+use crate::config::LogConfig;
+
+/// Control packets refused with an ERROR, which a misbehaving client can send nonstop.
+pub static REJECTED_PACKETS: Sampler = Sampler::new("rejected_packets", 10);
+
+/// Periodic control packets logged by `--dump-packets`, such as CLOCK_SYNC, SESSION_STATS,
+/// PING and FRAGMENT, which every session sends or receives every second or more often.
+pub static DUMPED_PACKETS: Sampler = Sampler::new("dumped_packets", 100);
+
+/// Audio frames dropped for being too large for a datagram.
+pub static OVERSIZED_FRAMES: Sampler = Sampler::new("oversized_frames", 100);
+
+/// Packets and media frames that could not be written to their streams.
+pub static STREAM_SEND_FAILURES: Sampler = Sampler::new("stream_send_failures", 100);
+
+/// Voice frames that could not be relayed over a trunk.
+pub static RELAY_FAILURES: Sampler = Sampler::new("relay_failures", 100);
+
+/// Frames relayed over a trunk that were malformed or for rooms the peer may not relay into.
+pub static IGNORED_RELAYED_FRAMES: Sampler = Sampler::new("ignored_relayed_frames", 100);
+
+/// The samplers that have sampled an event, for summaries.
+static SAMPLERS: Mutex<Vec<&'static Sampler>> = Mutex::new(Vec::new());
+
+/// `log.sample_rates`, for samplers that sample their first event after it was applied.
+static RATES: RwLock<Option<HashMap<String, u64>>> = RwLock::new(None);
+
+/// `log.summary_secs`.
+static SUMMARY_SECS: AtomicU64 = AtomicU64::new(60);
+
+/// Decides which events of one kind are logged.
+pub struct Sampler {
+    /// What the events are, to name them in `log.sample_rates` and summaries.
+    name: &'static str,
+
+    /// Logs one event in this many when `log.sample_rates` does not name the sampler.
+    default_every: u64,
+
+    every: AtomicU64,
+    seen: AtomicU64,
+
+    /// Events left out since the last summary.
+    left_out: AtomicU64,
+
+    registered: Once,
+}
+
+impl Sampler {
+    pub const fn new(name: &'static str, default_every: u64) -> Self {
+        Self {
+            name,
+            default_every,
+            every: AtomicU64::new(default_every),
+            seen: AtomicU64::new(0),
+            left_out: AtomicU64::new(0),
+            registered: Once::new(),
+        }
+    }
+
+    /// Whether to log this event: the first, then one in every `every`.
+    pub fn sample(&'static self) -> bool {
+        self.registered.call_once(|| {
+            if let Some(rates) = RATES.read().unwrap().as_ref() {
+                self.apply(rates);
+            }
+            SAMPLERS.lock().unwrap().push(self);
+        });
+
+        let every = self.every.load(Ordering::Relaxed).max(1);
+        if self
+            .seen
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(every)
+        {
+            return true;
+        }
+        self.left_out.fetch_add(1, Ordering::Relaxed);
+        false
+    }
+
+    fn apply(&self, rates: &HashMap<String, u64>) {
+        let every = rates.get(self.name).copied().unwrap_or(self.default_every);
+        self.every.store(every, Ordering::Relaxed);
+    }
+}
+
+/// Samples events as `config.sample_rates` says from now on, and summarizes them every
+/// `config.summary_secs`.
+pub fn configure(config: &LogConfig) {
+    SUMMARY_SECS.store(config.summary_secs.max(1), Ordering::Relaxed);
+    let mut rates = RATES.write().unwrap();
+    for sampler in SAMPLERS.lock().unwrap().iter() {
+        sampler.apply(&config.sample_rates);
+    }
+    *rates = Some(config.sample_rates.clone());
+}
+
+/// Logs how many events of each kind were left out, every `log.summary_secs`, forever.
+pub async fn summarize() {
+    loop {
+        let secs = SUMMARY_SECS.load(Ordering::Relaxed);
+        tokio::time::sleep(Duration::from_secs(secs)).await;
+
+        let samplers = SAMPLERS.lock().unwrap().clone();
+        for sampler in samplers {
+            let left_out = sampler.left_out.swap(0, Ordering::Relaxed);
+            if left_out > 0 {
+                info!(
+                    "Left {left_out} {} events out of the log in the last {secs} seconds, \
+                     logging 1 in {}",
+                    sampler.name,
+                    sampler.every.load(Ordering::Relaxed).max(1)
+                );
+            }
+        }
+    }
+}
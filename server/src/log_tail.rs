@@ -0,0 +1,156 @@
+//! The tail of the server's log, for the debug page.
+//!
+//! The [`LogTailLayer`] sees the same events as the log, through the same `log.level` filter,
+//! and keeps the last [`CAPACITY`] of them as JSON lines, with the fields their spans recorded,
+//! such as a connection's user and room. Followers get those, then every new line as it is
+//! logged.
+
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::sync::LazyLock;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use serde_json::json;
+use tokio::sync::broadcast;
+use tracing::Event;
+use tracing::Subscriber;
+use tracing::field::Field;
+use tracing::field::Visit;
+use tracing::span::Attributes;
+use tracing::span::Id;
+use tracing::span::Record;
+use tracing_subscriber::Layer;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+
+/// How many lines are kept for new followers.
+const CAPACITY: usize = 500;
+
+static TAIL: LazyLock<Tail> = LazyLock::new(|| Tail {
+    lines: Mutex::new(VecDeque::with_capacity(CAPACITY)),
+    sender: broadcast::channel(CAPACITY).0,
+});
+
+struct Tail {
+    lines: Mutex<VecDeque<String>>,
+    sender: broadcast::Sender<String>,
+}
+
+/// The lines kept, oldest first, and a receiver of the lines logged after them.
+pub fn follow() -> (Vec<String>, broadcast::Receiver<String>) {
+    let lines = TAIL.lines.lock().unwrap();
+    // Subscribing under the lock means no line is missed or seen twice.
+    let receiver = TAIL.sender.subscribe();
+    (lines.iter().cloned().collect(), receiver)
+}
+
+/// Keeps log events in the tail.
+pub struct LogTailLayer;
+
+impl<S> Layer<S> for LogTailLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut fields = SpanFields::default();
+        attrs.record(&mut fields);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(fields);
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        if let Some(fields) = span.extensions_mut().get_mut::<SpanFields>() {
+            values.record(fields);
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        let spans: Vec<String> = ctx
+            .event_scope(event)
+            .map(|scope| {
+                scope
+                    .from_root()
+                    .map(|span| match span.extensions().get::<SpanFields>() {
+                        Some(SpanFields(fields)) if !fields.is_empty() => {
+                            let fields: Vec<String> = fields
+                                .iter()
+                                .map(|(name, value)| format!("{name}={value}"))
+                                .collect();
+                            format!("{}{{{}}}", span.name(), fields.join(" "))
+                        }
+                        _ => span.name().to_owned(),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let time_ms = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |time| time.as_millis() as u64);
+        let metadata = event.metadata();
+        let line = json!({
+            "time_ms": time_ms,
+            "level": metadata.level().as_str(),
+            "target": metadata.target(),
+            "spans": spans,
+            "message": fields.message + &fields.rest,
+        })
+        .to_string();
+
+        let mut lines = TAIL.lines.lock().unwrap();
+        if lines.len() == CAPACITY {
+            lines.pop_front();
+        }
+        lines.push_back(line.clone());
+        // Nobody may be following, which is fine.
+        let _ = TAIL.sender.send(line);
+    }
+}
+
+/// An event's message, and its other fields as ` name=value`.
+#[derive(Default)]
+struct Fields {
+    message: String,
+    rest: String,
+}
+
+impl Visit for Fields {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.message, "{value:?}");
+        } else {
+            let _ = write!(self.rest, " {}={value:?}", field.name());
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message.push_str(value);
+        } else {
+            let _ = write!(self.rest, " {}={value}", field.name());
+        }
+    }
+}
+
+/// The fields a span recorded so far, in the order they were first recorded.
+#[derive(Default)]
+struct SpanFields(Vec<(&'static str, String)>);
+
+impl Visit for SpanFields {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.record_str(field, &format!("{value:?}"));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match self.0.iter_mut().find(|(name, _)| *name == field.name()) {
+            Some((_, recorded)) => *recorded = value.to_owned(),
+            None => self.0.push((field.name(), value.to_owned())),
+        }
+    }
+}
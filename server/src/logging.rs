@@ -0,0 +1,32 @@
+//! Logging to standard output, with the tail kept for the debug page and spans timed for the
+//! profiler.
+
+use tracing_subscriber::Layer;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+use crate::log_tail::LogTailLayer;
+use crate::profiler::ProfilerLayer;
+use crate::reload;
+
+/// Installs the server's logging as the global subscriber. Applications embedding the server
+/// may install their own instead, but then `log.level`, the debug page's log and the profiler
+/// do nothing.
+pub fn init_logging() {
+    // `log.level` replaces the filter once the config is loaded, and on every reload.
+    let (env_filter, handle) =
+        tracing_subscriber::reload::Layer::new(reload::log_filter(None).unwrap());
+    reload::set_log_filter_handle(handle);
+
+    // The filter only applies to logging and its tail, so the profiler sees every span.
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_target(true)
+                .with_level(true)
+                .and_then(LogTailLayer)
+                .with_filter(env_filter),
+        )
+        .with(ProfilerLayer)
+        .init();
+}
@@ -0,0 +1,180 @@
+//! Maintenance windows: shutdowns the operator schedules through the admin API, which clients
+//! count down to.
+//!
+//! Every connected client is sent a MAINTENANCE when a window is scheduled, then again an hour,
+//! half an hour, 15, 10, 5, 2 and 1 minutes and 30 and 10 seconds before it starts, and clients
+//! that authenticate meanwhile are sent one too. A client whose queue is full misses a notice
+//! rather than hold up the countdown, and catches the next. Joins are refused in the last
+//! `maintenance.join_cutoff_mins`, and when the window starts the server drains as for `Drain`.
+//! Windows are kept in memory only, since the server is meant to go down at them.
+
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use protobuf::system;
+use protobuf::system::PacketType;
+use tracing::info;
+
+use crate::accounts;
+use crate::handoff;
+use crate::protocol;
+use crate::state::ServerState;
+
+/// How many seconds before a window starts clients are reminded of it.
+const COUNTDOWN_SECS: [u64; 9] = [3600, 1800, 900, 600, 300, 120, 60, 30, 10];
+
+/// How often windows are checked for countdowns to send and drains to start.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The maintenance window scheduled, if any.
+#[derive(Default)]
+pub struct Maintenance {
+    window: Mutex<Option<Window>>,
+}
+
+pub struct Window {
+    /// Seconds since the Unix epoch.
+    pub starts_at: u64,
+
+    pub reason: String,
+
+    /// Where to drain to, if anywhere.
+    pub address: Option<String>,
+
+    /// How long the drain waits for sessions to end.
+    pub timeout: Duration,
+
+    /// How many of [`COUNTDOWN_SECS`] have passed, so each reminder is sent once.
+    reminded: usize,
+}
+
+impl Window {
+    pub fn new(starts_at: u64, reason: String, address: Option<String>, timeout: Duration) -> Self {
+        let secs_left = starts_at.saturating_sub(accounts::now_secs());
+        Self {
+            starts_at,
+            reason,
+            address,
+            timeout,
+            // Clients are told of the window when it is scheduled, so only the reminders after
+            // that are left.
+            reminded: COUNTDOWN_SECS
+                .iter()
+                .take_while(|&&secs| secs >= secs_left)
+                .count(),
+        }
+    }
+
+    fn notice(&self, now: u64) -> system::Maintenance {
+        system::Maintenance {
+            starts_at: self.starts_at,
+            secs_left: u32::try_from(self.starts_at.saturating_sub(now)).unwrap_or(u32::MAX),
+            reason: self.reason.clone(),
+        }
+    }
+}
+
+impl Maintenance {
+    /// Schedules a window, replacing the one scheduled if any. Returns whether there was one.
+    pub fn schedule(&self, window: Window) -> bool {
+        info!(
+            "Scheduled maintenance at {}: {}",
+            window.starts_at, window.reason
+        );
+        self.window.lock().unwrap().replace(window).is_some()
+    }
+
+    /// Cancels the window scheduled. Returns `false` if there is none.
+    pub fn cancel(&self) -> bool {
+        let cancelled = self.window.lock().unwrap().take().is_some();
+        if cancelled {
+            info!("Cancelled maintenance");
+        }
+        cancelled
+    }
+
+    /// What clients are told of the window scheduled, or of its cancellation if there is none.
+    pub fn notice(&self) -> system::Maintenance {
+        let window = self.window.lock().unwrap();
+        window
+            .as_ref()
+            .map(|window| window.notice(accounts::now_secs()))
+            .unwrap_or_default()
+    }
+
+    /// Whether a window is scheduled.
+    pub fn is_scheduled(&self) -> bool {
+        self.window.lock().unwrap().is_some()
+    }
+
+    /// How many seconds are left until the window scheduled, if it starts within `cutoff`, when
+    /// joins are refused.
+    pub fn closing(&self, cutoff: Duration) -> Option<u64> {
+        let window = self.window.lock().unwrap();
+        let secs_left = window
+            .as_ref()?
+            .starts_at
+            .saturating_sub(accounts::now_secs());
+        (secs_left <= cutoff.as_secs()).then_some(secs_left)
+    }
+}
+
+/// Sends every connected client what they are told of the window scheduled.
+pub fn notify(state: &ServerState) {
+    send(state, &state.maintenance.notice());
+}
+
+fn send(state: &ServerState, notice: &system::Maintenance) {
+    let packet = protocol::encode(PacketType::Maintenance, notice);
+    for (_, outbox) in state.registry.client_outboxes() {
+        outbox.try_send_control(packet.clone());
+    }
+}
+
+/// Counts down to the windows scheduled and drains the server when one starts, for as long as
+/// the server runs.
+pub async fn run(state: Arc<ServerState>) {
+    let mut ticks = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        ticks.tick().await;
+        match check(&state) {
+            Some(Check::Remind(notice)) => send(&state, &notice),
+            Some(Check::Start(window)) => {
+                info!("Maintenance starts: {}", window.reason);
+                send(&state, &window.notice(window.starts_at));
+                if handoff::drain(&state, window.address.as_deref(), window.timeout)
+                    .await
+                    .is_none()
+                {
+                    info!("The server is already draining");
+                }
+            }
+            None => {}
+        }
+    }
+}
+
+enum Check {
+    Remind(system::Maintenance),
+    Start(Window),
+}
+
+fn check(state: &ServerState) -> Option<Check> {
+    let now = accounts::now_secs();
+    let mut window = state.maintenance.window.lock().unwrap();
+    let scheduled = window.as_mut()?;
+    if now >= scheduled.starts_at {
+        return window.take().map(Check::Start);
+    }
+    let secs_left = scheduled.starts_at - now;
+    let due = COUNTDOWN_SECS
+        .iter()
+        .take_while(|&&secs| secs >= secs_left)
+        .count();
+    if due <= scheduled.reminded {
+        return None;
+    }
+    scheduled.reminded = due;
+    Some(Check::Remind(scheduled.notice(now)))
+}
@@ -0,0 +1,306 @@
+//! A Matrix bot bridging voice rooms' chat and presence, so each room has a persistent,
+//! federated text counterpart.
+//!
+//! With `matrix.homeserver` set, the bot joins the Matrix room paired with each voice room in
+//! `matrix.rooms`. Chat in a voice room is posted to its Matrix room as `<username>: <text>`,
+//! and joins and leaves as notices. Text messages sent in the Matrix room reach the voice room as
+//! chat from their sender's Matrix ID, with a session ID of 0. Notices are not bridged, so bots
+//! in the Matrix room never loop.
+//!
+//! The bot speaks the client-server API over plain HTTP; put a proxy in front of homeservers that
+//! require TLS.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use anyhow::Result;
+use protobuf::system::ChatMessage;
+use serde::Deserialize;
+use serde_json::Value;
+use serde_json::json;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::Instrument;
+use tracing::info;
+use tracing::info_span;
+use tracing::warn;
+
+use crate::events::Event;
+use crate::http_client;
+use crate::protocol;
+use crate::state::ServerState;
+
+/// How long the homeserver holds each sync request open when nothing happens.
+const SYNC_TIMEOUT: Duration = Duration::from_secs(30);
+
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+struct Bridge {
+    state: Arc<ServerState>,
+
+    /// The client-server API's base URL.
+    api: String,
+
+    /// The bot's own Matrix ID, whose messages are not bridged back.
+    user_id: String,
+
+    room_ids: HashMap<String, String>,
+    room_keys: HashMap<String, String>,
+
+    /// Transaction IDs must be unique for the access token, even across restarts.
+    transactions: AtomicU64,
+    transaction_prefix: u64,
+}
+
+#[derive(Deserialize)]
+struct WhoAmI {
+    user_id: String,
+}
+
+#[derive(Deserialize)]
+struct Sync {
+    next_batch: String,
+
+    #[serde(default)]
+    rooms: SyncRooms,
+}
+
+#[derive(Default, Deserialize)]
+struct SyncRooms {
+    #[serde(default)]
+    join: HashMap<String, JoinedRoom>,
+}
+
+#[derive(Deserialize)]
+struct JoinedRoom {
+    #[serde(default)]
+    timeline: Timeline,
+}
+
+#[derive(Default, Deserialize)]
+struct Timeline {
+    #[serde(default)]
+    events: Vec<RoomEvent>,
+}
+
+#[derive(Deserialize)]
+struct RoomEvent {
+    #[serde(rename = "type")]
+    event_type: String,
+    sender: String,
+
+    #[serde(default)]
+    content: Value,
+}
+
+/// Spawns the bridge, if a homeserver is configured.
+pub fn bridge(state: &Arc<ServerState>) {
+    if state.config.matrix.homeserver.is_none() {
+        return;
+    }
+
+    let state = state.clone();
+    tokio::spawn(
+        async move {
+            let bridge = loop {
+                match Bridge::connect(&state).await {
+                    Ok(bridge) => break Arc::new(bridge),
+                    Err(err) => warn!("Cannot connect to the homeserver: {err:#}"),
+                }
+                tokio::time::sleep(RETRY_DELAY).await;
+            };
+            tokio::join!(bridge.post_events(), bridge.sync_messages());
+        }
+        .instrument(info_span!("Matrix bridge")),
+    );
+}
+
+impl Bridge {
+    /// Finds out who the bot is and joins every bridged room.
+    async fn connect(state: &Arc<ServerState>) -> Result<Self> {
+        let config = &state.config.matrix;
+        let homeserver = config.homeserver.as_deref().unwrap_or_default();
+        let mut bridge = Self {
+            state: state.clone(),
+            api: format!("{}/_matrix/client/v3", homeserver.trim_end_matches('/')),
+            user_id: String::new(),
+            room_ids: HashMap::new(),
+            room_keys: HashMap::new(),
+            transactions: AtomicU64::new(0),
+            transaction_prefix: rand::random(),
+        };
+
+        let who_am_i: WhoAmI = bridge.call("GET", "/account/whoami", None).await?;
+        bridge.user_id = who_am_i.user_id;
+        for room in &config.rooms {
+            let path = format!("/join/{}", http_client::percent_encode(&room.room_id));
+            if let Err(err) = bridge.call::<Value>("POST", &path, Some(&json!({}))).await {
+                warn!("Cannot join {}: {err:#}", room.room_id);
+            }
+            bridge
+                .room_ids
+                .insert(room.room_key.clone(), room.room_id.clone());
+            bridge
+                .room_keys
+                .insert(room.room_id.clone(), room.room_key.clone());
+        }
+
+        info!(
+            "Bridging {} rooms as {}",
+            config.rooms.len(),
+            bridge.user_id
+        );
+        Ok(bridge)
+    }
+
+    /// Posts the voice rooms' chat, joins and leaves to their Matrix rooms.
+    async fn post_events(&self) {
+        let mut events = self.state.registry.events().subscribe();
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Bridge fell {skipped} events behind");
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+            let Some(room_id) = event
+                .room_key()
+                .and_then(|room_key| self.room_ids.get(&**room_key))
+            else {
+                continue;
+            };
+
+            let content = match event {
+                Event::SessionStarted { .. }
+                | Event::SessionEnded { .. }
+                | Event::RoomOpened { .. }
+                | Event::RoomClosed { .. }
+                | Event::SpeakingStarted { .. }
+                | Event::SpeakingStopped { .. } => continue,
+                Event::Joined { user, .. } => json!({
+                    "msgtype": "m.notice",
+                    "body": format!("{} joined the voice room", user.username),
+                }),
+                Event::Left { user, .. } => json!({
+                    "msgtype": "m.notice",
+                    "body": format!("{} left the voice room", user.username),
+                }),
+                // Messages bridged from outside the server, including from Matrix itself.
+                Event::Chat { message, .. } if message.session_id == 0 => continue,
+                Event::Chat { message, .. } => json!({
+                    "msgtype": "m.text",
+                    "body": format!("{}: {}", message.username, message.text),
+                }),
+            };
+
+            let transaction = self.transactions.fetch_add(1, Ordering::Relaxed);
+            let path = format!(
+                "/rooms/{}/send/m.room.message/{:016x}-{transaction}",
+                http_client::percent_encode(room_id),
+                self.transaction_prefix,
+            );
+            if let Err(err) = self.call::<Value>("PUT", &path, Some(&content)).await {
+                warn!("Cannot post to {room_id}: {err:#}");
+            }
+        }
+    }
+
+    /// Delivers messages sent in the bridged Matrix rooms to their voice rooms.
+    async fn sync_messages(&self) {
+        let room_ids: Vec<&String> = self.room_keys.keys().collect();
+        let filter = json!({
+            "room": {
+                "rooms": room_ids,
+                "timeline": { "types": ["m.room.message"] },
+                "state": { "types": [] },
+                "ephemeral": { "types": [] },
+                "account_data": { "types": [] },
+            },
+            "presence": { "types": [] },
+            "account_data": { "types": [] },
+        });
+        let filter = http_client::percent_encode(&filter.to_string());
+
+        // The first sync only finds where the timelines are now, so history is not replayed.
+        let mut since: Option<String> = None;
+        loop {
+            let path = match &since {
+                Some(since) => format!(
+                    "/sync?filter={filter}&since={}&timeout={}",
+                    http_client::percent_encode(since),
+                    SYNC_TIMEOUT.as_millis()
+                ),
+                None => format!("/sync?filter={filter}&timeout=0"),
+            };
+            let sync: Sync = match self.call("GET", &path, None).await {
+                Ok(sync) => sync,
+                Err(err) => {
+                    warn!("Cannot sync with the homeserver: {err:#}");
+                    tokio::time::sleep(RETRY_DELAY).await;
+                    continue;
+                }
+            };
+
+            if since.is_some() {
+                for (room_id, room) in sync.rooms.join {
+                    for event in room.timeline.events {
+                        self.deliver(&room_id, event).await;
+                    }
+                }
+            }
+            since = Some(sync.next_batch);
+        }
+    }
+
+    async fn deliver(&self, room_id: &str, event: RoomEvent) {
+        let Some(room_key) = self.room_keys.get(room_id) else {
+            return;
+        };
+        if event.event_type != "m.room.message" || event.sender == self.user_id {
+            return;
+        }
+        let Some(body) = event.content["body"].as_str() else {
+            return;
+        };
+        let text = match event.content["msgtype"].as_str() {
+            Some("m.text") => body.to_owned(),
+            Some("m.emote") => format!("* {body}"),
+            _ => return,
+        };
+
+        let message = ChatMessage {
+            session_id: 0,
+            username: event.sender,
+            text: text.chars().take(protocol::MAX_CHAT_LEN).collect(),
+            ..Default::default()
+        };
+        if let Err(reason) = self.state.send_chat(room_key, message) {
+            info!("Not bridging a message from {room_id}: {reason}");
+        }
+    }
+
+    async fn call<T: serde::de::DeserializeOwned>(
+        &self,
+        method: &str,
+        path: &str,
+        body: Option<&Value>,
+    ) -> Result<T> {
+        let timeout = if path.starts_with("/sync") {
+            SYNC_TIMEOUT + http_client::TIMEOUT
+        } else {
+            http_client::TIMEOUT
+        };
+        http_client::authorized_json(
+            method,
+            &format!("{}{path}", self.api),
+            &self.state.config.matrix.access_token,
+            body,
+            timeout,
+        )
+        .await
+    }
+}
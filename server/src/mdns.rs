@@ -0,0 +1,490 @@
+//! LAN discovery: the server advertises itself with mDNS as a `_voicechat._udp` DNS-SD service,
+//! so native clients on the local network find it without typing an address.
+//!
+//! The service's SRV record gives the WebTransport port, and its TXT record the certificate
+//! digest (`digest`, base64 encoded), the HTTP port (`http_port`) and the protocol version
+//! (`protocol`). The server announces the service at startup, answers queries for it on
+//! `224.0.0.251:5353`, and withdraws it when shutting down. Only IPv4 is advertised.
+
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+use std::net::SocketAddrV4;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use anyhow::Result;
+use anyhow::bail;
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use socket2::Domain;
+use socket2::Protocol;
+use socket2::Socket;
+use socket2::Type;
+use tokio::net::UdpSocket;
+use tracing::debug;
+use tracing::info;
+use tracing::warn;
+use wtransport::tls::Sha256Digest;
+
+use crate::config::MdnsConfig;
+use crate::protocol;
+
+const GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const PORT: u16 = 5353;
+
+const SERVICE: &str = "_voicechat._udp.local";
+
+/// The name DNS-SD browsers query to list every service type on the network.
+const SERVICE_TYPES: &str = "_services._dns-sd._udp.local";
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+
+const CLASS_IN: u16 = 1;
+
+/// Set on the class of records only this server answers for, so caches replace older ones.
+const CACHE_FLUSH: u16 = 0x8000;
+
+/// Set on the class of questions asking for a unicast response.
+const UNICAST_RESPONSE: u16 = 0x8000;
+
+/// The TTLs RFC 6762 recommends for records naming hosts, and for the others.
+const HOST_TTL: u32 = 120;
+const OTHER_TTL: u32 = 4500;
+
+/// The most a one-shot querier, which cannot refresh records, is told to cache them.
+const LEGACY_TTL: u32 = 10;
+
+/// How far apart the startup announcements are sent.
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The longest DNS message accepted.
+const MAX_MESSAGE_LEN: usize = 9000;
+
+pub struct Advertiser {
+    socket: UdpSocket,
+    instance: String,
+    host: String,
+    address: Ipv4Addr,
+    port: u16,
+    txt: Vec<String>,
+}
+
+/// A resource record to send.
+struct Record<'a> {
+    name: &'a str,
+    ttl: u32,
+    unique: bool,
+    data: RecordData<'a>,
+}
+
+enum RecordData<'a> {
+    A(Ipv4Addr),
+    Ptr(&'a str),
+    Txt(&'a [String]),
+    Srv { port: u16, target: &'a str },
+}
+
+impl Advertiser {
+    /// Joins the mDNS group to advertise WebTransport on `port` and HTTP on `http_port`.
+    pub fn bind(
+        config: &MdnsConfig,
+        cert_digest: &Sha256Digest,
+        port: u16,
+        http_port: u16,
+    ) -> Result<Arc<Self>> {
+        let address = match config.address {
+            Some(address) => address,
+            None => lan_address().context("Cannot find the LAN address; set mdns.address")?,
+        };
+        let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname")
+            .ok()
+            .map(|hostname| hostname.trim().to_owned())
+            .filter(|hostname| !hostname.is_empty())
+            .unwrap_or_else(|| "voice-chat".to_owned());
+        let instance = config
+            .instance_name
+            .clone()
+            .unwrap_or_else(|| format!("Voice chat on {hostname}"));
+        if instance.len() > 63 || instance.contains('.') {
+            bail!("mdns.instance_name must be at most 63 bytes long, without dots");
+        }
+
+        // Other responders on the host, such as Avahi, share the port.
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        socket.set_reuse_port(true)?;
+        socket
+            .bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, PORT)).into())
+            .context("Cannot bind the mDNS port")?;
+        socket
+            .join_multicast_v4(&GROUP, &address)
+            .context("Cannot join the mDNS group")?;
+        socket.set_multicast_if_v4(&address)?;
+        socket.set_multicast_ttl_v4(255)?;
+        socket.set_multicast_loop_v4(true)?;
+        socket.set_nonblocking(true)?;
+
+        info!("Advertising '{instance}' on {address} with mDNS");
+        Ok(Arc::new(Self {
+            socket: UdpSocket::from_std(socket.into())?,
+            instance: format!("{instance}.{SERVICE}"),
+            host: format!("{hostname}.local"),
+            address,
+            port,
+            txt: vec![
+                format!("digest={}", BASE64_STANDARD.encode(cert_digest.as_ref())),
+                format!("http_port={http_port}"),
+                format!("protocol={}", protocol::PROTOCOL_VERSION),
+            ],
+        }))
+    }
+
+    /// Announces the service, then answers queries for it until the task is dropped.
+    pub async fn serve(self: Arc<Self>) {
+        let announcer = self.clone();
+        tokio::spawn(async move {
+            for _ in 0..2 {
+                announcer.announce(false).await;
+                tokio::time::sleep(ANNOUNCE_INTERVAL).await;
+            }
+        });
+
+        let mut buffer = vec![0; MAX_MESSAGE_LEN];
+        loop {
+            let (len, from) = match self.socket.recv_from(&mut buffer).await {
+                Ok(received) => received,
+                Err(err) => {
+                    warn!("Cannot receive mDNS message: {err}");
+                    continue;
+                }
+            };
+            if let Err(err) = self.answer(&buffer[..len], from).await {
+                debug!("Cannot answer mDNS query from {from}: {err:#}");
+            }
+        }
+    }
+
+    /// Tells the network the service is gone.
+    pub async fn goodbye(&self) {
+        self.announce(true).await;
+    }
+
+    async fn announce(&self, goodbye: bool) {
+        let records = [
+            self.ptr(),
+            self.srv(),
+            self.txt(),
+            self.a(),
+            self.service_type(),
+        ];
+        let records = records.map(|record| Record {
+            ttl: if goodbye { 0 } else { record.ttl },
+            ..record
+        });
+        let message = response(None, &records, &[]);
+        if let Err(err) = self.socket.send_to(&message, (GROUP, PORT)).await {
+            warn!("Cannot send mDNS announcement: {err}");
+        }
+    }
+
+    async fn answer(&self, message: &[u8], from: SocketAddr) -> Result<()> {
+        let query = Query::parse(message)?;
+        let mut answers = Vec::new();
+        let mut additionals = Vec::new();
+        for question in &query.questions {
+            let wants = |qtype| question.qtype == qtype || question.qtype == TYPE_ANY;
+            if question.name.eq_ignore_ascii_case(SERVICE) && wants(TYPE_PTR) {
+                answers.push(self.ptr());
+                additionals.extend([self.srv(), self.txt(), self.a()]);
+            }
+            if question.name.eq_ignore_ascii_case(SERVICE_TYPES) && wants(TYPE_PTR) {
+                answers.push(self.service_type());
+            }
+            if question.name.eq_ignore_ascii_case(&self.instance) {
+                if wants(TYPE_SRV) {
+                    answers.push(self.srv());
+                }
+                if wants(TYPE_TXT) {
+                    answers.push(self.txt());
+                }
+                additionals.push(self.a());
+            }
+            if question.name.eq_ignore_ascii_case(&self.host) && wants(TYPE_A) {
+                answers.push(self.a());
+            }
+        }
+        if answers.is_empty() {
+            return Ok(());
+        }
+        // Several questions may call for the same record, which is sent once.
+        let mut sent = Vec::new();
+        let mut first = |record: &Record<'_>| {
+            let key = (record.name.to_owned(), record.rtype());
+            !sent.contains(&key) && {
+                sent.push(key);
+                true
+            }
+        };
+        answers.retain(&mut first);
+        additionals.retain(&mut first);
+
+        // Queriers not on the mDNS port are one-shot resolvers expecting a plain DNS response.
+        if from.port() != PORT {
+            let answers: Vec<_> = answers.into_iter().map(Record::legacy).collect();
+            let additionals: Vec<_> = additionals.into_iter().map(Record::legacy).collect();
+            let message = response(Some(&query), &answers, &additionals);
+            self.socket.send_to(&message, from).await?;
+            return Ok(());
+        }
+
+        let message = response(None, &answers, &additionals);
+        if query.questions.iter().all(|question| question.unicast) {
+            self.socket.send_to(&message, from).await?;
+        } else {
+            self.socket.send_to(&message, (GROUP, PORT)).await?;
+        }
+        Ok(())
+    }
+
+    fn ptr(&self) -> Record<'_> {
+        Record {
+            name: SERVICE,
+            ttl: OTHER_TTL,
+            unique: false,
+            data: RecordData::Ptr(&self.instance),
+        }
+    }
+
+    fn service_type(&self) -> Record<'_> {
+        Record {
+            name: SERVICE_TYPES,
+            ttl: OTHER_TTL,
+            unique: false,
+            data: RecordData::Ptr(SERVICE),
+        }
+    }
+
+    fn srv(&self) -> Record<'_> {
+        Record {
+            name: &self.instance,
+            ttl: HOST_TTL,
+            unique: true,
+            data: RecordData::Srv {
+                port: self.port,
+                target: &self.host,
+            },
+        }
+    }
+
+    fn txt(&self) -> Record<'_> {
+        Record {
+            name: &self.instance,
+            ttl: OTHER_TTL,
+            unique: true,
+            data: RecordData::Txt(&self.txt),
+        }
+    }
+
+    fn a(&self) -> Record<'_> {
+        Record {
+            name: &self.host,
+            ttl: HOST_TTL,
+            unique: true,
+            data: RecordData::A(self.address),
+        }
+    }
+}
+
+impl Record<'_> {
+    /// The record as sent to a one-shot querier, which neither refreshes records nor
+    /// understands cache flushes.
+    fn legacy(self) -> Self {
+        Self {
+            ttl: self.ttl.min(LEGACY_TTL),
+            unique: false,
+            ..self
+        }
+    }
+
+    fn rtype(&self) -> u16 {
+        match self.data {
+            RecordData::A(_) => TYPE_A,
+            RecordData::Ptr(_) => TYPE_PTR,
+            RecordData::Txt(_) => TYPE_TXT,
+            RecordData::Srv { .. } => TYPE_SRV,
+        }
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_name(self.name, out);
+        out.extend_from_slice(&self.rtype().to_be_bytes());
+        let class = if self.unique {
+            CLASS_IN | CACHE_FLUSH
+        } else {
+            CLASS_IN
+        };
+        out.extend_from_slice(&class.to_be_bytes());
+        out.extend_from_slice(&self.ttl.to_be_bytes());
+
+        let mut data = Vec::new();
+        match &self.data {
+            RecordData::A(address) => data.extend_from_slice(&address.octets()),
+            RecordData::Ptr(target) => encode_name(target, &mut data),
+            RecordData::Txt(entries) => {
+                for entry in entries.iter() {
+                    data.push(entry.len() as u8);
+                    data.extend_from_slice(entry.as_bytes());
+                }
+            }
+            RecordData::Srv { port, target } => {
+                // Priority and weight.
+                data.extend_from_slice(&[0, 0, 0, 0]);
+                data.extend_from_slice(&port.to_be_bytes());
+                encode_name(target, &mut data);
+            }
+        }
+        out.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        out.extend_from_slice(&data);
+    }
+}
+
+/// Encodes a response repeating `query`'s questions, if any.
+fn response(
+    query: Option<&Query<'_>>,
+    answers: &[Record<'_>],
+    additionals: &[Record<'_>],
+) -> Vec<u8> {
+    let (id, question_count, questions) = match query {
+        Some(query) => (query.id, query.questions.len() as u16, query.questions_raw),
+        None => (0, 0, &[][..]),
+    };
+    let mut out = Vec::with_capacity(512);
+    out.extend_from_slice(&id.to_be_bytes());
+    // A response, from the authority for its records.
+    out.extend_from_slice(&0x8400u16.to_be_bytes());
+    out.extend_from_slice(&question_count.to_be_bytes());
+    out.extend_from_slice(&(answers.len() as u16).to_be_bytes());
+    out.extend_from_slice(&0u16.to_be_bytes());
+    out.extend_from_slice(&(additionals.len() as u16).to_be_bytes());
+    out.extend_from_slice(questions);
+    for record in answers.iter().chain(additionals) {
+        record.encode(&mut out);
+    }
+    out
+}
+
+fn encode_name(name: &str, out: &mut Vec<u8>) {
+    // Instance names may contain anything but dots, so names split on them.
+    for label in name.split('.') {
+        out.push(label.len() as u8);
+        out.extend_from_slice(label.as_bytes());
+    }
+    out.push(0);
+}
+
+struct Query<'a> {
+    id: u16,
+    questions: Vec<Question>,
+
+    /// The question section as received, repeated in responses to one-shot queriers. Its
+    /// compression pointers stay valid there, since it follows a header of the same length.
+    questions_raw: &'a [u8],
+}
+
+struct Question {
+    name: String,
+    qtype: u16,
+    unicast: bool,
+}
+
+impl<'a> Query<'a> {
+    const HEADER_LEN: usize = 12;
+
+    fn parse(message: &'a [u8]) -> Result<Self> {
+        let Some(header) = message.get(..Self::HEADER_LEN) else {
+            bail!("message too short");
+        };
+        let id = u16::from_be_bytes([header[0], header[1]]);
+        if header[2] & 0x80 != 0 {
+            bail!("not a query");
+        }
+        let count = u16::from_be_bytes([header[4], header[5]]);
+
+        let mut offset = Self::HEADER_LEN;
+        let mut questions = Vec::with_capacity(count.into());
+        for _ in 0..count {
+            let (name, end) = decode_name(message, offset)?;
+            let Some(fields) = message.get(end..end + 4) else {
+                bail!("truncated question");
+            };
+            let class = u16::from_be_bytes([fields[2], fields[3]]);
+            questions.push(Question {
+                name,
+                qtype: u16::from_be_bytes([fields[0], fields[1]]),
+                unicast: class & UNICAST_RESPONSE != 0,
+            });
+            offset = end + 4;
+        }
+        Ok(Self {
+            id,
+            questions,
+            questions_raw: &message[Self::HEADER_LEN..offset],
+        })
+    }
+}
+
+/// Decodes the name at `offset`, following compression pointers. Returns it with the offset
+/// just past it.
+fn decode_name(message: &[u8], mut offset: usize) -> Result<(String, usize)> {
+    let mut name = String::new();
+    let mut end = None;
+    // Each pointer must point backwards, so following them terminates.
+    let mut limit = offset;
+    loop {
+        let Some(&len) = message.get(offset) else {
+            bail!("truncated name");
+        };
+        match len {
+            0 => break,
+            len if len & 0xC0 == 0xC0 => {
+                let Some(&low) = message.get(offset + 1) else {
+                    bail!("truncated name");
+                };
+                let target = usize::from(len & 0x3F) << 8 | usize::from(low);
+                if target >= limit {
+                    bail!("compression pointer does not point backwards");
+                }
+                end.get_or_insert(offset + 2);
+                offset = target;
+                limit = target;
+            }
+            len if len & 0xC0 == 0 => {
+                let label = message
+                    .get(offset + 1..offset + 1 + usize::from(len))
+                    .context("truncated label")?;
+                if !name.is_empty() {
+                    name.push('.');
+                }
+                name.push_str(&String::from_utf8_lossy(label));
+                offset += 1 + usize::from(len);
+            }
+            _ => bail!("invalid label type"),
+        }
+    }
+    Ok((name, end.unwrap_or(offset + 1)))
+}
+
+/// The local address of the route to the mDNS group, which is the host's LAN address.
+pub fn lan_address() -> Result<Ipv4Addr> {
+    let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect(SocketAddrV4::new(GROUP, PORT))?;
+    match socket.local_addr()?.ip() {
+        std::net::IpAddr::V4(address) if !address.is_unspecified() => Ok(address),
+        _ => bail!("no IPv4 route to the mDNS group"),
+    }
+}
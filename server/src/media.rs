@@ -0,0 +1,141 @@
+//! Auxiliary media, such as screen sharing or video, relayed between the members of a room
+//! alongside and independently of their voice.
+//!
+//! A client sends each media track on a unidirectional stream of its own, starting with
+//! [`MEDIA_STREAM_PREFIX`](protocol::MEDIA_STREAM_PREFIX), as described on
+//! [`system::MediaTrack`]. The server relays every frame to the room's other members on
+//! streams it opens to them, one per track, without decoding the frames' payloads. Each
+//! receiver has a [`MediaQueue`] of its own, so a slow receiver only loses its own frames: when
+//! its queue is full, frames are dropped and the track's later frames skipped until its next
+//! keyframe, so its decoder resumes from a clean state.
+//!
+//! [`MediaQueue`]: crate::outbox::MediaQueue
+
+use std::io::Cursor;
+use std::io::ErrorKind;
+use std::sync::Arc;
+
+use bytes::BufMut;
+use bytes::BytesMut;
+use prost::Message;
+use protobuf::system;
+use protobuf::system::PacketType;
+use protobuf::system::error;
+use tokio::io::AsyncReadExt;
+use tracing::info;
+use tracing::warn;
+
+use crate::outbox::Outbox;
+use crate::outbox::Track;
+use crate::protocol;
+use crate::rooms::SessionId;
+use crate::state::ServerState;
+use crate::transport::RecvStream;
+
+/// Tracks each session may send at once.
+pub const MAX_TRACKS_PER_SESSION: usize = 4;
+
+/// The largest accepted media frame.
+const MAX_FRAME_LEN: usize = 1024 * 1024;
+
+/// The longest accepted track kind.
+const MAX_KIND_LEN: usize = 64;
+
+/// Who is sending a track, and where to relay it.
+pub struct Sender {
+    pub session_id: SessionId,
+    pub room_key: Arc<str>,
+    pub outbox: Outbox,
+}
+
+/// Relays a track whose stream's first bytes are already in `received` to the sender's room,
+/// until the stream ends or the sender leaves the room. Failures are reported to the sender.
+pub async fn relay(
+    state: Arc<ServerState>,
+    sender: Sender,
+    stream: Box<dyn RecvStream>,
+    received: Vec<u8>,
+) {
+    let mut reader = Cursor::new(received).chain(stream);
+    let mut track = None;
+    let result = forward(&state, &sender, &mut reader, &mut track).await;
+
+    if let Some(track) = track {
+        for (session_id, outbox) in state.registry.room_members(&sender.room_key) {
+            if session_id != sender.session_id {
+                outbox.end_media(&track);
+            }
+        }
+    }
+    if let Err((code, detail)) = result {
+        warn!("Ending media track: {} ({detail})", code.as_str_name());
+        let packet = protocol::error(code, detail, Some(PacketType::MediaTrack));
+        let _ = sender.outbox.send_control(packet).await;
+    }
+}
+
+/// Why a track could not be relayed, as sent back to its sender.
+type MediaError = (error::Code, String);
+
+async fn forward(
+    state: &ServerState,
+    sender: &Sender,
+    reader: &mut (impl AsyncReadExt + Unpin),
+    relayed: &mut Option<Arc<Track>>,
+) -> Result<(), MediaError> {
+    let malformed = |detail: &str| (error::Code::MalformedPacket, detail.to_owned());
+    let failed =
+        |err: std::io::Error| (error::Code::Unknown, format!("media stream failed: {err}"));
+
+    // The prefix was checked by the session.
+    reader.read_u8().await.map_err(failed)?;
+    let header_len = reader.read_u16().await.map_err(failed)?;
+    let mut header = vec![0; usize::from(header_len)];
+    reader.read_exact(&mut header).await.map_err(failed)?;
+    let mut header = system::MediaTrack::decode(header.as_slice())
+        .map_err(|_| malformed("malformed MediaTrack header"))?;
+    if header.kind.chars().count() > MAX_KIND_LEN {
+        return Err(malformed("track kinds must be at most 64 characters"));
+    }
+
+    header.session_id = sender.session_id;
+    let encoded = header.encode_to_vec();
+    let mut stream_header = BytesMut::with_capacity(3 + encoded.len());
+    stream_header.put_u8(protocol::MEDIA_STREAM_PREFIX);
+    stream_header.put_u16(encoded.len() as u16);
+    stream_header.put_slice(&encoded);
+    let track = relayed.insert(Arc::new(Track::new(stream_header.freeze())));
+    info!(
+        "Session {} started {} track {} in '{}'",
+        sender.session_id, header.kind, header.track, sender.room_key
+    );
+
+    loop {
+        let len = match reader.read_u32().await {
+            Ok(len) => len as usize,
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Err(err) => return Err(failed(err)),
+        };
+        if len > MAX_FRAME_LEN {
+            return Err(malformed("media frames must be at most 1 MiB"));
+        }
+        let mut frame = BytesMut::with_capacity(4 + len);
+        frame.put_u32(len as u32);
+        frame.resize(4 + len, 0);
+        reader.read_exact(&mut frame[4..]).await.map_err(failed)?;
+        let keyframe = system::MediaFrame::decode(&frame[4..])
+            .map_err(|_| malformed("malformed MediaFrame"))?
+            .keyframe;
+        let frame = frame.freeze();
+
+        let members = state.registry.room_members(&sender.room_key);
+        if !members.iter().any(|(id, _)| *id == sender.session_id) {
+            return Ok(());
+        }
+        for (session_id, outbox) in &members {
+            if *session_id != sender.session_id {
+                outbox.push_media(track, frame.clone(), keyframe);
+            }
+        }
+    }
+}
@@ -0,0 +1,128 @@
+//! Approximate accounting of the memory held in the server's buffers, and a soft limit on it.
+//!
+//! Each kind of buffer charges the bytes it holds to a [`Gauge`] and releases them when it
+//! shrinks or is dropped. Frames fanned out to several listeners are shared rather than copied,
+//! but are charged to every queue holding them, so the totals overestimate somewhat.
+//!
+//! Once the total passes the soft limit, the server sheds load: new sessions are refused,
+//! audio queues are cut to a fraction of their length and the buffer pool stops keeping idle
+//! buffers, until usage falls back under the limit.
+
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+
+pub static MEMORY: Memory = Memory::new();
+
+/// Bytes currently held by one kind of buffer.
+pub struct Gauge(AtomicU64);
+
+impl Gauge {
+    const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn add(&self, bytes: usize) {
+        self.0.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn sub(&self, bytes: usize) {
+        self.0.fetch_sub(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+pub struct Memory {
+    /// Audio frames waiting in listeners' outboxes.
+    pub audio_queues: Gauge,
+
+    /// Read buffers, both idle in the pool and borrowed by sessions.
+    pub read_buffers: Gauge,
+
+    /// Fragments of control packets waiting for the rest of their pieces.
+    pub reassembly: Gauge,
+
+    /// Frames waiting to be mixed.
+    pub mixer: Gauge,
+
+    /// Chat messages kept in rooms' histories.
+    pub chat_history: Gauge,
+
+    /// The soft limit in bytes, or zero for none.
+    soft_limit: AtomicU64,
+}
+
+impl Memory {
+    const fn new() -> Self {
+        Self {
+            audio_queues: Gauge::new(),
+            read_buffers: Gauge::new(),
+            reassembly: Gauge::new(),
+            mixer: Gauge::new(),
+            chat_history: Gauge::new(),
+            soft_limit: AtomicU64::new(0),
+        }
+    }
+
+    /// Every gauge, with the label it is exported under.
+    pub fn gauges(&self) -> [(&'static str, &Gauge); 5] {
+        [
+            ("audio_queues", &self.audio_queues),
+            ("read_buffers", &self.read_buffers),
+            ("reassembly", &self.reassembly),
+            ("mixer", &self.mixer),
+            ("chat_history", &self.chat_history),
+        ]
+    }
+
+    pub fn total(&self) -> u64 {
+        self.gauges().iter().map(|(_, gauge)| gauge.get()).sum()
+    }
+
+    pub fn soft_limit(&self) -> Option<u64> {
+        match self.soft_limit.load(Ordering::Relaxed) {
+            0 => None,
+            limit => Some(limit),
+        }
+    }
+
+    pub fn set_soft_limit(&self, limit: Option<u64>) {
+        self.soft_limit.store(limit.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    /// Whether usage has passed the soft limit, so load should be shed.
+    pub fn over_soft_limit(&self) -> bool {
+        self.soft_limit().is_some_and(|limit| self.total() > limit)
+    }
+}
+
+/// Bytes charged to a gauge, released when dropped.
+pub struct Charge {
+    gauge: &'static Gauge,
+    bytes: usize,
+}
+
+impl Charge {
+    pub fn new(gauge: &'static Gauge, bytes: usize) -> Self {
+        gauge.add(bytes);
+        Self { gauge, bytes }
+    }
+
+    /// Changes the charge to `bytes`.
+    pub fn set(&mut self, bytes: usize) {
+        if bytes > self.bytes {
+            self.gauge.add(bytes - self.bytes);
+        } else {
+            self.gauge.sub(self.bytes - bytes);
+        }
+        self.bytes = bytes;
+    }
+}
+
+impl Drop for Charge {
+    fn drop(&mut self) {
+        self.gauge.sub(self.bytes);
+    }
+}
@@ -0,0 +1,533 @@
+//! Process-wide counters, exported in the Prometheus text format at `/metrics`.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+
+use crate::geoip::Location;
+use crate::latency::Ewma;
+use crate::latency::Latency;
+use crate::memory::MEMORY;
+use crate::rooms::SessionId;
+
+pub static METRICS: Metrics = Metrics::new();
+
+/// Reads one value from a session's metrics, along with the divisor converting it to the
+/// exported unit.
+type SessionGauge = (fn(&SessionMetrics) -> Option<i64>, f64);
+
+/// The values exported per session.
+#[derive(Default)]
+pub struct SessionMetrics {
+    pub latency: Latency,
+
+    /// The smoothed quality-of-experience score, from 1 to 5, in thousandths.
+    pub qoe: Ewma,
+
+    /// Bytes sent to and received from the client, as of the last stats report.
+    pub bytes_sent: AtomicU64,
+    pub bytes_received: AtomicU64,
+
+    /// Whether audio is sent to the client on streams, as of the last stats report.
+    pub audio_on_streams: AtomicBool,
+
+    /// Packets the session dropped on the relay path, shared with its outbox.
+    pub drops: Arc<Drops>,
+
+    /// Where the client connected from, as far as the GeoIP databases know.
+    pub location: Location,
+}
+
+/// Packets a session dropped on the relay path, by reason.
+#[derive(Default)]
+pub struct Drops {
+    /// Voice frames from the client dropped because it or its room sent over its bitrate cap.
+    pub rate_limited: Counter,
+
+    /// Audio frames to the client dropped because its queue was full.
+    pub queue_full: Counter,
+
+    /// Audio frames to the client discarded because they waited in its queue for too long.
+    pub stale: Counter,
+
+    /// Packets from the client that could not be decoded.
+    pub malformed: Counter,
+
+    /// Packets from the client over the size limits, and audio frames to it too large for a
+    /// datagram.
+    pub oversized: Counter,
+}
+
+impl Drops {
+    /// Counts a packet that could not be decoded, for the session and in the server's total.
+    pub fn count_malformed(&self) {
+        self.malformed.inc();
+        METRICS.packets_malformed.inc();
+    }
+
+    /// Counts a packet over the size limits, for the session and in the server's total.
+    pub fn count_oversized(&self) {
+        self.oversized.inc();
+        METRICS.packets_oversized.inc();
+    }
+}
+
+/// A monotonically increasing counter.
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for Counter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct Metrics {
+    /// Audio frames queued for delivery to a listener.
+    pub audio_frames_forwarded: Counter,
+
+    /// Audio frames dropped because a listener's queue was full.
+    pub audio_frames_dropped: Counter,
+
+    /// Audio frames discarded because they waited in a listener's queue for too long.
+    pub audio_frames_expired: Counter,
+
+    /// Audio frames not queued because the listener was limited to other speakers.
+    pub audio_frames_limited: Counter,
+
+    /// Control packets fanned out to a client dropped because its control queue was full.
+    pub control_packets_dropped: Counter,
+
+    /// Times a listener whose queue kept overflowing was limited to fewer speakers.
+    pub slow_consumer_downgrades: Counter,
+
+    /// Times a listener's speaker limit was lifted once its queue stopped overflowing.
+    pub slow_consumer_recoveries: Counter,
+
+    /// Voice frames dropped because their user or room sent over its bitrate cap.
+    pub audio_frames_over_bitrate: Counter,
+
+    /// Sessions closed for sending over their bitrate cap for too long.
+    pub bitrate_disconnects: Counter,
+
+    /// Datagrams the transport refused to send.
+    pub datagrams_send_failed: Counter,
+
+    /// Datagrams held back because QUIC's datagram buffer was full.
+    pub datagrams_blocked: Counter,
+
+    /// Packets sent on streams because datagrams were too small for them or unsupported.
+    pub datagrams_rerouted: Counter,
+
+    /// Control packets sent compressed.
+    pub packets_compressed: Counter,
+
+    /// Bytes compression took off the control packets sent compressed.
+    pub compression_bytes_saved: Counter,
+
+    /// WebSockets opened to carry sessions' control packets.
+    pub signaling_sockets: Counter,
+
+    /// Frames dropped because a mixer worker's queue was full.
+    pub mixer_frames_dropped: Counter,
+
+    /// Packets from clients that could not be decoded.
+    pub packets_malformed: Counter,
+
+    /// Packets from clients over the size limits, and audio frames too large for a datagram.
+    pub packets_oversized: Counter,
+
+    /// Media frames queued for delivery to a receiver.
+    pub media_frames_forwarded: Counter,
+
+    /// Media frames dropped because a receiver fell behind.
+    pub media_frames_dropped: Counter,
+
+    /// Sessions refused because memory was over the soft limit.
+    pub sessions_refused: Counter,
+
+    /// Sessions refused because the server was draining.
+    pub sessions_refused_draining: Counter,
+
+    /// Connections refused over the accept rate or the pending handshake limit.
+    pub connections_refused: Counter,
+
+    /// Connections closed for not requesting a session in time.
+    pub handshakes_timed_out: Counter,
+
+    /// Session requests refused for an authority or path that is not allowed.
+    pub session_requests_refused: Counter,
+
+    /// Frames relayed to other nodes.
+    pub relay_frames_sent: Counter,
+
+    /// Frames relayed from other nodes.
+    pub relay_frames_received: Counter,
+
+    /// Phone calls answered by the SIP bridge.
+    pub sip_calls: Counter,
+
+    /// Sessions' connections moved to a new client address, such as after NAT rebinding.
+    pub session_migrations: Counter,
+
+    /// QUIC connections established, resumed or not.
+    pub connections: Counter,
+
+    /// Connections that resumed a TLS session from a ticket instead of a full handshake.
+    pub tls_resumptions: Counter,
+
+    /// Metrics of every live session.
+    sessions: Mutex<BTreeMap<SessionId, Arc<SessionMetrics>>>,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            audio_frames_forwarded: Counter::new(),
+            audio_frames_dropped: Counter::new(),
+            audio_frames_expired: Counter::new(),
+            audio_frames_limited: Counter::new(),
+            control_packets_dropped: Counter::new(),
+            slow_consumer_downgrades: Counter::new(),
+            slow_consumer_recoveries: Counter::new(),
+            audio_frames_over_bitrate: Counter::new(),
+            bitrate_disconnects: Counter::new(),
+            datagrams_send_failed: Counter::new(),
+            datagrams_blocked: Counter::new(),
+            datagrams_rerouted: Counter::new(),
+            packets_compressed: Counter::new(),
+            compression_bytes_saved: Counter::new(),
+            signaling_sockets: Counter::new(),
+            mixer_frames_dropped: Counter::new(),
+            packets_malformed: Counter::new(),
+            packets_oversized: Counter::new(),
+            media_frames_forwarded: Counter::new(),
+            media_frames_dropped: Counter::new(),
+            sessions_refused: Counter::new(),
+            sessions_refused_draining: Counter::new(),
+            connections_refused: Counter::new(),
+            handshakes_timed_out: Counter::new(),
+            session_requests_refused: Counter::new(),
+            relay_frames_sent: Counter::new(),
+            relay_frames_received: Counter::new(),
+            sip_calls: Counter::new(),
+            session_migrations: Counter::new(),
+            connections: Counter::new(),
+            tls_resumptions: Counter::new(),
+            sessions: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Exports a session's metrics until [`Self::untrack_session`] is called.
+    pub fn track_session(&self, session_id: SessionId, metrics: Arc<SessionMetrics>) {
+        self.sessions.lock().unwrap().insert(session_id, metrics);
+    }
+
+    /// The metrics of a live session.
+    pub fn session(&self, session_id: SessionId) -> Option<Arc<SessionMetrics>> {
+        self.sessions.lock().unwrap().get(&session_id).cloned()
+    }
+
+    pub fn untrack_session(&self, session_id: SessionId) {
+        self.sessions.lock().unwrap().remove(&session_id);
+    }
+
+    /// How many sessions' metrics are exported.
+    pub fn tracked_sessions(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    /// Renders every metric in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let counters = [
+            (
+                "voice_audio_frames_forwarded_total",
+                "Audio frames queued for delivery to a listener.",
+                &self.audio_frames_forwarded,
+            ),
+            (
+                "voice_audio_frames_dropped_total",
+                "Audio frames dropped because a listener's queue was full.",
+                &self.audio_frames_dropped,
+            ),
+            (
+                "voice_audio_frames_expired_total",
+                "Audio frames discarded because they waited in a listener's queue for too long.",
+                &self.audio_frames_expired,
+            ),
+            (
+                "voice_audio_frames_limited_total",
+                "Audio frames not queued because the listener was limited to other speakers.",
+                &self.audio_frames_limited,
+            ),
+            (
+                "voice_control_packets_dropped_total",
+                "Control packets fanned out to a client dropped because its queue was full.",
+                &self.control_packets_dropped,
+            ),
+            (
+                "voice_slow_consumer_downgrades_total",
+                "Times a listener whose queue kept overflowing was limited to fewer speakers.",
+                &self.slow_consumer_downgrades,
+            ),
+            (
+                "voice_slow_consumer_recoveries_total",
+                "Times a listener's speaker limit was lifted once its queue stopped overflowing.",
+                &self.slow_consumer_recoveries,
+            ),
+            (
+                "voice_audio_frames_over_bitrate_total",
+                "Voice frames dropped because their user or room sent over its bitrate cap.",
+                &self.audio_frames_over_bitrate,
+            ),
+            (
+                "voice_bitrate_disconnects_total",
+                "Sessions closed for sending over their bitrate cap for too long.",
+                &self.bitrate_disconnects,
+            ),
+            (
+                "voice_datagrams_send_failed_total",
+                "Datagrams the transport refused to send.",
+                &self.datagrams_send_failed,
+            ),
+            (
+                "voice_datagrams_blocked_total",
+                "Datagrams held back because QUIC's datagram buffer was full.",
+                &self.datagrams_blocked,
+            ),
+            (
+                "voice_datagrams_rerouted_total",
+                "Packets sent on streams because datagrams were too small for them or unsupported.",
+                &self.datagrams_rerouted,
+            ),
+            (
+                "voice_packets_compressed_total",
+                "Control packets sent compressed.",
+                &self.packets_compressed,
+            ),
+            (
+                "voice_compression_bytes_saved_total",
+                "Bytes compression took off the control packets sent compressed.",
+                &self.compression_bytes_saved,
+            ),
+            (
+                "voice_signaling_sockets_total",
+                "WebSockets opened to carry sessions' control packets.",
+                &self.signaling_sockets,
+            ),
+            (
+                "voice_mixer_frames_dropped_total",
+                "Frames dropped because a mixer worker's queue was full.",
+                &self.mixer_frames_dropped,
+            ),
+            (
+                "voice_packets_malformed_total",
+                "Packets from clients that could not be decoded.",
+                &self.packets_malformed,
+            ),
+            (
+                "voice_packets_oversized_total",
+                "Packets from clients over the size limits, and audio frames too large for a datagram.",
+                &self.packets_oversized,
+            ),
+            (
+                "voice_media_frames_forwarded_total",
+                "Media frames queued for delivery to a receiver.",
+                &self.media_frames_forwarded,
+            ),
+            (
+                "voice_media_frames_dropped_total",
+                "Media frames dropped because a receiver fell behind.",
+                &self.media_frames_dropped,
+            ),
+            (
+                "voice_sessions_refused_total",
+                "Sessions refused because memory was over the soft limit.",
+                &self.sessions_refused,
+            ),
+            (
+                "voice_sessions_refused_draining_total",
+                "Sessions refused because the server was draining.",
+                &self.sessions_refused_draining,
+            ),
+            (
+                "voice_connections_refused_total",
+                "Connections refused over the accept rate or the pending handshake limit.",
+                &self.connections_refused,
+            ),
+            (
+                "voice_handshakes_timed_out_total",
+                "Connections closed for not requesting a session in time.",
+                &self.handshakes_timed_out,
+            ),
+            (
+                "voice_session_requests_refused_total",
+                "Session requests refused for an authority or path that is not allowed.",
+                &self.session_requests_refused,
+            ),
+            (
+                "voice_relay_frames_sent_total",
+                "Frames relayed to other nodes.",
+                &self.relay_frames_sent,
+            ),
+            (
+                "voice_relay_frames_received_total",
+                "Frames relayed from other nodes.",
+                &self.relay_frames_received,
+            ),
+            (
+                "voice_sip_calls_total",
+                "Phone calls answered by the SIP bridge.",
+                &self.sip_calls,
+            ),
+            (
+                "voice_session_migrations_total",
+                "Sessions' connections moved to a new client address, such as after NAT rebinding.",
+                &self.session_migrations,
+            ),
+            (
+                "voice_connections_total",
+                "QUIC connections established, resumed or not.",
+                &self.connections,
+            ),
+            (
+                "voice_tls_resumptions_total",
+                "Connections that resumed a TLS session from a ticket instead of a full handshake.",
+                &self.tls_resumptions,
+            ),
+        ];
+
+        let mut out = String::new();
+        for (name, help, counter) in counters {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} counter");
+            let _ = writeln!(out, "{name} {}", counter.get());
+        }
+
+        let name = "voice_memory_bytes";
+        let _ = writeln!(
+            out,
+            "# HELP {name} Approximate bytes held in buffers, by kind."
+        );
+        let _ = writeln!(out, "# TYPE {name} gauge");
+        for (kind, gauge) in MEMORY.gauges() {
+            let _ = writeln!(out, "{name}{{kind=\"{kind}\"}} {}", gauge.get());
+        }
+        if let Some(limit) = MEMORY.soft_limit() {
+            let name = "voice_memory_soft_limit_bytes";
+            let _ = writeln!(
+                out,
+                "# HELP {name} The buffered bytes past which load is shed."
+            );
+            let _ = writeln!(out, "# TYPE {name} gauge");
+            let _ = writeln!(out, "{name} {limit}");
+        }
+
+        let sessions = self.sessions.lock().unwrap();
+        const MICROS: f64 = 1e6;
+        let gauges: [(&str, &str, SessionGauge); 6] = [
+            (
+                "voice_session_clock_offset_seconds",
+                "How far the client's clock is ahead of the server's.",
+                (|session| session.latency.clock_offset.get(), MICROS),
+            ),
+            (
+                "voice_session_rtt_seconds",
+                "Round-trip time to the client.",
+                (|session| session.latency.rtt.get(), MICROS),
+            ),
+            (
+                "voice_session_uplink_latency_seconds",
+                "Time from capture on the client to arrival at the server.",
+                (|session| session.latency.uplink.get(), MICROS),
+            ),
+            (
+                "voice_session_playout_delay_seconds",
+                "Time from arrival at the client to playout, as reported by the client.",
+                (|session| session.latency.playout_delay.get(), MICROS),
+            ),
+            (
+                "voice_session_downlink_latency_seconds",
+                "Time from leaving the mixer or forwarder to playout on the client.",
+                (|session| session.latency.downlink(), MICROS),
+            ),
+            (
+                "voice_session_qoe_score",
+                "Estimated mean opinion score, from 1 (bad) to 5 (excellent).",
+                (|session| session.qoe.get(), 1e3),
+            ),
+        ];
+        for (name, help, (value, divisor)) in gauges {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} gauge");
+            for (session_id, session) in sessions.iter() {
+                if let Some(value) = value(session) {
+                    let value = value as f64 / divisor;
+                    let _ = writeln!(out, "{name}{{session_id=\"{session_id}\"}} {value}");
+                }
+            }
+        }
+
+        let name = "voice_session_packets_dropped_total";
+        let _ = writeln!(
+            out,
+            "# HELP {name} Packets the session dropped on the relay path, by reason."
+        );
+        let _ = writeln!(out, "# TYPE {name} counter");
+        for (session_id, session) in sessions.iter() {
+            let drops = &session.drops;
+            for (reason, counter) in [
+                ("rate_limit", &drops.rate_limited),
+                ("queue_full", &drops.queue_full),
+                ("stale", &drops.stale),
+                ("malformed", &drops.malformed),
+                ("oversized", &drops.oversized),
+            ] {
+                let _ = writeln!(
+                    out,
+                    "{name}{{session_id=\"{session_id}\",reason=\"{reason}\"}} {}",
+                    counter.get()
+                );
+            }
+        }
+
+        let mut countries = BTreeMap::<&str, usize>::new();
+        for session in sessions.values() {
+            if let Some(country) = &session.location.country {
+                *countries.entry(country).or_default() += 1;
+            }
+        }
+        let name = "voice_sessions_by_country";
+        let _ = writeln!(
+            out,
+            "# HELP {name} Live sessions by the country their client connected from."
+        );
+        let _ = writeln!(out, "# TYPE {name} gauge");
+        for (country, sessions) in countries {
+            let _ = writeln!(out, "{name}{{country=\"{country}\"}} {sessions}");
+        }
+
+        out
+    }
+}
@@ -0,0 +1,245 @@
+//! Server-side audio mixing on a dedicated worker pool.
+//!
+//! When mixing is enabled, speakers' frames are handed to the mixer instead of being forwarded
+//! as-is. Every room is pinned to one worker thread, which once per frame interval decodes the
+//! frames received since the last tick, mixes them, and sends each listener a mix of everyone
+//! but themselves and the speakers they block. Decoding, mixing and encoding therefore never run
+//! on the network tasks, and a room's frames are always processed in order by the same worker.
+//!
+//! Each worker's input queue is bounded; frames that arrive while it is full are dropped.
+
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::hash::RandomState;
+use std::sync::Arc;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::Result;
+use bytes::Bytes;
+use tracing::debug_span;
+use tracing::warn;
+
+use crate::config::MixerConfig;
+use crate::dsp;
+use crate::memory::Charge;
+use crate::memory::MEMORY;
+use crate::metrics::METRICS;
+use crate::protocol;
+use crate::protocol::VoiceFrame;
+use crate::protocol::VoiceHeader;
+use crate::rooms::Registry;
+use crate::rooms::SessionId;
+
+/// The session ID mixed frames are sent with, since they have no single speaker.
+pub const MIX_SESSION_ID: SessionId = 0;
+
+/// Frames quieter than this RMS level, about -60 dBFS, are left out of the mix.
+const SILENCE_RMS: f32 = 0.001;
+
+/// Converts between encoded frames and PCM samples.
+pub trait Codec: Send + Sync {
+    /// Decodes a frame into `pcm`, returning the number of samples written.
+    fn decode(&self, frame: &[u8], pcm: &mut [i16]) -> Result<usize>;
+
+    /// Encodes PCM samples into a frame.
+    fn encode(&self, pcm: &[i16]) -> Bytes;
+}
+
+/// Uncompressed 16-bit little-endian PCM.
+pub struct Pcm16Codec;
+
+impl Pcm16Codec {
+    /// The codec's name in `/config.json`.
+    pub const NAME: &str = "pcm_s16le";
+}
+
+impl Codec for Pcm16Codec {
+    fn decode(&self, frame: &[u8], pcm: &mut [i16]) -> Result<usize> {
+        let samples = (frame.len() / 2).min(pcm.len());
+        for (sample, bytes) in pcm.iter_mut().zip(frame.chunks_exact(2)) {
+            *sample = i16::from_le_bytes([bytes[0], bytes[1]]);
+        }
+        Ok(samples)
+    }
+
+    fn encode(&self, pcm: &[i16]) -> Bytes {
+        pcm.iter().flat_map(|sample| sample.to_le_bytes()).collect()
+    }
+}
+
+struct Input {
+    room_key: Arc<str>,
+    speaker: SessionId,
+    frame: Bytes,
+    charge: Charge,
+}
+
+pub struct Mixer {
+    workers: Box<[mpsc::SyncSender<Input>]>,
+    hasher: RandomState,
+}
+
+impl Mixer {
+    /// Spawns the worker threads.
+    pub fn new(config: &MixerConfig, registry: Arc<Registry>, codec: Arc<dyn Codec>) -> Self {
+        let worker_count = match config.workers {
+            0 => thread::available_parallelism().map_or(1, |n| n.get()),
+            n => n,
+        };
+
+        let workers = (0..worker_count)
+            .map(|index| {
+                let (tx, rx) = mpsc::sync_channel(config.queue_len.max(1));
+                let mut worker = Worker {
+                    inputs: rx,
+                    registry: registry.clone(),
+                    codec: codec.clone(),
+                    frame_samples: config.frame_samples,
+                    interval: config.frame_interval(),
+                    rooms: HashMap::new(),
+                    ticks: 0,
+                };
+                thread::Builder::new()
+                    .name(format!("mixer-{index}"))
+                    .spawn(move || worker.run())
+                    .expect("failed to spawn mixer thread");
+                tx
+            })
+            .collect();
+
+        Self {
+            workers,
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// Queues a speaker's frame for mixing into its room.
+    pub fn submit(&self, room_key: &Arc<str>, speaker: SessionId, frame: Bytes) {
+        let index = self.hasher.hash_one(room_key) as usize % self.workers.len();
+        let input = Input {
+            room_key: room_key.clone(),
+            speaker,
+            charge: Charge::new(&MEMORY.mixer, frame.len()),
+            frame,
+        };
+
+        if self.workers[index].try_send(input).is_err() {
+            METRICS.mixer_frames_dropped.inc();
+        }
+    }
+}
+
+struct Worker {
+    inputs: mpsc::Receiver<Input>,
+    registry: Arc<Registry>,
+    codec: Arc<dyn Codec>,
+    frame_samples: usize,
+    interval: Duration,
+    rooms: HashMap<Arc<str>, RoomPipeline>,
+
+    /// Counts the worker's ticks, as the sequence number of the mixes sent at each, so that
+    /// listeners tell a room that went quiet from lost mixes by the time between them.
+    ticks: u32,
+}
+
+/// The frames received for one room since the last tick.
+#[derive(Default)]
+struct RoomPipeline {
+    frames: HashMap<SessionId, (Bytes, Charge)>,
+}
+
+impl Worker {
+    fn run(&mut self) {
+        let mut next_tick = Instant::now() + self.interval;
+
+        loop {
+            match self
+                .inputs
+                .recv_timeout(next_tick.saturating_duration_since(Instant::now()))
+            {
+                Ok(input) => {
+                    self.rooms
+                        .entry(input.room_key)
+                        .or_default()
+                        .frames
+                        .insert(input.speaker, (input.frame, input.charge));
+                    continue;
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => return,
+            }
+
+            self.tick();
+            next_tick += self.interval;
+        }
+    }
+
+    fn tick(&mut self) {
+        let _span = debug_span!("mixer_tick").entered();
+        self.ticks = self.ticks.wrapping_add(1);
+
+        let mut decoded: Vec<(SessionId, Vec<i16>)> = Vec::new();
+        let mut total = vec![0i32; self.frame_samples];
+
+        // Rooms without new frames are forgotten until their speakers talk again.
+        self.rooms.retain(|_, room| !room.frames.is_empty());
+
+        for (room_key, room) in &mut self.rooms {
+            decoded.clear();
+            total.fill(0);
+
+            for (speaker, (frame, _charge)) in room.frames.drain() {
+                let mut pcm = vec![0i16; self.frame_samples];
+                match self.codec.decode(&frame, &mut pcm) {
+                    Ok(_) if dsp::rms(&pcm) < SILENCE_RMS => {}
+                    Ok(_) => decoded.push((speaker, pcm)),
+                    Err(err) => warn!("Cannot decode frame from {speaker}: {err}"),
+                }
+            }
+
+            for (_, pcm) in &decoded {
+                dsp::accumulate(&mut total, pcm);
+            }
+
+            let mut mix = vec![0i16; self.frame_samples];
+            let speakers: Vec<SessionId> = decoded.iter().map(|(speaker, _)| *speaker).collect();
+            for listener in self.registry.room_listeners(room_key, &speakers) {
+                let own = decoded
+                    .iter()
+                    .find(|(speaker, _)| *speaker == listener.session_id);
+                let left_out = usize::from(own.is_some()) + listener.blocked.len();
+                if decoded.len() == left_out {
+                    continue;
+                }
+
+                if listener.blocked.is_empty() {
+                    dsp::mix_down(&total, own.map(|(_, pcm)| pcm.as_slice()), &mut mix);
+                } else {
+                    // Listeners who block speakers get a mix of their own, without them.
+                    let mut heard = vec![0i32; self.frame_samples];
+                    for (speaker, pcm) in &decoded {
+                        if *speaker != listener.session_id && !listener.blocked.contains(speaker) {
+                            dsp::accumulate(&mut heard, pcm);
+                        }
+                    }
+                    dsp::mix_down(&heard, None, &mut mix);
+                }
+
+                let header = VoiceHeader {
+                    session_id: MIX_SESSION_ID,
+                    room_id: protocol::room_id(room_key),
+                    sequence: self.ticks,
+                    capture_time_us: 0,
+                };
+                let payload = self.codec.encode(&mix);
+                listener
+                    .outbox
+                    .push_voice(&mut VoiceFrame::new(header, payload));
+                METRICS.audio_frames_forwarded.inc();
+            }
+        }
+    }
+}
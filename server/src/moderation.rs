@@ -0,0 +1,234 @@
+//! Chat filtering, and the audit log of what moderation does.
+//!
+//! The [`ChatFilter`] plugin checks chat messages against `moderation.chat_filter`: blocked
+//! words and patterns, and flood detection over a sliding window. A message that breaks a rule
+//! gets that rule's [`FilterAction`], and every action taken is recorded in the [`AuditLog`].
+//! Direct messages are checked as chat messages with an empty room key, and count towards the
+//! same floods. Messages bridged from outside the server are filtered the same way, but never
+//! count towards a flood or mute anyone, because their senders share session ID 0.
+
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use anyhow::Context;
+use anyhow::Result;
+use protobuf::system::ChatMessage;
+use protobuf::system::RoomUser;
+use regex::Regex;
+use serde_json::Value;
+use serde_json::json;
+use tracing::info;
+use tracing::warn;
+
+use crate::config::ChatFilterConfig;
+use crate::config::FilterAction;
+use crate::json_log::JsonLog;
+use crate::latency;
+use crate::plugin::Plugin;
+use crate::plugin::Verdict;
+use crate::rooms::SessionId;
+use crate::tenants::Scoped;
+
+/// Records moderation actions in the server log, and as JSON lines in `moderation.audit_log`
+/// if it is set.
+#[derive(Default)]
+pub struct AuditLog {
+    file: Option<JsonLog>,
+}
+
+impl AuditLog {
+    pub fn open(path: Option<&Path>) -> Result<Self> {
+        let file = path
+            .map(|path| JsonLog::open(path).context("Cannot open the audit log"))
+            .transpose()?;
+        Ok(Self { file })
+    }
+
+    /// The entries about a user's messages.
+    pub fn find(&self, user: Scoped) -> Result<Vec<Value>> {
+        match &self.file {
+            Some(file) => file.find(user),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Removes a user's name and messages from the log, keeping the actions taken.
+    pub fn anonymize(&self, user: Scoped) -> Result<usize> {
+        match &self.file {
+            Some(file) => file.anonymize(user),
+            None => Ok(0),
+        }
+    }
+
+    /// Records that `action` was taken against a chat message, for breaking `rule`.
+    pub fn record(&self, action: FilterAction, rule: &str, room_key: &str, message: &ChatMessage) {
+        info!(
+            "Chat filter: {action:?} message from '{}' in '{room_key}' ({rule})",
+            message.username
+        );
+        let Some(file) = &self.file else {
+            return;
+        };
+
+        let entry = json!({
+            "time_us": latency::now_us(),
+            "action": action,
+            "rule": rule,
+            "room_key": room_key,
+            "session_id": message.session_id,
+            "username": message.username,
+            "text": message.text,
+        });
+        if let Err(err) = file.append(&entry) {
+            warn!("Cannot write to the audit log: {err}");
+        }
+    }
+}
+
+/// Filters chat messages by content and rate.
+pub struct ChatFilter {
+    config: ChatFilterConfig,
+
+    /// Every blocked word, as one case-insensitive pattern.
+    words: Option<Regex>,
+
+    patterns: Vec<Regex>,
+    audit: Arc<AuditLog>,
+    senders: Mutex<HashMap<SessionId, Sender>>,
+}
+
+#[derive(Default)]
+struct Sender {
+    /// When the sender's messages within the flood window were sent.
+    recent: VecDeque<Instant>,
+
+    muted_until: Option<Instant>,
+}
+
+impl ChatFilter {
+    /// Compiles the filter's rules, or returns `None` if it has none.
+    pub fn new(config: &ChatFilterConfig, audit: Arc<AuditLog>) -> Result<Option<Self>> {
+        if config.words.is_empty() && config.patterns.is_empty() && config.flood_messages == 0 {
+            return Ok(None);
+        }
+
+        let words = if config.words.is_empty() {
+            None
+        } else {
+            let words: Vec<String> = config
+                .words
+                .iter()
+                .map(|word| regex::escape(word))
+                .collect();
+            Some(Regex::new(&format!(r"(?i)\b(?:{})\b", words.join("|")))?)
+        };
+        let patterns = config
+            .patterns
+            .iter()
+            .map(|pattern| {
+                Regex::new(pattern)
+                    .with_context(|| format!("Invalid chat filter pattern {pattern}"))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Some(Self {
+            config: config.clone(),
+            words,
+            patterns,
+            audit,
+            senders: Mutex::default(),
+        }))
+    }
+
+    /// The rule a message's text breaks, if any.
+    fn blocked(&self, text: &str) -> Option<String> {
+        if let Some(word) = self.words.as_ref().and_then(|words| words.find(text)) {
+            return Some(format!("blocked word '{}'", word.as_str()));
+        }
+        self.patterns
+            .iter()
+            .find(|pattern| pattern.is_match(text))
+            .map(|pattern| format!("blocked pattern '{pattern}'"))
+    }
+
+    /// Takes `action` against a message for breaking `rule`.
+    fn enforce(
+        &self,
+        action: FilterAction,
+        rule: &str,
+        room_key: &str,
+        message: &ChatMessage,
+        sender: Option<&mut Sender>,
+    ) -> Verdict {
+        let action = match (action, sender) {
+            (FilterAction::Mute, Some(sender)) => {
+                sender.muted_until = Some(Instant::now() + self.config.mute_duration());
+                FilterAction::Mute
+            }
+            (FilterAction::Mute, None) => FilterAction::Drop,
+            (action, _) => action,
+        };
+        self.audit.record(action, rule, room_key, message);
+
+        match action {
+            FilterAction::Warn => Ok(()),
+            FilterAction::Drop => Err(format!("message refused: {rule}")),
+            FilterAction::Mute => Err(format!(
+                "muted from chat for {} seconds: {rule}",
+                self.config.mute_secs
+            )),
+        }
+    }
+}
+
+impl Plugin for ChatFilter {
+    fn on_chat(&self, room_key: &str, message: &mut ChatMessage) -> Verdict {
+        let now = Instant::now();
+        let mut senders = self.senders.lock().unwrap();
+        let mut sender =
+            (message.session_id != 0).then(|| senders.entry(message.session_id).or_default());
+
+        if let Some(sender) = sender.as_deref_mut() {
+            if let Some(muted_until) = sender.muted_until {
+                if now < muted_until {
+                    let remaining = (muted_until - now).as_secs() + 1;
+                    return Err(format!("muted from chat for {remaining} more seconds"));
+                }
+                sender.muted_until = None;
+            }
+
+            if self.config.flood_messages > 0 {
+                let window = self.config.flood_window();
+                sender.recent.push_back(now);
+                while sender
+                    .recent
+                    .front()
+                    .is_some_and(|sent| now.duration_since(*sent) > window)
+                {
+                    sender.recent.pop_front();
+                }
+                if sender.recent.len() > self.config.flood_messages as usize {
+                    let rule = format!(
+                        "over {} messages in {} seconds",
+                        self.config.flood_messages, self.config.flood_window_secs
+                    );
+                    let action = self.config.flood_action;
+                    return self.enforce(action, &rule, room_key, message, Some(sender));
+                }
+            }
+        }
+
+        match self.blocked(&message.text) {
+            Some(rule) => self.enforce(self.config.action, &rule, room_key, message, sender),
+            None => Ok(()),
+        }
+    }
+
+    fn on_leave(&self, user: &RoomUser, _room_key: &str) {
+        self.senders.lock().unwrap().remove(&user.session_id);
+    }
+}
@@ -0,0 +1,141 @@
+//! A minimal MQTT 3.1.1 client that publishes.
+//!
+//! Messages are published at QoS 0, so nothing is acknowledged and the only packets read from the
+//! broker are CONNACK and PINGRESP. URLs have the form
+//! `mqtt://[username[:password]@]host[:port]`; TLS is not supported.
+
+use std::time::Duration;
+
+use anyhow::Context;
+use anyhow::Result;
+use anyhow::bail;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::net::tcp::OwnedWriteHalf;
+
+const DEFAULT_PORT: u16 = 1883;
+
+/// How often the broker expects to hear from the client, which sends a PINGREQ when idle for half
+/// as long.
+pub const KEEP_ALIVE: Duration = Duration::from_secs(60);
+
+// Packet types, in the high nibble of the first byte.
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PINGREQ: u8 = 0xC0;
+
+// CONNECT flags.
+const USERNAME: u8 = 0x80;
+const PASSWORD: u8 = 0x40;
+const CLEAN_SESSION: u8 = 0x02;
+
+pub struct Client {
+    reader: OwnedReadHalf,
+    writer: OwnedWriteHalf,
+}
+
+impl Client {
+    /// Connects to the broker at `url` with a clean session.
+    pub async fn connect(url: &str, client_id: &str) -> Result<Self> {
+        let rest = url
+            .strip_prefix("mqtt://")
+            .context("MQTT URL must start with mqtt://")?;
+        let (credentials, authority) = match rest.trim_end_matches('/').rsplit_once('@') {
+            Some((credentials, authority)) => (Some(credentials), authority),
+            None => (None, rest.trim_end_matches('/')),
+        };
+        let address = if authority.contains(':') {
+            authority.to_owned()
+        } else {
+            format!("{authority}:{DEFAULT_PORT}")
+        };
+
+        let stream = TcpStream::connect(&address)
+            .await
+            .with_context(|| format!("Cannot connect to MQTT broker at {address}"))?;
+        let (reader, writer) = stream.into_split();
+        let mut client = Self { reader, writer };
+
+        let mut flags = CLEAN_SESSION;
+        let mut payload = Vec::new();
+        put_string(&mut payload, client_id);
+        if let Some(credentials) = credentials {
+            let (username, password) = match credentials.split_once(':') {
+                Some((username, password)) => (username, Some(password)),
+                None => (credentials, None),
+            };
+            flags |= USERNAME;
+            put_string(&mut payload, username);
+            if let Some(password) = password {
+                flags |= PASSWORD;
+                put_string(&mut payload, password);
+            }
+        }
+
+        let mut body = Vec::new();
+        put_string(&mut body, "MQTT");
+        body.push(4);
+        body.push(flags);
+        body.extend_from_slice(&(KEEP_ALIVE.as_secs() as u16).to_be_bytes());
+        body.extend_from_slice(&payload);
+        client.send(CONNECT, &body).await?;
+
+        let mut connack = [0; 4];
+        client
+            .reader
+            .read_exact(&mut connack)
+            .await
+            .context("MQTT broker closed the connection")?;
+        match connack {
+            [CONNACK, 2, _, 0] => Ok(client),
+            [CONNACK, 2, _, code] => bail!("MQTT broker refused the connection with code {code}"),
+            _ => bail!("MQTT broker did not answer with CONNACK"),
+        }
+    }
+
+    pub async fn publish(&mut self, topic: &str, payload: &[u8]) -> Result<()> {
+        let mut body = Vec::with_capacity(2 + topic.len() + payload.len());
+        put_string(&mut body, topic);
+        body.extend_from_slice(payload);
+        self.send(PUBLISH, &body).await
+    }
+
+    pub async fn ping(&mut self) -> Result<()> {
+        self.send(PINGREQ, &[]).await
+    }
+
+    /// Reads and discards what the broker sends, returning once it closes the connection. Safe to
+    /// cancel.
+    pub async fn closed(&mut self) -> Result<()> {
+        let mut buffer = [0; 256];
+        while self.reader.read(&mut buffer).await? > 0 {}
+        bail!("MQTT broker closed the connection")
+    }
+
+    async fn send(&mut self, packet_type: u8, body: &[u8]) -> Result<()> {
+        let mut packet = Vec::with_capacity(5 + body.len());
+        packet.push(packet_type);
+        // The remaining length, seven bits at a time.
+        let mut len = body.len();
+        loop {
+            let byte = (len % 128) as u8;
+            len /= 128;
+            if len == 0 {
+                packet.push(byte);
+                break;
+            }
+            packet.push(byte | 0x80);
+        }
+        packet.extend_from_slice(body);
+        self.writer.write_all(&packet).await?;
+        Ok(())
+    }
+}
+
+fn put_string(buf: &mut Vec<u8>, value: &str) {
+    buf.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    buf.extend_from_slice(value.as_bytes());
+}
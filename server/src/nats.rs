@@ -0,0 +1,146 @@
+//! A minimal NATS client that publishes.
+//!
+//! Only the core text protocol's CONNECT, PUB, PING and PONG are spoken. URLs have the form
+//! `nats://[user:password@ | token@]host[:port]`; TLS is not supported.
+
+use anyhow::Context;
+use anyhow::Result;
+use anyhow::bail;
+use serde_json::json;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::net::tcp::OwnedWriteHalf;
+
+const DEFAULT_PORT: u16 = 4222;
+
+/// The longest protocol line accepted from the server, which is usually its INFO.
+const MAX_LINE_LEN: usize = 64 * 1024;
+
+pub struct Client {
+    reader: OwnedReadHalf,
+    writer: OwnedWriteHalf,
+
+    /// Received bytes not yet ending in a full line.
+    incoming: Vec<u8>,
+
+    /// PINGs received and not yet returned by [`Client::next_ping`].
+    pings: usize,
+
+    /// Whether a PONG has arrived, which the handshake waits for.
+    ponged: bool,
+}
+
+impl Client {
+    /// Connects to the server at `url`, waiting until it has accepted the credentials.
+    pub async fn connect(url: &str, name: &str) -> Result<Self> {
+        let rest = url
+            .strip_prefix("nats://")
+            .context("NATS URL must start with nats://")?;
+        let (credentials, authority) = match rest.trim_end_matches('/').rsplit_once('@') {
+            Some((credentials, authority)) => (Some(credentials), authority),
+            None => (None, rest.trim_end_matches('/')),
+        };
+        let address = if authority.contains(':') {
+            authority.to_owned()
+        } else {
+            format!("{authority}:{DEFAULT_PORT}")
+        };
+
+        let stream = TcpStream::connect(&address)
+            .await
+            .with_context(|| format!("Cannot connect to NATS at {address}"))?;
+        let (reader, writer) = stream.into_split();
+        let mut client = Self {
+            reader,
+            writer,
+            incoming: Vec::new(),
+            pings: 0,
+            ponged: false,
+        };
+
+        let mut connect = json!({
+            "verbose": false,
+            "pedantic": false,
+            "name": name,
+            "lang": "rust",
+            "version": env!("CARGO_PKG_VERSION"),
+            "protocol": 0,
+        });
+        match credentials.map(|credentials| credentials.split_once(':')) {
+            Some(Some((user, password))) => {
+                connect["user"] = user.into();
+                connect["pass"] = password.into();
+            }
+            Some(None) => connect["auth_token"] = credentials.into(),
+            None => {}
+        }
+        // The server answers the PING only once it has accepted the CONNECT.
+        let handshake = format!("CONNECT {connect}\r\nPING\r\n");
+        client.writer.write_all(handshake.as_bytes()).await?;
+        while !client.ponged {
+            client.receive().await?;
+        }
+
+        Ok(client)
+    }
+
+    pub async fn publish(&mut self, subject: &str, payload: &[u8]) -> Result<()> {
+        let mut message = format!("PUB {subject} {}\r\n", payload.len()).into_bytes();
+        message.extend_from_slice(payload);
+        message.extend_from_slice(b"\r\n");
+        self.writer.write_all(&message).await?;
+        Ok(())
+    }
+
+    pub async fn ping(&mut self) -> Result<()> {
+        self.writer.write_all(b"PING\r\n").await?;
+        Ok(())
+    }
+
+    /// Waits for the server to PING, which must be answered with [`Client::pong`], failing once
+    /// it closes the connection or reports an error. Safe to cancel.
+    pub async fn next_ping(&mut self) -> Result<()> {
+        while self.pings == 0 {
+            self.receive().await?;
+        }
+        self.pings -= 1;
+        Ok(())
+    }
+
+    pub async fn pong(&mut self) -> Result<()> {
+        self.writer.write_all(b"PONG\r\n").await?;
+        Ok(())
+    }
+
+    /// Reads once from the server and handles every complete line.
+    async fn receive(&mut self) -> Result<()> {
+        let mut buffer = [0; 4096];
+        let len = self.reader.read(&mut buffer).await?;
+        if len == 0 {
+            bail!("NATS closed the connection");
+        }
+        self.incoming.extend_from_slice(&buffer[..len]);
+
+        while let Some(end) = self
+            .incoming
+            .windows(2)
+            .position(|window| window == b"\r\n")
+        {
+            let line: Vec<u8> = self.incoming.drain(..end + 2).collect();
+            let line = String::from_utf8_lossy(&line[..end]);
+            if line == "PING" {
+                self.pings += 1;
+            } else if line == "PONG" {
+                self.ponged = true;
+            } else if line.starts_with("-ERR") {
+                bail!("NATS reported {line}");
+            }
+        }
+        if self.incoming.len() > MAX_LINE_LEN {
+            bail!("NATS sent a line over {MAX_LINE_LEN} bytes");
+        }
+        Ok(())
+    }
+}
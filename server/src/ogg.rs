@@ -0,0 +1,70 @@
+//! Ogg pages, the container of live broadcast streams.
+
+use bytes::Bytes;
+
+/// Marks the first page of a logical stream.
+pub const BEGINNING_OF_STREAM: u8 = 0x02;
+
+/// The CRC-32 Ogg uses: polynomial 0x04C11DB7, unreflected, with no initial or final XOR.
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u32) << 24;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04C1_1DB7
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// One logical stream, numbering its pages.
+pub struct Stream {
+    serial: u32,
+    sequence: u32,
+}
+
+impl Stream {
+    pub fn new() -> Self {
+        Self {
+            serial: rand::random(),
+            sequence: 0,
+        }
+    }
+
+    /// Wraps a packet of under 64 KiB in a page of its own. `granule` is the position, in
+    /// samples, at the end of the packet.
+    pub fn page(&mut self, packet: &[u8], granule: u64, flags: u8) -> Bytes {
+        let lacing_len = packet.len() / 255 + 1;
+        assert!(lacing_len <= 255, "Ogg packet of {} bytes", packet.len());
+
+        let mut page = Vec::with_capacity(27 + lacing_len + packet.len());
+        page.extend_from_slice(b"OggS");
+        page.push(0);
+        page.push(flags);
+        page.extend_from_slice(&granule.to_le_bytes());
+        page.extend_from_slice(&self.serial.to_le_bytes());
+        page.extend_from_slice(&self.sequence.to_le_bytes());
+        page.extend_from_slice(&[0; 4]);
+        page.push(lacing_len as u8);
+        page.extend(std::iter::repeat_n(255, lacing_len - 1));
+        page.push((packet.len() % 255) as u8);
+        page.extend_from_slice(packet);
+
+        let crc = page.iter().fold(0u32, |crc, byte| {
+            (crc << 8) ^ CRC_TABLE[((crc >> 24) as u8 ^ byte) as usize]
+        });
+        page[22..26].copy_from_slice(&crc.to_le_bytes());
+
+        self.sequence += 1;
+        page.into()
+    }
+}
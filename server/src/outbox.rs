@@ -0,0 +1,1036 @@
+//! Per-client outbound queues.
+//!
+//! Every session owns an [`Outbox`] drained by its own writer task, so a slow client only
+//! backs up its own queue instead of stalling whoever is fanning packets out to it. Audio is
+//! queued with a drop-oldest policy and discarded once it is too old to be worth playing.
+//! Control packets a session sends its own client wait for space in the queue, while those
+//! fanned out to other clients are dropped when theirs is full, so that a client that stops
+//! reading holds up no one sending to it.
+//!
+//! Control packets of [`protocol::COMPRESSION_MIN_LEN`] or more are compressed for clients that
+//! negotiated `FEATURE_COMPRESSION`, when that makes them smaller; see [`crate::deflate`]. Control
+//! packets larger than the connection's current datagram size are then split into fragments.
+//! The writer task paces its datagrams with a token bucket, so a burst of frames fanned out at
+//! once leaves in small batches spread over time instead of all at once.
+//!
+//! When QUIC's datagram buffer is full, the writer task waits a little for room rather than have
+//! QUIC drop what it buffered. A control packet that no longer fits a datagram, because the path
+//! shrank after it was split, goes out on a stream of its own, while such an audio frame is
+//! dropped. Clients whose connection does not take datagrams get everything on streams, and
+//! clients that did not negotiate `FEATURE_VOICE_DATAGRAMS` get their audio on streams, one
+//! frame to a stream.
+//!
+//! Under memory pressure, audio queues hold only a quarter of their usual number of frames.
+//!
+//! A listener whose queue keeps overflowing may be limited to fewer speakers; see
+//! [`crate::slow_consumer`].
+//!
+//! Clients that negotiated media streams also get a [`MediaQueue`], whose frames go out on
+//! streams rather than datagrams; see [`crate::media`].
+//!
+//! Packets are [`Bytes`], so a frame fanned out to many listeners is shared by all of their
+//! queues rather than copied into each.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::OnceLock;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::Instant;
+
+use bytes::Bytes;
+use tokio::sync::Notify;
+use tokio::sync::mpsc;
+use tracing::Instrument;
+use tracing::Level;
+use tracing::debug;
+use tracing::debug_span;
+use tracing::enabled;
+use tracing::info;
+use tracing::info_span;
+use wtransport::error::SendDatagramError;
+
+use crate::config::SessionConfig;
+use crate::deflate;
+use crate::fragment;
+use crate::latency::Ewma;
+use crate::log_sampling;
+use crate::memory::Charge;
+use crate::memory::MEMORY;
+use crate::metrics::Drops;
+use crate::metrics::METRICS;
+use crate::packet_dump;
+use crate::protocol;
+use crate::protocol::VoiceFrame;
+use crate::transport::SendStream;
+use crate::transport::Transport;
+
+/// How much shorter audio queues are kept while memory is over the soft limit.
+const SHED_QUEUE_DIVISOR: usize = 4;
+
+/// How long to wait for room in QUIC's datagram buffer before checking again.
+const BLOCKED_RETRY_INTERVAL: Duration = Duration::from_millis(5);
+
+/// How many times to wait for room in QUIC's datagram buffer before sending anyway, which drops
+/// the oldest datagrams buffered.
+const BLOCKED_RETRIES: u32 = 10;
+
+/// Room kept in QUIC's datagram buffer for the WebTransport session ID preceding each datagram.
+const DATAGRAM_HEADER_LEN: usize = 8;
+
+/// How long a speaker keeps their place among a limited listener's speakers after their last
+/// frame, so that pauses between words do not hand it to someone else.
+const SPEAKER_HOLD: Duration = Duration::from_secs(2);
+
+/// A handle to a client's outbound queues. Cloning it yields another handle to the same queues.
+#[derive(Clone)]
+pub struct Outbox {
+    audio: Arc<AudioQueue>,
+    control: mpsc::Sender<Bytes>,
+    media: Arc<OnceLock<MediaQueue>>,
+    carriers: Arc<Carriers>,
+}
+
+/// Which packets go out on streams rather than datagrams.
+#[derive(Default)]
+struct Carriers {
+    audio_on_streams: AtomicBool,
+    control_on_streams: AtomicBool,
+
+    /// Whether voice frames go out with a [`VoiceHeader`](protocol::VoiceHeader).
+    audio_header: AtomicBool,
+
+    /// Whether large control packets go out COMPRESSED.
+    compress_control: AtomicBool,
+
+    /// Where control packets go instead, while the client's control channel is a WebSocket; see
+    /// [`crate::signaling`].
+    signaling: Mutex<Option<mpsc::Sender<Bytes>>>,
+}
+
+/// What a queued packet is, which decides what happens when it does not fit in a datagram.
+#[derive(Clone, Copy)]
+enum Kind {
+    Audio,
+    Control,
+}
+
+/// A bounded queue of audio frames that drops the oldest frame when full, and frames that have
+/// waited longer than their maximum age.
+pub struct AudioQueue {
+    frames: Mutex<VecDeque<QueuedFrame>>,
+    capacity: usize,
+    max_age: Duration,
+    notify: Notify,
+    queue_delay: Ewma,
+    speakers: Mutex<HashSet<i64>>,
+
+    /// Whether frames were dropped or expired since the last check.
+    overflowed: AtomicBool,
+
+    /// The speakers queued for a listener limited to fewer of them, if it is.
+    speaker_limit: Mutex<Option<SpeakerLimit>>,
+
+    /// The session's count of frames dropped, expired or too large for a datagram.
+    drops: Arc<Drops>,
+}
+
+/// The speakers a limited listener is sent, and when each last spoke.
+struct SpeakerLimit {
+    max_speakers: usize,
+    speakers: HashMap<i64, Instant>,
+}
+
+impl SpeakerLimit {
+    /// Whether to queue a frame of `speaker`, taking a free place for them if there is one.
+    fn admit(&mut self, speaker: i64, now: Instant) -> bool {
+        if let Some(last) = self.speakers.get_mut(&speaker) {
+            *last = now;
+            return true;
+        }
+        self.speakers
+            .retain(|_, last| now.duration_since(*last) < SPEAKER_HOLD);
+        if self.speakers.len() >= self.max_speakers {
+            return false;
+        }
+        self.speakers.insert(speaker, now);
+        true
+    }
+}
+
+struct QueuedFrame {
+    frame: Bytes,
+    queued: Instant,
+    _charge: Charge,
+}
+
+impl AudioQueue {
+    pub fn new(capacity: usize, max_age: Duration, drops: Arc<Drops>) -> Self {
+        let capacity = capacity.max(1);
+        Self {
+            frames: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+            max_age,
+            notify: Notify::new(),
+            queue_delay: Ewma::default(),
+            speakers: Mutex::default(),
+            overflowed: AtomicBool::new(false),
+            speaker_limit: Mutex::default(),
+            drops,
+        }
+    }
+
+    /// Queues a frame, dropping the oldest queued frames if the queue is full, unless the
+    /// listener is limited to other speakers.
+    pub fn push(&self, frame: Bytes) {
+        let queued = Instant::now();
+        if let Some(limit) = &mut *self.speaker_limit.lock().unwrap()
+            && let Some(speaker) = speaker(&frame)
+            && !limit.admit(speaker, queued)
+        {
+            METRICS.audio_frames_limited.inc();
+            return;
+        }
+        let capacity = if MEMORY.over_soft_limit() {
+            (self.capacity / SHED_QUEUE_DIVISOR).max(1)
+        } else {
+            self.capacity
+        };
+        let charge = Charge::new(&MEMORY.audio_queues, frame.len());
+
+        let mut frames = self.frames.lock().unwrap();
+        while frames.len() >= capacity {
+            frames.pop_front();
+            self.overflowed.store(true, Ordering::Relaxed);
+            METRICS.audio_frames_dropped.inc();
+            self.drops.queue_full.inc();
+        }
+        frames.push_back(QueuedFrame {
+            frame,
+            queued,
+            _charge: charge,
+        });
+        drop(frames);
+
+        self.notify.notify_one();
+    }
+
+    /// Takes the oldest queued frame that has not expired, discarding any expired ones.
+    pub fn pop(&self) -> Option<Bytes> {
+        let now = Instant::now();
+        let mut frames = self.frames.lock().unwrap();
+        while let Some(queued) = frames.pop_front() {
+            let waited = now.duration_since(queued.queued);
+            if waited <= self.max_age {
+                self.queue_delay.record_duration(waited);
+                if let Some(speaker) = speaker(&queued.frame) {
+                    self.speakers.lock().unwrap().insert(speaker);
+                }
+                return Some(queued.frame);
+            }
+            self.overflowed.store(true, Ordering::Relaxed);
+            METRICS.audio_frames_expired.inc();
+            self.drops.stale.inc();
+        }
+        None
+    }
+
+    /// The average time sent frames spent queued, in microseconds.
+    pub fn queue_delay(&self) -> Option<i64> {
+        self.queue_delay.get()
+    }
+
+    /// The number of distinct speakers whose frames were sent since the last call.
+    pub fn take_speaker_count(&self) -> usize {
+        let mut speakers = self.speakers.lock().unwrap();
+        let count = speakers.len();
+        speakers.clear();
+        count
+    }
+
+    /// Whether frames were dropped or expired since the last call.
+    pub fn take_overflowed(&self) -> bool {
+        self.overflowed.swap(false, Ordering::Relaxed)
+    }
+
+    /// The most speakers queued at once, if limited.
+    pub fn max_speakers(&self) -> Option<usize> {
+        self.speaker_limit
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|limit| limit.max_speakers)
+    }
+
+    /// Whether frames of `speaker` are queued, which they are unless the listener is limited and
+    /// the speaker holds none of its places.
+    pub fn holds_place(&self, speaker: i64) -> bool {
+        self.speaker_limit
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_none_or(|limit| {
+                limit
+                    .speakers
+                    .get(&speaker)
+                    .is_some_and(|last| last.elapsed() < SPEAKER_HOLD)
+            })
+    }
+
+    /// Queues frames of at most `max_speakers` speakers at once, or of every speaker with
+    /// `None`.
+    pub fn limit_speakers(&self, max_speakers: Option<usize>) {
+        *self.speaker_limit.lock().unwrap() = max_speakers.map(|max_speakers| SpeakerLimit {
+            max_speakers: max_speakers.max(1),
+            speakers: HashMap::new(),
+        });
+    }
+}
+
+/// The session ID of the speaker of a voice frame.
+fn speaker(frame: &[u8]) -> Option<i64> {
+    let speaker = frame.get(1..9)?;
+    Some(i64::from_be_bytes(speaker.try_into().unwrap()))
+}
+
+/// The client's connection has closed.
+#[derive(Debug)]
+pub struct Closed;
+
+/// The receiving end of an outbox for a participant bridged from another network, which takes
+/// the frames queued for it itself instead of having them sent as datagrams.
+pub struct Inbox {
+    audio: Arc<AudioQueue>,
+    control: mpsc::Receiver<Bytes>,
+}
+
+impl Inbox {
+    /// Takes every audio frame queued since the last call. Control packets are discarded, since
+    /// bridged participants have no use for them.
+    pub fn drain_audio(&mut self) -> Vec<Bytes> {
+        while self.control.try_recv().is_ok() {}
+        std::iter::from_fn(|| self.audio.pop()).collect()
+    }
+}
+
+impl Outbox {
+    /// Creates the queues for `connection` and spawns the task that drains them, counting the
+    /// frames they drop in `drops`.
+    pub fn new(connection: Arc<dyn Transport>, config: &SessionConfig, drops: Arc<Drops>) -> Self {
+        let audio = Arc::new(AudioQueue::new(
+            config.audio_queue_len,
+            config.audio_max_age(),
+            drops,
+        ));
+        let (control, control_rx) = mpsc::channel(config.control_queue_len.max(1));
+
+        let pacer = Pacer::new(config.pacing_rate, config.pacing_burst);
+        let carriers = Arc::<Carriers>::default();
+        if connection.max_datagram_size().is_none() {
+            info!("The connection takes no datagrams; sending everything on streams");
+            carriers.audio_on_streams.store(true, Ordering::Relaxed);
+            carriers.control_on_streams.store(true, Ordering::Relaxed);
+        }
+        // An info span, so that packets logged with `--dump-packets` show which connection they
+        // were sent on.
+        tokio::spawn(
+            Self::drain(
+                connection,
+                audio.clone(),
+                control_rx,
+                pacer,
+                carriers.clone(),
+            )
+            .instrument(info_span!("Outbox")),
+        );
+
+        Self {
+            audio,
+            control,
+            media: Arc::default(),
+            carriers,
+        }
+    }
+
+    /// Creates queues that are drained through the returned [`Inbox`] rather than a connection.
+    pub fn bridged(config: &SessionConfig) -> (Self, Inbox) {
+        let audio = Arc::new(AudioQueue::new(
+            config.audio_queue_len,
+            config.audio_max_age(),
+            Arc::default(),
+        ));
+        let (control, control_rx) = mpsc::channel(config.control_queue_len.max(1));
+        let inbox = Inbox {
+            audio: audio.clone(),
+            control: control_rx,
+        };
+        let outbox = Self {
+            audio,
+            control,
+            media: Arc::default(),
+            carriers: Arc::default(),
+        };
+        (outbox, inbox)
+    }
+
+    /// Sends audio on streams from now on, for a client that did not negotiate
+    /// `FEATURE_VOICE_DATAGRAMS`.
+    pub fn send_audio_on_streams(&self) {
+        self.carriers
+            .audio_on_streams
+            .store(true, Ordering::Relaxed);
+    }
+
+    /// Whether audio is sent on streams rather than datagrams.
+    pub fn audio_on_streams(&self) -> bool {
+        self.carriers.audio_on_streams.load(Ordering::Relaxed)
+    }
+
+    /// Sends voice frames with a [`VoiceHeader`](protocol::VoiceHeader) from now on, for a
+    /// client that negotiated `FEATURE_AUDIO_HEADER`.
+    pub fn send_audio_header(&self) {
+        self.carriers.audio_header.store(true, Ordering::Relaxed);
+    }
+
+    /// Compresses large control packets from now on, for a client that negotiated
+    /// `FEATURE_COMPRESSION`.
+    pub fn compress_control(&self) {
+        self.carriers
+            .compress_control
+            .store(true, Ordering::Relaxed);
+    }
+
+    /// Sends control packets whole into `signaling` from now on, for a client that moved its
+    /// control channel to a WebSocket, until the socket closes.
+    pub fn send_control_to(&self, signaling: mpsc::Sender<Bytes>) {
+        *self.carriers.signaling.lock().unwrap() = Some(signaling);
+    }
+
+    /// Queues a voice frame in the client's layout, dropping the oldest queued frame if the
+    /// queue is full.
+    pub fn push_voice(&self, frame: &mut VoiceFrame) {
+        let with_header = self.carriers.audio_header.load(Ordering::Relaxed);
+        self.audio.push(frame.layout(with_header));
+    }
+
+    /// The average time sent audio frames spent queued, in microseconds.
+    pub fn queue_delay(&self) -> Option<i64> {
+        self.audio.queue_delay()
+    }
+
+    /// The number of distinct speakers whose audio was sent since the last call.
+    pub fn take_speaker_count(&self) -> usize {
+        self.audio.take_speaker_count()
+    }
+
+    /// The audio queue, for checking and limiting a slow listener.
+    pub fn audio(&self) -> &AudioQueue {
+        &self.audio
+    }
+
+    /// Starts relaying media tracks to the client, once it has negotiated media streams.
+    pub fn enable_media(&self, connection: Arc<dyn Transport>, config: &SessionConfig) {
+        self.media
+            .get_or_init(|| MediaQueue::new(connection, config.media_queue_len));
+    }
+
+    /// Queues a media frame for the client, if it negotiated media streams.
+    pub fn push_media(&self, track: &Arc<Track>, frame: Bytes, keyframe: bool) {
+        if let Some(media) = self.media.get() {
+            media.push(track, frame, keyframe);
+        }
+    }
+
+    /// Finishes a track's stream to the client once its queued frames are sent.
+    pub fn end_media(&self, track: &Arc<Track>) {
+        if let Some(media) = self.media.get() {
+            media.end(track);
+        }
+    }
+
+    /// Queues a control packet, waiting for space if the queue is full.
+    pub async fn send_control(&self, packet: Bytes) -> Result<(), Closed> {
+        self.control.send(packet).await.map_err(|_| Closed)
+    }
+
+    /// Queues a control packet fanned out to this client by others, dropping it if the queue is
+    /// full rather than wait for the client to catch up. Returns whether it was queued.
+    pub fn try_send_control(&self, packet: Bytes) -> bool {
+        match self.control.try_send(packet) {
+            Ok(()) => true,
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                METRICS.control_packets_dropped.inc();
+                false
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => false,
+        }
+    }
+
+    async fn drain(
+        connection: Arc<dyn Transport>,
+        audio: Arc<AudioQueue>,
+        mut control: mpsc::Receiver<Bytes>,
+        mut pacer: Pacer,
+        carriers: Arc<Carriers>,
+    ) {
+        // Control packets split into fragments, waiting to go out before anything else.
+        let mut fragments = VecDeque::new();
+        let mut message_id = 0u32;
+        loop {
+            if fragments.is_empty()
+                && let Ok(packet) = control.try_recv()
+            {
+                Self::fragment(
+                    &*connection,
+                    &carriers,
+                    packet,
+                    &mut message_id,
+                    &mut fragments,
+                );
+            }
+
+            // Control packets always go out before queued audio.
+            let (packet, kind) = if let Some(packet) = fragments.pop_front() {
+                (packet, Kind::Control)
+            } else if let Some(frame) = audio.pop() {
+                (frame, Kind::Audio)
+            } else {
+                tokio::select! {
+                    packet = control.recv() => match packet {
+                        Some(packet) => Self::fragment(
+                            &*connection,
+                            &carriers,
+                            packet,
+                            &mut message_id,
+                            &mut fragments,
+                        ),
+                        None => return,
+                    },
+                    _ = audio.notify.notified() => {}
+                    _ = connection.closed() => return,
+                }
+                continue;
+            };
+
+            if let Some(delay) = pacer.reserve() {
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {}
+                    _ = connection.closed() => return,
+                }
+            }
+
+            if Self::send(&*connection, &carriers, &audio.drops, packet, kind)
+                .await
+                .is_err()
+            {
+                return;
+            }
+        }
+    }
+
+    /// Queues a control packet, compressed if the client takes that and it is large, and split
+    /// into fragments if it exceeds the datagram size and goes out as datagrams rather than on
+    /// streams or a WebSocket.
+    fn fragment(
+        connection: &dyn Transport,
+        carriers: &Carriers,
+        mut packet: Bytes,
+        message_id: &mut u32,
+        fragments: &mut VecDeque<Bytes>,
+    ) {
+        packet_dump::sent(&packet);
+        if carriers.compress_control.load(Ordering::Relaxed)
+            && packet.len() >= protocol::COMPRESSION_MIN_LEN
+        {
+            let deflated = deflate::compress(&packet);
+            if deflated.len() + 1 < packet.len() {
+                METRICS.packets_compressed.inc();
+                METRICS
+                    .compression_bytes_saved
+                    .add((packet.len() - deflated.len() - 1) as u64);
+                packet = protocol::compressed(&deflated);
+            }
+        }
+        if carriers.control_on_streams.load(Ordering::Relaxed)
+            || carriers.signaling.lock().unwrap().is_some()
+        {
+            fragments.push_back(packet);
+            return;
+        }
+        let max_size = connection
+            .max_datagram_size()
+            .unwrap_or(protocol::MAX_DATAGRAM_SIZE);
+        if packet.len() > max_size {
+            *message_id = message_id.wrapping_add(1);
+        }
+        fragments.extend(fragment::split(packet, max_size, *message_id));
+    }
+
+    /// Sends a packet as a datagram, or on a stream where datagrams cannot carry it. Control
+    /// packets go to the client's WebSocket instead while it has one open.
+    async fn send(
+        connection: &dyn Transport,
+        carriers: &Carriers,
+        drops: &Drops,
+        mut packet: Bytes,
+        kind: Kind,
+    ) -> Result<(), Closed> {
+        if let Kind::Control = kind {
+            let signaling = carriers.signaling.lock().unwrap().clone();
+            if let Some(signaling) = signaling {
+                match signaling.send(packet).await {
+                    Ok(()) => return Ok(()),
+                    Err(mpsc::error::SendError(unsent)) => {
+                        debug!("The WebSocket closed; sending control packets over WebTransport");
+                        *carriers.signaling.lock().unwrap() = None;
+                        packet = unsent;
+                    }
+                }
+            }
+        }
+
+        let on_streams = match kind {
+            Kind::Audio => &carriers.audio_on_streams,
+            Kind::Control => &carriers.control_on_streams,
+        };
+        if on_streams.load(Ordering::Relaxed) {
+            return Self::send_on_stream(connection, &packet, kind).await;
+        }
+
+        Self::wait_for_room(connection, packet.len()).await?;
+        match connection.send_datagram(&packet) {
+            Ok(()) => Ok(()),
+            Err(SendDatagramError::NotConnected) => Err(Closed),
+            Err(SendDatagramError::UnsupportedByPeer) => {
+                debug!("The client takes no datagrams; sending everything on streams");
+                carriers.audio_on_streams.store(true, Ordering::Relaxed);
+                carriers.control_on_streams.store(true, Ordering::Relaxed);
+                METRICS.datagrams_rerouted.inc();
+                Self::send_on_stream(connection, &packet, kind).await
+            }
+            Err(SendDatagramError::TooLarge) => match kind {
+                // A frame is not worth a stream of its own, and the next one is on its way.
+                Kind::Audio => {
+                    if enabled!(Level::DEBUG) && log_sampling::OVERSIZED_FRAMES.sample() {
+                        debug!("Dropping an audio frame too large for a datagram");
+                    }
+                    METRICS.datagrams_send_failed.inc();
+                    drops.count_oversized();
+                    Ok(())
+                }
+                Kind::Control => {
+                    METRICS.datagrams_rerouted.inc();
+                    Self::send_on_stream(connection, &packet, kind).await
+                }
+            },
+        }
+    }
+
+    /// Waits while QUIC's datagram buffer has no room for a datagram of `len` bytes, so that a
+    /// burst does not push out the datagrams buffered before it. After [`BLOCKED_RETRIES`] waits,
+    /// the datagram is sent anyway.
+    async fn wait_for_room(connection: &dyn Transport, len: usize) -> Result<(), Closed> {
+        for retry in 0..BLOCKED_RETRIES {
+            if connection.datagram_send_buffer_space() >= len + DATAGRAM_HEADER_LEN {
+                return Ok(());
+            }
+            if retry == 0 {
+                METRICS.datagrams_blocked.inc();
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(BLOCKED_RETRY_INTERVAL) => {}
+                _ = connection.closed() => return Err(Closed),
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends a packet on a stream of its own. Control streams go before any other.
+    async fn send_on_stream(
+        connection: &dyn Transport,
+        packet: &[u8],
+        kind: Kind,
+    ) -> Result<(), Closed> {
+        let stream = match kind {
+            Kind::Control => protocol::open_control_stream(connection).await,
+            Kind::Audio => connection.open_uni().await,
+        };
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                debug!("Cannot open a stream: {err:#}");
+                return Err(Closed);
+            }
+        };
+        if let Err(err) = stream.write_all(packet).await {
+            if enabled!(Level::DEBUG) && log_sampling::STREAM_SEND_FAILURES.sample() {
+                debug!("Cannot send a packet on a stream: {err}");
+            }
+            METRICS.datagrams_send_failed.inc();
+            return Ok(());
+        }
+        // Finishing waits for the client to acknowledge the stream, which the next packet need
+        // not wait for.
+        tokio::spawn(async move {
+            let _ = stream.finish().await;
+        });
+        Ok(())
+    }
+}
+
+/// Tells apart tracks relayed by this server, whatever numbers their senders gave them.
+static LAST_TRACK_ID: AtomicU64 = AtomicU64::new(0);
+
+/// A media track being relayed.
+pub struct Track {
+    id: u64,
+
+    /// What starts each stream the track is relayed on.
+    header: Bytes,
+}
+
+impl Track {
+    pub fn new(header: Bytes) -> Self {
+        Self {
+            id: LAST_TRACK_ID.fetch_add(1, Ordering::Relaxed) + 1,
+            header,
+        }
+    }
+}
+
+enum Item {
+    /// A frame with its length prefix, as it goes on the stream.
+    Frame { track: Arc<Track>, frame: Bytes },
+
+    /// The track ended, so its stream can be finished.
+    End(Arc<Track>),
+}
+
+/// A receiver's queue of media frames, drained onto streams to it by a task of its own.
+pub struct MediaQueue {
+    items: mpsc::Sender<Item>,
+
+    /// Tracks that ended while the queue was full, each with how many items had been queued by
+    /// then, so that their streams are finished once those are sent.
+    late_ends: mpsc::UnboundedSender<(Arc<Track>, u64)>,
+
+    /// Tracks that lost frames, whose frames are skipped until their next keyframe, and how many
+    /// items have been queued.
+    resyncing: Mutex<(HashSet<u64>, u64)>,
+}
+
+impl MediaQueue {
+    /// Creates the queue for `connection` and spawns the task that drains it.
+    pub fn new(connection: Arc<dyn Transport>, queue_len: usize) -> Self {
+        let (items, items_rx) = mpsc::channel(queue_len.max(1));
+        let (late_ends, late_ends_rx) = mpsc::unbounded_channel();
+        let drain = Self::drain(connection, items_rx, late_ends_rx);
+        tokio::spawn(drain.instrument(debug_span!("MediaQueue")));
+        Self {
+            items,
+            late_ends,
+            resyncing: Mutex::default(),
+        }
+    }
+
+    /// Queues a frame, unless the queue is full or the track is waiting for a keyframe.
+    fn push(&self, track: &Arc<Track>, frame: Bytes, keyframe: bool) {
+        let mut guard = self.resyncing.lock().unwrap();
+        let (resyncing, queued) = &mut *guard;
+        if !keyframe && resyncing.contains(&track.id) {
+            METRICS.media_frames_dropped.inc();
+            return;
+        }
+
+        let item = Item::Frame {
+            track: track.clone(),
+            frame,
+        };
+        if self.items.try_send(item).is_ok() {
+            *queued += 1;
+            resyncing.remove(&track.id);
+            METRICS.media_frames_forwarded.inc();
+        } else {
+            resyncing.insert(track.id);
+            METRICS.media_frames_dropped.inc();
+        }
+    }
+
+    /// Finishes a track's stream once its queued frames are sent, without waiting for room in
+    /// the queue.
+    fn end(&self, track: &Arc<Track>) {
+        let mut guard = self.resyncing.lock().unwrap();
+        let (resyncing, queued) = &mut *guard;
+        if self.items.try_send(Item::End(track.clone())).is_ok() {
+            *queued += 1;
+        } else {
+            let _ = self.late_ends.send((track.clone(), *queued));
+        }
+        resyncing.remove(&track.id);
+    }
+
+    async fn drain(
+        connection: Arc<dyn Transport>,
+        mut items: mpsc::Receiver<Item>,
+        mut late_ends: mpsc::UnboundedReceiver<(Arc<Track>, u64)>,
+    ) {
+        // `None` for tracks whose stream could not be opened or was stopped by the receiver,
+        // which are not sent again.
+        let mut streams: HashMap<u64, Option<Box<dyn SendStream>>> = HashMap::new();
+        let mut received = 0;
+        let mut ending: Vec<(Arc<Track>, u64)> = Vec::new();
+        loop {
+            // Tracks that ended once every item queued before they did has been sent.
+            for (track, _) in ending.extract_if(.., |(_, queued)| *queued <= received) {
+                Self::finish(&mut streams, track.id).await;
+            }
+            let item = tokio::select! {
+                item = items.recv() => match item {
+                    Some(item) => item,
+                    None => break,
+                },
+                Some(end) = late_ends.recv() => {
+                    ending.push(end);
+                    continue;
+                }
+            };
+            received += 1;
+            match item {
+                Item::Frame { track, frame } => {
+                    let stream = match streams.get_mut(&track.id) {
+                        Some(stream) => stream,
+                        None => {
+                            let stream = Self::open(&*connection, &track.header).await;
+                            streams.entry(track.id).or_insert(stream)
+                        }
+                    };
+                    if let Some(open) = stream
+                        && let Err(err) = open.write_all(&frame).await
+                    {
+                        if enabled!(Level::DEBUG) && log_sampling::STREAM_SEND_FAILURES.sample() {
+                            debug!("Cannot send media frame: {err}");
+                        }
+                        *stream = None;
+                    }
+                }
+                Item::End(track) => Self::finish(&mut streams, track.id).await,
+            }
+        }
+    }
+
+    async fn finish(streams: &mut HashMap<u64, Option<Box<dyn SendStream>>>, track_id: u64) {
+        if let Some(Some(mut stream)) = streams.remove(&track_id) {
+            let _ = stream.finish().await;
+        }
+    }
+
+    async fn open(connection: &dyn Transport, header: &[u8]) -> Option<Box<dyn SendStream>> {
+        let result = async {
+            let mut stream = connection.open_uni().await?;
+            stream.write_all(header).await?;
+            anyhow::Ok(stream)
+        };
+        match result.await {
+            Ok(stream) => Some(stream),
+            Err(err) => {
+                debug!("Cannot open media stream: {err}");
+                None
+            }
+        }
+    }
+}
+
+/// A token bucket spacing out a connection's datagrams.
+struct Pacer {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Pacer {
+    /// Allows `rate` datagrams per second in bursts of up to `burst`. A zero rate never waits.
+    fn new(rate: u32, burst: u32) -> Self {
+        let burst = f64::from(burst.max(1));
+        Self {
+            rate: f64::from(rate),
+            burst,
+            tokens: burst,
+            refilled: Instant::now(),
+        }
+    }
+
+    /// Takes a token for the next datagram, returning how long to wait before sending it.
+    fn reserve(&mut self) -> Option<Duration> {
+        if self.rate == 0.0 {
+            return None;
+        }
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.refilled = now;
+
+        // Going into debt keeps the long-run rate exact even though timers are coarse.
+        self.tokens -= 1.0;
+        (self.tokens < 0.0).then(|| Duration::from_secs_f64(-self.tokens / self.rate))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use prost::Message;
+    use protobuf::system;
+    use protobuf::system::PacketType;
+
+    use super::Outbox;
+    use super::Track;
+    use crate::config::SessionConfig;
+    use crate::fragment::Reassembler;
+    use crate::metrics::Drops;
+    use crate::transport::mock;
+
+    fn outbox() -> (Outbox, mock::Client, Arc<Drops>) {
+        let (connection, client) = mock::connect();
+        let drops = Arc::new(Drops::default());
+        let outbox = Outbox::new(connection, &SessionConfig::default(), drops.clone());
+        (outbox, client, drops)
+    }
+
+    fn packet(len: usize) -> Bytes {
+        (0..len).map(|byte| byte as u8).collect()
+    }
+
+    /// Reads fragments until one completes a packet, returning it and the fragments' indexes in
+    /// the order they arrived.
+    async fn reassemble(client: &mut mock::Client) -> (Vec<u8>, Vec<u32>) {
+        let mut reassembler = Reassembler::new(1 << 16);
+        let mut indexes = Vec::new();
+        loop {
+            let datagram = client.receive().await;
+            assert_eq!(datagram[0], PacketType::Fragment as u8);
+            indexes.push(system::Fragment::decode(&datagram[1..]).unwrap().index);
+            if let Some(packet) = reassembler.push(&datagram[1..]).unwrap() {
+                return (packet, indexes);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn reordered_fragments_reassemble() {
+        let (outbox, mut client, _) = outbox();
+        client.reorder_datagrams(2);
+        let sent = packet(3000);
+        outbox.send_control(sent.clone()).await.unwrap();
+
+        let (received, indexes) = reassemble(&mut client).await;
+        assert_eq!(received, sent);
+        assert_eq!(indexes, [2, 1, 0]);
+    }
+
+    #[tokio::test]
+    async fn lost_fragments_lose_only_their_packet() {
+        let (outbox, mut client, _) = outbox();
+        client.lose_datagrams(1);
+        outbox.send_control(packet(3000)).await.unwrap();
+        let sent = packet(2500);
+        outbox.send_control(sent.clone()).await.unwrap();
+
+        let (received, indexes) = reassemble(&mut client).await;
+        assert_eq!(received, sent);
+        assert_eq!(indexes, [1, 2, 0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn control_packets_the_path_shrank_under_go_on_streams() {
+        let (outbox, mut client, _) = outbox();
+        client.shrink_path(500);
+        let sent = packet(1000);
+        outbox.send_control(sent.clone()).await.unwrap();
+        assert_eq!(client.receive().await, sent);
+
+        // Later packets are split for the smaller path.
+        outbox.send_control(sent.clone()).await.unwrap();
+        let fragment = client.receive().await;
+        assert_eq!(fragment[0], PacketType::Fragment as u8);
+        assert!(fragment.len() <= 500);
+    }
+
+    #[tokio::test]
+    async fn audio_too_large_for_the_path_is_dropped() {
+        let (outbox, mut client, drops) = outbox();
+        client.shrink_path(500);
+        outbox.audio().push(packet(1000));
+        let sent = packet(100);
+        outbox.audio().push(sent.clone());
+
+        assert_eq!(client.receive().await, sent);
+        assert_eq!(drops.oversized.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn clients_refusing_datagrams_get_everything_on_streams() {
+        let (outbox, mut client, _) = outbox();
+        client.refuse_datagrams();
+        let sent = packet(100);
+        outbox.send_control(sent.clone()).await.unwrap();
+        assert_eq!(client.receive().await, sent);
+        assert!(outbox.audio_on_streams());
+
+        outbox.audio().push(sent.clone());
+        assert_eq!(client.receive().await, sent);
+    }
+
+    #[tokio::test]
+    async fn tracks_end_for_everyone_past_a_stalled_client() {
+        let config = SessionConfig {
+            media_queue_len: 1,
+            ..Default::default()
+        };
+        let (stalled_connection, mut stalled) = mock::connect();
+        let stalled_outbox = Outbox::new(stalled_connection.clone(), &config, Arc::default());
+        stalled_outbox.enable_media(stalled_connection, &config);
+        stalled.stall_streams(true);
+        let (connection, mut client) = mock::connect();
+        let outbox = Outbox::new(connection.clone(), &config, Arc::default());
+        outbox.enable_media(connection, &config);
+
+        let track = Arc::new(Track::new(Bytes::from_static(b"header ")));
+        for frame in ["one ", "two"] {
+            for outbox in [&stalled_outbox, &outbox] {
+                outbox.push_media(&track, Bytes::from_static(frame.as_bytes()), true);
+            }
+            // The stalled client's queue takes the first frame off the queue, and then waits.
+            tokio::task::yield_now().await;
+        }
+        // As the relay does, for each member in turn, though the stalled client's queue is full.
+        stalled_outbox.end_media(&track);
+        outbox.end_media(&track);
+        assert_eq!(client.receive().await, "header one two");
+
+        // The stalled client's stream is finished once the frames before the end are sent.
+        stalled.stall_streams(false);
+        assert_eq!(stalled.receive().await, "header one two");
+    }
+
+    #[tokio::test]
+    async fn datagrams_wait_for_room_in_the_send_buffer() {
+        let (outbox, mut client, _) = outbox();
+        client.set_send_buffer_space(0);
+        let sent = packet(100);
+        outbox.send_control(sent.clone()).await.unwrap();
+        assert!(client.is_silent_for(Duration::from_millis(20)).await);
+
+        // Past its retries, it is sent anyway.
+        assert_eq!(client.receive().await, sent);
+    }
+}
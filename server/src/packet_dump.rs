@@ -0,0 +1,127 @@
+//! `--dump-packets`: every control packet a session receives or sends is logged as JSON, for
+//! debugging the protocol.
+//!
+//! Packets are logged whole, after reassembly on the way in and before fragmentation on the way
+//! out, under the `packets` target. Tokens in AUTH_REQUEST packets are left out. Packets every
+//! session exchanges periodically are sampled as [`DUMPED_PACKETS`] says.
+
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+
+use prost::Message;
+use protobuf::system;
+use protobuf::system::PacketType;
+use serde::Serialize;
+use serde_json::Value;
+use serde_json::json;
+use tracing::info;
+
+use crate::log_sampling::DUMPED_PACKETS;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Turns dumping on, as the server does when started with `--dump-packets`.
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Logs a control packet received from a client.
+pub fn received(packet: &[u8]) {
+    if dumps(packet) {
+        info!(target: "packets", "received {}", to_json(packet));
+    }
+}
+
+/// Logs a control packet sent to a client.
+pub fn sent(packet: &[u8]) {
+    if dumps(packet) {
+        info!(target: "packets", "sent {}", to_json(packet));
+    }
+}
+
+/// Whether to log a packet: all of them while dumping, but for a sample of the periodic ones.
+fn dumps(packet: &[u8]) -> bool {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return false;
+    }
+    let periodic = packet.first().is_some_and(|&packet_type| {
+        [
+            PacketType::ClockSync,
+            PacketType::PlayoutReport,
+            PacketType::SessionStats,
+            PacketType::Ping,
+            PacketType::Pong,
+            PacketType::Fragment,
+        ]
+        .iter()
+        .any(|periodic| *periodic as u8 == packet_type)
+    });
+    !periodic || DUMPED_PACKETS.sample()
+}
+
+fn to_json(packet: &[u8]) -> Value {
+    let Some((&type_byte, payload)) = packet.split_first() else {
+        return json!({ "error": "empty packet" });
+    };
+    let Ok(packet_type) = PacketType::try_from(type_byte as i32) else {
+        return json!({ "type": type_byte, "error": "unknown packet type" });
+    };
+
+    fn decode<M: Message + Default + Serialize>(payload: &[u8]) -> Result<Value, String> {
+        let message = M::decode(payload).map_err(|err| err.to_string())?;
+        serde_json::to_value(message).map_err(|err| err.to_string())
+    }
+    fn redact_resume_token(mut message: Value) -> Value {
+        if let Some(token) = message.get_mut("resume_token").filter(|token| *token != "") {
+            *token = "<redacted>".into();
+        }
+        message
+    }
+    let message = match packet_type {
+        PacketType::AuthRequest => decode::<system::AuthRequest>(payload).map(|mut message| {
+            message["token"] = "<redacted>".into();
+            redact_resume_token(message)
+        }),
+        PacketType::AuthResponseSuccess => decode::<system::AuthResponseSuccess>(payload),
+        PacketType::AuthResponseError => decode::<system::AuthResponseError>(payload),
+        PacketType::JoinRoomRequest => decode::<system::JoinRoomRequest>(payload),
+        PacketType::JoinRoomResponse => decode::<system::JoinRoomResponse>(payload),
+        PacketType::UserJoined => decode::<system::RoomUser>(payload),
+        PacketType::UserLeft => <[u8; 8]>::try_from(payload)
+            .map(|session_id| json!({ "session_id": i64::from_be_bytes(session_id) }))
+            .map_err(|_| "malformed session ID".to_owned()),
+        PacketType::Hello => decode::<system::Hello>(payload),
+        PacketType::HelloAck => decode::<system::HelloAck>(payload),
+        PacketType::HelloError => decode::<system::HelloError>(payload),
+        PacketType::Error => decode::<system::Error>(payload),
+        PacketType::Fragment => decode::<system::Fragment>(payload),
+        PacketType::ClockSync => decode::<system::ClockSync>(payload),
+        PacketType::PlayoutReport => decode::<system::PlayoutReport>(payload),
+        PacketType::SessionStats => decode::<system::SessionStats>(payload),
+        PacketType::JoinRoomRedirect => {
+            decode::<system::JoinRoomRedirect>(payload).map(redact_resume_token)
+        }
+        PacketType::ChatMessage => decode::<system::ChatMessage>(payload),
+        PacketType::Reaction => decode::<system::Reaction>(payload),
+        PacketType::FileUpload => decode::<system::FileUpload>(payload),
+        PacketType::MediaTrack => decode::<system::MediaTrack>(payload),
+        PacketType::ForwardingLimit => decode::<system::ForwardingLimit>(payload),
+        PacketType::Ping | PacketType::Pong => decode::<system::Ping>(payload),
+        PacketType::ChatAck => decode::<system::ChatAck>(payload),
+        PacketType::ChatBacklogRequest => decode::<system::ChatBacklogRequest>(payload),
+        PacketType::DirectMessage => decode::<system::DirectMessage>(payload),
+        PacketType::BlockList => decode::<system::BlockList>(payload),
+        PacketType::LobbyWaiting => decode::<system::LobbyWaiting>(payload),
+        PacketType::LobbyPending => decode::<system::LobbyPending>(payload),
+        PacketType::LobbyDecision => decode::<system::LobbyDecision>(payload),
+        PacketType::SlowMode => decode::<system::SlowMode>(payload),
+        PacketType::Announcement => decode::<system::Announcement>(payload),
+        PacketType::Maintenance => decode::<system::Maintenance>(payload),
+        // Packets are dumped before they are compressed, so this comes from clients only.
+        PacketType::Compressed => Ok(json!({ "deflated_len": payload.len() })),
+    };
+    match message {
+        Ok(message) => json!({ "type": packet_type.as_str_name(), "message": message }),
+        Err(error) => json!({ "type": packet_type.as_str_name(), "error": error }),
+    }
+}
@@ -0,0 +1,165 @@
+//! Room participants without a WebTransport connection, such as phone callers and broadcast
+//! streams, which take the room's audio as a single mixed-down stream.
+//!
+//! Room audio is assumed to be 16-bit PCM in 20 ms frames of 960 samples, as at 48 kHz.
+
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use protobuf::system::PacketType;
+use tokio::sync::Notify;
+use tracing::Instrument;
+use tracing::warn;
+
+use crate::dsp;
+use crate::mixer::Codec;
+use crate::mixer::Pcm16Codec;
+use crate::outbox::Inbox;
+use crate::outbox::Outbox;
+use crate::protocol;
+use crate::rooms::JoinError;
+use crate::rooms::Link;
+use crate::rooms::MAX_USERNAME_LEN;
+use crate::rooms::RegisterError;
+use crate::rooms::Registration;
+use crate::rooms::SessionId;
+use crate::state::ServerState;
+use crate::store::StateStore;
+use crate::tenants::Scoped;
+
+/// How long each frame of room audio lasts.
+pub const FRAME_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Samples in a frame of room audio.
+pub const FRAME_SAMPLES: usize = 960;
+
+/// Frames waiting to be mixed, past which the oldest are dropped.
+const MAX_PENDING_FRAMES: usize = 50;
+
+pub struct Participant {
+    registration: Registration,
+    inbox: Inbox,
+
+    /// Frames from speakers heard more than once since the last mix, waiting for a later one.
+    pending: VecDeque<Bytes>,
+
+    store: Arc<dyn StateStore>,
+}
+
+#[derive(Debug)]
+pub enum JoinRefused {
+    /// Another session is already using the username.
+    UsernameTaken,
+
+    /// The room key is empty, too long, or names no tenant on a server with tenants.
+    InvalidRoomKey,
+}
+
+impl Participant {
+    /// Registers a participant under a new session ID and joins it to a room, by its key with
+    /// its tenant. The username is cut to [`MAX_USERNAME_LEN`]. Kicking the participant
+    /// notifies `hang_up`.
+    pub async fn join(
+        state: &ServerState,
+        username: &str,
+        room_key: &str,
+        hang_up: Arc<Notify>,
+    ) -> Result<Self, JoinRefused> {
+        let username: String = username.chars().take(MAX_USERNAME_LEN).collect();
+        let room = Scoped::parse(&state.config, room_key).ok_or(JoinRefused::InvalidRoomKey)?;
+        let session_id = rand::random_range(1..SessionId::MAX);
+        let (outbox, inbox) = Outbox::bridged(&state.config.session);
+
+        let mut registration = match state.registry.register(
+            session_id,
+            room.tenant,
+            &username,
+            outbox,
+            Link::Bridged(hang_up),
+        ) {
+            Ok(registration) => registration,
+            Err(RegisterError::UsernameTaken | RegisterError::InvalidUsername) => {
+                return Err(JoinRefused::UsernameTaken);
+            }
+        };
+        let joined = match registration.join(room.name) {
+            Ok(joined) => joined,
+            Err(JoinError::InvalidRoomKey | JoinError::AlreadyInRoom) => {
+                return Err(JoinRefused::InvalidRoomKey);
+            }
+        };
+
+        if let Err(err) = state.store.join(room_key, &joined.user).await {
+            warn!("Cannot record join in the state store: {err:#}");
+        }
+        let packet = protocol::encode(PacketType::UserJoined, &joined.user);
+        for peer in joined.peers {
+            peer.try_send_control(packet.clone());
+        }
+
+        Ok(Self {
+            registration,
+            inbox,
+            pending: VecDeque::new(),
+            store: state.store.clone(),
+        })
+    }
+
+    pub fn session_id(&self) -> SessionId {
+        self.registration.session_id()
+    }
+
+    /// Sends a frame of room audio to the room, as the participant's voice.
+    pub fn speak(&self, state: &ServerState, pcm: &[i16]) {
+        let frame = self.registration.voice_frame(Pcm16Codec.encode(pcm), 0);
+        state.forward_voice(&self.registration, frame);
+    }
+
+    /// Mixes one frame from each speaker heard since the last mix into a frame of room audio.
+    pub fn mix(&mut self) -> Vec<i16> {
+        self.pending.extend(self.inbox.drain_audio());
+        while self.pending.len() > MAX_PENDING_FRAMES {
+            self.pending.pop_front();
+        }
+
+        let mut total = vec![0; FRAME_SAMPLES];
+        let mut pcm = vec![0; FRAME_SAMPLES];
+        let mut speakers = HashSet::new();
+        self.pending.retain(|frame| {
+            let Some(speaker) = frame.get(1..9) else {
+                return false;
+            };
+            if !speakers.insert(SessionId::from_be_bytes(speaker.try_into().unwrap())) {
+                return true;
+            }
+            let samples = Pcm16Codec.decode(&frame[9..], &mut pcm).unwrap_or(0);
+            dsp::accumulate(&mut total, &pcm[..samples]);
+            false
+        });
+
+        let mut mix = vec![0; FRAME_SAMPLES];
+        dsp::mix_down(&total, None, &mut mix);
+        mix
+    }
+}
+
+impl Drop for Participant {
+    fn drop(&mut self) {
+        let Some(room_key) = self.registration.room_key() else {
+            return;
+        };
+        let (store, room_key, session_id) =
+            (self.store.clone(), room_key.clone(), self.session_id());
+        tokio::spawn(
+            async move {
+                if let Err(err) = store.leave(&room_key, session_id).await {
+                    warn!("Cannot record leave in the state store: {err:#}");
+                }
+            }
+            .in_current_span(),
+        );
+    }
+}
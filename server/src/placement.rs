@@ -0,0 +1,70 @@
+//! Placing rooms on nodes by consistent hashing.
+//!
+//! Every node hashes the same list of addresses onto a ring of points, and a room belongs to the
+//! first point at or after its key's hash. Adding or removing a node only moves the rooms on its
+//! own points. The hash is FNV-1a rather than a randomly seeded hasher, so every node computes
+//! the same ring.
+
+/// Points each node has on the ring, which evens out the share of rooms each node gets.
+const POINTS_PER_NODE: u32 = 64;
+
+pub struct Placement {
+    /// This node's address.
+    local: String,
+
+    /// The ring's points, sorted by hash, with the index of the node each belongs to.
+    ring: Vec<(u64, usize)>,
+
+    nodes: Vec<String>,
+}
+
+impl Placement {
+    /// Places rooms over `local` and `peers`, identified by their HTTP addresses.
+    pub fn new(local: &str, peers: &[String]) -> Self {
+        let mut nodes: Vec<String> = peers.iter().map(|peer| normalize(peer)).collect();
+        nodes.push(normalize(local));
+        nodes.sort();
+        nodes.dedup();
+
+        let mut ring: Vec<(u64, usize)> = nodes
+            .iter()
+            .enumerate()
+            .flat_map(|(index, node)| {
+                (0..POINTS_PER_NODE)
+                    .map(move |point| (fnv1a(format!("{node}#{point}").as_bytes()), index))
+            })
+            .collect();
+        ring.sort_unstable();
+
+        Self {
+            local: normalize(local),
+            ring,
+            nodes,
+        }
+    }
+
+    /// The address of the node a room belongs to.
+    pub fn owner(&self, room_key: &str) -> &str {
+        let hash = fnv1a(room_key.as_bytes());
+        let point = self.ring.partition_point(|(point, _)| *point < hash);
+        let (_, node) = self.ring[point % self.ring.len()];
+        &self.nodes[node]
+    }
+
+    /// The address of the node a room belongs to, unless it is this one.
+    pub fn redirect(&self, room_key: &str) -> Option<&str> {
+        let owner = self.owner(room_key);
+        (owner != self.local).then_some(owner)
+    }
+}
+
+/// Strips a trailing slash, so `http://a:8080/` and `http://a:8080` are the same node.
+fn normalize(address: &str) -> String {
+    address.trim_end_matches('/').to_owned()
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
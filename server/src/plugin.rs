@@ -0,0 +1,241 @@
+//! Hooks letting deployments add their own logic to sessions, such as word filters, logging or
+//! game integration, without changing the session handler.
+//!
+//! A [`Plugin`] implements whichever hooks it needs. Plugins are registered once at startup in
+//! [`Plugins::new`], and each hook runs them in registration order. Hooks that return a
+//! [`Verdict`] can refuse what the client asked for, with a reason sent back to it in an error
+//! packet; the first plugin to refuse wins, and later plugins are not asked.
+//!
+//! Hooks run on the session's task, so they must return quickly: plugins that talk to other
+//! services should queue the work on a task of their own.
+
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Context;
+use anyhow::Result;
+use protobuf::system::ChatMessage;
+use protobuf::system::DirectMessage;
+use protobuf::system::RoomUser;
+use serde_json::Map;
+use serde_json::Value;
+use serde_json::json;
+use tracing::warn;
+
+use crate::config::Config;
+use crate::json_log::JsonLog;
+use crate::latency;
+use crate::moderation::AuditLog;
+use crate::moderation::ChatFilter;
+use crate::protocol::ClientPacket;
+use crate::rooms::SessionId;
+use crate::tenants::Scoped;
+
+/// Whether a plugin lets a request through, or the reason it refuses it.
+pub type Verdict = Result<(), String>;
+
+pub trait Plugin: Send + Sync {
+    /// Called before a user joins a room.
+    fn on_join(&self, _user: &RoomUser, _room_key: &str) -> Verdict {
+        Ok(())
+    }
+
+    /// Called for every control packet a session sends after its handshake, before the server
+    /// handles it.
+    fn on_packet(&self, _session_id: SessionId, _packet: &ClientPacket) -> Verdict {
+        Ok(())
+    }
+
+    /// Called before a chat message is delivered to a room, including messages bridged from
+    /// outside the server. The message may be rewritten.
+    fn on_chat(&self, _room_key: &str, _message: &mut ChatMessage) -> Verdict {
+        Ok(())
+    }
+
+    /// Called before a direct message is delivered. By default the message is checked as a chat
+    /// message with an empty room key, so that plugins filtering chat filter direct messages too.
+    /// The message's text may be rewritten.
+    fn on_direct_message(&self, message: &mut DirectMessage) -> Verdict {
+        let mut chat = ChatMessage {
+            session_id: message.session_id,
+            username: message.username.clone(),
+            text: std::mem::take(&mut message.text),
+            ..Default::default()
+        };
+        let verdict = self.on_chat("", &mut chat);
+        message.text = chat.text;
+        verdict
+    }
+
+    /// Called after a user leaves a room.
+    fn on_leave(&self, _user: &RoomUser, _room_key: &str) {}
+
+    /// Called when a user asks for the data kept about them, to add what the plugin keeps to the
+    /// export under a key of its own. On servers with tenants, only what the user did in their
+    /// tenant's rooms is theirs.
+    fn export_user(&self, _user: Scoped, _export: &mut Map<String, Value>) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called when a user's data is deleted, to erase or anonymize what the plugin keeps about
+    /// them.
+    fn erase_user(&self, _user: Scoped) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// The plugins registered at startup.
+#[derive(Default)]
+pub struct Plugins {
+    plugins: Vec<Box<dyn Plugin>>,
+
+    /// Kept here as well as by the chat filter, so its entries can be exported and erased even
+    /// while the filter is off.
+    audit: Arc<AuditLog>,
+}
+
+impl Plugins {
+    /// Registers the bundled plugins enabled in `config`. Deployments with plugins of their own
+    /// register them here too.
+    pub fn new(config: &Config) -> Result<Self> {
+        let mut plugins = Self {
+            plugins: Vec::new(),
+            audit: Arc::new(AuditLog::open(config.moderation.audit_log.as_deref())?),
+        };
+        if let Some(path) = &config.plugins.event_log {
+            plugins.register(EventLog::open(path)?);
+        }
+
+        let audit = plugins.audit.clone();
+        if let Some(filter) = ChatFilter::new(&config.moderation.chat_filter, audit)? {
+            plugins.register(filter);
+        }
+        Ok(plugins)
+    }
+
+    pub fn register(&mut self, plugin: impl Plugin + 'static) {
+        self.plugins.push(Box::new(plugin));
+    }
+
+    pub fn on_join(&self, user: &RoomUser, room_key: &str) -> Verdict {
+        self.plugins
+            .iter()
+            .try_for_each(|plugin| plugin.on_join(user, room_key))
+    }
+
+    pub fn on_packet(&self, session_id: SessionId, packet: &ClientPacket) -> Verdict {
+        self.plugins
+            .iter()
+            .try_for_each(|plugin| plugin.on_packet(session_id, packet))
+    }
+
+    pub fn on_chat(&self, room_key: &str, message: &mut ChatMessage) -> Verdict {
+        self.plugins
+            .iter()
+            .try_for_each(|plugin| plugin.on_chat(room_key, message))
+    }
+
+    pub fn on_direct_message(&self, message: &mut DirectMessage) -> Verdict {
+        self.plugins
+            .iter()
+            .try_for_each(|plugin| plugin.on_direct_message(message))
+    }
+
+    pub fn on_leave(&self, user: &RoomUser, room_key: &str) {
+        for plugin in &self.plugins {
+            plugin.on_leave(user, room_key);
+        }
+    }
+
+    /// Collects what every plugin and the audit log keep about a user.
+    pub fn export_user(&self, user: Scoped, export: &mut Map<String, Value>) -> Result<()> {
+        export.insert("audit_log".to_owned(), self.audit.find(user)?.into());
+        self.plugins
+            .iter()
+            .try_for_each(|plugin| plugin.export_user(user, export))
+    }
+
+    /// Erases what every plugin and the audit log keep about a user.
+    pub fn erase_user(&self, user: Scoped) -> Result<()> {
+        self.audit.anonymize(user)?;
+        self.plugins
+            .iter()
+            .try_for_each(|plugin| plugin.erase_user(user))
+    }
+}
+
+/// Appends a JSON line to a file for every join, leave and chat message.
+struct EventLog {
+    file: JsonLog,
+}
+
+impl EventLog {
+    fn open(path: &Path) -> Result<Self> {
+        Ok(Self {
+            file: JsonLog::open(path).context("Cannot open the event log")?,
+        })
+    }
+
+    fn entry(event: &str, room_key: &str, session_id: SessionId, username: &str) -> Value {
+        json!({
+            "time_us": latency::now_us(),
+            "event": event,
+            "room_key": room_key,
+            "session_id": session_id,
+            "username": username,
+        })
+    }
+
+    fn append(&self, entry: Value) {
+        if let Err(err) = self.file.append(&entry) {
+            warn!("Cannot write to the event log: {err}");
+        }
+    }
+}
+
+impl Plugin for EventLog {
+    fn on_join(&self, user: &RoomUser, room_key: &str) -> Verdict {
+        self.append(Self::entry(
+            "join",
+            room_key,
+            user.session_id,
+            &user.username,
+        ));
+        Ok(())
+    }
+
+    fn on_chat(&self, room_key: &str, message: &mut ChatMessage) -> Verdict {
+        let mut entry = Self::entry("chat", room_key, message.session_id, &message.username);
+        entry["text"] = message.text.as_str().into();
+        self.append(entry);
+        Ok(())
+    }
+
+    fn on_direct_message(&self, message: &mut DirectMessage) -> Verdict {
+        let mut entry = Self::entry("direct_message", "", message.session_id, &message.username);
+        entry["to_session_id"] = message.to_session_id.into();
+        entry["text"] = message.text.as_str().into();
+        self.append(entry);
+        Ok(())
+    }
+
+    fn on_leave(&self, user: &RoomUser, room_key: &str) {
+        self.append(Self::entry(
+            "leave",
+            room_key,
+            user.session_id,
+            &user.username,
+        ));
+    }
+
+    fn export_user(&self, user: Scoped, export: &mut Map<String, Value>) -> Result<()> {
+        let entries = self.file.find(user)?;
+        export.insert("event_log".to_owned(), entries.into());
+        Ok(())
+    }
+
+    fn erase_user(&self, user: Scoped) -> Result<()> {
+        self.file.anonymize(user)?;
+        Ok(())
+    }
+}
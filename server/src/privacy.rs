@@ -0,0 +1,269 @@
+//! Users' data requests, such as under the GDPR: exporting what the server keeps about a user,
+//! and deleting it.
+//!
+//! `GET /users/<username>/data` returns a JSON export of the user's account, avatar, and the
+//! entries about them in the chat history, in the event and audit logs and in plugins, with the
+//! recordings they appear in, each of which is downloaded from
+//! `/users/<username>/data/recordings/<id>`. `DELETE` on the same path deletes the account,
+//! avatar and those recordings, anonymizes those entries, and ends the user's sessions. Any of
+//! these is allowed with the admin token, or with the user's own password as HTTP Basic
+//! credentials. On servers with tenants, users are named with their tenant, as in `acme/alice`,
+//! and each tenant's API key is allowed for the tenant's users.
+//!
+//! Files the user shared in chat are not tied to them once stored, so they are neither exported
+//! nor deleted. Recordings are tied to everyone who was in the room while they were made.
+
+use std::sync::Arc;
+
+use anyhow::Context;
+use axum::Json;
+use axum::Router;
+use axum::body::Body;
+use axum::extract::Path;
+use axum::extract::State;
+use axum::http::HeaderMap;
+use axum::http::StatusCode;
+use axum::http::header::CONTENT_TYPE;
+use axum::response::IntoResponse;
+use axum::response::Response;
+use axum::routing::get;
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use serde_json::Map;
+use serde_json::Value;
+use serde_json::json;
+use tracing::info;
+use tracing::warn;
+
+use crate::accounts;
+use crate::accounts::LoginError;
+use crate::admin;
+use crate::api_keys::Permission;
+use crate::avatars;
+use crate::state::ServerState;
+use crate::tenants::Scoped;
+
+pub fn router(state: Arc<ServerState>) -> Router {
+    Router::new()
+        .route("/users/{username}/data", get(export).delete(erase))
+        .route(
+            "/users/{username}/data/recordings/{id}",
+            get(download_recording),
+        )
+        .with_state(state)
+}
+
+/// Checks that the request comes from an admin of the user's tenant, or from the user
+/// themselves.
+async fn authorize(
+    state: &ServerState,
+    headers: &HeaderMap,
+    username: &str,
+) -> Result<(), (StatusCode, &'static str)> {
+    if Scoped::parse(&state.config, username).is_none() {
+        return Err((
+            StatusCode::NOT_FOUND,
+            "usernames must start with their tenant",
+        ));
+    }
+    if let Ok(caller) = admin::authenticate(state, headers) {
+        if !caller.allows(Permission::UsersData) {
+            return Err((
+                StatusCode::FORBIDDEN,
+                "the token lacks the permission for this endpoint",
+            ));
+        }
+        return match caller.scope.name(username) {
+            Some(_) => Ok(()),
+            None => Err((
+                StatusCode::FORBIDDEN,
+                "tenant API keys only reach their own users",
+            )),
+        };
+    }
+
+    let unauthorized = (StatusCode::UNAUTHORIZED, "invalid username or password");
+    let (Some(accounts), Some((login, password))) =
+        (&state.accounts, accounts::basic_credentials(headers))
+    else {
+        return Err(unauthorized);
+    };
+    if login != username {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "users may only request their own data",
+        ));
+    }
+    match accounts::login(accounts.as_ref(), &login, &password, false).await {
+        // Banned users still own their data.
+        Ok(Ok(_) | Err(LoginError::Banned(_))) => Ok(()),
+        Ok(Err(LoginError::InvalidCredentials)) => Err(unauthorized),
+        Err(err) => {
+            warn!("Cannot check data request login: {err:#}");
+            Err((StatusCode::INTERNAL_SERVER_ERROR, "cannot check the login"))
+        }
+    }
+}
+
+async fn export(
+    State(state): State<Arc<ServerState>>,
+    Path(username): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(rejection) = authorize(&state, &headers, &username).await {
+        return rejection.into_response();
+    }
+    match collect(state, &username).await {
+        Ok(export) => Json(export).into_response(),
+        Err(err) => {
+            warn!("Cannot export the data of '{username}': {err:#}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+async fn collect(state: Arc<ServerState>, username: &str) -> anyhow::Result<Value> {
+    let mut export = Map::new();
+    export.insert("username".to_owned(), username.into());
+
+    let account = match &state.accounts {
+        Some(accounts) => accounts.get(username).await?,
+        None => None,
+    };
+    let account = account.map(|account| {
+        json!({
+            "role": account.role,
+            "ban": account.ban,
+            "created_at": account.created_at,
+            "avatar_updated_at": account.avatar_updated_at,
+            "blocked": account.blocked,
+        })
+    });
+    export.insert("account".to_owned(), account.into());
+
+    let avatar = match &state.blobs {
+        Some(blobs) => blobs.get(&avatars::key(username)).await?,
+        None => None,
+    };
+    let avatar = avatar.map(|avatar| BASE64_STANDARD.encode(avatar));
+    export.insert("avatar_base64".to_owned(), avatar.into());
+
+    let recordings: Vec<Value> = state
+        .recordings
+        .of_user(user(&state, username)?)
+        .await?
+        .into_iter()
+        .map(|recording| {
+            json!({
+                "room_key": recording.room_key,
+                "started_at": recording.started_at,
+                "url": format!("/users/{username}/data/recordings/{}", recording.id),
+            })
+        })
+        .collect();
+    export.insert("recordings".to_owned(), recordings.into());
+
+    // The logs are plain files, read on a blocking thread.
+    let username = username.to_owned();
+    tokio::task::spawn_blocking(move || {
+        let user = user(&state, &username)?;
+        let chat_history = state.chat_history.export_user(user)?;
+        export.insert("chat_history".to_owned(), chat_history);
+        state.plugins.export_user(user, &mut export)?;
+        Ok(export.into())
+    })
+    .await?
+}
+
+async fn erase(
+    State(state): State<Arc<ServerState>>,
+    Path(username): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(rejection) = authorize(&state, &headers, &username).await {
+        return rejection.into_response();
+    }
+    match delete(state, &username).await {
+        Ok(()) => {
+            info!("Deleted the data of '{username}'");
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(err) => {
+            warn!("Cannot delete the data of '{username}': {err:#}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+async fn delete(state: Arc<ServerState>, username: &str) -> anyhow::Result<()> {
+    for (session_id, session_username) in state.registry.sessions() {
+        if session_username == username {
+            state.registry.kick(session_id);
+        }
+    }
+
+    if let Some(blobs) = &state.blobs {
+        blobs.delete(&avatars::key(username)).await?;
+    }
+    state.recordings.erase_user(user(&state, username)?).await?;
+    if let Some(accounts) = &state.accounts {
+        accounts.delete(username).await?;
+    }
+
+    let username = username.to_owned();
+    tokio::task::spawn_blocking(move || {
+        let user = user(&state, &username)?;
+        state.chat_history.erase_user(user)?;
+        state.plugins.erase_user(user)
+    })
+    .await?
+}
+
+/// Streams a recording the user appears in, decrypting it a segment at a time.
+async fn download_recording(
+    State(state): State<Arc<ServerState>>,
+    Path((username, id)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(rejection) = authorize(&state, &headers, &username).await {
+        return rejection.into_response();
+    }
+    let recordings = match user(&state, &username) {
+        Ok(user) => state.recordings.of_user(user).await,
+        Err(err) => Err(err),
+    };
+    let recording = match recordings {
+        Ok(recordings) => recordings.into_iter().find(|recording| recording.id == id),
+        Err(err) => {
+            warn!("Cannot list the recordings of '{username}': {err:#}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let Some(recording) = recording else {
+        return (StatusCode::NOT_FOUND, "no such recording of the user").into_response();
+    };
+
+    let segments = futures_util::stream::unfold(0, move |segment| {
+        let (state, id) = (state.clone(), recording.id.clone());
+        async move {
+            if segment == recording.segments {
+                return None;
+            }
+            let data = match state.recordings.segment(&id, segment).await {
+                Ok(Some(data)) => Ok(data),
+                Ok(None) => Err(anyhow::anyhow!("segment {segment} of {id} is missing")),
+                Err(err) => Err(err),
+            };
+            if let Err(err) = &data {
+                warn!("Cannot serve recording {id}: {err:#}");
+            }
+            Some((data.map_err(std::io::Error::other), segment + 1))
+        }
+    });
+    ([(CONTENT_TYPE, "audio/ogg")], Body::from_stream(segments)).into_response()
+}
+
+/// The user a username names, checked by [`authorize`].
+fn user<'a>(state: &'a ServerState, username: &'a str) -> anyhow::Result<Scoped<'a>> {
+    Scoped::parse(&state.config, username).context("the username has no tenant")
+}
@@ -0,0 +1,149 @@
+//! An on-demand profiler built on tracing spans.
+//!
+//! While a profile is running, the [`ProfilerLayer`] measures the time spent inside every span
+//! (for async tasks, the time spent polling them) minus the time spent in its child spans, and
+//! adds it to the span's stack. The result is rendered in the folded stack format read by
+//! `flamegraph.pl`, inferno and speedscope, with one line per stack and weights in
+//! microseconds.
+//!
+//! Only code running inside a span is measured, so the hot paths (connections, outbox writers
+//! and mixer ticks) all run in spans. Spans entered on several threads at once are measured
+//! approximately.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::Instant;
+
+use tracing::Subscriber;
+use tracing::span;
+use tracing_subscriber::Layer;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+
+static PROFILE: Profile = Profile {
+    active: AtomicBool::new(false),
+    stacks: Mutex::new(None),
+};
+
+struct Profile {
+    active: AtomicBool,
+    stacks: Mutex<Option<HashMap<String, Duration>>>,
+}
+
+/// A profile is already running.
+#[derive(Debug)]
+pub struct Busy;
+
+/// Profiles the server for `duration` and returns the folded stacks.
+pub async fn profile(duration: Duration) -> Result<String, Busy> {
+    if PROFILE
+        .active
+        .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+        .is_err()
+    {
+        return Err(Busy);
+    }
+    let running = Running;
+    *PROFILE.stacks.lock().unwrap() = Some(HashMap::new());
+
+    tokio::time::sleep(duration).await;
+
+    let stacks = PROFILE.stacks.lock().unwrap().take().unwrap_or_default();
+    drop(running);
+
+    let mut stacks: Vec<_> = stacks.into_iter().collect();
+    stacks.sort();
+    let mut out = String::new();
+    for (stack, time) in stacks {
+        let _ = writeln!(out, "{stack} {}", time.as_micros());
+    }
+    Ok(out)
+}
+
+/// Ends the running profile when dropped, even if the request for it is abandoned partway.
+struct Running;
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        PROFILE.stacks.lock().unwrap().take();
+        PROFILE.active.store(false, Ordering::Release);
+    }
+}
+
+/// Records span timings into the running profile, if any.
+pub struct ProfilerLayer;
+
+/// Timing state kept in the extensions of each entered span.
+struct Timing {
+    entered: Instant,
+    children: Duration,
+}
+
+impl<S> Layer<S> for ProfilerLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_enter(&self, id: &span::Id, ctx: Context<'_, S>) {
+        if !PROFILE.active.load(Ordering::Relaxed) {
+            return;
+        }
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().replace(Timing {
+                entered: Instant::now(),
+                children: Duration::ZERO,
+            });
+        }
+    }
+
+    fn on_exit(&self, id: &span::Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let Some(timing) = span.extensions_mut().remove::<Timing>() else {
+            return;
+        };
+
+        let total = timing.entered.elapsed();
+        if let Some(parent) = span.parent()
+            && let Some(parent_timing) = parent.extensions_mut().get_mut::<Timing>()
+        {
+            parent_timing.children += total;
+        }
+
+        let mut stack = String::new();
+        for ancestor in span.scope().from_root() {
+            if !stack.is_empty() {
+                stack.push(';');
+            }
+            stack.push_str(ancestor.name());
+        }
+
+        if let Some(stacks) = PROFILE.stacks.lock().unwrap().as_mut() {
+            *stacks.entry(stack).or_default() += total.saturating_sub(timing.children);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::PROFILE;
+    use super::profile;
+
+    #[tokio::test]
+    async fn abandoned_profiles_end() {
+        let abandoned = tokio::time::timeout(
+            Duration::from_millis(10),
+            profile(Duration::from_secs(3600)),
+        );
+        assert!(abandoned.await.is_err());
+        assert!(PROFILE.stacks.lock().unwrap().is_none());
+
+        assert!(profile(Duration::ZERO).await.is_ok());
+    }
+}
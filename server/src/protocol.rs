@@ -0,0 +1,510 @@
+//! Framing of the packets exchanged with clients.
+//!
+//! Control packets are a single [`PacketType`] byte followed by the protobuf-encoded message.
+//! Voice datagrams start with [`VOICE_PACKET_PREFIX`] instead of a packet type.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::Duration;
+
+use bytes::BufMut;
+use bytes::Bytes;
+use bytes::BytesMut;
+use prost::Message;
+use protobuf::system;
+use protobuf::system::CloseCode;
+use protobuf::system::Feature;
+use protobuf::system::PacketType;
+use protobuf::system::error;
+use protobuf::system::hello_error;
+use wtransport::VarInt;
+
+use crate::transport::SendStream;
+use crate::transport::Transport;
+
+/// The first byte of every voice datagram.
+pub const VOICE_PACKET_PREFIX: u8 = 0xFF;
+
+/// The first byte of streams carrying a file upload; see [`system::FileUpload`].
+pub const FILE_STREAM_PREFIX: u8 = 0xFE;
+
+/// The first byte of streams carrying a media track; see [`system::MediaTrack`].
+pub const MEDIA_STREAM_PREFIX: u8 = 0xFD;
+
+/// The largest UDP payload quinn's path MTU discovery probes for, so no datagram is larger.
+pub const MAX_DATAGRAM_SIZE: usize = 1452;
+
+/// The newest protocol version spoken by the server.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The oldest protocol version still spoken by the server.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// The send priority of streams carrying control packets. Other streams, such as bulk
+/// transfers, keep QUIC's default priority of 0, so buffered control data always goes first.
+pub const CONTROL_STREAM_PRIORITY: i32 = 100;
+
+/// The features the server supports, as a [`Feature`] bitmask.
+pub const SUPPORTED_FEATURES: u32 = Feature::VoiceDatagrams as u32
+    | Feature::Fragmentation as u32
+    | Feature::LatencyReports as u32
+    | Feature::SessionStats as u32
+    | Feature::Redirects as u32
+    | Feature::MediaStreams as u32
+    | Feature::ForwardingLimits as u32
+    | Feature::Pings as u32
+    | Feature::RoomMoves as u32
+    | Feature::Migration as u32
+    | Feature::ChatHistory as u32
+    | Feature::ChatReceipts as u32
+    | Feature::DirectMessages as u32
+    | Feature::Lobby as u32
+    | Feature::AudioHeader as u32
+    | Feature::Compression as u32
+    | Feature::WebsocketSignaling as u32;
+
+/// The shortest control packet sent compressed to clients that negotiated
+/// `FEATURE_COMPRESSION`; shorter ones gain too little to be worth it.
+pub const COMPRESSION_MIN_LEN: usize = 512;
+
+/// The longest accepted chat message, in characters.
+pub const MAX_CHAT_LEN: usize = 2000;
+
+/// The longest accepted reaction emoji or short code, in characters.
+pub const MAX_EMOJI_LEN: usize = 32;
+
+/// The most users a session may block.
+pub const MAX_BLOCKED_USERS: usize = 1000;
+
+/// The longest a room's slow mode may have users wait between chat messages, in seconds.
+pub const MAX_SLOW_MODE_SECS: u32 = 60 * 60;
+
+/// The length of the capture timestamp leading voice datagrams from clients that negotiated
+/// [`Feature::LatencyReports`]: microseconds since the Unix epoch on the client's clock, as a
+/// big-endian `u64`. The server strips it before forwarding.
+pub const CAPTURE_TIMESTAMP_LEN: usize = 8;
+
+/// A packet received from a client.
+#[derive(Debug)]
+pub enum ClientPacket {
+    Hello(system::Hello),
+    AuthRequest(system::AuthRequest),
+    JoinRoomRequest(system::JoinRoomRequest),
+    ClockSync(system::ClockSync),
+    PlayoutReport(system::PlayoutReport),
+    ChatMessage(system::ChatMessage),
+    Reaction(system::Reaction),
+    Pong(system::Ping),
+    ChatBacklogRequest(system::ChatBacklogRequest),
+    DirectMessage(system::DirectMessage),
+    BlockList(system::BlockList),
+    LobbyDecision(system::LobbyDecision),
+    SlowMode(system::SlowMode),
+}
+
+impl ClientPacket {
+    /// Decodes a control packet.
+    pub fn decode(data: &[u8]) -> Result<Self, PacketError> {
+        let (&type_byte, payload) = data.split_first().ok_or(PacketError::Empty)?;
+        let packet_type = PacketType::try_from(type_byte as i32)
+            .map_err(|_| PacketError::UnknownType(type_byte))?;
+
+        let decode_error = |err| PacketError::Decode(packet_type, err);
+        let packet = match packet_type {
+            PacketType::Hello => Self::Hello(system::Hello::decode(payload).map_err(decode_error)?),
+            PacketType::AuthRequest => {
+                Self::AuthRequest(system::AuthRequest::decode(payload).map_err(decode_error)?)
+            }
+            PacketType::JoinRoomRequest => Self::JoinRoomRequest(
+                system::JoinRoomRequest::decode(payload).map_err(decode_error)?,
+            ),
+            PacketType::ClockSync => {
+                Self::ClockSync(system::ClockSync::decode(payload).map_err(decode_error)?)
+            }
+            PacketType::PlayoutReport => {
+                Self::PlayoutReport(system::PlayoutReport::decode(payload).map_err(decode_error)?)
+            }
+            PacketType::ChatMessage => {
+                Self::ChatMessage(system::ChatMessage::decode(payload).map_err(decode_error)?)
+            }
+            PacketType::Reaction => {
+                Self::Reaction(system::Reaction::decode(payload).map_err(decode_error)?)
+            }
+            PacketType::Pong => Self::Pong(system::Ping::decode(payload).map_err(decode_error)?),
+            PacketType::ChatBacklogRequest => Self::ChatBacklogRequest(
+                system::ChatBacklogRequest::decode(payload).map_err(decode_error)?,
+            ),
+            PacketType::DirectMessage => {
+                Self::DirectMessage(system::DirectMessage::decode(payload).map_err(decode_error)?)
+            }
+            PacketType::BlockList => {
+                Self::BlockList(system::BlockList::decode(payload).map_err(decode_error)?)
+            }
+            PacketType::LobbyDecision => {
+                Self::LobbyDecision(system::LobbyDecision::decode(payload).map_err(decode_error)?)
+            }
+            PacketType::SlowMode => {
+                Self::SlowMode(system::SlowMode::decode(payload).map_err(decode_error)?)
+            }
+            other => return Err(PacketError::UnexpectedType(other)),
+        };
+
+        Ok(packet)
+    }
+
+    /// The packet's type.
+    pub fn packet_type(&self) -> PacketType {
+        match self {
+            Self::Hello(_) => PacketType::Hello,
+            Self::AuthRequest(_) => PacketType::AuthRequest,
+            Self::JoinRoomRequest(_) => PacketType::JoinRoomRequest,
+            Self::ClockSync(_) => PacketType::ClockSync,
+            Self::PlayoutReport(_) => PacketType::PlayoutReport,
+            Self::ChatMessage(_) => PacketType::ChatMessage,
+            Self::Reaction(_) => PacketType::Reaction,
+            Self::Pong(_) => PacketType::Pong,
+            Self::ChatBacklogRequest(_) => PacketType::ChatBacklogRequest,
+            Self::DirectMessage(_) => PacketType::DirectMessage,
+            Self::BlockList(_) => PacketType::BlockList,
+            Self::LobbyDecision(_) => PacketType::LobbyDecision,
+            Self::SlowMode(_) => PacketType::SlowMode,
+        }
+    }
+}
+
+/// Encodes a control packet.
+pub fn encode(packet_type: PacketType, message: &impl Message) -> Bytes {
+    let mut buf = BytesMut::with_capacity(1 + message.encoded_len());
+    buf.put_u8(packet_type as u8);
+    message
+        .encode(&mut buf)
+        .expect("buffer was sized for the message");
+    buf.freeze()
+}
+
+/// The length of the header leading voice datagrams to and from clients that negotiated
+/// [`Feature::AudioHeader`], prefix included; see [`VoiceHeader`].
+pub const VOICE_HEADER_LEN: usize = 25;
+
+/// How many bytes of voice frames are cut from one buffer, so that a frame left queued keeps
+/// little else alive.
+const VOICE_BUFFER_LEN: usize = 4 * 1024;
+
+/// How many used-up buffers each thread keeps waiting for their frames to be dropped.
+const MAX_RETIRED_VOICE_BUFFERS: usize = 8;
+
+thread_local! {
+    /// The buffers each thread cuts voice frames from.
+    static VOICE_BUFFERS: RefCell<VoiceBuffers> = RefCell::default();
+}
+
+/// A thread's buffers for voice frames: the one frames are cut from, and the used-up ones whose
+/// frames may still be queued.
+struct VoiceBuffers {
+    current: BytesMut,
+    retired: VecDeque<BytesMut>,
+}
+
+impl Default for VoiceBuffers {
+    fn default() -> Self {
+        Self {
+            current: BytesMut::with_capacity(VOICE_BUFFER_LEN),
+            retired: VecDeque::with_capacity(MAX_RETIRED_VOICE_BUFFERS),
+        }
+    }
+}
+
+impl VoiceBuffers {
+    /// Replaces the current buffer with one of at least `len` bytes: a retired one whose frames
+    /// were all dropped if there is one, and a new one otherwise.
+    fn renew(&mut self, len: usize) {
+        let len = len.max(VOICE_BUFFER_LEN);
+        let next = match self.retired.iter_mut().position(|buf| buf.try_reclaim(len)) {
+            Some(index) => self.retired.remove(index).unwrap(),
+            None => {
+                if self.retired.len() >= MAX_RETIRED_VOICE_BUFFERS {
+                    self.retired.pop_front();
+                }
+                BytesMut::with_capacity(len)
+            }
+        };
+        let used = std::mem::replace(&mut self.current, next);
+        self.retired.push_back(used);
+    }
+}
+
+/// The routing fields of a voice frame. Clients that negotiated [`Feature::AudioHeader`] send
+/// and receive them ahead of the payload, at fixed offsets: the prefix, the session ID as an
+/// `i64`, the room ID and the sequence number as `u32`s and the capture time as a `u64`, all
+/// big-endian. The server routes frames on them alone and passes the payload on untouched, so
+/// such clients may encrypt it end to end, in rooms that are not mixed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VoiceHeader {
+    /// The speaker's.
+    pub session_id: i64,
+
+    /// The [`room_id`] of the speaker's room.
+    pub room_id: u32,
+
+    /// Counts the speaker's frames, wrapping around, so listeners can tell lost and reordered
+    /// ones.
+    pub sequence: u32,
+
+    /// Microseconds since the Unix epoch on the speaker's clock, or 0 if unknown.
+    pub capture_time_us: u64,
+}
+
+impl VoiceHeader {
+    /// Reads the header of a voice datagram, without looking past it.
+    pub fn parse(datagram: &[u8]) -> Option<Self> {
+        let header = datagram.get(..VOICE_HEADER_LEN)?;
+        if header[0] != VOICE_PACKET_PREFIX {
+            return None;
+        }
+        Some(Self {
+            session_id: i64::from_be_bytes(header[1..9].try_into().unwrap()),
+            room_id: u32::from_be_bytes(header[9..13].try_into().unwrap()),
+            sequence: u32::from_be_bytes(header[13..17].try_into().unwrap()),
+            capture_time_us: u64::from_be_bytes(header[17..25].try_into().unwrap()),
+        })
+    }
+}
+
+/// The ID voice headers carry for a room, by its key with its tenant: the key's 32-bit FNV-1a
+/// hash, so every node gives a room the same one.
+pub fn room_id(room_key: &str) -> u32 {
+    room_key.bytes().fold(0x811c_9dc5, |hash, byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// A voice frame being forwarded, built in each listener's layout the first time a listener
+/// takes it in that layout. Every listener taking it in the same layout shares the result.
+pub struct VoiceFrame {
+    header: VoiceHeader,
+    payload: Bytes,
+    plain: Option<Bytes>,
+    with_header: Option<Bytes>,
+}
+
+impl VoiceFrame {
+    pub fn new(header: VoiceHeader, payload: Bytes) -> Self {
+        Self {
+            header,
+            payload,
+            plain: None,
+            with_header: None,
+        }
+    }
+
+    /// A frame received with its header, `datagram` being all of it, which listeners taking
+    /// headers are sent as it was received.
+    pub fn received(header: VoiceHeader, datagram: Bytes) -> Self {
+        Self {
+            header,
+            payload: datagram.slice(VOICE_HEADER_LEN..),
+            plain: None,
+            with_header: Some(datagram),
+        }
+    }
+
+    pub fn header(&self) -> &VoiceHeader {
+        &self.header
+    }
+
+    pub fn payload(&self) -> &Bytes {
+        &self.payload
+    }
+
+    /// The frame as a datagram for a listener, with the [`VoiceHeader`] or, as [`voice`] builds
+    /// it, with the speaker's session ID only.
+    pub fn layout(&mut self, with_header: bool) -> Bytes {
+        if with_header {
+            self.with_header
+                .get_or_insert_with(|| {
+                    cut(VOICE_HEADER_LEN + self.payload.len(), |buf| {
+                        buf.put_u8(VOICE_PACKET_PREFIX);
+                        buf.put_i64(self.header.session_id);
+                        buf.put_u32(self.header.room_id);
+                        buf.put_u32(self.header.sequence);
+                        buf.put_u64(self.header.capture_time_us);
+                        buf.put_slice(&self.payload);
+                    })
+                })
+                .clone()
+        } else {
+            self.plain
+                .get_or_insert_with(|| voice(self.header.session_id, &self.payload))
+                .clone()
+        }
+    }
+}
+
+/// Builds a voice datagram for listeners without [`Feature::AudioHeader`]: the prefix, the
+/// speaker's big-endian session ID, then the payload as received.
+pub fn voice(session_id: i64, payload: &[u8]) -> Bytes {
+    cut(9 + payload.len(), |buf| {
+        buf.put_u8(VOICE_PACKET_PREFIX);
+        buf.put_i64(session_id);
+        buf.put_slice(payload);
+    })
+}
+
+/// Cuts a voice frame of `len` bytes, as `write` writes it, from the thread's buffers. Used-up
+/// buffers are reused once every frame cut from them is dropped, so forwarding allocates only
+/// while frames are held up in queues for long, and then once per buffer rather than per frame.
+fn cut(len: usize, write: impl FnOnce(&mut BytesMut)) -> Bytes {
+    VOICE_BUFFERS.with_borrow_mut(|buffers| {
+        if buffers.current.capacity() < len {
+            buffers.renew(len);
+        }
+        write(&mut buffers.current);
+        buffers.current.split().freeze()
+    })
+}
+
+/// Builds a COMPRESSED packet from a control packet compressed with raw DEFLATE.
+pub fn compressed(deflated: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(1 + deflated.len());
+    buf.put_u8(PacketType::Compressed as u8);
+    buf.put_slice(deflated);
+    buf.freeze()
+}
+
+/// Builds a USER_LEFT packet, whose payload is the big-endian session ID rather than a message.
+pub fn user_left(session_id: i64) -> Bytes {
+    let mut buf = BytesMut::with_capacity(9);
+    buf.put_u8(PacketType::UserLeft as u8);
+    buf.put_i64(session_id);
+    buf.freeze()
+}
+
+/// Builds an [`system::Error`] packet.
+pub fn error(
+    code: error::Code,
+    detail: impl Into<String>,
+    packet_type: Option<PacketType>,
+) -> Bytes {
+    encode(
+        PacketType::Error,
+        &system::Error {
+            code: code.into(),
+            detail: detail.into(),
+            packet_type: packet_type.map(Into::into),
+            retry_after_ms: 0,
+        },
+    )
+}
+
+/// Builds an [`system::Error`] packet refusing a packet sent too soon, with how long to wait.
+pub fn rate_limited(
+    detail: impl Into<String>,
+    packet_type: Option<PacketType>,
+    retry_after: Duration,
+) -> Bytes {
+    encode(
+        PacketType::Error,
+        &system::Error {
+            code: error::Code::RateLimited.into(),
+            detail: detail.into(),
+            packet_type: packet_type.map(Into::into),
+            retry_after_ms: u32::try_from(retry_after.as_millis()).unwrap_or(u32::MAX),
+        },
+    )
+}
+
+/// Opens a unidirectional stream for control packets, prioritized over other streams.
+pub async fn open_control_stream(
+    connection: &dyn Transport,
+) -> anyhow::Result<Box<dyn SendStream>> {
+    let stream = connection.open_uni().await?;
+    stream.set_priority(CONTROL_STREAM_PRIORITY);
+    Ok(stream)
+}
+
+/// Closes a connection, reporting `code` in the CONNECTION_CLOSE frame.
+pub fn close(connection: &dyn Transport, code: CloseCode) {
+    connection.close(close_code(code), code.as_str_name().as_bytes());
+}
+
+/// The QUIC application error code for a [`CloseCode`].
+pub fn close_code(code: CloseCode) -> VarInt {
+    VarInt::from_u32(code as u32)
+}
+
+/// Negotiates the protocol version and features for a session whose connection currently
+/// carries datagrams of up to `max_datagram_size` bytes.
+pub fn negotiate(
+    hello: &system::Hello,
+    max_datagram_size: usize,
+) -> Result<system::HelloAck, system::HelloError> {
+    let protocol_version = hello.protocol_version.min(PROTOCOL_VERSION);
+
+    if protocol_version < MIN_PROTOCOL_VERSION || protocol_version < hello.min_protocol_version {
+        return Err(system::HelloError {
+            r#type: hello_error::Type::UnsupportedVersion.into(),
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+            max_protocol_version: PROTOCOL_VERSION,
+        });
+    }
+
+    Ok(system::HelloAck {
+        protocol_version,
+        features: hello.features & SUPPORTED_FEATURES,
+        max_datagram_size: max_datagram_size as u32,
+        // Set by the session, which offers the socket.
+        signaling_token: String::new(),
+    })
+}
+
+/// An error decoding a packet.
+#[derive(Debug)]
+pub enum PacketError {
+    /// The packet had no type byte.
+    Empty,
+
+    /// The type byte is not a known [`PacketType`].
+    UnknownType(u8),
+
+    /// The packet type is valid but not one the server accepts.
+    UnexpectedType(PacketType),
+
+    /// The payload is not a valid message for its packet type.
+    Decode(PacketType, prost::DecodeError),
+}
+
+impl PacketError {
+    /// The type of the offending packet, if it could be determined.
+    pub fn packet_type(&self) -> Option<PacketType> {
+        match self {
+            Self::Empty | Self::UnknownType(_) => None,
+            Self::UnexpectedType(packet_type) | Self::Decode(packet_type, _) => Some(*packet_type),
+        }
+    }
+
+    /// The [`error::Code`] reported to the client.
+    pub fn code(&self) -> error::Code {
+        match self {
+            Self::UnexpectedType(_) => error::Code::UnexpectedPacket,
+            Self::Empty | Self::UnknownType(_) | Self::Decode(..) => error::Code::MalformedPacket,
+        }
+    }
+}
+
+impl fmt::Display for PacketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => write!(f, "empty packet"),
+            Self::UnknownType(type_byte) => write!(f, "unknown packet type {type_byte}"),
+            Self::UnexpectedType(packet_type) => {
+                write!(f, "unexpected packet type {}", packet_type.as_str_name())
+            }
+            Self::Decode(packet_type, err) => {
+                write!(f, "malformed {} payload: {err}", packet_type.as_str_name())
+            }
+        }
+    }
+}
+
+impl std::error::Error for PacketError {}
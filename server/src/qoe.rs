@@ -0,0 +1,96 @@
+//! Quality-of-experience scoring and alerting.
+//!
+//! Each session's per-second stats are turned into a MOS-like score from 1 (bad) to 5
+//! (excellent) with a simplified ITU-T G.107 E-model. A monitor task periodically takes the
+//! median score of every room and raises an alert, logged and optionally sent to a webhook,
+//! when it falls below the configured threshold.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use protobuf::system::SessionStats;
+use serde_json::json;
+use tracing::info;
+use tracing::warn;
+
+use crate::http_client;
+use crate::metrics::METRICS;
+use crate::state::ServerState;
+
+/// Scores a session's connection quality, in thousandths of a MOS point.
+pub fn score(stats: &SessionStats) -> i64 {
+    // Jitter counts double since the client's jitter buffer has to absorb it.
+    let latency_ms = stats.rtt_us as f64 / 2000.0 + 2.0 * stats.jitter_us as f64 / 1000.0 + 10.0;
+    let latency_impairment = if latency_ms < 160.0 {
+        latency_ms / 40.0
+    } else {
+        (latency_ms - 120.0) / 10.0
+    };
+    let loss_impairment = stats.loss as f64 * 100.0 * 2.5;
+
+    let r = (93.2 - latency_impairment - loss_impairment).clamp(0.0, 100.0);
+    let mos = 1.0 + 0.035 * r + 7e-6 * r * (r - 60.0) * (100.0 - r);
+    (mos.clamp(1.0, 5.0) * 1000.0) as i64
+}
+
+/// Checks every room's median score on the configured interval, forever.
+pub async fn monitor(state: Arc<ServerState>) {
+    let config = &state.config.qoe;
+    let threshold = (config.alert_threshold * 1000.0) as i64;
+    let mut interval = tokio::time::interval(config.check_interval());
+    let mut alerting = HashSet::new();
+
+    loop {
+        interval.tick().await;
+
+        let mut degraded = HashSet::new();
+        for (room_key, members) in state.registry.rooms() {
+            let mut scores: Vec<i64> = members
+                .into_iter()
+                .filter_map(|session_id| METRICS.session(session_id)?.qoe.get())
+                .collect();
+            if scores.is_empty() {
+                continue;
+            }
+            scores.sort_unstable();
+            let median = scores[scores.len() / 2];
+
+            if median >= threshold {
+                if alerting.contains(&room_key) {
+                    info!(
+                        "Room '{room_key}' QoE recovered to {:.2}",
+                        median as f64 / 1000.0
+                    );
+                }
+                continue;
+            }
+            if !alerting.contains(&room_key) {
+                alert(&state, &room_key, median, scores.len()).await;
+            }
+            degraded.insert(room_key);
+        }
+        alerting = degraded;
+    }
+}
+
+async fn alert(state: &ServerState, room_key: &str, median: i64, sessions: usize) {
+    let score = median as f64 / 1000.0;
+    warn!(
+        "Room '{room_key}' median QoE {score:.2} is below {:.2} ({sessions} sessions)",
+        state.config.qoe.alert_threshold
+    );
+
+    let Some(url) = &state.config.qoe.webhook_url else {
+        return;
+    };
+    let body = json!({
+        "event": "qoe_degraded",
+        "room": room_key,
+        "median_score": score,
+        "threshold": state.config.qoe.alert_threshold,
+        "sessions": sessions,
+    });
+    if let Err(err) = http_client::post(url, &body).await {
+        warn!("Cannot send QoE alert webhook: {err:#}");
+    }
+}
@@ -0,0 +1,414 @@
+//! A QR code encoder, for join links shown in the terminal.
+//!
+//! Only what links need is supported: byte mode at error correction level M, in versions 1 to
+//! 10, which hold up to 213 bytes.
+
+use std::fmt::Write;
+
+/// Per version, from 1: the error correction codewords per block, then the count of blocks
+/// and data codewords per block in each of the two groups.
+const BLOCKS: [(usize, usize, usize, usize, usize); 10] = [
+    (10, 1, 16, 0, 0),
+    (16, 1, 28, 0, 0),
+    (26, 1, 44, 0, 0),
+    (18, 2, 32, 0, 0),
+    (24, 2, 43, 0, 0),
+    (16, 4, 27, 0, 0),
+    (18, 4, 31, 0, 0),
+    (22, 2, 38, 2, 39),
+    (22, 3, 36, 2, 37),
+    (26, 4, 43, 1, 44),
+];
+
+/// Per version, from 1: the rows and columns alignment patterns are centered on.
+const ALIGNMENT: [&[usize]; 10] = [
+    &[],
+    &[6, 18],
+    &[6, 22],
+    &[6, 26],
+    &[6, 30],
+    &[6, 34],
+    &[6, 22, 38],
+    &[6, 24, 42],
+    &[6, 26, 46],
+    &[6, 28, 50],
+];
+
+/// The light modules around the code that scanners need to find it.
+const QUIET_ZONE: usize = 4;
+
+pub struct QrCode {
+    size: usize,
+    modules: Vec<bool>,
+    function: Vec<bool>,
+}
+
+impl QrCode {
+    /// Encodes `data`, or returns `None` if it is too long.
+    pub fn encode(data: &[u8]) -> Option<Self> {
+        let (version, &(ec_len, blocks1, len1, blocks2, len2)) =
+            BLOCKS
+                .iter()
+                .enumerate()
+                .find(|&(version, &(_, b1, l1, b2, l2))| {
+                    header_bits(version + 1) + data.len() * 8 <= (b1 * l1 + b2 * l2) * 8
+                })?;
+        let version = version + 1;
+        let capacity = blocks1 * len1 + blocks2 * len2;
+
+        // Mode, length, data, then a terminator and padding to the capacity.
+        let mut bits = Bits::default();
+        bits.push(0b0100, 4);
+        bits.push(data.len() as u32, header_bits(version) - 4);
+        for &byte in data {
+            bits.push(byte.into(), 8);
+        }
+        bits.push(0, (capacity * 8 - bits.len).min(4));
+        bits.push(0, (8 - bits.len % 8) % 8);
+        let mut codewords = bits.bytes;
+        for pad in [0xEC, 0x11].into_iter().cycle() {
+            if codewords.len() == capacity {
+                break;
+            }
+            codewords.push(pad);
+        }
+
+        // Error correction per block, then the blocks interleaved.
+        let mut blocks = Vec::new();
+        let mut rest = &codewords[..];
+        for len in std::iter::repeat_n(len1, blocks1).chain(std::iter::repeat_n(len2, blocks2)) {
+            let (block, tail) = rest.split_at(len);
+            blocks.push((block, reed_solomon(block, ec_len)));
+            rest = tail;
+        }
+        let mut interleaved = Vec::new();
+        for i in 0..len1.max(len2) {
+            interleaved.extend(blocks.iter().filter_map(|(block, _)| block.get(i)));
+        }
+        for i in 0..ec_len {
+            interleaved.extend(blocks.iter().map(|(_, ec)| ec[i]));
+        }
+
+        let size = version * 4 + 17;
+        let mut code = Self {
+            size,
+            modules: vec![false; size * size],
+            function: vec![false; size * size],
+        };
+        code.draw_function_patterns(version);
+        code.draw_codewords(&interleaved);
+
+        // Use the mask leaving the fewest patterns that confuse scanners.
+        let best = (0..8)
+            .min_by_key(|&mask| {
+                code.apply_mask(mask);
+                code.draw_format(mask);
+                let penalty = code.penalty();
+                code.apply_mask(mask);
+                penalty
+            })
+            .unwrap();
+        code.apply_mask(best);
+        code.draw_format(best);
+        Some(code)
+    }
+
+    /// Renders the code with half blocks, two rows per line, black on white.
+    pub fn render(&self) -> String {
+        let padded = self.size + QUIET_ZONE * 2;
+        let dark = |x: usize, y: usize| {
+            let (x, y) = (x.wrapping_sub(QUIET_ZONE), y.wrapping_sub(QUIET_ZONE));
+            x < self.size && y < self.size && self.get(x, y)
+        };
+        let mut out = String::new();
+        for y in (0..padded).step_by(2) {
+            out.push_str("\x1b[30;47m");
+            for x in 0..padded {
+                out.push(match (dark(x, y), dark(x, y + 1)) {
+                    (true, true) => '█',
+                    (true, false) => '▀',
+                    (false, true) => '▄',
+                    (false, false) => ' ',
+                });
+            }
+            let _ = writeln!(out, "\x1b[0m");
+        }
+        out
+    }
+
+    fn get(&self, x: usize, y: usize) -> bool {
+        self.modules[y * self.size + x]
+    }
+
+    fn set_function(&mut self, x: usize, y: usize, dark: bool) {
+        self.modules[y * self.size + x] = dark;
+        self.function[y * self.size + x] = true;
+    }
+
+    fn draw_function_patterns(&mut self, version: usize) {
+        let size = self.size;
+        for i in 0..size {
+            self.set_function(6, i, i % 2 == 0);
+            self.set_function(i, 6, i % 2 == 0);
+        }
+
+        // Finders in three corners, with their light separators.
+        for (cx, cy) in [(3, 3), (size - 4, 3), (3, size - 4)] {
+            for dy in -4..=4_isize {
+                for dx in -4..=4_isize {
+                    let (x, y) = (cx as isize + dx, cy as isize + dy);
+                    if (0..size as isize).contains(&x) && (0..size as isize).contains(&y) {
+                        let distance = dx.abs().max(dy.abs());
+                        self.set_function(x as usize, y as usize, distance != 2 && distance != 4);
+                    }
+                }
+            }
+        }
+
+        // Alignment patterns everywhere but over the finders.
+        let positions = ALIGNMENT[version - 1];
+        for (i, &cy) in positions.iter().enumerate() {
+            for (j, &cx) in positions.iter().enumerate() {
+                let last = positions.len().wrapping_sub(1);
+                if (i, j) == (0, 0) || (i, j) == (0, last) || (i, j) == (last, 0) {
+                    continue;
+                }
+                for dy in -2..=2_isize {
+                    for dx in -2..=2_isize {
+                        let (x, y) = ((cx as isize + dx) as usize, (cy as isize + dy) as usize);
+                        self.set_function(x, y, dx.abs().max(dy.abs()) != 1);
+                    }
+                }
+            }
+        }
+
+        // Reserve the format areas, drawn once the mask is chosen.
+        self.draw_format(0);
+
+        if version >= 7 {
+            let mut remainder = version as u32;
+            for _ in 0..12 {
+                remainder = (remainder << 1) ^ ((remainder >> 11) * 0x1F25);
+            }
+            let bits = (version as u32) << 12 | remainder;
+            for i in 0..18 {
+                let dark = bits >> i & 1 != 0;
+                let (a, b) = (size - 11 + i % 3, i / 3);
+                self.set_function(a, b, dark);
+                self.set_function(b, a, dark);
+            }
+        }
+    }
+
+    /// Draws the error correction level and mask, with the module always dark beside them.
+    fn draw_format(&mut self, mask: u32) {
+        // Level M is 0b00.
+        let data = mask;
+        let mut remainder = data;
+        for _ in 0..10 {
+            remainder = (remainder << 1) ^ ((remainder >> 9) * 0x537);
+        }
+        let bits = (data << 10 | remainder) ^ 0x5412;
+        let bit = |i: usize| bits >> i & 1 != 0;
+
+        let size = self.size;
+        for i in 0..6 {
+            self.set_function(8, i, bit(i));
+        }
+        self.set_function(8, 7, bit(6));
+        self.set_function(8, 8, bit(7));
+        self.set_function(7, 8, bit(8));
+        for i in 9..15 {
+            self.set_function(14 - i, 8, bit(i));
+        }
+        for i in 0..8 {
+            self.set_function(size - 1 - i, 8, bit(i));
+        }
+        for i in 8..15 {
+            self.set_function(8, size - 15 + i, bit(i));
+        }
+        self.set_function(8, size - 8, true);
+    }
+
+    /// Places the codewords in two-module columns zigzagging up and down from the bottom right.
+    fn draw_codewords(&mut self, codewords: &[u8]) {
+        let size = self.size;
+        let mut i = 0;
+        let mut right = size - 1;
+        while right >= 1 {
+            // The vertical timing pattern is skipped.
+            if right == 6 {
+                right = 5;
+            }
+            for vertical in 0..size {
+                for x in [right, right - 1] {
+                    let upward = (right + 1) & 2 == 0;
+                    let y = if upward {
+                        size - 1 - vertical
+                    } else {
+                        vertical
+                    };
+                    if !self.function[y * size + x] && i < codewords.len() * 8 {
+                        self.modules[y * size + x] = codewords[i / 8] >> (7 - i % 8) & 1 != 0;
+                        i += 1;
+                    }
+                }
+            }
+            if right < 2 {
+                break;
+            }
+            right -= 2;
+        }
+    }
+
+    /// Inverts the data modules `mask` selects, so applying it twice undoes it.
+    fn apply_mask(&mut self, mask: u32) {
+        for y in 0..self.size {
+            for x in 0..self.size {
+                let invert = match mask {
+                    0 => (x + y) % 2 == 0,
+                    1 => y % 2 == 0,
+                    2 => x % 3 == 0,
+                    3 => (x + y) % 3 == 0,
+                    4 => (x / 3 + y / 2) % 2 == 0,
+                    5 => x * y % 2 + x * y % 3 == 0,
+                    6 => (x * y % 2 + x * y % 3) % 2 == 0,
+                    _ => ((x + y) % 2 + x * y % 3) % 2 == 0,
+                };
+                let index = y * self.size + x;
+                if invert && !self.function[index] {
+                    self.modules[index] = !self.modules[index];
+                }
+            }
+        }
+    }
+
+    /// Scores the code by the four penalty rules of the standard.
+    fn penalty(&self) -> usize {
+        let size = self.size;
+        let mut penalty = 0;
+        const FINDER_LIKE: [[bool; 11]; 2] = [
+            [
+                true, false, true, true, true, false, true, false, false, false, false,
+            ],
+            [
+                false, false, false, false, true, false, true, true, true, false, true,
+            ],
+        ];
+        for transpose in [false, true] {
+            let get = |a: usize, b: usize| {
+                if transpose {
+                    self.get(b, a)
+                } else {
+                    self.get(a, b)
+                }
+            };
+            for b in 0..size {
+                // Runs of five or more modules of one color.
+                let mut run = 1;
+                for a in 1..size {
+                    if get(a, b) == get(a - 1, b) {
+                        run += 1;
+                    } else {
+                        run = 1;
+                    }
+                    penalty += match run {
+                        5 => 3,
+                        6.. => 1,
+                        _ => 0,
+                    };
+                }
+                // Patterns looking like finders.
+                for a in 0..size.saturating_sub(10) {
+                    if FINDER_LIKE
+                        .iter()
+                        .any(|pattern| (0..11).all(|i| get(a + i, b) == pattern[i]))
+                    {
+                        penalty += 40;
+                    }
+                }
+            }
+        }
+
+        // Two-by-two blocks of one color.
+        for y in 0..size - 1 {
+            for x in 0..size - 1 {
+                let dark = self.get(x, y);
+                if self.get(x + 1, y) == dark
+                    && self.get(x, y + 1) == dark
+                    && self.get(x + 1, y + 1) == dark
+                {
+                    penalty += 3;
+                }
+            }
+        }
+
+        // How far the proportion of dark modules is from half, in steps of 5%.
+        let dark = self.modules.iter().filter(|&&dark| dark).count();
+        let total = size * size;
+        penalty + (dark * 20).abs_diff(total * 10) / total * 10
+    }
+}
+
+/// The bits before the data: the mode, and the data length, which is longer from version 10.
+fn header_bits(version: usize) -> usize {
+    4 + if version < 10 { 8 } else { 16 }
+}
+
+#[derive(Default)]
+struct Bits {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl Bits {
+    fn push(&mut self, value: u32, count: usize) {
+        for i in (0..count).rev() {
+            if self.len.is_multiple_of(8) {
+                self.bytes.push(0);
+            }
+            if value >> i & 1 != 0 {
+                *self.bytes.last_mut().unwrap() |= 0x80 >> (self.len % 8);
+            }
+            self.len += 1;
+        }
+    }
+}
+
+/// The `len` error correction codewords of `data`, over GF(256) with the polynomial 0x11D.
+fn reed_solomon(data: &[u8], len: usize) -> Vec<u8> {
+    // The generator polynomial, the product of (x - 2^i) for i below `len`, highest term
+    // first and without its leading 1.
+    let mut generator = vec![0u8; len];
+    generator[len - 1] = 1;
+    let mut root = 1;
+    for _ in 0..len {
+        for i in 0..len {
+            generator[i] = multiply(generator[i], root);
+            if i + 1 < len {
+                generator[i] ^= generator[i + 1];
+            }
+        }
+        root = multiply(root, 2);
+    }
+
+    let mut remainder = vec![0u8; len];
+    for &byte in data {
+        let factor = byte ^ remainder[0];
+        remainder.rotate_left(1);
+        remainder[len - 1] = 0;
+        for (remainder, &coefficient) in remainder.iter_mut().zip(&generator) {
+            *remainder ^= multiply(coefficient, factor);
+        }
+    }
+    remainder
+}
+
+fn multiply(x: u8, y: u8) -> u8 {
+    let mut product: u16 = 0;
+    for i in (0..8).rev() {
+        product = (product << 1) ^ ((product >> 7) * 0x11D);
+        product ^= ((y >> i) & 1) as u16 * x as u16;
+    }
+    product as u8
+}
@@ -0,0 +1,59 @@
+//! A token bucket rate limiter, for reactions, new connections and session requests.
+
+use std::time::Instant;
+
+/// A token bucket refusing requests over a rate.
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl RateLimiter {
+    /// Allows `rate` requests per second in bursts of up to `burst`. A zero rate allows
+    /// everything.
+    pub fn new(rate: u32, burst: u32) -> Self {
+        let burst = f64::from(burst.max(1));
+        Self {
+            rate: f64::from(rate),
+            burst,
+            tokens: burst,
+            refilled: Instant::now(),
+        }
+    }
+
+    /// Changes the rate and burst, as after a config reload, keeping the tokens left.
+    pub fn set_limit(&mut self, rate: u32, burst: u32) {
+        self.rate = f64::from(rate);
+        self.burst = f64::from(burst.max(1));
+        self.tokens = self.tokens.min(self.burst);
+    }
+
+    /// Takes a token for a request, returning `false` if none are left.
+    pub fn try_acquire(&mut self) -> bool {
+        if self.rate == 0.0 {
+            return true;
+        }
+
+        let now = Instant::now();
+        self.tokens = self.tokens_at(now);
+        self.refilled = now;
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    /// Whether the bucket is full again, as a new limiter's is.
+    pub fn is_full(&self) -> bool {
+        self.rate == 0.0 || self.tokens_at(Instant::now()) >= self.burst
+    }
+
+    fn tokens_at(&self, now: Instant) -> f64 {
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        (self.tokens + elapsed * self.rate).min(self.burst)
+    }
+}
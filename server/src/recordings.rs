@@ -0,0 +1,286 @@
+//! Recordings of scheduled rooms, kept in the [`BlobStore`] with shared files, so that they are
+//! encrypted at rest whenever `storage.encryption_key` is set.
+//!
+//! A recording is an Ogg FLAC stream, as [`broadcast`](crate::broadcast) streams it, stored in
+//! segments of about [`SEGMENT_LEN`] bytes under `recordings/<id>/<n>`, which make up the stream
+//! one after another. Which recordings there are, and who was in each room while it was
+//! recorded, is kept in the index blob [`INDEX_KEY`], saved after every segment, so a server that
+//! stops while recording loses at most the segment it was filling. Users' data requests list the
+//! recordings a user appears in, serve them decrypted to those allowed to make the request, and
+//! delete them, since a room's mix cannot be split back into its speakers.
+//!
+//! [`BlobStore`]: crate::blobs::BlobStore
+
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+use anyhow::Result;
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+use crate::blobs::BlobStore;
+use crate::tenants::Scoped;
+
+/// How many bytes of a recording are stored at once.
+pub const SEGMENT_LEN: usize = 1 << 20;
+
+/// The blob listing every recording.
+const INDEX_KEY: &str = "recordings/index.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Recording {
+    /// The scheduled room's ID and when the recording started, as `<id>-<started>`.
+    pub id: String,
+
+    /// The room's key, with its tenant.
+    pub room_key: String,
+
+    /// Seconds since the Unix epoch.
+    pub started_at: u64,
+
+    /// How many segments are stored.
+    pub segments: u32,
+
+    /// The names of the users who were in the room while it was recorded.
+    pub usernames: BTreeSet<String>,
+}
+
+impl Recording {
+    pub fn new(room_id: &str, room_key: String, started_at: u64) -> Self {
+        Self {
+            id: format!("{room_id}-{started_at}"),
+            room_key,
+            started_at,
+            segments: 0,
+            usernames: BTreeSet::new(),
+        }
+    }
+
+    /// Whether a user appears in the recording.
+    pub fn includes(&self, user: Scoped) -> bool {
+        user.owns(&self.room_key) && self.usernames.contains(user.name)
+    }
+}
+
+/// The recordings in the blob store.
+pub struct Recordings {
+    blobs: Option<Arc<dyn BlobStore>>,
+
+    /// The index, read from the store when first needed.
+    index: Mutex<Option<Vec<Recording>>>,
+}
+
+impl Recordings {
+    pub fn new(blobs: Option<Arc<dyn BlobStore>>) -> Self {
+        Self {
+            blobs,
+            index: Mutex::default(),
+        }
+    }
+
+    /// Whether there is a store to keep recordings in.
+    pub fn enabled(&self) -> bool {
+        self.blobs.is_some()
+    }
+
+    /// Lists a recording that is starting.
+    pub async fn begin(&self, recording: &Recording) -> Result<()> {
+        let Some(blobs) = &self.blobs else {
+            return Ok(());
+        };
+        let mut index = self.index.lock().await;
+        let recordings = load(blobs.as_ref(), &mut index).await?;
+        recordings.push(recording.clone());
+        save(blobs.as_ref(), recordings).await
+    }
+
+    /// Stores the next segment of a recording, with who has been in its room so far. Returns
+    /// `false` without storing it if the recording was deleted meanwhile, after which it should
+    /// stop.
+    pub async fn store(
+        &self,
+        id: &str,
+        segment: &[u8],
+        usernames: &BTreeSet<String>,
+    ) -> Result<bool> {
+        let Some(blobs) = &self.blobs else {
+            return Ok(false);
+        };
+        // Held while the segment is stored, so it cannot be deleted halfway.
+        let mut index = self.index.lock().await;
+        let recordings = load(blobs.as_ref(), &mut index).await?;
+        let Some(recording) = recordings.iter_mut().find(|recording| recording.id == id) else {
+            return Ok(false);
+        };
+        blobs
+            .put(&segment_key(id, recording.segments), "audio/ogg", segment)
+            .await?;
+        recording.segments += 1;
+        recording.usernames.extend(usernames.iter().cloned());
+        save(blobs.as_ref(), recordings).await?;
+        Ok(true)
+    }
+
+    /// The recordings a user appears in.
+    pub async fn of_user(&self, user: Scoped<'_>) -> Result<Vec<Recording>> {
+        let Some(blobs) = &self.blobs else {
+            return Ok(Vec::new());
+        };
+        let mut index = self.index.lock().await;
+        let recordings = load(blobs.as_ref(), &mut index).await?;
+        Ok(recordings
+            .iter()
+            .filter(|recording| recording.includes(user))
+            .cloned()
+            .collect())
+    }
+
+    /// A segment of a recording, decrypted, or `None` if there is no such segment.
+    pub async fn segment(&self, id: &str, segment: u32) -> Result<Option<Vec<u8>>> {
+        match &self.blobs {
+            Some(blobs) => blobs.get(&segment_key(id, segment)).await,
+            None => Ok(None),
+        }
+    }
+
+    /// Deletes the recordings a user appears in, returning how many there were.
+    pub async fn erase_user(&self, user: Scoped<'_>) -> Result<usize> {
+        let Some(blobs) = &self.blobs else {
+            return Ok(0);
+        };
+        let mut index = self.index.lock().await;
+        let recordings = load(blobs.as_ref(), &mut index).await?;
+        let erased: Vec<Recording> = recordings
+            .extract_if(.., |recording| recording.includes(user))
+            .collect();
+        if erased.is_empty() {
+            return Ok(0);
+        }
+
+        // Unlisted first, so a failure part way leaves stray segments rather than a listed
+        // recording with holes in it.
+        save(blobs.as_ref(), recordings).await?;
+        for recording in &erased {
+            for segment in 0..recording.segments {
+                blobs.delete(&segment_key(&recording.id, segment)).await?;
+            }
+        }
+        Ok(erased.len())
+    }
+}
+
+fn segment_key(id: &str, segment: u32) -> String {
+    format!("recordings/{id}/{segment}")
+}
+
+/// The index, read from the store unless it already was.
+async fn load<'a>(
+    blobs: &dyn BlobStore,
+    index: &'a mut Option<Vec<Recording>>,
+) -> Result<&'a mut Vec<Recording>> {
+    if index.is_none() {
+        let recordings = match blobs.get(INDEX_KEY).await? {
+            Some(json) => serde_json::from_slice(&json)?,
+            None => Vec::new(),
+        };
+        *index = Some(recordings);
+    }
+    Ok(index.as_mut().unwrap())
+}
+
+async fn save(blobs: &dyn BlobStore, recordings: &[Recording]) -> Result<()> {
+    let json = serde_json::to_vec(recordings)?;
+    blobs.put(INDEX_KEY, "application/json", &json).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+
+    use anyhow::Result;
+
+    use super::Recording;
+    use super::Recordings;
+    use crate::blobs::BlobStore;
+    use crate::store::BoxFuture;
+    use crate::tenants::Scoped;
+
+    #[derive(Default)]
+    struct MemoryBlobs(Mutex<HashMap<String, Vec<u8>>>);
+
+    impl BlobStore for MemoryBlobs {
+        fn put<'a>(
+            &'a self,
+            key: &'a str,
+            _content_type: &'a str,
+            data: &'a [u8],
+        ) -> BoxFuture<'a, Result<()>> {
+            self.0.lock().unwrap().insert(key.to_owned(), data.to_vec());
+            Box::pin(async { Ok(()) })
+        }
+
+        fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Vec<u8>>>> {
+            let data = self.0.lock().unwrap().get(key).cloned();
+            Box::pin(async { Ok(data) })
+        }
+
+        fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<()>> {
+            self.0.lock().unwrap().remove(key);
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    fn user(name: &str) -> Scoped<'_> {
+        Scoped { tenant: None, name }
+    }
+
+    #[tokio::test]
+    async fn users_get_and_delete_the_recordings_they_are_in() {
+        let blobs = Arc::new(MemoryBlobs::default());
+        let recordings = Recordings::new(Some(blobs.clone()));
+        let recording = Recording::new("standup", "lobby".into(), 1000);
+        recordings.begin(&recording).await.unwrap();
+
+        let alice = BTreeSet::from(["alice".to_owned()]);
+        let bob = BTreeSet::from(["bob".to_owned()]);
+        assert!(
+            recordings
+                .store(&recording.id, b"one", &alice)
+                .await
+                .unwrap()
+        );
+        assert!(recordings.store(&recording.id, b"two", &bob).await.unwrap());
+
+        let listed = recordings.of_user(user("bob")).await.unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].segments, 2);
+        assert!(recordings.of_user(user("carol")).await.unwrap().is_empty());
+        let segment = recordings.segment(&recording.id, 1).await.unwrap();
+        assert_eq!(segment.as_deref(), Some(&b"two"[..]));
+
+        assert_eq!(recordings.erase_user(user("alice")).await.unwrap(), 1);
+        assert!(recordings.of_user(user("bob")).await.unwrap().is_empty());
+        assert!(
+            recordings
+                .segment(&recording.id, 0)
+                .await
+                .unwrap()
+                .is_none()
+        );
+        // The recorder stops once its recording is gone.
+        assert!(
+            !recordings
+                .store(&recording.id, b"three", &bob)
+                .await
+                .unwrap()
+        );
+
+        // The index is kept in the store, so the deletion outlives the server.
+        let reopened = Recordings::new(Some(blobs));
+        assert!(reopened.of_user(user("bob")).await.unwrap().is_empty());
+    }
+}
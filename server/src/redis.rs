@@ -0,0 +1,174 @@
+//! A minimal Redis client speaking RESP2 over TCP.
+//!
+//! Only what the [`RedisStore`](crate::store::RedisStore) needs is supported: sending commands,
+//! reading their replies and reading pub/sub messages. URLs have the form
+//! `redis://[:password@]host[:port][/db]`; TLS is not supported.
+
+use std::fmt;
+
+use anyhow::Context;
+use anyhow::Result;
+use anyhow::bail;
+use tokio::io::AsyncBufReadExt;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::io::BufReader;
+use tokio::net::TcpStream;
+
+/// The largest bulk string or array accepted in a reply.
+const MAX_REPLY_LEN: usize = 16 * 1024 * 1024;
+
+/// A reply from the server. Error replies are returned as errors instead.
+#[derive(Debug)]
+pub enum Value {
+    Nil,
+    Integer(i64),
+    Status(String),
+    Bulk(Vec<u8>),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    /// The reply as an array of bulk strings, treating nil as empty.
+    pub fn into_bulks(self) -> Result<Vec<Vec<u8>>> {
+        match self {
+            Self::Nil => Ok(Vec::new()),
+            Self::Array(values) => values
+                .into_iter()
+                .map(|value| match value {
+                    Self::Bulk(bytes) => Ok(bytes),
+                    other => bail!("expected a bulk string, got {other}"),
+                })
+                .collect(),
+            other => bail!("expected an array, got {other}"),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Nil => write!(f, "nil"),
+            Self::Integer(n) => write!(f, "{n}"),
+            Self::Status(status) => write!(f, "{status}"),
+            Self::Bulk(bytes) => write!(f, "{:?}", String::from_utf8_lossy(bytes)),
+            Self::Array(values) => write!(f, "an array of {}", values.len()),
+        }
+    }
+}
+
+pub struct Connection {
+    stream: BufReader<TcpStream>,
+}
+
+impl Connection {
+    /// Connects to the server at `url`, authenticating and selecting the database it names.
+    pub async fn connect(url: &str) -> Result<Self> {
+        let rest = url
+            .strip_prefix("redis://")
+            .context("Redis URL must start with redis://")?;
+        let (password, rest) = match rest.rsplit_once('@') {
+            Some((credentials, rest)) => (Some(credentials.trim_start_matches(':')), rest),
+            None => (None, rest),
+        };
+        let (authority, db) = match rest.split_once('/') {
+            Some((authority, db)) => (authority, Some(db).filter(|db| !db.is_empty())),
+            None => (rest, None),
+        };
+        let address = if authority.contains(':') {
+            authority.to_owned()
+        } else {
+            format!("{authority}:6379")
+        };
+
+        let stream = TcpStream::connect(&address)
+            .await
+            .with_context(|| format!("cannot connect to Redis at {address}"))?;
+        let mut connection = Self {
+            stream: BufReader::new(stream),
+        };
+
+        if let Some(password) = password {
+            connection.command(&[b"AUTH", password.as_bytes()]).await?;
+        }
+        if let Some(db) = db {
+            connection.command(&[b"SELECT", db.as_bytes()]).await?;
+        }
+
+        Ok(connection)
+    }
+
+    /// Sends a command and waits for its reply.
+    pub async fn command(&mut self, args: &[&[u8]]) -> Result<Value> {
+        self.send(args).await?;
+        self.read().await
+    }
+
+    /// Sends a command without waiting for a reply, as when subscribing.
+    pub async fn send(&mut self, args: &[&[u8]]) -> Result<()> {
+        let mut request = format!("*{}\r\n", args.len()).into_bytes();
+        for arg in args {
+            request.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+            request.extend_from_slice(arg);
+            request.extend_from_slice(b"\r\n");
+        }
+        self.stream.get_mut().write_all(&request).await?;
+        Ok(())
+    }
+
+    /// Reads the next reply or pub/sub message.
+    pub async fn read(&mut self) -> Result<Value> {
+        let line = self.read_line().await?;
+        let (kind, rest) = line.split_at_checked(1).unwrap_or_default();
+        match kind {
+            "+" => Ok(Value::Status(rest.to_owned())),
+            "-" => bail!("Redis error: {rest}"),
+            ":" => Ok(Value::Integer(
+                rest.parse().context("malformed integer reply")?,
+            )),
+            "$" => {
+                let Some(len) = Self::parse_len(rest)? else {
+                    return Ok(Value::Nil);
+                };
+                let mut bytes = vec![0; len + 2];
+                self.stream.read_exact(&mut bytes).await?;
+                bytes.truncate(len);
+                Ok(Value::Bulk(bytes))
+            }
+            "*" => {
+                let Some(len) = Self::parse_len(rest)? else {
+                    return Ok(Value::Nil);
+                };
+                let mut values = Vec::with_capacity(len.min(1024));
+                for _ in 0..len {
+                    values.push(Box::pin(self.read()).await?);
+                }
+                Ok(Value::Array(values))
+            }
+            _ => bail!("malformed Redis reply: {line:?}"),
+        }
+    }
+
+    async fn read_line(&mut self) -> Result<String> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line).await? == 0 {
+            bail!("Redis closed the connection");
+        }
+        match line.strip_suffix("\r\n") {
+            Some(line) if !line.is_empty() => Ok(line.to_owned()),
+            _ => bail!("malformed Redis reply: {line:?}"),
+        }
+    }
+
+    /// Parses a bulk string or array length, where -1 means nil.
+    fn parse_len(len: &str) -> Result<Option<usize>> {
+        if len == "-1" {
+            return Ok(None);
+        }
+        let len: usize = len.parse().context("malformed reply length")?;
+        if len > MAX_REPLY_LEN {
+            bail!("Redis reply of {len} elements is too large");
+        }
+        Ok(Some(len))
+    }
+}
@@ -0,0 +1,126 @@
+//! Hot config reload: the config file is read again on SIGHUP or the admin API's
+//! `ReloadConfig`, and its reloadable settings applied while sessions stay connected.
+//!
+//! The reloadable settings are `log`, `http.cors_origins`, `session.max_room_members`,
+//! `session.slow_consumer_secs` and `slow_consumer_recovery_secs`, and the `session` rate limits:
+//! `reaction_rate` and `reaction_burst` apply at once, `max_user_kbps`, `max_room_kbps` and
+//! `bitrate_grace_secs` within a second, while `pacing_rate` and `pacing_burst` apply to
+//! sessions that connect afterwards. `chaos`, and `accept` but for `max_pending_handshakes` and
+//! `handshake_timeout_secs`, apply at once. Changes to any other setting are logged and wait for
+//! a restart.
+
+use std::sync::Arc;
+use std::sync::OnceLock;
+
+use anyhow::Context;
+use anyhow::Result;
+use tokio::signal::unix::SignalKind;
+use tokio::signal::unix::signal;
+use tracing::info;
+use tracing::warn;
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::Registry;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::reload::Handle;
+
+use crate::chaos;
+use crate::config::Config;
+use crate::log_sampling;
+use crate::secrets;
+use crate::state::ServerState;
+use crate::tenants;
+
+/// Swaps the filter deciding which logs are written.
+static LOG_FILTER: OnceLock<Handle<EnvFilter, Registry>> = OnceLock::new();
+
+/// The filter for `log.level`, or for `RUST_LOG` without one.
+pub fn log_filter(level: Option<&str>) -> Result<EnvFilter> {
+    let builder = EnvFilter::builder().with_default_directive(LevelFilter::INFO.into());
+    match level {
+        Some(level) => builder
+            .parse(level)
+            .with_context(|| format!("Invalid log.level '{level}'")),
+        None => Ok(builder.from_env_lossy()),
+    }
+}
+
+/// Lets [`apply_log_level`] replace the filter logging was set up with.
+pub fn set_log_filter_handle(handle: Handle<EnvFilter, Registry>) {
+    let _ = LOG_FILTER.set(handle);
+}
+
+/// Writes logs from now on as `config.log.level` says, sampling frequent events as
+/// `config.log.sample_rates` does.
+pub fn apply_log_level(config: &Config) -> Result<()> {
+    let filter = log_filter(config.log.level.as_deref())?;
+    log_sampling::configure(&config.log);
+    if let Some(handle) = LOG_FILTER.get() {
+        handle
+            .reload(filter)
+            .context("Cannot replace the log filter")?;
+    }
+    Ok(())
+}
+
+/// Reloads the config file on every SIGHUP, forever.
+pub async fn on_hangup(state: Arc<ServerState>) {
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(err) => {
+            warn!("Cannot listen for SIGHUP: {err}");
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        if let Err(err) = reload(&state).await {
+            warn!("Cannot reload the config: {err:#}");
+        }
+    }
+}
+
+/// Reads the config file again and applies its reloadable settings. Returns whether it also
+/// changed settings that only apply after a restart.
+pub async fn reload(state: &ServerState) -> Result<bool> {
+    let mut config = Config::load()?;
+    secrets::resolve(&mut config).await?;
+    tenants::validate(&config)?;
+    chaos::validate(&config)?;
+    apply_log_level(&config)?;
+
+    let mut live = (*state.live_config()).clone();
+    take_reloadable(&mut live, &config);
+    let restart_required = serde_json::to_value(&live)? != serde_json::to_value(&config)?;
+    state.set_live_config(live);
+
+    if restart_required {
+        warn!("Reloaded the config; changes to settings that are not reloadable need a restart");
+    } else {
+        info!("Reloaded the config");
+    }
+    Ok(restart_required)
+}
+
+/// Copies the reloadable settings of `from` into `into`.
+fn take_reloadable(into: &mut Config, from: &Config) {
+    into.log = from.log.clone();
+    into.http = from.http.clone();
+    into.session.max_room_members = from.session.max_room_members;
+    into.session.slow_consumer_secs = from.session.slow_consumer_secs;
+    into.session.slow_consumer_recovery_secs = from.session.slow_consumer_recovery_secs;
+    into.session.reaction_rate = from.session.reaction_rate;
+    into.session.reaction_burst = from.session.reaction_burst;
+    into.session.max_user_kbps = from.session.max_user_kbps;
+    into.session.max_room_kbps = from.session.max_room_kbps;
+    into.session.bitrate_grace_secs = from.session.bitrate_grace_secs;
+    into.session.pacing_rate = from.session.pacing_rate;
+    into.session.pacing_burst = from.session.pacing_burst;
+    into.accept.allowed_authorities = from.accept.allowed_authorities.clone();
+    into.accept.allowed_paths = from.accept.allowed_paths.clone();
+    into.accept.rate = from.accept.rate;
+    into.accept.burst = from.accept.burst;
+    into.accept.session_rate = from.accept.session_rate;
+    into.accept.session_burst = from.accept.session_burst;
+    into.accept.max_session_clients = from.accept.max_session_clients;
+    into.accept.max_session_tokens = from.accept.max_session_tokens;
+    into.chaos = from.chaos.clone();
+}
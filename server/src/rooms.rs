@@ -0,0 +1,912 @@
+//! Registry of authenticated sessions and the rooms they are in.
+//!
+//! Sessions, usernames and rooms live in separate maps, each split into shards selected by
+//! hashing the key, so a broadcast in one room never waits on a join in another. Sessions, rooms,
+//! joins and leaves are published to the registry's [`Events`] as they come and go.
+//!
+//! Each member can block other users by username. The registry leaves their voice and chat out
+//! of what it hands out for the member, and refuses direct messages between them, so blocks hold
+//! whatever the clients do.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::hash::BuildHasher;
+use std::hash::Hash;
+use std::hash::RandomState;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::Instant;
+
+use bytes::Bytes;
+use protobuf::system::CloseCode;
+use protobuf::system::RoomUser;
+use tokio::sync::Notify;
+use tokio::sync::mpsc;
+use tracing::info;
+
+use crate::bitrate::ByteBucket;
+use crate::events::Event;
+use crate::events::Events;
+use crate::outbox::Outbox;
+use crate::protocol;
+use crate::protocol::VoiceFrame;
+use crate::protocol::VoiceHeader;
+use crate::tenants;
+use crate::transport::Transport;
+
+/// Identifies an authenticated session.
+pub type SessionId = i64;
+
+/// The longest accepted username, in characters.
+pub const MAX_USERNAME_LEN: usize = 32;
+
+/// The longest accepted room key, in characters.
+pub const MAX_ROOM_KEY_LEN: usize = 64;
+
+/// The number of shards in each map.
+const SHARDS: usize = 64;
+
+/// Moves a session can have waiting before further ones are refused.
+const MAX_PENDING_MOVES: usize = 4;
+
+pub struct Registry {
+    sessions: Sharded<HashMap<SessionId, Member>>,
+    usernames: Sharded<HashSet<String>>,
+    rooms: Sharded<HashMap<String, Room>>,
+    events: Events,
+}
+
+#[derive(Clone)]
+struct Member {
+    username: String,
+    tenant: Option<Arc<str>>,
+    outbox: Outbox,
+    link: Link,
+
+    /// Set while an operator keeps the member's voice from its room.
+    muted: Arc<AtomicBool>,
+
+    /// Where to send the rooms the member is moved to, if it can be moved.
+    moves: Option<mpsc::Sender<Arc<str>>>,
+
+    /// Whether the member's client takes direct messages.
+    direct_messages: bool,
+
+    /// The usernames the member blocks.
+    blocked: Arc<Mutex<HashSet<String>>>,
+
+    /// Whether the member is a moderator whose client is told who waits to join its room.
+    moderates: bool,
+}
+
+/// How a member is connected to the server, so it can be disconnected.
+#[derive(Clone)]
+pub enum Link {
+    WebTransport(Arc<dyn Transport>),
+
+    /// A participant bridged from another network, such as a phone call, which hangs up when
+    /// notified.
+    Bridged(Arc<Notify>),
+}
+
+#[derive(Default)]
+struct Room {
+    members: HashMap<SessionId, Member>,
+
+    /// Meters the voice the room's speakers send together against `session.max_room_kbps`.
+    audio: ByteBucket,
+
+    /// Kept open without members, by [`Registry::hold`].
+    held: bool,
+
+    /// How long each user waits between chat messages, while the room is in slow mode.
+    slow_mode: Option<Duration>,
+
+    /// When each user, by their key, last sent a chat message, while the room is in slow mode.
+    last_chat: HashMap<String, Instant>,
+}
+
+impl Member {
+    /// The username's key in [`Registry::usernames`], unique across tenants.
+    fn key(&self) -> String {
+        tenants::key(self.tenant.as_deref(), &self.username)
+    }
+
+    fn blocks(&self, username: &str) -> bool {
+        self.blocked.lock().unwrap().contains(username)
+    }
+
+    fn room_user(&self, session_id: SessionId) -> RoomUser {
+        RoomUser {
+            session_id,
+            username: self.username.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+/// A map split into independently locked shards.
+struct Sharded<T> {
+    shards: Box<[Mutex<T>]>,
+    hasher: RandomState,
+}
+
+impl<T: Default> Sharded<T> {
+    fn new() -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| Mutex::default()).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn shard(&self, key: &(impl Hash + ?Sized)) -> &Mutex<T> {
+        let index = self.hasher.hash_one(key) as usize % self.shards.len();
+        &self.shards[index]
+    }
+}
+
+/// A member of a room, as listed by [`Registry::room_roster`].
+pub struct RosterEntry {
+    pub session_id: SessionId,
+    pub username: String,
+    pub tenant: Option<Arc<str>>,
+    pub muted: bool,
+    pub bridged: bool,
+    pub outbox: Outbox,
+}
+
+/// A member of a room the room's mix is sent to, as listed by [`Registry::room_listeners`].
+pub struct Listener {
+    pub session_id: SessionId,
+    pub outbox: Outbox,
+
+    /// The speakers the member blocks.
+    pub blocked: Vec<SessionId>,
+}
+
+/// The result of a successful join.
+pub struct Joined {
+    /// The users already in the room.
+    pub users: Vec<RoomUser>,
+
+    /// The outboxes of the users already in the room.
+    pub peers: Vec<Outbox>,
+
+    /// The joining user.
+    pub user: RoomUser,
+}
+
+#[derive(Debug)]
+pub enum RegisterError {
+    /// The username is empty or too long.
+    InvalidUsername,
+
+    /// Another session is already using the username.
+    UsernameTaken,
+}
+
+#[derive(Debug)]
+pub enum DirectError {
+    /// There is no such session, in the sender's tenant on servers with tenants.
+    Offline,
+
+    /// The recipient's client takes no direct messages.
+    NotAccepted,
+
+    /// The sender or the recipient blocks the other.
+    Blocked,
+}
+
+#[derive(Debug)]
+pub enum JoinError {
+    /// The room key is empty or too long.
+    InvalidRoomKey,
+
+    /// The session is already in a room.
+    AlreadyInRoom,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self {
+            sessions: Sharded::new(),
+            usernames: Sharded::new(),
+            rooms: Sharded::new(),
+            events: Events::default(),
+        }
+    }
+}
+
+impl Registry {
+    /// Registers an authenticated session until the returned [`Registration`] is dropped. On
+    /// servers with tenants, usernames need only be unique within a tenant.
+    pub fn register(
+        self: &Arc<Self>,
+        session_id: SessionId,
+        tenant: Option<&str>,
+        username: &str,
+        outbox: Outbox,
+        link: Link,
+    ) -> Result<Registration, RegisterError> {
+        if username.is_empty() || username.chars().count() > MAX_USERNAME_LEN {
+            return Err(RegisterError::InvalidUsername);
+        }
+
+        let member = Member {
+            username: username.to_owned(),
+            tenant: tenant.map(Arc::from),
+            outbox,
+            link,
+            muted: Arc::default(),
+            moves: None,
+            direct_messages: false,
+            blocked: Arc::default(),
+            moderates: false,
+        };
+        let key = member.key();
+        let inserted = self.usernames.shard(&key).lock().unwrap().insert(key);
+        if !inserted {
+            return Err(RegisterError::UsernameTaken);
+        }
+
+        let tenant = member.tenant.clone();
+        let muted = member.muted.clone();
+        let blocked = member.blocked.clone();
+        self.sessions
+            .shard(&session_id)
+            .lock()
+            .unwrap()
+            .insert(session_id, member);
+
+        self.events.publish(Event::SessionStarted {
+            user: RoomUser {
+                session_id,
+                username: username.to_owned(),
+                ..Default::default()
+            },
+        });
+        Ok(Registration {
+            registry: self.clone(),
+            session_id,
+            username: username.into(),
+            tenant,
+            room_key: None,
+            room_id: 0,
+            voice_sequence: AtomicU32::new(0),
+            muted,
+            blocked,
+        })
+    }
+
+    /// The joins, leaves and chat messages in every room.
+    pub fn events(&self) -> &Events {
+        &self.events
+    }
+
+    /// The open room with `room_key`, opening it if necessary.
+    fn open<'a>(&self, rooms: &'a mut HashMap<String, Room>, room_key: &str) -> &'a mut Room {
+        rooms.entry(room_key.to_owned()).or_insert_with(|| {
+            self.events.publish(Event::RoomOpened {
+                room_key: room_key.into(),
+            });
+            Room::default()
+        })
+    }
+
+    /// Opens a room if it is not open, and keeps it open without members until
+    /// [`release`](Self::release)d.
+    pub fn hold(&self, room_key: &str) {
+        let mut rooms = self.rooms.shard(room_key).lock().unwrap();
+        self.open(&mut rooms, room_key).held = true;
+    }
+
+    /// Lets a held room close once it has no members, closing it now if it has none.
+    pub fn release(&self, room_key: &str) {
+        let mut rooms = self.rooms.shard(room_key).lock().unwrap();
+        let Some(room) = rooms.get_mut(room_key) else {
+            return;
+        };
+        room.held = false;
+        if room.members.is_empty() {
+            rooms.remove(room_key);
+            self.events.publish(Event::RoomClosed {
+                room_key: room_key.into(),
+            });
+        }
+    }
+
+    fn join(&self, session_id: SessionId, room_key: &str) -> Joined {
+        let member = self.sessions.shard(&session_id).lock().unwrap()[&session_id].clone();
+        let user = member.room_user(session_id);
+
+        let mut rooms = self.rooms.shard(room_key).lock().unwrap();
+        let room = self.open(&mut rooms, room_key);
+
+        let mut users = Vec::with_capacity(room.members.len());
+        let mut peers = Vec::with_capacity(room.members.len());
+        for (peer_id, peer) in &room.members {
+            users.push(peer.room_user(*peer_id));
+            peers.push(peer.outbox.clone());
+        }
+        room.members.insert(session_id, member);
+
+        // Published under the lock, so subscribers see a room's events in order.
+        self.events.publish(Event::Joined {
+            room_key: room_key.into(),
+            user: user.clone(),
+        });
+        Joined { users, peers, user }
+    }
+
+    fn leave(&self, user: RoomUser, room_key: Arc<str>) -> Vec<Outbox> {
+        let mut rooms = self.rooms.shard(&*room_key).lock().unwrap();
+        let Some(room) = rooms.get_mut(&*room_key) else {
+            return Vec::new();
+        };
+
+        room.members.remove(&user.session_id);
+        let closed = room.members.is_empty() && !room.held;
+        self.events.publish(Event::Left {
+            room_key: room_key.clone(),
+            user,
+        });
+        if closed {
+            rooms.remove(&*room_key);
+            self.events.publish(Event::RoomClosed { room_key });
+            return Vec::new();
+        }
+
+        room.members
+            .values()
+            .map(|peer| peer.outbox.clone())
+            .collect()
+    }
+
+    /// Meters a voice frame of `bytes` bytes sent in a room, returning `false` if it is over the
+    /// room's cap of `max_kbps`.
+    fn admit_audio(&self, room_key: &str, bytes: usize, max_kbps: u32) -> bool {
+        let mut rooms = self.rooms.shard(room_key).lock().unwrap();
+        rooms
+            .get_mut(room_key)
+            .is_none_or(|room| room.audio.try_take(max_kbps, bytes))
+    }
+
+    fn for_each_peer(
+        &self,
+        session_id: SessionId,
+        room_key: &str,
+        mut f: impl FnMut(&Outbox),
+    ) -> usize {
+        let rooms = self.rooms.shard(room_key).lock().unwrap();
+        let Some(room) = rooms.get(room_key) else {
+            return 0;
+        };
+        let Some(speaker) = room.members.get(&session_id) else {
+            return 0;
+        };
+
+        let mut peers = 0;
+        for (peer_id, peer) in &room.members {
+            if *peer_id != session_id && !peer.blocks(&speaker.username) {
+                f(&peer.outbox);
+                peers += 1;
+            }
+        }
+        peers
+    }
+
+    /// The outboxes of the members of a room who do not block `sender`, for its chat messages.
+    pub fn room_audience(&self, room_key: &str, sender: &str) -> Vec<Outbox> {
+        let rooms = self.rooms.shard(room_key).lock().unwrap();
+        let Some(room) = rooms.get(room_key) else {
+            return Vec::new();
+        };
+
+        room.members
+            .values()
+            .filter(|member| !member.blocks(sender))
+            .map(|member| member.outbox.clone())
+            .collect()
+    }
+
+    /// The outboxes of the moderators in a room, for who waits to join it.
+    pub fn room_moderators(&self, room_key: &str) -> Vec<Outbox> {
+        let rooms = self.rooms.shard(room_key).lock().unwrap();
+        let Some(room) = rooms.get(room_key) else {
+            return Vec::new();
+        };
+
+        room.members
+            .values()
+            .filter(|member| member.moderates)
+            .map(|member| member.outbox.clone())
+            .collect()
+    }
+
+    /// Every member of a room, with which of `speakers` they block, for the room's mix.
+    pub fn room_listeners(&self, room_key: &str, speakers: &[SessionId]) -> Vec<Listener> {
+        let rooms = self.rooms.shard(room_key).lock().unwrap();
+        let Some(room) = rooms.get(room_key) else {
+            return Vec::new();
+        };
+
+        let speakers: Vec<(SessionId, &str)> = speakers
+            .iter()
+            .filter_map(|id| Some((*id, room.members.get(id)?.username.as_str())))
+            .collect();
+        room.members
+            .iter()
+            .map(|(session_id, member)| Listener {
+                session_id: *session_id,
+                outbox: member.outbox.clone(),
+                blocked: speakers
+                    .iter()
+                    .filter(|(_, username)| member.blocks(username))
+                    .map(|(speaker, _)| *speaker)
+                    .collect(),
+            })
+            .collect()
+    }
+
+    /// Puts a room in slow mode, where each user may send a chat message every `interval`, or
+    /// takes it out of it with `None`. Returns `false` if the room is not open.
+    pub fn set_slow_mode(&self, room_key: &str, interval: Option<Duration>) -> bool {
+        let mut rooms = self.rooms.shard(room_key).lock().unwrap();
+        let Some(room) = rooms.get_mut(room_key) else {
+            return false;
+        };
+        info!("Setting slow mode in room '{room_key}' to {interval:?}");
+        room.slow_mode = interval;
+        room.last_chat.clear();
+        true
+    }
+
+    /// How long each user waits between chat messages in a room, if it is in slow mode.
+    pub fn slow_mode(&self, room_key: &str) -> Option<Duration> {
+        let rooms = self.rooms.shard(room_key).lock().unwrap();
+        rooms.get(room_key).and_then(|room| room.slow_mode)
+    }
+
+    /// Counts a chat message a user, by their key, sends in a room, unless the room is in slow
+    /// mode and they sent one too recently, in which case it returns how long they have left to
+    /// wait.
+    pub fn count_chat(&self, room_key: &str, user_key: &str) -> Result<(), Duration> {
+        let mut rooms = self.rooms.shard(room_key).lock().unwrap();
+        let Some(room) = rooms.get_mut(room_key) else {
+            return Ok(());
+        };
+        let Some(interval) = room.slow_mode else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        if let Some(last) = room.last_chat.get(user_key) {
+            let waited = now.duration_since(*last);
+            if waited < interval {
+                return Err(interval - waited);
+            }
+        }
+        room.last_chat
+            .retain(|_, last| now.duration_since(*last) < interval);
+        room.last_chat.insert(user_key.to_owned(), now);
+        Ok(())
+    }
+
+    /// Calls `f` with the outbox of each member of a room, returning how many there are, as
+    /// [`Registration::for_each_peer`] does for a speaker's peers.
+    pub fn for_each_member(&self, room_key: &str, mut f: impl FnMut(&Outbox)) -> usize {
+        let rooms = self.rooms.shard(room_key).lock().unwrap();
+        let Some(room) = rooms.get(room_key) else {
+            return 0;
+        };
+
+        room.members.values().for_each(|member| f(&member.outbox));
+        room.members.len()
+    }
+
+    /// Every member of a room, with their outboxes.
+    pub fn room_members(&self, room_key: &str) -> Vec<(SessionId, Outbox)> {
+        let rooms = self.rooms.shard(room_key).lock().unwrap();
+        let Some(room) = rooms.get(room_key) else {
+            return Vec::new();
+        };
+
+        room.members
+            .iter()
+            .map(|(session_id, member)| (*session_id, member.outbox.clone()))
+            .collect()
+    }
+
+    /// Every member of a room, with what is known about them.
+    pub fn room_roster(&self, room_key: &str) -> Vec<RosterEntry> {
+        let rooms = self.rooms.shard(room_key).lock().unwrap();
+        let Some(room) = rooms.get(room_key) else {
+            return Vec::new();
+        };
+
+        room.members
+            .iter()
+            .map(|(session_id, member)| RosterEntry {
+                session_id: *session_id,
+                username: member.username.clone(),
+                tenant: member.tenant.clone(),
+                muted: member.muted.load(Ordering::Relaxed),
+                bridged: matches!(member.link, Link::Bridged(_)),
+                outbox: member.outbox.clone(),
+            })
+            .collect()
+    }
+
+    /// Every room, with the sessions in it.
+    pub fn rooms(&self) -> Vec<(String, Vec<SessionId>)> {
+        let mut rooms = Vec::new();
+        for shard in self.rooms.shards.iter() {
+            let shard = shard.lock().unwrap();
+            rooms.extend(shard.iter().map(|(room_key, room)| {
+                (room_key.clone(), room.members.keys().copied().collect())
+            }));
+        }
+        rooms
+    }
+
+    /// Every registered session, with its username as keyed with its tenant.
+    pub fn sessions(&self) -> Vec<(SessionId, String)> {
+        let mut sessions = Vec::new();
+        for shard in self.sessions.shards.iter() {
+            let shard = shard.lock().unwrap();
+            sessions.extend(
+                shard
+                    .iter()
+                    .map(|(session_id, member)| (*session_id, member.key())),
+            );
+        }
+        sessions
+    }
+
+    /// The outbox of every session with a client, rather than bridged, with the key of its user.
+    pub fn client_outboxes(&self) -> Vec<(String, Outbox)> {
+        let mut outboxes = Vec::new();
+        for shard in self.sessions.shards.iter() {
+            let shard = shard.lock().unwrap();
+            outboxes.extend(
+                shard
+                    .values()
+                    .filter(|member| matches!(member.link, Link::WebTransport(_)))
+                    .map(|member| (member.key(), member.outbox.clone())),
+            );
+        }
+        outboxes
+    }
+
+    /// How many entries each of the registry's maps holds, to catch sessions that were never
+    /// cleaned up.
+    pub fn sizes(&self) -> [(&'static str, usize); 3] {
+        fn len<T>(map: &Sharded<T>, len: impl Fn(&T) -> usize) -> usize {
+            map.shards
+                .iter()
+                .map(|shard| len(&shard.lock().unwrap()))
+                .sum()
+        }
+        [
+            ("sessions", len(&self.sessions, HashMap::len)),
+            ("usernames", len(&self.usernames, HashSet::len)),
+            ("rooms", len(&self.rooms, HashMap::len)),
+        ]
+    }
+
+    /// Disconnects a session with [`CloseCode::Kicked`]. Returns `false` if there is no such
+    /// session.
+    pub fn kick(&self, session_id: SessionId) -> bool {
+        self.close(session_id, CloseCode::Kicked)
+    }
+
+    /// Disconnects a session with `code`, which bridged sessions, having no close codes, are not
+    /// told. Returns `false` if there is no such session.
+    pub fn close(&self, session_id: SessionId, code: CloseCode) -> bool {
+        let link = self
+            .sessions
+            .shard(&session_id)
+            .lock()
+            .unwrap()
+            .get(&session_id)
+            .map(|member| member.link.clone());
+
+        let Some(link) = link else {
+            return false;
+        };
+        info!("Closing session {session_id} with {}", code.as_str_name());
+        match link {
+            Link::WebTransport(connection) => protocol::close(&*connection, code),
+            Link::Bridged(hang_up) => hang_up.notify_one(),
+        }
+        true
+    }
+
+    /// Mutes or unmutes a session's voice. Returns `false` if there is no such session.
+    pub fn set_muted(&self, session_id: SessionId, muted: bool) -> bool {
+        let sessions = self.sessions.shard(&session_id).lock().unwrap();
+        let Some(member) = sessions.get(&session_id) else {
+            return false;
+        };
+        info!(
+            "{} session {session_id}",
+            if muted { "Muting" } else { "Unmuting" }
+        );
+        member.muted.store(muted, Ordering::Relaxed);
+        true
+    }
+
+    /// Moves a session in a room to another room of its tenant on this node, by its key with
+    /// the tenant. The session moves once it gets to it, unless a plugin refuses the join.
+    /// Returns `false` if there is no such session, the room belongs to another tenant, or the
+    /// session cannot be moved because its client did not negotiate `FEATURE_ROOM_MOVES`.
+    pub fn move_session(&self, session_id: SessionId, room_key: &str) -> bool {
+        let sessions = self.sessions.shard(&session_id).lock().unwrap();
+        let Some(member) = sessions.get(&session_id) else {
+            return false;
+        };
+        let owned = member
+            .tenant
+            .as_deref()
+            .is_none_or(|tenant| tenants::name(tenant, room_key).is_some());
+        let Some(moves) = member.moves.as_ref().filter(|_| owned) else {
+            return false;
+        };
+        info!("Moving session {session_id} to room '{room_key}'");
+        moves.try_send(room_key.into()).is_ok()
+    }
+
+    pub fn is_muted(&self, session_id: SessionId) -> bool {
+        let sessions = self.sessions.shard(&session_id).lock().unwrap();
+        sessions
+            .get(&session_id)
+            .is_some_and(|member| member.muted.load(Ordering::Relaxed))
+    }
+
+    fn unregister(&self, session_id: SessionId) {
+        let member = self
+            .sessions
+            .shard(&session_id)
+            .lock()
+            .unwrap()
+            .remove(&session_id);
+
+        if let Some(member) = member {
+            let key = member.key();
+            self.usernames.shard(&key).lock().unwrap().remove(&key);
+        }
+    }
+}
+
+/// Keeps a session registered. Dropping it removes the session and notifies its room.
+pub struct Registration {
+    registry: Arc<Registry>,
+    session_id: SessionId,
+    username: Arc<str>,
+    tenant: Option<Arc<str>>,
+    room_key: Option<Arc<str>>,
+
+    /// The [`protocol::room_id`] of the session's room.
+    room_id: u32,
+
+    /// The sequence number of the session's next voice frame, for clients that do not number
+    /// their own.
+    voice_sequence: AtomicU32,
+
+    muted: Arc<AtomicBool>,
+    blocked: Arc<Mutex<HashSet<String>>>,
+}
+
+impl Registration {
+    pub fn session_id(&self) -> SessionId {
+        self.session_id
+    }
+
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    /// The tenant the session belongs to, on servers with tenants.
+    pub fn tenant(&self) -> Option<&str> {
+        self.tenant.as_deref()
+    }
+
+    pub fn user(&self) -> RoomUser {
+        RoomUser {
+            session_id: self.session_id,
+            username: self.username.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Lets the session be moved with [`Registry::move_session`], returning the keys of the
+    /// rooms it is moved to.
+    pub fn accept_moves(&self) -> mpsc::Receiver<Arc<str>> {
+        let (moves, receiver) = mpsc::channel(MAX_PENDING_MOVES);
+        let mut sessions = self
+            .registry
+            .sessions
+            .shard(&self.session_id)
+            .lock()
+            .unwrap();
+        if let Some(member) = sessions.get_mut(&self.session_id) {
+            member.moves = Some(moves);
+        }
+        receiver
+    }
+
+    /// Tells the session who waits to join its room, as one of its moderators.
+    pub fn moderate_lobby(&self) {
+        let mut sessions = self
+            .registry
+            .sessions
+            .shard(&self.session_id)
+            .lock()
+            .unwrap();
+        if let Some(member) = sessions.get_mut(&self.session_id) {
+            member.moderates = true;
+        }
+    }
+
+    /// Lets the session receive direct messages.
+    pub fn accept_direct_messages(&self) {
+        let mut sessions = self
+            .registry
+            .sessions
+            .shard(&self.session_id)
+            .lock()
+            .unwrap();
+        if let Some(member) = sessions.get_mut(&self.session_id) {
+            member.direct_messages = true;
+        }
+    }
+
+    /// Replaces the usernames the session blocks.
+    pub fn set_blocked(&self, usernames: HashSet<String>) {
+        *self.blocked.lock().unwrap() = usernames;
+    }
+
+    /// Whether the session blocks `username`.
+    pub fn blocks(&self, username: &str) -> bool {
+        self.blocked.lock().unwrap().contains(username)
+    }
+
+    /// The username and outbox of the session a direct message from this one is for, unless it
+    /// cannot be sent there.
+    pub fn direct_recipient(&self, session_id: SessionId) -> Result<(String, Outbox), DirectError> {
+        let sessions = self.registry.sessions.shard(&session_id).lock().unwrap();
+        let recipient = sessions
+            .get(&session_id)
+            .filter(|member| member.tenant == self.tenant)
+            .ok_or(DirectError::Offline)?;
+        if !recipient.direct_messages {
+            return Err(DirectError::NotAccepted);
+        }
+        if recipient.blocks(&self.username) || self.blocks(&recipient.username) {
+            return Err(DirectError::Blocked);
+        }
+        Ok((recipient.username.clone(), recipient.outbox.clone()))
+    }
+
+    /// Checks that the session could join a room, without joining it.
+    pub fn can_join(&self, room_key: &str) -> Result<(), JoinError> {
+        if room_key.is_empty() || room_key.chars().count() > MAX_ROOM_KEY_LEN {
+            return Err(JoinError::InvalidRoomKey);
+        }
+        if self.room_key.is_some() {
+            return Err(JoinError::AlreadyInRoom);
+        }
+        Ok(())
+    }
+
+    /// Adds the session to a room of its tenant, creating the room if necessary.
+    pub fn join(&mut self, room_key: &str) -> Result<Joined, JoinError> {
+        self.can_join(room_key)?;
+
+        let room_key = tenants::key(self.tenant(), room_key);
+        let joined = self.registry.join(self.session_id, &room_key);
+        self.room_id = protocol::room_id(&room_key);
+        self.room_key = Some(room_key.into());
+
+        Ok(joined)
+    }
+
+    /// Moves the session from its room to another of its tenant, by its key with the tenant,
+    /// notifying the users of the room it leaves.
+    pub fn move_to(&mut self, room_key: &str) -> Result<Joined, JoinError> {
+        let name = match self.tenant() {
+            Some(tenant) => tenants::name(tenant, room_key).ok_or(JoinError::InvalidRoomKey)?,
+            None => room_key,
+        };
+        if name.is_empty() || name.chars().count() > MAX_ROOM_KEY_LEN {
+            return Err(JoinError::InvalidRoomKey);
+        }
+
+        self.leave_room();
+        let joined = self.registry.join(self.session_id, room_key);
+        self.room_id = protocol::room_id(room_key);
+        self.room_key = Some(room_key.into());
+        Ok(joined)
+    }
+
+    /// The name the client knows the session's room by, without its tenant.
+    pub fn room_name(&self) -> Option<&str> {
+        let room_key = self.room_key.as_deref()?;
+        match self.tenant() {
+            Some(tenant) => tenants::name(tenant, room_key),
+            None => Some(room_key),
+        }
+    }
+
+    /// The key of the session's room, with its tenant, if it has joined one.
+    pub fn room_key(&self) -> Option<&Arc<str>> {
+        self.room_key.as_ref()
+    }
+
+    /// The [`protocol::room_id`] of the session's room, if it is in one.
+    pub fn room_id(&self) -> Option<u32> {
+        self.room_key.as_ref().map(|_| self.room_id)
+    }
+
+    /// A voice frame of the session's in its room, numbered after the last one built.
+    pub fn voice_frame(&self, payload: Bytes, capture_time_us: u64) -> VoiceFrame {
+        let header = VoiceHeader {
+            session_id: self.session_id,
+            room_id: self.room_id,
+            sequence: self.voice_sequence.fetch_add(1, Ordering::Relaxed),
+            capture_time_us,
+        };
+        VoiceFrame::new(header, payload)
+    }
+
+    /// Whether an operator muted the session, so its voice is not relayed.
+    pub fn is_muted(&self) -> bool {
+        self.muted.load(Ordering::Relaxed)
+    }
+
+    /// Meters a voice frame of `bytes` bytes against the cap of `max_kbps` on the session's room,
+    /// returning `false` if it is over the cap. A zero cap admits everything.
+    pub fn admit_room_audio(&self, bytes: usize, max_kbps: u32) -> bool {
+        match &self.room_key {
+            Some(room_key) if max_kbps > 0 => self.registry.admit_audio(room_key, bytes, max_kbps),
+            _ => true,
+        }
+    }
+
+    /// Calls `f` with the outbox of each other user in the session's room, but those who block
+    /// it, returning how many there are. The room is locked meanwhile, so that forwarding a
+    /// frame need not collect the outboxes first.
+    pub fn for_each_peer(&self, f: impl FnMut(&Outbox)) -> usize {
+        match &self.room_key {
+            Some(room_key) => self.registry.for_each_peer(self.session_id, room_key, f),
+            None => 0,
+        }
+    }
+
+    /// Takes the session out of its room, if it is in one, and notifies the room's users.
+    fn leave_room(&mut self) {
+        let Some(room_key) = self.room_key.take() else {
+            return;
+        };
+        info!("Session {} left room '{room_key}'", self.session_id);
+
+        let peers = self.registry.leave(self.user(), room_key);
+        let packet = protocol::user_left(self.session_id);
+        for peer in peers {
+            peer.try_send_control(packet.clone());
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.registry.unregister(self.session_id);
+        self.leave_room();
+        self.registry
+            .events
+            .publish(Event::SessionEnded { user: self.user() });
+    }
+}
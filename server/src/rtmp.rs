@@ -0,0 +1,528 @@
+//! An RTMP publisher, for pushing a room's mix to a streaming service.
+//!
+//! Only what publishing one audio stream needs is implemented: the simple handshake, chunking
+//! with a single outgoing chunk size, AMF0 commands, and enough of the incoming chunk stream to
+//! read the server's replies. Audio is sent as FLAC using the Enhanced RTMP audio tag format.
+//!
+//! URLs have the form `rtmp://host[:port]/app/stream-key`. RTMPS is not supported.
+
+use std::collections::HashMap;
+
+use anyhow::Context;
+use anyhow::Result;
+use anyhow::bail;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::io::BufReader;
+use tokio::net::TcpStream;
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::task::JoinHandle;
+
+const DEFAULT_PORT: u16 = 1935;
+
+const HANDSHAKE_LEN: usize = 1536;
+
+/// The chunk size used for everything sent after the handshake.
+const CHUNK_SIZE: u32 = 4096;
+
+/// The timestamp field value marking an extended timestamp.
+const EXTENDED_TIMESTAMP: u32 = 0xFF_FFFF;
+
+/// The largest message accepted from the server.
+const MAX_MESSAGE_LEN: usize = 1024 * 1024;
+
+// Chunk streams, by what is sent on them.
+const CONTROL_CHUNK_STREAM: u32 = 2;
+const COMMAND_CHUNK_STREAM: u32 = 3;
+const AUDIO_CHUNK_STREAM: u32 = 4;
+
+// Message types.
+const SET_CHUNK_SIZE: u8 = 1;
+const AUDIO: u8 = 8;
+const DATA_AMF0: u8 = 18;
+const COMMAND_AMF0: u8 = 20;
+
+/// The Enhanced RTMP sound format marking an extended audio tag header.
+const EX_HEADER: u8 = 9;
+
+// Enhanced RTMP audio packet types.
+const SEQUENCE_START: u8 = 0;
+const CODED_FRAMES: u8 = 1;
+
+const FLAC_FOURCC: &[u8; 4] = b"fLaC";
+
+/// A connection publishing one stream.
+pub struct Publisher {
+    writer: OwnedWriteHalf,
+    reader: ChunkReader,
+    stream_id: u32,
+
+    /// Reads and discards whatever the server sends once publishing has started.
+    drain: Option<JoinHandle<Result<()>>>,
+}
+
+impl Publisher {
+    /// Connects to `url` and starts publishing to its stream key.
+    pub async fn connect(url: &str) -> Result<Self> {
+        let rest = url
+            .strip_prefix("rtmp://")
+            .context("RTMP URL must start with rtmp://")?;
+        let (authority, path) = rest
+            .split_once('/')
+            .context("RTMP URL must name an app and a stream key")?;
+        let (app, stream_key) = path
+            .split_once('/')
+            .context("RTMP URL must name an app and a stream key")?;
+        let address = if authority.contains(':') {
+            authority.to_owned()
+        } else {
+            format!("{authority}:{DEFAULT_PORT}")
+        };
+
+        let stream = TcpStream::connect(&address)
+            .await
+            .with_context(|| format!("Cannot connect to RTMP server at {address}"))?;
+        stream.set_nodelay(true)?;
+        let (reader, writer) = stream.into_split();
+        let mut publisher = Self {
+            writer,
+            reader: ChunkReader::new(reader),
+            stream_id: 0,
+            drain: None,
+        };
+
+        publisher.handshake().await?;
+        publisher
+            .send(
+                CONTROL_CHUNK_STREAM,
+                SET_CHUNK_SIZE,
+                0,
+                0,
+                &CHUNK_SIZE.to_be_bytes(),
+            )
+            .await?;
+
+        let tc_url = format!("rtmp://{authority}/{app}");
+        publisher
+            .command(&[
+                Amf::String("connect".into()),
+                Amf::Number(1.0),
+                Amf::Object(vec![
+                    ("app".into(), Amf::String(app.into())),
+                    ("type".into(), Amf::String("nonprivate".into())),
+                    ("flashVer".into(), Amf::String("FMLE/3.0".into())),
+                    ("tcUrl".into(), Amf::String(tc_url)),
+                ]),
+            ])
+            .await?;
+        publisher.result(1.0).await?;
+
+        for (transaction, name) in [(2.0, "releaseStream"), (3.0, "FCPublish")] {
+            publisher
+                .command(&[
+                    Amf::String(name.into()),
+                    Amf::Number(transaction),
+                    Amf::Null,
+                    Amf::String(stream_key.into()),
+                ])
+                .await?;
+        }
+        publisher
+            .command(&[
+                Amf::String("createStream".into()),
+                Amf::Number(4.0),
+                Amf::Null,
+            ])
+            .await?;
+        let Some(Amf::Number(stream_id)) = publisher.result(4.0).await?.into_iter().nth(3) else {
+            bail!("RTMP server did not return a stream ID");
+        };
+        publisher.stream_id = stream_id as u32;
+
+        publisher
+            .command(&[
+                Amf::String("publish".into()),
+                Amf::Number(5.0),
+                Amf::Null,
+                Amf::String(stream_key.into()),
+                Amf::String("live".into()),
+            ])
+            .await?;
+        publisher.publish_started().await?;
+
+        let mut reader = std::mem::replace(&mut publisher.reader, ChunkReader::closed());
+        publisher.drain = Some(tokio::spawn(async move {
+            loop {
+                reader.message().await?;
+            }
+        }));
+        Ok(publisher)
+    }
+
+    /// Sends the stream's metadata and the FLAC header, which must come before any frame.
+    pub async fn start_audio(&mut self, stream_info: &[u8]) -> Result<()> {
+        let metadata = amf_encode(&[
+            Amf::String("@setDataFrame".into()),
+            Amf::String("onMetaData".into()),
+            Amf::Object(vec![
+                (
+                    "audiocodecid".into(),
+                    Amf::Number(f64::from(u32::from_be_bytes(*FLAC_FOURCC))),
+                ),
+                ("audiosamplerate".into(), Amf::Number(48_000.0)),
+                ("audiosamplesize".into(), Amf::Number(16.0)),
+                ("stereo".into(), Amf::Bool(false)),
+            ]),
+        ]);
+        self.send(
+            COMMAND_CHUNK_STREAM,
+            DATA_AMF0,
+            self.stream_id,
+            0,
+            &metadata,
+        )
+        .await?;
+
+        let mut header = b"fLaC".to_vec();
+        header.extend_from_slice(stream_info);
+        self.send_audio(SEQUENCE_START, 0, &header).await
+    }
+
+    /// Sends a FLAC frame starting `timestamp_ms` into the stream.
+    pub async fn send_frame(&mut self, timestamp_ms: u32, frame: &[u8]) -> Result<()> {
+        self.send_audio(CODED_FRAMES, timestamp_ms, frame).await
+    }
+
+    /// Waits for the server to close the connection.
+    pub async fn closed(&mut self) -> Result<()> {
+        let Some(drain) = &mut self.drain else {
+            return std::future::pending().await;
+        };
+        drain.await?
+    }
+
+    async fn send_audio(&mut self, packet_type: u8, timestamp_ms: u32, body: &[u8]) -> Result<()> {
+        let mut tag = Vec::with_capacity(5 + body.len());
+        tag.push(EX_HEADER << 4 | packet_type);
+        tag.extend_from_slice(FLAC_FOURCC);
+        tag.extend_from_slice(body);
+        self.send(
+            AUDIO_CHUNK_STREAM,
+            AUDIO,
+            self.stream_id,
+            timestamp_ms,
+            &tag,
+        )
+        .await
+    }
+
+    async fn handshake(&mut self) -> Result<()> {
+        let mut c0c1 = vec![3];
+        c0c1.extend_from_slice(&[0; 8]);
+        c0c1.extend((0..HANDSHAKE_LEN - 8).map(|_| rand::random::<u8>()));
+        self.writer.write_all(&c0c1).await?;
+
+        let reader = self.reader.stream.as_mut().unwrap();
+        let version = reader.read_u8().await?;
+        if version != 3 {
+            bail!("RTMP server speaks version {version}");
+        }
+        let mut s1 = vec![0; HANDSHAKE_LEN];
+        reader.read_exact(&mut s1).await?;
+        // C2 echoes S1.
+        self.writer.write_all(&s1).await?;
+        let mut s2 = vec![0; HANDSHAKE_LEN];
+        reader.read_exact(&mut s2).await?;
+        Ok(())
+    }
+
+    async fn command(&mut self, values: &[Amf]) -> Result<()> {
+        let stream_id = match values.first() {
+            Some(Amf::String(name)) if name == "publish" => self.stream_id,
+            _ => 0,
+        };
+        self.send(
+            COMMAND_CHUNK_STREAM,
+            COMMAND_AMF0,
+            stream_id,
+            0,
+            &amf_encode(values),
+        )
+        .await
+    }
+
+    /// Waits for the reply to a command, failing if it is an error.
+    async fn result(&mut self, transaction: f64) -> Result<Vec<Amf>> {
+        loop {
+            let values = self.reader.command().await?;
+            match &values[..] {
+                [Amf::String(name), Amf::Number(id), ..] if *id == transaction => {
+                    if name == "_result" {
+                        return Ok(values);
+                    }
+                    bail!("RTMP server refused the command: {}", describe(&values));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Waits for the server to accept the publish.
+    async fn publish_started(&mut self) -> Result<()> {
+        loop {
+            let values = self.reader.command().await?;
+            let [Amf::String(name), _, _, Amf::Object(info), ..] = &values[..] else {
+                continue;
+            };
+            if name != "onStatus" {
+                continue;
+            }
+            let code = info.iter().find_map(|(key, value)| match value {
+                Amf::String(code) if key == "code" => Some(code.as_str()),
+                _ => None,
+            });
+            match code {
+                Some("NetStream.Publish.Start") => return Ok(()),
+                _ => bail!("RTMP server refused to publish: {}", describe(&values)),
+            }
+        }
+    }
+
+    /// Sends a message as chunks, all but the first with a type 3 header.
+    async fn send(
+        &mut self,
+        chunk_stream: u32,
+        message_type: u8,
+        stream_id: u32,
+        timestamp: u32,
+        payload: &[u8],
+    ) -> Result<()> {
+        let chunk_stream = chunk_stream as u8;
+        // Timestamps that do not fit in three bytes follow every chunk's header instead.
+        let extended = (timestamp >= EXTENDED_TIMESTAMP).then_some(timestamp.to_be_bytes());
+        let mut message = Vec::with_capacity(16 + payload.len() + payload.len() / 1024);
+        message.push(chunk_stream);
+        message.extend_from_slice(&timestamp.min(EXTENDED_TIMESTAMP).to_be_bytes()[1..]);
+        message.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+        message.push(message_type);
+        message.extend_from_slice(&stream_id.to_le_bytes());
+
+        for (index, chunk) in payload.chunks(CHUNK_SIZE as usize).enumerate() {
+            if index > 0 {
+                message.push(0xC0 | chunk_stream);
+            }
+            message.extend_from_slice(extended.as_ref().map_or(&[][..], |bytes| &bytes[..]));
+            message.extend_from_slice(chunk);
+        }
+        self.writer.write_all(&message).await?;
+        Ok(())
+    }
+}
+
+/// Reassembles the messages the server sends from their chunks.
+struct ChunkReader {
+    /// Unset once the reader has been moved into the drain task.
+    stream: Option<BufReader<OwnedReadHalf>>,
+    chunk_size: usize,
+
+    /// The last header and any partial message on each chunk stream.
+    chunk_streams: HashMap<u32, ChunkStream>,
+}
+
+#[derive(Default)]
+struct ChunkStream {
+    len: usize,
+    extended: bool,
+    message_type: u8,
+    payload: Vec<u8>,
+}
+
+impl ChunkReader {
+    fn new(stream: OwnedReadHalf) -> Self {
+        Self {
+            stream: Some(BufReader::new(stream)),
+            chunk_size: 128,
+            chunk_streams: HashMap::new(),
+        }
+    }
+
+    fn closed() -> Self {
+        Self {
+            stream: None,
+            chunk_size: 128,
+            chunk_streams: HashMap::new(),
+        }
+    }
+
+    /// Reads the next AMF0 command.
+    async fn command(&mut self) -> Result<Vec<Amf>> {
+        loop {
+            let (message_type, payload) = self.message().await?;
+            if message_type == COMMAND_AMF0 {
+                return amf_decode(&payload).context("RTMP server sent a malformed command");
+            }
+        }
+    }
+
+    /// Reads the next complete message, handling chunk size changes along the way.
+    async fn message(&mut self) -> Result<(u8, Vec<u8>)> {
+        let stream = self.stream.as_mut().context("RTMP connection is closed")?;
+        loop {
+            let first = stream.read_u8().await?;
+            let format = first >> 6;
+            let chunk_stream = match first & 0x3F {
+                0 => 64 + u32::from(stream.read_u8().await?),
+                1 => 64 + u32::from(stream.read_u16_le().await?),
+                id => u32::from(id),
+            };
+
+            let mut header = [0; 11];
+            let header_len = [11, 7, 3, 0][usize::from(format)];
+            stream.read_exact(&mut header[..header_len]).await?;
+            let state = self.chunk_streams.entry(chunk_stream).or_default();
+            if header_len > 0 {
+                state.extended = header[..3] == [0xFF; 3];
+            }
+            if state.extended {
+                stream.read_u32().await?;
+            }
+            if header_len >= 7 {
+                state.len = u32::from_be_bytes([0, header[3], header[4], header[5]]) as usize;
+                state.message_type = header[6];
+                if state.len > MAX_MESSAGE_LEN {
+                    bail!("RTMP message of {} bytes is too large", state.len);
+                }
+            }
+
+            let chunk_len = (state.len - state.payload.len()).min(self.chunk_size);
+            let start = state.payload.len();
+            state.payload.resize(start + chunk_len, 0);
+            stream.read_exact(&mut state.payload[start..]).await?;
+            if state.payload.len() < state.len {
+                continue;
+            }
+
+            let message_type = state.message_type;
+            let payload = std::mem::take(&mut state.payload);
+            if message_type == SET_CHUNK_SIZE && payload.len() >= 4 {
+                let size = u32::from_be_bytes(payload[..4].try_into().unwrap()) & 0x7FFF_FFFF;
+                self.chunk_size = (size as usize).clamp(1, MAX_MESSAGE_LEN);
+            }
+            return Ok((message_type, payload));
+        }
+    }
+}
+
+/// An AMF0 value.
+#[derive(Debug, Clone)]
+enum Amf {
+    Number(f64),
+    Bool(bool),
+    String(String),
+    Object(Vec<(String, Amf)>),
+    Null,
+}
+
+fn amf_encode(values: &[Amf]) -> Vec<u8> {
+    fn string(out: &mut Vec<u8>, value: &str) {
+        out.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        out.extend_from_slice(value.as_bytes());
+    }
+    fn value(out: &mut Vec<u8>, amf: &Amf) {
+        match amf {
+            Amf::Number(number) => {
+                out.push(0x00);
+                out.extend_from_slice(&number.to_be_bytes());
+            }
+            Amf::Bool(boolean) => out.extend_from_slice(&[0x01, u8::from(*boolean)]),
+            Amf::String(text) => {
+                out.push(0x02);
+                string(out, text);
+            }
+            Amf::Object(properties) => {
+                out.push(0x03);
+                for (key, property) in properties {
+                    string(out, key);
+                    value(out, property);
+                }
+                out.extend_from_slice(&[0, 0, 0x09]);
+            }
+            Amf::Null => out.push(0x05),
+        }
+    }
+
+    let mut out = Vec::new();
+    for amf in values {
+        value(&mut out, amf);
+    }
+    out
+}
+
+fn amf_decode(mut bytes: &[u8]) -> Option<Vec<Amf>> {
+    fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+        let (taken, rest) = bytes.split_at_checked(len)?;
+        *bytes = rest;
+        Some(taken)
+    }
+    fn string(bytes: &mut &[u8]) -> Option<String> {
+        let len = u16::from_be_bytes(take(bytes, 2)?.try_into().unwrap());
+        Some(String::from_utf8_lossy(take(bytes, len.into())?).into_owned())
+    }
+    fn properties(bytes: &mut &[u8]) -> Option<Vec<(String, Amf)>> {
+        let mut properties = Vec::new();
+        loop {
+            let key = string(bytes)?;
+            if key.is_empty() && bytes.first() == Some(&0x09) {
+                *bytes = &bytes[1..];
+                return Some(properties);
+            }
+            properties.push((key, value(bytes)?));
+        }
+    }
+    fn value(bytes: &mut &[u8]) -> Option<Amf> {
+        match take(bytes, 1)?[0] {
+            0x00 => Some(Amf::Number(f64::from_be_bytes(
+                take(bytes, 8)?.try_into().unwrap(),
+            ))),
+            0x01 => Some(Amf::Bool(take(bytes, 1)?[0] != 0)),
+            0x02 => Some(Amf::String(string(bytes)?)),
+            0x03 => Some(Amf::Object(properties(bytes)?)),
+            0x05 | 0x06 => Some(Amf::Null),
+            // An ECMA array is an object with a count in front.
+            0x08 => {
+                take(bytes, 4)?;
+                Some(Amf::Object(properties(bytes)?))
+            }
+            _ => None,
+        }
+    }
+
+    let mut values = Vec::new();
+    while !bytes.is_empty() {
+        values.push(value(&mut bytes)?);
+    }
+    Some(values)
+}
+
+/// A command's description or code, for error messages.
+fn describe(values: &[Amf]) -> String {
+    values
+        .iter()
+        .find_map(|value| match value {
+            Amf::Object(properties) => properties.iter().find_map(|(key, value)| match value {
+                Amf::String(text) if key == "description" || key == "code" => Some(text.clone()),
+                _ => None,
+            }),
+            _ => None,
+        })
+        .unwrap_or_else(|| "no reason given".to_owned())
+}
+
+impl Drop for Publisher {
+    fn drop(&mut self) {
+        if let Some(drain) = &self.drain {
+            drain.abort();
+        }
+    }
+}
@@ -0,0 +1,67 @@
+//! RTP packets, as carried between the SIP bridge and callers.
+//!
+//! Only what a single audio stream needs is handled: the fixed header, CSRCs, header
+//! extensions and padding are parsed and skipped, and RTCP is neither sent nor read.
+
+/// The length of the fixed RTP header.
+const HEADER_LEN: usize = 12;
+
+const VERSION: u8 = 2;
+
+/// The payload type and payload of an RTP packet, or `None` if it is malformed.
+pub fn parse(packet: &[u8]) -> Option<(u8, &[u8])> {
+    let header = packet.get(..HEADER_LEN)?;
+    if header[0] >> 6 != VERSION {
+        return None;
+    }
+    let padded = header[0] & 0x20 != 0;
+    let extended = header[0] & 0x10 != 0;
+    let csrc_count = usize::from(header[0] & 0x0F);
+    let payload_type = header[1] & 0x7F;
+
+    let mut payload = packet.get(HEADER_LEN + 4 * csrc_count..)?;
+    if extended {
+        let extension_len = u16::from_be_bytes([*payload.get(2)?, *payload.get(3)?]);
+        payload = payload.get(4 + 4 * usize::from(extension_len)..)?;
+    }
+    if padded {
+        let padding = usize::from(*payload.last()?);
+        payload = payload.get(..payload.len().checked_sub(padding)?)?;
+    }
+
+    Some((payload_type, payload))
+}
+
+/// Builds the packets of an outgoing stream.
+pub struct Sender {
+    payload_type: u8,
+    sequence: u16,
+    timestamp: u32,
+    ssrc: u32,
+}
+
+impl Sender {
+    pub fn new(payload_type: u8) -> Self {
+        Self {
+            payload_type,
+            sequence: rand::random(),
+            timestamp: rand::random(),
+            ssrc: rand::random(),
+        }
+    }
+
+    /// Builds the next packet, advancing the timestamp by `samples`.
+    pub fn packet(&mut self, payload: &[u8], samples: u32) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(HEADER_LEN + payload.len());
+        packet.push(VERSION << 6);
+        packet.push(self.payload_type);
+        packet.extend_from_slice(&self.sequence.to_be_bytes());
+        packet.extend_from_slice(&self.timestamp.to_be_bytes());
+        packet.extend_from_slice(&self.ssrc.to_be_bytes());
+        packet.extend_from_slice(payload);
+
+        self.sequence = self.sequence.wrapping_add(1);
+        self.timestamp = self.timestamp.wrapping_add(samples);
+        packet
+    }
+}
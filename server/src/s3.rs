@@ -0,0 +1,100 @@
+//! A minimal S3 client that stores and fetches objects, signing requests with AWS Signature
+//! Version 4.
+//!
+//! Buckets are addressed path-style, as `<endpoint>/<bucket>/<key>`, which S3 and compatible
+//! stores such as MinIO all accept. Like the rest of [`http_client`], only `http://`
+//! endpoints are supported.
+
+use std::time::Duration;
+
+use anyhow::Context;
+use anyhow::Result;
+use anyhow::bail;
+
+use crate::config::S3Config;
+use crate::http_client;
+use crate::sigv4::Signer;
+
+/// How long an object may take to transfer.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+pub struct Client {
+    config: S3Config,
+
+    /// The endpoint's `host[:port]`, which is signed as the `host` header.
+    authority: String,
+}
+
+impl Client {
+    pub fn new(config: &S3Config) -> Result<Self> {
+        let authority = config
+            .endpoint
+            .trim_end_matches('/')
+            .strip_prefix("http://")
+            .context("S3 endpoint must start with http://")?;
+        if authority.contains('/') {
+            bail!("S3 endpoint must not have a path");
+        }
+        Ok(Self {
+            authority: authority.to_owned(),
+            config: config.clone(),
+        })
+    }
+
+    pub async fn put_object(&self, key: &str, content_type: &str, body: &[u8]) -> Result<()> {
+        let status = self
+            .send("PUT", key, Some(("Content-Type", content_type)), body)
+            .await?
+            .status;
+        if !(200..300).contains(&status) {
+            bail!("S3 refused to store {key} with status {status}");
+        }
+        Ok(())
+    }
+
+    /// Fetches an object, or `None` if it does not exist.
+    pub async fn get_object(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let response = self.send("GET", key, None, &[]).await?;
+        match response.status {
+            200..300 => Ok(Some(response.body)),
+            404 => Ok(None),
+            status => bail!("S3 refused to fetch {key} with status {status}"),
+        }
+    }
+
+    /// Deletes an object. Deleting one that does not exist succeeds.
+    pub async fn delete_object(&self, key: &str) -> Result<()> {
+        let status = self.send("DELETE", key, None, &[]).await?.status;
+        if !(200..300).contains(&status) && status != 404 {
+            bail!("S3 refused to delete {key} with status {status}");
+        }
+        Ok(())
+    }
+
+    async fn send(
+        &self,
+        method: &str,
+        key: &str,
+        content_type: Option<(&str, &str)>,
+        body: &[u8],
+    ) -> Result<http_client::Response> {
+        let path: Vec<String> = key.split('/').map(http_client::percent_encode).collect();
+        let path = format!("/{}/{}", self.config.bucket, path.join("/"));
+
+        let signer = Signer {
+            access_key_id: &self.config.access_key_id,
+            secret_access_key: &self.config.secret_access_key,
+            region: &self.config.region,
+            service: "s3",
+        };
+        let signature = signer.sign(method, &self.authority, &path, body);
+        let mut headers: Vec<(&str, &str)> = signature
+            .iter()
+            .map(|(name, value)| (*name, value.as_str()))
+            .collect();
+        headers.extend(content_type);
+        let url = format!("{}{path}", self.config.endpoint.trim_end_matches('/'));
+        let body = (method == "PUT").then_some(body);
+        http_client::send(&url, method, &headers, body, TIMEOUT).await
+    }
+}
@@ -0,0 +1,299 @@
+//! Scheduled rooms: rooms booked through the admin API for meetings.
+//!
+//! A scheduled room is opened when it starts and held open until it ends, with or without
+//! members, and while it is on only its participants may join it, if it names any. With
+//! `schedule.webhook_url` set, a reminder is POSTed there `schedule.reminder_mins` before the
+//! room starts, and its start and end as they happen. Rooms booked with `record` have their mix
+//! recorded while they are on, as [`broadcast`](crate::broadcast) streams it, into the blob
+//! store; see [`recordings`](crate::recordings).
+//!
+//! The schedule is kept in `schedule.path` if it is set, and in memory otherwise, and rooms are
+//! dropped from it once they end. A room still on when the server restarts is opened again, and
+//! recorded anew.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::Context;
+use anyhow::Result;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::json;
+use tokio::sync::oneshot;
+use tracing::info;
+use tracing::warn;
+
+use crate::accounts;
+use crate::broadcast::Broadcasts;
+use crate::http_client;
+use crate::recordings::Recording;
+use crate::state::ServerState;
+
+/// How often the schedule is checked for rooms to remind of, start and end.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The longest a room may be booked for.
+pub const MAX_DURATION_SECS: u64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduledRoom {
+    pub id: String,
+
+    /// The room's key, with its tenant.
+    pub room_key: String,
+
+    pub title: String,
+
+    /// Seconds since the Unix epoch.
+    pub starts_at: u64,
+
+    pub duration_secs: u64,
+
+    /// The keys of the users that may join while the room is on. Empty lets anyone join.
+    pub participants: Vec<String>,
+
+    pub record: bool,
+
+    /// Whether the reminder was sent.
+    #[serde(default)]
+    reminded: bool,
+
+    /// Whether the start was sent, so a restart does not send it again.
+    #[serde(default)]
+    started: bool,
+}
+
+impl ScheduledRoom {
+    pub fn new(
+        room_key: String,
+        title: String,
+        starts_at: u64,
+        duration_secs: u64,
+        participants: Vec<String>,
+        record: bool,
+    ) -> Self {
+        Self {
+            id: format!("{:016x}", rand::random::<u64>()),
+            room_key,
+            title,
+            starts_at,
+            duration_secs,
+            participants,
+            record,
+            reminded: false,
+            started: false,
+        }
+    }
+
+    pub fn ends_at(&self) -> u64 {
+        self.starts_at.saturating_add(self.duration_secs)
+    }
+
+    fn is_on(&self, now: u64) -> bool {
+        (self.starts_at..self.ends_at()).contains(&now)
+    }
+}
+
+/// The rooms scheduled that have not ended.
+pub struct Schedule {
+    path: Option<PathBuf>,
+    rooms: Mutex<Vec<ScheduledRoom>>,
+
+    /// The rooms that are on, by ID.
+    on: Mutex<HashMap<String, On>>,
+}
+
+/// A scheduled room that started.
+struct On {
+    room: ScheduledRoom,
+
+    /// Stops the room's recording, when sent to or dropped.
+    recording: Option<oneshot::Sender<()>>,
+}
+
+impl Schedule {
+    /// Opens the schedule file at `path`, if any, creating it when a room is first scheduled.
+    pub fn open(path: Option<PathBuf>) -> Result<Self> {
+        let rooms = match &path {
+            Some(path) => match std::fs::read_to_string(path) {
+                Ok(json) => serde_json::from_str(&json)
+                    .with_context(|| format!("Invalid schedule file {}", path.display()))?,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+                Err(err) => {
+                    return Err(err)
+                        .with_context(|| format!("Cannot read schedule file {}", path.display()));
+                }
+            },
+            None => Vec::new(),
+        };
+        Ok(Self {
+            path,
+            rooms: Mutex::new(rooms),
+            on: Mutex::default(),
+        })
+    }
+
+    /// Books a room, unless it is already booked for some of that time. Returns whether it was.
+    pub fn add(&self, room: ScheduledRoom) -> Result<bool> {
+        let mut rooms = self.rooms.lock().unwrap();
+        let overlaps = rooms.iter().any(|other| {
+            other.room_key == room.room_key
+                && other.starts_at < room.ends_at()
+                && room.starts_at < other.ends_at()
+        });
+        if overlaps {
+            return Ok(false);
+        }
+        rooms.push(room);
+        self.save(&rooms)?;
+        Ok(true)
+    }
+
+    /// Cancels a room that `allowed` accepts, which if it is on ends at the next check. Returns
+    /// `false` if there is no such room.
+    pub fn cancel(&self, id: &str, allowed: impl Fn(&ScheduledRoom) -> bool) -> Result<bool> {
+        let mut rooms = self.rooms.lock().unwrap();
+        let Some(index) = rooms.iter().position(|room| room.id == id && allowed(room)) else {
+            return Ok(false);
+        };
+        rooms.remove(index);
+        self.save(&rooms)?;
+        Ok(true)
+    }
+
+    pub fn list(&self) -> Vec<ScheduledRoom> {
+        self.rooms.lock().unwrap().clone()
+    }
+
+    /// Whether a user, by their key, may join a room now: anyone may, unless the room is on and
+    /// names its participants.
+    pub fn admits(&self, room_key: &str, user_key: &str) -> bool {
+        let now = accounts::now_secs();
+        self.rooms
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|room| room.room_key == room_key && room.is_on(now))
+            .all(|room| {
+                room.participants.is_empty()
+                    || room
+                        .participants
+                        .iter()
+                        .any(|participant| participant == user_key)
+            })
+    }
+
+    /// Writes the schedule to a temporary file and moves it over the old one, so a crash never
+    /// leaves a partly written file behind.
+    fn save(&self, rooms: &[ScheduledRoom]) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let json = serde_json::to_string_pretty(rooms)?;
+        let temp = path.with_extension("tmp");
+        std::fs::write(&temp, json)
+            .with_context(|| format!("Cannot write schedule file {}", temp.display()))?;
+        std::fs::rename(&temp, path)
+            .with_context(|| format!("Cannot replace schedule file {}", path.display()))
+    }
+}
+
+/// Reminds of, starts and ends scheduled rooms as their times come, for as long as the server
+/// runs.
+pub async fn run(state: Arc<ServerState>) {
+    let mut ticks = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        ticks.tick().await;
+        for (event, room) in check(&state) {
+            notify(&state, event, &room).await;
+        }
+    }
+}
+
+/// Starts the rooms whose time came and ends those whose time passed or that were cancelled,
+/// returning the webhook events to send.
+fn check(state: &Arc<ServerState>) -> Vec<(&'static str, ScheduledRoom)> {
+    let schedule = &state.schedule;
+    let reminder_secs = state.config.schedule.reminder_mins.saturating_mul(60);
+    let now = accounts::now_secs();
+    let mut events = Vec::new();
+
+    let mut rooms = schedule.rooms.lock().unwrap();
+    let mut on = schedule.on.lock().unwrap();
+    let mut changed = false;
+    for room in rooms.iter_mut() {
+        if !room.reminded && now < room.starts_at && now + reminder_secs >= room.starts_at {
+            room.reminded = true;
+            changed = true;
+            events.push(("scheduled_room_reminder", room.clone()));
+        }
+        if room.is_on(now) && !on.contains_key(&room.id) {
+            on.insert(room.id.clone(), start(state, room, now));
+            if !room.started {
+                room.started = true;
+                changed = true;
+                events.push(("scheduled_room_started", room.clone()));
+            }
+        }
+    }
+
+    let over: Vec<String> = on
+        .keys()
+        .filter(|id| !rooms.iter().any(|room| room.id == **id && room.is_on(now)))
+        .cloned()
+        .collect();
+    for id in over {
+        let ended = on.remove(&id).unwrap();
+        info!("Scheduled room {id} in '{}' ended", ended.room.room_key);
+        state.registry.release(&ended.room.room_key);
+        if let Some(recording) = ended.recording {
+            let _ = recording.send(());
+        }
+        events.push(("scheduled_room_ended", ended.room));
+    }
+
+    let scheduled = rooms.len();
+    rooms.retain(|room| now < room.ends_at());
+    if (changed || rooms.len() != scheduled)
+        && let Err(err) = schedule.save(&rooms)
+    {
+        warn!("Cannot save the schedule: {err:#}");
+    }
+    events
+}
+
+/// Opens a scheduled room until it ends, and starts recording it if it is to be.
+fn start(state: &Arc<ServerState>, room: &ScheduledRoom, now: u64) -> On {
+    info!("Scheduled room {} in '{}' started", room.id, room.room_key);
+    state.registry.hold(&room.room_key);
+    let recording = (room.record && state.recordings.enabled()).then(|| {
+        let recording = Recording::new(&room.id, room.room_key.clone(), now);
+        Broadcasts::start_recording(state, recording)
+    });
+    On {
+        room: room.clone(),
+        recording,
+    }
+}
+
+async fn notify(state: &ServerState, event: &str, room: &ScheduledRoom) {
+    let Some(url) = &state.config.schedule.webhook_url else {
+        return;
+    };
+    let body = json!({
+        "event": event,
+        "id": room.id,
+        "room": room.room_key,
+        "title": room.title,
+        "starts_at": room.starts_at,
+        "ends_at": room.ends_at(),
+        "participants": room.participants,
+        "record": room.record,
+    });
+    if let Err(err) = http_client::post(url, &body).await {
+        warn!("Cannot send scheduled room webhook: {err:#}");
+    }
+}
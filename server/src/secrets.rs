@@ -0,0 +1,281 @@
+//! Secrets in the config, such as tokens, passwords and keys, loaded from where they are kept
+//! instead of written into the config file.
+//!
+//! Any secret field may hold a reference to the secret instead of the secret itself:
+//!
+//! - `env:<NAME>` reads an environment variable.
+//! - `file:<path>` reads a file, without its trailing newline.
+//! - `vault:<path>#<field>` reads a field of a HashiCorp Vault secret, as in
+//!   `vault:secret/data/voice#trunk_secret`, from the server in `secrets.vault`. Both KV
+//!   version 1 and 2 secrets engines are supported.
+//! - `kms:<ciphertext>` decrypts a base64 ciphertext with AWS KMS, or a compatible service, in
+//!   `secrets.kms`. The plaintext must be UTF-8 text.
+//!
+//! Any other value is the secret itself. References are loaded once at startup, and a secret
+//! that cannot be loaded stops the server from starting. Like the rest of [`http_client`], only
+//! `http://` Vault and KMS endpoints are supported.
+
+use anyhow::Context;
+use anyhow::Result;
+use anyhow::bail;
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use ring::digest;
+use serde_json::Value;
+use serde_json::json;
+
+use crate::config::Config;
+use crate::config::KmsConfig;
+use crate::config::SecretsConfig;
+use crate::config::VaultConfig;
+use crate::http_client;
+use crate::sigv4::Signer;
+use crate::store::BoxFuture;
+
+/// Somewhere secrets are kept.
+pub trait SecretProvider: Send + Sync {
+    /// Loads the secret a reference names, given without the provider's prefix.
+    fn load<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<String>>;
+}
+
+/// Replaces every secret reference in the config with the secret it refers to.
+pub async fn resolve(config: &mut Config) -> Result<()> {
+    let secrets = Secrets::new(&config.secrets).await?;
+
+    let optional = [
+        ("admin.token", &mut config.admin.token),
+        ("cluster.redis_url", &mut config.cluster.redis_url),
+        ("cluster.trunk_secret", &mut config.cluster.trunk_secret),
+        ("events.mqtt_url", &mut config.events.mqtt_url),
+        ("events.nats_url", &mut config.events.nats_url),
+        ("storage.encryption_key", &mut config.storage.encryption_key),
+        ("files.url_secret", &mut config.files.url_secret),
+    ];
+    let mut fields: Vec<(&str, &mut String)> = optional
+        .into_iter()
+        .filter_map(|(field, value)| Some((field, value.as_mut()?)))
+        .collect();
+    fields.push(("matrix.access_token", &mut config.matrix.access_token));
+    if let Some(s3) = &mut config.storage.s3 {
+        fields.push(("storage.s3.access_key_id", &mut s3.access_key_id));
+        fields.push(("storage.s3.secret_access_key", &mut s3.secret_access_key));
+    }
+    for mount in &mut config.broadcast.icecast {
+        fields.push(("broadcast.icecast.url", &mut mount.url));
+    }
+    for peer in &mut config.federation.peers {
+        fields.push(("federation.peers.secret", &mut peer.secret));
+    }
+    for tenant in config.tenants.values_mut() {
+        fields.push(("tenants.api_key", &mut tenant.api_key));
+    }
+
+    for (field, value) in fields {
+        *value = secrets
+            .load(value)
+            .await
+            .with_context(|| format!("Cannot load {field}"))?;
+    }
+    Ok(())
+}
+
+/// Whether `provided` is `secret`, in time that says nothing of how much of it was right. Both
+/// are hashed first, so neither does it say how long the secret is.
+pub fn matches(secret: &str, provided: &str) -> bool {
+    let secret = digest::digest(&digest::SHA256, secret.as_bytes());
+    let provided = digest::digest(&digest::SHA256, provided.as_bytes());
+    let difference = secret
+        .as_ref()
+        .iter()
+        .zip(provided.as_ref())
+        .fold(0, |difference, (a, b)| difference | (a ^ b));
+    std::hint::black_box(difference) == 0
+}
+
+/// The configured providers, by the prefix of the references they load.
+pub struct Secrets {
+    providers: Vec<(&'static str, Box<dyn SecretProvider>)>,
+}
+
+impl Secrets {
+    /// Sets up the providers in `config`. Their own credentials may be `env:` or `file:`
+    /// references.
+    pub async fn new(config: &SecretsConfig) -> Result<Self> {
+        let mut secrets = Self {
+            providers: vec![("env", Box::new(Env)), ("file", Box::new(SecretFile))],
+        };
+        if let Some(vault) = &config.vault {
+            let vault = VaultConfig {
+                address: vault.address.clone(),
+                token: secrets
+                    .load(&vault.token)
+                    .await
+                    .context("Cannot load secrets.vault.token")?,
+            };
+            secrets.providers.push(("vault", Box::new(Vault(vault))));
+        }
+        if let Some(kms) = &config.kms {
+            let kms = KmsConfig {
+                endpoint: kms.endpoint.clone(),
+                region: kms.region.clone(),
+                access_key_id: secrets
+                    .load(&kms.access_key_id)
+                    .await
+                    .context("Cannot load secrets.kms.access_key_id")?,
+                secret_access_key: secrets
+                    .load(&kms.secret_access_key)
+                    .await
+                    .context("Cannot load secrets.kms.secret_access_key")?,
+            };
+            secrets.providers.push(("kms", Box::new(Kms::new(kms)?)));
+        }
+        Ok(secrets)
+    }
+
+    /// The secret a config value refers to, or the value itself if it is not a reference.
+    pub async fn load(&self, value: &str) -> Result<String> {
+        let Some((prefix, name)) = value.split_once(':') else {
+            return Ok(value.to_owned());
+        };
+        match self.providers.iter().find(|(known, _)| *known == prefix) {
+            Some((_, provider)) => provider.load(name).await,
+            None if matches!(prefix, "vault" | "kms") => {
+                bail!("{prefix}: references need secrets.{prefix} to be configured")
+            }
+            None => Ok(value.to_owned()),
+        }
+    }
+}
+
+struct Env;
+
+impl SecretProvider for Env {
+    fn load<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<String>> {
+        Box::pin(async move {
+            std::env::var(name).with_context(|| format!("Cannot read the {name} variable"))
+        })
+    }
+}
+
+struct SecretFile;
+
+impl SecretProvider for SecretFile {
+    fn load<'a>(&'a self, path: &'a str) -> BoxFuture<'a, Result<String>> {
+        Box::pin(async move {
+            let secret = tokio::fs::read_to_string(path)
+                .await
+                .with_context(|| format!("Cannot read {path}"))?;
+            Ok(secret.trim_end_matches(['\r', '\n']).to_owned())
+        })
+    }
+}
+
+struct Vault(VaultConfig);
+
+impl SecretProvider for Vault {
+    fn load<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<String>> {
+        Box::pin(async move {
+            let (path, field) = name
+                .split_once('#')
+                .context("Vault references must be <path>#<field>")?;
+            let url = format!(
+                "{}/v1/{}",
+                self.0.address.trim_end_matches('/'),
+                path.trim_start_matches('/')
+            );
+            let headers = [("X-Vault-Token", self.0.token.as_str())];
+            let response =
+                http_client::send(&url, "GET", &headers, None, http_client::TIMEOUT).await?;
+            if response.status != 200 {
+                bail!("Vault answered {} for {path}", response.status);
+            }
+
+            let body: Value =
+                serde_json::from_slice(&response.body).context("invalid JSON from Vault")?;
+            // KV version 2 nests the secret's fields one level deeper than version 1.
+            let data = &body["data"];
+            let data = if data["data"].is_object() {
+                &data["data"]
+            } else {
+                data
+            };
+            match &data[field] {
+                Value::String(secret) => Ok(secret.clone()),
+                Value::Null => bail!("Vault secret {path} has no field {field}"),
+                other => Ok(other.to_string()),
+            }
+        })
+    }
+}
+
+struct Kms {
+    config: KmsConfig,
+
+    /// The endpoint's `host[:port]`, which is signed as the `host` header.
+    authority: String,
+}
+
+impl Kms {
+    fn new(config: KmsConfig) -> Result<Self> {
+        let authority = config
+            .endpoint
+            .trim_end_matches('/')
+            .strip_prefix("http://")
+            .context("secrets.kms.endpoint must start with http://")?
+            .to_owned();
+        if authority.contains('/') {
+            bail!("secrets.kms.endpoint must not have a path");
+        }
+        Ok(Self { config, authority })
+    }
+}
+
+impl SecretProvider for Kms {
+    fn load<'a>(&'a self, ciphertext: &'a str) -> BoxFuture<'a, Result<String>> {
+        Box::pin(async move {
+            let body = json!({ "CiphertextBlob": ciphertext }).to_string();
+            let signer = Signer {
+                access_key_id: &self.config.access_key_id,
+                secret_access_key: &self.config.secret_access_key,
+                region: &self.config.region,
+                service: "kms",
+            };
+            let signature = signer.sign("POST", &self.authority, "/", body.as_bytes());
+            let mut headers: Vec<(&str, &str)> = signature
+                .iter()
+                .map(|(name, value)| (*name, value.as_str()))
+                .collect();
+            headers.push(("Content-Type", "application/x-amz-json-1.1"));
+            headers.push(("X-Amz-Target", "TrentService.Decrypt"));
+
+            let url = format!("{}/", self.config.endpoint.trim_end_matches('/'));
+            let response = http_client::send(
+                &url,
+                "POST",
+                &headers,
+                Some(body.as_bytes()),
+                http_client::TIMEOUT,
+            )
+            .await?;
+            let body: Value = serde_json::from_slice(&response.body).unwrap_or_default();
+            if response.status != 200 {
+                bail!(
+                    "KMS refused to decrypt with status {}: {}",
+                    response.status,
+                    body["message"]
+                        .as_str()
+                        .or(body["Message"].as_str())
+                        .unwrap_or("")
+                );
+            }
+
+            let plaintext = body["Plaintext"]
+                .as_str()
+                .context("KMS answered without a plaintext")?;
+            let plaintext = BASE64_STANDARD
+                .decode(plaintext)
+                .context("KMS answered an invalid plaintext")?;
+            String::from_utf8(plaintext).context("the decrypted secret is not UTF-8 text")
+        })
+    }
+}
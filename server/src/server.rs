@@ -0,0 +1,383 @@
+//! Starting and stopping the server, and the handles applications embedding it use to follow
+//! and manage it.
+
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use anyhow::Result;
+use protobuf::system;
+use protobuf::system::RoomUser;
+use tokio::sync::broadcast::Receiver;
+use tracing::Instrument;
+use tracing::error;
+use tracing::info;
+use tracing::info_span;
+use wtransport::Identity;
+use wtransport::tls::Sha256Digest;
+
+use crate::broadcast;
+use crate::config::Config;
+use crate::event_bus;
+use crate::events::Event;
+use crate::handoff;
+use crate::http::HttpServer;
+use crate::join;
+use crate::log_sampling;
+use crate::maintenance;
+use crate::matrix;
+use crate::mdns;
+use crate::packet_dump;
+use crate::participant;
+use crate::participant::JoinRefused;
+use crate::participant::Participant;
+use crate::qoe;
+use crate::reload;
+use crate::rooms::SessionId;
+use crate::schedule;
+use crate::secrets;
+use crate::siblings;
+use crate::sip;
+use crate::state::ServerState;
+use crate::systemd;
+use crate::usage;
+use crate::webtransport::WebTransportServer;
+
+/// Starts a [`Server`] from a [`Config`].
+pub struct ServerBuilder {
+    config: Config,
+    dump_packets: bool,
+    print_join_link: bool,
+}
+
+impl ServerBuilder {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            dump_packets: false,
+            print_join_link: false,
+        }
+    }
+
+    /// Logs every control packet sent and received, as the `server` binary does with
+    /// `--dump-packets`.
+    pub fn dump_packets(mut self, enabled: bool) -> Self {
+        self.dump_packets = enabled;
+        self
+    }
+
+    /// Logs the join link once started, and prints it as a QR code if `join.qr_code` is set.
+    pub fn print_join_link(mut self, enabled: bool) -> Self {
+        self.print_join_link = enabled;
+        self
+    }
+
+    /// Resolves the config's secrets, binds the WebTransport and HTTP servers, and starts the
+    /// background tasks and bridges the config asks for. Nothing is served until
+    /// [`Server::run`].
+    pub async fn start(self) -> Result<Server> {
+        if self.dump_packets {
+            packet_dump::enable();
+        }
+        let mut config = self.config;
+        secrets::resolve(&mut config).await?;
+        reload::apply_log_level(&config)?;
+
+        let state = ServerState::new(config)?;
+
+        let listen = &state.config.listen;
+        let identity = match (&listen.certificate_path, &listen.private_key_path) {
+            (Some(certificate), Some(private_key)) => {
+                Identity::load_pemfiles(certificate, private_key)
+                    .await
+                    .context("Cannot load the TLS certificate")?
+            }
+            _ => Identity::self_signed(["localhost", "127.0.0.1", "::1"]).unwrap(),
+        };
+        let cert_digest = identity.certificate_chain().as_slice()[0].hash();
+
+        tokio::spawn(qoe::monitor(state.clone()).instrument(info_span!("QoE monitor")));
+        tokio::spawn(usage::account(state.clone()).instrument(info_span!("Usage accounting")));
+        tokio::spawn(reload::on_hangup(state.clone()).instrument(info_span!("Config reload")));
+        tokio::spawn(log_sampling::summarize().instrument(info_span!("Log sampling")));
+        tokio::spawn(schedule::run(state.clone()).instrument(info_span!("Schedule")));
+        tokio::spawn(maintenance::run(state.clone()).instrument(info_span!("Maintenance")));
+        broadcast::push_to_icecast(&state);
+        matrix::bridge(&state);
+        event_bus::publish(&state);
+        if let Some(listen) = state.config.sip.listen {
+            let bridge = sip::Bridge::bind(listen, state.clone()).await?;
+            tokio::spawn(bridge.serve().instrument(info_span!("SIP bridge")));
+        }
+
+        let mut listeners = systemd::listeners()?;
+        if listeners.udp.is_none()
+            && let Some(port) = listen.port
+        {
+            listeners.udp = Some(siblings::bind_udp(port, listen.reuse_port)?);
+        }
+        if listeners.tcp.is_none() && listen.reuse_port {
+            listeners.tcp = Some(siblings::bind_tcp(HttpServer::PORT)?);
+        }
+        if listen.reuse_port {
+            siblings::serve(&state, &identity, &cert_digest)?;
+        }
+        let webtransport = WebTransportServer::new(identity, state.clone(), listeners.udp)?;
+        let http = HttpServer::new(
+            &cert_digest,
+            webtransport.local_port(),
+            &state,
+            listeners.tcp,
+        )
+        .await?;
+
+        info!(
+            "Open the browser and go to: http://127.0.0.1:{}",
+            http.local_port()
+        );
+        if self.print_join_link {
+            join::print(&state.config, &cert_digest, webtransport.local_port());
+        }
+        let advertiser = if state.config.mdns.enabled {
+            let advertiser = mdns::Advertiser::bind(
+                &state.config.mdns,
+                &cert_digest,
+                webtransport.local_port(),
+                http.local_port(),
+            )?;
+            tokio::spawn(advertiser.clone().serve().instrument(info_span!("mDNS")));
+            Some(advertiser)
+        } else {
+            None
+        };
+
+        Ok(Server {
+            state,
+            cert_digest,
+            webtransport,
+            http,
+            advertiser,
+        })
+    }
+}
+
+/// A started server, serving once [`run`](Self::run).
+pub struct Server {
+    state: Arc<ServerState>,
+    cert_digest: Sha256Digest,
+    webtransport: WebTransportServer,
+    http: HttpServer,
+    advertiser: Option<Arc<mdns::Advertiser>>,
+}
+
+impl Server {
+    /// The UDP port clients connect to over WebTransport.
+    pub fn webtransport_port(&self) -> u16 {
+        self.webtransport.local_port()
+    }
+
+    /// The TCP port of the HTTP server, serving `/config.json`, metrics and the admin API.
+    pub fn http_port(&self) -> u16 {
+        self.http.local_port()
+    }
+
+    /// The digest of the server's certificate, which browsers need to trust a self-signed one.
+    pub fn cert_digest(&self) -> &Sha256Digest {
+        &self.cert_digest
+    }
+
+    /// Every event on the server from now on, as with [`ServerHandle::events`].
+    pub fn events(&self) -> Receiver<Event> {
+        self.state.registry.events().subscribe()
+    }
+
+    /// A handle to follow and manage the server while it runs.
+    pub fn handle(&self) -> ServerHandle {
+        ServerHandle {
+            state: self.state.clone(),
+        }
+    }
+
+    /// Serves until `shutdown` completes or a server fails, then closes every connection.
+    pub async fn run(self, shutdown: impl Future<Output = ()>) -> Result<()> {
+        systemd::notify("READY=1");
+        tokio::spawn(systemd::watchdog());
+
+        tokio::select! {
+            result = self.http.serve() => {
+                error!("HTTP server: {:?}", result);
+            }
+            result = self.webtransport.serve() => {
+                error!("WebTransport server: {:?}", result);
+            }
+            () = shutdown => {
+                info!("Shutting down");
+            }
+            () = self.state.drain.drained(&self.state.registry) => {
+                info!("Shutting down once drained");
+            }
+        }
+
+        systemd::notify("STOPPING=1");
+        if let Some(advertiser) = self.advertiser {
+            advertiser.goodbye().await;
+        }
+        self.webtransport.shutdown().await;
+
+        Ok(())
+    }
+}
+
+/// Follows and manages a running server.
+#[derive(Clone)]
+pub struct ServerHandle {
+    state: Arc<ServerState>,
+}
+
+impl ServerHandle {
+    /// A handle to the room with `room_key`, which need not be open yet. On servers with
+    /// tenants, room keys carry their tenant as in [`Event::room_key`].
+    pub fn room(&self, room_key: &str) -> RoomHandle {
+        RoomHandle {
+            state: self.state.clone(),
+            room_key: room_key.into(),
+        }
+    }
+
+    /// Opens a room on this node with `room_key`, if it is not open, and keeps it open without
+    /// members until [`RoomHandle::release`]d.
+    pub fn create_room(&self, room_key: &str) -> RoomHandle {
+        self.state.registry.hold(room_key);
+        self.room(room_key)
+    }
+
+    /// Handles to every open room on this node.
+    pub fn rooms(&self) -> Vec<RoomHandle> {
+        self.state
+            .registry
+            .rooms()
+            .into_iter()
+            .map(|(room_key, _)| self.room(&room_key))
+            .collect()
+    }
+
+    /// Every session starting and ending, room opening and closing, join, leave, chat message
+    /// and start and end of speech from now on. Subscribers that fall too far behind skip the
+    /// oldest events.
+    pub fn events(&self) -> Receiver<Event> {
+        self.state.registry.events().subscribe()
+    }
+
+    /// Disconnects a session. Returns `false` if there is no such session.
+    pub fn kick(&self, session_id: SessionId) -> bool {
+        self.state.registry.kick(session_id)
+    }
+
+    /// Mutes or unmutes a session's voice. Returns `false` if there is no such session.
+    pub fn set_muted(&self, session_id: SessionId, muted: bool) -> bool {
+        self.state.registry.set_muted(session_id, muted)
+    }
+
+    /// Drains the server for a rolling deploy, sending clients to `address` if given, and has
+    /// [`Server::run`] return once the last session ends or `timeout` elapses. With
+    /// `cluster.trunk_secret` set, sessions in rooms are first handed over to `address` to resume
+    /// there. Returns how many were, or `None` if the server is already draining.
+    pub async fn drain(&self, address: Option<&str>, timeout: Duration) -> Option<usize> {
+        handoff::drain(&self.state, address, timeout).await
+    }
+
+    /// Moves a session in a room to the room with `room_key` on this node, which is opened if
+    /// necessary. The client is sent the new room's users, and the users of both rooms are told.
+    /// Returns `false` if there is no such session, the room belongs to another tenant, or the
+    /// client did not negotiate `FEATURE_ROOM_MOVES`.
+    pub fn move_session(&self, session_id: SessionId, room_key: &str) -> bool {
+        self.state.registry.move_session(session_id, room_key)
+    }
+}
+
+/// A room on a running server.
+#[derive(Clone)]
+pub struct RoomHandle {
+    state: Arc<ServerState>,
+    room_key: Arc<str>,
+}
+
+impl RoomHandle {
+    pub fn key(&self) -> &str {
+        &self.room_key
+    }
+
+    /// Lets the room close once it has no members, after [`ServerHandle::create_room`].
+    pub fn release(&self) {
+        self.state.registry.release(&self.room_key);
+    }
+
+    /// The users in the room on this node, which is none if it is not open.
+    pub fn users(&self) -> Vec<RoomUser> {
+        self.state
+            .registry
+            .room_roster(&self.room_key)
+            .into_iter()
+            .map(|member| RoomUser {
+                session_id: member.session_id,
+                username: member.username,
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    /// Sends a chat message to everyone in the room as `username`, unless a plugin refuses it,
+    /// returning the reason if one does.
+    pub async fn send_chat(&self, username: &str, text: &str) -> Result<(), String> {
+        let message = system::ChatMessage {
+            username: username.to_owned(),
+            text: text.to_owned(),
+            ..Default::default()
+        };
+        self.state.send_chat(&self.room_key, message).map(drop)
+    }
+
+    /// Joins the room as `username`, to play audio into it, such as announcements. The room's
+    /// users see the announcer as any other user, until it is dropped.
+    pub async fn announcer(&self, username: &str) -> Result<Announcer, JoinRefused> {
+        let participant =
+            Participant::join(&self.state, username, &self.room_key, Arc::default()).await?;
+        Ok(Announcer {
+            state: self.state.clone(),
+            participant,
+        })
+    }
+}
+
+/// A user the application plays audio into a room as, from [`RoomHandle::announcer`].
+pub struct Announcer {
+    state: Arc<ServerState>,
+    participant: Participant,
+}
+
+impl Announcer {
+    /// Samples in each frame of audio, which is 16-bit PCM at 48 kHz.
+    pub const FRAME_SAMPLES: usize = participant::FRAME_SAMPLES;
+
+    /// How long each frame of audio lasts.
+    pub const FRAME_INTERVAL: Duration = participant::FRAME_INTERVAL;
+
+    pub fn session_id(&self) -> SessionId {
+        self.participant.session_id()
+    }
+
+    /// Sends a frame of [`FRAME_SAMPLES`](Self::FRAME_SAMPLES) samples to the room now.
+    pub fn speak(&self, frame: &[i16]) {
+        self.participant.speak(&self.state, frame);
+    }
+
+    /// Sends audio of any length to the room a frame at a time, at the pace it plays at.
+    pub async fn play(&self, pcm: &[i16]) {
+        let mut ticks = tokio::time::interval(Self::FRAME_INTERVAL);
+        for frame in pcm.chunks(Self::FRAME_SAMPLES) {
+            ticks.tick().await;
+            self.speak(frame);
+        }
+    }
+}
@@ -0,0 +1,2299 @@
+//! Per-connection protocol handling.
+
+use std::collections::BTreeSet;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::task::Poll;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::Result;
+use bytes::Bytes;
+use protobuf::system;
+use protobuf::system::CloseCode;
+use protobuf::system::Feature;
+use protobuf::system::PacketType;
+use protobuf::system::auth_response_error;
+use protobuf::system::error;
+use protobuf::system::hello_error;
+use tokio::io::AsyncReadExt;
+use tokio::sync::Semaphore;
+use tokio::sync::mpsc;
+use tokio::sync::oneshot;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tracing::Instrument;
+use tracing::Level;
+use tracing::Span;
+use tracing::debug;
+use tracing::enabled;
+use tracing::field;
+use tracing::info;
+use tracing::warn;
+
+use crate::accounts;
+use crate::accounts::LoginError;
+use crate::accounts::Role;
+use crate::avatars;
+use crate::bitrate::UserBitrate;
+use crate::bitrate::Verdict;
+use crate::buffer_pool::PooledBuffer;
+#[cfg(feature = "chaos")]
+use crate::chaos;
+use crate::drain;
+use crate::events::Event;
+use crate::files;
+use crate::files::Uploader;
+use crate::fragment::FragmentError;
+use crate::fragment::Reassembler;
+use crate::handoff::Resume;
+use crate::latency;
+use crate::lifecycle::Phase;
+use crate::lifecycle::Transition;
+use crate::lobby::Ticket;
+use crate::log_sampling;
+use crate::media;
+use crate::metrics::METRICS;
+use crate::metrics::SessionMetrics;
+use crate::outbox::Outbox;
+use crate::packet_dump;
+use crate::protocol;
+use crate::protocol::ClientPacket;
+use crate::protocol::VoiceFrame;
+use crate::protocol::VoiceHeader;
+use crate::qoe;
+use crate::rate_limit::RateLimiter;
+use crate::rooms::DirectError;
+use crate::rooms::JoinError;
+use crate::rooms::Joined;
+use crate::rooms::Link;
+use crate::rooms::RegisterError;
+use crate::rooms::Registration;
+use crate::rooms::SessionId;
+use crate::signaling::Socket;
+use crate::slow_consumer::Change;
+use crate::slow_consumer::SlowConsumer;
+use crate::state::ServerState;
+use crate::stats;
+use crate::stats::StatsWindow;
+use crate::tenants;
+use crate::tenants::Scoped;
+use crate::transport::RecvStream;
+use crate::transport::Transport;
+
+/// Per-connection protocol state.
+pub struct Session {
+    session_id: SessionId,
+    connection: Arc<dyn Transport>,
+    outbox: Outbox,
+    state: Arc<ServerState>,
+    phase: Phase,
+    hello: Option<system::HelloAck>,
+    registration: Option<Registration>,
+    reassembler: Reassembler,
+    metrics: Arc<SessionMetrics>,
+    stats: StatsWindow,
+    slow_consumer: SlowConsumer,
+    bitrate: UserBitrate,
+    last_activity: Instant,
+    reactions: RateLimiter,
+
+    /// When the client last sent a forwarded voice frame, while it is speaking.
+    last_spoke: Option<Instant>,
+
+    /// The rooms the session is moved to, once authenticated if the client can be moved.
+    moves: Option<mpsc::Receiver<Arc<str>>>,
+
+    /// Whether the session's account is a moderator's or an admin's.
+    moderator: bool,
+
+    /// The session's place in the lobby of the room it waits to join, if it waits to join one.
+    waiting: Option<Ticket>,
+
+    /// Changes when the server starts draining.
+    drain: watch::Receiver<Option<drain::Target>>,
+
+    /// The session a draining server handed over, if the client resumed one, until it rejoins
+    /// its room.
+    resumed: Option<Resume>,
+
+    /// Permits for uploads in progress.
+    uploads: Arc<Semaphore>,
+
+    /// Permits for media tracks being relayed.
+    tracks: Arc<Semaphore>,
+
+    /// The bytes sent and received that have been added to the tenant's usage.
+    accounted_bytes: (u64, u64),
+
+    /// The client's address, which changes when QUIC migrates the connection to a new path.
+    remote_address: SocketAddr,
+
+    /// The connection's span, which records the session's user, room and address as they become
+    /// known, so every line the session logs carries them.
+    span: Span,
+
+    /// How many times the connection has changed paths.
+    path_changes: u32,
+
+    /// The ID of the last PING sent.
+    last_ping_id: u32,
+
+    /// PINGs sent since the last one the client answered.
+    unanswered_pings: u32,
+
+    /// The WebSocket offered to carry the session's control packets, until the client opens it.
+    signaling: Option<oneshot::Receiver<Socket>>,
+
+    /// The control packets the client sends on its WebSocket, while it is open.
+    signaling_packets: Option<mpsc::Receiver<Bytes>>,
+
+    /// The streams the client opened, each read by a task of its own, in the order they were
+    /// opened.
+    streams: VecDeque<Reading>,
+
+    /// Streams still being read [`Session::STREAM_ORDER_GRACE`] after they were opened, handled
+    /// whenever they finish so that they hold up none opened after them.
+    stalled: Vec<JoinHandle<StreamRead>>,
+}
+
+/// A stream the client opened, being read.
+struct Reading {
+    opened: Instant,
+    read: JoinHandle<StreamRead>,
+}
+
+/// What a stream the client opened turned out to carry.
+enum StreamRead {
+    /// A control packet or a voice frame, the buffer's first bytes up to the length.
+    Packet(PooledBuffer, usize),
+
+    /// The start of a file upload, with the stream the rest arrives on.
+    Upload(Box<dyn RecvStream>, Vec<u8>),
+
+    /// The start of a media track, with the stream the rest arrives on.
+    Track(Box<dyn RecvStream>, Vec<u8>),
+
+    /// A control packet longer than [`Session::MAX_STREAM_PACKET_LEN`].
+    Oversized,
+
+    /// The stream was reset, or not finished within [`Session::STREAM_READ_TIMEOUT`].
+    Failed,
+}
+
+impl Session {
+    /// How long to wait for the client to read a final packet before closing.
+    const FINAL_PACKET_GRACE: Duration = Duration::from_secs(1);
+
+    /// The largest control packet accepted over a stream.
+    pub const MAX_STREAM_PACKET_LEN: usize = 65536;
+
+    /// How long a client has to finish a stream carrying a control packet once it opened it.
+    const STREAM_READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// How many streams may be read or waiting to be handled at once, in the order they were
+    /// opened. The client cannot open more until some are handled.
+    const MAX_QUEUED_STREAMS: usize = 64;
+
+    /// How long a stream keeps its place in line while it is read. One taking longer is set
+    /// aside, so that a slow stream does not keep the control packets opened after it waiting.
+    const STREAM_ORDER_GRACE: Duration = Duration::from_millis(500);
+
+    /// How many streams may be set aside at once, besides those queued. Further slow streams
+    /// keep their place in line until some of these finish.
+    const MAX_STALLED_STREAMS: usize = 16;
+
+    /// How long a speaking client can go without sending voice before it stops speaking.
+    const SPEAKING_HANGOVER: Duration = Duration::from_millis(500);
+
+    pub fn new(connection: Arc<dyn Transport>, state: Arc<ServerState>) -> Self {
+        let session_id = rand::random_range(1..SessionId::MAX);
+        let remote_address = connection.remote_address();
+        let location = state.geoip.locate(remote_address.ip());
+        let span = Span::current();
+        if let Some(country) = &location.country {
+            span.record("country", field::display(country));
+        }
+        if let Some(asn) = location.asn {
+            span.record("asn", asn);
+        }
+        let metrics = Arc::new(SessionMetrics {
+            location,
+            ..Default::default()
+        });
+        METRICS.track_session(session_id, metrics.clone());
+        let config = state.live_config();
+        let drain = state.drain.subscribe();
+        let reactions =
+            RateLimiter::new(config.session.reaction_rate, config.session.reaction_burst);
+
+        Self {
+            session_id,
+            outbox: Outbox::new(connection.clone(), &config.session, metrics.drops.clone()),
+            connection,
+            state,
+            phase: Phase::Handshaking,
+            hello: None,
+            registration: None,
+            reassembler: Reassembler::new(Self::MAX_STREAM_PACKET_LEN),
+            metrics,
+            stats: StatsWindow::default(),
+            slow_consumer: SlowConsumer::default(),
+            bitrate: UserBitrate::new(&config.session),
+            last_activity: Instant::now(),
+            reactions,
+            last_spoke: None,
+            moves: None,
+            moderator: false,
+            waiting: None,
+            drain,
+            resumed: None,
+            uploads: Arc::new(Semaphore::new(files::MAX_CONCURRENT_UPLOADS)),
+            tracks: Arc::new(Semaphore::new(media::MAX_TRACKS_PER_SESSION)),
+            accounted_bytes: (0, 0),
+            remote_address,
+            span,
+            path_changes: 0,
+            last_ping_id: 0,
+            unanswered_pings: 0,
+            signaling: None,
+            signaling_packets: None,
+            streams: VecDeque::new(),
+            stalled: Vec::new(),
+        }
+    }
+
+    /// Runs the session until the connection ends.
+    ///
+    /// The session leaves the registry when it is dropped, so cleanup happens on every exit
+    /// path, including errors and task cancellation.
+    pub async fn run(mut self) -> Result<()> {
+        let idle_timeout = self.state.config.session.idle_timeout();
+        let mut clock_sync = tokio::time::interval(self.state.config.session.clock_sync_interval());
+        let mut stats = tokio::time::interval(stats::STATS_INTERVAL);
+        let pings_enabled = self.state.config.session.ping_interval_secs > 0;
+        let mut pings = tokio::time::interval(self.state.config.session.ping_interval());
+
+        info!("Waiting for data from client...");
+
+        loop {
+            tokio::select! {
+                stream = self.connection.accept_uni(),
+                    if self.streams.len() < Self::MAX_QUEUED_STREAMS =>
+                {
+                    let stream = stream?;
+                    self.last_activity = Instant::now();
+                    // Read elsewhere, so that a client slow to finish a stream holds up none of
+                    // the rest of the session.
+                    let buffer = self.state.buffers.acquire();
+                    self.streams.push_back(Reading {
+                        opened: Instant::now(),
+                        read: tokio::spawn(read_stream(stream, buffer).in_current_span()),
+                    });
+                }
+                read = next_stream(&mut self.streams, &mut self.stalled) => {
+                    self.last_activity = Instant::now();
+                    match read {
+                        StreamRead::Packet(buffer, len) => {
+                            // A voice frame from a client that cannot send it as a datagram.
+                            if buffer[..len].first() == Some(&protocol::VOICE_PACKET_PREFIX) {
+                                self.forward_voice(Bytes::copy_from_slice(&buffer[..len]));
+                                continue;
+                            }
+                            if !self.handle_control(&buffer[..len]).await? {
+                                return Ok(());
+                            }
+                        }
+                        StreamRead::Upload(stream, received) => {
+                            self.start_upload(stream, received).await?;
+                        }
+                        StreamRead::Track(stream, received) => {
+                            self.start_track(stream, received).await?;
+                        }
+                        StreamRead::Oversized => {
+                            warn!(
+                                "Control packet exceeds {} bytes",
+                                Self::MAX_STREAM_PACKET_LEN
+                            );
+                            self.metrics.drops.count_oversized();
+                            protocol::close(&*self.connection, CloseCode::ProtocolViolation);
+                            return Ok(());
+                        }
+                        StreamRead::Failed => {}
+                    }
+                }
+                dgram = self.connection.receive_datagram() => {
+                    let dgram = dgram?;
+                    self.last_activity = Instant::now();
+
+                    if dgram.first() == Some(&protocol::VOICE_PACKET_PREFIX) {
+                        self.forward_voice(dgram);
+                        continue;
+                    }
+
+                    if !self.handle_control(&dgram).await? {
+                        return Ok(());
+                    }
+                }
+                _ = clock_sync.tick(), if self.has_feature(Feature::LatencyReports) => {
+                    self.sync_clock().await?;
+                }
+                _ = pings.tick(), if pings_enabled && self.has_feature(Feature::Pings) => {
+                    if !self.ping().await? {
+                        return Ok(());
+                    }
+                }
+                _ = stats.tick() => {
+                    let report = self.stats.report(&*self.connection, &self.outbox);
+                    self.metrics.qoe.record(qoe::score(&report));
+                    self.metrics
+                        .audio_on_streams
+                        .store(report.audio_on_streams, Ordering::Relaxed);
+                    self.account_bandwidth();
+                    self.check_path();
+                    self.check_slow_consumer(report.forwarded_speakers as usize).await?;
+                    if !self.check_bitrate().await? {
+                        return Ok(());
+                    }
+                    #[cfg(feature = "chaos")]
+                    if chaos::close_session(&self.state.live_config().chaos) {
+                        warn!("Chaos: closing session {}", self.session_id);
+                        protocol::close(&*self.connection, CloseCode::ServerShutdown);
+                        return Ok(());
+                    }
+                    if self.has_feature(Feature::SessionStats) {
+                        self.send(protocol::encode(PacketType::SessionStats, &report)).await?;
+                    }
+                }
+                _ = tokio::time::sleep_until((self.last_activity + idle_timeout).into()) => {
+                    info!("Closing idle session {}", self.session_id);
+                    self.close_with_error(
+                        error::Code::IdleTimeout,
+                        format!("no traffic for {} seconds", idle_timeout.as_secs()),
+                        CloseCode::IdleTimeout,
+                    )
+                    .await?;
+                    return Ok(());
+                }
+                _ = tokio::time::sleep_until(
+                    (self.last_spoke.unwrap_or_else(Instant::now) + Self::SPEAKING_HANGOVER).into()
+                ), if self.last_spoke.is_some() => {
+                    self.stop_speaking();
+                }
+                socket = next_socket(&mut self.signaling) => {
+                    self.signaling = None;
+                    if let Some(socket) = socket {
+                        info!("The client's control packets moved to a WebSocket");
+                        let (packets, outgoing) = socket.start(Self::MAX_STREAM_PACKET_LEN);
+                        self.outbox.send_control_to(outgoing);
+                        self.signaling_packets = Some(packets);
+                    }
+                }
+                packet = next_signal(&mut self.signaling_packets) => match packet {
+                    Some(packet) => {
+                        self.last_activity = Instant::now();
+                        if !self.handle_control(&packet).await? {
+                            return Ok(());
+                        }
+                    }
+                    None => {
+                        info!("The client's WebSocket closed; control packets go over WebTransport");
+                        self.signaling_packets = None;
+                    }
+                },
+                Some(room_key) = next_move(&mut self.moves) => {
+                    self.handle_move(room_key).await?;
+                }
+                admitted = next_admission(&mut self.waiting) => {
+                    self.handle_admission(admitted).await?;
+                }
+                Ok(()) = self.drain.changed() => {
+                    self.migrate().await?;
+                }
+            }
+        }
+    }
+
+    /// Handles a control packet. Returns `false` if the session has ended.
+    async fn handle_control(&mut self, data: &[u8]) -> Result<bool> {
+        #[cfg(feature = "chaos")]
+        if chaos::drop_control(&self.state.live_config().chaos) {
+            warn!(
+                "Chaos: dropping a control packet from session {}",
+                self.session_id
+            );
+            return Ok(true);
+        }
+        let reassembled;
+        let data = match data.split_first() {
+            Some((&packet_type, payload)) if packet_type == PacketType::Fragment as u8 => {
+                match self.reassembler.push(payload) {
+                    Ok(Some(packet)) => {
+                        reassembled = packet;
+                        &reassembled[..]
+                    }
+                    Ok(None) => return Ok(true),
+                    Err(err) => {
+                        match err {
+                            FragmentError::TooLarge => self.metrics.drops.count_oversized(),
+                            _ => self.metrics.drops.count_malformed(),
+                        }
+                        self.reject(
+                            error::Code::MalformedPacket,
+                            err.to_string(),
+                            Some(PacketType::Fragment),
+                        )
+                        .await?;
+                        return Ok(true);
+                    }
+                }
+            }
+            _ => data,
+        };
+        packet_dump::received(data);
+
+        if self.phase == Phase::Handshaking {
+            return self.handle_handshake(data).await;
+        }
+
+        let packet = match ClientPacket::decode(data) {
+            Ok(packet) => packet,
+            Err(err) => {
+                if err.code() == error::Code::MalformedPacket {
+                    self.metrics.drops.count_malformed();
+                }
+                self.reject(err.code(), err.to_string(), err.packet_type())
+                    .await?;
+                return Ok(true);
+            }
+        };
+
+        if let Err(refusal) = self.phase.admit(packet.packet_type()) {
+            self.reject(refusal.code, refusal.detail, Some(packet.packet_type()))
+                .await?;
+            return Ok(true);
+        }
+
+        if let Err(reason) = self.state.plugins.on_packet(self.session_id, &packet) {
+            return self
+                .reject(
+                    error::Code::PermissionDenied,
+                    reason,
+                    Some(packet.packet_type()),
+                )
+                .await
+                .map(|()| true);
+        }
+
+        match packet {
+            // Not admitted after the handshake.
+            ClientPacket::Hello(_) => {}
+            ClientPacket::AuthRequest(request) => self.handle_auth(request).await?,
+            ClientPacket::JoinRoomRequest(request) => self.handle_join(request).await?,
+            ClientPacket::ClockSync(reply) => {
+                self.metrics
+                    .latency
+                    .record_clock_sync(&reply, latency::now_us());
+            }
+            ClientPacket::PlayoutReport(report) => {
+                self.metrics
+                    .latency
+                    .playout_delay
+                    .record(report.playout_delay_us.into());
+            }
+            ClientPacket::ChatMessage(message) => self.handle_chat(message).await?,
+            ClientPacket::Reaction(reaction) => self.handle_reaction(reaction).await?,
+            ClientPacket::ChatBacklogRequest(request) => self.handle_chat_backlog(request).await?,
+            ClientPacket::DirectMessage(message) => self.handle_direct_message(message).await?,
+            ClientPacket::BlockList(list) => self.handle_block_list(list).await?,
+            ClientPacket::LobbyDecision(decision) => self.handle_lobby_decision(decision).await?,
+            ClientPacket::SlowMode(slow_mode) => self.handle_slow_mode(slow_mode).await?,
+            ClientPacket::Pong(pong) => {
+                // Answers to PINGs that were already answered, or never sent, change nothing.
+                let sent_after = self.last_ping_id.wrapping_sub(pong.id);
+                self.unanswered_pings = self.unanswered_pings.min(sent_after);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Handles the first control packet, which must be a HELLO.
+    async fn handle_handshake(&mut self, data: &[u8]) -> Result<bool> {
+        let hello = match ClientPacket::decode(data) {
+            Ok(ClientPacket::Hello(hello)) => hello,
+            other => {
+                let reason = match other {
+                    Ok(packet) => packet.packet_type().as_str_name().to_owned(),
+                    Err(err) => {
+                        if err.code() == error::Code::MalformedPacket {
+                            self.metrics.drops.count_malformed();
+                        }
+                        err.to_string()
+                    }
+                };
+                info!("Refusing packet before handshake: {reason}");
+                let refusal = system::HelloError {
+                    r#type: hello_error::Type::HandshakeRequired.into(),
+                    min_protocol_version: protocol::MIN_PROTOCOL_VERSION,
+                    max_protocol_version: protocol::PROTOCOL_VERSION,
+                };
+                self.refuse(&refusal, CloseCode::ProtocolViolation).await?;
+                return Ok(false);
+            }
+        };
+
+        let max_datagram_size = self
+            .connection
+            .max_datagram_size()
+            .unwrap_or(protocol::MAX_DATAGRAM_SIZE);
+        match protocol::negotiate(&hello, max_datagram_size) {
+            Ok(mut ack) => {
+                info!(
+                    "Negotiated protocol version {} (features: {:#x}, session_id: {})",
+                    ack.protocol_version, ack.features, self.session_id
+                );
+                if ack.features & Feature::WebsocketSignaling as u32 != 0 {
+                    let (token, socket) = self.state.signaling.offer();
+                    ack.signaling_token = token;
+                    self.signaling = Some(socket);
+                }
+                self.send(protocol::encode(PacketType::HelloAck, &ack))
+                    .await?;
+                self.hello = Some(ack);
+                self.advance(Transition::Negotiate);
+                if self.has_feature(Feature::MediaStreams) {
+                    self.outbox
+                        .enable_media(self.connection.clone(), &self.state.config.session);
+                }
+                if !self.has_feature(Feature::VoiceDatagrams) {
+                    self.outbox.send_audio_on_streams();
+                }
+                if self.has_feature(Feature::AudioHeader) {
+                    self.outbox.send_audio_header();
+                }
+                if self.has_feature(Feature::Compression) {
+                    self.outbox.compress_control();
+                }
+                Ok(true)
+            }
+            Err(refusal) => {
+                info!(
+                    "Refusing client protocol versions {}..={}",
+                    hello.min_protocol_version, hello.protocol_version
+                );
+                self.refuse(&refusal, CloseCode::UnsupportedVersion).await?;
+                Ok(false)
+            }
+        }
+    }
+
+    async fn handle_auth(&mut self, request: system::AuthRequest) -> Result<()> {
+        let resumed = match request.resume_token.as_str() {
+            "" => None,
+            token => match self.state.handoffs.take(token) {
+                Some(resumed) => Some(resumed),
+                None => {
+                    info!("Rejecting an unknown or expired resume token");
+                    return self
+                        .send(protocol::encode(
+                            PacketType::AuthResponseError,
+                            &system::AuthResponseError {
+                                r#type: auth_response_error::Type::InvalidCredentials.into(),
+                            },
+                        ))
+                        .await;
+                }
+            },
+        };
+        let granted = match request.session_token.as_str() {
+            "" => None,
+            token => match self.state.session_tokens.take(token) {
+                Some(granted) => Some(granted),
+                None => {
+                    info!("Rejecting an unknown or expired session token");
+                    return self
+                        .send(protocol::encode(
+                            PacketType::AuthResponseError,
+                            &system::AuthResponseError {
+                                r#type: auth_response_error::Type::InvalidCredentials.into(),
+                            },
+                        ))
+                        .await;
+                }
+            },
+        };
+        let (tenant, username) = match (&resumed, &granted) {
+            (Some(resumed), _) => (resumed.tenant.as_str(), resumed.username.as_str()),
+            (None, Some(granted)) => (granted.tenant.as_str(), granted.username.as_str()),
+            (None, None) => (request.tenant.as_str(), request.username.as_str()),
+        };
+
+        let tenant = match self.state.config.tenants.get_key_value(tenant) {
+            Some((tenant, _)) => Some(tenant.as_str()),
+            None if self.state.config.tenants.is_empty() => None,
+            None => {
+                info!("Rejecting login to unknown tenant '{tenant}'");
+                return self
+                    .send(protocol::encode(
+                        PacketType::AuthResponseError,
+                        &system::AuthResponseError {
+                            r#type: auth_response_error::Type::UnknownTenant.into(),
+                        },
+                    ))
+                    .await;
+            }
+        };
+        let user = Scoped {
+            tenant,
+            name: username,
+        };
+
+        // The server that handed the session over, or `POST /session`, already checked the
+        // credentials.
+        if let Some(accounts) = &self.state.accounts
+            && resumed.is_none()
+            && granted.is_none()
+        {
+            let register = self.state.config.accounts.allow_registration;
+            match accounts::login(accounts.as_ref(), &user.key(), &request.token, register).await? {
+                Ok(_) => {}
+                Err(LoginError::InvalidCredentials) => {
+                    info!("Rejecting login for '{username}'");
+                    return self
+                        .send(protocol::encode(
+                            PacketType::AuthResponseError,
+                            &system::AuthResponseError {
+                                r#type: auth_response_error::Type::InvalidCredentials.into(),
+                            },
+                        ))
+                        .await;
+                }
+                Err(LoginError::Banned(ban)) => {
+                    info!("Refusing banned user '{username}'");
+                    return self
+                        .close_with_error(
+                            error::Code::PermissionDenied,
+                            format!("banned: {}", ban.reason),
+                            CloseCode::Banned,
+                        )
+                        .await;
+                }
+            }
+        }
+
+        let result = self.state.registry.register(
+            self.session_id,
+            tenant,
+            username,
+            self.outbox.clone(),
+            Link::WebTransport(self.connection.clone()),
+        );
+
+        let error_type = match result {
+            Ok(registration) => {
+                info!(
+                    "Authenticated '{username}' (session_id: {}{})",
+                    self.session_id,
+                    if resumed.is_some() { ", resumed" } else { "" }
+                );
+                if self.has_feature(Feature::RoomMoves) {
+                    self.moves = Some(registration.accept_moves());
+                }
+                if self.has_feature(Feature::DirectMessages) {
+                    registration.accept_direct_messages();
+                }
+                let mut blocked = Vec::new();
+                if let Some(accounts) = &self.state.accounts
+                    && let Some(account) = accounts.get(&user.key()).await?
+                {
+                    registration.set_blocked(account.blocked.iter().cloned().collect());
+                    blocked = account.blocked.into_iter().collect();
+                    self.moderator = account.role >= Role::Moderator;
+                }
+                if self.moderator && self.has_feature(Feature::Lobby) {
+                    registration.moderate_lobby();
+                }
+                self.span.record("session_id", self.session_id);
+                self.span.record("user", username);
+                self.registration = Some(registration);
+                self.resumed = resumed;
+                self.advance(Transition::Authenticate);
+                self.send(protocol::encode(
+                    PacketType::AuthResponseSuccess,
+                    &system::AuthResponseSuccess {
+                        session_id: self.session_id,
+                        blocked,
+                    },
+                ))
+                .await?;
+                if self.state.maintenance.is_scheduled() {
+                    let notice = self.state.maintenance.notice();
+                    self.send(protocol::encode(PacketType::Maintenance, &notice))
+                        .await?;
+                }
+                return Ok(());
+            }
+            Err(RegisterError::InvalidUsername) => auth_response_error::Type::InvalidCredentials,
+            Err(RegisterError::UsernameTaken) => auth_response_error::Type::AlreadyLoggedIn,
+        };
+
+        info!("Rejecting authentication: {error_type:?}");
+        self.send(protocol::encode(
+            PacketType::AuthResponseError,
+            &system::AuthResponseError {
+                r#type: error_type.into(),
+            },
+        ))
+        .await
+    }
+
+    async fn handle_join(&mut self, request: system::JoinRoomRequest) -> Result<()> {
+        // Asking to join any room stops waiting to join another.
+        self.waiting = None;
+        let tenant = self.registration.as_ref().and_then(Registration::tenant);
+        let room_key = tenants::key(tenant, &request.room_key);
+        let redirect = self.redirect(&room_key);
+        // Admitted only once authenticated.
+        let Some(registration) = &mut self.registration else {
+            return Ok(());
+        };
+
+        if let Some(address) = redirect {
+            info!(
+                "Redirecting session {} to {address} for room '{room_key}'",
+                self.session_id
+            );
+            return self
+                .send(protocol::encode(
+                    PacketType::JoinRoomRedirect,
+                    &system::JoinRoomRedirect {
+                        room_key: request.room_key,
+                        address,
+                        resume_token: String::new(),
+                    },
+                ))
+                .await;
+        }
+
+        let cutoff = Duration::from_secs(
+            self.state
+                .config
+                .maintenance
+                .join_cutoff_mins
+                .saturating_mul(60),
+        );
+        if let Some(secs_left) = self.state.maintenance.closing(cutoff) {
+            return self
+                .reject(
+                    error::Code::Maintenance,
+                    format!("the server shuts down for maintenance in {secs_left} seconds"),
+                    Some(PacketType::JoinRoomRequest),
+                )
+                .await;
+        }
+
+        let max_members = self.state.live_config().session.max_room_members;
+        if registration.can_join(&request.room_key).is_ok()
+            && max_members
+                .is_some_and(|max| self.state.registry.room_members(&room_key).len() >= max)
+        {
+            return self
+                .reject(
+                    error::Code::RoomFull,
+                    format!("room '{}' is full", request.room_key),
+                    Some(PacketType::JoinRoomRequest),
+                )
+                .await;
+        }
+
+        let user_key = tenants::key(registration.tenant(), registration.username());
+        if registration.can_join(&request.room_key).is_ok()
+            && !self.state.schedule.admits(&room_key, &user_key)
+        {
+            return self
+                .reject(
+                    error::Code::PermissionDenied,
+                    format!("room '{}' is booked for others", request.room_key),
+                    Some(PacketType::JoinRoomRequest),
+                )
+                .await;
+        }
+
+        // Plugins are only asked about joins that would otherwise succeed.
+        if registration.can_join(&request.room_key).is_ok()
+            && let Err(reason) = self.state.plugins.on_join(&registration.user(), &room_key)
+        {
+            return self
+                .reject(
+                    error::Code::PermissionDenied,
+                    reason,
+                    Some(PacketType::JoinRoomRequest),
+                )
+                .await;
+        }
+
+        if registration.can_join(&request.room_key).is_ok()
+            && !self.moderator
+            && self.state.config.lobby.rooms.contains(&room_key)
+        {
+            return self.wait_in_lobby(&room_key, request.room_key).await;
+        }
+
+        let code = match registration.join(&request.room_key) {
+            Ok(joined) => {
+                if let Some(resumed) = self.resumed.take()
+                    && resumed.muted
+                    && resumed.room_key == room_key
+                {
+                    self.state.registry.set_muted(self.session_id, true);
+                }
+                self.enter(&room_key, request.room_key, joined).await?;
+                self.advance(Transition::Join);
+                return Ok(());
+            }
+            Err(JoinError::InvalidRoomKey) => error::Code::InvalidRoom,
+            Err(JoinError::AlreadyInRoom) => error::Code::AlreadyInRoom,
+        };
+
+        self.reject(
+            code,
+            format!("cannot join room '{}'", request.room_key),
+            Some(PacketType::JoinRoomRequest),
+        )
+        .await
+    }
+
+    /// Puts the session in the lobby of the room with `room_key`, which the client calls `name`,
+    /// until a moderator admits or refuses it.
+    async fn wait_in_lobby(&mut self, room_key: &str, name: String) -> Result<()> {
+        if !self.has_feature(Feature::Lobby) {
+            return self
+                .reject(
+                    error::Code::PermissionDenied,
+                    format!("room '{name}' has a waiting room"),
+                    Some(PacketType::JoinRoomRequest),
+                )
+                .await;
+        }
+        let Some(registration) = &self.registration else {
+            return Ok(());
+        };
+        let username = registration.username().to_owned();
+        info!(
+            "Session {} waits to join room '{room_key}'",
+            self.session_id
+        );
+        let ticket = self
+            .state
+            .lobby
+            .wait(room_key, &name, self.session_id, &username);
+        self.waiting = Some(ticket);
+        self.send(protocol::encode(
+            PacketType::LobbyWaiting,
+            &system::LobbyWaiting { room_key: name },
+        ))
+        .await
+    }
+
+    /// Joins the room the session waited to join if a moderator admitted it, and tells the
+    /// client it was refused otherwise.
+    async fn handle_admission(&mut self, admitted: bool) -> Result<()> {
+        let Some(ticket) = self.waiting.take() else {
+            return Ok(());
+        };
+        let (room_key, name) = (ticket.room_key().to_owned(), ticket.name().to_owned());
+        drop(ticket);
+        if !admitted {
+            info!(
+                "Session {} was not admitted to room '{room_key}'",
+                self.session_id
+            );
+            return self
+                .reject(
+                    error::Code::PermissionDenied,
+                    format!("not admitted to room '{name}'"),
+                    Some(PacketType::JoinRoomRequest),
+                )
+                .await;
+        }
+
+        let Some(registration) = &mut self.registration else {
+            return Ok(());
+        };
+        match registration.join(&name) {
+            Ok(joined) => {
+                self.enter(&room_key, name, joined).await?;
+                self.advance(Transition::Join);
+                Ok(())
+            }
+            Err(err) => {
+                warn!(
+                    "Cannot admit session {} to room '{room_key}': {err:?}",
+                    self.session_id
+                );
+                self.reject(
+                    error::Code::InvalidRoom,
+                    format!("cannot join room '{name}'"),
+                    Some(PacketType::JoinRoomRequest),
+                )
+                .await
+            }
+        }
+    }
+
+    async fn handle_lobby_decision(&mut self, decision: system::LobbyDecision) -> Result<()> {
+        // Admitted only in a room.
+        let Some(room_key) = self
+            .registration
+            .as_ref()
+            .and_then(Registration::room_key)
+            .cloned()
+        else {
+            return Ok(());
+        };
+        if !self.moderator || !self.has_feature(Feature::Lobby) {
+            return self
+                .reject(
+                    error::Code::PermissionDenied,
+                    "only moderators may admit users",
+                    Some(PacketType::LobbyDecision),
+                )
+                .await;
+        }
+        // Sessions that stopped waiting, or were decided on by another moderator, are left be.
+        if self
+            .state
+            .lobby
+            .decide(&room_key, decision.session_id, decision.admit)
+        {
+            info!(
+                "Session {} {} session {} to room '{room_key}'",
+                self.session_id,
+                if decision.admit {
+                    "admitted"
+                } else {
+                    "refused"
+                },
+                decision.session_id
+            );
+        }
+        Ok(())
+    }
+
+    /// Moves the session to a room an embedding application asked for, and tells the client.
+    async fn handle_move(&mut self, room_key: Arc<str>) -> Result<()> {
+        // Sessions only move between rooms.
+        let Some(from) = self
+            .registration
+            .as_ref()
+            .and_then(Registration::room_key)
+            .cloned()
+        else {
+            return Ok(());
+        };
+        if from == room_key {
+            return Ok(());
+        }
+        self.stop_speaking();
+
+        let Some(registration) = &mut self.registration else {
+            return Ok(());
+        };
+        let user = registration.user();
+        if let Err(reason) = self.state.plugins.on_join(&user, &room_key) {
+            info!(
+                "Not moving session {} to room '{room_key}': {reason}",
+                self.session_id
+            );
+            return Ok(());
+        }
+        let joined = match registration.move_to(&room_key) {
+            Ok(joined) => joined,
+            Err(err) => {
+                warn!(
+                    "Cannot move session {} to room '{room_key}': {err:?}",
+                    self.session_id
+                );
+                return Ok(());
+            }
+        };
+        let name = registration.room_name().unwrap_or_default().to_owned();
+
+        self.state.plugins.on_leave(&user, &from);
+        if let Err(err) = self.state.store.leave(&from, self.session_id).await {
+            warn!("Cannot record leave in the state store: {err:#}");
+        }
+        self.enter(&room_key, name, joined).await
+    }
+
+    /// Tells a client in a room to move to the server this one drains to, if it can.
+    async fn migrate(&mut self) -> Result<()> {
+        let Some(address) = self.state.drain.address() else {
+            return Ok(());
+        };
+        if !self.has_feature(Feature::Migration) {
+            return Ok(());
+        }
+        let Some(room_key) = self.registration.as_ref().and_then(Registration::room_name) else {
+            return Ok(());
+        };
+        let resume_token = self.state.drain.resume_token(self.session_id);
+        let redirect = system::JoinRoomRedirect {
+            room_key: room_key.to_owned(),
+            address: address.to_string(),
+            resume_token: resume_token.as_deref().unwrap_or_default().to_owned(),
+        };
+        info!("Migrating session {} to {address}", self.session_id);
+        self.send(protocol::encode(PacketType::JoinRoomRedirect, &redirect))
+            .await
+    }
+
+    /// Tells the client and the room's other users that the session joined the room with
+    /// `room_key`, which the client calls `name`.
+    async fn enter(&mut self, room_key: &str, name: String, joined: Joined) -> Result<()> {
+        self.span.record("room", room_key);
+        let tenant = self.registration.as_ref().and_then(Registration::tenant);
+        let mut users = joined.users;
+        match self.state.store.join(room_key, &joined.user).await {
+            Ok(remote_users) => users.extend(remote_users),
+            Err(err) => warn!("Cannot record join in the state store: {err:#}"),
+        }
+        let mut user = joined.user;
+        for user in users.iter_mut().chain([&mut user]) {
+            avatars::fill(&self.state, tenant, user).await;
+        }
+
+        info!(
+            "Session {} joined room '{room_key}' ({} other users)",
+            self.session_id,
+            users.len()
+        );
+
+        self.send(protocol::encode(
+            PacketType::JoinRoomResponse,
+            &system::JoinRoomResponse {
+                users,
+                room_key: name,
+                room_id: protocol::room_id(room_key),
+            },
+        ))
+        .await?;
+        if self.has_feature(Feature::ChatHistory) {
+            self.send_chat_history(room_key, 0).await?;
+        }
+        if let Some(interval) = self.state.registry.slow_mode(room_key) {
+            let slow_mode = system::SlowMode {
+                interval_secs: u32::try_from(interval.as_secs()).unwrap_or(u32::MAX),
+            };
+            self.send(protocol::encode(PacketType::SlowMode, &slow_mode))
+                .await?;
+        }
+        if self.moderator && self.has_feature(Feature::Lobby) {
+            for pending in self.state.lobby.waiting(room_key) {
+                self.send(protocol::encode(PacketType::LobbyPending, &pending))
+                    .await?;
+            }
+        }
+
+        let packet = protocol::encode(PacketType::UserJoined, &user);
+        for peer in joined.peers {
+            peer.try_send_control(packet.clone());
+        }
+        Ok(())
+    }
+
+    async fn handle_chat(&mut self, message: system::ChatMessage) -> Result<()> {
+        // Admitted only in a room.
+        let Some(registration) = &self.registration else {
+            return Ok(());
+        };
+        let Some(room_key) = registration.room_key().cloned() else {
+            return Ok(());
+        };
+        if message.text.trim().is_empty() || message.text.chars().count() > protocol::MAX_CHAT_LEN {
+            return self
+                .reject(
+                    error::Code::MalformedPacket,
+                    format!(
+                        "chat messages must have 1 to {} characters",
+                        protocol::MAX_CHAT_LEN
+                    ),
+                    Some(PacketType::ChatMessage),
+                )
+                .await;
+        }
+
+        let user_key = tenants::key(registration.tenant(), registration.username());
+        if !self.moderator
+            && let Err(retry_after) = self.state.registry.count_chat(&room_key, &user_key)
+        {
+            let detail = format!(
+                "room is in slow mode, wait {} seconds",
+                retry_after.as_millis().div_ceil(1000)
+            );
+            if enabled!(Level::WARN) && log_sampling::REJECTED_PACKETS.sample() {
+                warn!("Rejecting packet: RATE_LIMITED ({detail})");
+            }
+            return self
+                .send(protocol::rate_limited(
+                    detail,
+                    Some(PacketType::ChatMessage),
+                    retry_after,
+                ))
+                .await;
+        }
+
+        let message = system::ChatMessage {
+            session_id: self.session_id,
+            username: registration.username().to_owned(),
+            text: message.text,
+            client_message_id: message.client_message_id,
+            ..Default::default()
+        };
+        match self.state.send_chat(&room_key, message) {
+            Ok(ack) if self.has_feature(Feature::ChatReceipts) => {
+                self.send(protocol::encode(PacketType::ChatAck, &ack)).await
+            }
+            Ok(_) => Ok(()),
+            Err(reason) => {
+                self.reject(
+                    error::Code::PermissionDenied,
+                    reason,
+                    Some(PacketType::ChatMessage),
+                )
+                .await
+            }
+        }
+    }
+
+    async fn handle_slow_mode(&mut self, slow_mode: system::SlowMode) -> Result<()> {
+        // Admitted only in a room.
+        let Some(room_key) = self
+            .registration
+            .as_ref()
+            .and_then(Registration::room_key)
+            .cloned()
+        else {
+            return Ok(());
+        };
+        if !self.moderator {
+            return self
+                .reject(
+                    error::Code::PermissionDenied,
+                    "only moderators may set slow mode",
+                    Some(PacketType::SlowMode),
+                )
+                .await;
+        }
+        if slow_mode.interval_secs > protocol::MAX_SLOW_MODE_SECS {
+            return self
+                .reject(
+                    error::Code::MalformedPacket,
+                    format!(
+                        "slow mode may be at most {} seconds",
+                        protocol::MAX_SLOW_MODE_SECS
+                    ),
+                    Some(PacketType::SlowMode),
+                )
+                .await;
+        }
+
+        let interval = (slow_mode.interval_secs > 0)
+            .then(|| Duration::from_secs(slow_mode.interval_secs.into()));
+        self.state.registry.set_slow_mode(&room_key, interval);
+        let packet = protocol::encode(PacketType::SlowMode, &slow_mode);
+        for (_, outbox) in self.state.registry.room_members(&room_key) {
+            outbox.try_send_control(packet.clone());
+        }
+        Ok(())
+    }
+
+    async fn handle_chat_backlog(&mut self, request: system::ChatBacklogRequest) -> Result<()> {
+        // Admitted only in a room, from clients that negotiated receipts.
+        let Some(room_key) = self
+            .registration
+            .as_ref()
+            .and_then(Registration::room_key)
+            .cloned()
+        else {
+            return Ok(());
+        };
+        if !self.has_feature(Feature::ChatReceipts) {
+            return Ok(());
+        }
+        self.send_chat_history(&room_key, request.after_message_id)
+            .await
+    }
+
+    /// Sends the client the messages kept for a room after the one with ID `after_message_id`,
+    /// but those from users it blocks.
+    async fn send_chat_history(&self, room_key: &str, after_message_id: u64) -> Result<()> {
+        let Some(registration) = &self.registration else {
+            return Ok(());
+        };
+        for message in self.state.chat_history.recent(room_key, after_message_id) {
+            if !registration.blocks(&message.username) {
+                self.send(protocol::encode(PacketType::ChatMessage, &message))
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_direct_message(&mut self, message: system::DirectMessage) -> Result<()> {
+        let registration = match &self.registration {
+            Some(registration) if self.has_feature(Feature::DirectMessages) => registration,
+            _ => {
+                return self
+                    .reject(
+                        error::Code::UnexpectedPacket,
+                        "direct messages need an authenticated session with \
+                         FEATURE_DIRECT_MESSAGES",
+                        Some(PacketType::DirectMessage),
+                    )
+                    .await;
+            }
+        };
+        if message.text.trim().is_empty() || message.text.chars().count() > protocol::MAX_CHAT_LEN {
+            return self
+                .reject(
+                    error::Code::MalformedPacket,
+                    format!(
+                        "direct messages must have 1 to {} characters",
+                        protocol::MAX_CHAT_LEN
+                    ),
+                    Some(PacketType::DirectMessage),
+                )
+                .await;
+        }
+
+        let (code, reason) = match registration.direct_recipient(message.to_session_id) {
+            Ok((_, outbox)) => {
+                let mut delivered = system::DirectMessage {
+                    session_id: self.session_id,
+                    username: registration.username().to_owned(),
+                    to_session_id: message.to_session_id,
+                    text: message.text,
+                    sent_at_ms: latency::now_us() / 1000,
+                    client_message_id: 0,
+                };
+                // Direct messages are filtered as room chat is, so they are no way around the filter.
+                if let Err(reason) = self.state.plugins.on_direct_message(&mut delivered) {
+                    return self
+                        .reject(
+                            error::Code::PermissionDenied,
+                            reason,
+                            Some(PacketType::DirectMessage),
+                        )
+                        .await;
+                }
+                // A recipient that stops reading must not hold up the sender's session.
+                let packet = protocol::encode(PacketType::DirectMessage, &delivered);
+                if outbox.try_send_control(packet) {
+                    let copy = system::DirectMessage {
+                        client_message_id: message.client_message_id,
+                        ..delivered
+                    };
+                    return self
+                        .send(protocol::encode(PacketType::DirectMessage, &copy))
+                        .await;
+                }
+                (
+                    error::Code::UserOffline,
+                    "the user disconnected or is not keeping up",
+                )
+            }
+            Err(DirectError::Offline) => (error::Code::UserOffline, "the user is not connected"),
+            Err(DirectError::NotAccepted) => (
+                error::Code::PermissionDenied,
+                "the user does not take direct messages",
+            ),
+            Err(DirectError::Blocked) => (
+                error::Code::PermissionDenied,
+                "direct messages with the user are blocked",
+            ),
+        };
+        self.reject(code, reason, Some(PacketType::DirectMessage))
+            .await
+    }
+
+    async fn handle_block_list(&mut self, list: system::BlockList) -> Result<()> {
+        let Some(registration) = &self.registration else {
+            return Ok(());
+        };
+        if list.usernames.len() > protocol::MAX_BLOCKED_USERS {
+            return self
+                .reject(
+                    error::Code::MalformedPacket,
+                    format!(
+                        "at most {} users may be blocked",
+                        protocol::MAX_BLOCKED_USERS
+                    ),
+                    Some(PacketType::BlockList),
+                )
+                .await;
+        }
+        let usernames: BTreeSet<String> = list.usernames.into_iter().collect();
+        registration.set_blocked(usernames.iter().cloned().collect());
+
+        if let Some(accounts) = &self.state.accounts {
+            let key = tenants::key(registration.tenant(), registration.username());
+            let saved: Result<()> = async {
+                let Some(mut account) = accounts.get(&key).await? else {
+                    return Ok(());
+                };
+                account.blocked = usernames;
+                accounts.put(account).await
+            }
+            .await;
+            if let Err(err) = saved {
+                warn!("Cannot save who '{key}' blocks: {err:#}");
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_reaction(&mut self, reaction: system::Reaction) -> Result<()> {
+        // Admitted only in a room.
+        let Some(room_key) = self
+            .registration
+            .as_ref()
+            .and_then(Registration::room_key)
+            .cloned()
+        else {
+            return Ok(());
+        };
+        let emoji_len = reaction.emoji.chars().count();
+        if emoji_len == 0
+            || emoji_len > protocol::MAX_EMOJI_LEN
+            || reaction.emoji.chars().any(char::is_whitespace)
+        {
+            return self
+                .reject(
+                    error::Code::MalformedPacket,
+                    format!(
+                        "reactions must have 1 to {} characters and no whitespace",
+                        protocol::MAX_EMOJI_LEN
+                    ),
+                    Some(PacketType::Reaction),
+                )
+                .await;
+        }
+        let config = self.state.live_config();
+        self.reactions
+            .set_limit(config.session.reaction_rate, config.session.reaction_burst);
+        if !self.reactions.try_acquire() {
+            return self
+                .reject(
+                    error::Code::RateLimited,
+                    "too many reactions",
+                    Some(PacketType::Reaction),
+                )
+                .await;
+        }
+
+        let reaction = system::Reaction {
+            session_id: self.session_id,
+            ..reaction
+        };
+        self.state.send_reaction(&room_key, &reaction);
+        Ok(())
+    }
+
+    /// Hands a file upload stream to a task of its own, so the session keeps handling packets
+    /// while the file arrives.
+    async fn start_upload(&self, stream: Box<dyn RecvStream>, received: Vec<u8>) -> Result<()> {
+        let room = self
+            .registration
+            .as_ref()
+            .and_then(|registration| Some((registration, registration.room_key()?)));
+        let Some((registration, room_key)) = room else {
+            return self
+                .reject(
+                    error::Code::UnexpectedPacket,
+                    "cannot share files before joining a room",
+                    Some(PacketType::FileUpload),
+                )
+                .await;
+        };
+        let Ok(permit) = self.uploads.clone().try_acquire_owned() else {
+            return self
+                .reject(
+                    error::Code::RateLimited,
+                    format!(
+                        "at most {} uploads may be in progress",
+                        files::MAX_CONCURRENT_UPLOADS
+                    ),
+                    Some(PacketType::FileUpload),
+                )
+                .await;
+        };
+
+        let uploader = Uploader {
+            session_id: self.session_id,
+            username: registration.username().to_owned(),
+            room_key: room_key.clone(),
+            outbox: self.outbox.clone(),
+        };
+        let state = self.state.clone();
+        tokio::spawn(
+            async move {
+                files::receive(state, uploader, stream, received).await;
+                drop(permit);
+            }
+            .in_current_span(),
+        );
+        Ok(())
+    }
+
+    /// Hands a media track's stream to a task of its own, which relays it to the session's room.
+    async fn start_track(&self, stream: Box<dyn RecvStream>, received: Vec<u8>) -> Result<()> {
+        let room_key = self
+            .registration
+            .as_ref()
+            .and_then(|registration| registration.room_key());
+        let (Some(room_key), true) = (room_key, self.has_feature(Feature::MediaStreams)) else {
+            return self
+                .reject(
+                    error::Code::UnexpectedPacket,
+                    "media tracks need FEATURE_MEDIA_STREAMS and a room",
+                    Some(PacketType::MediaTrack),
+                )
+                .await;
+        };
+        let Ok(permit) = self.tracks.clone().try_acquire_owned() else {
+            return self
+                .reject(
+                    error::Code::RateLimited,
+                    format!(
+                        "at most {} media tracks may be sent at once",
+                        media::MAX_TRACKS_PER_SESSION
+                    ),
+                    Some(PacketType::MediaTrack),
+                )
+                .await;
+        };
+
+        let sender = media::Sender {
+            session_id: self.session_id,
+            room_key: room_key.clone(),
+            outbox: self.outbox.clone(),
+        };
+        let state = self.state.clone();
+        tokio::spawn(
+            async move {
+                media::relay(state, sender, stream, received).await;
+                drop(permit);
+            }
+            .in_current_span(),
+        );
+        Ok(())
+    }
+
+    /// Records a voice datagram's timing and forwards it to the session's room. Only its header
+    /// is read; the payload is passed on as received.
+    fn forward_voice(&mut self, datagram: Bytes) {
+        if !self.phase.in_room() {
+            return;
+        }
+        let Some(registration) = &self.registration else {
+            return;
+        };
+
+        let frame = if self.has_feature(Feature::AudioHeader) {
+            let Some(header) = VoiceHeader::parse(&datagram) else {
+                self.metrics.drops.count_malformed();
+                return;
+            };
+            if header.session_id != self.session_id {
+                self.metrics.drops.count_malformed();
+                return;
+            }
+            // Frames sent before the client was moved are of no use to either room.
+            if registration.room_id() != Some(header.room_id) {
+                return;
+            }
+            VoiceFrame::received(header, datagram)
+        } else if self.has_feature(Feature::LatencyReports) {
+            let Some(timestamp) = datagram.get(1..1 + protocol::CAPTURE_TIMESTAMP_LEN) else {
+                self.metrics.drops.count_malformed();
+                return;
+            };
+            let capture_time_us = u64::from_be_bytes(timestamp.try_into().unwrap());
+            registration.voice_frame(
+                datagram.slice(1 + protocol::CAPTURE_TIMESTAMP_LEN..),
+                capture_time_us,
+            )
+        } else {
+            registration.voice_frame(datagram.slice(1..), 0)
+        };
+
+        let capture_time_us = frame.header().capture_time_us;
+        let capture_time_us = (self.has_feature(Feature::LatencyReports) && capture_time_us != 0)
+            .then_some(capture_time_us);
+        if let Some(capture_time_us) = capture_time_us {
+            self.metrics
+                .latency
+                .record_capture(capture_time_us, latency::now_us());
+        }
+        self.stats.record_voice(capture_time_us);
+
+        let len = frame.payload().len();
+        if !self.bitrate.admit(len) {
+            self.metrics.drops.rate_limited.inc();
+            return;
+        }
+        if !registration.admit_room_audio(len, self.bitrate.max_room_kbps()) {
+            METRICS.audio_frames_over_bitrate.inc();
+            self.metrics.drops.rate_limited.inc();
+            return;
+        }
+        let muted = registration.is_muted();
+        self.state.forward_voice(registration, frame);
+        if !muted {
+            self.start_speaking();
+        }
+    }
+
+    /// Records that the client sent voice, publishing [`Event::SpeakingStarted`] if it was not
+    /// speaking.
+    fn start_speaking(&mut self) {
+        let started = self.last_spoke.replace(Instant::now()).is_none();
+        if let Some(registration) = &self.registration
+            && let Some(room_key) = registration.room_key()
+            && started
+        {
+            self.state
+                .registry
+                .events()
+                .publish(Event::SpeakingStarted {
+                    room_key: room_key.clone(),
+                    user: registration.user(),
+                });
+        }
+    }
+
+    /// Publishes [`Event::SpeakingStopped`] if the client was speaking.
+    fn stop_speaking(&mut self) {
+        if self.last_spoke.take().is_some()
+            && let Some(registration) = &self.registration
+            && let Some(room_key) = registration.room_key()
+        {
+            self.state
+                .registry
+                .events()
+                .publish(Event::SpeakingStopped {
+                    room_key: room_key.clone(),
+                    user: registration.user(),
+                });
+        }
+    }
+
+    /// The node to send the client to for a room placed elsewhere, or for every room while
+    /// draining. Clients that cannot follow redirects join wherever they are, and the trunks
+    /// carry their audio.
+    fn redirect(&self, room_key: &str) -> Option<String> {
+        if !self.has_feature(Feature::Redirects) {
+            return None;
+        }
+        if let Some(address) = self.state.drain.address() {
+            return Some(address.to_string());
+        }
+        let placement = self.state.placement.as_ref()?;
+        placement.redirect(room_key).map(str::to_owned)
+    }
+
+    /// Records the bytes moved so far in the session's metrics, and adds those moved since the
+    /// last call to the tenant's usage. Bytes moved before the session authenticated count once
+    /// it has.
+    fn account_bandwidth(&mut self) {
+        let stats = self.connection.stats();
+        let (sent, received) = (stats.udp_tx.bytes, stats.udp_rx.bytes);
+        self.metrics.bytes_sent.store(sent, Ordering::Relaxed);
+        self.metrics
+            .bytes_received
+            .store(received, Ordering::Relaxed);
+
+        let Some(registration) = &self.registration else {
+            return;
+        };
+        let (accounted_sent, accounted_received) = self.accounted_bytes;
+        self.state.usage.record(registration.tenant(), |usage| {
+            usage.bytes_sent += sent.saturating_sub(accounted_sent);
+            usage.bytes_received += received.saturating_sub(accounted_received);
+        });
+        self.accounted_bytes = (sent, received);
+    }
+
+    /// Logs and counts a change of the client's address, such as after NAT rebinding or a switch
+    /// between networks. The session keeps its ID, registration and room across it.
+    fn check_path(&mut self) {
+        let address = self.connection.remote_address();
+        if address == self.remote_address {
+            return;
+        }
+        self.path_changes += 1;
+        info!(
+            "Session {} moved from {} to {} (path change {})",
+            self.session_id, self.remote_address, address, self.path_changes
+        );
+        METRICS.session_migrations.inc();
+        self.remote_address = address;
+        self.span.record("remote", field::display(address));
+    }
+
+    /// Limits or lifts the speakers sent to a listener that cannot keep up, and tells the client.
+    async fn check_slow_consumer(&mut self, forwarded_speakers: usize) -> Result<()> {
+        let config = self.state.live_config();
+        let Some(change) =
+            self.slow_consumer
+                .check(&self.outbox, &config.session, forwarded_speakers)
+        else {
+            return Ok(());
+        };
+        let limit = match change {
+            Change::Limited(max_speakers) => {
+                info!(
+                    "Session {} is falling behind; sending it at most {max_speakers} {}",
+                    self.session_id,
+                    speakers(max_speakers)
+                );
+                system::ForwardingLimit {
+                    max_speakers: max_speakers as u32,
+                    detail: format!(
+                        "Your connection is falling behind, so you hear at most {max_speakers} \
+                         {} at once",
+                        speakers(max_speakers)
+                    ),
+                }
+            }
+            Change::Lifted => {
+                info!("Session {} is sent every speaker again", self.session_id);
+                system::ForwardingLimit {
+                    max_speakers: 0,
+                    detail: "You hear every speaker again".to_owned(),
+                }
+            }
+        };
+        if self.has_feature(Feature::ForwardingLimits) {
+            self.send(protocol::encode(PacketType::ForwardingLimit, &limit))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Moves the session to the phase after `transition`.
+    fn advance(&mut self, transition: Transition) {
+        match self.phase.next(transition) {
+            Some(phase) => {
+                debug!("Session {} is now {phase:?}", self.session_id);
+                self.phase = phase;
+            }
+            None => warn!(
+                "Session {} cannot {transition:?} while {:?}",
+                self.session_id, self.phase
+            ),
+        }
+    }
+
+    /// Whether the client negotiated `feature` in the handshake.
+    fn has_feature(&self, feature: Feature) -> bool {
+        self.hello
+            .as_ref()
+            .is_some_and(|ack| ack.features & feature as u32 != 0)
+    }
+
+    /// Warns a client that started sending over its bitrate cap, or closes the session of one
+    /// that kept on for too long. Returns whether the session is still open.
+    async fn check_bitrate(&mut self) -> Result<bool> {
+        let config = self.state.live_config();
+        match self.bitrate.check(&config.session) {
+            None => Ok(true),
+            Some(Verdict::Warn(kbps)) => {
+                let detail = format!(
+                    "sending {kbps} kbit/s of audio, over the cap of {} kbit/s",
+                    config.session.max_user_kbps
+                );
+                warn!("Session {} is {detail}", self.session_id);
+                self.send(protocol::error(error::Code::BitrateExceeded, detail, None))
+                    .await?;
+                Ok(true)
+            }
+            Some(Verdict::Close(kbps)) => {
+                info!(
+                    "Closing session {} after {} seconds over the bitrate cap ({kbps} kbit/s)",
+                    self.session_id, config.session.bitrate_grace_secs
+                );
+                self.close_with_error(
+                    error::Code::BitrateExceeded,
+                    format!(
+                        "sent audio over the cap of {} kbit/s for {} seconds",
+                        config.session.max_user_kbps, config.session.bitrate_grace_secs
+                    ),
+                    CloseCode::BitrateExceeded,
+                )
+                .await?;
+                Ok(false)
+            }
+        }
+    }
+
+    /// Sends a PING, or closes the session if the client left too many unanswered. Returns
+    /// `false` if the session has ended.
+    async fn ping(&mut self) -> Result<bool> {
+        let max_missed = self.state.config.session.max_missed_pings.max(1);
+        if self.unanswered_pings >= max_missed {
+            info!(
+                "Closing session {} after {max_missed} unanswered pings",
+                self.session_id
+            );
+            self.close_with_error(
+                error::Code::IdleTimeout,
+                format!("no answer to {max_missed} pings"),
+                CloseCode::IdleTimeout,
+            )
+            .await?;
+            return Ok(false);
+        }
+
+        self.last_ping_id = self.last_ping_id.wrapping_add(1);
+        self.unanswered_pings += 1;
+        let ping = system::Ping {
+            id: self.last_ping_id,
+        };
+        self.send(protocol::encode(PacketType::Ping, &ping)).await?;
+        Ok(true)
+    }
+
+    /// Sends a clock offset probe and refreshes the latency estimates measured locally.
+    async fn sync_clock(&self) -> Result<()> {
+        self.metrics
+            .latency
+            .rtt
+            .set(self.connection.rtt().as_micros() as i64);
+        if let Some(queue_delay) = self.outbox.queue_delay() {
+            self.metrics.latency.queue_delay.set(queue_delay);
+        }
+
+        self.send(protocol::encode(
+            PacketType::ClockSync,
+            &system::ClockSync {
+                server_send_time_us: latency::now_us(),
+                ..Default::default()
+            },
+        ))
+        .await
+    }
+
+    /// Responds to a rejected packet with an [`system::Error`].
+    async fn reject(
+        &self,
+        code: error::Code,
+        detail: impl Into<String>,
+        packet_type: Option<PacketType>,
+    ) -> Result<()> {
+        let detail = detail.into();
+        if enabled!(Level::WARN) && log_sampling::REJECTED_PACKETS.sample() {
+            warn!("Rejecting packet: {} ({detail})", code.as_str_name());
+        }
+        self.send(protocol::error(code, detail, packet_type)).await
+    }
+
+    /// Sends a control packet to the client.
+    async fn send(&self, packet: Bytes) -> Result<()> {
+        self.outbox
+            .send_control(packet)
+            .await
+            .map_err(|_| anyhow::anyhow!("Connection closed"))
+    }
+
+    /// Sends a handshake refusal and closes the connection.
+    async fn refuse(&mut self, refusal: &system::HelloError, code: CloseCode) -> Result<()> {
+        self.send_and_close(&protocol::encode(PacketType::HelloError, refusal), code)
+            .await
+    }
+
+    /// Sends an [`system::Error`] and closes the connection.
+    async fn close_with_error(
+        &mut self,
+        error_code: error::Code,
+        detail: impl Into<String>,
+        close_code: CloseCode,
+    ) -> Result<()> {
+        self.send_and_close(&protocol::error(error_code, detail, None), close_code)
+            .await
+    }
+
+    /// Sends a final packet over a reliable stream, then closes the connection once the client
+    /// has had a chance to read it.
+    async fn send_and_close(&mut self, packet: &[u8], code: CloseCode) -> Result<()> {
+        self.advance(Transition::Close);
+        packet_dump::sent(packet);
+        let mut stream = protocol::open_control_stream(&*self.connection).await?;
+        stream.write_all(packet).await?;
+        stream.finish().await?;
+
+        let _ = tokio::time::timeout(Self::FINAL_PACKET_GRACE, self.connection.closed()).await;
+        protocol::close(&*self.connection, code);
+
+        Ok(())
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        METRICS.untrack_session(self.session_id);
+        self.account_bandwidth();
+        // Before the registration is dropped, so the room hears it before the user leaves.
+        self.stop_speaking();
+
+        let Some(registration) = &self.registration else {
+            return;
+        };
+        let Some(room_key) = registration.room_key() else {
+            return;
+        };
+        self.state.plugins.on_leave(&registration.user(), room_key);
+        let (store, room_key, session_id) =
+            (self.state.store.clone(), room_key.clone(), self.session_id);
+        tokio::spawn(
+            async move {
+                if let Err(err) = store.leave(&room_key, session_id).await {
+                    warn!("Cannot record leave in the state store: {err:#}");
+                }
+            }
+            .in_current_span(),
+        );
+    }
+}
+
+/// Reads a stream the client opened: to its end for a control packet or voice frame, or as far
+/// as its first byte for an upload or a media track.
+async fn read_stream(mut stream: Box<dyn RecvStream>, mut buffer: PooledBuffer) -> StreamRead {
+    let read = read_packet(&mut *stream, &mut buffer);
+    let len = match tokio::time::timeout(Session::STREAM_READ_TIMEOUT, read).await {
+        Ok(Ok(Some(len))) => len,
+        Ok(Ok(None)) => return StreamRead::Oversized,
+        Ok(Err(err)) => {
+            debug!("Cannot read a stream from the client: {err}");
+            return StreamRead::Failed;
+        }
+        Err(_) => {
+            debug!(
+                "The client did not finish a stream within {} seconds",
+                Session::STREAM_READ_TIMEOUT.as_secs()
+            );
+            return StreamRead::Failed;
+        }
+    };
+    match buffer[..len].first() {
+        Some(&protocol::FILE_STREAM_PREFIX) => StreamRead::Upload(stream, buffer[..len].to_vec()),
+        Some(&protocol::MEDIA_STREAM_PREFIX) => StreamRead::Track(stream, buffer[..len].to_vec()),
+        _ => StreamRead::Packet(buffer, len),
+    }
+}
+
+/// Reads a stream into `buffer`, growing it as needed, returning how much was read or `None` if
+/// the packet is too long.
+async fn read_packet(
+    stream: &mut dyn RecvStream,
+    buffer: &mut PooledBuffer,
+) -> io::Result<Option<usize>> {
+    let mut len = 0;
+    loop {
+        if len == buffer.len() {
+            if len >= Session::MAX_STREAM_PACKET_LEN {
+                return Ok(None);
+            }
+            buffer.grow((len * 2).min(Session::MAX_STREAM_PACKET_LEN));
+        }
+        match stream.read(&mut buffer[len..]).await? {
+            0 => return Ok(Some(len)),
+            bytes_read => len += bytes_read,
+        }
+        if matches!(
+            buffer[..len].first(),
+            Some(&(protocol::FILE_STREAM_PREFIX | protocol::MEDIA_STREAM_PREFIX))
+        ) {
+            return Ok(Some(len));
+        }
+    }
+}
+
+/// What the first of the streams being read carried, or any stream set aside, waiting forever if
+/// none is read. The first stream is set aside once it has been read for
+/// [`Session::STREAM_ORDER_GRACE`], if there is room.
+///
+/// Cancel safe: a stream is removed only as what it carried is returned.
+async fn next_stream(
+    streams: &mut VecDeque<Reading>,
+    stalled: &mut Vec<JoinHandle<StreamRead>>,
+) -> StreamRead {
+    loop {
+        let set_aside_at = streams
+            .front()
+            .filter(|_| stalled.len() < Session::MAX_STALLED_STREAMS)
+            .map(|reading| reading.opened + Session::STREAM_ORDER_GRACE);
+        let first = async {
+            match streams.front_mut() {
+                Some(reading) => (&mut reading.read).await,
+                None => std::future::pending().await,
+            }
+        };
+        let set_aside = async {
+            match set_aside_at {
+                Some(at) => tokio::time::sleep_until(at.into()).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            biased;
+            read = first => {
+                streams.pop_front();
+                return read.unwrap_or(StreamRead::Failed);
+            }
+            read = next_stalled(stalled) => return read,
+            () = set_aside => {
+                let reading = streams.pop_front().unwrap();
+                stalled.push(reading.read);
+            }
+        }
+    }
+}
+
+/// What one of the streams set aside carried, waiting forever if none is set aside.
+async fn next_stalled(stalled: &mut Vec<JoinHandle<StreamRead>>) -> StreamRead {
+    std::future::poll_fn(|cx| {
+        for index in 0..stalled.len() {
+            if let Poll::Ready(read) = Pin::new(&mut stalled[index]).poll(cx) {
+                stalled.swap_remove(index);
+                return Poll::Ready(read.unwrap_or(StreamRead::Failed));
+            }
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+/// Whether a moderator admitted the session to the room it waits to join, waiting forever if it
+/// waits to join none.
+async fn next_admission(waiting: &mut Option<Ticket>) -> bool {
+    match waiting {
+        Some(ticket) => ticket.decision().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// The WebSocket the client opened for the session, if it opened the one offered, waiting forever
+/// if none was.
+async fn next_socket(offer: &mut Option<oneshot::Receiver<Socket>>) -> Option<Socket> {
+    match offer {
+        Some(offer) => offer.await.ok(),
+        None => std::future::pending().await,
+    }
+}
+
+/// The next control packet the client sends on its WebSocket, waiting forever if it has none.
+async fn next_signal(packets: &mut Option<mpsc::Receiver<Bytes>>) -> Option<Bytes> {
+    match packets {
+        Some(packets) => packets.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// The next room the session is moved to, waiting forever if it cannot be moved.
+async fn next_move(moves: &mut Option<mpsc::Receiver<Arc<str>>>) -> Option<Arc<str>> {
+    match moves {
+        Some(moves) => moves.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+fn speakers(count: usize) -> &'static str {
+    if count == 1 { "speaker" } else { "speakers" }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use prost::Message;
+    use protobuf::system;
+    use protobuf::system::CloseCode;
+    use protobuf::system::Feature;
+    use protobuf::system::PacketType;
+    use protobuf::system::error;
+    use tokio::io::AsyncWriteExt;
+    use wtransport::VarInt;
+
+    use super::Session;
+    use crate::config::Config;
+    use crate::protocol;
+    use crate::state::ServerState;
+    use crate::transport::mock;
+
+    /// Logs in as `username` on a session of its own and joins `room_key`, returning the client,
+    /// its session ID and the room's users as it joined.
+    async fn enter(
+        state: &Arc<ServerState>,
+        username: &str,
+        room_key: &str,
+    ) -> (mock::Client, i64, system::JoinRoomResponse) {
+        enter_with(state, username, room_key, 0).await
+    }
+
+    /// Enters as [`enter`] does, negotiating `features`.
+    async fn enter_with(
+        state: &Arc<ServerState>,
+        username: &str,
+        room_key: &str,
+        features: u32,
+    ) -> (mock::Client, i64, system::JoinRoomResponse) {
+        let (connection, mut client) = mock::connect();
+        tokio::spawn(Session::new(connection, state.clone()).run());
+
+        let hello = system::Hello {
+            protocol_version: protocol::PROTOCOL_VERSION,
+            min_protocol_version: protocol::PROTOCOL_VERSION,
+            features,
+        };
+        client.send(PacketType::Hello, &hello);
+        client
+            .expect::<system::HelloAck>(PacketType::HelloAck)
+            .await;
+
+        let auth = system::AuthRequest {
+            username: username.into(),
+            token: "password".into(),
+            ..Default::default()
+        };
+        client.send(PacketType::AuthRequest, &auth);
+        let success = client
+            .expect::<system::AuthResponseSuccess>(PacketType::AuthResponseSuccess)
+            .await;
+
+        let request = system::JoinRoomRequest {
+            room_key: room_key.into(),
+        };
+        client.send(PacketType::JoinRoomRequest, &request);
+        let response = client
+            .expect::<system::JoinRoomResponse>(PacketType::JoinRoomResponse)
+            .await;
+        (client, success.session_id, response)
+    }
+
+    /// The next voice datagram the client got, skipping control packets.
+    async fn next_voice(client: &mut mock::Client) -> Bytes {
+        loop {
+            let packet = client.receive().await;
+            if packet.first() == Some(&protocol::VOICE_PACKET_PREFIX) {
+                return packet;
+            }
+        }
+    }
+
+    fn state() -> Arc<ServerState> {
+        ServerState::new(Config::default()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn join() {
+        let state = state();
+        let (_client, session_id, response) = enter(&state, "alice", "lobby").await;
+
+        assert_ne!(session_id, 0);
+        assert_eq!(response.room_key, "lobby");
+        assert!(response.users.is_empty());
+    }
+
+    #[tokio::test]
+    async fn roster() {
+        let state = state();
+        let (mut alice, alice_id, _) = enter(&state, "alice", "lobby").await;
+        let (bob, bob_id, response) = enter(&state, "bob", "lobby").await;
+
+        let users: Vec<_> = response.users.iter().map(|user| user.session_id).collect();
+        assert_eq!(users, [alice_id]);
+
+        let joined = alice
+            .expect::<system::RoomUser>(PacketType::UserJoined)
+            .await;
+        assert_eq!(joined.session_id, bob_id);
+        assert_eq!(joined.username, "bob");
+
+        bob.close();
+        loop {
+            let packet = alice.receive().await;
+            if packet.first() == Some(&(PacketType::UserLeft as u8)) {
+                assert_eq!(packet[1..], bob_id.to_be_bytes());
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn mute() {
+        let state = state();
+        let (alice, alice_id, _) = enter(&state, "alice", "lobby").await;
+        let (mut bob, _, _) = enter(&state, "bob", "lobby").await;
+
+        alice.send_datagram(Bytes::from_static(&[
+            protocol::VOICE_PACKET_PREFIX,
+            1,
+            2,
+            3,
+        ]));
+        let voice = next_voice(&mut bob).await;
+        assert!(voice.ends_with(&[1, 2, 3]));
+
+        assert!(state.registry.set_muted(alice_id, true));
+        // Let whatever was sent before settle, so that silence comes from the mute alone.
+        while !bob.is_silent_for(Duration::from_millis(200)).await {}
+        alice.send_datagram(Bytes::from_static(&[
+            protocol::VOICE_PACKET_PREFIX,
+            4,
+            5,
+            6,
+        ]));
+        assert!(bob.is_silent_for(Duration::from_millis(500)).await);
+
+        assert!(state.registry.set_muted(alice_id, false));
+        alice.send_datagram(Bytes::from_static(&[
+            protocol::VOICE_PACKET_PREFIX,
+            7,
+            8,
+            9,
+        ]));
+        let voice = next_voice(&mut bob).await;
+        assert!(voice.ends_with(&[7, 8, 9]));
+    }
+
+    #[tokio::test]
+    async fn chat() {
+        let state = state();
+        let (alice, _, _) = enter(&state, "alice", "lobby").await;
+        let (mut bob, _, _) = enter(&state, "bob", "lobby").await;
+
+        for text in ["one", "two", "three"] {
+            let message = system::ChatMessage {
+                text: text.into(),
+                ..Default::default()
+            };
+            alice.send(PacketType::ChatMessage, &message);
+        }
+        let mut last_message_id = 0;
+        for text in ["one", "two", "three"] {
+            let message = bob
+                .expect::<system::ChatMessage>(PacketType::ChatMessage)
+                .await;
+            assert_eq!(message.username, "alice");
+            assert_eq!(message.text, text);
+            assert!(message.message_id > last_message_id);
+            last_message_id = message.message_id;
+        }
+    }
+
+    #[tokio::test]
+    async fn direct_messages_are_filtered() {
+        let mut config = Config::default();
+        config.moderation.chat_filter.words = vec!["darn".into()];
+        let state = ServerState::new(config).unwrap();
+        let features = Feature::DirectMessages as u32;
+        let (mut alice, _, _) = enter_with(&state, "alice", "lobby", features).await;
+        let (mut bob, bob_id, _) = enter_with(&state, "bob", "lobby", features).await;
+
+        let message = |text: &str| system::DirectMessage {
+            to_session_id: bob_id,
+            text: text.into(),
+            ..Default::default()
+        };
+        alice.send(PacketType::DirectMessage, &message("darn it"));
+        let error = alice.expect::<system::Error>(PacketType::Error).await;
+        assert_eq!(error.code(), error::Code::PermissionDenied);
+
+        alice.send(PacketType::DirectMessage, &message("hello"));
+        let delivered = bob
+            .expect::<system::DirectMessage>(PacketType::DirectMessage)
+            .await;
+        assert_eq!(delivered.username, "alice");
+        assert_eq!(delivered.text, "hello");
+    }
+
+    #[tokio::test]
+    async fn slow_streams_do_not_hold_up_control_packets() {
+        let state = state();
+        let (alice, _, _) = enter(&state, "alice", "lobby").await;
+        let (mut bob, _, _) = enter(&state, "bob", "lobby").await;
+
+        let mut slow: Vec<_> = (0..Session::MAX_STALLED_STREAMS)
+            .map(|_| alice.open_stream())
+            .collect();
+        let message = system::ChatMessage {
+            text: "after".into(),
+            ..Default::default()
+        };
+        alice.send(PacketType::ChatMessage, &message);
+        // Well before the slow streams time out.
+        let message = bob
+            .expect::<system::ChatMessage>(PacketType::ChatMessage)
+            .await;
+        assert_eq!(message.text, "after");
+
+        // A stream set aside is still handled once it finishes.
+        let message = system::ChatMessage {
+            text: "finally".into(),
+            ..Default::default()
+        };
+        let mut packet = vec![PacketType::ChatMessage as u8];
+        message.encode(&mut packet).unwrap();
+        let mut stream = slow.pop().unwrap();
+        stream.write_all(&packet).await.unwrap();
+        drop(stream);
+        let message = bob
+            .expect::<system::ChatMessage>(PacketType::ChatMessage)
+            .await;
+        assert_eq!(message.text, "finally");
+    }
+
+    #[tokio::test]
+    async fn kick() {
+        let state = state();
+        let (mut alice, _, _) = enter(&state, "alice", "lobby").await;
+        let (bob, bob_id, _) = enter(&state, "bob", "lobby").await;
+
+        assert!(state.registry.kick(bob_id));
+        assert_eq!(
+            bob.closed().await,
+            VarInt::from_u32(CloseCode::Kicked as u32)
+        );
+
+        loop {
+            let packet = alice.receive().await;
+            if packet.first() == Some(&(PacketType::UserLeft as u8)) {
+                assert_eq!(packet[1..], bob_id.to_be_bytes());
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn close_with_code() {
+        let state = state();
+        let (bob, bob_id, _) = enter(&state, "bob", "lobby").await;
+
+        assert!(state.registry.close(bob_id, CloseCode::Banned));
+        assert_eq!(
+            bob.closed().await,
+            VarInt::from_u32(CloseCode::Banned as u32)
+        );
+        assert!(!state.registry.close(bob_id + 1, CloseCode::Banned));
+    }
+}
@@ -0,0 +1,184 @@
+//! Several server processes on one host sharing its ports with SO_REUSEPORT, as a simpler way
+//! to use every core than clustering separate servers.
+//!
+//! With `listen.reuse_port`, every process binds WebTransport to `listen.port` and HTTP to the
+//! usual port, and the kernel spreads connections among them. They share the room directory
+//! through `cluster.redis_url` and serve the same certificate, so a client may fetch
+//! `/config.json` from one process and connect to another. For audio, each process also serves
+//! trunks on a loopback port of its own, registered in Redis as `voice:sibling:<node ID>`, and
+//! dials a trunk to every other process registered there.
+
+use std::collections::HashSet;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+use std::net::TcpListener;
+use std::net::UdpSocket;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use anyhow::Result;
+use anyhow::bail;
+use socket2::Domain;
+use socket2::Protocol;
+use socket2::Socket;
+use socket2::Type;
+use tracing::Instrument;
+use tracing::info_span;
+use tracing::warn;
+use wtransport::Identity;
+use wtransport::tls::Sha256Digest;
+
+use crate::config::Config;
+use crate::redis;
+use crate::redis::Value;
+use crate::state::ServerState;
+use crate::webtransport::WebTransportServer;
+
+const KEY_PREFIX: &str = "voice:sibling:";
+
+/// How long a process's registration outlives its last refresh.
+const TTL_SECS: u64 = 15;
+
+/// How often each process refreshes its registration and looks for the others.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Checks that the config has what sharing ports needs.
+pub fn validate(config: &Config) -> Result<()> {
+    if !config.listen.reuse_port {
+        return Ok(());
+    }
+    if config.listen.port.is_none() {
+        bail!("listen.reuse_port needs listen.port");
+    }
+    if config.listen.certificate_path.is_none() || config.listen.private_key_path.is_none() {
+        bail!(
+            "listen.reuse_port needs listen.certificate_path and listen.private_key_path, so \
+             every process serves the same certificate"
+        );
+    }
+    if config.cluster.redis_url.is_none() || config.cluster.trunk_secret.is_none() {
+        bail!("listen.reuse_port needs cluster.redis_url and cluster.trunk_secret");
+    }
+    if config.cluster.node_id.is_some() {
+        bail!("listen.reuse_port needs cluster.node_id unset, so each process has its own");
+    }
+    Ok(())
+}
+
+/// Binds a UDP socket to `port` on every interface, shared with other processes if
+/// `reuse_port` is set.
+pub fn bind_udp(port: u16, reuse_port: bool) -> Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_only_v6(false)?;
+    socket.set_reuse_port(reuse_port)?;
+    socket
+        .bind(&SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port).into())
+        .with_context(|| format!("Cannot bind UDP port {port}"))?;
+    Ok(socket.into())
+}
+
+/// Binds a TCP listener to `port` on the loopback interface, shared with other processes.
+pub fn bind_tcp(port: u16) -> Result<TcpListener> {
+    let socket = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    socket
+        .bind(&SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port).into())
+        .with_context(|| format!("Cannot bind TCP port {port}"))?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+/// Serves trunks on a loopback port, and dials the other processes' trunks.
+pub fn serve(state: &Arc<ServerState>, identity: &Identity, digest: &Sha256Digest) -> Result<()> {
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).context("Cannot bind trunk port")?;
+    let trunks = WebTransportServer::new(identity.clone_identity(), state.clone(), Some(socket))?;
+    let port = trunks.local_port();
+    tokio::spawn(
+        async move {
+            if let Err(err) = trunks.serve().await {
+                warn!("Sibling trunks stopped: {err:#}");
+            }
+        }
+        .instrument(info_span!("Sibling trunks")),
+    );
+    tokio::spawn(discover(state.clone(), digest.clone(), port).instrument(info_span!("Siblings")));
+    Ok(())
+}
+
+/// Registers this process's trunk port in Redis and keeps a trunk to every other process
+/// registered there, forever.
+async fn discover(state: Arc<ServerState>, digest: Sha256Digest, trunk_port: u16) {
+    let mut connection = None;
+    let mut refreshes = tokio::time::interval(REFRESH_INTERVAL);
+    loop {
+        refreshes.tick().await;
+        if let Err(err) = refresh(&state, &mut connection, &digest, trunk_port).await {
+            warn!("Cannot refresh sibling processes: {err:#}");
+            connection = None;
+        }
+    }
+}
+
+async fn refresh(
+    state: &ServerState,
+    connection: &mut Option<redis::Connection>,
+    digest: &Sha256Digest,
+    trunk_port: u16,
+) -> Result<()> {
+    let connection = match connection {
+        Some(connection) => connection,
+        None => {
+            let url = state.config.cluster.redis_url.as_deref().unwrap();
+            connection.insert(redis::Connection::connect(url).await?)
+        }
+    };
+
+    let own_key = format!("{KEY_PREFIX}{}", state.node_id);
+    let port = trunk_port.to_string();
+    let ttl = TTL_SECS.to_string();
+    connection
+        .command(&[
+            b"SET",
+            own_key.as_bytes(),
+            port.as_bytes(),
+            b"EX",
+            ttl.as_bytes(),
+        ])
+        .await?;
+
+    let pattern = format!("{KEY_PREFIX}*");
+    let keys = connection
+        .command(&[b"KEYS", pattern.as_bytes()])
+        .await?
+        .into_bulks()?;
+    let secret = state.config.cluster.trunk_secret.as_deref().unwrap();
+    let mut alive = HashSet::new();
+    for key in keys {
+        let Some(node_id) = String::from_utf8(key.clone())
+            .ok()
+            .and_then(|key| Some(key.strip_prefix(KEY_PREFIX)?.to_owned()))
+        else {
+            continue;
+        };
+        if *node_id == *state.node_id {
+            continue;
+        }
+        let port = match connection.command(&[b"GET", &key]).await? {
+            Value::Bulk(port) => String::from_utf8_lossy(&port).parse().ok(),
+            _ => None,
+        };
+        let Some(port) = port else {
+            continue;
+        };
+        state
+            .relay
+            .add_sibling(&node_id, digest.clone(), port, secret);
+        alive.insert(node_id);
+    }
+    state.relay.retain_siblings(&alive);
+    Ok(())
+}
@@ -0,0 +1,329 @@
+//! WebSocket signaling: a session's control channel carried over a WebSocket, for clients that
+//! would rather not handle WebTransport streams, while their voice stays on WebTransport.
+//!
+//! A client that negotiates `FEATURE_WEBSOCKET_SIGNALING` is given a token in HELLO_ACK, opens a
+//! WebSocket to `/signaling?token=` with it, and from then on sends and receives control packets
+//! as binary messages, one packet to a message. The token is random, single use and only good
+//! while its WebTransport session lasts, so a socket can only join the session that offered it.
+//! The WebSocket framing (RFC 6455) is written here, over hyper's upgraded connection, so the
+//! server needs no WebSocket dependency; extensions and text messages are not supported.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use axum::Router;
+use axum::body::Body;
+use axum::extract::Query;
+use axum::extract::Request;
+use axum::extract::State;
+use axum::http::HeaderMap;
+use axum::http::HeaderValue;
+use axum::http::StatusCode;
+use axum::http::header;
+use axum::response::Response;
+use axum::routing::get;
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use bytes::Bytes;
+use bytes::BytesMut;
+use hyper::upgrade::Upgraded;
+use hyper_util::rt::TokioIo;
+use ring::digest;
+use serde::Deserialize;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWrite;
+use tokio::io::AsyncWriteExt;
+use tokio::io::BufReader;
+use tokio::sync::mpsc;
+use tokio::sync::oneshot;
+use tracing::Instrument;
+use tracing::debug;
+use tracing::warn;
+
+use crate::metrics::METRICS;
+use crate::state::ServerState;
+
+/// Appended to the client's key to answer the handshake, as RFC 6455 specifies.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// How many packets may wait to be written to a socket, or to be handled by its session.
+const QUEUE_LEN: usize = 64;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_UNSUPPORTED_DATA: u16 = 1003;
+const CLOSE_TOO_BIG: u16 = 1009;
+
+/// The sockets offered to sessions, by token, until their clients open them.
+#[derive(Default)]
+pub struct Signaling {
+    offers: Mutex<HashMap<String, oneshot::Sender<Socket>>>,
+}
+
+impl Signaling {
+    /// Offers a session a socket. Returns the token its client opens the socket with, and where
+    /// the socket arrives once it does.
+    pub fn offer(&self) -> (String, oneshot::Receiver<Socket>) {
+        let token = BASE64_URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>());
+        let (sender, receiver) = oneshot::channel();
+        let mut offers = self.offers.lock().unwrap();
+        // The offers of sessions that ended without their socket being opened.
+        offers.retain(|_, offer| !offer.is_closed());
+        offers.insert(token.clone(), sender);
+        (token, receiver)
+    }
+
+    /// Takes the offer with `token`, if its session still waits for it.
+    fn take(&self, token: &str) -> Option<oneshot::Sender<Socket>> {
+        self.offers
+            .lock()
+            .unwrap()
+            .remove(token)
+            .filter(|offer| !offer.is_closed())
+    }
+}
+
+/// A WebSocket opened for a session, until the session starts it.
+pub struct Socket {
+    io: TokioIo<Upgraded>,
+}
+
+impl Socket {
+    /// Starts reading and writing the socket, taking messages of up to `max_packet_len` bytes.
+    /// Returns the control packets the client sends, which end when the socket closes, and where
+    /// to send the server's.
+    pub fn start(self, max_packet_len: usize) -> (mpsc::Receiver<Bytes>, mpsc::Sender<Bytes>) {
+        let (reader, writer) = tokio::io::split(self.io);
+        let (incoming, incoming_rx) = mpsc::channel(QUEUE_LEN);
+        let (outgoing, outgoing_rx) = mpsc::channel(QUEUE_LEN);
+        let (replies, replies_rx) = mpsc::channel(1);
+        tokio::spawn(write(writer, outgoing_rx, replies_rx).in_current_span());
+        tokio::spawn(
+            read(BufReader::new(reader), incoming, replies, max_packet_len).in_current_span(),
+        );
+        (incoming_rx, outgoing)
+    }
+}
+
+/// A frame the server sends of its own accord, rather than a control packet.
+enum Reply {
+    Pong(Bytes),
+    Close(u16),
+}
+
+pub fn router(state: Arc<ServerState>) -> Router {
+    Router::new()
+        .route("/signaling", get(upgrade))
+        .with_state(state)
+}
+
+#[derive(Deserialize)]
+struct UpgradeQuery {
+    token: String,
+}
+
+/// Upgrades a request to a WebSocket and hands it to the session whose token it carries.
+async fn upgrade(
+    State(state): State<Arc<ServerState>>,
+    Query(query): Query<UpgradeQuery>,
+    mut request: Request,
+) -> Result<Response, (StatusCode, &'static str)> {
+    let headers = request.headers();
+    if !has_token(headers, header::UPGRADE, "websocket")
+        || !has_token(headers, header::CONNECTION, "upgrade")
+    {
+        return Err((StatusCode::UPGRADE_REQUIRED, "expected a WebSocket upgrade"));
+    }
+    if headers.get(header::SEC_WEBSOCKET_VERSION) != Some(&HeaderValue::from_static("13")) {
+        return Err((StatusCode::BAD_REQUEST, "unsupported WebSocket version"));
+    }
+    let Some(key) = headers.get(header::SEC_WEBSOCKET_KEY) else {
+        return Err((StatusCode::BAD_REQUEST, "missing Sec-WebSocket-Key"));
+    };
+    let accept = accept_key(key.as_bytes());
+    let Some(offer) = state.signaling.take(&query.token) else {
+        warn!("Refusing a WebSocket with an unknown signaling token");
+        return Err((StatusCode::NOT_FOUND, "unknown signaling token"));
+    };
+
+    let upgraded = hyper::upgrade::on(&mut request);
+    tokio::spawn(
+        async move {
+            match upgraded.await {
+                Ok(upgraded) => {
+                    METRICS.signaling_sockets.inc();
+                    let _ = offer.send(Socket {
+                        io: TokioIo::new(upgraded),
+                    });
+                }
+                Err(err) => debug!("Cannot upgrade to a WebSocket: {err}"),
+            }
+        }
+        .in_current_span(),
+    );
+
+    Ok(Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(header::UPGRADE, "websocket")
+        .header(header::CONNECTION, "Upgrade")
+        .header(header::SEC_WEBSOCKET_ACCEPT, accept)
+        .body(Body::empty())
+        .unwrap())
+}
+
+/// Whether a comma-separated header lists `token`, ignoring case.
+fn has_token(headers: &HeaderMap, name: header::HeaderName, token: &str) -> bool {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case(token))
+}
+
+/// The `Sec-WebSocket-Accept` answering a client's `Sec-WebSocket-Key`.
+fn accept_key(key: &[u8]) -> String {
+    let mut input = key.to_vec();
+    input.extend_from_slice(ACCEPT_GUID.as_bytes());
+    BASE64_STANDARD.encode(digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, &input))
+}
+
+/// Reads the client's messages into `incoming` until the socket closes or the session ends.
+async fn read(
+    mut reader: impl AsyncRead + Unpin,
+    incoming: mpsc::Sender<Bytes>,
+    replies: mpsc::Sender<Reply>,
+    max_packet_len: usize,
+) {
+    let code = tokio::select! {
+        code = read_messages(&mut reader, &incoming, &replies, max_packet_len) => code,
+        () = incoming.closed() => Some(CLOSE_NORMAL),
+    };
+    if let Some(code) = code {
+        let _ = replies.send(Reply::Close(code)).await;
+    }
+}
+
+/// Reads messages until the socket closes. Returns the close code to send the client, unless the
+/// connection is gone.
+async fn read_messages(
+    reader: &mut (impl AsyncRead + Unpin),
+    incoming: &mpsc::Sender<Bytes>,
+    replies: &mpsc::Sender<Reply>,
+    max_packet_len: usize,
+) -> Option<u16> {
+    // The message being reassembled from continuation frames, if any.
+    let mut message: Option<BytesMut> = None;
+    loop {
+        let mut head = [0; 2];
+        reader.read_exact(&mut head).await.ok()?;
+        let fin = head[0] & 0x80 != 0;
+        let opcode = head[0] & 0x0F;
+        // Reserved bits are for extensions, and none were negotiated. Clients must mask every
+        // frame they send.
+        if head[0] & 0x70 != 0 || head[1] & 0x80 == 0 {
+            return Some(CLOSE_PROTOCOL_ERROR);
+        }
+        let len = match head[1] & 0x7F {
+            126 => u64::from(reader.read_u16().await.ok()?),
+            127 => reader.read_u64().await.ok()?,
+            len => u64::from(len),
+        };
+        let is_control = opcode & 0x8 != 0;
+        if is_control && (!fin || len > 125) {
+            return Some(CLOSE_PROTOCOL_ERROR);
+        }
+        let buffered = message.as_ref().map_or(0, BytesMut::len) as u64;
+        if buffered + len > max_packet_len as u64 {
+            debug!("WebSocket message exceeds {max_packet_len} bytes");
+            return Some(CLOSE_TOO_BIG);
+        }
+
+        let mut mask = [0; 4];
+        reader.read_exact(&mut mask).await.ok()?;
+        let mut payload = vec![0; len as usize];
+        reader.read_exact(&mut payload).await.ok()?;
+        for (index, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[index % 4];
+        }
+
+        match opcode {
+            OPCODE_BINARY if message.is_none() => message = Some(BytesMut::from(&payload[..])),
+            OPCODE_CONTINUATION if message.is_some() => {
+                message.as_mut().unwrap().extend_from_slice(&payload);
+            }
+            OPCODE_TEXT => return Some(CLOSE_UNSUPPORTED_DATA),
+            OPCODE_CLOSE => return Some(CLOSE_NORMAL),
+            OPCODE_PING => {
+                replies.send(Reply::Pong(payload.into())).await.ok()?;
+                continue;
+            }
+            OPCODE_PONG => continue,
+            _ => return Some(CLOSE_PROTOCOL_ERROR),
+        }
+        if fin && let Some(message) = message.take() {
+            incoming.send(message.freeze()).await.ok()?;
+        }
+    }
+}
+
+/// Writes the server's control packets and replies until the socket closes.
+async fn write(
+    mut writer: impl AsyncWrite + Unpin,
+    mut outgoing: mpsc::Receiver<Bytes>,
+    mut replies: mpsc::Receiver<Reply>,
+) {
+    loop {
+        let (opcode, payload) = tokio::select! {
+            // Replies go first, so a close is not held up behind packets.
+            biased;
+            reply = replies.recv() => match reply {
+                Some(Reply::Pong(payload)) => (OPCODE_PONG, payload),
+                Some(Reply::Close(code)) => (OPCODE_CLOSE, Bytes::copy_from_slice(&code.to_be_bytes())),
+                None => break,
+            },
+            packet = outgoing.recv() => match packet {
+                Some(packet) => (OPCODE_BINARY, packet),
+                None => (OPCODE_CLOSE, Bytes::copy_from_slice(&CLOSE_NORMAL.to_be_bytes())),
+            },
+        };
+        if write_frame(&mut writer, opcode, &payload).await.is_err() || opcode == OPCODE_CLOSE {
+            break;
+        }
+    }
+    let _ = writer.shutdown().await;
+}
+
+/// Writes an unmasked frame holding a whole message, as servers send them.
+async fn write_frame(
+    writer: &mut (impl AsyncWrite + Unpin),
+    opcode: u8,
+    payload: &[u8],
+) -> std::io::Result<()> {
+    let mut head = Vec::with_capacity(10);
+    head.push(0x80 | opcode);
+    match payload.len() {
+        len @ 0..=125 => head.push(len as u8),
+        len @ 126..=0xFFFF => {
+            head.push(126);
+            head.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            head.push(127);
+            head.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    writer.write_all(&head).await?;
+    writer.write_all(payload).await?;
+    writer.flush().await
+}
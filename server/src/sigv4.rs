@@ -0,0 +1,105 @@
+//! AWS Signature Version 4, which authenticates requests to S3, KMS and compatible services.
+
+use std::fmt::Write;
+use std::time::SystemTime;
+
+use ring::digest;
+use ring::hmac;
+
+/// The credentials and scope requests are signed for.
+pub struct Signer<'a> {
+    pub access_key_id: &'a str,
+    pub secret_access_key: &'a str,
+    pub region: &'a str,
+
+    /// Such as `s3` or `kms`.
+    pub service: &'a str,
+}
+
+impl Signer<'_> {
+    /// The headers that authenticate a request without a query string to `authority` (the
+    /// `host[:port]` it is sent to), to be sent alongside its other headers.
+    pub fn sign(
+        &self,
+        method: &str,
+        authority: &str,
+        path: &str,
+        body: &[u8],
+    ) -> Vec<(&'static str, String)> {
+        let (date, time) = utc_now();
+        let timestamp = format!("{date}T{time}Z");
+        let payload_hash = hex(digest::digest(&digest::SHA256, body).as_ref());
+        let scope = format!("{date}/{}/{}/aws4_request", self.region, self.service);
+
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical_request = format!(
+            "{method}\n{path}\n\nhost:{authority}\nx-amz-content-sha256:{payload_hash}\n\
+             x-amz-date:{timestamp}\n\n{signed_headers}\n{payload_hash}"
+        );
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{timestamp}\n{scope}\n{}",
+            hex(digest::digest(&digest::SHA256, canonical_request.as_bytes()).as_ref())
+        );
+
+        let mut key = format!("AWS4{}", self.secret_access_key).into_bytes();
+        for part in [date.as_str(), self.region, self.service, "aws4_request"] {
+            key = hmac_sha256(&key, part.as_bytes());
+        }
+        let signature = hex(&hmac_sha256(&key, string_to_sign.as_bytes()));
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, \
+             Signature={signature}",
+            self.access_key_id
+        );
+
+        vec![
+            ("Authorization", authorization),
+            ("x-amz-content-sha256", payload_hash),
+            ("x-amz-date", timestamp),
+        ]
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, key), data)
+        .as_ref()
+        .to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        write!(hex, "{byte:02x}").unwrap();
+    }
+    hex
+}
+
+/// The current UTC date as `YYYYMMDD` and time as `HHMMSS`.
+fn utc_now() -> (String, String) {
+    let secs = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, secs) = (secs / 86400, secs % 86400);
+
+    // Howard Hinnant's days-to-civil algorithm.
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    (
+        format!("{year:04}{month:02}{day:02}"),
+        format!("{:02}{:02}{:02}", secs / 3600, secs % 3600 / 60, secs % 60),
+    )
+}
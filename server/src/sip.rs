@@ -0,0 +1,594 @@
+//! A SIP dial-in bridge, so phone callers can join rooms through a SIP trunk.
+//!
+//! The bridge answers INVITEs over UDP at `sip.listen`. The user part of the request URI names
+//! the room, so a call to `sip:standup@voice.example.com` joins the room `standup`. Each call
+//! is registered as a participant under its own session ID, named after the caller, and
+//! exchanges G.711 audio with the room over RTP: the caller's audio is decoded and resampled
+//! into room audio, and everyone else in the room is mixed down into one stream for the caller.
+//!
+//! Only PCMU and PCMA are offered. Opus is not supported yet, so calls supporting neither G.711
+//! law, including those offering only Opus, are refused with 488. Callers are not
+//! authenticated, so only the trunks listed in `sip.trunks` may place calls.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use anyhow::Context;
+use anyhow::Result;
+use anyhow::bail;
+use tokio::net::UdpSocket;
+use tokio::sync::Notify;
+use tracing::Instrument;
+use tracing::debug;
+use tracing::info;
+use tracing::info_span;
+use tracing::warn;
+
+use crate::config::Config;
+use crate::g711;
+use crate::g711::Law;
+use crate::metrics::METRICS;
+use crate::participant::FRAME_INTERVAL;
+use crate::participant::JoinRefused;
+use crate::participant::Participant;
+use crate::rooms::SessionId;
+use crate::rtp;
+use crate::state::ServerState;
+
+/// The largest SIP message accepted.
+const MAX_MESSAGE_LEN: usize = 65535;
+
+/// The largest RTP packet accepted.
+const MAX_RTP_LEN: usize = 2048;
+
+pub fn validate(config: &Config) -> Result<()> {
+    if config.sip.listen.is_some() && config.sip.trunks.is_empty() {
+        bail!("sip.listen needs sip.trunks, since callers are not authenticated");
+    }
+    Ok(())
+}
+
+pub struct Bridge {
+    socket: UdpSocket,
+    state: Arc<ServerState>,
+
+    /// Answered calls, by Call-ID.
+    calls: Mutex<HashMap<String, Call>>,
+}
+
+struct Call {
+    /// The response to the INVITE, sent again if the INVITE is retransmitted.
+    answer: String,
+
+    hang_up: Arc<Notify>,
+}
+
+/// A call's audio stream, and its place in the room.
+struct Media {
+    socket: UdpSocket,
+
+    /// Where the caller's audio comes from and is sent.
+    remote: Remote,
+
+    law: Law,
+    participant: Participant,
+}
+
+/// Where a call's audio is exchanged with the caller.
+struct Remote {
+    /// Where the caller's audio is sent.
+    address: SocketAddr,
+
+    /// The address the call was placed from.
+    signaling: IpAddr,
+
+    /// Whether audio has come from `address`, after which no other address is accepted.
+    latched: bool,
+}
+
+impl Remote {
+    fn new(offered: SocketAddr, signaling: IpAddr) -> Self {
+        Self {
+            address: offered,
+            signaling,
+            latched: false,
+        }
+    }
+
+    /// Whether to take a packet from `from`. Until audio arrives, it may come from the IP the
+    /// SDP offered or the one the call was placed from: behind NAT the caller's audio comes
+    /// from elsewhere than its SDP says, and the way back is the way it came. The first packet
+    /// taken fixes the address, so no one else can take over the call's audio.
+    fn accept(&mut self, from: SocketAddr) -> bool {
+        if self.latched {
+            return from == self.address;
+        }
+        if from.ip() != self.address.ip() && from.ip() != self.signaling {
+            return false;
+        }
+        self.address = from;
+        self.latched = true;
+        true
+    }
+}
+
+impl Bridge {
+    pub async fn bind(listen: SocketAddr, state: Arc<ServerState>) -> Result<Arc<Self>> {
+        let socket = UdpSocket::bind(listen)
+            .await
+            .with_context(|| format!("Cannot listen for SIP calls on {listen}"))?;
+        info!("Accepting SIP calls on {listen}");
+
+        Ok(Arc::new(Self {
+            socket,
+            state,
+            calls: Mutex::default(),
+        }))
+    }
+
+    /// Answers SIP requests until the task is dropped.
+    pub async fn serve(self: Arc<Self>) {
+        let mut buffer = vec![0; MAX_MESSAGE_LEN];
+        loop {
+            let (len, from) = match self.socket.recv_from(&mut buffer).await {
+                Ok(received) => received,
+                Err(err) => {
+                    warn!("Cannot receive SIP message: {err}");
+                    continue;
+                }
+            };
+
+            // Keepalives and the responses to our BYEs need no answer.
+            let Some(request) = std::str::from_utf8(&buffer[..len])
+                .ok()
+                .and_then(Request::parse)
+            else {
+                debug!("Ignoring SIP message from {from}");
+                continue;
+            };
+            if let Err(err) = self.handle(&request, from).await {
+                warn!("Cannot handle SIP {} from {from}: {err:#}", request.method);
+            }
+        }
+    }
+
+    async fn handle(self: &Arc<Self>, request: &Request<'_>, from: SocketAddr) -> Result<()> {
+        let response = match request.method {
+            "INVITE" => self.invite(request, from).await?,
+            "ACK" => return Ok(()),
+            "BYE" => {
+                let call = request
+                    .header("call-id")
+                    .and_then(|call_id| self.calls.lock().unwrap().remove(call_id));
+                match call {
+                    Some(call) => {
+                        call.hang_up.notify_one();
+                        request.respond("200 OK", None, &[], "")
+                    }
+                    None => request.respond("481 Call/Transaction Does Not Exist", None, &[], ""),
+                }
+            }
+            // Calls are answered at once, so there is never a pending INVITE to cancel.
+            "CANCEL" => request.respond("200 OK", None, &[], ""),
+            "OPTIONS" => request.respond(
+                "200 OK",
+                None,
+                &[("Allow", "INVITE, ACK, BYE, CANCEL, OPTIONS".to_owned())],
+                "",
+            ),
+            _ => request.respond("501 Not Implemented", None, &[], ""),
+        };
+
+        self.socket.send_to(response.as_bytes(), from).await?;
+        Ok(())
+    }
+
+    /// Answers an INVITE, joining the caller to the room it names. Returns the response.
+    async fn invite(self: &Arc<Self>, request: &Request<'_>, from: SocketAddr) -> Result<String> {
+        let config = &self.state.config.sip;
+        if !config.trunks.contains(&from.ip()) {
+            warn!("Refusing call from {from}, which is not a configured trunk");
+            return Ok(request.respond("403 Forbidden", None, &[], ""));
+        }
+
+        let Some(call_id) = request.header("call-id") else {
+            return Ok(request.respond("400 Bad Request", None, &[], ""));
+        };
+        if let Some(call) = self.calls.lock().unwrap().get(call_id) {
+            return Ok(call.answer.clone());
+        }
+        let Some(room_key) = uri_user(request.uri) else {
+            return Ok(request.respond("404 Not Found", None, &[], ""));
+        };
+        let Some(offer) = Offer::parse(request.body) else {
+            info!("Refusing call without a PCMU or PCMA audio stream; Opus is not supported yet");
+            return Ok(request.respond("488 Not Acceptable Here", None, &[], ""));
+        };
+
+        let media_ip = match config.media_address {
+            Some(ip) => ip,
+            None => route_to(offer.address).await?,
+        };
+        let socket = UdpSocket::bind(SocketAddr::new(unspecified(media_ip), 0)).await?;
+
+        let caller = request
+            .header("from")
+            .and_then(uri_user)
+            .unwrap_or("caller");
+        let hang_up = Arc::new(Notify::new());
+        let participant = match Participant::join(
+            &self.state,
+            &format!("phone {caller}"),
+            room_key,
+            hang_up.clone(),
+        )
+        .await
+        {
+            Ok(participant) => participant,
+            Err(JoinRefused::UsernameTaken) => {
+                return Ok(request.respond("486 Busy Here", None, &[], ""));
+            }
+            Err(JoinRefused::InvalidRoomKey) => {
+                return Ok(request.respond("404 Not Found", None, &[], ""));
+            }
+        };
+        let session_id = participant.session_id();
+
+        let to_tag = format!("{:016x}", rand::random::<u64>());
+        let contact = SocketAddr::new(media_ip, self.socket.local_addr()?.port());
+        let sdp = answer_sdp(media_ip, socket.local_addr()?.port(), offer.law, session_id);
+        let answer = request.respond(
+            "200 OK",
+            Some(&to_tag),
+            &[
+                ("Contact", format!("<sip:{contact}>")),
+                ("Content-Type", "application/sdp".to_owned()),
+            ],
+            &sdp,
+        );
+        let bye = request.bye(contact, &to_tag);
+
+        self.calls.lock().unwrap().insert(
+            call_id.to_owned(),
+            Call {
+                answer: answer.clone(),
+                hang_up: hang_up.clone(),
+            },
+        );
+        METRICS.sip_calls.inc();
+        info!(
+            "Answered call from '{caller}' into room '{room_key}' ({}, session_id: {session_id})",
+            offer.law.name()
+        );
+
+        let media = Media {
+            socket,
+            remote: Remote::new(offer.address, from.ip()),
+            law: offer.law,
+            participant,
+        };
+        tokio::spawn(
+            self.clone()
+                .run_call(call_id.to_owned(), from, bye, media, hang_up)
+                .instrument(info_span!("Call", session_id)),
+        );
+
+        Ok(answer)
+    }
+
+    /// Runs a call until either side hangs up, then takes the caller out of the room.
+    async fn run_call(
+        self: Arc<Self>,
+        call_id: String,
+        caller: SocketAddr,
+        bye: String,
+        media: Media,
+        hang_up: Arc<Notify>,
+    ) {
+        if let Err(err) = media.run(&self.state, &hang_up).await {
+            warn!("Call failed: {err:#}");
+        }
+
+        // Calls still listed were ended here rather than by the caller, who needs telling.
+        let ended_here = self.calls.lock().unwrap().remove(&call_id).is_some();
+        if ended_here && let Err(err) = self.socket.send_to(bye.as_bytes(), caller).await {
+            warn!("Cannot send BYE: {err}");
+        }
+        info!("Call ended");
+    }
+}
+
+impl Media {
+    /// Exchanges audio with the caller until the call is hung up or the caller goes quiet for
+    /// the session idle timeout.
+    async fn run(mut self, state: &ServerState, hang_up: &Notify) -> Result<()> {
+        let idle_timeout = state.config.session.idle_timeout();
+        let mut sender = rtp::Sender::new(self.law.payload_type());
+        let mut ticks = tokio::time::interval(FRAME_INTERVAL);
+        let mut last_received = Instant::now();
+        let mut previous_sample = 0;
+        let mut buffer = [0; MAX_RTP_LEN];
+
+        loop {
+            tokio::select! {
+                _ = hang_up.notified() => return Ok(()),
+                _ = ticks.tick() => {
+                    if last_received.elapsed() > idle_timeout {
+                        info!("No audio from the caller for {} seconds", idle_timeout.as_secs());
+                        return Ok(());
+                    }
+
+                    let pcm = g711::downsample(&self.participant.mix());
+                    let packet = sender.packet(&self.law.encode(&pcm), pcm.len() as u32);
+                    self.socket.send_to(&packet, self.remote.address).await?;
+                }
+                received = self.socket.recv_from(&mut buffer) => {
+                    let (len, from) = received?;
+                    if !self.remote.accept(from) {
+                        debug!("Ignoring RTP from {from}");
+                        continue;
+                    }
+                    // Other payload types, such as DTMF events, are ignored.
+                    let Some((payload_type, payload)) = rtp::parse(&buffer[..len]) else {
+                        continue;
+                    };
+                    if payload_type != self.law.payload_type() {
+                        continue;
+                    }
+                    last_received = Instant::now();
+
+                    let pcm = g711::upsample(&self.law.decode(payload), &mut previous_sample);
+                    self.participant.speak(state, &pcm);
+                }
+            }
+        }
+    }
+}
+
+/// A SIP request, borrowing from the message it was parsed from.
+struct Request<'a> {
+    method: &'a str,
+    uri: &'a str,
+    headers: Vec<(&'a str, &'a str)>,
+    body: &'a str,
+}
+
+impl<'a> Request<'a> {
+    /// Parses a request, or returns `None` for responses and malformed messages.
+    fn parse(message: &'a str) -> Option<Self> {
+        let (head, body) = message.split_once("\r\n\r\n").unwrap_or((message, ""));
+        let mut lines = head.split("\r\n");
+        let mut start = lines.next()?.split(' ');
+        let (method, uri) = (start.next()?, start.next()?);
+        if start.next()? != "SIP/2.0" {
+            return None;
+        }
+
+        let headers = lines
+            .filter_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                Some((name.trim(), value.trim()))
+            })
+            .collect();
+
+        Some(Self {
+            method,
+            uri,
+            headers,
+            body,
+        })
+    }
+
+    /// The values of every header with the given lowercase name, or its compact form.
+    fn headers(&self, name: &str) -> impl Iterator<Item = &'a str> {
+        let compact = match name {
+            "via" => "v",
+            "from" => "f",
+            "to" => "t",
+            "call-id" => "i",
+            "contact" => "m",
+            _ => name,
+        };
+        self.headers
+            .iter()
+            .filter(move |(header, _)| {
+                header.eq_ignore_ascii_case(name) || header.eq_ignore_ascii_case(compact)
+            })
+            .map(|(_, value)| *value)
+    }
+
+    fn header(&self, name: &str) -> Option<&'a str> {
+        self.headers(name).next()
+    }
+
+    /// Builds a response, adding `to_tag` to the To header if it has no tag yet.
+    fn respond(
+        &self,
+        status: &str,
+        to_tag: Option<&str>,
+        headers: &[(&str, String)],
+        body: &str,
+    ) -> String {
+        let mut response = format!("SIP/2.0 {status}\r\n");
+        for via in self.headers("via") {
+            let _ = write!(response, "Via: {via}\r\n");
+        }
+        let to = self.header("to").unwrap_or_default();
+        let _ = write!(response, "To: {}\r\n", with_tag(to, to_tag));
+        for name in ["From", "Call-ID", "CSeq"] {
+            if let Some(value) = self.header(&name.to_ascii_lowercase()) {
+                let _ = write!(response, "{name}: {value}\r\n");
+            }
+        }
+        for (name, value) in headers {
+            let _ = write!(response, "{name}: {value}\r\n");
+        }
+        let _ = write!(response, "Content-Length: {}\r\n\r\n{body}", body.len());
+        response
+    }
+
+    /// Builds the BYE that ends the call this INVITE started, from our side.
+    fn bye(&self, contact: SocketAddr, to_tag: &str) -> String {
+        let from = self.header("from").unwrap_or_default();
+        let to = with_tag(self.header("to").unwrap_or_default(), Some(to_tag));
+        let target = self
+            .header("contact")
+            .or(self.header("from"))
+            .and_then(|uri| uri.split_once('<'))
+            .and_then(|(_, uri)| uri.split_once('>'))
+            .map_or(self.uri, |(uri, _)| uri);
+        format!(
+            "BYE {target} SIP/2.0\r\n\
+             Via: SIP/2.0/UDP {contact};branch=z9hG4bK{:016x}\r\n\
+             Max-Forwards: 70\r\n\
+             From: {to}\r\n\
+             To: {from}\r\n\
+             Call-ID: {}\r\n\
+             CSeq: 1 BYE\r\n\
+             Content-Length: 0\r\n\r\n",
+            rand::random::<u64>(),
+            self.header("call-id").unwrap_or_default(),
+        )
+    }
+}
+
+fn with_tag(header: &str, tag: Option<&str>) -> String {
+    match tag {
+        Some(tag) if !header.contains(";tag=") => format!("{header};tag={tag}"),
+        _ => header.to_owned(),
+    }
+}
+
+/// The user part of a SIP URI, alone or in a From or To header.
+fn uri_user(uri: &str) -> Option<&str> {
+    let uri = uri.split_once('<').map_or(uri, |(_, uri)| uri);
+    let uri = uri.strip_prefix("sip:").or(uri.strip_prefix("sips:"))?;
+    let (user, _) = uri.split_once('@')?;
+    let user = user.split([':', ';']).next()?;
+    (!user.is_empty()).then_some(user)
+}
+
+/// The audio stream offered in an INVITE's SDP.
+struct Offer {
+    address: SocketAddr,
+    law: Law,
+}
+
+impl Offer {
+    /// Parses the offer's first audio stream, if it supports PCMU or PCMA.
+    fn parse(sdp: &str) -> Option<Self> {
+        let mut session_ip = None;
+        let mut media_ip = None;
+        let mut audio = None;
+        for line in sdp.lines() {
+            if let Some(connection) = line.strip_prefix("c=") {
+                let ip = connection.split(' ').nth(2)?.parse().ok();
+                if audio.is_some() {
+                    media_ip = media_ip.or(ip);
+                } else {
+                    session_ip = ip;
+                }
+            } else if let Some(media) = line.strip_prefix("m=") {
+                if audio.is_some() {
+                    break;
+                }
+                let mut fields = media.split(' ');
+                if fields.next()? != "audio" {
+                    continue;
+                }
+                let port: u16 = fields.next()?.parse().ok()?;
+                // The rest are payload types, in order of preference.
+                let law = fields
+                    .skip(1)
+                    .filter_map(|payload_type| payload_type.parse().ok())
+                    .find_map(Law::from_payload_type)?;
+                audio = Some((port, law));
+            }
+        }
+
+        let (port, law) = audio?;
+        Some(Self {
+            address: SocketAddr::new(media_ip.or(session_ip)?, port),
+            law,
+        })
+    }
+}
+
+fn answer_sdp(ip: IpAddr, port: u16, law: Law, session_id: SessionId) -> String {
+    let family = if ip.is_ipv4() { "IP4" } else { "IP6" };
+    let payload_type = law.payload_type();
+    format!(
+        "v=0\r\n\
+         o=- {session_id} 1 IN {family} {ip}\r\n\
+         s=voice\r\n\
+         c=IN {family} {ip}\r\n\
+         t=0 0\r\n\
+         m=audio {port} RTP/AVP {payload_type}\r\n\
+         a=rtpmap:{payload_type} {}/8000\r\n\
+         a=ptime:{}\r\n\
+         a=sendrecv\r\n",
+        law.name(),
+        FRAME_INTERVAL.as_millis(),
+    )
+}
+
+/// The local address of the route to `remote`, which is where it can reach this host.
+async fn route_to(remote: SocketAddr) -> Result<IpAddr> {
+    let socket = UdpSocket::bind(SocketAddr::new(unspecified(remote.ip()), 0)).await?;
+    socket
+        .connect(remote)
+        .await
+        .with_context(|| format!("No route to caller at {remote}"))?;
+    Ok(socket.local_addr()?.ip())
+}
+
+/// The unspecified address of the same family as `ip`, to bind sockets reaching it.
+fn unspecified(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::Remote;
+    use crate::config::Config;
+
+    fn address(address: &str) -> SocketAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn calls_need_trunks() {
+        let mut config = Config::default();
+        config.sip.listen = Some(address("0.0.0.0:5060"));
+        assert!(super::validate(&config).is_err());
+
+        config.sip.trunks.push("203.0.113.5".parse().unwrap());
+        assert!(super::validate(&config).is_ok());
+    }
+
+    #[test]
+    fn media_is_taken_from_the_caller_alone() {
+        let mut remote = Remote::new(address("192.168.1.10:4000"), "203.0.113.5".parse().unwrap());
+        assert!(!remote.accept(address("198.51.100.7:4000")));
+
+        // Behind NAT, from the address the call was placed from, on a port of the NAT's.
+        assert!(remote.accept(address("203.0.113.5:31000")));
+        assert_eq!(remote.address, address("203.0.113.5:31000"));
+
+        assert!(!remote.accept(address("192.168.1.10:4000")));
+        assert!(!remote.accept(address("203.0.113.5:31001")));
+        assert_eq!(remote.address, address("203.0.113.5:31000"));
+    }
+}
@@ -0,0 +1,82 @@
+//! Limiting listeners that cannot keep up to fewer speakers.
+//!
+//! Once a second, each session checks whether its audio queue overflowed, dropping or expiring
+//! frames. After `session.slow_consumer_secs` such seconds in a row, the listener is sent half
+//! as many speakers as it was, and half again each time it keeps overflowing, down to one. After
+//! `session.slow_consumer_recovery_secs` seconds without overflowing, it is sent every speaker
+//! again. The server has no lower quality to downgrade listeners to, so speakers are all it
+//! sheds.
+
+use crate::config::SessionConfig;
+use crate::metrics::METRICS;
+use crate::outbox::Outbox;
+
+/// What a check changed about the speakers sent to a listener.
+pub enum Change {
+    /// The listener is sent at most this many speakers at once.
+    Limited(usize),
+
+    /// The listener is sent every speaker again.
+    Lifted,
+}
+
+/// Tracks how a listener's audio queue has fared between checks.
+#[derive(Default)]
+pub struct SlowConsumer {
+    /// Seconds in a row the queue overflowed.
+    overflowing_secs: u64,
+
+    /// Seconds in a row the queue did not overflow while limited.
+    clear_secs: u64,
+}
+
+impl SlowConsumer {
+    /// Checks the second that just ended, given how many speakers were sent in it, and limits
+    /// or lifts the listener's speakers if it is time to.
+    pub fn check(
+        &mut self,
+        outbox: &Outbox,
+        config: &SessionConfig,
+        forwarded_speakers: usize,
+    ) -> Option<Change> {
+        let audio = outbox.audio();
+        let limit = audio.max_speakers();
+        if config.slow_consumer_secs == 0 {
+            *self = Self::default();
+            audio.take_overflowed();
+            // Limits from before a reload turned this off are lifted at once.
+            return limit.map(|_| self.lift(outbox));
+        }
+
+        if !audio.take_overflowed() {
+            self.overflowing_secs = 0;
+            limit?;
+            self.clear_secs += 1;
+            return (self.clear_secs >= config.slow_consumer_recovery_secs)
+                .then(|| self.lift(outbox));
+        }
+
+        self.clear_secs = 0;
+        self.overflowing_secs += 1;
+        if self.overflowing_secs < config.slow_consumer_secs {
+            return None;
+        }
+        self.overflowing_secs = 0;
+        let speakers = limit.unwrap_or(forwarded_speakers);
+        let max_speakers = (speakers / 2).max(1);
+        // A listener sent a single speaker has nobody left to shed.
+        if max_speakers >= speakers {
+            return None;
+        }
+        audio.limit_speakers(Some(max_speakers));
+        METRICS.slow_consumer_downgrades.inc();
+        Some(Change::Limited(max_speakers))
+    }
+
+    fn lift(&mut self, outbox: &Outbox) -> Change {
+        self.clear_secs = 0;
+        outbox.audio().limit_speakers(None);
+        METRICS.slow_consumer_recoveries.inc();
+        Change::Lifted
+    }
+}
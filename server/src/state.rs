@@ -0,0 +1,309 @@
+//! State shared by every session.
+
+use std::sync::Arc;
+use std::sync::RwLock;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+
+use anyhow::Result;
+use protobuf::system;
+use protobuf::system::PacketType;
+
+use crate::accounts::AccountStore;
+use crate::accounts::FileStore;
+use crate::api_keys::ApiKeys;
+use crate::blobs;
+use crate::blobs::BlobStore;
+use crate::bootstrap::SessionTokens;
+use crate::broadcast::Broadcasts;
+use crate::buffer_pool::BufferPool;
+use crate::chaos;
+use crate::chat_history::ChatHistory;
+use crate::config::Config;
+use crate::drain::Drain;
+use crate::events::Event;
+use crate::files::Files;
+use crate::geoip::GeoIp;
+use crate::handoff::Handoffs;
+use crate::latency;
+use crate::lobby;
+use crate::lobby::Lobby;
+use crate::maintenance::Maintenance;
+use crate::memory::MEMORY;
+use crate::metrics::METRICS;
+use crate::mixer::Mixer;
+use crate::mixer::Pcm16Codec;
+use crate::placement::Placement;
+use crate::plugin::Plugins;
+use crate::protocol;
+use crate::protocol::VoiceFrame;
+use crate::recordings::Recordings;
+use crate::rooms::Registration;
+use crate::rooms::Registry;
+use crate::schedule::Schedule;
+use crate::siblings;
+use crate::signaling::Signaling;
+use crate::sip;
+use crate::store::MemoryStore;
+use crate::store::RedisStore;
+use crate::store::StateStore;
+use crate::tenants;
+use crate::trunk::Relay;
+use crate::usage::Usage;
+#[cfg(feature = "chaos")]
+use tracing::debug;
+
+pub struct ServerState {
+    pub config: Arc<Config>,
+
+    /// This instance's name among the nodes sharing rooms.
+    pub node_id: Arc<str>,
+
+    pub registry: Arc<Registry>,
+    pub buffers: Arc<BufferPool>,
+    pub mixer: Option<Mixer>,
+
+    /// The room directory shared with other nodes.
+    pub store: Arc<dyn StateStore>,
+
+    /// Trunks to the other nodes sharing rooms.
+    pub relay: Relay,
+
+    /// Which node each room belongs to, in clustered mode.
+    pub placement: Option<Placement>,
+
+    /// User accounts, if logins require them.
+    pub accounts: Option<Arc<dyn AccountStore>>,
+
+    /// The rooms whose mix is being streamed.
+    pub broadcasts: Broadcasts,
+
+    pub plugins: Plugins,
+
+    /// Where shared files are stored, if anywhere.
+    pub blobs: Option<Arc<dyn BlobStore>>,
+
+    pub files: Files,
+
+    /// What each tenant used, for billing.
+    pub usage: Usage,
+
+    /// The API keys created through the admin API.
+    pub api_keys: ApiKeys,
+
+    /// Whether the server is draining for a deploy.
+    pub drain: Drain,
+
+    /// The maintenance window scheduled through the admin API.
+    pub maintenance: Maintenance,
+
+    /// The WebSockets offered to sessions to carry their control packets.
+    pub signaling: Signaling,
+
+    /// Sessions a draining server handed over, for their clients to resume.
+    pub handoffs: Handoffs,
+
+    /// The session tokens issued by `POST /session`, for clients to log in with.
+    pub session_tokens: SessionTokens,
+
+    /// The databases clients are located in.
+    pub geoip: GeoIp,
+
+    /// Each room's recent chat messages, for clients that join it.
+    pub chat_history: ChatHistory,
+
+    /// The rooms booked through the admin API.
+    pub schedule: Schedule,
+
+    /// The recordings of scheduled rooms, kept with shared files.
+    pub recordings: Recordings,
+
+    /// Who waits to join the rooms with a waiting room.
+    pub lobby: Arc<Lobby>,
+
+    /// The config with the settings reloaded since startup, which are only ever read from here.
+    live_config: RwLock<Arc<Config>>,
+
+    /// The ID of the last chat message delivered.
+    last_message_id: AtomicU64,
+}
+
+impl ServerState {
+    pub fn new(config: Config) -> Result<Arc<Self>> {
+        tenants::validate(&config)?;
+        siblings::validate(&config)?;
+        chaos::validate(&config)?;
+        lobby::validate(&config)?;
+        sip::validate(&config)?;
+        MEMORY.set_soft_limit(config.memory.soft_limit());
+        let buffers = BufferPool::new(protocol::MAX_DATAGRAM_SIZE, config.session.buffer_pool_size);
+
+        let registry = Arc::new(Registry::default());
+        let mixer = config
+            .mixer
+            .enabled
+            .then(|| Mixer::new(&config.mixer, registry.clone(), Arc::new(Pcm16Codec)));
+
+        let node_id: Arc<str> = match &config.cluster.node_id {
+            Some(node_id) => node_id.as_str().into(),
+            None => format!("{:016x}", rand::random::<u64>()).into(),
+        };
+        let store: Arc<dyn StateStore> = match &config.cluster.redis_url {
+            Some(url) => RedisStore::new(url.clone(), node_id.clone(), registry.clone()),
+            None => Arc::new(MemoryStore),
+        };
+
+        let accounts = match &config.accounts.path {
+            Some(path) => Some(Arc::new(FileStore::open(path.clone())?) as Arc<dyn AccountStore>),
+            None => None,
+        };
+        let relay = Relay::new(&config);
+        let plugins = Plugins::new(&config)?;
+        let blobs = blobs::open(&config.storage)?;
+        let files = Files::new(&config.files);
+        let recordings = Recordings::new(blobs.clone());
+        let api_keys = ApiKeys::open(config.admin.api_keys_path.clone())?;
+        let geoip = GeoIp::open(&config.geoip)?;
+        let chat_history = ChatHistory::open(&config.chat)?;
+        let schedule = Schedule::open(config.schedule.path.clone())?;
+        let lobby = Arc::new(Lobby::new(registry.clone()));
+        let last_message_id = AtomicU64::new(chat_history.last_message_id());
+        let placement = config
+            .cluster
+            .advertise_address
+            .as_ref()
+            .map(|address| Placement::new(address, &config.cluster.peers));
+
+        let config = Arc::new(config);
+        Ok(Arc::new(Self {
+            live_config: RwLock::new(config.clone()),
+            config,
+            node_id,
+            registry,
+            buffers,
+            mixer,
+            store,
+            relay,
+            placement,
+            accounts,
+            broadcasts: Broadcasts::default(),
+            plugins,
+            blobs,
+            files,
+            usage: Usage::default(),
+            api_keys,
+            drain: Drain::default(),
+            maintenance: Maintenance::default(),
+            signaling: Signaling::default(),
+            handoffs: Handoffs::default(),
+            session_tokens: SessionTokens::default(),
+            geoip,
+            chat_history,
+            schedule,
+            recordings,
+            lobby,
+            last_message_id,
+        }))
+    }
+
+    /// The ID of the last chat message delivered, which is handed over when draining.
+    pub fn last_message_id(&self) -> u64 {
+        self.last_message_id.load(Ordering::Relaxed)
+    }
+
+    /// Continues chat message IDs after `id`, from a server handing its rooms over, unless
+    /// this one's are already past it.
+    pub fn continue_message_ids(&self, id: u64) {
+        self.last_message_id.fetch_max(id, Ordering::Relaxed);
+    }
+
+    /// The config as last reloaded, for the reloadable settings.
+    pub fn live_config(&self) -> Arc<Config> {
+        self.live_config.read().unwrap().clone()
+    }
+
+    pub fn set_live_config(&self, config: Config) {
+        *self.live_config.write().unwrap() = Arc::new(config);
+    }
+
+    /// Forwards a voice frame to everyone else in the speaker's room, or to the room's mixer,
+    /// and to the other nodes with members in the room, unless the speaker is muted.
+    pub fn forward_voice(&self, speaker: &Registration, mut frame: VoiceFrame) {
+        let Some(room_key) = speaker.room_key() else {
+            return;
+        };
+        if speaker.is_muted() {
+            return;
+        }
+        self.relay
+            .forward(room_key, frame.header(), frame.payload());
+
+        if let Some(mixer) = &self.mixer {
+            mixer.submit(room_key, speaker.session_id(), frame.payload().clone());
+            return;
+        }
+
+        #[cfg(feature = "chaos")]
+        if let Some(delay) = chaos::forward_delay(&self.live_config().chaos) {
+            debug!("Chaos: holding back a voice frame for {delay:?}");
+            let mut peers = Vec::new();
+            speaker.for_each_peer(|peer| peers.push(peer.clone()));
+            METRICS.audio_frames_forwarded.add(peers.len() as u64);
+            tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+                for peer in &peers {
+                    peer.push_voice(&mut frame);
+                }
+            });
+            return;
+        }
+
+        let peers = speaker.for_each_peer(|peer| peer.push_voice(&mut frame));
+        METRICS.audio_frames_forwarded.add(peers as u64);
+    }
+
+    /// Delivers a chat message to everyone in a room who does not block its sender, but those
+    /// whose control queue is full, keeps it in the room's history and publishes it to the room's
+    /// events, unless a plugin refuses it, returning what the sender is acknowledged with. Those
+    /// it missed can fetch it with CHAT_BACKLOG_REQUEST.
+    pub fn send_chat(
+        &self,
+        room_key: &str,
+        mut message: system::ChatMessage,
+    ) -> Result<system::ChatAck, String> {
+        self.plugins.on_chat(room_key, &mut message)?;
+        let client_message_id = std::mem::take(&mut message.client_message_id);
+        {
+            // Queueing never waits, so the lock is held only as long as it takes to queue the
+            // message for everyone.
+            let delivery = self.chat_history.delivery(room_key);
+            let _delivering = delivery.lock().unwrap();
+            message.message_id = self.last_message_id.fetch_add(1, Ordering::Relaxed) + 1;
+            message.sent_at_ms = latency::now_us() / 1000;
+            self.chat_history.record(room_key, &mut message);
+            let packet = protocol::encode(PacketType::ChatMessage, &message);
+            for outbox in self.registry.room_audience(room_key, &message.username) {
+                outbox.try_send_control(packet.clone());
+            }
+        }
+        let ack = system::ChatAck {
+            client_message_id,
+            message_id: message.message_id,
+            previous_message_id: message.previous_message_id,
+            sent_at_ms: message.sent_at_ms,
+        };
+        self.registry.events().publish(Event::Chat {
+            room_key: room_key.into(),
+            message,
+        });
+        Ok(ack)
+    }
+
+    /// Delivers a reaction to everyone in a room, but those whose control queue is full.
+    pub fn send_reaction(&self, room_key: &str, reaction: &system::Reaction) {
+        let packet = protocol::encode(PacketType::Reaction, reaction);
+        for (_, outbox) in self.registry.room_members(room_key) {
+            outbox.try_send_control(packet.clone());
+        }
+    }
+}
@@ -0,0 +1,85 @@
+//! The once-per-second SESSION_STATS report sent to clients.
+//!
+//! Loss comes from QUIC's packet counters for the connection, RTT from its smoothed estimate,
+//! and jitter from the arrival times of the client's voice datagrams. The forwarded speaker
+//! count is the number of distinct speakers the client's outbox sent audio from.
+
+use std::time::Duration;
+use std::time::Instant;
+
+use protobuf::system;
+
+use crate::outbox::Outbox;
+use crate::transport::Transport;
+
+/// How often SESSION_STATS is sent.
+pub const STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Collects a session's stats between reports.
+#[derive(Default)]
+pub struct StatsWindow {
+    jitter: Jitter,
+    lost_packets: u64,
+    sent_packets: u64,
+}
+
+impl StatsWindow {
+    /// Records the arrival of a voice datagram, with its capture time if the client sent one.
+    pub fn record_voice(&mut self, capture_time_us: Option<u64>) {
+        self.jitter.record(Instant::now(), capture_time_us);
+    }
+
+    /// Builds the report for the window that just ended and starts a new one.
+    pub fn report(&mut self, connection: &dyn Transport, outbox: &Outbox) -> system::SessionStats {
+        let path = connection.stats().path;
+        // The counters restart if the client migrates to a new path.
+        let lost = path.lost_packets.saturating_sub(self.lost_packets);
+        let sent = path.sent_packets.saturating_sub(self.sent_packets);
+        self.lost_packets = path.lost_packets;
+        self.sent_packets = path.sent_packets;
+
+        system::SessionStats {
+            loss: if sent == 0 {
+                0.0
+            } else {
+                lost as f32 / sent as f32
+            },
+            jitter_us: self.jitter.get(),
+            rtt_us: connection.rtt().as_micros().min(u32::MAX.into()) as u32,
+            forwarded_speakers: outbox.take_speaker_count() as u32,
+            audio_on_streams: outbox.audio_on_streams(),
+        }
+    }
+}
+
+/// Interarrival jitter as in RFC 3550: a smoothed average of how much the spacing between
+/// consecutive frames' arrivals differs from the spacing between their captures.
+///
+/// Without capture times, frames are assumed to be captured at a steady rate, so the previous
+/// arrival interval stands in for the capture interval.
+#[derive(Default)]
+struct Jitter {
+    last: Option<(Instant, Option<u64>)>,
+    last_interval_us: f64,
+    jitter_us: f64,
+}
+
+impl Jitter {
+    fn record(&mut self, arrival: Instant, capture_time_us: Option<u64>) {
+        if let Some((last_arrival, last_capture)) = self.last {
+            let arrival_interval = arrival.duration_since(last_arrival).as_micros() as f64;
+            let capture_interval = match (capture_time_us, last_capture) {
+                (Some(capture), Some(last_capture)) => capture as f64 - last_capture as f64,
+                _ => self.last_interval_us,
+            };
+            let deviation = (arrival_interval - capture_interval).abs();
+            self.jitter_us += (deviation - self.jitter_us) / 16.0;
+            self.last_interval_us = arrival_interval;
+        }
+        self.last = Some((arrival, capture_time_us));
+    }
+
+    fn get(&self) -> u32 {
+        self.jitter_us as u32
+    }
+}
@@ -0,0 +1,342 @@
+//! The room directory shared between server instances.
+//!
+//! Each instance's [`Registry`] holds its own sessions and their outboxes. A [`StateStore`]
+//! lets rooms span several instances by recording which users are in each room on every node,
+//! and by telling each node when users join or leave elsewhere so it can notify its own
+//! members. The default [`MemoryStore`] is for a single instance, whose registry already holds
+//! everything there is to know.
+
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use protobuf::system::PacketType;
+use protobuf::system::RoomUser;
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::Instrument;
+use tracing::info_span;
+use tracing::warn;
+
+use crate::protocol;
+use crate::redis;
+use crate::redis::Value;
+use crate::rooms::Registry;
+use crate::rooms::SessionId;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+pub trait StateStore: Send + Sync {
+    /// Records that a user on this node joined a room, returning the users in the room on
+    /// other nodes.
+    fn join<'a>(
+        &'a self,
+        room_key: &'a str,
+        user: &'a RoomUser,
+    ) -> BoxFuture<'a, Result<Vec<RoomUser>>>;
+
+    /// Records that a user on this node left a room.
+    fn leave<'a>(&'a self, room_key: &'a str, session_id: SessionId) -> BoxFuture<'a, Result<()>>;
+}
+
+/// The directory of a lone instance, where every room is entirely local.
+pub struct MemoryStore;
+
+impl StateStore for MemoryStore {
+    fn join<'a>(
+        &'a self,
+        _room_key: &'a str,
+        _user: &'a RoomUser,
+    ) -> BoxFuture<'a, Result<Vec<RoomUser>>> {
+        Box::pin(async { Ok(Vec::new()) })
+    }
+
+    fn leave<'a>(
+        &'a self,
+        _room_key: &'a str,
+        _session_id: SessionId,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// A directory shared through Redis.
+///
+/// Each room is a hash `voice:room:<key>` from session ID to the user's name and node, and
+/// every join and leave is published on `voice:events`. Nodes keep their key `voice:node:<id>`
+/// alive while they run, so entries left behind by a node that died are skipped and removed.
+pub struct RedisStore {
+    url: String,
+    node_id: Arc<str>,
+    connection: tokio::sync::Mutex<Option<redis::Connection>>,
+}
+
+/// A room hash entry.
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    username: String,
+    node: String,
+}
+
+/// A message on the events channel.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Event {
+    Joined {
+        node: String,
+        room_key: String,
+        session_id: SessionId,
+        username: String,
+    },
+    Left {
+        node: String,
+        room_key: String,
+        session_id: SessionId,
+    },
+}
+
+impl RedisStore {
+    const EVENTS_CHANNEL: &str = "voice:events";
+
+    /// How long a node's key outlives its last refresh.
+    const NODE_TTL_SECS: u64 = 30;
+
+    const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+    const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+    /// Creates the store and spawns the tasks keeping this node alive and relaying other
+    /// nodes' events to `registry`'s sessions. Connections are made on first use.
+    pub fn new(url: String, node_id: Arc<str>, registry: Arc<Registry>) -> Arc<Self> {
+        let store = Arc::new(Self {
+            url,
+            node_id,
+            connection: tokio::sync::Mutex::new(None),
+        });
+
+        tokio::spawn(
+            store
+                .clone()
+                .heartbeat()
+                .instrument(info_span!("Redis heartbeat")),
+        );
+        tokio::spawn(
+            store
+                .clone()
+                .subscribe(registry)
+                .instrument(info_span!("Redis subscriber")),
+        );
+
+        store
+    }
+
+    /// Sends a command over the shared connection, reconnecting if it has failed.
+    async fn command(&self, args: &[&[u8]]) -> Result<Value> {
+        let mut connection = self.connection.lock().await;
+        let result = match connection.as_mut() {
+            Some(connection) => connection.command(args).await,
+            None => {
+                let new = connection.insert(redis::Connection::connect(&self.url).await?);
+                new.command(args).await
+            }
+        };
+        if result.is_err() {
+            *connection = None;
+        }
+        result
+    }
+
+    async fn publish(&self, event: &Event) -> Result<()> {
+        let event = serde_json::to_vec(event)?;
+        self.command(&[b"PUBLISH", Self::EVENTS_CHANNEL.as_bytes(), &event])
+            .await?;
+        Ok(())
+    }
+
+    fn room_hash(room_key: &str) -> String {
+        format!("voice:room:{room_key}")
+    }
+
+    fn node_key(node_id: &str) -> String {
+        format!("voice:node:{node_id}")
+    }
+
+    async fn node_alive(&self, node_id: &str) -> Result<bool> {
+        let reply = self
+            .command(&[b"EXISTS", Self::node_key(node_id).as_bytes()])
+            .await?;
+        Ok(matches!(reply, Value::Integer(1)))
+    }
+
+    async fn join_impl(&self, room_key: &str, user: &RoomUser) -> Result<Vec<RoomUser>> {
+        let hash = Self::room_hash(room_key);
+        let session_id = user.session_id.to_string();
+        let entry = serde_json::to_vec(&Entry {
+            username: user.username.clone(),
+            node: self.node_id.to_string(),
+        })?;
+
+        let existing = self
+            .command(&[b"HGETALL", hash.as_bytes()])
+            .await?
+            .into_bulks()?;
+        self.command(&[b"HSET", hash.as_bytes(), session_id.as_bytes(), &entry])
+            .await?;
+        self.publish(&Event::Joined {
+            node: self.node_id.to_string(),
+            room_key: room_key.to_owned(),
+            session_id: user.session_id,
+            username: user.username.clone(),
+        })
+        .await?;
+
+        let mut users = Vec::new();
+        let mut alive = HashMap::new();
+        for pair in existing.chunks_exact(2) {
+            let session_id = std::str::from_utf8(&pair[0])
+                .ok()
+                .and_then(|id| id.parse().ok());
+            let (Some(session_id), Ok(entry)) =
+                (session_id, serde_json::from_slice::<Entry>(&pair[1]))
+            else {
+                continue;
+            };
+            // Users on this node are already known to the registry.
+            if *entry.node == *self.node_id {
+                continue;
+            }
+
+            let node_alive = match alive.get(&entry.node) {
+                Some(node_alive) => *node_alive,
+                None => {
+                    let node_alive = self.node_alive(&entry.node).await?;
+                    alive.insert(entry.node.clone(), node_alive);
+                    node_alive
+                }
+            };
+            if node_alive {
+                users.push(RoomUser {
+                    session_id,
+                    username: entry.username,
+                    ..Default::default()
+                });
+            } else {
+                self.command(&[b"HDEL", hash.as_bytes(), &pair[0]]).await?;
+            }
+        }
+
+        Ok(users)
+    }
+
+    async fn leave_impl(&self, room_key: &str, session_id: SessionId) -> Result<()> {
+        let hash = Self::room_hash(room_key);
+        self.command(&[b"HDEL", hash.as_bytes(), session_id.to_string().as_bytes()])
+            .await?;
+        self.publish(&Event::Left {
+            node: self.node_id.to_string(),
+            room_key: room_key.to_owned(),
+            session_id,
+        })
+        .await
+    }
+
+    async fn heartbeat(self: Arc<Self>) {
+        let key = Self::node_key(&self.node_id);
+        let ttl = Self::NODE_TTL_SECS.to_string();
+        let mut interval = tokio::time::interval(Self::HEARTBEAT_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(err) = self
+                .command(&[b"SET", key.as_bytes(), b"1", b"EX", ttl.as_bytes()])
+                .await
+            {
+                warn!("Cannot refresh node key: {err:#}");
+            }
+        }
+    }
+
+    /// Relays other nodes' joins and leaves to the members of the affected rooms on this node.
+    async fn subscribe(self: Arc<Self>, registry: Arc<Registry>) {
+        loop {
+            if let Err(err) = self.subscribe_impl(&registry).await {
+                warn!("Redis subscription failed: {err:#}");
+            }
+            tokio::time::sleep(Self::RECONNECT_DELAY).await;
+        }
+    }
+
+    async fn subscribe_impl(&self, registry: &Registry) -> Result<()> {
+        let mut connection = redis::Connection::connect(&self.url).await?;
+        connection
+            .send(&[b"SUBSCRIBE", Self::EVENTS_CHANNEL.as_bytes()])
+            .await?;
+
+        loop {
+            let Value::Array(message) = connection.read().await? else {
+                continue;
+            };
+            let [Value::Bulk(kind), _, Value::Bulk(payload)] = &message[..] else {
+                continue;
+            };
+            if kind != b"message" {
+                continue;
+            }
+
+            match serde_json::from_slice(payload) {
+                Ok(event) => self.relay(registry, event),
+                Err(err) => warn!("Ignoring malformed room event: {err}"),
+            }
+        }
+    }
+
+    /// Tells this node's members of a room about a join or leave elsewhere. Members whose queue
+    /// is full miss it rather than hold up presence for every room on the node.
+    fn relay(&self, registry: &Registry, event: Event) {
+        let (room_key, packet) = match event {
+            Event::Joined { node, .. } | Event::Left { node, .. } if *node == *self.node_id => {
+                return;
+            }
+            Event::Joined {
+                room_key,
+                session_id,
+                username,
+                ..
+            } => (
+                room_key,
+                protocol::encode(
+                    PacketType::UserJoined,
+                    &RoomUser {
+                        session_id,
+                        username,
+                        ..Default::default()
+                    },
+                ),
+            ),
+            Event::Left {
+                room_key,
+                session_id,
+                ..
+            } => (room_key, protocol::user_left(session_id)),
+        };
+
+        for (_, outbox) in registry.room_members(&room_key) {
+            outbox.try_send_control(packet.clone());
+        }
+    }
+}
+
+impl StateStore for RedisStore {
+    fn join<'a>(
+        &'a self,
+        room_key: &'a str,
+        user: &'a RoomUser,
+    ) -> BoxFuture<'a, Result<Vec<RoomUser>>> {
+        Box::pin(self.join_impl(room_key, user))
+    }
+
+    fn leave<'a>(&'a self, room_key: &'a str, session_id: SessionId) -> BoxFuture<'a, Result<()>> {
+        Box::pin(self.leave_impl(room_key, session_id))
+    }
+}
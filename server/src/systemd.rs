@@ -0,0 +1,117 @@
+//! systemd integration: sockets passed by socket activation, and readiness and watchdog
+//! notifications for `Type=notify` services.
+//!
+//! With socket activation, systemd binds the sockets of the service's `.socket` unit and passes
+//! them as `LISTEN_FDS`: a UDP socket (`ListenDatagram=`) serves WebTransport and a TCP socket
+//! (`ListenStream=`) serves HTTP, each in place of the socket the server would bind. With
+//! `NOTIFY_SOCKET` set, the server reports `READY=1` once it serves and `STOPPING=1` when
+//! shutting down, on SIGTERM as on Ctrl-C, and, with `WatchdogSec=`, pings the watchdog at half
+//! its interval.
+
+use std::net::TcpListener;
+use std::net::UdpSocket;
+use std::os::fd::FromRawFd;
+use std::os::fd::RawFd;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::SocketAddr;
+use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
+
+use anyhow::Context;
+use anyhow::Result;
+use anyhow::bail;
+use socket2::Socket;
+use socket2::Type;
+use tokio::signal::unix::SignalKind;
+use tokio::signal::unix::signal;
+use tracing::info;
+use tracing::warn;
+
+/// The first socket systemd passes.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// The sockets systemd passed, if any.
+#[derive(Default)]
+pub struct Listeners {
+    pub udp: Option<UdpSocket>,
+    pub tcp: Option<TcpListener>,
+}
+
+/// Takes the sockets systemd passed to this process.
+pub fn listeners() -> Result<Listeners> {
+    let mut listeners = Listeners::default();
+    let for_us = std::env::var("LISTEN_PID").is_ok_and(|pid| pid.parse() == Ok(std::process::id()));
+    let count: RawFd = match std::env::var("LISTEN_FDS") {
+        Ok(count) if for_us => count.parse().context("Invalid LISTEN_FDS")?,
+        _ => return Ok(listeners),
+    };
+
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+        // SAFETY: systemd passes this process `count` open sockets from fd 3 on, which
+        // nothing else in the process owns.
+        let socket = unsafe { Socket::from_raw_fd(fd) };
+        match socket
+            .r#type()
+            .context("Cannot inspect a socket from systemd")?
+        {
+            Type::DGRAM if listeners.udp.is_none() => listeners.udp = Some(socket.into()),
+            Type::STREAM if listeners.tcp.is_none() => {
+                socket.set_nonblocking(true)?;
+                listeners.tcp = Some(socket.into());
+            }
+            _ => bail!("systemd may pass one UDP and one TCP socket, unlike socket {fd}"),
+        }
+    }
+    info!("Using {count} sockets from systemd");
+    Ok(listeners)
+}
+
+/// Tells systemd about the service's state, such as `READY=1`, if it runs the service.
+pub fn notify(state: &str) {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let sent = (|| -> std::io::Result<()> {
+        // Paths starting with '@' are in the abstract namespace.
+        let address = match path.as_encoded_bytes().strip_prefix(b"@") {
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            None => SocketAddr::from_pathname(&path)?,
+        };
+        UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &address)?;
+        Ok(())
+    })();
+    if let Err(err) = sent {
+        warn!("Cannot notify systemd of {state}: {err}");
+    }
+}
+
+/// Waits for SIGTERM, which systemd stops services with.
+pub async fn terminated() {
+    match signal(SignalKind::terminate()) {
+        Ok(mut terminations) => {
+            terminations.recv().await;
+        }
+        Err(err) => {
+            warn!("Cannot listen for SIGTERM: {err}");
+            std::future::pending().await
+        }
+    }
+}
+
+/// Pings the systemd watchdog at half its interval, forever, if it is enabled.
+pub async fn watchdog() {
+    let for_us =
+        std::env::var("WATCHDOG_PID").map_or(true, |pid| pid.parse() == Ok(std::process::id()));
+    let usec = std::env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.parse().ok());
+    let Some(usec) = usec.filter(|&usec: &u64| for_us && usec > 0) else {
+        return;
+    };
+
+    let mut pings = tokio::time::interval(Duration::from_micros(usec / 2));
+    loop {
+        pings.tick().await;
+        notify("WATCHDOG=1");
+    }
+}
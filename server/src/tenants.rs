@@ -0,0 +1,149 @@
+//! Tenants: separate communities served by one deployment, whose rooms, users, bans and
+//! metrics are kept apart.
+//!
+//! With `tenants` configured, every client names its tenant when it authenticates, and only
+//! ever meets the rooms and users of that tenant. Inside the server, rooms and users are keyed
+//! as `<tenant>/<name>`: accounts and bans are stored under those keys, users log in to the
+//! HTTP endpoints with them, and other config sections name a tenant's rooms with them, as in
+//! `acme/standup`. Tenant IDs are letters, digits, `-`, `_` and `.`, so keys of different
+//! tenants never collide.
+//!
+//! Each tenant has an API key, which serves the tenant's operators as the admin token does,
+//! limited to what belongs to the tenant.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use anyhow::Result;
+use anyhow::bail;
+
+use crate::config::Config;
+use crate::secrets;
+use crate::state::ServerState;
+
+/// Separates a tenant from the name of one of its rooms or users in their keys.
+pub const SEPARATOR: char = '/';
+
+/// A room's or user's name, and the tenant it belongs to on servers with tenants.
+#[derive(Clone, Copy, Debug)]
+pub struct Scoped<'a> {
+    pub tenant: Option<&'a str>,
+    pub name: &'a str,
+}
+
+impl<'a> Scoped<'a> {
+    /// Splits a key, such as `acme/standup`. On servers with tenants, keys without a
+    /// configured tenant are refused.
+    pub fn parse(config: &Config, key: &'a str) -> Option<Self> {
+        if config.tenants.is_empty() {
+            return Some(Self {
+                tenant: None,
+                name: key,
+            });
+        }
+        let (tenant, name) = key.split_once(SEPARATOR)?;
+        config.tenants.contains_key(tenant).then_some(Self {
+            tenant: Some(tenant),
+            name,
+        })
+    }
+
+    pub fn key(&self) -> String {
+        key(self.tenant, self.name)
+    }
+
+    /// Whether a room, by its key, belongs to the same tenant.
+    pub fn owns(&self, room_key: &str) -> bool {
+        self.tenant
+            .is_none_or(|tenant| name(tenant, room_key).is_some())
+    }
+}
+
+/// The key of a tenant's room or user.
+pub fn key(tenant: Option<&str>, name: &str) -> String {
+    match tenant {
+        Some(tenant) => format!("{tenant}{SEPARATOR}{name}"),
+        None => name.to_owned(),
+    }
+}
+
+/// The name within a tenant of one of its rooms or users, or `None` if the key belongs to
+/// another tenant.
+pub fn name<'a>(tenant: &str, key: &'a str) -> Option<&'a str> {
+    key.strip_prefix(tenant)?.strip_prefix(SEPARATOR)
+}
+
+/// The tenant an API key belongs to.
+pub fn by_api_key<'a>(config: &'a Config, api_key: &str) -> Option<&'a str> {
+    config
+        .tenants
+        .iter()
+        .find(|(_, tenant)| {
+            !tenant.api_key.is_empty() && secrets::matches(&tenant.api_key, api_key)
+        })
+        .map(|(id, _)| id.as_str())
+}
+
+/// Checks the tenants' IDs and API keys.
+pub fn validate(config: &Config) -> Result<()> {
+    for (id, tenant) in &config.tenants {
+        let valid = |c: char| c.is_ascii_alphanumeric() || "-_.".contains(c);
+        if id.is_empty() || !id.chars().all(valid) {
+            bail!("tenant IDs must be letters, digits, '-', '_' and '.', unlike '{id}'");
+        }
+        if tenant.api_key.is_empty() {
+            bail!("tenant '{id}' needs an api_key");
+        }
+        let shared = config
+            .tenants
+            .iter()
+            .any(|(other, them)| other != id && them.api_key == tenant.api_key);
+        if shared || config.admin.token.as_ref() == Some(&tenant.api_key) {
+            bail!("tenant '{id}' must have an api_key of its own");
+        }
+    }
+    Ok(())
+}
+
+/// Renders each tenant's sessions and rooms in the Prometheus text exposition format.
+pub fn render_metrics(state: &ServerState) -> String {
+    let config = &state.config;
+    let mut out = String::new();
+    if config.tenants.is_empty() {
+        return out;
+    }
+
+    fn tenant_of<'a>(config: &Config, key: &'a str) -> Option<&'a str> {
+        Scoped::parse(config, key)?.tenant
+    }
+    let mut sessions: BTreeMap<&str, usize> =
+        config.tenants.keys().map(|id| (id.as_str(), 0)).collect();
+    let mut rooms = sessions.clone();
+    for (_, username) in state.registry.sessions() {
+        if let Some(count) = tenant_of(config, &username).and_then(|id| sessions.get_mut(id)) {
+            *count += 1;
+        }
+    }
+    for (room_key, _) in state.registry.rooms() {
+        if let Some(count) = tenant_of(config, &room_key).and_then(|id| rooms.get_mut(id)) {
+            *count += 1;
+        }
+    }
+
+    let gauges = [
+        (
+            "voice_tenant_sessions",
+            "Sessions logged in to each tenant.",
+            sessions,
+        ),
+        ("voice_tenant_rooms", "Open rooms of each tenant.", rooms),
+    ];
+    for (name, help, counts) in gauges {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} gauge");
+        for (tenant, count) in counts {
+            let _ = writeln!(out, "{name}{{tenant=\"{tenant}\"}} {count}");
+        }
+    }
+    out
+}
@@ -0,0 +1,493 @@
+//! What a session needs of its client's connection: streams each way, datagrams, closing, and
+//! the few figures it reports on the connection. Sessions and their outboxes hold it as an
+//! `Arc<dyn Transport>`, which is a WebTransport [`Connection`] when serving and a [`mock`]
+//! connection scripted by the test in tests.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use bytes::Bytes;
+use tokio::io::AsyncRead;
+use wtransport::Connection;
+use wtransport::VarInt;
+use wtransport::error::ConnectionError;
+use wtransport::error::SendDatagramError;
+use wtransport::error::StreamWriteError;
+use wtransport::quinn::ConnectionStats;
+
+use crate::store::BoxFuture;
+
+/// A client's connection.
+pub trait Transport: Send + Sync {
+    /// Waits for the next stream the client opens.
+    fn accept_uni(&self) -> BoxFuture<'_, Result<Box<dyn RecvStream>, ConnectionError>>;
+
+    /// Opens a stream to the client.
+    fn open_uni(&self) -> BoxFuture<'_, anyhow::Result<Box<dyn SendStream>>>;
+
+    /// Waits for the next datagram the client sends.
+    fn receive_datagram(&self) -> BoxFuture<'_, Result<Bytes, ConnectionError>>;
+
+    fn send_datagram(&self, payload: &[u8]) -> Result<(), SendDatagramError>;
+
+    /// The largest datagram the connection takes now, or `None` if it takes none.
+    fn max_datagram_size(&self) -> Option<usize>;
+
+    /// The bytes of datagrams that can be buffered now before older ones are dropped.
+    fn datagram_send_buffer_space(&self) -> usize;
+
+    /// Closes the connection with an application error `code` and `reason`.
+    fn close(&self, code: VarInt, reason: &[u8]);
+
+    /// Waits until the connection is closed, by either side.
+    fn closed(&self) -> BoxFuture<'_, ()>;
+
+    /// The client's address, which changes when QUIC migrates the connection to a new path.
+    fn remote_address(&self) -> SocketAddr;
+
+    /// The smoothed round-trip time.
+    fn rtt(&self) -> Duration;
+
+    /// QUIC's counts of what the connection sent, received and lost.
+    fn stats(&self) -> ConnectionStats;
+}
+
+/// A stream from the client, read as any other; a read of no bytes means it is finished.
+pub trait RecvStream: AsyncRead + Send + Unpin {}
+
+impl<T: AsyncRead + Send + Unpin> RecvStream for T {}
+
+/// A stream to the client.
+pub trait SendStream: Send {
+    fn write_all<'a>(&'a mut self, data: &'a [u8]) -> BoxFuture<'a, Result<(), StreamWriteError>>;
+
+    /// Finishes the stream, waiting for the client to acknowledge what was written.
+    fn finish(&mut self) -> BoxFuture<'_, Result<(), StreamWriteError>>;
+
+    /// Sets the stream's priority among the connection's streams; higher goes first.
+    fn set_priority(&self, priority: i32);
+}
+
+impl Transport for Connection {
+    fn accept_uni(&self) -> BoxFuture<'_, Result<Box<dyn RecvStream>, ConnectionError>> {
+        Box::pin(async move {
+            let stream = Connection::accept_uni(self).await?;
+            Ok(Box::new(stream) as Box<dyn RecvStream>)
+        })
+    }
+
+    fn open_uni(&self) -> BoxFuture<'_, anyhow::Result<Box<dyn SendStream>>> {
+        Box::pin(async move {
+            let stream = Connection::open_uni(self).await?.await?;
+            Ok(Box::new(stream) as Box<dyn SendStream>)
+        })
+    }
+
+    fn receive_datagram(&self) -> BoxFuture<'_, Result<Bytes, ConnectionError>> {
+        Box::pin(async move { Ok(Connection::receive_datagram(self).await?.payload()) })
+    }
+
+    fn send_datagram(&self, payload: &[u8]) -> Result<(), SendDatagramError> {
+        Connection::send_datagram(self, payload)
+    }
+
+    fn max_datagram_size(&self) -> Option<usize> {
+        Connection::max_datagram_size(self)
+    }
+
+    fn datagram_send_buffer_space(&self) -> usize {
+        self.quic_connection().datagram_send_buffer_space()
+    }
+
+    fn close(&self, code: VarInt, reason: &[u8]) {
+        Connection::close(self, code, reason);
+    }
+
+    fn closed(&self) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            Connection::closed(self).await;
+        })
+    }
+
+    fn remote_address(&self) -> SocketAddr {
+        Connection::remote_address(self)
+    }
+
+    fn rtt(&self) -> Duration {
+        Connection::rtt(self)
+    }
+
+    fn stats(&self) -> ConnectionStats {
+        self.quic_connection().stats()
+    }
+}
+
+impl SendStream for wtransport::SendStream {
+    fn write_all<'a>(&'a mut self, data: &'a [u8]) -> BoxFuture<'a, Result<(), StreamWriteError>> {
+        Box::pin(wtransport::SendStream::write_all(self, data))
+    }
+
+    fn finish(&mut self) -> BoxFuture<'_, Result<(), StreamWriteError>> {
+        Box::pin(wtransport::SendStream::finish(self))
+    }
+
+    fn set_priority(&self, priority: i32) {
+        wtransport::SendStream::set_priority(self, priority);
+    }
+}
+
+/// A connection for tests, driven by a [`mock::Client`] that sends what a client would and
+/// reads what the server sends it. The client can script what happens to the server's
+/// datagrams on the way: losing them, reordering them, a path too small for them, a full send
+/// buffer, or a peer taking none.
+#[cfg(test)]
+pub mod mock {
+    use std::io::Cursor;
+    use std::net::Ipv4Addr;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::sync::Mutex as SyncMutex;
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use prost::Message;
+    use protobuf::system::PacketType;
+    use tokio::io::DuplexStream;
+    use tokio::sync::Mutex;
+    use tokio::sync::mpsc;
+    use tokio::sync::watch;
+    use wtransport::VarInt;
+    use wtransport::error::ConnectionError;
+    use wtransport::error::SendDatagramError;
+    use wtransport::error::StreamWriteError;
+    use wtransport::quinn::ConnectionStats;
+
+    use super::RecvStream;
+    use super::SendStream;
+    use super::Transport;
+    use crate::store::BoxFuture;
+
+    /// How long [`Client::receive`] waits for a packet before failing the test.
+    const RECEIVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// The largest datagram the mock connection takes.
+    const MAX_DATAGRAM_SIZE: usize = 1200;
+
+    /// The server's side.
+    pub struct Connection {
+        streams: Mutex<mpsc::UnboundedReceiver<Box<dyn RecvStream>>>,
+        datagrams: Mutex<mpsc::UnboundedReceiver<Bytes>>,
+        sent: mpsc::UnboundedSender<Bytes>,
+        close: Arc<watch::Sender<Option<VarInt>>>,
+        path: Arc<SyncMutex<Path>>,
+
+        /// Whether the client takes no new streams, as when the server has used up its credit.
+        streams_stalled: Arc<watch::Sender<bool>>,
+    }
+
+    /// The client's side.
+    pub struct Client {
+        streams: mpsc::UnboundedSender<Box<dyn RecvStream>>,
+        datagrams: mpsc::UnboundedSender<Bytes>,
+        received: mpsc::UnboundedReceiver<Bytes>,
+        close: Arc<watch::Sender<Option<VarInt>>>,
+        path: Arc<SyncMutex<Path>>,
+
+        /// Whether the client takes no new streams, as when the server has used up its credit.
+        streams_stalled: Arc<watch::Sender<bool>>,
+    }
+
+    /// What happens to the server's datagrams on the way to the client, as scripted by it.
+    struct Path {
+        /// The largest datagram the connection reports taking, or `None` if it takes none.
+        max_datagram_size: Option<usize>,
+
+        /// The largest datagram that gets through, which the connection reports only once one
+        /// larger fails, as when the path shrinks under packets already split for it.
+        mtu: usize,
+
+        /// How many of the next datagrams are lost.
+        lose: usize,
+
+        /// How many of the next datagrams are held back, to arrive after the one following them.
+        hold: usize,
+        held: Vec<Bytes>,
+
+        send_buffer_space: usize,
+    }
+
+    /// A connection and the client at its other end.
+    pub fn connect() -> (Arc<Connection>, Client) {
+        let (streams, streams_rx) = mpsc::unbounded_channel();
+        let (datagrams, datagrams_rx) = mpsc::unbounded_channel();
+        let (sent, received) = mpsc::unbounded_channel();
+        let close = Arc::new(watch::Sender::new(None));
+        let streams_stalled = Arc::new(watch::Sender::new(false));
+        let path = Arc::new(SyncMutex::new(Path {
+            max_datagram_size: Some(MAX_DATAGRAM_SIZE),
+            mtu: MAX_DATAGRAM_SIZE,
+            lose: 0,
+            hold: 0,
+            held: Vec::new(),
+            send_buffer_space: usize::MAX,
+        }));
+        let connection = Connection {
+            streams: Mutex::new(streams_rx),
+            datagrams: Mutex::new(datagrams_rx),
+            sent,
+            close: close.clone(),
+            path: path.clone(),
+            streams_stalled: streams_stalled.clone(),
+        };
+        let client = Client {
+            streams,
+            datagrams,
+            received,
+            close,
+            path,
+            streams_stalled,
+        };
+        (Arc::new(connection), client)
+    }
+
+    impl Client {
+        /// Sends a control packet on a stream of its own.
+        pub fn send(&self, packet_type: PacketType, message: &impl Message) {
+            let mut packet = vec![packet_type as u8];
+            message.encode(&mut packet).unwrap();
+            let _ = self.streams.send(Box::new(Cursor::new(packet)));
+        }
+
+        /// Opens a stream, returning the end to write it from, which finishes it when dropped.
+        pub fn open_stream(&self) -> DuplexStream {
+            let (client, server) = tokio::io::duplex(MAX_DATAGRAM_SIZE);
+            let _ = self.streams.send(Box::new(server));
+            client
+        }
+
+        /// Sends a datagram.
+        pub fn send_datagram(&self, datagram: Bytes) {
+            let _ = self.datagrams.send(datagram);
+        }
+
+        /// The next packet the server sent, on a stream or as a datagram, failing the test if
+        /// none comes.
+        pub async fn receive(&mut self) -> Bytes {
+            tokio::time::timeout(RECEIVE_TIMEOUT, self.received.recv())
+                .await
+                .expect("the server sent nothing")
+                .expect("the connection is gone")
+        }
+
+        /// The next packet of `packet_type` the server sent, skipping others.
+        pub async fn expect<M: Message + Default>(&mut self, packet_type: PacketType) -> M {
+            loop {
+                let packet = self.receive().await;
+                if packet.first() == Some(&(packet_type as u8)) {
+                    return M::decode(&packet[1..]).unwrap();
+                }
+            }
+        }
+
+        /// Whether the server sent anything within `wait`.
+        pub async fn is_silent_for(&mut self, wait: Duration) -> bool {
+            tokio::time::timeout(wait, self.received.recv())
+                .await
+                .is_err()
+        }
+
+        /// Loses the next `count` datagrams the server sends.
+        pub fn lose_datagrams(&self, count: usize) {
+            self.path.lock().unwrap().lose += count;
+        }
+
+        /// Holds back the next `count` datagrams the server sends, to arrive, in reverse order,
+        /// right after the one following them.
+        pub fn reorder_datagrams(&self, count: usize) {
+            self.path.lock().unwrap().hold += count;
+        }
+
+        /// Shrinks the path to datagrams of `mtu` bytes, which the server finds out by sending
+        /// one larger.
+        pub fn shrink_path(&self, mtu: usize) {
+            self.path.lock().unwrap().mtu = mtu;
+        }
+
+        /// Stops taking datagrams, as a peer that did not negotiate them.
+        pub fn refuse_datagrams(&self) {
+            self.path.lock().unwrap().max_datagram_size = None;
+        }
+
+        /// Sets how many bytes of datagrams the server can buffer before older ones are dropped.
+        pub fn set_send_buffer_space(&self, space: usize) {
+            self.path.lock().unwrap().send_buffer_space = space;
+        }
+
+        /// Stops taking new streams from the server, which waits to open them until this is
+        /// called again with `false`.
+        pub fn stall_streams(&self, stalled: bool) {
+            self.streams_stalled.send_replace(stalled);
+        }
+
+        /// Closes the connection, as a client does when its user quits.
+        pub fn close(&self) {
+            self.close.send_if_modified(|close| {
+                let first = close.is_none();
+                close.get_or_insert(VarInt::from_u32(0));
+                first
+            });
+        }
+
+        /// The code the server closed the connection with, waiting for it to.
+        pub async fn closed(&self) -> VarInt {
+            let mut close = self.close.subscribe();
+            let close =
+                tokio::time::timeout(RECEIVE_TIMEOUT, close.wait_for(|close| close.is_some()));
+            let code = close
+                .await
+                .expect("the server did not close the connection")
+                .expect("the connection is gone");
+            code.unwrap()
+        }
+    }
+
+    impl Connection {
+        fn is_closed(&self) -> bool {
+            self.close.borrow().is_some()
+        }
+    }
+
+    impl Transport for Connection {
+        fn accept_uni(&self) -> BoxFuture<'_, Result<Box<dyn RecvStream>, ConnectionError>> {
+            Box::pin(async move {
+                let mut close = self.close.subscribe();
+                let mut streams = self.streams.lock().await;
+                tokio::select! {
+                    Some(stream) = streams.recv() => Ok(stream),
+                    _ = close.wait_for(|close| close.is_some()) => {
+                        Err(ConnectionError::LocallyClosed)
+                    }
+                }
+            })
+        }
+
+        fn open_uni(&self) -> BoxFuture<'_, anyhow::Result<Box<dyn SendStream>>> {
+            Box::pin(async move {
+                if self.is_closed() {
+                    anyhow::bail!("The connection is closed");
+                }
+                let mut stalled = self.streams_stalled.subscribe();
+                let _ = stalled.wait_for(|stalled| !stalled).await;
+                Ok(Box::new(Outgoing {
+                    data: Vec::new(),
+                    sent: self.sent.clone(),
+                }) as Box<dyn SendStream>)
+            })
+        }
+
+        fn receive_datagram(&self) -> BoxFuture<'_, Result<Bytes, ConnectionError>> {
+            Box::pin(async move {
+                let mut close = self.close.subscribe();
+                let mut datagrams = self.datagrams.lock().await;
+                tokio::select! {
+                    Some(datagram) = datagrams.recv() => Ok(datagram),
+                    _ = close.wait_for(|close| close.is_some()) => {
+                        Err(ConnectionError::LocallyClosed)
+                    }
+                }
+            })
+        }
+
+        fn send_datagram(&self, payload: &[u8]) -> Result<(), SendDatagramError> {
+            if self.is_closed() {
+                return Err(SendDatagramError::NotConnected);
+            }
+            let mut path = self.path.lock().unwrap();
+            let Some(max_datagram_size) = path.max_datagram_size else {
+                return Err(SendDatagramError::UnsupportedByPeer);
+            };
+            if payload.len() > max_datagram_size.min(path.mtu) {
+                path.max_datagram_size = Some(max_datagram_size.min(path.mtu));
+                return Err(SendDatagramError::TooLarge);
+            }
+            let datagram = Bytes::copy_from_slice(payload);
+            if path.lose > 0 {
+                path.lose -= 1;
+            } else if path.hold > 0 {
+                path.hold -= 1;
+                path.held.push(datagram);
+            } else {
+                let _ = self.sent.send(datagram);
+                while let Some(held) = path.held.pop() {
+                    let _ = self.sent.send(held);
+                }
+            }
+            Ok(())
+        }
+
+        fn max_datagram_size(&self) -> Option<usize> {
+            self.path.lock().unwrap().max_datagram_size
+        }
+
+        fn datagram_send_buffer_space(&self) -> usize {
+            self.path.lock().unwrap().send_buffer_space
+        }
+
+        fn close(&self, code: VarInt, _reason: &[u8]) {
+            self.close.send_if_modified(|close| {
+                let first = close.is_none();
+                close.get_or_insert(code);
+                first
+            });
+        }
+
+        fn closed(&self) -> BoxFuture<'_, ()> {
+            Box::pin(async move {
+                let _ = self
+                    .close
+                    .subscribe()
+                    .wait_for(|close| close.is_some())
+                    .await;
+            })
+        }
+
+        fn remote_address(&self) -> SocketAddr {
+            (Ipv4Addr::LOCALHOST, 4433).into()
+        }
+
+        fn rtt(&self) -> Duration {
+            Duration::ZERO
+        }
+
+        fn stats(&self) -> ConnectionStats {
+            ConnectionStats::default()
+        }
+    }
+
+    /// A stream to the client, which it receives once it is finished.
+    struct Outgoing {
+        data: Vec<u8>,
+        sent: mpsc::UnboundedSender<Bytes>,
+    }
+
+    impl SendStream for Outgoing {
+        fn write_all<'a>(
+            &'a mut self,
+            data: &'a [u8],
+        ) -> BoxFuture<'a, Result<(), StreamWriteError>> {
+            self.data.extend_from_slice(data);
+            Box::pin(async { Ok(()) })
+        }
+
+        fn finish(&mut self) -> BoxFuture<'_, Result<(), StreamWriteError>> {
+            let data = std::mem::take(&mut self.data);
+            let result = self
+                .sent
+                .send(data.into())
+                .map_err(|_| StreamWriteError::NotConnected);
+            Box::pin(async move { result })
+        }
+
+        fn set_priority(&self, _priority: i32) {}
+    }
+}
@@ -0,0 +1,484 @@
+//! Audio relay links between nodes, so a room can span several server instances.
+//!
+//! A node dials a trunk to every peer in `cluster.peers`: a WebTransport session to the peer's
+//! `/trunk` path, authenticated with `cluster.trunk_secret` and pinned to the certificate the
+//! peer publishes in its `/config.json`. Once a second the peer sends back the keys of the rooms
+//! it has members in, and the dialing node relays its own speakers' frames in those rooms as
+//! datagrams. Each frame carries its room key, speaker, sequence number and capture time, so the
+//! receiving node delivers it to its members, or its mixer, as if the speaker were local.
+//!
+//! Trunks carry only the dialing node's own speakers, so a mesh of nodes that all list each
+//! other never relays a frame twice.
+//!
+//! Trunks to federated servers work the same way, with the differences described in
+//! [`federation`](crate::federation).
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::RwLock;
+use std::time::Duration;
+
+use anyhow::Context;
+use anyhow::Result;
+use anyhow::bail;
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use bytes::BufMut;
+use bytes::Bytes;
+use bytes::BytesMut;
+use serde::Deserialize;
+use tokio::task::AbortHandle;
+use tracing::Instrument;
+use tracing::Level;
+use tracing::debug;
+use tracing::enabled;
+use tracing::info;
+use tracing::info_span;
+use tracing::warn;
+use wtransport::ClientConfig;
+use wtransport::Connection;
+use wtransport::Endpoint;
+use wtransport::endpoint::ConnectOptions;
+use wtransport::endpoint::SessionRequest;
+use wtransport::tls::Sha256Digest;
+
+use crate::config::Config;
+use crate::federation;
+use crate::federation::Federation;
+use crate::http_client;
+use crate::log_sampling;
+use crate::metrics::METRICS;
+use crate::protocol;
+use crate::protocol::VoiceFrame;
+use crate::protocol::VoiceHeader;
+use crate::secrets;
+use crate::state::ServerState;
+
+/// The path trunk sessions are requested on.
+pub const TRUNK_PATH: &str = "/trunk";
+
+/// The request header carrying the trunk secret.
+const SECRET_HEADER: &str = "x-trunk-secret";
+
+/// How often the accepting node sends the rooms it wants audio for.
+const INTEREST_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The largest room list accepted from a peer.
+const MAX_INTEREST_LEN: usize = 1024 * 1024;
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// The trunks this node relays its speakers' audio over.
+pub struct Relay {
+    trunks: RwLock<Vec<Arc<Trunk>>>,
+
+    /// The trunks to processes sharing this one's port, by node ID, with the tasks keeping
+    /// them connected.
+    siblings: Mutex<HashMap<String, (Arc<Trunk>, AbortHandle)>>,
+}
+
+struct Trunk {
+    /// The peer's HTTP address.
+    peer: String,
+
+    /// The certificate digest and WebTransport port of a sibling process, which are known
+    /// without fetching its `/config.json`.
+    sibling: Option<(Sha256Digest, u16)>,
+
+    credentials: Credentials,
+
+    connection: RwLock<Option<Connection>>,
+
+    /// The rooms the peer has members in.
+    rooms: RwLock<HashSet<String>>,
+}
+
+enum Credentials {
+    /// The cluster's trunk secret.
+    Cluster(String),
+
+    /// A federated server's shared secret, and how room keys are translated for it.
+    Federation(Federation),
+}
+
+/// The part of a peer's `/config.json` needed to reach it.
+#[derive(Deserialize)]
+struct PeerConfig {
+    cert_digest_base64: String,
+    default_port: u16,
+}
+
+impl Relay {
+    /// Spawns a task dialing each configured peer and federated server. Without a trunk secret,
+    /// nothing is relayed to other nodes, and without a server name, nothing to other servers.
+    pub fn new(config: &Config) -> Self {
+        let mut peers = Vec::new();
+        match &config.cluster.trunk_secret {
+            Some(secret) => peers.extend(
+                config
+                    .cluster
+                    .peers
+                    .iter()
+                    .map(|peer| (peer.clone(), Credentials::Cluster(secret.clone()))),
+            ),
+            None if !config.cluster.peers.is_empty() => {
+                warn!("cluster.peers is set without cluster.trunk_secret, so no audio is relayed");
+            }
+            None => {}
+        }
+        match &config.federation.server_name {
+            Some(server_name) => peers.extend(config.federation.peers.iter().map(|peer| {
+                let federation = Federation {
+                    server_name: server_name.clone(),
+                    peer: peer.clone(),
+                };
+                (peer.address.clone(), Credentials::Federation(federation))
+            })),
+            None if !config.federation.peers.is_empty() => {
+                warn!(
+                    "federation.peers is set without federation.server_name, so no audio is relayed"
+                );
+            }
+            None => {}
+        }
+
+        let trunks = peers
+            .into_iter()
+            .map(|(peer, credentials)| {
+                let span = match &credentials {
+                    Credentials::Cluster(_) => info_span!("Trunk", peer),
+                    Credentials::Federation(federation) => {
+                        info_span!("Federated trunk", peer = federation.peer.name)
+                    }
+                };
+                let trunk = Arc::new(Trunk {
+                    peer,
+                    sibling: None,
+                    credentials,
+                    connection: RwLock::new(None),
+                    rooms: RwLock::default(),
+                });
+                tokio::spawn(trunk.clone().maintain().instrument(span));
+                trunk
+            })
+            .collect();
+
+        Self {
+            trunks: RwLock::new(trunks),
+            siblings: Mutex::default(),
+        }
+    }
+
+    /// Dials a trunk to another process sharing this one's port, which serves trunks on the
+    /// loopback `port` with the certificate whose digest is `digest`.
+    pub fn add_sibling(&self, node_id: &str, digest: Sha256Digest, port: u16, secret: &str) {
+        let mut siblings = self.siblings.lock().unwrap();
+        if siblings.contains_key(node_id) {
+            return;
+        }
+
+        info!("Dialing sibling {node_id} on trunk port {port}");
+        let trunk = Arc::new(Trunk {
+            peer: format!("http://127.0.0.1:{port}"),
+            sibling: Some((digest, port)),
+            credentials: Credentials::Cluster(secret.to_owned()),
+            connection: RwLock::new(None),
+            rooms: RwLock::default(),
+        });
+        let task = tokio::spawn(
+            trunk
+                .clone()
+                .maintain()
+                .instrument(info_span!("Sibling trunk", node_id)),
+        );
+        self.trunks.write().unwrap().push(trunk.clone());
+        siblings.insert(node_id.to_owned(), (trunk, task.abort_handle()));
+    }
+
+    /// Closes the trunks to sibling processes that are not in `alive`.
+    pub fn retain_siblings(&self, alive: &HashSet<String>) {
+        let mut siblings = self.siblings.lock().unwrap();
+        siblings.retain(|node_id, (trunk, task)| {
+            if alive.contains(node_id) {
+                return true;
+            }
+            info!("Sibling {node_id} is gone");
+            task.abort();
+            self.trunks
+                .write()
+                .unwrap()
+                .retain(|other| !Arc::ptr_eq(other, trunk));
+            false
+        });
+    }
+
+    /// The peers with members in a room, which its local speakers are relayed to.
+    pub fn relayed_to(&self, room_key: &str) -> Vec<String> {
+        self.trunks
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|trunk| {
+                let peer_room_key = match &trunk.credentials {
+                    Credentials::Cluster(_) => Some(Cow::Borrowed(room_key)),
+                    Credentials::Federation(federation) => {
+                        federation.to_peer(room_key).map(Cow::Owned)
+                    }
+                };
+                peer_room_key.is_some_and(|peer_room_key| {
+                    trunk.rooms.read().unwrap().contains(&*peer_room_key)
+                        && trunk.connection.read().unwrap().is_some()
+                })
+            })
+            .map(|trunk| trunk.peer.clone())
+            .collect()
+    }
+
+    /// Relays a local speaker's frame to every peer with members in the room.
+    pub fn forward(&self, room_key: &str, header: &VoiceHeader, payload: &[u8]) {
+        let mut frame = None;
+        for trunk in self.trunks.read().unwrap().iter() {
+            let peer_room_key = match &trunk.credentials {
+                Credentials::Cluster(_) => Cow::Borrowed(room_key),
+                Credentials::Federation(federation) => match federation.to_peer(room_key) {
+                    Some(peer_room_key) => Cow::Owned(peer_room_key),
+                    None => continue,
+                },
+            };
+            if !trunk.rooms.read().unwrap().contains(&*peer_room_key) {
+                continue;
+            }
+            let connection = trunk.connection.read().unwrap();
+            let Some(connection) = connection.as_ref() else {
+                continue;
+            };
+
+            // Frames to other nodes carry the same room key, so they are built only once.
+            let federated_frame;
+            let frame = match &trunk.credentials {
+                Credentials::Cluster(_) => {
+                    &*frame.get_or_insert_with(|| encode(room_key, header, payload))
+                }
+                Credentials::Federation(_) => {
+                    federated_frame = encode(&peer_room_key, header, payload);
+                    &federated_frame
+                }
+            };
+            match connection.send_datagram(&frame[..]) {
+                Ok(()) => METRICS.relay_frames_sent.inc(),
+                Err(err) => {
+                    if enabled!(Level::DEBUG) && log_sampling::RELAY_FAILURES.sample() {
+                        debug!("Cannot relay frame to {}: {err}", trunk.peer);
+                    }
+                    METRICS.datagrams_send_failed.inc();
+                }
+            }
+        }
+    }
+}
+
+impl Trunk {
+    /// Keeps the trunk connected, reconnecting whenever it drops.
+    async fn maintain(self: Arc<Self>) {
+        loop {
+            if let Err(err) = self.run().await {
+                warn!("Trunk to {} failed: {err:#}", self.peer);
+            }
+            *self.connection.write().unwrap() = None;
+            self.rooms.write().unwrap().clear();
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    }
+
+    async fn run(&self) -> Result<()> {
+        let peer = self.peer.trim_end_matches('/');
+        let (digest, port) = match &self.sibling {
+            Some(sibling) => sibling.clone(),
+            None => {
+                let config: PeerConfig =
+                    http_client::get_json(&format!("{peer}/config.json")).await?;
+                let digest: [u8; 32] = BASE64_STANDARD
+                    .decode(&config.cert_digest_base64)
+                    .ok()
+                    .and_then(|digest| digest.try_into().ok())
+                    .context("peer sent an invalid certificate digest")?;
+                (Sha256Digest::new(digest), config.default_port)
+            }
+        };
+
+        let host = peer
+            .strip_prefix("http://")
+            .context("peer address must start with http://")?;
+        let host = host.rsplit_once(':').map_or(host, |(host, _)| host);
+
+        let client_config = ClientConfig::builder()
+            .with_bind_default()
+            .with_server_certificate_hashes([digest])
+            .build();
+        let url = format!("https://{host}:{port}");
+        let options = match &self.credentials {
+            Credentials::Cluster(secret) => ConnectOptions::builder(format!("{url}{TRUNK_PATH}"))
+                .add_header(SECRET_HEADER, secret),
+            Credentials::Federation(federation) => {
+                ConnectOptions::builder(format!("{url}{}", federation::FEDERATION_PATH))
+                    .add_header(federation::ORIGIN_HEADER, &federation.server_name)
+            }
+        }
+        .build();
+        let connection = Endpoint::client(client_config)?.connect(options).await?;
+        if let Credentials::Federation(federation) = &self.credentials {
+            federation.authenticate_dialer(&connection).await?;
+        }
+
+        info!("Trunk to {} connected", self.peer);
+        *self.connection.write().unwrap() = Some(connection.clone());
+
+        loop {
+            let mut stream = connection.accept_uni().await?;
+            let mut interest = Vec::new();
+            let mut buffer = [0; 4096];
+            while let Some(len) = stream.read(&mut buffer).await? {
+                interest.extend_from_slice(&buffer[..len]);
+                if interest.len() > MAX_INTEREST_LEN {
+                    bail!("peer sent a room list over {MAX_INTEREST_LEN} bytes");
+                }
+            }
+
+            let rooms: HashSet<String> =
+                serde_json::from_slice(&interest).context("peer sent an invalid room list")?;
+            *self.rooms.write().unwrap() = rooms;
+        }
+    }
+}
+
+/// Serves a trunk dialed by a peer, delivering its speakers' frames to this node's rooms.
+pub async fn serve(request: SessionRequest, state: Arc<ServerState>) -> Result<()> {
+    if request.path() == federation::FEDERATION_PATH {
+        let origin = request.headers().get(federation::ORIGIN_HEADER);
+        let Some(federation) = origin.and_then(|origin| Federation::find(&state.config, origin))
+        else {
+            warn!(
+                "Refusing federated trunk from unknown server {:?}",
+                origin.map_or("", String::as_str)
+            );
+            request.forbidden().await;
+            return Ok(());
+        };
+
+        let connection = request.accept().await?;
+        federation.authenticate_acceptor(&connection).await?;
+        info!("Federated trunk from {} accepted", federation.peer.name);
+        return relay_from(&connection, &state, Some(&federation)).await;
+    }
+
+    let authorized = state
+        .config
+        .cluster
+        .trunk_secret
+        .as_ref()
+        .is_some_and(|secret| {
+            request
+                .headers()
+                .get(SECRET_HEADER)
+                .is_some_and(|provided| secrets::matches(secret, provided))
+        });
+    if !authorized {
+        warn!("Refusing unauthorized trunk");
+        request.forbidden().await;
+        return Ok(());
+    }
+
+    let connection = request.accept().await?;
+    info!("Trunk from {} accepted", connection.remote_address());
+    relay_from(&connection, &state, None).await
+}
+
+/// Delivers a peer's frames to the rooms on this node, and keeps it told which rooms those are.
+/// A federated server is only told about, and only relays into, the rooms it may.
+async fn relay_from(
+    connection: &Connection,
+    state: &ServerState,
+    federation: Option<&Federation>,
+) -> Result<()> {
+    let accepts = |room_key: &str| federation.is_none_or(|federation| federation.accepts(room_key));
+    let mut interest = tokio::time::interval(INTEREST_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interest.tick() => {
+                let rooms: Vec<String> = state
+                    .registry
+                    .rooms()
+                    .into_iter()
+                    .map(|(room_key, _)| room_key)
+                    .filter(|room_key| accepts(room_key))
+                    .collect();
+                let mut stream = protocol::open_control_stream(connection).await?;
+                stream.write_all(&serde_json::to_vec(&rooms)?).await?;
+                stream.finish().await?;
+            }
+            dgram = connection.receive_datagram() => {
+                let dgram = dgram?;
+                let dgram = dgram.payload();
+                match decode(&dgram) {
+                    Some((room_key, header, payload)) if accepts(room_key) => {
+                        deliver(state, room_key, header, dgram.slice_ref(payload));
+                    }
+                    _ if !enabled!(Level::DEBUG)
+                        || !log_sampling::IGNORED_RELAYED_FRAMES.sample() => {}
+                    Some((room_key, ..)) => debug!("Ignoring frame relayed into {room_key}"),
+                    None => debug!("Ignoring malformed relayed frame"),
+                }
+            }
+        }
+    }
+}
+
+/// Delivers a relayed frame to the room's members on this node, or to its mixer.
+fn deliver(state: &ServerState, room_key: &str, mut header: VoiceHeader, payload: Bytes) {
+    METRICS.relay_frames_received.inc();
+
+    if let Some(mixer) = &state.mixer {
+        mixer.submit(&room_key.into(), header.session_id, payload);
+        return;
+    }
+
+    // The room is this node's, so listeners know it by this node's name for it.
+    header.room_id = protocol::room_id(room_key);
+    let mut frame = VoiceFrame::new(header, payload);
+    let members = state
+        .registry
+        .for_each_member(room_key, |outbox| outbox.push_voice(&mut frame));
+    METRICS.audio_frames_forwarded.add(members as u64);
+}
+
+/// Builds a relayed frame: the room key's big-endian 16-bit length and bytes, the speaker's
+/// big-endian session ID, the frame's big-endian sequence number and capture time, then the
+/// payload.
+fn encode(room_key: &str, header: &VoiceHeader, payload: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(2 + room_key.len() + 20 + payload.len());
+    buf.put_u16(room_key.len() as u16);
+    buf.put_slice(room_key.as_bytes());
+    buf.put_i64(header.session_id);
+    buf.put_u32(header.sequence);
+    buf.put_u64(header.capture_time_us);
+    buf.put_slice(payload);
+    buf.freeze()
+}
+
+/// Reads a relayed frame. The header's room ID is left for the receiving node to fill in.
+fn decode(frame: &[u8]) -> Option<(&str, VoiceHeader, &[u8])> {
+    let (key_len, rest) = frame.split_at_checked(2)?;
+    let key_len = u16::from_be_bytes(key_len.try_into().unwrap());
+    let (room_key, rest) = rest.split_at_checked(key_len.into())?;
+    let (header, payload) = rest.split_at_checked(20)?;
+    let header = VoiceHeader {
+        session_id: i64::from_be_bytes(header[..8].try_into().unwrap()),
+        room_id: 0,
+        sequence: u32::from_be_bytes(header[8..12].try_into().unwrap()),
+        capture_time_us: u64::from_be_bytes(header[12..].try_into().unwrap()),
+    };
+    Some((std::str::from_utf8(room_key).ok()?, header, payload))
+}
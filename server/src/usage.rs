@@ -0,0 +1,171 @@
+//! Usage accounting for billing: how much of the server each tenant used.
+//!
+//! Participant time is counted by sampling the rooms every [`SAMPLE_INTERVAL`], so it includes
+//! bridged participants such as phone callers. Bandwidth comes from each session's QUIC
+//! counters, and storage is the bytes of shared files and avatars stored. The totals since
+//! startup are served by the admin API's `GetUsage`, and with `usage.export_dir` set, the usage
+//! of every `usage.export_interval_secs` is written there as a JSON or CSV file for billing.
+//!
+//! Servers without tenants account everything to the tenant `""`.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::Context;
+use anyhow::Result;
+use serde::Serialize;
+use serde_json::json;
+use tracing::info;
+use tracing::warn;
+
+use crate::accounts;
+use crate::config::UsageFormat;
+use crate::state::ServerState;
+use crate::tenants::Scoped;
+
+/// How often the rooms are counted for participant time.
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// What a tenant used.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct TenantUsage {
+    /// Time spent in rooms, summed over participants.
+    pub participant_seconds: u64,
+
+    /// Bytes sent to and received from the tenant's sessions over QUIC.
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+
+    /// Bytes of files and avatars stored.
+    pub stored_bytes: u64,
+}
+
+impl TenantUsage {
+    /// What was used since `earlier`.
+    fn since(&self, earlier: &Self) -> Self {
+        Self {
+            participant_seconds: self.participant_seconds - earlier.participant_seconds,
+            bytes_sent: self.bytes_sent - earlier.bytes_sent,
+            bytes_received: self.bytes_received - earlier.bytes_received,
+            stored_bytes: self.stored_bytes - earlier.stored_bytes,
+        }
+    }
+}
+
+/// Every tenant's usage since startup.
+#[derive(Default)]
+pub struct Usage {
+    tenants: Mutex<BTreeMap<String, TenantUsage>>,
+}
+
+impl Usage {
+    pub fn record(&self, tenant: Option<&str>, add: impl FnOnce(&mut TenantUsage)) {
+        let tenant = tenant.unwrap_or_default();
+        let mut tenants = self.tenants.lock().unwrap();
+        match tenants.get_mut(tenant) {
+            Some(usage) => add(usage),
+            None => add(tenants.entry(tenant.to_owned()).or_default()),
+        }
+    }
+
+    /// Records storage used by a room's or user's tenant, given its key.
+    pub fn record_stored(&self, state: &ServerState, key: &str, bytes: usize) {
+        let tenant = Scoped::parse(&state.config, key).and_then(|scoped| scoped.tenant);
+        self.record(tenant, |usage| usage.stored_bytes += bytes as u64);
+    }
+
+    pub fn totals(&self) -> BTreeMap<String, TenantUsage> {
+        self.tenants.lock().unwrap().clone()
+    }
+}
+
+/// Counts participant time and writes the periodic exports, forever.
+pub async fn account(state: Arc<ServerState>) {
+    let config = &state.config.usage;
+    let mut sample = tokio::time::interval(SAMPLE_INTERVAL);
+    let mut export = tokio::time::interval(config.export_interval());
+    // Both intervals tick at once first.
+    export.tick().await;
+
+    let mut exported = state.usage.totals();
+    let mut period_start = accounts::now_secs();
+    loop {
+        tokio::select! {
+            _ = sample.tick() => {
+                for (room_key, session_ids) in state.registry.rooms() {
+                    let tenant =
+                        Scoped::parse(&state.config, &room_key).and_then(|scoped| scoped.tenant);
+                    state.usage.record(tenant, |usage| {
+                        usage.participant_seconds +=
+                            session_ids.len() as u64 * SAMPLE_INTERVAL.as_secs();
+                    });
+                }
+            }
+            _ = export.tick(), if config.export_dir.is_some() => {
+                let totals = state.usage.totals();
+                let period: BTreeMap<&str, TenantUsage> = totals
+                    .iter()
+                    .map(|(tenant, usage)| {
+                        let earlier = exported.get(tenant).copied().unwrap_or_default();
+                        (tenant.as_str(), usage.since(&earlier))
+                    })
+                    .collect();
+                let period_end = accounts::now_secs();
+                let dir = config.export_dir.as_deref().unwrap();
+                match write_export(dir, config.export_format, period_start, period_end, &period)
+                    .await
+                {
+                    Ok(path) => info!("Exported usage to {}", path.display()),
+                    Err(err) => warn!("Cannot export usage: {err:#}"),
+                }
+                exported = totals;
+                period_start = period_end;
+            }
+        }
+    }
+}
+
+async fn write_export(
+    dir: &Path,
+    format: UsageFormat,
+    start: u64,
+    end: u64,
+    period: &BTreeMap<&str, TenantUsage>,
+) -> Result<std::path::PathBuf> {
+    let (extension, contents) = match format {
+        UsageFormat::Json => {
+            let export = json!({ "start": start, "end": end, "tenants": period });
+            ("json", serde_json::to_string_pretty(&export)?)
+        }
+        UsageFormat::Csv => {
+            let mut csv = String::from(
+                "start,end,tenant,participant_seconds,bytes_sent,bytes_received,stored_bytes\n",
+            );
+            for (tenant, usage) in period {
+                // Tenant IDs are letters, digits, '-', '_' and '.', so they need no quoting.
+                writeln!(
+                    csv,
+                    "{start},{end},{tenant},{},{},{},{}",
+                    usage.participant_seconds,
+                    usage.bytes_sent,
+                    usage.bytes_received,
+                    usage.stored_bytes
+                )?;
+            }
+            ("csv", csv)
+        }
+    };
+
+    tokio::fs::create_dir_all(dir)
+        .await
+        .with_context(|| format!("Cannot create {}", dir.display()))?;
+    let path = dir.join(format!("usage-{end}.{extension}"));
+    tokio::fs::write(&path, contents)
+        .await
+        .with_context(|| format!("Cannot write {}", path.display()))?;
+    Ok(path)
+}
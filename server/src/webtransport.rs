@@ -0,0 +1,317 @@
+use std::cell::Cell;
+use std::net::UdpSocket;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use anyhow::Result;
+use protobuf::system::CloseCode;
+use tokio::sync::OwnedSemaphorePermit;
+use tokio::sync::Semaphore;
+use tracing::Instrument;
+use tracing::Span;
+use tracing::debug;
+use tracing::field;
+use tracing::info;
+use tracing::info_span;
+use tracing::warn;
+use wtransport::Endpoint;
+use wtransport::Identity;
+use wtransport::ServerConfig;
+use wtransport::config::QuicTransportConfig;
+use wtransport::endpoint::IncomingSession;
+use wtransport::endpoint::endpoint_side::Server;
+use wtransport::quinn::IdleTimeout;
+use wtransport::quinn::VarInt;
+use wtransport::quinn::congestion;
+use wtransport::tls::rustls;
+use wtransport::tls::rustls::crypto::ring::Ticketer;
+use wtransport::tls::rustls::server::NoServerSessionStorage;
+use wtransport::tls::rustls::server::ProducesTickets;
+
+use crate::config::CongestionController;
+use crate::config::QuicConfig;
+use crate::federation;
+use crate::memory::MEMORY;
+use crate::metrics::METRICS;
+use crate::protocol;
+use crate::rate_limit::RateLimiter;
+use crate::session::Session;
+use crate::state::ServerState;
+use crate::trunk;
+
+pub struct WebTransportServer {
+    endpoint: Endpoint<Server>,
+    state: Arc<ServerState>,
+}
+
+impl WebTransportServer {
+    /// Serves on `socket`, such as one passed by systemd, or on a random port without one.
+    pub fn new(
+        identity: Identity,
+        state: Arc<ServerState>,
+        socket: Option<UdpSocket>,
+    ) -> Result<Self> {
+        let builder = ServerConfig::builder();
+        let builder = match socket {
+            Some(socket) => builder.with_bind_socket(socket),
+            None => builder.with_bind_default(0),
+        };
+        let server_config = builder
+            .with_custom_tls_and_transport(
+                tls_config(identity, &state.config.quic)?,
+                transport_config(&state.config.quic)?,
+            )
+            // Sessions outlive a change of the client's address, see `Session::check_path`.
+            .allow_migration(true)
+            .build();
+
+        let endpoint = Endpoint::server(server_config)?;
+
+        Ok(Self { endpoint, state })
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.endpoint.local_addr().unwrap().port()
+    }
+
+    /// How long to wait for clients to acknowledge the shutdown.
+    const SHUTDOWN_GRACE: Duration = Duration::from_secs(3);
+
+    /// Accepts connections until the endpoint closes. Connections over `accept.rate`, or while
+    /// `accept.max_pending_handshakes` are in progress, are refused before anything is spent on
+    /// them.
+    pub async fn serve(&self) -> Result<()> {
+        info!("Server running on port {}", self.local_port());
+
+        let config = &self.state.config.accept;
+        let handshakes = Arc::new(Semaphore::new(config.max_pending_handshakes.max(1)));
+        let mut accepts = RateLimiter::new(config.rate, config.burst);
+        for id in 0.. {
+            let incoming_session = self.endpoint.accept().await;
+
+            let config = self.state.live_config();
+            accepts.set_limit(config.accept.rate, config.accept.burst);
+            if !accepts.try_acquire() {
+                debug!(
+                    "Refusing connection from {}: over the accept rate",
+                    incoming_session.remote_address()
+                );
+                METRICS.connections_refused.inc();
+                incoming_session.refuse();
+                continue;
+            }
+            let Ok(handshake) = handshakes.clone().try_acquire_owned() else {
+                debug!(
+                    "Refusing connection from {}: too many pending handshakes",
+                    incoming_session.remote_address()
+                );
+                METRICS.connections_refused.inc();
+                incoming_session.refuse();
+                continue;
+            };
+
+            // `resumed` is recorded once the handshake completes, if it resumed a TLS session,
+            // and the session records the rest as it learns them.
+            let span = info_span!(
+                "Connection",
+                id,
+                remote = %incoming_session.remote_address(),
+                country = field::Empty,
+                asn = field::Empty,
+                resumed = field::Empty,
+                session_id = field::Empty,
+                user = field::Empty,
+                room = field::Empty,
+            );
+            tokio::spawn(
+                Self::handle_incoming_session(incoming_session, self.state.clone(), handshake)
+                    .instrument(span),
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Closes every connection with [`CloseCode::ServerShutdown`].
+    pub async fn shutdown(&self) {
+        info!("Closing {} connections", self.endpoint.open_connections());
+
+        let code = CloseCode::ServerShutdown;
+        self.endpoint
+            .close(protocol::close_code(code), code.as_str_name().as_bytes());
+        let _ = tokio::time::timeout(Self::SHUTDOWN_GRACE, self.endpoint.wait_idle()).await;
+    }
+
+    /// Serves a connection, holding `handshake` until it is accepted as a session.
+    async fn handle_incoming_session(
+        incoming_session: IncomingSession,
+        state: Arc<ServerState>,
+        handshake: OwnedSemaphorePermit,
+    ) {
+        async fn handle_incoming_session_impl(
+            incoming_session: IncomingSession,
+            state: Arc<ServerState>,
+            handshake: OwnedSemaphorePermit,
+        ) -> Result<()> {
+            info!("Waiting for session request...");
+
+            let timeout = state.config.accept.handshake_timeout();
+            let Ok(session_request) = tokio::time::timeout(timeout, incoming_session).await else {
+                info!("Closing connection: no session request within {timeout:?}");
+                METRICS.handshakes_timed_out.inc();
+                return Ok(());
+            };
+            let session_request = session_request?;
+            METRICS.connections.inc();
+            if RESUMED.with(Cell::get) {
+                Span::current().record("resumed", true);
+            }
+
+            info!(
+                "New session: Authority: '{}', Path: '{}'",
+                session_request.authority(),
+                session_request.path()
+            );
+
+            if session_request.path() == trunk::TRUNK_PATH
+                || session_request.path() == federation::FEDERATION_PATH
+            {
+                drop(handshake);
+                return trunk::serve(session_request, state).await;
+            }
+
+            let config = state.live_config();
+            if !config.accept.allows_authority(session_request.authority()) {
+                info!("Refusing session: the authority is not allowed");
+                METRICS.session_requests_refused.inc();
+                session_request.forbidden().await;
+                return Ok(());
+            }
+            if !config.accept.allows_path(session_request.path()) {
+                info!("Refusing session: the path is not allowed");
+                METRICS.session_requests_refused.inc();
+                session_request.not_found().await;
+                return Ok(());
+            }
+
+            if state.drain.is_draining() {
+                info!("Refusing session: the server is draining");
+                METRICS.sessions_refused_draining.inc();
+                session_request.too_many_requests().await;
+                return Ok(());
+            }
+            if MEMORY.over_soft_limit() {
+                warn!("Refusing session: memory is over the soft limit");
+                METRICS.sessions_refused.inc();
+                session_request.too_many_requests().await;
+                return Ok(());
+            }
+
+            let connection = session_request.accept().await?;
+            drop(handshake);
+
+            Session::new(Arc::new(connection), state).run().await
+        }
+
+        let result = RESUMED
+            .scope(
+                Cell::new(false),
+                handle_incoming_session_impl(incoming_session, state, handshake),
+            )
+            .await;
+        info!("Result: {:?}", result);
+    }
+}
+
+/// Builds the TLS config: wtransport's default, with session tickets and 0-RTT unless
+/// `quic.session_tickets` is off.
+///
+/// 0-RTT data is not replayed into sessions: wtransport only hands over connections once their
+/// handshake completes, which someone replaying a client's first flight cannot do.
+fn tls_config(identity: Identity, config: &QuicConfig) -> Result<rustls::ServerConfig> {
+    let mut tls = wtransport::tls::server::build_default_tls_config(identity);
+    if config.session_tickets {
+        let ticketer = Ticketer::new().context("Cannot create the TLS session ticketer")?;
+        tls.ticketer = Arc::new(CountingTicketer(ticketer));
+        // QUIC allows either no early data or any amount.
+        tls.max_early_data_size = u32::MAX;
+    } else {
+        tls.session_storage = Arc::new(NoServerSessionStorage {});
+        tls.send_tls13_tickets = 0;
+    }
+    Ok(tls)
+}
+
+tokio::task_local! {
+    /// Whether the connection served by the current task resumed a TLS session.
+    static RESUMED: Cell<bool>;
+}
+
+/// Counts the tickets clients resume sessions with, and marks their connections as resumed.
+///
+/// Quinn handles the client's first flight as the connection is accepted, in the task serving
+/// it, so [`RESUMED`] is the connection's.
+#[derive(Debug)]
+struct CountingTicketer(Arc<dyn ProducesTickets>);
+
+impl ProducesTickets for CountingTicketer {
+    fn enabled(&self) -> bool {
+        self.0.enabled()
+    }
+
+    fn lifetime(&self) -> u32 {
+        self.0.lifetime()
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        self.0.encrypt(plain)
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        let plain = self.0.decrypt(cipher)?;
+        METRICS.tls_resumptions.inc();
+        // Tickets decrypted outside a connection's task have no connection to mark.
+        let _ = RESUMED.try_with(|resumed| resumed.set(true));
+        Some(plain)
+    }
+}
+
+/// Builds the QUIC transport parameters from the config file.
+fn transport_config(config: &QuicConfig) -> Result<QuicTransportConfig> {
+    let mut transport = QuicTransportConfig::default();
+
+    let idle_timeout = IdleTimeout::try_from(config.max_idle_timeout())
+        .context("quic.max_idle_timeout_secs is too large")?;
+    transport
+        .max_idle_timeout(Some(idle_timeout))
+        .keep_alive_interval(config.keep_alive_interval())
+        .max_concurrent_bidi_streams(VarInt::from_u32(config.max_concurrent_bidi_streams))
+        .max_concurrent_uni_streams(VarInt::from_u32(config.max_concurrent_uni_streams));
+
+    match config.congestion_controller {
+        CongestionController::Cubic => {
+            let mut controller = congestion::CubicConfig::default();
+            if let Some(window) = config.initial_window {
+                controller.initial_window(window);
+            }
+            transport.congestion_controller_factory(Arc::new(controller));
+        }
+        CongestionController::Bbr => {
+            let mut controller = congestion::BbrConfig::default();
+            if let Some(window) = config.initial_window {
+                controller.initial_window(window);
+            }
+            transport.congestion_controller_factory(Arc::new(controller));
+        }
+        CongestionController::NewReno => {
+            let mut controller = congestion::NewRenoConfig::default();
+            if let Some(window) = config.initial_window {
+                controller.initial_window(window);
+            }
+            transport.congestion_controller_factory(Arc::new(controller));
+        }
+    }
+
+    Ok(transport)
+}
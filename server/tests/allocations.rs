@@ -0,0 +1,126 @@
+//! Checks that forwarding a voice frame makes no heap allocations.
+//!
+//! Each round parses the header of a speaker's datagram, fans the frame out through the
+//! registry to everyone else in the room, half of them taking voice headers, and drains their
+//! queues, while a counting allocator tallies what the server does on the way. Receiving and
+//! sending the datagrams is left out, since wtransport allocates every datagram it receives and
+//! copies every one it sends. Only the test's own thread is counted, so the harness allocating
+//! meanwhile does not fail it.
+
+use std::alloc::GlobalAlloc;
+use std::alloc::Layout;
+use std::alloc::System;
+use std::cell::Cell;
+use std::hint::black_box;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use voice_server::config::SessionConfig;
+use voice_server::fanout;
+use voice_server::fanout::Link;
+use voice_server::fanout::Outbox;
+use voice_server::fanout::Registry;
+use voice_server::fanout::VoiceFrame;
+use voice_server::fanout::VoiceHeader;
+
+/// Speakers taking turns in the room.
+const SPEAKERS: usize = 4;
+
+/// Frames forwarded per count, enough to wear through several frame buffers.
+const FRAMES: usize = 1000;
+
+/// The system allocator, counting the allocations made through it on each thread.
+struct Counting;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+fn count() {
+    // Allocations made while the thread's counter is being torn down go uncounted.
+    let _ = ALLOCATIONS.try_with(|allocations| allocations.set(allocations.get() + 1));
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count();
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count();
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+// Leaving a room spawns a task to tell its members, so the sessions need a runtime to drop in.
+#[tokio::test]
+async fn forwarding_does_not_allocate() {
+    let payload = Bytes::from(vec![0x5A; 80]);
+    let config = SessionConfig::default();
+
+    for listeners in [1, 10, 100, 1000] {
+        let registry = Arc::new(Registry::default());
+        let mut outboxes = Vec::new();
+        let sessions: Vec<_> = (0..SPEAKERS + listeners)
+            .map(|index| {
+                let (outbox, _) = Outbox::bridged(&config);
+                if index % 2 == 1 {
+                    outbox.send_audio_header();
+                }
+                outboxes.push(outbox.clone());
+                let username = format!("user-{index}");
+                let link = Link::Bridged(Arc::default());
+                let mut session = registry
+                    .register(index as i64 + 1, None, &username, outbox, link)
+                    .unwrap();
+                session.join("lobby").unwrap();
+                session
+            })
+            .collect();
+        let datagrams: Vec<Bytes> = (0..SPEAKERS)
+            .map(|speaker| {
+                let header = VoiceHeader {
+                    session_id: speaker as i64 + 1,
+                    room_id: fanout::room_id("lobby"),
+                    sequence: 0,
+                    capture_time_us: 0,
+                };
+                VoiceFrame::new(header, payload.clone()).layout(true)
+            })
+            .collect();
+
+        let forward = |speaker: usize| {
+            let datagram = black_box(&datagrams[speaker]).clone();
+            let header = VoiceHeader::parse(&datagram).unwrap();
+            let mut frame = VoiceFrame::received(header, datagram);
+            let peers = sessions[speaker].for_each_peer(|peer| peer.push_voice(&mut frame));
+            assert_eq!(peers, SPEAKERS + listeners - 1);
+            for outbox in &outboxes {
+                black_box(outbox.audio().pop());
+            }
+        };
+
+        // The first frames allocate the frame buffers and the queues' speaker sets.
+        for frame in 0..FRAMES {
+            forward(frame % SPEAKERS);
+        }
+        let before = ALLOCATIONS.with(Cell::get);
+        for frame in 0..FRAMES {
+            forward(frame % SPEAKERS);
+        }
+        let allocations = ALLOCATIONS.with(Cell::get) - before;
+
+        assert_eq!(
+            allocations, 0,
+            "forwarding {FRAMES} frames to {listeners} listeners allocated"
+        );
+    }
+}
@@ -0,0 +1,427 @@
+//! Where voice is captured from and played to, behind [`Backend`]s that list their devices and
+//! open them: [`NullBackend`], which captures silence and plays to nowhere, and [`FileBackend`],
+//! which captures WAV files and plays into them, for running without a sound card. [`Audio`]
+//! holds the devices in use and switches them while running.
+//!
+//! Audio is 16-bit mono PCM at [`SAMPLE_RATE`], moved a frame of [`FRAME_SAMPLES`] at a time, as
+//! the server's mixer takes it.
+
+use std::fmt;
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use anyhow::Result;
+use anyhow::bail;
+
+/// The sample rate of all audio, which is the rooms'.
+pub const SAMPLE_RATE: u32 = 48_000;
+
+/// The samples in a frame, 20 ms of audio.
+pub const FRAME_SAMPLES: usize = 960;
+
+/// The length of the header [`FileBackend`] writes ahead of the samples.
+const WAV_HEADER_LEN: u32 = 44;
+
+/// A device, by its backend's name and its own, written `backend:name`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Device {
+    pub backend: &'static str,
+    pub name: String,
+}
+
+impl fmt::Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.backend, self.name)
+    }
+}
+
+/// A kind of device, such as a sound system.
+pub trait Backend: Send {
+    /// The name devices are selected by, ahead of their own.
+    fn name(&self) -> &'static str;
+
+    /// The names of the devices that capture, the default first.
+    fn inputs(&self) -> Result<Vec<String>>;
+
+    /// The names of the devices that play, the default first.
+    fn outputs(&self) -> Result<Vec<String>>;
+
+    fn open_input(&self, name: &str) -> Result<Box<dyn Capture>>;
+
+    fn open_output(&self, name: &str) -> Result<Box<dyn Playback>>;
+}
+
+/// A device capturing.
+pub trait Capture: Send {
+    /// Fills `frame` with the next samples captured, returning false once there will be no more.
+    fn read(&mut self, frame: &mut [i16]) -> Result<bool>;
+}
+
+/// A device playing.
+pub trait Playback: Send {
+    /// Plays `frame` after what it was given before.
+    fn write(&mut self, frame: &[i16]) -> Result<()>;
+}
+
+/// One device each way, `default`, capturing silence for as long as it is read and playing to
+/// nowhere.
+pub struct NullBackend;
+
+impl NullBackend {
+    pub const NAME: &str = "null";
+}
+
+struct Silence;
+
+impl Capture for Silence {
+    fn read(&mut self, frame: &mut [i16]) -> Result<bool> {
+        frame.fill(0);
+        Ok(true)
+    }
+}
+
+struct Discard;
+
+impl Playback for Discard {
+    fn write(&mut self, _frame: &[i16]) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Backend for NullBackend {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+
+    fn inputs(&self) -> Result<Vec<String>> {
+        Ok(vec!["default".to_owned()])
+    }
+
+    fn outputs(&self) -> Result<Vec<String>> {
+        Ok(vec!["default".to_owned()])
+    }
+
+    fn open_input(&self, name: &str) -> Result<Box<dyn Capture>> {
+        if name != "default" {
+            bail!("No null input '{name}'");
+        }
+        Ok(Box::new(Silence))
+    }
+
+    fn open_output(&self, name: &str) -> Result<Box<dyn Playback>> {
+        if name != "default" {
+            bail!("No null output '{name}'");
+        }
+        Ok(Box::new(Discard))
+    }
+}
+
+/// The WAV files in a directory. Its inputs are the `.wav` files there, of 16-bit mono PCM at
+/// [`SAMPLE_RATE`], each captured once through. Its outputs are any file name there, created or
+/// overwritten when opened, so none are listed. A file played into is complete once its
+/// [`Playback`] is dropped.
+pub struct FileBackend {
+    dir: PathBuf,
+}
+
+impl FileBackend {
+    pub const NAME: &str = "file";
+
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    fn path(&self, name: &str) -> Result<PathBuf> {
+        if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+            bail!("Invalid file name '{name}'");
+        }
+        Ok(self.dir.join(name))
+    }
+}
+
+struct FileCapture {
+    samples: Vec<i16>,
+    position: usize,
+}
+
+impl Capture for FileCapture {
+    fn read(&mut self, frame: &mut [i16]) -> Result<bool> {
+        if self.position >= self.samples.len() {
+            return Ok(false);
+        }
+        let samples = &self.samples[self.position..];
+        let len = samples.len().min(frame.len());
+        frame[..len].copy_from_slice(&samples[..len]);
+        frame[len..].fill(0);
+        self.position += len;
+        Ok(true)
+    }
+}
+
+struct FilePlayback {
+    file: BufWriter<File>,
+    data_len: u32,
+}
+
+impl Playback for FilePlayback {
+    fn write(&mut self, frame: &[i16]) -> Result<()> {
+        for sample in frame {
+            self.file.write_all(&sample.to_le_bytes())?;
+        }
+        self.data_len = self.data_len.saturating_add(frame.len() as u32 * 2);
+        Ok(())
+    }
+}
+
+impl Drop for FilePlayback {
+    fn drop(&mut self) {
+        // Failing here leaves the sizes in the header at 0, which most players still read.
+        let _ = (|| -> std::io::Result<()> {
+            self.file.seek(SeekFrom::Start(4))?;
+            self.file.write_all(
+                &(WAV_HEADER_LEN - 8)
+                    .saturating_add(self.data_len)
+                    .to_le_bytes(),
+            )?;
+            self.file
+                .seek(SeekFrom::Start(u64::from(WAV_HEADER_LEN) - 4))?;
+            self.file.write_all(&self.data_len.to_le_bytes())?;
+            self.file.flush()
+        })();
+    }
+}
+
+impl Backend for FileBackend {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+
+    fn inputs(&self) -> Result<Vec<String>> {
+        let entries = std::fs::read_dir(&self.dir)
+            .with_context(|| format!("Cannot list {}", self.dir.display()))?;
+        let mut names = Vec::new();
+        for entry in entries {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if name.to_ascii_lowercase().ends_with(".wav") && !name.starts_with('.') {
+                names.push(name);
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    fn outputs(&self) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+
+    fn open_input(&self, name: &str) -> Result<Box<dyn Capture>> {
+        let path = self.path(name)?;
+        let bytes =
+            std::fs::read(&path).with_context(|| format!("Cannot read {}", path.display()))?;
+        let samples = parse_wav(&bytes)
+            .with_context(|| format!("Unsupported WAV file {}", path.display()))?;
+        Ok(Box::new(FileCapture {
+            samples,
+            position: 0,
+        }))
+    }
+
+    fn open_output(&self, name: &str) -> Result<Box<dyn Playback>> {
+        let path = self.path(name)?;
+        let file =
+            File::create(&path).with_context(|| format!("Cannot create {}", path.display()))?;
+        let mut file = BufWriter::new(file);
+        let mut header = Vec::with_capacity(WAV_HEADER_LEN as usize);
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&(WAV_HEADER_LEN - 8).to_le_bytes());
+        header.extend_from_slice(b"WAVEfmt ");
+        header.extend_from_slice(&16u32.to_le_bytes());
+        header.extend_from_slice(&1u16.to_le_bytes());
+        header.extend_from_slice(&1u16.to_le_bytes());
+        header.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+        header.extend_from_slice(&(SAMPLE_RATE * 2).to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&16u16.to_le_bytes());
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&0u32.to_le_bytes());
+        file.write_all(&header)?;
+        Ok(Box::new(FilePlayback { file, data_len: 0 }))
+    }
+}
+
+/// The samples of a WAV file of 16-bit mono PCM at [`SAMPLE_RATE`].
+fn parse_wav(bytes: &[u8]) -> Result<Vec<i16>> {
+    if bytes.get(..4) != Some(b"RIFF") || bytes.get(8..12) != Some(b"WAVE") {
+        bail!("not a WAV file");
+    }
+    let mut format = None;
+    let mut offset = 12;
+    while let Some(header) = bytes.get(offset..offset + 8) {
+        let len = u32::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+        let start = offset + 8;
+        let Some(chunk) = bytes.get(start..start.saturating_add(len)) else {
+            bail!(
+                "truncated {:?} chunk",
+                String::from_utf8_lossy(&header[..4])
+            );
+        };
+        match &header[..4] {
+            b"fmt " if chunk.len() >= 16 => {
+                let u16_at = |at: usize| u16::from_le_bytes([chunk[at], chunk[at + 1]]);
+                let rate = u32::from_le_bytes(chunk[4..8].try_into().unwrap());
+                format = Some((u16_at(0), u16_at(2), rate, u16_at(14)));
+            }
+            b"data" => {
+                match format {
+                    Some((1, 1, SAMPLE_RATE, 16)) => {}
+                    Some((encoding, channels, rate, bits)) => bail!(
+                        "{bits}-bit audio in format {encoding} with {channels} channels at \
+                         {rate} Hz, not 16-bit mono PCM at {SAMPLE_RATE} Hz"
+                    ),
+                    None => bail!("no fmt chunk before the data"),
+                }
+                return Ok(chunk
+                    .chunks_exact(2)
+                    .map(|sample| i16::from_le_bytes([sample[0], sample[1]]))
+                    .collect());
+            }
+            _ => {}
+        }
+        // Chunks are padded to an even length.
+        offset = start + len + len % 2;
+    }
+    bail!("no data chunk")
+}
+
+/// The devices in use, one of each way, from among the backends'. Both start as the null
+/// backend's, and selecting another opens it before the one it replaces is closed, so that a
+/// device failing to open leaves the one in use as it was.
+pub struct Audio {
+    backends: Vec<Box<dyn Backend>>,
+    input: Device,
+    capture: Box<dyn Capture>,
+    output: Device,
+    playback: Box<dyn Playback>,
+}
+
+impl Audio {
+    /// Selects devices from `backends`, after [`NullBackend`], which is always there.
+    pub fn new(backends: Vec<Box<dyn Backend>>) -> Self {
+        let null = Device {
+            backend: NullBackend::NAME,
+            name: "default".to_owned(),
+        };
+        let mut all: Vec<Box<dyn Backend>> = vec![Box::new(NullBackend)];
+        all.extend(backends);
+        Self {
+            backends: all,
+            input: null.clone(),
+            capture: Box::new(Silence),
+            output: null,
+            playback: Box::new(Discard),
+        }
+    }
+
+    /// Every backend's inputs.
+    pub fn inputs(&self) -> Result<Vec<Device>> {
+        self.list(|backend| backend.inputs())
+    }
+
+    /// Every backend's outputs.
+    pub fn outputs(&self) -> Result<Vec<Device>> {
+        self.list(|backend| backend.outputs())
+    }
+
+    /// The input in use.
+    pub fn input(&self) -> &Device {
+        &self.input
+    }
+
+    /// The output in use.
+    pub fn output(&self) -> &Device {
+        &self.output
+    }
+
+    /// Captures from `device` from now on, written `backend:name`, or just `backend` for its
+    /// default input.
+    pub fn select_input(&mut self, device: &str) -> Result<()> {
+        let (backend, name) = self.resolve(device, |backend| backend.inputs())?;
+        let backend = &self.backends[backend];
+        self.capture = backend.open_input(&name)?;
+        self.input = Device {
+            backend: backend.name(),
+            name,
+        };
+        Ok(())
+    }
+
+    /// Plays to `device` from now on, written as for [`Audio::select_input`].
+    pub fn select_output(&mut self, device: &str) -> Result<()> {
+        let (backend, name) = self.resolve(device, |backend| backend.outputs())?;
+        let backend = &self.backends[backend];
+        self.playback = backend.open_output(&name)?;
+        self.output = Device {
+            backend: backend.name(),
+            name,
+        };
+        Ok(())
+    }
+
+    /// Fills `frame` from the input, returning false once it has no more to give.
+    pub fn capture(&mut self, frame: &mut [i16]) -> Result<bool> {
+        self.capture
+            .read(frame)
+            .with_context(|| format!("Capturing from {} failed", self.input))
+    }
+
+    /// Plays `frame` on the output.
+    pub fn play(&mut self, frame: &[i16]) -> Result<()> {
+        self.playback
+            .write(frame)
+            .with_context(|| format!("Playing to {} failed", self.output))
+    }
+
+    fn list(&self, names: impl Fn(&dyn Backend) -> Result<Vec<String>>) -> Result<Vec<Device>> {
+        let mut devices = Vec::new();
+        for backend in &self.backends {
+            for name in names(backend.as_ref())? {
+                devices.push(Device {
+                    backend: backend.name(),
+                    name,
+                });
+            }
+        }
+        Ok(devices)
+    }
+
+    fn resolve(
+        &self,
+        device: &str,
+        names: impl Fn(&dyn Backend) -> Result<Vec<String>>,
+    ) -> Result<(usize, String)> {
+        let (backend_name, name) = match device.split_once(':') {
+            Some((backend, name)) => (backend, Some(name)),
+            None => (device, None),
+        };
+        let Some(index) = self
+            .backends
+            .iter()
+            .position(|backend| backend.name() == backend_name)
+        else {
+            bail!("No audio backend '{backend_name}'");
+        };
+        let name = match name {
+            Some(name) => name.to_owned(),
+            None => names(self.backends[index].as_ref())?
+                .into_iter()
+                .next()
+                .with_context(|| format!("The {backend_name} backend has no default device"))?,
+        };
+        Ok((index, name))
+    }
+}
@@ -0,0 +1,483 @@
+//! A session with the server: connecting, the HELLO, AUTH_REQUEST and JOIN_ROOM_REQUEST
+//! handshake, then the packets and voice exchanged in the room.
+//!
+//! Two tasks read the connection, one its datagrams and one its streams, and queue what they read
+//! for [`Client::next_event`], which decodes it with an [`Incoming`] and answers the server's
+//! PINGs and CLOCK_SYNCs itself. Control packets go out on a stream each, voice as
+//! datagrams, or on a stream each for sessions that did not negotiate `FEATURE_VOICE_DATAGRAMS`.
+
+use std::fmt;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use anyhow::Context;
+use anyhow::Result;
+use anyhow::bail;
+use bytes::Bytes;
+use protobuf::system;
+use protobuf::system::CloseCode;
+use protobuf::system::Feature;
+use protobuf::system::PacketType;
+use protobuf::system::auth_response_error;
+use protobuf::system::hello_error;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use wtransport::ClientConfig;
+use wtransport::Connection;
+use wtransport::RecvStream;
+use wtransport::error::ConnectionError;
+
+use crate::http::Endpoint;
+use crate::http::Server;
+use crate::incoming::Event;
+use crate::incoming::Incoming;
+use crate::incoming::MAX_PACKET_LEN;
+use crate::packet::ClientPacket;
+use crate::packet::ServerPacket;
+use crate::voice::VoiceHeader;
+
+/// The newest protocol version the client speaks.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The oldest protocol version the client still speaks.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// The features offered unless [`Options::features`] says otherwise: those a client needs no
+/// code of its own for, since [`Client`] and [`crate::Session`] handle them.
+pub const DEFAULT_FEATURES: u32 = Feature::VoiceDatagrams as u32
+    | Feature::Fragmentation as u32
+    | Feature::Redirects as u32
+    | Feature::ForwardingLimits as u32
+    | Feature::Pings as u32
+    | Feature::Migration as u32
+    | Feature::AudioHeader as u32;
+
+/// How long each step of the handshake may take.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long the connection may go silent before it counts as dropped, so that a server gone
+/// without closing it is noticed well before its own idle timeout.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often the connection is kept alive while nothing else is sent.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(3);
+
+/// How many JOIN_ROOM_REDIRECTs are followed before giving up on joining.
+const MAX_REDIRECTS: usize = 4;
+
+/// How many packets the reading tasks queue ahead of [`Client::next_event`].
+const INCOMING_QUEUE_LEN: usize = 256;
+
+/// Who to log in as.
+#[derive(Debug, Clone, Default)]
+pub struct Login {
+    pub username: String,
+
+    /// The account password, on servers with user accounts.
+    pub password: String,
+
+    /// The tenant to log in to, on servers hosting several.
+    pub tenant: String,
+
+    /// A token from `POST /session`, to log in with instead of the fields above. It works once.
+    pub session_token: String,
+}
+
+/// How to join.
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub login: Login,
+    pub room_key: String,
+
+    /// The [`Feature`]s to offer, as a bitmask.
+    pub features: u32,
+}
+
+impl Options {
+    /// Options for joining `room_key` as `username`, offering [`DEFAULT_FEATURES`].
+    pub fn new(username: impl Into<String>, room_key: impl Into<String>) -> Self {
+        Self {
+            login: Login {
+                username: username.into(),
+                ..Default::default()
+            },
+            room_key: room_key.into(),
+            features: DEFAULT_FEATURES,
+        }
+    }
+}
+
+/// Why the server refused or ended the session, for a reason trying again would not fix. Errors
+/// carrying one can be told apart with `downcast_ref`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refusal {
+    Handshake(hello_error::Type),
+    Login(auth_response_error::Type),
+    Closed(CloseCode),
+}
+
+impl fmt::Display for Refusal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Handshake(error) => write!(
+                f,
+                "The server refused the handshake: {}",
+                error.as_str_name()
+            ),
+            Self::Login(error) => {
+                write!(f, "The server refused the login: {}", error.as_str_name())
+            }
+            Self::Closed(code) => {
+                write!(f, "The server closed the session: {}", code.as_str_name())
+            }
+        }
+    }
+}
+
+impl std::error::Error for Refusal {}
+
+/// A session in a room.
+pub struct Client {
+    connection: Connection,
+    queue: mpsc::Receiver<Bytes>,
+    incoming: Incoming,
+
+    /// The tasks reading the connection, stopped when the client is dropped.
+    _readers: JoinSet<()>,
+
+    features: u32,
+    session_id: i64,
+    room_key: String,
+    room_id: u32,
+    users: Vec<system::RoomUser>,
+    blocked: Vec<String>,
+
+    /// The sequence number of the next voice frame sent.
+    sequence: u32,
+}
+
+/// How a connection's handshake ended.
+enum Handshake {
+    Joined(Client),
+    Redirected(system::JoinRoomRedirect),
+}
+
+impl Client {
+    /// Connects to `endpoint` and joins as `options` say, following JOIN_ROOM_REDIRECTs to the
+    /// server hosting the room.
+    pub async fn connect(endpoint: &Endpoint, options: &Options) -> Result<Self> {
+        Self::resume(endpoint, options, "").await
+    }
+
+    /// Connects as [`Client::connect`] does, logging in with a JOIN_ROOM_REDIRECT's
+    /// `resume_token` instead of `options.login` unless it is empty.
+    pub async fn resume(
+        endpoint: &Endpoint,
+        options: &Options,
+        resume_token: &str,
+    ) -> Result<Self> {
+        let mut endpoint = endpoint.clone();
+        let mut resume_token = resume_token.to_owned();
+        for _ in 0..=MAX_REDIRECTS {
+            match Self::handshake(&endpoint, options, &resume_token).await? {
+                Handshake::Joined(client) => return Ok(client),
+                Handshake::Redirected(redirect) => {
+                    endpoint = Server::new(&redirect.address)?.endpoint().await?;
+                    resume_token = redirect.resume_token;
+                }
+            }
+        }
+        bail!(
+            "Redirected more than {MAX_REDIRECTS} times joining {}",
+            options.room_key
+        )
+    }
+
+    async fn handshake(
+        endpoint: &Endpoint,
+        options: &Options,
+        resume_token: &str,
+    ) -> Result<Handshake> {
+        let config = ClientConfig::builder()
+            .with_bind_default()
+            .with_server_certificate_hashes([endpoint.cert_digest.clone()])
+            .keep_alive_interval(Some(KEEP_ALIVE_INTERVAL))
+            .max_idle_timeout(Some(IDLE_TIMEOUT))?
+            .build();
+        let connection = wtransport::Endpoint::client(config)?
+            .connect(&endpoint.url)
+            .await
+            .with_context(|| format!("Cannot connect to {}", endpoint.url))?;
+        let mut client = Self::start(connection);
+
+        let hello = system::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+            features: options.features,
+        };
+        client.send(&ClientPacket::Hello(hello)).await?;
+        let ServerPacket::HelloAck(ack) = client.expect(PacketType::HelloAck).await? else {
+            unreachable!();
+        };
+        client.features = ack.features;
+        client.incoming.set_features(ack.features);
+
+        let auth = system::AuthRequest {
+            username: options.login.username.clone(),
+            token: options.login.password.clone(),
+            tenant: options.login.tenant.clone(),
+            resume_token: resume_token.to_owned(),
+            session_token: options.login.session_token.clone(),
+        };
+        client.send(&ClientPacket::AuthRequest(auth)).await?;
+        let ServerPacket::AuthResponseSuccess(success) =
+            client.expect(PacketType::AuthResponseSuccess).await?
+        else {
+            unreachable!();
+        };
+        client.session_id = success.session_id;
+        client.blocked = success.blocked;
+
+        let join = system::JoinRoomRequest {
+            room_key: options.room_key.clone(),
+        };
+        client.send(&ClientPacket::JoinRoomRequest(join)).await?;
+        match client.expect(PacketType::JoinRoomResponse).await? {
+            ServerPacket::JoinRoomResponse(response) => {
+                client.room_id = response.room_id;
+                client.room_key = response.room_key;
+                client.users = response.users;
+                Ok(Handshake::Joined(client))
+            }
+            ServerPacket::JoinRoomRedirect(redirect) => {
+                client.close();
+                Ok(Handshake::Redirected(redirect))
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Starts reading `connection`.
+    fn start(connection: Connection) -> Self {
+        let (sender, queue) = mpsc::channel(INCOMING_QUEUE_LEN);
+        let mut readers = JoinSet::new();
+        readers.spawn({
+            let connection = connection.clone();
+            let sender = sender.clone();
+            async move {
+                while let Ok(datagram) = connection.receive_datagram().await {
+                    if sender.send(datagram.payload()).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+        readers.spawn({
+            let connection = connection.clone();
+            async move {
+                let mut streams = JoinSet::new();
+                while let Ok(stream) = connection.accept_uni().await {
+                    let sender = sender.clone();
+                    streams.spawn(async move {
+                        if let Some(packet) = read_packet(stream).await {
+                            let _ = sender.send(packet).await;
+                        }
+                    });
+                    while streams.try_join_next().is_some() {}
+                }
+            }
+        });
+        Self {
+            connection,
+            queue,
+            incoming: Incoming::new(),
+            _readers: readers,
+            features: 0,
+            session_id: 0,
+            room_key: String::new(),
+            room_id: 0,
+            users: Vec::new(),
+            blocked: Vec::new(),
+            sequence: 0,
+        }
+    }
+
+    /// Waits for what the server sends next. PINGs, CLOCK_SYNCs and packets this crate does
+    /// not know, from newer servers, are handled here and not returned.
+    ///
+    /// Cancel safe: nothing is lost if the future is dropped before it completes. Fails once the
+    /// connection closes, with a [`Refusal`] if the server closed it for good.
+    pub async fn next_event(&mut self) -> Result<Event> {
+        loop {
+            let Some(data) = self.queue.recv().await else {
+                return Err(closed(self.connection.closed().await));
+            };
+            let Some(event) = self.incoming.push(data)? else {
+                continue;
+            };
+            match event {
+                Event::Packet(ServerPacket::Ping(ping)) => self.reply(ClientPacket::Pong(ping)),
+                Event::Packet(ServerPacket::ClockSync(mut sync)) => {
+                    sync.client_receive_time_us = now_us();
+                    sync.client_send_time_us = now_us();
+                    self.reply(ClientPacket::ClockSync(sync));
+                }
+                event => return Ok(event),
+            }
+        }
+    }
+
+    /// Sends a control packet, on a stream of its own.
+    pub async fn send(&self, packet: &ClientPacket) -> Result<()> {
+        send_on_stream(&self.connection, &packet.encode()).await
+    }
+
+    /// Sends a voice frame's payload, in the layout the session negotiated.
+    pub fn send_voice(&mut self, payload: &[u8]) -> Result<()> {
+        let header = VoiceHeader {
+            session_id: self.session_id,
+            room_id: self.room_id,
+            sequence: self.sequence,
+            capture_time_us: now_us(),
+        };
+        self.sequence = self.sequence.wrapping_add(1);
+        let datagram = header.encode(self.features, payload);
+        if self.has_feature(Feature::VoiceDatagrams) {
+            self.connection.send_datagram(&datagram)?;
+        } else {
+            let connection = self.connection.clone();
+            tokio::spawn(async move { send_on_stream(&connection, &datagram).await });
+        }
+        Ok(())
+    }
+
+    /// Closes the connection, as leaving does.
+    pub fn close(self) {
+        self.connection
+            .close(0u32.into(), CloseCode::Normal.as_str_name().as_bytes());
+    }
+
+    /// The features negotiated, as a [`Feature`] bitmask.
+    pub fn features(&self) -> u32 {
+        self.features
+    }
+
+    pub fn has_feature(&self, feature: Feature) -> bool {
+        self.features & feature as u32 != 0
+    }
+
+    pub fn session_id(&self) -> i64 {
+        self.session_id
+    }
+
+    /// The room joined, which is the one asked for unless the server moved the client.
+    pub fn room_key(&self) -> &str {
+        &self.room_key
+    }
+
+    /// The room's ID in voice headers.
+    pub fn room_id(&self) -> u32 {
+        self.room_id
+    }
+
+    /// Who was in the room on joining.
+    pub fn users(&self) -> &[system::RoomUser] {
+        &self.users
+    }
+
+    /// The users the account blocks, on servers with accounts.
+    pub fn blocked(&self) -> &[String] {
+        &self.blocked
+    }
+
+    pub fn connection(&self) -> &Connection {
+        &self.connection
+    }
+
+    /// Waits, during the handshake, for the packet that completes a step, failing on a refusal
+    /// or after [`HANDSHAKE_TIMEOUT`]. Joining may instead be answered with a redirect, or wait
+    /// in the room's lobby, which lifts the timeout.
+    async fn expect(&mut self, packet_type: PacketType) -> Result<ServerPacket> {
+        let mut deadline = Some(tokio::time::Instant::now() + HANDSHAKE_TIMEOUT);
+        loop {
+            let event = match deadline {
+                Some(deadline) => tokio::time::timeout_at(deadline, self.next_event())
+                    .await
+                    .with_context(|| format!("Timed out waiting for {packet_type:?}"))??,
+                None => self.next_event().await?,
+            };
+            let Event::Packet(packet) = event else {
+                continue;
+            };
+            match packet {
+                packet if packet.packet_type() == packet_type => return Ok(packet),
+                ServerPacket::JoinRoomRedirect(_)
+                    if packet_type == PacketType::JoinRoomResponse =>
+                {
+                    return Ok(packet);
+                }
+                ServerPacket::LobbyWaiting(_) => deadline = None,
+                ServerPacket::HelloError(error) => bail!(Refusal::Handshake(error.r#type())),
+                ServerPacket::AuthResponseError(error) => bail!(Refusal::Login(error.r#type())),
+                ServerPacket::Error(error) => {
+                    bail!("{}: {}", error.code().as_str_name(), error.detail)
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Sends a packet in answer to the server's, without holding up [`Client::next_event`].
+    fn reply(&self, packet: ClientPacket) {
+        let connection = self.connection.clone();
+        tokio::spawn(async move { send_on_stream(&connection, &packet.encode()).await });
+    }
+}
+
+async fn send_on_stream(connection: &Connection, data: &[u8]) -> Result<()> {
+    let mut stream = connection.open_uni().await?.await?;
+    stream.write_all(data).await?;
+    stream.finish().await?;
+    Ok(())
+}
+
+/// Reads a stream carrying one packet, unless it runs past [`MAX_PACKET_LEN`].
+async fn read_packet(mut stream: RecvStream) -> Option<Bytes> {
+    let mut packet = Vec::new();
+    let mut buffer = [0; 4096];
+    while let Some(len) = stream.read(&mut buffer).await.ok()? {
+        packet.extend_from_slice(&buffer[..len]);
+        if packet.len() > MAX_PACKET_LEN {
+            return None;
+        }
+    }
+    Some(packet.into())
+}
+
+/// The error for a closed connection, a [`Refusal`] if the server closed it for a reason
+/// reconnecting would not fix.
+fn closed(error: ConnectionError) -> anyhow::Error {
+    if let ConnectionError::ApplicationClosed(close) = &error {
+        let code = u32::try_from(close.code().into_inner())
+            .ok()
+            .and_then(|code| CloseCode::try_from(code as i32).ok());
+        if let Some(
+            code @ (CloseCode::Kicked
+            | CloseCode::Banned
+            | CloseCode::ProtocolViolation
+            | CloseCode::UnsupportedVersion),
+        ) = code
+        {
+            return Refusal::Closed(code).into();
+        }
+    }
+    anyhow::Error::new(error).context("The connection closed")
+}
+
+/// Microseconds since the Unix epoch.
+fn now_us() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_micros() as u64)
+}
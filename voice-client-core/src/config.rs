@@ -0,0 +1,51 @@
+//! The server's `/config.json`, as clients read it.
+
+use serde::Deserialize;
+
+/// The parts of `/config.json` a client acts on.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ServerConfig {
+    pub cert_digest_base64: String,
+    pub default_port: u16,
+
+    /// The addresses the server may be reached at, for clients to pick one they reach.
+    #[serde(default)]
+    pub endpoints: Vec<ListedEndpoint>,
+
+    /// Missing from older servers' documents.
+    #[serde(default)]
+    pub capabilities: Capabilities,
+}
+
+/// An address `/config.json` lists.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ListedEndpoint {
+    pub name: Option<String>,
+    pub url: String,
+    pub host: String,
+    pub port: u16,
+    pub cert_digest_base64: String,
+}
+
+/// What the server supports, as `/config.json` describes it.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Capabilities {
+    pub min_protocol_version: u32,
+    pub max_protocol_version: u32,
+
+    /// The `Feature`s the server negotiates, as a bitmask.
+    pub feature_bits: u32,
+
+    pub max_datagram_size: u32,
+    pub max_control_packet_len: u32,
+
+    /// The voice codecs the server decodes, which mixed rooms take.
+    pub codecs: Vec<String>,
+
+    /// Whether rooms are mixed on the server rather than forwarded.
+    pub mixing: bool,
+
+    /// Whether voice may be encrypted end to end.
+    pub e2ee: bool,
+}
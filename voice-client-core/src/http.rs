@@ -0,0 +1,114 @@
+//! The server's HTTP port, where clients find out how to connect: `/config.json` gives the
+//! WebTransport port, the certificate's digest and what the server supports; see
+//! [`ServerConfig`].
+
+use std::time::Duration;
+
+use anyhow::Context;
+use anyhow::Result;
+use anyhow::bail;
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use wtransport::tls::Sha256Digest;
+
+use crate::config::ServerConfig;
+
+/// How long a request may take before it fails.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct Server {
+    /// The `host:port` the server's HTTP port is at.
+    authority: String,
+}
+
+/// Where clients connect to.
+#[derive(Debug, Clone)]
+pub struct Endpoint {
+    pub url: String,
+    pub cert_digest: Sha256Digest,
+}
+
+impl Server {
+    /// The server at `url`, such as `http://127.0.0.1:8080`.
+    pub fn new(url: &str) -> Result<Self> {
+        let Some(rest) = url.strip_prefix("http://") else {
+            bail!("The server URL must start with http://");
+        };
+        let authority = rest.split('/').next().unwrap_or_default();
+        let authority = match authority.rsplit_once(':') {
+            Some((_, port)) if port.parse::<u16>().is_ok() => authority.to_owned(),
+            _ => format!("{authority}:80"),
+        };
+        Ok(Self { authority })
+    }
+
+    /// The server's `/config.json`.
+    pub async fn config(&self) -> Result<ServerConfig> {
+        let body = self.get("/config.json").await?;
+        serde_json::from_str(&body).context("Malformed /config.json")
+    }
+
+    /// The server's WebTransport endpoint, on the host its HTTP port was reached at.
+    pub async fn endpoint(&self) -> Result<Endpoint> {
+        let config = self.config().await?;
+        let digest = BASE64_STANDARD
+            .decode(&config.cert_digest_base64)
+            .ok()
+            .and_then(|digest| <[u8; 32]>::try_from(digest).ok())
+            .context("/config.json has no valid cert_digest_base64")?;
+        let (host, _) = self.authority.rsplit_once(':').unwrap();
+        Ok(Endpoint {
+            url: format!("https://{host}:{}/", config.default_port),
+            cert_digest: Sha256Digest::new(digest),
+        })
+    }
+
+    /// The body of a GET request for `path`, which must answer 200.
+    pub async fn get(&self, path: &str) -> Result<String> {
+        tokio::time::timeout(TIMEOUT, self.exchange(path))
+            .await
+            .with_context(|| format!("GET {path} timed out"))?
+            .with_context(|| format!("GET {path} failed"))
+    }
+
+    async fn exchange(&self, path: &str) -> Result<String> {
+        let head = format!(
+            "GET {path} HTTP/1.1\r\n\
+             Host: {}\r\n\
+             Connection: close\r\n\r\n",
+            self.authority
+        );
+        let mut stream = TcpStream::connect(&self.authority)
+            .await
+            .with_context(|| format!("Cannot connect to {}", self.authority))?;
+        stream.write_all(head.as_bytes()).await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+
+        let Some(split) = response.windows(4).position(|window| window == b"\r\n\r\n") else {
+            bail!("Malformed HTTP response");
+        };
+        let head = String::from_utf8_lossy(&response[..split]);
+        let body = String::from_utf8_lossy(&response[split + 4..]).into_owned();
+        let mut lines = head.split("\r\n");
+        let status = lines
+            .next()
+            .and_then(|line| line.split(' ').nth(1))
+            .and_then(|code| code.parse::<u16>().ok())
+            .context("Malformed HTTP status line")?;
+        if lines.any(|line| {
+            line.split_once(':').is_some_and(|(name, value)| {
+                name.eq_ignore_ascii_case("transfer-encoding") && value.contains("chunked")
+            })
+        }) {
+            bail!("Chunked responses are not supported");
+        }
+        if status != 200 {
+            bail!("The server answered {status}: {}", body.trim());
+        }
+        Ok(body)
+    }
+}
@@ -0,0 +1,78 @@
+//! Turning what the server sends into [`Event`]s, whatever carried it: each datagram, stream or
+//! WebSocket message is one packet, handed to [`Incoming::push`] as it arrives. Nothing here
+//! does any I/O, so it serves the native `Client` and clients whose transport is not this
+//! crate's alike.
+
+use anyhow::Result;
+use bytes::Bytes;
+use protobuf::system::PacketType;
+
+use crate::fragment::Reassembler;
+use crate::packet::PacketError;
+use crate::packet::ServerPacket;
+use crate::voice;
+use crate::voice::VoiceFrame;
+
+/// The largest control packet accepted, as the server accepts from clients.
+pub const MAX_PACKET_LEN: usize = 65536;
+
+/// Something the server sent.
+#[derive(Debug, Clone)]
+pub enum Event {
+    Packet(ServerPacket),
+    Voice(VoiceFrame),
+}
+
+/// A session's packets from the server, decoded.
+pub struct Incoming {
+    features: u32,
+    reassembler: Reassembler,
+}
+
+impl Default for Incoming {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Incoming {
+    pub fn new() -> Self {
+        Self {
+            features: 0,
+            reassembler: Reassembler::new(MAX_PACKET_LEN),
+        }
+    }
+
+    /// Sets the features HELLO_ACK negotiated, which decide how voice is laid out.
+    pub fn set_features(&mut self, features: u32) {
+        self.features = features;
+    }
+
+    /// Decodes a packet, returning what it makes once it is whole. The pieces of fragmented
+    /// packets make nothing until the last arrives, and neither do malformed voice frames and
+    /// packets of types this crate does not know, from newer servers.
+    pub fn push(&mut self, data: Bytes) -> Result<Option<Event>> {
+        if data.first() == Some(&voice::VOICE_PACKET_PREFIX) {
+            return Ok(VoiceFrame::decode(self.features, data).map(Event::Voice));
+        }
+
+        let reassembled;
+        let data = match data.split_first() {
+            Some((&packet_type, payload)) if packet_type == PacketType::Fragment as u8 => {
+                match self.reassembler.push(payload)? {
+                    Some(packet) => {
+                        reassembled = packet;
+                        &reassembled[..]
+                    }
+                    None => return Ok(None),
+                }
+            }
+            _ => &data,
+        };
+        match ServerPacket::decode(data) {
+            Ok(packet) => Ok(Some(Event::Packet(packet))),
+            Err(PacketError::UnknownType(_) | PacketError::UnexpectedType(_)) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
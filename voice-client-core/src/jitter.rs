@@ -0,0 +1,329 @@
+//! A speaker's jitter buffer: it holds back a few frames so that ones delayed or reordered on
+//! the way still play in order, and says when one was lost.
+//!
+//! Frames are placed by sequence number, as voice headers carry it. For frames without one, the
+//! order they arrived in serves. Playout waits until [`JitterBuffer::new`]'s `depth` frames are
+//! buffered, then takes one frame per frame interval. A frame that arrives after its turn has
+//! passed is dropped, and one whose turn comes before it arrives is reported lost, for the
+//! decoder to conceal. When the buffer runs dry it refills to `depth` before playing again, and
+//! when the speaker's clock runs ahead of the listener's and frames pile up, the oldest are
+//! skipped to bring the delay back down.
+
+use std::collections::VecDeque;
+
+use bytes::Bytes;
+
+/// How many frames past `depth` may pile up before the oldest are skipped.
+const MAX_EXCESS: usize = 4;
+
+/// How far a frame's sequence number may be from the next to play, either way, before the
+/// buffer takes the speaker to have restarted their sequence and starts over from it.
+const MAX_GAP: u32 = 256;
+
+/// What to play for one frame interval.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Playout {
+    Frame(Bytes),
+
+    /// The frame due was lost, or is too late to wait for.
+    Lost,
+
+    /// Nothing is due yet: the buffer is filling, after starting or running dry.
+    Buffering,
+}
+
+/// What a jitter buffer has done to the frames pushed into it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct JitterStats {
+    /// Frames dropped for arriving after their turn.
+    pub late: u64,
+
+    /// Turns that came with no frame to play.
+    pub lost: u64,
+
+    /// Frames skipped to bring the delay back down. Gaps skipped with them are not counted, as
+    /// there was nothing in them to skip.
+    pub skipped: u64,
+
+    /// Times the buffer ran dry and refilled.
+    pub underruns: u64,
+}
+
+pub struct JitterBuffer {
+    depth: usize,
+
+    /// The frames from `next` on, by their distance from it, with gaps for those yet to arrive.
+    slots: VecDeque<Option<Bytes>>,
+
+    /// The sequence number of the frame due next, once the first frame arrived.
+    next: Option<u32>,
+
+    /// Whether the buffer filled to `depth`, so frames play.
+    playing: bool,
+
+    stats: JitterStats,
+}
+
+impl JitterBuffer {
+    /// A buffer that delays playout by `depth` frames, at least one.
+    pub fn new(depth: usize) -> Self {
+        Self {
+            depth: depth.max(1),
+            slots: VecDeque::new(),
+            next: None,
+            playing: false,
+            stats: JitterStats::default(),
+        }
+    }
+
+    /// Adds the frame numbered `sequence`.
+    pub fn push(&mut self, sequence: u32, frame: Bytes) {
+        let next = *self.next.get_or_insert(sequence);
+        let offset = sequence.wrapping_sub(next) as i32;
+        if offset.unsigned_abs() >= MAX_GAP {
+            self.slots.clear();
+            self.next = Some(sequence);
+            self.playing = false;
+            self.slots.push_back(Some(frame));
+            return;
+        }
+        if offset < 0 {
+            self.stats.late += 1;
+            return;
+        }
+        let offset = offset as usize;
+        if self.slots.len() <= offset {
+            self.slots.resize(offset + 1, None);
+        }
+        self.slots[offset] = Some(frame);
+    }
+
+    /// Takes what to play for the next frame interval.
+    pub fn pop(&mut self) -> Playout {
+        if !self.playing {
+            if self.slots.len() < self.depth {
+                return Playout::Buffering;
+            }
+            self.playing = true;
+        }
+        while self.slots.len() > self.depth + MAX_EXCESS {
+            if self.advance().is_some() {
+                self.stats.skipped += 1;
+            }
+        }
+        if self.slots.is_empty() {
+            self.playing = false;
+            self.stats.underruns += 1;
+            return Playout::Buffering;
+        }
+        match self.advance() {
+            Some(frame) => Playout::Frame(frame),
+            None => {
+                self.stats.lost += 1;
+                Playout::Lost
+            }
+        }
+    }
+
+    /// How many frames' worth are buffered, counting the gaps between them.
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    pub fn stats(&self) -> JitterStats {
+        self.stats
+    }
+
+    fn advance(&mut self) -> Option<Bytes> {
+        self.next = self.next.map(|next| next.wrapping_add(1));
+        self.slots.pop_front().flatten()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use rand::Rng;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    use super::JitterBuffer;
+    use super::JitterStats;
+    use super::MAX_EXCESS;
+    use super::MAX_GAP;
+    use super::Playout;
+
+    fn frame(sequence: u32) -> Bytes {
+        Bytes::copy_from_slice(&sequence.to_be_bytes())
+    }
+
+    fn played(sequence: u32) -> Playout {
+        Playout::Frame(frame(sequence))
+    }
+
+    #[test]
+    fn reordered_frames_play_in_order() {
+        let mut buffer = JitterBuffer::new(4);
+        for sequence in [0, 2, 1] {
+            buffer.push(sequence, frame(sequence));
+            assert_eq!(buffer.pop(), Playout::Buffering);
+        }
+        buffer.push(3, frame(3));
+        for sequence in 0..4 {
+            assert_eq!(buffer.pop(), played(sequence));
+        }
+    }
+
+    #[test]
+    fn late_frames_are_dropped() {
+        let mut buffer = JitterBuffer::new(1);
+        buffer.push(0, frame(0));
+        buffer.push(2, frame(2));
+        assert_eq!(buffer.pop(), played(0));
+        assert_eq!(buffer.pop(), Playout::Lost);
+        buffer.push(1, frame(1));
+        assert_eq!(buffer.pop(), played(2));
+        assert_eq!(buffer.stats().late, 1);
+        assert_eq!(buffer.stats().lost, 1);
+    }
+
+    #[test]
+    fn lost_frames_are_reported() {
+        let mut buffer = JitterBuffer::new(4);
+        for sequence in [0, 1, 3] {
+            buffer.push(sequence, frame(sequence));
+        }
+        assert_eq!(buffer.pop(), played(0));
+        assert_eq!(buffer.pop(), played(1));
+        assert_eq!(buffer.pop(), Playout::Lost);
+        assert_eq!(buffer.pop(), played(3));
+        let stats = JitterStats {
+            lost: 1,
+            ..JitterStats::default()
+        };
+        assert_eq!(buffer.stats(), stats);
+    }
+
+    #[test]
+    fn restarted_sequences_start_over() {
+        let mut buffer = JitterBuffer::new(2);
+        buffer.push(10, frame(10));
+        buffer.push(11, frame(11));
+        assert_eq!(buffer.pop(), played(10));
+
+        // Too far ahead, as after the speaker reconnected: it refills from there.
+        buffer.push(11 + MAX_GAP, frame(11 + MAX_GAP));
+        assert_eq!(buffer.len(), 1);
+        assert_eq!(buffer.pop(), Playout::Buffering);
+        buffer.push(12 + MAX_GAP, frame(12 + MAX_GAP));
+        assert_eq!(buffer.pop(), played(11 + MAX_GAP));
+
+        // Too far behind starts over the same way, rather than counting as late.
+        buffer.push(0, frame(0));
+        buffer.push(1, frame(1));
+        assert_eq!(buffer.pop(), played(0));
+        assert_eq!(buffer.stats().late, 0);
+
+        // Sequence numbers wrapping around are not a restart.
+        let mut buffer = JitterBuffer::new(2);
+        buffer.push(u32::MAX, frame(u32::MAX));
+        buffer.push(0, frame(0));
+        assert_eq!(buffer.pop(), played(u32::MAX));
+        assert_eq!(buffer.pop(), played(0));
+    }
+
+    #[test]
+    fn piled_up_frames_are_skipped() {
+        let mut buffer = JitterBuffer::new(2);
+        let last = (2 + MAX_EXCESS + 3) as u32;
+        for sequence in 0..=last {
+            // A gap among the skipped frames is not counted as one.
+            if sequence != 1 {
+                buffer.push(sequence, frame(sequence));
+            }
+        }
+        assert_eq!(buffer.pop(), played(4));
+        assert_eq!(buffer.stats().skipped, 3);
+        assert_eq!(buffer.len(), 2 + MAX_EXCESS - 1);
+    }
+
+    #[test]
+    fn running_dry_refills() {
+        let mut buffer = JitterBuffer::new(2);
+        buffer.push(0, frame(0));
+        buffer.push(1, frame(1));
+        assert_eq!(buffer.pop(), played(0));
+        assert_eq!(buffer.pop(), played(1));
+        assert_eq!(buffer.pop(), Playout::Buffering);
+        assert_eq!(buffer.stats().underruns, 1);
+
+        buffer.push(2, frame(2));
+        assert_eq!(buffer.pop(), Playout::Buffering);
+        buffer.push(3, frame(3));
+        assert_eq!(buffer.pop(), played(2));
+        assert_eq!(buffer.stats().underruns, 1);
+    }
+
+    /// Plays frames through a buffer, one pop per frame interval, with each frame delayed by up
+    /// to `max_delay` intervals, lost with `loss` probability, and the speaker's clock running
+    /// fast by one interval in every `drift`.
+    fn simulate(rng: &mut StdRng, depth: usize, max_delay: u32, loss: f64, drift: u32) {
+        const FRAMES: u32 = 500;
+        let start = rng.random::<u32>();
+        let mut arrivals = Vec::new();
+        for index in 0..FRAMES {
+            if !rng.random_bool(loss) {
+                let sent = index - index / drift;
+                let arrival = sent + rng.random_range(0..=max_delay);
+                arrivals.push((arrival, start.wrapping_add(index)));
+            }
+        }
+        arrivals.sort_by_key(|&(arrival, _)| arrival);
+        let arrived = arrivals.len() as u64;
+
+        let mut buffer = JitterBuffer::new(depth);
+        let mut arrivals = arrivals.into_iter().peekable();
+        let mut last_played = None;
+        let mut played = 0;
+        for tick in 0.. {
+            while let Some((_, sequence)) = arrivals.next_if(|&(arrival, _)| arrival <= tick) {
+                buffer.push(sequence, frame(sequence));
+            }
+            let playout = buffer.pop();
+            if let Playout::Frame(frame) = &playout {
+                let sequence = u32::from_be_bytes(frame[..].try_into().unwrap());
+                let index = sequence.wrapping_sub(start);
+                // Each frame plays once, in order.
+                assert!(last_played.is_none_or(|last| index > last));
+                last_played = Some(index);
+                played += 1;
+            }
+            // The delay stays bounded.
+            assert!(buffer.len() < depth + MAX_EXCESS);
+            if arrivals.peek().is_none() && playout == Playout::Buffering {
+                break;
+            }
+        }
+
+        // Every frame that arrived is played, dropped for being late, skipped or still buffered.
+        let stats = buffer.stats();
+        let buffered = buffer.slots.iter().flatten().count() as u64;
+        assert_eq!(played + stats.late + stats.skipped + buffered, arrived);
+    }
+
+    #[test]
+    fn arbitrary_arrivals_play_in_order_with_bounded_delay() {
+        let mut rng = StdRng::seed_from_u64(0x6a69_7474_6572);
+        for _ in 0..200 {
+            let depth = rng.random_range(1..8);
+            let max_delay = rng.random_range(0..12);
+            let loss = rng.random_range(0.0..0.3);
+            let drift = rng.random_range(2..200);
+            simulate(&mut rng, depth, max_delay, loss, drift);
+        }
+    }
+}
@@ -0,0 +1,89 @@
+//! The client side of the voice chat protocol, shared by the bots and load tester in
+//! `voiceload` and by whatever else talks to the server from Rust.
+//!
+//! [`Server`] reads a server's `/config.json` for the [`Endpoint`] to connect to.
+//! [`Client::connect`] connects there and takes the session through the handshake into a room,
+//! after which [`Client::next_event`] yields the [`ServerPacket`]s and [`VoiceFrame`]s the server
+//! sends and [`Client::send_voice`] sends voice in the layout the session negotiated. A
+//! [`Session`] wraps a client and reconnects it when its connection drops or the server hands it
+//! over. A [`JitterBuffer`] per speaker evens out when their frames arrive, for playing them,
+//! and [`Audio`] captures and plays them on devices that can be switched while running.
+//!
+//! Those four need the `native` feature, on by default, for their sockets. Without it the crate
+//! is the protocol alone and builds for targets that have none, such as wasm32: encoding
+//! [`ClientPacket`]s and [`VoiceHeader`]s, and [`Incoming`] for decoding what arrives on a
+//! transport supplied from outside, such as the browser's `WebTransport`.
+
+mod audio;
+#[cfg(feature = "native")]
+mod client;
+mod config;
+mod fragment;
+#[cfg(feature = "native")]
+mod http;
+mod incoming;
+mod jitter;
+mod packet;
+#[cfg(feature = "native")]
+mod session;
+mod voice;
+
+pub use audio::Audio;
+pub use audio::Backend;
+pub use audio::Capture;
+pub use audio::Device;
+pub use audio::FRAME_SAMPLES;
+pub use audio::FileBackend;
+pub use audio::NullBackend;
+pub use audio::Playback;
+pub use audio::SAMPLE_RATE;
+#[cfg(feature = "native")]
+pub use client::Client;
+#[cfg(feature = "native")]
+pub use client::DEFAULT_FEATURES;
+#[cfg(feature = "native")]
+pub use client::Login;
+#[cfg(feature = "native")]
+pub use client::MIN_PROTOCOL_VERSION;
+#[cfg(feature = "native")]
+pub use client::Options;
+#[cfg(feature = "native")]
+pub use client::PROTOCOL_VERSION;
+#[cfg(feature = "native")]
+pub use client::Refusal;
+pub use config::Capabilities;
+pub use config::ListedEndpoint;
+pub use config::ServerConfig;
+pub use fragment::FragmentError;
+pub use fragment::Reassembler;
+#[cfg(feature = "native")]
+pub use http::Endpoint;
+#[cfg(feature = "native")]
+pub use http::Server;
+pub use incoming::Event;
+pub use incoming::Incoming;
+pub use incoming::MAX_PACKET_LEN;
+pub use jitter::JitterBuffer;
+pub use jitter::JitterStats;
+pub use jitter::Playout;
+pub use packet::ClientPacket;
+pub use packet::PacketError;
+pub use packet::ServerPacket;
+pub use packet::encode;
+pub use protobuf;
+#[cfg(feature = "native")]
+pub use session::MAX_ATTEMPTS;
+#[cfg(feature = "native")]
+pub use session::MAX_BACKOFF;
+#[cfg(feature = "native")]
+pub use session::MIN_BACKOFF;
+#[cfg(feature = "native")]
+pub use session::Session;
+#[cfg(feature = "native")]
+pub use session::SessionEvent;
+pub use voice::CAPTURE_TIMESTAMP_LEN;
+pub use voice::VOICE_HEADER_LEN;
+pub use voice::VOICE_PACKET_PREFIX;
+pub use voice::VoiceFrame;
+pub use voice::VoiceHeader;
+pub use voice::room_id;
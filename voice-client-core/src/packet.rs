@@ -0,0 +1,224 @@
+//! Typed control packets: a single [`PacketType`] byte followed by the protobuf-encoded message,
+//! as the server frames them.
+
+use std::fmt;
+
+use prost::Message;
+use protobuf::system;
+use protobuf::system::PacketType;
+
+/// A control packet sent to the server.
+#[derive(Debug, Clone)]
+pub enum ClientPacket {
+    Hello(system::Hello),
+    AuthRequest(system::AuthRequest),
+    JoinRoomRequest(system::JoinRoomRequest),
+    ClockSync(system::ClockSync),
+    PlayoutReport(system::PlayoutReport),
+    ChatMessage(system::ChatMessage),
+    Reaction(system::Reaction),
+    Pong(system::Ping),
+    ChatBacklogRequest(system::ChatBacklogRequest),
+    DirectMessage(system::DirectMessage),
+    BlockList(system::BlockList),
+    LobbyDecision(system::LobbyDecision),
+    SlowMode(system::SlowMode),
+}
+
+impl ClientPacket {
+    /// The packet's type.
+    pub fn packet_type(&self) -> PacketType {
+        match self {
+            Self::Hello(_) => PacketType::Hello,
+            Self::AuthRequest(_) => PacketType::AuthRequest,
+            Self::JoinRoomRequest(_) => PacketType::JoinRoomRequest,
+            Self::ClockSync(_) => PacketType::ClockSync,
+            Self::PlayoutReport(_) => PacketType::PlayoutReport,
+            Self::ChatMessage(_) => PacketType::ChatMessage,
+            Self::Reaction(_) => PacketType::Reaction,
+            Self::Pong(_) => PacketType::Pong,
+            Self::ChatBacklogRequest(_) => PacketType::ChatBacklogRequest,
+            Self::DirectMessage(_) => PacketType::DirectMessage,
+            Self::BlockList(_) => PacketType::BlockList,
+            Self::LobbyDecision(_) => PacketType::LobbyDecision,
+            Self::SlowMode(_) => PacketType::SlowMode,
+        }
+    }
+
+    /// Encodes the packet, type byte first.
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            Self::Hello(message) => encode(self.packet_type(), message),
+            Self::AuthRequest(message) => encode(self.packet_type(), message),
+            Self::JoinRoomRequest(message) => encode(self.packet_type(), message),
+            Self::ClockSync(message) => encode(self.packet_type(), message),
+            Self::PlayoutReport(message) => encode(self.packet_type(), message),
+            Self::ChatMessage(message) => encode(self.packet_type(), message),
+            Self::Reaction(message) => encode(self.packet_type(), message),
+            Self::Pong(message) => encode(self.packet_type(), message),
+            Self::ChatBacklogRequest(message) => encode(self.packet_type(), message),
+            Self::DirectMessage(message) => encode(self.packet_type(), message),
+            Self::BlockList(message) => encode(self.packet_type(), message),
+            Self::LobbyDecision(message) => encode(self.packet_type(), message),
+            Self::SlowMode(message) => encode(self.packet_type(), message),
+        }
+    }
+}
+
+/// Encodes a control packet.
+pub fn encode(packet_type: PacketType, message: &impl Message) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(1 + message.encoded_len());
+    packet.push(packet_type as u8);
+    message
+        .encode(&mut packet)
+        .expect("a Vec grows to fit the message");
+    packet
+}
+
+/// A control packet received from the server. FRAGMENT packets are reassembled before they are
+/// decoded, so they never appear here.
+#[derive(Debug, Clone)]
+pub enum ServerPacket {
+    HelloAck(system::HelloAck),
+    HelloError(system::HelloError),
+    AuthResponseSuccess(system::AuthResponseSuccess),
+    AuthResponseError(system::AuthResponseError),
+    JoinRoomResponse(system::JoinRoomResponse),
+    JoinRoomRedirect(system::JoinRoomRedirect),
+    UserJoined(system::RoomUser),
+
+    /// The session ID of the user who left.
+    UserLeft(i64),
+
+    Error(system::Error),
+    ClockSync(system::ClockSync),
+    SessionStats(system::SessionStats),
+    ChatMessage(system::ChatMessage),
+    ChatAck(system::ChatAck),
+    Reaction(system::Reaction),
+    DirectMessage(system::DirectMessage),
+    ForwardingLimit(system::ForwardingLimit),
+    Ping(system::Ping),
+    LobbyWaiting(system::LobbyWaiting),
+    LobbyPending(system::LobbyPending),
+    SlowMode(system::SlowMode),
+    Announcement(system::Announcement),
+    Maintenance(system::Maintenance),
+}
+
+impl ServerPacket {
+    /// Decodes a control packet.
+    pub fn decode(data: &[u8]) -> Result<Self, PacketError> {
+        let (&type_byte, payload) = data.split_first().ok_or(PacketError::Empty)?;
+        let packet_type = PacketType::try_from(type_byte as i32)
+            .map_err(|_| PacketError::UnknownType(type_byte))?;
+
+        fn decode<M: Message + Default>(
+            packet_type: PacketType,
+            payload: &[u8],
+        ) -> Result<M, PacketError> {
+            M::decode(payload).map_err(|err| PacketError::Decode(packet_type, err))
+        }
+        let packet = match packet_type {
+            PacketType::HelloAck => Self::HelloAck(decode(packet_type, payload)?),
+            PacketType::HelloError => Self::HelloError(decode(packet_type, payload)?),
+            PacketType::AuthResponseSuccess => {
+                Self::AuthResponseSuccess(decode(packet_type, payload)?)
+            }
+            PacketType::AuthResponseError => Self::AuthResponseError(decode(packet_type, payload)?),
+            PacketType::JoinRoomResponse => Self::JoinRoomResponse(decode(packet_type, payload)?),
+            PacketType::JoinRoomRedirect => Self::JoinRoomRedirect(decode(packet_type, payload)?),
+            PacketType::UserJoined => Self::UserJoined(decode(packet_type, payload)?),
+            PacketType::UserLeft => {
+                let session_id = <[u8; 8]>::try_from(payload)
+                    .map_err(|_| PacketError::Malformed(packet_type))?;
+                Self::UserLeft(i64::from_be_bytes(session_id))
+            }
+            PacketType::Error => Self::Error(decode(packet_type, payload)?),
+            PacketType::ClockSync => Self::ClockSync(decode(packet_type, payload)?),
+            PacketType::SessionStats => Self::SessionStats(decode(packet_type, payload)?),
+            PacketType::ChatMessage => Self::ChatMessage(decode(packet_type, payload)?),
+            PacketType::ChatAck => Self::ChatAck(decode(packet_type, payload)?),
+            PacketType::Reaction => Self::Reaction(decode(packet_type, payload)?),
+            PacketType::DirectMessage => Self::DirectMessage(decode(packet_type, payload)?),
+            PacketType::ForwardingLimit => Self::ForwardingLimit(decode(packet_type, payload)?),
+            PacketType::Ping => Self::Ping(decode(packet_type, payload)?),
+            PacketType::LobbyWaiting => Self::LobbyWaiting(decode(packet_type, payload)?),
+            PacketType::LobbyPending => Self::LobbyPending(decode(packet_type, payload)?),
+            PacketType::SlowMode => Self::SlowMode(decode(packet_type, payload)?),
+            PacketType::Announcement => Self::Announcement(decode(packet_type, payload)?),
+            PacketType::Maintenance => Self::Maintenance(decode(packet_type, payload)?),
+            // COMPRESSED is only sent to clients that negotiate FEATURE_COMPRESSION, which this
+            // crate does not offer, having no inflater.
+            other => return Err(PacketError::UnexpectedType(other)),
+        };
+        Ok(packet)
+    }
+
+    /// The packet's type.
+    pub fn packet_type(&self) -> PacketType {
+        match self {
+            Self::HelloAck(_) => PacketType::HelloAck,
+            Self::HelloError(_) => PacketType::HelloError,
+            Self::AuthResponseSuccess(_) => PacketType::AuthResponseSuccess,
+            Self::AuthResponseError(_) => PacketType::AuthResponseError,
+            Self::JoinRoomResponse(_) => PacketType::JoinRoomResponse,
+            Self::JoinRoomRedirect(_) => PacketType::JoinRoomRedirect,
+            Self::UserJoined(_) => PacketType::UserJoined,
+            Self::UserLeft(_) => PacketType::UserLeft,
+            Self::Error(_) => PacketType::Error,
+            Self::ClockSync(_) => PacketType::ClockSync,
+            Self::SessionStats(_) => PacketType::SessionStats,
+            Self::ChatMessage(_) => PacketType::ChatMessage,
+            Self::ChatAck(_) => PacketType::ChatAck,
+            Self::Reaction(_) => PacketType::Reaction,
+            Self::DirectMessage(_) => PacketType::DirectMessage,
+            Self::ForwardingLimit(_) => PacketType::ForwardingLimit,
+            Self::Ping(_) => PacketType::Ping,
+            Self::LobbyWaiting(_) => PacketType::LobbyWaiting,
+            Self::LobbyPending(_) => PacketType::LobbyPending,
+            Self::SlowMode(_) => PacketType::SlowMode,
+            Self::Announcement(_) => PacketType::Announcement,
+            Self::Maintenance(_) => PacketType::Maintenance,
+        }
+    }
+}
+
+/// An error decoding a packet.
+#[derive(Debug)]
+pub enum PacketError {
+    /// The packet had no type byte.
+    Empty,
+
+    /// The type byte is not a known [`PacketType`].
+    UnknownType(u8),
+
+    /// The packet type is valid but not one the server sends.
+    UnexpectedType(PacketType),
+
+    /// The payload is not a valid message for its packet type.
+    Decode(PacketType, prost::DecodeError),
+
+    /// The payload, which is not a message, is the wrong length.
+    Malformed(PacketType),
+}
+
+impl fmt::Display for PacketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => write!(f, "empty packet"),
+            Self::UnknownType(type_byte) => write!(f, "unknown packet type {type_byte}"),
+            Self::UnexpectedType(packet_type) => {
+                write!(f, "unexpected packet type {}", packet_type.as_str_name())
+            }
+            Self::Decode(packet_type, err) => {
+                write!(f, "malformed {} payload: {err}", packet_type.as_str_name())
+            }
+            Self::Malformed(packet_type) => {
+                write!(f, "malformed {} payload", packet_type.as_str_name())
+            }
+        }
+    }
+}
+
+impl std::error::Error for PacketError {}
@@ -0,0 +1,161 @@
+//! A [`Client`] that reconnects: when the connection drops it connects again and rejoins, and
+//! when the server hands the session over to another, with a JOIN_ROOM_REDIRECT carrying a
+//! `resume_token`, it moves there.
+//!
+//! Attempts back off from [`MIN_BACKOFF`] to [`MAX_BACKOFF`], each delay shortened by up to half
+//! at random so that many clients dropped at once do not all come back at once. A [`Refusal`]
+//! ends the session instead, as does failing [`MAX_ATTEMPTS`] times in a row.
+
+use std::pin::Pin;
+use std::time::Duration;
+
+use anyhow::Context;
+use anyhow::Result;
+use anyhow::bail;
+
+use crate::client::Client;
+use crate::client::Options;
+use crate::client::Refusal;
+use crate::http::Endpoint;
+use crate::http::Server;
+use crate::incoming::Event;
+use crate::packet::ServerPacket;
+
+/// How long to wait before the first attempt after a drop.
+pub const MIN_BACKOFF: Duration = Duration::from_millis(500);
+
+/// The longest to wait between attempts.
+pub const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// How many attempts in a row may fail before the session gives up.
+pub const MAX_ATTEMPTS: u32 = 10;
+
+/// What a [`Session`] has to report.
+#[derive(Debug, Clone)]
+pub enum SessionEvent {
+    Event(Event),
+
+    /// The session reconnected, or moved to another server, and is back in a room, with the
+    /// users in it as [`Client::users`] gives them. Voice sent meanwhile was lost.
+    Reconnected,
+}
+
+pub struct Session {
+    endpoint: Endpoint,
+    options: Options,
+    client: Client,
+
+    /// Reconnecting after the connection dropped, kept across calls to [`Session::next_event`]
+    /// so that dropping one does not start over.
+    reconnecting: Option<Pin<Box<dyn Future<Output = Result<Reconnected>> + Send>>>,
+}
+
+/// Where a reconnect ended up.
+struct Reconnected {
+    endpoint: Endpoint,
+    room_key: String,
+    client: Client,
+}
+
+impl Session {
+    /// Connects to `endpoint` and joins as `options` say. The first attempt is not retried.
+    pub async fn connect(endpoint: Endpoint, mut options: Options) -> Result<Self> {
+        let client = Client::connect(&endpoint, &options).await?;
+        // A session token works once, so reconnecting has nothing to log in with.
+        options.login.session_token.clear();
+        Ok(Self {
+            endpoint,
+            options,
+            client,
+            reconnecting: None,
+        })
+    }
+
+    /// The connection as it is now, replaced on every reconnect. While reconnecting it is the one
+    /// that dropped, and sending on it fails.
+    pub fn client(&mut self) -> &mut Client {
+        &mut self.client
+    }
+
+    /// Waits for what the server sends next, reconnecting first if the connection dropped.
+    ///
+    /// Cancel safe: a reconnect under way carries on at the next call.
+    pub async fn next_event(&mut self) -> Result<SessionEvent> {
+        if self.reconnecting.is_none() {
+            let (address, resume_token, room_key) = match self.client.next_event().await {
+                Ok(Event::Packet(ServerPacket::JoinRoomRedirect(redirect)))
+                    if !redirect.resume_token.is_empty() =>
+                {
+                    (
+                        Some(redirect.address),
+                        redirect.resume_token,
+                        redirect.room_key,
+                    )
+                }
+                Ok(event) => return Ok(SessionEvent::Event(event)),
+                Err(err) if err.is::<Refusal>() => return Err(err),
+                Err(_) => (None, String::new(), self.options.room_key.clone()),
+            };
+            let mut options = self.options.clone();
+            options.room_key = room_key;
+            self.reconnecting = Some(Box::pin(reconnect(
+                self.endpoint.clone(),
+                address,
+                options,
+                resume_token,
+            )));
+        }
+        let reconnected = self.reconnecting.as_mut().unwrap().await;
+        self.reconnecting = None;
+        let reconnected = reconnected?;
+        self.endpoint = reconnected.endpoint;
+        self.options.room_key = reconnected.room_key;
+        self.client = reconnected.client;
+        Ok(SessionEvent::Reconnected)
+    }
+
+    /// Closes the connection, as leaving does.
+    pub fn close(self) {
+        self.client.close();
+    }
+}
+
+/// Connects again as `options` say, to the server at the HTTP `address` when the session is
+/// handed over there with `resume_token`, and to `endpoint` otherwise.
+async fn reconnect(
+    mut endpoint: Endpoint,
+    address: Option<String>,
+    options: Options,
+    resume_token: String,
+) -> Result<Reconnected> {
+    if resume_token.is_empty() && options.login.username.is_empty() {
+        bail!("Cannot reconnect a session that logged in with a session token");
+    }
+    if let Some(address) = address {
+        endpoint = Server::new(&address)?.endpoint().await?;
+    }
+    let mut backoff = MIN_BACKOFF;
+    let mut resume_token = Some(resume_token).filter(|token| !token.is_empty());
+    let mut last_error = None;
+    for _ in 0..MAX_ATTEMPTS {
+        // A handover is taken up at once, while its token is good.
+        if resume_token.is_none() {
+            tokio::time::sleep(backoff.mul_f64(rand::random_range(0.5..1.0))).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+        let token = resume_token.take().unwrap_or_default();
+        match Client::resume(&endpoint, &options, &token).await {
+            Ok(client) => {
+                return Ok(Reconnected {
+                    endpoint,
+                    room_key: options.room_key,
+                    client,
+                });
+            }
+            Err(err) if err.is::<Refusal>() && token.is_empty() => return Err(err),
+            Err(err) => last_error = Some(err),
+        }
+    }
+    let error = last_error.unwrap();
+    Err(error).with_context(|| format!("Gave up reconnecting after {MAX_ATTEMPTS} attempts"))
+}
@@ -0,0 +1,127 @@
+//! Voice datagrams, in the layout the session's features call for.
+//!
+//! Every voice datagram starts with [`VOICE_PACKET_PREFIX`]. What follows depends on what was
+//! negotiated: with `FEATURE_AUDIO_HEADER`, a [`VoiceHeader`] both ways. Without it, clients
+//! send the payload alone, after a capture timestamp if they negotiated
+//! `FEATURE_LATENCY_REPORTS`, and receive it after the speaker's session ID.
+
+use bytes::Bytes;
+use protobuf::system::Feature;
+
+/// The first byte of every voice datagram.
+pub const VOICE_PACKET_PREFIX: u8 = 0xFF;
+
+/// The length of a [`VoiceHeader`], prefix included.
+pub const VOICE_HEADER_LEN: usize = 25;
+
+/// The length of the capture timestamp leading the payload of voice sent with
+/// `FEATURE_LATENCY_REPORTS` but no voice header.
+pub const CAPTURE_TIMESTAMP_LEN: usize = 8;
+
+/// The length of the speaker's session ID leading voice received without a voice header.
+const SESSION_ID_LEN: usize = 8;
+
+/// The routing fields of a voice frame, sent and received ahead of the payload by clients that
+/// negotiated `FEATURE_AUDIO_HEADER`: the prefix, the session ID as an `i64`, the room ID and the
+/// sequence number as `u32`s and the capture time as a `u64`, all big-endian. The server reads
+/// nothing past them, so the payload may be encrypted end to end in rooms that are not mixed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VoiceHeader {
+    /// The speaker's.
+    pub session_id: i64,
+
+    /// The [`room_id`] of the speaker's room, as JOIN_ROOM_RESPONSE gives it.
+    pub room_id: u32,
+
+    /// Counts the speaker's frames, wrapping around.
+    pub sequence: u32,
+
+    /// Microseconds since the Unix epoch on the speaker's clock, or 0 if unknown.
+    pub capture_time_us: u64,
+}
+
+impl VoiceHeader {
+    /// Reads the header of a voice datagram.
+    pub fn parse(datagram: &[u8]) -> Option<Self> {
+        let header = datagram.get(..VOICE_HEADER_LEN)?;
+        if header[0] != VOICE_PACKET_PREFIX {
+            return None;
+        }
+        Some(Self {
+            session_id: i64::from_be_bytes(header[1..9].try_into().unwrap()),
+            room_id: u32::from_be_bytes(header[9..13].try_into().unwrap()),
+            sequence: u32::from_be_bytes(header[13..17].try_into().unwrap()),
+            capture_time_us: u64::from_be_bytes(header[17..25].try_into().unwrap()),
+        })
+    }
+
+    /// Builds a voice datagram to send, in the layout for a session that negotiated `features`.
+    /// Only the capture time goes with the payload without `FEATURE_AUDIO_HEADER`, and only with
+    /// `FEATURE_LATENCY_REPORTS`.
+    pub fn encode(&self, features: u32, payload: &[u8]) -> Vec<u8> {
+        let mut datagram = Vec::with_capacity(VOICE_HEADER_LEN + payload.len());
+        if features & Feature::AudioHeader as u32 != 0 {
+            self.write(&mut datagram);
+        } else {
+            datagram.push(VOICE_PACKET_PREFIX);
+            if features & Feature::LatencyReports as u32 != 0 {
+                datagram.extend_from_slice(&self.capture_time_us.to_be_bytes());
+            }
+        }
+        datagram.extend_from_slice(payload);
+        datagram
+    }
+
+    /// Writes the header, prefix first.
+    pub fn write(&self, out: &mut Vec<u8>) {
+        out.push(VOICE_PACKET_PREFIX);
+        out.extend_from_slice(&self.session_id.to_be_bytes());
+        out.extend_from_slice(&self.room_id.to_be_bytes());
+        out.extend_from_slice(&self.sequence.to_be_bytes());
+        out.extend_from_slice(&self.capture_time_us.to_be_bytes());
+    }
+}
+
+/// The ID voice headers carry for a room, by its key with its tenant: the key's 32-bit FNV-1a
+/// hash, as the server computes it.
+pub fn room_id(room_key: &str) -> u32 {
+    room_key.bytes().fold(0x811c_9dc5, |hash, byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// A voice frame received from the server.
+#[derive(Debug, Clone)]
+pub struct VoiceFrame {
+    /// The speaker's session ID.
+    pub session_id: i64,
+
+    /// The frame's header, if the session negotiated `FEATURE_AUDIO_HEADER`.
+    pub header: Option<VoiceHeader>,
+
+    pub payload: Bytes,
+}
+
+impl VoiceFrame {
+    /// Reads a voice datagram from the server, in the layout for a session that negotiated
+    /// `features`.
+    pub fn decode(features: u32, datagram: Bytes) -> Option<Self> {
+        if features & Feature::AudioHeader as u32 != 0 {
+            let header = VoiceHeader::parse(&datagram)?;
+            return Some(Self {
+                session_id: header.session_id,
+                header: Some(header),
+                payload: datagram.slice(VOICE_HEADER_LEN..),
+            });
+        }
+        let session_id = datagram.get(1..1 + SESSION_ID_LEN)?;
+        if datagram[0] != VOICE_PACKET_PREFIX {
+            return None;
+        }
+        Some(Self {
+            session_id: i64::from_be_bytes(session_id.try_into().unwrap()),
+            header: None,
+            payload: datagram.slice(1 + SESSION_ID_LEN..),
+        })
+    }
+}
@@ -0,0 +1,265 @@
+//! A client for the server's admin API: the `admin.Admin` gRPC-Web service, called over a new
+//! HTTP/1.1 connection per request.
+
+use std::time::Duration;
+
+use anyhow::Context;
+use anyhow::Result;
+use anyhow::bail;
+use prost::Message;
+use protobuf::admin;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+/// How long a call may take before it fails.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+const TRAILER_FLAG: u8 = 0x80;
+
+pub struct Client {
+    /// The `host:port` the server's HTTP port is at.
+    authority: String,
+    token: String,
+}
+
+impl Client {
+    /// A client for the server at `url`, such as `http://127.0.0.1:8080`.
+    pub fn new(url: &str, token: String) -> Result<Self> {
+        let Some(rest) = url.strip_prefix("http://") else {
+            bail!("The server URL must start with http://");
+        };
+        let authority = rest.split('/').next().unwrap_or_default();
+        let authority = match authority.rsplit_once(':') {
+            Some((_, port)) if port.parse::<u16>().is_ok() => authority.to_owned(),
+            _ => format!("{authority}:80"),
+        };
+        Ok(Self { authority, token })
+    }
+
+    pub async fn list_rooms(&self) -> Result<Vec<admin::Room>> {
+        let response: admin::ListRoomsResponse =
+            self.call("ListRooms", &admin::ListRoomsRequest {}).await?;
+        Ok(response.rooms)
+    }
+
+    pub async fn list_sessions(&self) -> Result<Vec<admin::Session>> {
+        let response: admin::ListSessionsResponse = self
+            .call("ListSessions", &admin::ListSessionsRequest {})
+            .await?;
+        Ok(response.sessions)
+    }
+
+    pub async fn room_forwarding(
+        &self,
+        room_key: &str,
+    ) -> Result<admin::GetRoomForwardingResponse> {
+        let request = admin::GetRoomForwardingRequest {
+            room_key: room_key.to_owned(),
+        };
+        self.call("GetRoomForwarding", &request).await
+    }
+
+    /// Disconnects a session, returning whether it existed.
+    pub async fn kick(&self, session_id: i64) -> Result<bool> {
+        let response: admin::KickSessionResponse = self
+            .call("KickSession", &admin::KickSessionRequest { session_id })
+            .await?;
+        Ok(response.kicked)
+    }
+
+    /// Mutes or unmutes a session, returning whether it existed.
+    pub async fn mute(&self, session_id: i64, muted: bool) -> Result<bool> {
+        let request = admin::MuteSessionRequest { session_id, muted };
+        let response: admin::MuteSessionResponse = self.call("MuteSession", &request).await?;
+        Ok(response.found)
+    }
+
+    /// Bans a user, returning how many of their sessions were disconnected.
+    pub async fn ban(&self, username: &str, reason: &str, days: Option<u32>) -> Result<u32> {
+        let request = admin::BanUserRequest {
+            username: username.to_owned(),
+            reason: reason.to_owned(),
+            days,
+        };
+        let response: admin::BanUserResponse = self.call("BanUser", &request).await?;
+        Ok(response.kicked)
+    }
+
+    /// Drains the server, returning whether draining started, how many sessions are left and
+    /// how many were handed over to `address`.
+    pub async fn drain(&self, address: &str, timeout_secs: u32) -> Result<(bool, u32, u32)> {
+        let request = admin::DrainRequest {
+            address: address.to_owned(),
+            timeout_secs,
+        };
+        let response: admin::DrainResponse = self.call("Drain", &request).await?;
+        Ok((response.started, response.sessions, response.handed_over))
+    }
+
+    /// Schedules a maintenance window starting at `starts_at`, or cancels the one scheduled with
+    /// 0, returning whether one was scheduled before.
+    pub async fn schedule_maintenance(
+        &self,
+        starts_at: u64,
+        reason: &str,
+        address: &str,
+        timeout_secs: u32,
+    ) -> Result<bool> {
+        let request = admin::ScheduleMaintenanceRequest {
+            starts_at,
+            reason: reason.to_owned(),
+            address: address.to_owned(),
+            timeout_secs,
+        };
+        let response: admin::ScheduleMaintenanceResponse =
+            self.call("ScheduleMaintenance", &request).await?;
+        Ok(response.replaced)
+    }
+
+    /// Books a room, returning it with its ID.
+    pub async fn schedule_room(&self, room: admin::ScheduledRoom) -> Result<admin::ScheduledRoom> {
+        let request = admin::ScheduleRoomRequest { room: Some(room) };
+        let response: admin::ScheduleRoomResponse = self.call("ScheduleRoom", &request).await?;
+        response.room.context("The response has no room")
+    }
+
+    /// Cancels a scheduled room, returning whether it was scheduled.
+    pub async fn cancel_scheduled_room(&self, id: &str) -> Result<bool> {
+        let request = admin::CancelScheduledRoomRequest { id: id.to_owned() };
+        let response: admin::CancelScheduledRoomResponse =
+            self.call("CancelScheduledRoom", &request).await?;
+        Ok(response.cancelled)
+    }
+
+    pub async fn list_scheduled_rooms(&self) -> Result<Vec<admin::ScheduledRoom>> {
+        let response: admin::ListScheduledRoomsResponse = self
+            .call("ListScheduledRooms", &admin::ListScheduledRoomsRequest {})
+            .await?;
+        Ok(response.rooms)
+    }
+
+    /// Sends an announcement, playing the WAV file called `audio` on the server if it is not
+    /// empty, returning how many sessions it was sent to and how many rooms it plays in.
+    pub async fn announce(&self, text: &str, audio: &str) -> Result<(u32, u32)> {
+        let request = admin::AnnounceRequest {
+            text: text.to_owned(),
+            audio: audio.to_owned(),
+        };
+        let response: admin::AnnounceResponse = self.call("Announce", &request).await?;
+        Ok((response.sessions, response.rooms))
+    }
+
+    /// Calls `method` with `request`, returning its response message.
+    async fn call<T: Message + Default>(&self, method: &str, request: &impl Message) -> Result<T> {
+        tokio::time::timeout(TIMEOUT, self.exchange(method, request))
+            .await
+            .with_context(|| format!("{method} timed out"))?
+            .with_context(|| format!("{method} failed"))
+    }
+
+    async fn exchange<T: Message + Default>(
+        &self,
+        method: &str,
+        request: &impl Message,
+    ) -> Result<T> {
+        let body = frame(0, &request.encode_to_vec());
+        let head = format!(
+            "POST /admin.Admin/{method} HTTP/1.1\r\n\
+             Host: {}\r\n\
+             Authorization: Bearer {}\r\n\
+             Content-Type: application/grpc-web+proto\r\n\
+             Content-Length: {}\r\n\
+             Connection: close\r\n\r\n",
+            self.authority,
+            self.token,
+            body.len()
+        );
+
+        let mut stream = TcpStream::connect(&self.authority)
+            .await
+            .with_context(|| format!("Cannot connect to {}", self.authority))?;
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(&body).await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+
+        let Some(split) = response.windows(4).position(|window| window == b"\r\n\r\n") else {
+            bail!("Malformed HTTP response");
+        };
+        let head = String::from_utf8_lossy(&response[..split]);
+        let body = &response[split + 4..];
+        let mut lines = head.split("\r\n");
+        let status = lines
+            .next()
+            .and_then(|line| line.split(' ').nth(1))
+            .and_then(|code| code.parse::<u16>().ok())
+            .context("Malformed HTTP status line")?;
+        let headers: Vec<(String, &str)> = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.to_ascii_lowercase(), value.trim()))
+            .collect();
+        let header = |name: &str| {
+            headers
+                .iter()
+                .find(|(header, _)| header == name)
+                .map(|(_, value)| *value)
+        };
+        if status != 200 {
+            bail!(
+                "The server answered {status}: {}",
+                String::from_utf8_lossy(body).trim()
+            );
+        }
+        if header("transfer-encoding").is_some_and(|value| value.contains("chunked")) {
+            bail!("Chunked responses are not supported");
+        }
+
+        // Errors come as trailers-only responses, with the status in the headers.
+        if let Some(code) = header("grpc-status") {
+            check_status(code, header("grpc-message"))?;
+        }
+
+        let mut message = None;
+        let mut rest = body;
+        while rest.len() >= 5 {
+            let flag = rest[0];
+            let length = u32::from_be_bytes(rest[1..5].try_into().unwrap()) as usize;
+            let Some(payload) = rest.get(5..5 + length) else {
+                bail!("Truncated gRPC-Web frame");
+            };
+            rest = &rest[5 + length..];
+            if flag & TRAILER_FLAG == 0 {
+                message = Some(T::decode(payload).context("Malformed response message")?);
+                continue;
+            }
+            let trailers = String::from_utf8_lossy(payload);
+            let trailer = |name: &str| {
+                trailers
+                    .split("\r\n")
+                    .filter_map(|line| line.split_once(':'))
+                    .find(|(trailer, _)| trailer.eq_ignore_ascii_case(name))
+                    .map(|(_, value)| value.trim().to_owned())
+            };
+            if let Some(code) = trailer("grpc-status") {
+                check_status(&code, trailer("grpc-message").as_deref())?;
+            }
+        }
+        message.context("The response has no message")
+    }
+}
+
+fn check_status(code: &str, message: Option<&str>) -> Result<()> {
+    if code == "0" {
+        return Ok(());
+    }
+    bail!("{} (gRPC status {code})", message.unwrap_or("error"))
+}
+
+fn frame(flag: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(5 + payload.len());
+    frame.push(flag);
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
@@ -0,0 +1,191 @@
+//! The non-interactive subcommands, for scripts and cron jobs. They print tab-separated lines
+//! and exit with an error when the server refuses or the target does not exist.
+
+use anyhow::Context;
+use anyhow::Result;
+use anyhow::bail;
+
+use protobuf::admin;
+
+use crate::USAGE;
+use crate::client::Client;
+
+pub async fn run(client: &Client, args: &[String]) -> Result<()> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args[..] {
+        ["rooms", "list"] => {
+            for room in client.list_rooms().await? {
+                println!("{}\t{}", room.room_key, room.session_ids.len());
+            }
+        }
+        ["room", "forwarding", room_key] => {
+            let forwarding = client.room_forwarding(room_key).await?;
+            if !forwarding.found {
+                bail!("No room {room_key:?}");
+            }
+            for route in &forwarding.forwarding {
+                let route_name = match route.route() {
+                    admin::Route::Forwarded => "forwarded",
+                    admin::Route::Mixed => "mixed",
+                    admin::Route::SpeakerMuted => "speaker muted",
+                    admin::Route::ListenerLimited => "listener limited",
+                };
+                println!(
+                    "{}\t{}\t{route_name}",
+                    route.speaker_session_id, route.listener_session_id
+                );
+            }
+            for relay in &forwarding.relays {
+                println!("-\t{relay}\trelayed");
+            }
+        }
+        ["sessions", "list"] => {
+            for session in client.list_sessions().await? {
+                let drops = session.drops.unwrap_or_default();
+                println!(
+                    "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                    session.session_id,
+                    session.username,
+                    session.room_key.as_deref().unwrap_or("-"),
+                    session.bytes_sent,
+                    session.bytes_received,
+                    if session.muted { "muted" } else { "-" },
+                    if session.audio_on_streams {
+                        "streams"
+                    } else {
+                        "datagrams"
+                    },
+                    drops.rate_limited,
+                    drops.queue_full,
+                    drops.stale,
+                    drops.malformed,
+                    drops.oversized,
+                    session.country.as_deref().unwrap_or("-"),
+                    session.asn.map_or("-".to_owned(), |asn| asn.to_string())
+                );
+            }
+        }
+        ["session", "kick", id] => {
+            if !client.kick(session_id(id)?).await? {
+                bail!("No session {id}");
+            }
+        }
+        ["session", action @ ("mute" | "unmute"), id] => {
+            if !client.mute(session_id(id)?, action == "mute").await? {
+                bail!("No session {id}");
+            }
+        }
+        ["ban", "add", username, reason, ref days @ ..] => {
+            let days = match days {
+                [] => None,
+                [days] => Some(days.parse().context("The days must be a number")?),
+                _ => bail!("{USAGE}"),
+            };
+            let kicked = client.ban(username, reason, days).await?;
+            println!("{kicked}");
+        }
+        ["drain", ref rest @ ..] => {
+            let (address, timeout_secs) = match rest {
+                [] => ("", 0),
+                [address] => (*address, 0),
+                [address, secs] => (
+                    *address,
+                    secs.parse().context("The timeout must be a number")?,
+                ),
+                _ => bail!("{USAGE}"),
+            };
+            let (started, sessions, handed_over) = client.drain(address, timeout_secs).await?;
+            if !started {
+                bail!("The server is already draining");
+            }
+            println!("{sessions}\t{handed_over}");
+        }
+        ["maintenance", "cancel"] => {
+            if !client.schedule_maintenance(0, "", "", 0).await? {
+                bail!("No maintenance is scheduled");
+            }
+        }
+        ["maintenance", starts_at, reason, ref rest @ ..] => {
+            let starts_at = starts_at
+                .parse()
+                .context("The start must be in seconds since the Unix epoch")?;
+            let (address, timeout_secs) = match rest {
+                [] => ("", 0),
+                [address] => (*address, 0),
+                [address, secs] => (
+                    *address,
+                    secs.parse().context("The timeout must be a number")?,
+                ),
+                _ => bail!("{USAGE}"),
+            };
+            client
+                .schedule_maintenance(starts_at, reason, address, timeout_secs)
+                .await?;
+        }
+        ["announce", text, ref audio @ ..] => {
+            let audio = match audio {
+                [] => "",
+                [audio] => audio,
+                _ => bail!("{USAGE}"),
+            };
+            let (sessions, rooms) = client.announce(text, audio).await?;
+            println!("{sessions}\t{rooms}");
+        }
+        [
+            "schedule",
+            action @ ("add" | "record"),
+            room_key,
+            title,
+            starts_at,
+            duration_secs,
+            ref participants @ ..,
+        ] => {
+            let room = client
+                .schedule_room(admin::ScheduledRoom {
+                    id: String::new(),
+                    room_key: room_key.to_owned(),
+                    title: title.to_owned(),
+                    starts_at: starts_at
+                        .parse()
+                        .context("The start must be in seconds since the Unix epoch")?,
+                    duration_secs: duration_secs
+                        .parse()
+                        .context("The duration must be a number of seconds")?,
+                    participants: participants.iter().map(|name| (*name).to_owned()).collect(),
+                    record: action == "record",
+                })
+                .await?;
+            println!("{}", room.id);
+        }
+        ["schedule", "cancel", id] => {
+            if !client.cancel_scheduled_room(id).await? {
+                bail!("No scheduled room {id}");
+            }
+        }
+        ["schedule", "list"] => {
+            for room in client.list_scheduled_rooms().await? {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                    room.id,
+                    room.room_key,
+                    room.title,
+                    room.starts_at,
+                    room.duration_secs,
+                    if room.record { "recorded" } else { "-" },
+                    if room.participants.is_empty() {
+                        "-".to_owned()
+                    } else {
+                        room.participants.join(",")
+                    }
+                );
+            }
+        }
+        _ => bail!("{USAGE}"),
+    }
+    Ok(())
+}
+
+fn session_id(id: &str) -> Result<i64> {
+    id.parse()
+        .with_context(|| format!("{id:?} is not a session ID"))
+}
@@ -0,0 +1,481 @@
+//! The live dashboard: the server's rooms and sessions, refreshed every second, each session's
+//! bandwidth and the server's total as graphs, and keys to kick, mute and ban the selected
+//! session.
+
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::io::Write as _;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::Result;
+use protobuf::admin;
+use tokio::signal::unix::SignalKind;
+use tokio::signal::unix::signal;
+
+use crate::client::Client;
+use crate::terminal;
+use crate::terminal::Key;
+use crate::terminal::Terminal;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many seconds of bandwidth each graph keeps.
+const HISTORY: usize = 240;
+
+/// How many rows the total bandwidth graph takes.
+const GRAPH_ROWS: usize = 4;
+
+const BLOCKS: [char; 9] = [' ', '▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+const BOLD: &str = "\x1b[1m";
+const DIM: &str = "\x1b[2m";
+const REVERSE: &str = "\x1b[7m";
+const RESET: &str = "\x1b[0m";
+
+/// Runs the dashboard until the operator quits.
+pub async fn run(client: Client, server: String) -> Result<()> {
+    let terminal = Terminal::enter()?;
+    let mut keys = terminal::keys();
+    let mut resizes = signal(SignalKind::window_change())?;
+    let mut polls = tokio::time::interval(POLL_INTERVAL);
+    let mut dashboard = Dashboard::new(client, server);
+    loop {
+        tokio::select! {
+            _ = polls.tick() => dashboard.poll().await,
+            _ = resizes.recv() => {}
+            key = keys.recv() => {
+                let Some(key) = key else {
+                    break;
+                };
+                if !dashboard.press(key).await {
+                    break;
+                }
+            }
+        }
+        dashboard.draw(&terminal)?;
+    }
+    Ok(())
+}
+
+struct Dashboard {
+    client: Client,
+    server: String,
+    rooms: Vec<admin::Room>,
+    /// Sorted by room, then ID.
+    sessions: Vec<admin::Session>,
+    traffic: HashMap<i64, Traffic>,
+    /// The bandwidth of all sessions together, in bits per second, oldest first.
+    total: VecDeque<u64>,
+    selected: Option<i64>,
+    /// The reason being typed for banning the selected session's user.
+    ban_reason: Option<String>,
+    /// The outcome of the last action, or the last error.
+    status: String,
+}
+
+/// A session's bandwidth, in bits per second, oldest first.
+#[derive(Default)]
+struct Traffic {
+    /// The bytes sent and received, and when the server last reported a change.
+    bytes: Option<((u64, u64), Instant)>,
+    sent: VecDeque<u64>,
+    received: VecDeque<u64>,
+}
+
+impl Traffic {
+    fn total(&self, index: usize) -> u64 {
+        self.sent.get(index).copied().unwrap_or(0) + self.received.get(index).copied().unwrap_or(0)
+    }
+}
+
+impl Dashboard {
+    fn new(client: Client, server: String) -> Self {
+        Self {
+            client,
+            server,
+            rooms: Vec::new(),
+            sessions: Vec::new(),
+            traffic: HashMap::new(),
+            total: VecDeque::new(),
+            selected: None,
+            ban_reason: None,
+            status: String::new(),
+        }
+    }
+
+    /// Fetches the rooms and sessions, and works out their bandwidth since the last poll.
+    async fn poll(&mut self) {
+        let (rooms, sessions) =
+            match tokio::try_join!(self.client.list_rooms(), self.client.list_sessions()) {
+                Ok(lists) => lists,
+                Err(err) => {
+                    self.status = format!("{err:#}");
+                    return;
+                }
+            };
+        let now = Instant::now();
+
+        self.rooms = rooms;
+        self.rooms.sort_by(|a, b| a.room_key.cmp(&b.room_key));
+        self.sessions = sessions;
+        self.sessions
+            .sort_by(|a, b| (&a.room_key, a.session_id).cmp(&(&b.room_key, b.session_id)));
+        self.traffic.retain(|id, _| {
+            self.sessions
+                .iter()
+                .any(|session| session.session_id == *id)
+        });
+
+        for session in &self.sessions {
+            let traffic = self.traffic.entry(session.session_id).or_default();
+            let bytes = (session.bytes_sent, session.bytes_received);
+            match traffic.bytes {
+                // The server updates the counts on a clock of its own, so unchanged counts
+                // are counted with the next poll instead of as a second of silence.
+                Some((last, _)) if last == bytes => continue,
+                Some(((bytes_sent, bytes_received), at)) => {
+                    let elapsed = now.duration_since(at).as_secs_f64();
+                    let rate = |now: u64, last: u64| {
+                        (now.saturating_sub(last) as f64 * 8.0 / elapsed) as u64
+                    };
+                    push(&mut traffic.sent, rate(session.bytes_sent, bytes_sent));
+                    push(
+                        &mut traffic.received,
+                        rate(session.bytes_received, bytes_received),
+                    );
+                }
+                None => {}
+            }
+            traffic.bytes = Some((bytes, now));
+        }
+        let total = self
+            .traffic
+            .values()
+            .map(|traffic| traffic.total(traffic.sent.len().saturating_sub(1)))
+            .sum();
+        push(&mut self.total, total);
+
+        let selected = self.selected;
+        if !self
+            .sessions
+            .iter()
+            .any(|session| Some(session.session_id) == selected)
+        {
+            self.selected = self.sessions.first().map(|session| session.session_id);
+        }
+    }
+
+    /// Handles a key, returning whether the dashboard keeps running.
+    async fn press(&mut self, key: Key) -> bool {
+        if let Some(reason) = &mut self.ban_reason {
+            match key {
+                Key::Char(c) => reason.push(c),
+                Key::Backspace => {
+                    reason.pop();
+                }
+                Key::Escape => self.ban_reason = None,
+                Key::Enter => {
+                    let reason = self.ban_reason.take().unwrap_or_default();
+                    self.ban(reason).await;
+                }
+                Key::Interrupt => return false,
+                Key::Up | Key::Down => {}
+            }
+            return true;
+        }
+
+        match key {
+            Key::Char('q') | Key::Escape | Key::Interrupt => return false,
+            Key::Up => self.select(-1),
+            Key::Down => self.select(1),
+            Key::Char('k') => self.kick().await,
+            Key::Char('m') => self.toggle_mute().await,
+            Key::Char('b') if self.selected_session().is_some() => {
+                self.ban_reason = Some(String::new());
+            }
+            _ => {}
+        }
+        true
+    }
+
+    fn select(&mut self, step: isize) {
+        let Some(index) = self.selected_index() else {
+            return;
+        };
+        let index = index
+            .saturating_add_signed(step)
+            .min(self.sessions.len() - 1);
+        self.selected = Some(self.sessions[index].session_id);
+    }
+
+    fn selected_index(&self) -> Option<usize> {
+        self.sessions
+            .iter()
+            .position(|session| Some(session.session_id) == self.selected)
+    }
+
+    fn selected_session(&self) -> Option<&admin::Session> {
+        self.selected_index().map(|index| &self.sessions[index])
+    }
+
+    async fn kick(&mut self) {
+        let Some(session) = self.selected_session() else {
+            return;
+        };
+        let (id, username) = (session.session_id, session.username.clone());
+        self.status = match self.client.kick(id).await {
+            Ok(true) => format!("Kicked {username} (session {id})"),
+            Ok(false) => format!("Session {id} has already left"),
+            Err(err) => format!("{err:#}"),
+        };
+        self.poll().await;
+    }
+
+    async fn toggle_mute(&mut self) {
+        let Some(session) = self.selected_session() else {
+            return;
+        };
+        let (id, username, muted) = (session.session_id, session.username.clone(), !session.muted);
+        self.status = match self.client.mute(id, muted).await {
+            Ok(true) if muted => format!("Muted {username} (session {id})"),
+            Ok(true) => format!("Unmuted {username} (session {id})"),
+            Ok(false) => format!("Session {id} has already left"),
+            Err(err) => format!("{err:#}"),
+        };
+        self.poll().await;
+    }
+
+    async fn ban(&mut self, reason: String) {
+        let Some(session) = self.selected_session() else {
+            return;
+        };
+        let username = session.username.clone();
+        let reason = match reason.trim() {
+            "" => "Banned by an operator".to_owned(),
+            reason => reason.to_owned(),
+        };
+        self.status = match self.client.ban(&username, &reason, None).await {
+            Ok(kicked) => format!("Banned {username}, disconnecting {kicked} session(s)"),
+            Err(err) => format!("{err:#}"),
+        };
+        self.poll().await;
+    }
+
+    fn draw(&self, terminal: &Terminal) -> Result<()> {
+        let (width, height) = terminal.size();
+        let mut lines = Vec::new();
+
+        let sent: u64 = self.traffic.values().filter_map(|t| t.sent.back()).sum();
+        let received: u64 = self
+            .traffic
+            .values()
+            .filter_map(|t| t.received.back())
+            .sum();
+        let summary = format!(
+            "{} rooms · {} sessions · sent {} · received {}",
+            self.rooms.len(),
+            self.sessions.len(),
+            rate(sent),
+            rate(received)
+        );
+        let title = format!(" voicectl  {}", self.server);
+        let gap = width
+            .saturating_sub(title.chars().count() + summary.chars().count() + 1)
+            .max(2);
+        lines.push(styled(
+            BOLD,
+            &fit(&format!("{title}{}{summary}", " ".repeat(gap)), width),
+        ));
+        lines.push(String::new());
+
+        // The rooms take at most a fifth of the screen.
+        let room_rows = self.rooms.len().clamp(1, (height / 5).max(1));
+        lines.push(styled(BOLD, &fit(" ROOMS", width)));
+        if self.rooms.is_empty() {
+            lines.push(styled(DIM, &fit("   No rooms", width)));
+        }
+        for (index, room) in self.rooms.iter().take(room_rows).enumerate() {
+            let line = if index + 1 == room_rows && self.rooms.len() > room_rows {
+                format!("   … and {} more", self.rooms.len() - index)
+            } else {
+                let count = room.session_ids.len();
+                let noun = if count == 1 {
+                    "participant"
+                } else {
+                    "participants"
+                };
+                format!("   {:<32} {count} {noun}", room.room_key)
+            };
+            lines.push(fit(&line, width));
+        }
+        lines.push(String::new());
+
+        // The sessions take what the rest leaves.
+        let fixed = lines.len() + 3 + GRAPH_ROWS + 1;
+        let session_rows = height.saturating_sub(fixed).max(1);
+        let sessions_end = lines.len() + 1 + session_rows;
+        let columns = format!(
+            " {:<19} {:<16} {:<16} {:>4} {:>7} {:>12} {:>12} {:<5} ",
+            "SESSION", "USER", "ROOM", "QOE", "RTT", "SENT", "RECEIVED", "MUTE"
+        );
+        let graph_width = width.saturating_sub(columns.chars().count() + 1);
+        lines.push(styled(BOLD, &fit(&format!("{columns}TRAFFIC"), width)));
+        if self.sessions.is_empty() {
+            lines.push(styled(DIM, &fit("   No sessions", width)));
+        }
+        let peak = self
+            .traffic
+            .values()
+            .flat_map(|traffic| (0..traffic.sent.len()).map(|index| traffic.total(index)))
+            .max()
+            .unwrap_or(0);
+        let selected = self.selected_index().unwrap_or(0);
+        let first = selected.saturating_sub(session_rows.saturating_sub(1));
+        for session in self.sessions.iter().skip(first).take(session_rows) {
+            let traffic = self.traffic.get(&session.session_id);
+            let history: Vec<u64> = traffic
+                .map(|traffic| (0..traffic.sent.len()).map(|i| traffic.total(i)).collect())
+                .unwrap_or_default();
+            let line = format!(
+                " {:<19} {:<16} {:<16} {:>4} {:>7} {:>12} {:>12} {:<5} {}",
+                session.session_id,
+                clip(&session.username, 16),
+                clip(session.room_key.as_deref().unwrap_or("-"), 16),
+                session
+                    .qoe_score
+                    .map_or("-".to_owned(), |score| format!("{score:.1}")),
+                session
+                    .rtt_us
+                    .map_or("-".to_owned(), |rtt| format!("{} ms", rtt / 1000)),
+                rate(traffic.and_then(|t| t.sent.back().copied()).unwrap_or(0)),
+                rate(
+                    traffic
+                        .and_then(|t| t.received.back().copied())
+                        .unwrap_or(0)
+                ),
+                if session.muted { "yes" } else { "" },
+                sparkline(&history, peak, graph_width),
+            );
+            let line = fit(&line, width);
+            if Some(session.session_id) == self.selected {
+                lines.push(styled(REVERSE, &line));
+            } else {
+                lines.push(line);
+            }
+        }
+        while lines.len() < sessions_end {
+            lines.push(String::new());
+        }
+        lines.push(String::new());
+
+        let peak = self.total.iter().copied().max().unwrap_or(0);
+        lines.push(styled(
+            BOLD,
+            &fit(&format!(" TOTAL TRAFFIC  peak {}", rate(peak)), width),
+        ));
+        for row in graph(&self.total, peak, width.saturating_sub(2), GRAPH_ROWS) {
+            lines.push(format!(" {row}"));
+        }
+
+        let footer = match &self.ban_reason {
+            Some(reason) => {
+                let username = self.selected_session().map_or("", |s| s.username.as_str());
+                format!(" Ban {username} for: {reason}█   (Enter to ban, Esc to cancel)")
+            }
+            None if !self.status.is_empty() => format!(" {}", self.status),
+            None => " ↑/↓ select · k kick · m mute · b ban · q quit".to_owned(),
+        };
+        lines.truncate(height.saturating_sub(1));
+        while lines.len() < height.saturating_sub(1) {
+            lines.push(String::new());
+        }
+        lines.push(styled(REVERSE, &fit(&footer, width)));
+
+        let mut screen = String::new();
+        for (row, line) in lines.iter().enumerate() {
+            write!(screen, "\x1b[{};1H{line}\x1b[K", row + 1)?;
+        }
+        screen.push_str("\x1b[J");
+        let mut stdout = std::io::stdout().lock();
+        stdout.write_all(screen.as_bytes())?;
+        stdout.flush()?;
+        Ok(())
+    }
+}
+
+/// Appends a second's bandwidth to a history, dropping the oldest past `HISTORY`.
+fn push(history: &mut VecDeque<u64>, value: u64) {
+    if history.len() == HISTORY {
+        history.pop_front();
+    }
+    history.push_back(value);
+}
+
+/// A bandwidth such as "64 kbit/s".
+fn rate(bits_per_sec: u64) -> String {
+    match bits_per_sec {
+        0..1_000 => format!("{bits_per_sec} bit/s"),
+        1_000..1_000_000 => format!("{:.0} kbit/s", bits_per_sec as f64 / 1e3),
+        _ => format!("{:.1} Mbit/s", bits_per_sec as f64 / 1e6),
+    }
+}
+
+/// The last `width` values of a history as one row of blocks, scaled to `peak`.
+fn sparkline(history: &[u64], peak: u64, width: usize) -> String {
+    let start = history.len().saturating_sub(width);
+    history[start..]
+        .iter()
+        .map(|&value| BLOCKS[level(value, peak, 8)])
+        .collect()
+}
+
+/// The last `width` values of a history as a chart `rows` high, scaled to `peak`, top row
+/// first.
+fn graph(history: &VecDeque<u64>, peak: u64, width: usize, rows: usize) -> Vec<String> {
+    let start = history.len().saturating_sub(width);
+    let levels: Vec<usize> = history
+        .range(start..)
+        .map(|&value| level(value, peak, rows * 8))
+        .collect();
+    (0..rows)
+        .rev()
+        .map(|row| {
+            let mut line = " ".repeat(width - levels.len());
+            line.extend(
+                levels
+                    .iter()
+                    .map(|level| BLOCKS[level.saturating_sub(row * 8).min(8)]),
+            );
+            line
+        })
+        .collect()
+}
+
+/// How many of `levels` eighths of a block `value` fills, rounding up so any traffic shows.
+fn level(value: u64, peak: u64, levels: usize) -> usize {
+    if peak == 0 {
+        return 0;
+    }
+    (value * levels as u64).div_ceil(peak) as usize
+}
+
+/// Cuts a line to `width` characters.
+fn fit(line: &str, width: usize) -> String {
+    line.chars().take(width).collect()
+}
+
+/// Cuts a field to `width` characters, marking that it was cut.
+fn clip(field: &str, width: usize) -> String {
+    if field.chars().count() <= width {
+        return field.to_owned();
+    }
+    let mut field: String = field.chars().take(width - 1).collect();
+    field.push('…');
+    field
+}
+
+fn styled(style: &str, line: &str) -> String {
+    format!("{style}{line}{RESET}")
+}
@@ -0,0 +1,84 @@
+//! `voicectl`: a terminal dashboard for the voice chat server, through its admin API, and
+//! subcommands for scripting it.
+//!
+//! ```text
+//! voicectl [--server <url>] [--token <token>] [<command>]
+//! ```
+//!
+//! Without a command, it runs the dashboard.
+//!
+//! The server defaults to `http://127.0.0.1:8080`, or `VOICECTL_SERVER`. The token is the
+//! server's `admin.token` or an API key, from `--token` or `VOICECTL_TOKEN`.
+
+use anyhow::Context;
+use anyhow::Result;
+use anyhow::bail;
+
+use client::Client;
+
+mod client;
+mod commands;
+mod dashboard;
+mod terminal;
+
+const USAGE: &str = "\
+usage: voicectl [--server <url>] [--token <token>] [<command>]
+
+commands:
+  rooms list                               room key, participants
+  room forwarding <room key>               speaker ID, listener ID or relay, route
+  sessions list                            ID, user, room, bytes sent, bytes received, muted, audio
+                                           carrier, frames dropped over the bitrate cap, for a full
+                                           queue and stale, malformed and oversized packets,
+                                           country, ASN
+  session kick <id>
+  session mute <id>
+  session unmute <id>
+  ban add <username> <reason> [<days>]     prints how many sessions were kicked
+  drain [<address> [<timeout secs>]]       sessions left, sessions handed over
+  maintenance <starts at> <reason> [<address> [<timeout secs>]]
+                                           drains the server then, counting down on clients;
+                                           starts at is in seconds since the Unix epoch
+  maintenance cancel
+  announce <text> [<audio file>]           sessions sent to, rooms the audio plays in; the
+                                           audio file is a WAV file in announcements.audio_dir
+  schedule add <room key> <title> <starts at> <duration secs> [<participant>...]
+                                           prints the scheduled room's ID; starts at is in
+                                           seconds since the Unix epoch
+  schedule record <room key> <title> <starts at> <duration secs> [<participant>...]
+                                           the same, recording the room while it is on
+  schedule cancel <id>
+  schedule list                            ID, room key, title, starts at, duration secs,
+                                           recorded, participants
+
+Without a command, voicectl runs the dashboard.";
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let mut server = std::env::var("VOICECTL_SERVER").ok();
+    let mut token = std::env::var("VOICECTL_TOKEN").ok();
+    let mut command = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--server" => server = Some(args.next().context(USAGE)?),
+            "--token" => token = Some(args.next().context(USAGE)?),
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return Ok(());
+            }
+            _ if arg.starts_with("--") => bail!("Unknown option {arg:?}\n{USAGE}"),
+            _ => command.push(arg),
+        }
+    }
+    let server = server.unwrap_or_else(|| "http://127.0.0.1:8080".to_owned());
+    let Some(token) = token else {
+        bail!("voicectl needs an admin token, from --token or VOICECTL_TOKEN");
+    };
+
+    let client = Client::new(&server, token)?;
+    if !command.is_empty() {
+        return commands::run(&client, &command).await;
+    }
+    dashboard::run(client, server).await
+}
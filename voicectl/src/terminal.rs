@@ -0,0 +1,130 @@
+//! The terminal the dashboard draws on: raw mode and the alternate screen while it runs, its
+//! size, and the keys pressed.
+
+use std::io::Read;
+use std::io::Write;
+
+use anyhow::Result;
+use anyhow::bail;
+use tokio::sync::mpsc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Up,
+    Down,
+    Enter,
+    Escape,
+    Backspace,
+    /// Ctrl-C, since raw mode keeps it from raising SIGINT.
+    Interrupt,
+    Char(char),
+}
+
+/// Puts the terminal in raw mode on the alternate screen, and restores it when dropped.
+pub struct Terminal {
+    original: libc::termios,
+}
+
+impl Terminal {
+    pub fn enter() -> Result<Self> {
+        // SAFETY: `termios` is plain data, filled in by `tcgetattr` before it is read.
+        let mut original: libc::termios = unsafe { std::mem::zeroed() };
+        // SAFETY: `original` is a valid `termios` to write to.
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut original) } != 0 {
+            bail!("voicectl needs a terminal");
+        }
+        let mut raw = original;
+        // SAFETY: `raw` is a valid `termios`, and stdin is a terminal since `tcgetattr` worked.
+        unsafe {
+            libc::cfmakeraw(&mut raw);
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw);
+        }
+        // The alternate screen, with the cursor hidden.
+        print!("\x1b[?1049h\x1b[?25l");
+        std::io::stdout().flush()?;
+        Ok(Self { original })
+    }
+
+    /// The terminal's size in columns and rows.
+    pub fn size(&self) -> (usize, usize) {
+        // SAFETY: `winsize` is plain data, filled in by the ioctl.
+        let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+        // SAFETY: TIOCGWINSZ writes a `winsize` to the pointer it is given.
+        if unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } != 0
+            || size.ws_col == 0
+        {
+            return (80, 24);
+        }
+        (size.ws_col as usize, size.ws_row as usize)
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        print!("\x1b[?25h\x1b[?1049l");
+        let _ = std::io::stdout().flush();
+        // SAFETY: `original` is the settings `tcgetattr` returned for stdin.
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original);
+        }
+    }
+}
+
+/// Reads keys from stdin on a thread of their own, for as long as the receiver is kept.
+pub fn keys() -> mpsc::UnboundedReceiver<Key> {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        let mut stdin = std::io::stdin().lock();
+        let mut buffer = [0; 64];
+        loop {
+            let length = match stdin.read(&mut buffer) {
+                Ok(0) | Err(_) => return,
+                Ok(length) => length,
+            };
+            for key in parse(&buffer[..length]) {
+                if sender.send(key).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    receiver
+}
+
+/// The keys in one read from the terminal, which holds a whole escape sequence if any.
+fn parse(bytes: &[u8]) -> Vec<Key> {
+    let mut keys = Vec::new();
+    let mut rest = bytes;
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+        let key = match byte {
+            0x1b => match rest {
+                [b'[' | b'O', b'A', tail @ ..] => {
+                    rest = tail;
+                    Key::Up
+                }
+                [b'[' | b'O', b'B', tail @ ..] => {
+                    rest = tail;
+                    Key::Down
+                }
+                // Other sequences are skipped up to their final byte.
+                [b'[', tail @ ..] => {
+                    let end = tail
+                        .iter()
+                        .position(|byte| (0x40..=0x7e).contains(byte))
+                        .map_or(tail.len(), |end| end + 1);
+                    rest = &tail[end..];
+                    continue;
+                }
+                _ => Key::Escape,
+            },
+            b'\r' | b'\n' => Key::Enter,
+            0x7f | 0x08 => Key::Backspace,
+            0x03 => Key::Interrupt,
+            byte if byte.is_ascii() && !byte.is_ascii_control() => Key::Char(byte as char),
+            _ => continue,
+        };
+        keys.push(key);
+    }
+    keys
+}
@@ -0,0 +1,102 @@
+//! A simulated user: it logs in, joins a room, then talks continuously and reads what the room
+//! sends back, as a browser client would.
+
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use anyhow::Result;
+use protobuf::system::Feature;
+use voice_client_core::Client;
+use voice_client_core::Endpoint;
+use voice_client_core::Event;
+use voice_client_core::Options;
+use voice_client_core::ServerPacket;
+
+/// How often a voice frame is sent, as an Opus client would with 20 ms frames.
+const FRAME_INTERVAL: Duration = Duration::from_millis(20);
+
+/// The size of the frames sent, about that of an Opus frame at 64 kbit/s.
+const FRAME_LEN: usize = 160;
+
+/// What every bot has done, for the reports.
+#[derive(Default)]
+pub struct Stats {
+    pub connected: AtomicUsize,
+    pub logins: AtomicU64,
+    pub failures: AtomicU64,
+    pub frames_sent: AtomicU64,
+    pub frames_received: AtomicU64,
+
+    /// Bots the server sends fewer speakers, because they fell behind.
+    pub limited: AtomicUsize,
+}
+
+/// A bot in a room.
+pub struct Bot {
+    client: Client,
+}
+
+impl Bot {
+    /// Connects to `endpoint`, logs in as `username` and joins `room_key`.
+    pub async fn join(
+        endpoint: &Endpoint,
+        username: &str,
+        token: &str,
+        room_key: &str,
+    ) -> Result<Self> {
+        let mut options = Options::new(username, room_key);
+        options.login.password = token.to_owned();
+        options.features = Feature::VoiceDatagrams as u32 | Feature::ForwardingLimits as u32;
+        let client = Client::connect(endpoint, &options).await?;
+        Ok(Self { client })
+    }
+
+    /// Talks until `stop` resolves or the server closes the connection, then leaves.
+    pub async fn talk(mut self, stats: &Stats, stop: impl Future<Output = ()>) -> Result<()> {
+        let frame = [0; FRAME_LEN];
+        let mut ticks = tokio::time::interval(FRAME_INTERVAL);
+        tokio::pin!(stop);
+        let mut limited = false;
+        let result = loop {
+            let event = tokio::select! {
+                _ = &mut stop => break Ok(()),
+                _ = ticks.tick() => None,
+                event = self.client.next_event() => Some(event),
+            };
+            let event = match event {
+                None => {
+                    if let Err(error) = self.client.send_voice(&frame) {
+                        break Err(error);
+                    }
+                    stats.frames_sent.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+                Some(Ok(event)) => event,
+                Some(Err(error)) => break Err(error),
+            };
+            match event {
+                Event::Voice(_) => {
+                    stats.frames_received.fetch_add(1, Ordering::Relaxed);
+                }
+                Event::Packet(ServerPacket::ForwardingLimit(limit)) => {
+                    if limited != (limit.max_speakers > 0) {
+                        limited = !limited;
+                        if limited {
+                            stats.limited.fetch_add(1, Ordering::Relaxed);
+                        } else {
+                            stats.limited.fetch_sub(1, Ordering::Relaxed);
+                        }
+                    }
+                }
+                Event::Packet(_) => {}
+            }
+        };
+        if limited {
+            stats.limited.fetch_sub(1, Ordering::Relaxed);
+        }
+        self.client.close();
+        result
+    }
+}
@@ -0,0 +1,119 @@
+//! A crowd of bots spread over rooms, each reconnecting when it fails or churns.
+
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use voice_client_core::Endpoint;
+
+use crate::bot::Bot;
+use crate::bot::Stats;
+
+/// How long apart the bots first connect, so the server is not hit by every handshake at once.
+const RAMP_INTERVAL: Duration = Duration::from_millis(20);
+
+/// How long a bot waits before reconnecting after a failure.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+pub struct Options {
+    pub clients: usize,
+    pub rooms: usize,
+
+    /// The password bots log in with, for servers with accounts.
+    pub token: String,
+
+    /// How long each bot stays before leaving and joining again, if it ever leaves.
+    pub churn: Option<Duration>,
+}
+
+pub struct Crowd {
+    stop: watch::Sender<bool>,
+    bots: JoinSet<()>,
+}
+
+impl Crowd {
+    /// Starts the bots `voiceload-0` to `voiceload-<clients - 1>`, in rooms `voiceload-0` to
+    /// `voiceload-<rooms - 1>`.
+    pub fn start(endpoint: Arc<Endpoint>, options: &Options, stats: Arc<Stats>) -> Self {
+        let (stop, stopped) = watch::channel(false);
+        let mut bots = JoinSet::new();
+        for i in 0..options.clients {
+            let endpoint = endpoint.clone();
+            let stats = stats.clone();
+            let mut stopped = stopped.clone();
+            let username = format!("voiceload-{i}");
+            let room_key = format!("voiceload-{}", i % options.rooms);
+            let token = options.token.clone();
+            // The first stay is spread over the churn period, so bots do not all leave at once.
+            let mut stay = options
+                .churn
+                .map(|churn| churn.mul_f64((i + 1) as f64 / options.clients as f64));
+            let churn = options.churn;
+            bots.spawn(async move {
+                let ramp = tokio::time::sleep(RAMP_INTERVAL * i as u32);
+                if wait_or_stop(ramp, &mut stopped).await {
+                    return;
+                }
+                loop {
+                    let bot = tokio::select! {
+                        bot = Bot::join(&endpoint, &username, &token, &room_key) => bot,
+                        _ = stopped.wait_for(|&stop| stop) => return,
+                    };
+                    let bot = match bot {
+                        Ok(bot) => bot,
+                        Err(error) => {
+                            stats.failures.fetch_add(1, Ordering::Relaxed);
+                            eprintln!("{username}: {error:#}");
+                            let retry = tokio::time::sleep(RETRY_DELAY);
+                            if wait_or_stop(retry, &mut stopped).await {
+                                return;
+                            }
+                            continue;
+                        }
+                    };
+                    stats.logins.fetch_add(1, Ordering::Relaxed);
+                    stats.connected.fetch_add(1, Ordering::Relaxed);
+                    let mut leave_stopped = stopped.clone();
+                    let leave = async {
+                        match stay {
+                            Some(stay) => {
+                                let _ = wait_or_stop(tokio::time::sleep(stay), &mut leave_stopped)
+                                    .await;
+                            }
+                            None => {
+                                let _ = leave_stopped.wait_for(|&stop| stop).await;
+                            }
+                        }
+                    };
+                    let result = bot.talk(&stats, leave).await;
+                    stats.connected.fetch_sub(1, Ordering::Relaxed);
+                    if let Err(error) = result {
+                        stats.failures.fetch_add(1, Ordering::Relaxed);
+                        eprintln!("{username}: {error:#}");
+                    }
+                    if *stopped.borrow() {
+                        return;
+                    }
+                    stay = churn;
+                }
+            });
+        }
+        Self { stop, bots }
+    }
+
+    /// Makes every bot leave, and waits until they have.
+    pub async fn stop(mut self) {
+        let _ = self.stop.send(true);
+        while self.bots.join_next().await.is_some() {}
+    }
+}
+
+/// Waits for `sleep`, returning `true` if the crowd was stopped first.
+async fn wait_or_stop(sleep: tokio::time::Sleep, stopped: &mut watch::Receiver<bool>) -> bool {
+    tokio::select! {
+        _ = sleep => false,
+        _ = stopped.wait_for(|&stop| stop) => true,
+    }
+}
@@ -0,0 +1,18 @@
+//! The metrics the load tool reads from the server's `/metrics`.
+
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use voice_client_core::Server;
+
+/// The samples of `/metrics`, keyed by name and labels as written, such as
+/// `voice_registry_entries{map="rooms"}`.
+pub async fn metrics(server: &Server) -> Result<BTreeMap<String, f64>> {
+    let body = server.get("/metrics").await?;
+    Ok(body
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| line.rsplit_once(' '))
+        .filter_map(|(key, value)| Some((key.to_owned(), value.parse().ok()?)))
+        .collect())
+}
@@ -0,0 +1,142 @@
+//! `voiceload`: a load generator for the voice chat server. It connects a crowd of bots that
+//! log in, join rooms and talk, and reports what they send and receive.
+//!
+//! ```text
+//! voiceload [<options>] [soak]
+//! ```
+//!
+//! With `soak`, it instead runs the crowd in rounds and fails when the server does not clean up
+//! after it (see [`soak`]).
+
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use anyhow::Context;
+use anyhow::Result;
+use anyhow::bail;
+use voice_client_core::Server;
+
+use bot::Stats;
+use crowd::Crowd;
+use crowd::Options;
+use soak::SoakOptions;
+
+mod bot;
+mod crowd;
+mod http;
+mod soak;
+
+const USAGE: &str = "\
+usage: voiceload [<options>] [soak]
+
+options:
+  --server <url>      the server's HTTP address, http://127.0.0.1:8080 by default
+  --clients <n>       how many bots to connect, 20 by default
+  --rooms <n>         how many rooms to spread them over, 4 by default
+  --token <password>  the password bots log in with, for servers with accounts
+  --churn <secs>      how long each bot stays before leaving and joining again
+  --seconds <secs>    how long to run, until interrupted by default
+
+soak options:
+  --hold <secs>       how long the crowd stays connected each round, 30 by default
+  --settle <secs>     how long the server has to clean up after it, 10 by default
+  --rounds <n>        how many rounds to run, until a leak is found by default";
+
+/// How often the load is reported.
+const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let mut server = "http://127.0.0.1:8080".to_owned();
+    let mut options = Options {
+        clients: 20,
+        rooms: 4,
+        token: String::new(),
+        churn: None,
+    };
+    let mut seconds = None;
+    let mut soak = false;
+    let mut soak_options = SoakOptions {
+        hold: Duration::from_secs(30),
+        settle: Duration::from_secs(10),
+        rounds: None,
+    };
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--server" => server = args.next().context(USAGE)?,
+            "--clients" => options.clients = number(&arg, args.next())? as usize,
+            "--rooms" => options.rooms = number(&arg, args.next())? as usize,
+            "--token" => options.token = args.next().context(USAGE)?,
+            "--churn" => options.churn = Some(Duration::from_secs(number(&arg, args.next())?)),
+            "--seconds" => seconds = Some(Duration::from_secs(number(&arg, args.next())?)),
+            "--hold" => soak_options.hold = Duration::from_secs(number(&arg, args.next())?),
+            "--settle" => soak_options.settle = Duration::from_secs(number(&arg, args.next())?),
+            "--rounds" => soak_options.rounds = Some(number(&arg, args.next())? as u32),
+            "soak" => soak = true,
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return Ok(());
+            }
+            _ => bail!("Unknown argument {arg:?}\n{USAGE}"),
+        }
+    }
+    if options.clients == 0 || options.rooms == 0 {
+        bail!("--clients and --rooms must be at least 1");
+    }
+
+    let server = Server::new(&server)?;
+    let endpoint = Arc::new(server.endpoint().await?);
+    if soak {
+        return tokio::select! {
+            result = soak::run(&server, endpoint, &options, &soak_options) => result,
+            _ = tokio::signal::ctrl_c() => Ok(()),
+        };
+    }
+
+    let stats = Arc::new(Stats::default());
+    let crowd = Crowd::start(endpoint, &options, stats.clone());
+    let end = async {
+        match seconds {
+            Some(seconds) => tokio::time::sleep(seconds).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(end);
+    let mut reports = tokio::time::interval(REPORT_INTERVAL);
+    reports.tick().await;
+    let (mut sent, mut received) = (0, 0);
+    loop {
+        tokio::select! {
+            _ = reports.tick() => {}
+            _ = &mut end => break,
+            _ = tokio::signal::ctrl_c() => break,
+        }
+        let now_sent = stats.frames_sent.load(Ordering::Relaxed);
+        let now_received = stats.frames_received.load(Ordering::Relaxed);
+        let per_second = |frames: u64| frames as f64 / REPORT_INTERVAL.as_secs_f64();
+        println!(
+            "{}/{} connected, {} logins, {} failures, {:.0} frames/s sent, {:.0} frames/s received, \
+             {} limited",
+            stats.connected.load(Ordering::Relaxed),
+            options.clients,
+            stats.logins.load(Ordering::Relaxed),
+            stats.failures.load(Ordering::Relaxed),
+            per_second(now_sent - sent),
+            per_second(now_received - received),
+            stats.limited.load(Ordering::Relaxed),
+        );
+        (sent, received) = (now_sent, now_received);
+    }
+    crowd.stop().await;
+    Ok(())
+}
+
+fn number(option: &str, value: Option<String>) -> Result<u64> {
+    let value = value.context(USAGE)?;
+    value
+        .parse()
+        .with_context(|| format!("{option} must be a number, not {value:?}"))
+}
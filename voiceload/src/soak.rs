@@ -0,0 +1,176 @@
+//! Soak mode: rounds of connecting the crowd, holding it, and disconnecting it, checking
+//! after each round that what the server holds on to went back to where it was.
+//!
+//! With every bot gone, the registry entries, exported session metrics and borrowed read
+//! buffers must return to their values from before the first round. Tasks, idle buffers and
+//! buffered memory may settle higher as pools fill, but must not grow round after round.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use anyhow::Result;
+use anyhow::bail;
+use voice_client_core::Endpoint;
+use voice_client_core::Server;
+
+use crate::bot::Stats;
+use crate::crowd::Crowd;
+use crate::crowd::Options;
+use crate::http;
+
+/// The metrics that must return to where they started once every session is gone.
+const SETTLED: &[&str] = &[
+    "voice_registry_entries",
+    "voice_tracked_sessions",
+    "voice_read_buffers{state=\"in_use\"}",
+];
+
+/// The metrics that must not keep growing.
+const BOUNDED: &[&str] = &[
+    "voice_tasks",
+    "voice_read_buffers{state=\"idle\"}",
+    "voice_memory_bytes",
+];
+
+/// After how many rounds of growing in a row a bounded metric counts as leaking.
+const GROWTH_ROUNDS: usize = 3;
+
+pub struct SoakOptions {
+    /// How long the crowd stays connected each round.
+    pub hold: Duration,
+
+    /// How long the server has to clean up after the crowd leaves.
+    pub settle: Duration,
+
+    /// How many rounds to run, or until a leak is found.
+    pub rounds: Option<u32>,
+}
+
+pub async fn run(
+    server: &Server,
+    endpoint: Arc<Endpoint>,
+    options: &Options,
+    soak: &SoakOptions,
+) -> Result<()> {
+    let baseline = watched(http::metrics(server).await?);
+    if !SETTLED
+        .iter()
+        .chain(BOUNDED)
+        .all(|name| baseline.keys().any(|key| matches(key, name)))
+    {
+        bail!("The server's /metrics lacks the gauges soak mode watches; is it up to date?");
+    }
+    println!("before: {}", summary(&baseline));
+
+    let mut history: BTreeMap<String, Vec<f64>> = BTreeMap::new();
+    let mut round = 0;
+    while soak.rounds.is_none_or(|rounds| round < rounds) {
+        round += 1;
+        let stats = Arc::new(Stats::default());
+        let crowd = Crowd::start(endpoint.clone(), options, stats.clone());
+        tokio::time::sleep(soak.hold).await;
+        let connected = stats.connected.load(Ordering::Relaxed);
+        crowd.stop().await;
+        if stats.logins.load(Ordering::Relaxed) == 0 {
+            bail!("Round {round}: no bot could log in");
+        }
+
+        // Cleanup may take a moment, so the settled metrics get until the settle time is up.
+        let deadline = tokio::time::Instant::now() + soak.settle;
+        let sample = loop {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            let sample = watched(http::metrics(server).await?);
+            if unsettled(&baseline, &sample).is_empty() || tokio::time::Instant::now() >= deadline {
+                break sample;
+            }
+        };
+        println!(
+            "round {round}: {connected}/{} connected, {} failures; after: {}",
+            options.clients,
+            stats.failures.load(Ordering::Relaxed),
+            summary(&sample)
+        );
+
+        let unsettled = unsettled(&baseline, &sample);
+        if !unsettled.is_empty() {
+            bail!(
+                "LEAK after round {round}, {:?} after every bot left:\n{}",
+                soak.settle,
+                unsettled.join("\n")
+            );
+        }
+        for (key, value) in &sample {
+            if BOUNDED.iter().any(|name| matches(key, name)) {
+                history.entry(key.clone()).or_default().push(*value);
+            }
+        }
+        let growing: Vec<String> = history
+            .iter()
+            .filter(|(_, values)| {
+                values.len() > GROWTH_ROUNDS
+                    && values[values.len() - GROWTH_ROUNDS - 1..]
+                        .windows(2)
+                        .all(|pair| pair[1] > pair[0])
+            })
+            .map(|(key, values)| {
+                let start = baseline.get(key).copied().unwrap_or_default();
+                format!("  {key}: {start} before, then {values:?}")
+            })
+            .collect();
+        if !growing.is_empty() {
+            bail!(
+                "LEAK after round {round}, growing for {GROWTH_ROUNDS} rounds in a row:\n{}",
+                growing.join("\n")
+            );
+        }
+    }
+    println!("No leaks in {round} rounds");
+    Ok(())
+}
+
+/// The settled metrics that are not back where they started, as lines for the failure.
+fn unsettled(baseline: &BTreeMap<String, f64>, sample: &BTreeMap<String, f64>) -> Vec<String> {
+    sample
+        .iter()
+        .filter(|(key, _)| SETTLED.iter().any(|name| matches(key, name)))
+        .filter_map(|(key, value)| {
+            let start = baseline.get(key).copied().unwrap_or_default();
+            (*value > start).then(|| format!("  {key}: {start} before, {value} now"))
+        })
+        .collect()
+}
+
+fn watched(metrics: BTreeMap<String, f64>) -> BTreeMap<String, f64> {
+    metrics
+        .into_iter()
+        .filter(|(key, _)| SETTLED.iter().chain(BOUNDED).any(|name| matches(key, name)))
+        .collect()
+}
+
+/// Whether a sample's key is the metric `name`, which may name a single labelled sample.
+fn matches(key: &str, name: &str) -> bool {
+    key == name
+        || (!name.contains('{')
+            && key
+                .strip_prefix(name)
+                .is_some_and(|rest| rest.starts_with('{')))
+}
+
+fn summary(sample: &BTreeMap<String, f64>) -> String {
+    let get = |key: &str| sample.get(key).copied().unwrap_or_default();
+    let memory: f64 = sample
+        .iter()
+        .filter(|(key, _)| matches(key, "voice_memory_bytes"))
+        .map(|(_, value)| value)
+        .sum();
+    format!(
+        "{} sessions, {} rooms, {} tasks, {} buffers in use, {} idle, {memory} bytes buffered",
+        get("voice_registry_entries{map=\"sessions\"}"),
+        get("voice_registry_entries{map=\"rooms\"}"),
+        get("voice_tasks"),
+        get("voice_read_buffers{state=\"in_use\"}"),
+        get("voice_read_buffers{state=\"idle\"}"),
+    )
+}